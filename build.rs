@@ -0,0 +1,19 @@
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        cbindgen::generate(&crate_dir)
+            .expect("generating C bindings")
+            .write_to_file(std::path::Path::new(&crate_dir).join("include/payments.h"));
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+    }
+    #[cfg(feature = "protobuf")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        prost_build::compile_protos(&["proto/payments.proto"], &["proto"])
+            .expect("generating protobuf messages");
+        println!("cargo:rerun-if-changed=proto/payments.proto");
+    }
+}
@@ -0,0 +1,26 @@
+//! Any input is parsed and applied without panicking, and the state it ends up in can be
+//! written, replayed and rolled back.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use payments::{
+    parser::{parse_quoted, ParseOptions},
+    payments::Payments,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let options = ParseOptions {
+        lenient_quotes: true,
+        flexible: true,
+    };
+    let mut payments = Payments::default();
+    for transaction in parse_quoted(data, options).flatten() {
+        let _ = payments.preview(&transaction);
+        let _ = payments.apply(transaction);
+    }
+    let _ = payments.serialize(std::io::sink());
+    let _ = payments.stats().to_string();
+    Payments::replay_with(payments.config().clone(), payments.events().to_vec());
+    payments.rollback(payments.events().len() / 2);
+    let _ = payments.serialize(std::io::sink());
+});
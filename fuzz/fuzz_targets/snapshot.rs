@@ -0,0 +1,11 @@
+//! Snapshots are read from disk, any content is refused or read, migrating it, without
+//! panicking. Replaying the events is left out, snapshots are verified before they're restored.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use payments::snapshot;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = snapshot::read_header(data);
+    let _ = snapshot::read(data);
+});
@@ -0,0 +1,184 @@
+#ifndef PAYMENTS_H
+#define PAYMENTS_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * How many of the clients holding the most funds `Status` reports
+ */
+#define TOP_HELD 10
+
+/**
+ * The version snapshots are written in
+ */
+#define VERSION 2
+
+/**
+ * How many of the latest rejected transactions are kept
+ */
+#define RECENT_REJECTIONS 10
+
+/**
+ * Decimal places amounts may have
+ */
+#define AMOUNT_SCALE 4
+
+typedef enum PaymentsOperationType {
+  PAYMENTS_OPERATION_TYPE_DEPOSIT,
+  PAYMENTS_OPERATION_TYPE_WITHDRAWAL,
+  PAYMENTS_OPERATION_TYPE_DISPUTE,
+  PAYMENTS_OPERATION_TYPE_RESOLVE,
+  PAYMENTS_OPERATION_TYPE_CHARGEBACK,
+  /**
+   * Correct the amount of a deposit or withdrawal which wasn't disputed
+   */
+  PAYMENTS_OPERATION_TYPE_AMEND,
+  /**
+   * Make the pending funds of a deposit available
+   */
+  PAYMENTS_OPERATION_TYPE_SETTLE,
+} PaymentsOperationType;
+
+typedef enum PaymentsStatus {
+  PAYMENTS_STATUS_OK = 0,
+  PAYMENTS_STATUS_NULL_POINTER,
+  PAYMENTS_STATUS_INVALID_AMOUNT,
+  PAYMENTS_STATUS_CLIENT_NOT_FOUND,
+  PAYMENTS_STATUS_BUFFER_TOO_SMALL,
+  PAYMENTS_STATUS_SERIALIZATION_FAILURE,
+  PAYMENTS_STATUS_PARSING_FAILURE,
+  PAYMENTS_STATUS_DUPLICATED_TRANSACTION,
+  PAYMENTS_STATUS_TRANSACTION_NOT_FOUND,
+  PAYMENTS_STATUS_INSUFFICIENT_FUNDS,
+  PAYMENTS_STATUS_INVALID_TRANSACTION_STATE_CHANGE,
+  PAYMENTS_STATUS_ACCOUNT_LOCKED,
+  PAYMENTS_STATUS_FAILED_DISPUTE_NOT_ENOUGH_FUNDS,
+  PAYMENTS_STATUS_BATCH_ROLLED_BACK,
+  PAYMENTS_STATUS_BATCH_ABORTED,
+  PAYMENTS_STATUS_INVALID_REFERENCE,
+  PAYMENTS_STATUS_RISK_SCORE_EXCEEDED,
+  PAYMENTS_STATUS_DEDUP_FAILURE,
+  PAYMENTS_STATUS_MEMORY_LIMIT_EXCEEDED,
+  PAYMENTS_STATUS_CHECKSUM_MISSING,
+  PAYMENTS_STATUS_CHECKSUM_MISMATCH,
+  PAYMENTS_STATUS_DUPLICATE_FILE,
+  PAYMENTS_STATUS_BROKEN_SEAL,
+  PAYMENTS_STATUS_INVALID_SIGNATURE,
+  PAYMENTS_STATUS_ACCOUNT_NOT_LOCKED,
+  PAYMENTS_STATUS_RATE_LIMITED,
+  PAYMENTS_STATUS_FX_RATE_NOT_FOUND,
+  PAYMENTS_STATUS_BELOW_RESERVE,
+  PAYMENTS_STATUS_BALANCE_NOT_NEGATIVE,
+  PAYMENTS_STATUS_CLIENT_BLOCKED,
+  PAYMENTS_STATUS_BANK_ACCOUNT_NOT_FOUND,
+  PAYMENTS_STATUS_ACCOUNT_CLOSED,
+  PAYMENTS_STATUS_ACCOUNT_NOT_EMPTY,
+  PAYMENTS_STATUS_BROKEN_CHAIN,
+  PAYMENTS_STATUS_UNSUPPORTED_SNAPSHOT_VERSION,
+  PAYMENTS_STATUS_AMENDMENT_OF_DISPUTED,
+  PAYMENTS_STATUS_AMENDMENT_OF_PENDING,
+  PAYMENTS_STATUS_OVERFLOW,
+  PAYMENTS_STATUS_IO,
+  PAYMENTS_STATUS_CURRENCY_MISMATCH,
+  PAYMENTS_STATUS_NOT_QUARANTINED,
+  PAYMENTS_STATUS_SAME_OPERATOR,
+  PAYMENTS_STATUS_APPROVAL_EXCEEDED,
+  PAYMENTS_STATUS_REJECTED,
+} PaymentsStatus;
+
+/**
+ * Opaque engine handle.
+ */
+typedef struct PaymentsEngine PaymentsEngine;
+
+typedef uint16_t ClientId;
+
+typedef uint32_t TransactionId;
+
+/**
+ * Fixed-point decimal: `mantissa * 10^-scale`, `scale` must not exceed 28.
+ */
+typedef struct PaymentsAmount {
+  int64_t mantissa;
+  uint32_t scale;
+} PaymentsAmount;
+
+/**
+ * A transaction to apply. `amount` is ignored for disputes, resolves and chargebacks, it's
+ * the corrected amount of an amendment.
+ */
+typedef struct PaymentsTransaction {
+  enum PaymentsOperationType kind;
+  ClientId client;
+  TransactionId tx;
+  struct PaymentsAmount amount;
+} PaymentsTransaction;
+
+typedef struct PaymentsBalance {
+  struct PaymentsAmount available;
+  struct PaymentsAmount held;
+  struct PaymentsAmount total;
+  bool locked;
+} PaymentsBalance;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a new, empty engine. Must be released with `payments_free`.
+ */
+struct PaymentsEngine *payments_new(void);
+
+/**
+ * Release an engine created by `payments_new`. Passing NULL is a no-op.
+ *
+ * # Safety
+ * `engine` must be NULL or a pointer returned by `payments_new` that wasn't freed yet.
+ */
+void payments_free(struct PaymentsEngine *engine);
+
+/**
+ * Apply a single transaction.
+ *
+ * # Safety
+ * `engine` must be a valid engine pointer and `transaction` must point to a valid struct.
+ */
+enum PaymentsStatus payments_apply(struct PaymentsEngine *engine,
+                                   const struct PaymentsTransaction *transaction);
+
+/**
+ * Query balance of a client.
+ *
+ * # Safety
+ * `engine` must be a valid engine pointer and `balance` must point to writable memory.
+ */
+enum PaymentsStatus payments_balance(const struct PaymentsEngine *engine,
+                                     ClientId client,
+                                     struct PaymentsBalance *balance);
+
+/**
+ * Serialize all accounts to CSV (not NUL-terminated) into `buffer`.
+ * The number of bytes required is always stored in `written`; if `buffer` is NULL
+ * or `capacity` is too small, nothing is copied and `BufferTooSmall` is returned.
+ *
+ * # Safety
+ * `engine` must be a valid engine pointer, `written` must point to writable memory and
+ * `buffer` must be NULL or point to at least `capacity` writable bytes.
+ */
+enum PaymentsStatus payments_serialize(const struct PaymentsEngine *engine,
+                                       char *buffer,
+                                       size_t capacity,
+                                       size_t *written);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* PAYMENTS_H */
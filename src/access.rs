@@ -0,0 +1,104 @@
+//! Access lists of clients: a denylist of clients whose transactions are rejected outright,
+//! e.g. sanctioned or blocked accounts, and optionally an allowlist, outside of which every
+//! client is rejected. Both are files of client IDs:
+//!
+//! ```text
+//! client
+//! 7
+//! 12
+//! ```
+use std::{collections::HashSet, path::Path};
+
+use serde::Deserialize;
+
+use crate::{client::ClientId, error::Error};
+
+#[derive(Debug, Deserialize)]
+struct ClientRecord {
+    client: ClientId,
+}
+
+/// A set of clients read from a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientList {
+    clients: HashSet<ClientId>,
+}
+
+impl ClientList {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| Error::ParsingFailure(format!("{}: {}", path.display(), e)))?;
+        Self::parse(rdr)
+    }
+
+    pub fn parse<R: std::io::Read>(mut rdr: csv::Reader<R>) -> Result<Self, Error> {
+        let mut list = Self::default();
+        for record in rdr.deserialize() {
+            let ClientRecord { client } =
+                record.map_err(|e| Error::ParsingFailure(e.to_string()))?;
+            list.insert(client);
+        }
+        Ok(list)
+    }
+
+    pub fn insert(&mut self, client: ClientId) {
+        self.clients.insert(client);
+    }
+
+    pub fn contains(&self, client: ClientId) -> bool {
+        self.clients.contains(&client)
+    }
+}
+
+/// See the module documentation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    pub denied: ClientList,
+    /// Only these clients are let in, if given
+    pub allowed: Option<ClientList>,
+}
+
+impl Access {
+    /// Reject the transactions of `client` if it's denied or not allowed
+    pub fn check(&self, client: ClientId) -> Result<(), Error> {
+        let allowed = self
+            .allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(client));
+        match allowed && !self.denied.contains(client) {
+            true => Ok(()),
+            false => Err(Error::ClientBlocked(client)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, ClientList};
+    use crate::error::Error;
+
+    #[test]
+    fn denied_and_allowed_clients() {
+        let rdr = csv::Reader::from_reader("client\n1\n2".as_bytes());
+        let mut access = Access {
+            denied: ClientList::parse(rdr).unwrap(),
+            allowed: None,
+        };
+        assert_eq!(access.check(1), Err(Error::ClientBlocked(1)));
+        assert_eq!(access.check(3), Ok(()));
+
+        let mut allowed = ClientList::default();
+        allowed.insert(2);
+        allowed.insert(3);
+        access.allowed = Some(allowed);
+        // Denied even if allowed
+        assert_eq!(access.check(2), Err(Error::ClientBlocked(2)));
+        assert_eq!(access.check(3), Ok(()));
+        assert_eq!(access.check(4), Err(Error::ClientBlocked(4)));
+
+        let rdr = csv::Reader::from_reader("client\nx".as_bytes());
+        assert!(ClientList::parse(rdr).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+//! Four-eyes approval of large operator actions. With `Config::approval_threshold`, an unlock,
+//! write-off or forced resolution of a larger amount isn't taken when an operator asks for it,
+//! but recorded as pending, `Event::ApprovalRequested`. It's taken once an operator other than
+//! the one who asked for it asks for the same action, which approves it,
+//! `Event::ActionApproved`. The amount of an unlock is the total funds of the account, of a
+//! write-off the negative balance and of a forced resolution the held funds.
+//!
+//! Pending approvals are a read model of the event log, so they're kept in snapshots, see
+//! `Payments::pending_approvals`.
+//!
+//! ```
+//! use payments::{
+//!     approval::{AdminAction, Approval},
+//!     payments::{Config, Payments},
+//!     transaction::Transaction,
+//! };
+//! use rust_decimal_macros::dec;
+//!
+//! let mut payments = Payments::with_config(Config {
+//!     approval_threshold: Some(dec!(1000)),
+//!     ..Config::default()
+//! });
+//! payments.apply(Transaction::deposit(1, 1, dec!(5000)).unwrap()).unwrap();
+//! payments.apply(Transaction::dispute(1, 1)).unwrap();
+//!
+//! let resolve = AdminAction::ForceResolve { tx: 1 };
+//! let pending = payments.act(1, resolve, 7, None).unwrap();
+//! assert_eq!(pending, Approval::Pending { amount: dec!(5000) });
+//! assert_eq!(payments.pending_approvals().count(), 1);
+//! // Approved by another operator
+//! let taken = payments.act(1, resolve, 8, None).unwrap();
+//! assert_eq!(taken, Approval::Taken { amount: dec!(5000) });
+//! assert_eq!(payments.client(1).unwrap().available(), dec!(5000));
+//! ```
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::ClientId,
+    transaction::{Timestamp, TransactionId},
+};
+
+/// Who took an operator action, e.g. an employee number
+pub type OperatorId = u32;
+
+/// An operator action on a client which may need approval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum AdminAction {
+    /// See `Payments::unlock`
+    Unlock,
+    /// See `Payments::write_off`
+    WriteOff,
+    /// See `Payments::force_resolve`
+    ForceResolve { tx: TransactionId },
+}
+
+/// What became of an operator action, see `Payments::act`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approval {
+    Taken {
+        amount: Decimal,
+    },
+    /// Waiting for the approval of another operator
+    Pending {
+        amount: Decimal,
+    },
+}
+
+/// An action waiting for the approval of a second operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PendingApproval {
+    pub client: ClientId,
+    #[serde(flatten)]
+    pub action: AdminAction,
+    pub amount: Decimal,
+    pub requested_by: OperatorId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<Timestamp>,
+}
@@ -0,0 +1,247 @@
+//! Storage of a client's operations.
+//!
+//! Operations are never removed, so they're kept back to back in a `Vec`, in the order they
+//! were inserted, and the lookup index only holds their small positions. This makes a single
+//! allocation grow per client instead of the map moving full operations around, and keeps
+//! iteration over the operations sequential in memory.
+//!
+//! Transaction IDs are often near-contiguous, so the index starts out as a `Vec` of positions
+//! offset by the lowest ID, and falls back to a hash map once the IDs get too sparse for it.
+use std::{collections::HashMap, iter::repeat_n, mem::size_of};
+
+use crate::transaction::TransactionId;
+
+/// Marks an ID without an operation in a dense index
+const VACANT: u32 = u32::MAX;
+
+/// A dense index may span this many IDs per operation, plus `DENSE_SLACK`,
+/// before it's converted to a hash map
+const DENSE_IDS_PER_OPERATION: u64 = 2;
+const DENSE_SLACK: u64 = 64;
+
+#[derive(Debug, Clone)]
+enum Index {
+    /// Positions of the operations with IDs `first..first + slots.len()`
+    Dense {
+        first: TransactionId,
+        slots: Vec<u32>,
+    },
+    Sparse(HashMap<TransactionId, u32>),
+}
+
+impl Index {
+    fn get(&self, id: TransactionId) -> Option<u32> {
+        match self {
+            Index::Dense { first, slots } => id
+                .checked_sub(*first)
+                .and_then(|offset| slots.get(offset as usize))
+                .copied()
+                .filter(|&slot| slot != VACANT),
+            Index::Sparse(map) => map.get(&id).copied(),
+        }
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (TransactionId, u32)> + '_> {
+        match self {
+            Index::Dense { first, slots } => Box::new(
+                (*first..=TransactionId::MAX)
+                    .zip(slots.iter().copied())
+                    .filter(|&(_, slot)| slot != VACANT),
+            ),
+            Index::Sparse(map) => Box::new(map.iter().map(|(&id, &slot)| (id, slot))),
+        }
+    }
+
+    /// Index a new ID, `len` being the number of indexed IDs including it
+    fn insert(&mut self, id: TransactionId, slot: u32, len: usize) {
+        if let Index::Dense { first, slots } = self {
+            let (low, high) = match slots.len() {
+                0 => (id, id),
+                n => (id.min(*first), id.max(*first + (n - 1) as TransactionId)),
+            };
+            let span = u64::from(high - low) + 1;
+            if span <= len as u64 * DENSE_IDS_PER_OPERATION + DENSE_SLACK {
+                if slots.is_empty() {
+                    *first = id;
+                } else if id < *first {
+                    slots.splice(0..0, repeat_n(VACANT, (*first - id) as usize));
+                    *first = id;
+                }
+                let offset = (id - *first) as usize;
+                if offset >= slots.len() {
+                    slots.resize(offset + 1, VACANT);
+                }
+                slots[offset] = slot;
+                return;
+            }
+            *self = Index::Sparse(self.entries().collect());
+        }
+        if let Index::Sparse(map) = self {
+            map.insert(id, slot);
+        }
+    }
+
+    /// Approximate heap memory used, in bytes
+    fn memory_usage(&self) -> usize {
+        match self {
+            Index::Dense { slots, .. } => slots.capacity() * size_of::<u32>(),
+            // The hash map keeps a control byte per bucket
+            Index::Sparse(map) => map.capacity() * (size_of::<(TransactionId, u32)>() + 1),
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        match self {
+            Index::Dense { slots, .. } => slots.shrink_to_fit(),
+            Index::Sparse(map) => map.shrink_to_fit(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct OperationArena<T> {
+    slots: Vec<T>,
+    index: Index,
+}
+
+impl<T> Default for OperationArena<T> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            index: Index::Dense {
+                first: 0,
+                slots: Vec::new(),
+            },
+        }
+    }
+}
+
+impl<T> OperationArena<T> {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn contains_key(&self, id: &TransactionId) -> bool {
+        self.index.get(*id).is_some()
+    }
+
+    pub fn get(&self, id: &TransactionId) -> Option<&T> {
+        self.index.get(*id).map(|slot| &self.slots[slot as usize])
+    }
+
+    pub fn get_mut(&mut self, id: &TransactionId) -> Option<&mut T> {
+        self.index
+            .get(*id)
+            .map(|slot| &mut self.slots[slot as usize])
+    }
+
+    /// Insert an operation, replacing the one with the same ID in place. An operation beyond
+    /// the `u32::MAX - 1` slots, of a client using every transaction ID, isn't kept.
+    pub fn insert(&mut self, id: TransactionId, value: T) {
+        match self.index.get(id) {
+            Some(slot) => self.slots[slot as usize] = value,
+            None => {
+                let Some(slot) = u32::try_from(self.slots.len())
+                    .ok()
+                    .filter(|&slot| slot != VACANT)
+                else {
+                    return;
+                };
+                self.slots.push(value);
+                self.index.insert(id, slot, self.slots.len());
+            }
+        }
+    }
+
+    /// Operations in insertion order
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter()
+    }
+
+    /// Approximate heap memory used, in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.slots.capacity() * size_of::<T>() + self.index.memory_usage()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.slots.shrink_to_fit();
+        self.index.shrink_to_fit();
+    }
+}
+
+/// Equal when holding equal operations under the same IDs, regardless of insertion order
+impl<T: PartialEq> PartialEq for OperationArena<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .index
+                .entries()
+                .all(|(id, slot)| other.get(&id) == Some(&self.slots[slot as usize]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Index, OperationArena};
+
+    #[test]
+    fn insert_and_lookup() {
+        let mut arena = OperationArena::default();
+        arena.insert(7, "a");
+        arena.insert(3, "b");
+        arena.insert(7, "c");
+        assert_eq!(arena.len(), 2);
+        assert_eq!(arena.get(&7), Some(&"c"));
+        assert_eq!(arena.get(&1), None);
+        *arena.get_mut(&3).unwrap() = "d";
+        assert_eq!(arena.values().collect::<Vec<_>>(), [&"c", &"d"]);
+    }
+
+    #[test]
+    fn equality_ignores_order() {
+        let mut a = OperationArena::default();
+        a.insert(1, 10);
+        a.insert(2, 20);
+        let mut b = OperationArena::default();
+        b.insert(2, 20);
+        b.insert(1, 10);
+        assert_eq!(a, b);
+        b.insert(3, 30);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn dense_ids() {
+        let mut arena = OperationArena::default();
+        // Roughly ascending, with gaps and IDs below the first one
+        let ids = (1000..2000).filter(|id| id % 3 != 0).chain(990..1000);
+        for id in ids.clone() {
+            arena.insert(id, id);
+        }
+        assert!(matches!(arena.index, Index::Dense { first: 990, .. }));
+        assert!(ids.clone().all(|id| arena.get(&id) == Some(&id)));
+        assert!([0, 989, 1002, 2000]
+            .iter()
+            .all(|id| arena.get(id).is_none()));
+
+        let mut sparse = OperationArena::default();
+        for id in ids.map(|id| id * 1000) {
+            sparse.insert(id, id);
+        }
+        assert!(matches!(sparse.index, Index::Sparse(_)));
+        assert!(arena.memory_usage() < sparse.memory_usage());
+    }
+
+    #[test]
+    fn falls_back_to_sparse() {
+        let mut arena = OperationArena::default();
+        for id in 0..100 {
+            arena.insert(id, id);
+        }
+        arena.insert(u32::MAX - 1, 1);
+        assert!(matches!(arena.index, Index::Sparse(_)));
+        assert_eq!(arena.len(), 101);
+        assert!((0..100).all(|id| arena.get(&id) == Some(&id)));
+        assert_eq!(arena.get(&(u32::MAX - 1)), Some(&1));
+    }
+}
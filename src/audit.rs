@@ -0,0 +1,207 @@
+//! Audit log of the actions affecting accounts beyond moving funds: locks by chargebacks,
+//! reversals and write-offs of charged back withdrawals, amendments of transactions,
+//! quarantines, and the operator's unlocks, write-offs, closures and forced resolutions, with the
+//! ID of the operator. Records are appended to a hash-chained log (see `hashchain`), so they
+//! can't be altered or removed unnoticed.
+//!
+//! ```text
+//! {"seq":0,"prev":"00..00","recorded_at":"2024-03-31T12:00:05Z","timestamp":"2024-03-31T12:00:00Z","actor":"transaction","action":"lock","client":1,"tx":7,"hash":"5f..c1"}
+//! {"seq":1,"prev":"5f..c1","recorded_at":"2024-04-02T09:30:00Z","actor":"operator","operator":7,"action":"unlock","client":1,"hash":"a3..0e"}
+//! ```
+use std::{fs::File, io::Write, path::Path};
+
+use chrono::Utc;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    approval::OperatorId,
+    client::ClientId,
+    event::Event,
+    hashchain::Chain,
+    payments::{Marker, Payments},
+    transaction::{TenantId, Timestamp, TransactionId},
+};
+
+/// Who took an action
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Actor {
+    /// A transaction, e.g. a chargeback locking the account
+    Transaction,
+    /// An operator, e.g. with the admin endpoints
+    Operator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Lock,
+    Unlock,
+    /// A charged back withdrawal credited back to the client
+    Reverse,
+    WriteOff,
+    Close,
+    ForceResolve,
+    /// A deposit or withdrawal corrected to another amount
+    Amend,
+    /// See `quarantine`
+    Quarantine,
+    LiftQuarantine,
+    /// An action waiting for a second operator's approval, see `approval`
+    RequestApproval,
+    Approve,
+}
+
+/// The action of `event` taken by `actor`, if it's audited
+fn action(event: &Event, actor: Actor) -> Option<Action> {
+    Some(match event {
+        Event::AccountLocked { .. } => Action::Lock,
+        Event::AccountUnlocked => Action::Unlock,
+        Event::WithdrawalReversed { .. } => Action::Reverse,
+        Event::WithdrawalWrittenOff { .. } | Event::BalanceWrittenOff { .. } => Action::WriteOff,
+        Event::AccountClosed => Action::Close,
+        Event::FundsReleased { .. } if actor == Actor::Operator => Action::ForceResolve,
+        Event::TransactionAmended { .. } => Action::Amend,
+        Event::ClientQuarantined { .. } => Action::Quarantine,
+        Event::QuarantineLifted => Action::LiftQuarantine,
+        Event::ApprovalRequested { .. } => Action::RequestApproval,
+        Event::ActionApproved { .. } => Action::Approve,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    /// When the record was written
+    pub recorded_at: Timestamp,
+    /// Of the transaction causing the action
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    pub actor: Actor,
+    /// Who took the action, if it was an operator
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operator: Option<OperatorId>,
+    /// Whose state the client is in, with `--tenants`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    pub action: Action,
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TransactionId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Decimal>,
+}
+
+/// Appends the audited actions to a log
+pub struct AuditLog<W: Write> {
+    output: W,
+    chain: Chain,
+}
+
+impl AuditLog<File> {
+    /// Append to the log at `path`, continuing its chain once it's verified
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let chain = Chain::resume(path)?;
+        let output = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self { output, chain })
+    }
+}
+
+impl<W: Write> AuditLog<W> {
+    pub fn new(output: W, chain: Chain) -> Self {
+        Self { output, chain }
+    }
+
+    /// Record the audited actions among the events of `payments` since `marker`
+    pub fn record(
+        &mut self,
+        payments: &Payments,
+        marker: Marker,
+        actor: Actor,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.record_tenant(payments, None, marker, actor)
+    }
+
+    /// Same as `record`, for the state of `tenant`, see `tenant`
+    pub fn record_tenant(
+        &mut self,
+        payments: &Payments,
+        tenant: Option<&str>,
+        marker: Marker,
+        actor: Actor,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let recorded_at = Utc::now();
+        for event in payments.events_since(marker) {
+            let Some(action) = action(&event.event, actor) else {
+                continue;
+            };
+            let amount = match event.event {
+                Event::WithdrawalReversed { amount, .. }
+                | Event::WithdrawalWrittenOff { amount, .. }
+                | Event::BalanceWrittenOff { amount }
+                | Event::FundsReleased { amount, .. }
+                | Event::TransactionAmended { amount, .. }
+                | Event::ApprovalRequested { amount, .. } => Some(amount),
+                _ => None,
+            };
+            let record = AuditRecord {
+                recorded_at,
+                timestamp: event.timestamp,
+                actor,
+                operator: event.operator,
+                tenant: tenant.map(str::to_string),
+                action,
+                client: event.client,
+                tx: event.event.tx(),
+                amount,
+            };
+            self.chain.append(&record, &mut self.output)?;
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+
+    /// The end of the log's chain
+    pub fn chain(&self) -> &Chain {
+        &self.chain
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Actor, AuditLog};
+    use crate::{hashchain::Chain, payments::Marker, testing::process};
+
+    #[test]
+    fn chargeback_lock_and_operator_unlock() {
+        let mut payments = process(
+            "type, client, tx, amount
+            deposit, 1, 1, 5
+            dispute, 1, 1,
+            chargeback, 1, 1,",
+        );
+        let mut audit = AuditLog::new(Vec::new(), Chain::default());
+        audit
+            .record(&payments, Marker::default(), Actor::Transaction)
+            .unwrap();
+        let marker = payments.marker();
+        payments.unlock(1, Some(7), None).unwrap();
+        audit.record(&payments, marker, Actor::Operator).unwrap();
+
+        let log = String::from_utf8(audit.output).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""actor":"transaction","action":"lock","client":1,"tx":1"#));
+        assert!(lines[1]
+            .contains(r#""actor":"operator","operator":7,"action":"unlock","client":1,"hash""#));
+        assert_eq!(Chain::verify(log.as_bytes()).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,401 @@
+//! Built-in synthetic workloads (`payments bench`), to measure the write throughput of a
+//! deployment's settings without a harness of one's own: transactions per second, the p99
+//! latency of applying a transaction (and logging it, with a transaction log) and the memory
+//! the state takes in the end.
+//!
+//! Workloads are generated from a seed, so runs with the same settings apply the same
+//! transactions. Disputes, resolves and chargebacks refer to earlier deposits of their client.
+use std::{
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    client::ClientId,
+    latency::{Histogram, Latencies},
+    payments::{Config, Payments},
+    signature::canonical,
+    transaction::{Transaction, TransactionId},
+    txlog::TransactionLog,
+};
+
+/// How many transactions may be queued for a shard, see `parallel`
+const SHARD_QUEUE: usize = 1024;
+
+/// The mix of operations of a workload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Mostly deposits, e.g. a payroll run
+    DepositsHeavy,
+    /// A quarter of the transactions dispute a deposit, most disputes are settled
+    DisputeHeavy,
+    /// Deposits and withdrawals with occasional disputes
+    Mixed,
+}
+
+impl Profile {
+    /// Percentages of deposits, withdrawals, disputes, resolves and chargebacks
+    fn weights(self) -> [u64; 5] {
+        match self {
+            Profile::DepositsHeavy => [90, 8, 1, 1, 0],
+            Profile::DisputeHeavy => [45, 10, 25, 18, 2],
+            Profile::Mixed => [60, 30, 5, 4, 1],
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposits-heavy" => Ok(Profile::DepositsHeavy),
+            "dispute-heavy" => Ok(Profile::DisputeHeavy),
+            "mixed" => Ok(Profile::Mixed),
+            _ => Err(format!(
+                "unknown profile `{}`, expected deposits-heavy, dispute-heavy or mixed",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Profile::DepositsHeavy => "deposits-heavy",
+            Profile::DisputeHeavy => "dispute-heavy",
+            Profile::Mixed => "mixed",
+        })
+    }
+}
+
+/// SplitMix64, deterministic and good enough to pick operations
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+}
+
+/// The deposits of a client which may be disputed, and those which are
+#[derive(Default)]
+struct Deposits {
+    undisputed: Vec<TransactionId>,
+    disputed: Vec<TransactionId>,
+}
+
+/// `transactions` transactions of `profile` by `clients` clients, generated from `seed`
+pub fn workload(
+    profile: Profile,
+    transactions: usize,
+    clients: ClientId,
+    seed: u64,
+) -> impl Iterator<Item = Transaction> {
+    let mut rng = Rng(seed);
+    let mut deposits = (0..clients.max(1))
+        .map(|_| Deposits::default())
+        .collect::<Vec<_>>();
+    let weights = profile.weights();
+    (0..transactions)
+        .map(move |n| {
+            let client = rng.below(deposits.len() as u64) as usize;
+            let deposits = &mut deposits[client];
+            let (client, tx) = (client as ClientId, n as TransactionId);
+            let mut roll = rng.below(100);
+            let kind = weights
+                .iter()
+                .position(|&weight| {
+                    let hit = roll < weight;
+                    roll = roll.saturating_sub(weight);
+                    hit
+                })
+                .unwrap_or(0);
+            let pick = |ids: &mut Vec<TransactionId>, rng: &mut Rng| {
+                let i = rng.below(ids.len() as u64) as usize;
+                ids.swap_remove(i)
+            };
+            match kind {
+                1 => {
+                    Transaction::withdrawal(client, tx, Decimal::new(rng.below(1000) as i64 + 1, 2))
+                }
+                2 if !deposits.undisputed.is_empty() => {
+                    let disputed = pick(&mut deposits.undisputed, &mut rng);
+                    deposits.disputed.push(disputed);
+                    Ok(Transaction::dispute(client, disputed))
+                }
+                // Resolved deposits can't be disputed again
+                3 if !deposits.disputed.is_empty() => Ok(Transaction::resolve(
+                    client,
+                    pick(&mut deposits.disputed, &mut rng),
+                )),
+                4 if !deposits.disputed.is_empty() => Ok(Transaction::chargeback(
+                    client,
+                    pick(&mut deposits.disputed, &mut rng),
+                )),
+                _ => {
+                    deposits.undisputed.push(tx);
+                    Transaction::deposit(client, tx, Decimal::new(rng.below(100_000) as i64 + 1, 2))
+                }
+            }
+        })
+        // The amounts are positive, so none is left out
+        .filter_map(Result::ok)
+}
+
+/// How to run a workload
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub transactions: usize,
+    pub clients: ClientId,
+    pub seed: u64,
+    /// Apply the transactions in this many parallel shards by client, in this thread if 1
+    pub shards: usize,
+    /// Append the accepted transactions to this transaction log, see `txlog`
+    pub transaction_log: Option<PathBuf>,
+    pub config: Config,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            transactions: 1_000_000,
+            clients: 10_000,
+            seed: 0,
+            shards: 1,
+            transaction_log: None,
+            config: Config::default(),
+        }
+    }
+}
+
+/// The results of a run
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub profile: Profile,
+    pub transactions: usize,
+    pub rejected: usize,
+    #[serde(serialize_with = "seconds")]
+    pub elapsed: Duration,
+    pub tps: f64,
+    pub p99_ns: u64,
+    /// Approximate, see `Payments::memory_usage`
+    pub memory_bytes: usize,
+    pub latencies: Latencies,
+}
+
+fn seconds<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} transactions ({} rejected) in {:.3?}",
+            self.profile, self.transactions, self.rejected, self.elapsed
+        )?;
+        writeln!(f, "throughput: {:.0} tps", self.tps)?;
+        writeln!(f, "p99 latency: {:?}", Duration::from_nanos(self.p99_ns))?;
+        for latency in self.latencies.summary() {
+            writeln!(
+                f,
+                "  {}: {}, p50: {:?}, p99: {:?}",
+                latency.kind,
+                latency.count,
+                Duration::from_nanos(latency.p50_ns),
+                Duration::from_nanos(latency.p99_ns)
+            )?;
+        }
+        write!(
+            f,
+            "memory: {:.1} MiB",
+            self.memory_bytes as f64 / (1 << 20) as f64
+        )
+    }
+}
+
+/// What a shard applied
+struct Shard {
+    payments: Payments,
+    rejected: usize,
+}
+
+/// Apply `transactions`, timing every one (and its logging), to a new state of `config`
+fn apply(
+    transactions: impl IntoIterator<Item = Transaction>,
+    config: Config,
+    log: Option<&Mutex<TransactionLog<std::fs::File>>>,
+) -> Result<Shard, String> {
+    let mut shard = Shard {
+        payments: Payments::with_config(config),
+        rejected: 0,
+    };
+    for transaction in transactions {
+        let kind = transaction.op.kind;
+        let entry = log.map(|_| canonical(&transaction));
+        let started = Instant::now();
+        match shard.payments.apply(transaction) {
+            Ok(()) => {
+                if let (Some(log), Some(entry)) = (log, entry) {
+                    let mut log = log.lock().unwrap_or_else(PoisonError::into_inner);
+                    log.append(entry).map_err(|e| e.to_string())?;
+                }
+            }
+            Err(_) => shard.rejected += 1,
+        }
+        shard.payments.record_latency(&kind, started.elapsed());
+    }
+    Ok(shard)
+}
+
+/// Run `profile` with `options`
+pub fn run(profile: Profile, options: &Options) -> Result<Report, Box<dyn std::error::Error>> {
+    let transactions = workload(profile, options.transactions, options.clients, options.seed);
+    let log = options
+        .transaction_log
+        .as_deref()
+        .map(TransactionLog::open)
+        .transpose()?
+        .map(Mutex::new);
+    let shards = options.shards.max(1);
+    let started = Instant::now();
+    let parts = match shards {
+        1 => vec![apply(transactions, options.config.clone(), log.as_ref())?],
+        _ => std::thread::scope(|scope| {
+            let (senders, workers): (Vec<_>, Vec<_>) = (0..shards)
+                .map(|_| {
+                    let (sender, receiver) = mpsc::sync_channel::<Transaction>(SHARD_QUEUE);
+                    let (config, log) = (options.config.clone(), log.as_ref());
+                    (sender, scope.spawn(move || apply(receiver, config, log)))
+                })
+                .unzip();
+            for transaction in transactions {
+                let shard = usize::from(transaction.client_id) % shards;
+                // A shard stops early only when it fails, reported on joining it
+                if senders[shard].send(transaction).is_err() {
+                    break;
+                }
+            }
+            drop(senders);
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|_| Err("shard worker panicked".to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?,
+    };
+    if let Some(log) = log {
+        log.into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush()?;
+    }
+    let elapsed = started.elapsed();
+
+    let mut latencies = Latencies::default();
+    let mut all = Histogram::default();
+    for part in &parts {
+        latencies.merge(&part.payments.stats().latencies);
+    }
+    for kind in latencies.histograms() {
+        all.merge(kind);
+    }
+    Ok(Report {
+        profile,
+        transactions: options.transactions,
+        rejected: parts.iter().map(|part| part.rejected).sum(),
+        elapsed,
+        tps: options.transactions as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        p99_ns: all.percentile(99).unwrap_or_default().as_nanos() as u64,
+        memory_bytes: parts.iter().map(|part| part.payments.memory_usage()).sum(),
+        latencies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{run, workload, Options, Profile};
+    use crate::transaction::OperationType;
+
+    #[test]
+    fn workloads() {
+        let mut disputes = HashMap::new();
+        for profile in [
+            Profile::DepositsHeavy,
+            Profile::DisputeHeavy,
+            Profile::Mixed,
+        ] {
+            let transactions = workload(profile, 10_000, 100, 7).collect::<Vec<_>>();
+            assert_eq!(
+                transactions,
+                workload(profile, 10_000, 100, 7).collect::<Vec<_>>()
+            );
+
+            // Disputes, resolves and chargebacks refer to earlier deposits of their client
+            let mut deposits = HashMap::new();
+            for transaction in &transactions {
+                match transaction.op.kind {
+                    OperationType::Deposit { .. } => {
+                        deposits.insert(transaction.op.id, transaction.client_id);
+                    }
+                    OperationType::Withdrawal { .. } => {}
+                    _ => {
+                        assert_eq!(
+                            deposits.get(&transaction.op.id),
+                            Some(&transaction.client_id)
+                        );
+                        *disputes.entry(profile).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+        assert!(disputes[&Profile::DisputeHeavy] > disputes[&Profile::Mixed]);
+        assert!(disputes[&Profile::Mixed] > disputes[&Profile::DepositsHeavy]);
+    }
+
+    #[test]
+    fn sharded_run() {
+        let options = Options {
+            transactions: 10_000,
+            clients: 100,
+            ..Default::default()
+        };
+        let single = run(Profile::Mixed, &options).unwrap();
+        let sharded = run(
+            Profile::Mixed,
+            &Options {
+                shards: 4,
+                ..options
+            },
+        )
+        .unwrap();
+        assert_eq!(single.rejected, sharded.rejected);
+        let count = |report: &super::Report| -> u64 {
+            report.latencies.summary().map(|kind| kind.count).sum()
+        };
+        assert_eq!(count(&single), 10_000);
+        assert_eq!(count(&sharded), 10_000);
+        assert!(single.p99_ns > 0 && single.memory_bytes > 0);
+    }
+}
@@ -0,0 +1,183 @@
+//! Composing the configuration of `Payments` in library use:
+//!
+//! ```
+//! use chrono::Duration;
+//! use std::sync::{
+//!     atomic::{AtomicUsize, Ordering},
+//!     Arc,
+//! };
+//!
+//! use payments::{
+//!     client::WithdrawalChargeback,
+//!     dedup::DedupScope,
+//!     payments::{ErrorPolicy, Payments},
+//! };
+//!
+//! let events = Arc::new(AtomicUsize::new(0));
+//! let counter = events.clone();
+//! let payments = Payments::builder()
+//!     .dispute_timeout(Duration::days(30))
+//!     .withdrawal_chargeback(WithdrawalChargeback::WriteOff)
+//!     .max_risk_score(80.0)
+//!     .dedup_scope(DedupScope::ClientOperation)
+//!     .error_policy(ErrorPolicy::Strict)
+//!     .observer(move |_event| {
+//!         counter.fetch_add(1, Ordering::Relaxed);
+//!     })
+//!     .build();
+//! ```
+use chrono::Duration;
+use rust_decimal::Decimal;
+
+use crate::{
+    access::Access,
+    client::{DisputePolicy, WithdrawalChargeback},
+    credit::CreditLimits,
+    dedup::DedupScope,
+    event::ClientEvent,
+    money::Currency,
+    payments::{Config, ErrorPolicy, Payments},
+    reserve::Reserves,
+    signature::SigningKey,
+};
+
+/// Builds `Payments` with the settings of `Config`, see there for what they do
+#[derive(Debug, Clone, Default)]
+pub struct PaymentsBuilder {
+    config: Config,
+}
+
+impl PaymentsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from `config`, e.g. read from a settings file
+    pub fn from_config(config: Config) -> Self {
+        Self { config }
+    }
+
+    pub fn dispute_timeout(mut self, timeout: Duration) -> Self {
+        self.config.dispute_timeout = Some(timeout);
+        self
+    }
+
+    pub fn settlement_delay(mut self, delay: Duration) -> Self {
+        self.config.settlement_delay = Some(delay);
+        self
+    }
+
+    pub fn withdrawal_chargeback(mut self, policy: WithdrawalChargeback) -> Self {
+        self.config.withdrawal_chargeback = policy;
+        self
+    }
+
+    pub fn transfer_dispute(mut self, policy: DisputePolicy) -> Self {
+        self.config.transfer_dispute = policy;
+        self
+    }
+
+    pub fn create_clients_on_success(mut self, enabled: bool) -> Self {
+        self.config.create_clients_on_success = enabled;
+        self
+    }
+
+    pub fn max_risk_score(mut self, score: f64) -> Self {
+        self.config.max_risk_score = Some(score);
+        self
+    }
+
+    pub fn quarantine_risk_score(mut self, score: f64) -> Self {
+        self.config.quarantine_risk_score = Some(score);
+        self
+    }
+
+    pub fn approval_threshold(mut self, amount: Decimal) -> Self {
+        self.config.approval_threshold = Some(amount);
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.config.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn reserves(mut self, reserves: Reserves) -> Self {
+        self.config.reserves = reserves;
+        self
+    }
+
+    pub fn credit_limits(mut self, limits: CreditLimits) -> Self {
+        self.config.credit_limits = limits;
+        self
+    }
+
+    pub fn access(mut self, access: Access) -> Self {
+        self.config.access = access;
+        self
+    }
+
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.config.currency = currency;
+        self
+    }
+
+    pub fn dedup_scope(mut self, scope: DedupScope) -> Self {
+        self.config.dedup_scope = scope;
+        self
+    }
+
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.config.error_policy = policy;
+        self
+    }
+
+    /// Call `observer` with the events of every applied transaction, see `Observers`
+    pub fn observer(mut self, observer: impl Fn(&ClientEvent) + Send + Sync + 'static) -> Self {
+        self.config.observers.push(observer);
+        self
+    }
+
+    pub fn signing_key(mut self, key: SigningKey) -> Self {
+        self.config.signing_key = Some(key);
+        self
+    }
+
+    pub fn skip_empty_accounts(mut self, enabled: bool) -> Self {
+        self.config.skip_empty_accounts = enabled;
+        self
+    }
+
+    pub fn risk_score_column(mut self, enabled: bool) -> Self {
+        self.config.risk_score_column = enabled;
+        self
+    }
+
+    pub fn dispute_columns(mut self, enabled: bool) -> Self {
+        self.config.dispute_columns = enabled;
+        self
+    }
+
+    pub fn sub_account_columns(mut self, enabled: bool) -> Self {
+        self.config.sub_account_columns = enabled;
+        self
+    }
+
+    pub fn reserve_column(mut self, enabled: bool) -> Self {
+        self.config.reserve_column = enabled;
+        self
+    }
+
+    pub fn pending_column(mut self, enabled: bool) -> Self {
+        self.config.pending_column = enabled;
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn build(self) -> Payments {
+        Payments::with_config(self.config)
+    }
+}
@@ -0,0 +1,216 @@
+//! Change data capture: a stream of balance changes as they happen, for downstream caches
+//! which apply deltas instead of reloading full snapshots.
+//!
+//! Every transaction changing a client's balances is reported with the deltas and the
+//! resulting balances. When a batch is rolled back, the clients changed by it so far get
+//! a compensating change back to their restored balances.
+use std::{collections::HashMap, io::Write};
+
+use itertools::Itertools;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    client::{Client, ClientId},
+    error::Error,
+    payments::{Marker, Payments},
+    transaction::{BatchId, Timestamp, TransactionId},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceChange {
+    pub client: ClientId,
+    /// The transaction causing the change, none for postings of the day's close
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TransactionId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    pub available_delta: Decimal,
+    pub held_delta: Decimal,
+    pub total_delta: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Available, held and total funds and the lock of a client, which may not exist
+pub(crate) fn balances(client: Option<&Client>) -> (Decimal, Decimal, Decimal, bool) {
+    client.map_or(Default::default(), |client| {
+        (
+            client.available(),
+            client.held(),
+            client.total(),
+            client.locked(),
+        )
+    })
+}
+
+impl BalanceChange {
+    /// The change from the balances of `last` to the current ones of `client`
+    fn compensation(last: &BalanceChange, client: Option<&Client>, tx: TransactionId) -> Self {
+        let (available, held, total, locked) = balances(client);
+        BalanceChange {
+            client: last.client,
+            tx: Some(tx),
+            timestamp: None,
+            available_delta: available - last.available,
+            held_delta: held - last.held,
+            total_delta: total - last.total,
+            available,
+            held,
+            total,
+            locked,
+        }
+    }
+
+    fn is_noop(&self, last: &BalanceChange) -> bool {
+        self.available_delta.is_zero()
+            && self.held_delta.is_zero()
+            && self.total_delta.is_zero()
+            && self.locked == last.locked
+    }
+}
+
+/// Writes balance changes as JSON lines
+pub struct ChangeStream<W: Write> {
+    output: W,
+    batch: Option<BatchId>,
+    /// The latest change of each client in the open batch, to compensate if it's rolled back
+    pending: HashMap<ClientId, BalanceChange>,
+}
+
+impl<W: Write> ChangeStream<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            batch: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Write the changes since `marker`, made by applying the transaction `tx` of `batch`
+    /// with `result`
+    pub fn record(
+        &mut self,
+        payments: &Payments,
+        marker: Marker,
+        tx: TransactionId,
+        batch: Option<BatchId>,
+        result: &Result<(), Error>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if batch.is_none() || batch != self.batch {
+            self.pending.clear();
+            self.batch = batch;
+        }
+        let changes = match result {
+            Err(Error::BatchRolledBack { .. }) => {
+                let restored = std::mem::take(&mut self.pending)
+                    .into_values()
+                    .sorted_by_key(|last| last.client)
+                    .filter_map(|last| {
+                        let client = payments.client(last.client);
+                        let change = BalanceChange::compensation(&last, client, tx);
+                        (!change.is_noop(&last)).then_some(change)
+                    });
+                restored.collect()
+            }
+            _ => payments.changes_since(marker),
+        };
+        for change in changes {
+            serde_json::to_writer(&mut self.output, &change)?;
+            writeln!(self.output)?;
+            if batch.is_some() && result.is_ok() {
+                self.pending.insert(change.client, change);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChangeStream;
+    use crate::{parser::parse, payments::Payments};
+
+    fn stream(input: &str) -> Vec<serde_json::Value> {
+        let mut payments = Payments::default();
+        let mut changes = ChangeStream::new(Vec::new());
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        for trans in parse(rdr) {
+            let trans = trans.unwrap();
+            let (tx, batch) = (trans.op.id, trans.batch);
+            let marker = payments.marker();
+            let result = payments.apply(trans);
+            changes
+                .record(&payments, marker, tx, batch, &result)
+                .unwrap();
+        }
+        String::from_utf8(changes.output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn deltas_and_balances() {
+        let changes = stream(
+            "type, client, tx, amount
+            deposit, 1, 1, 5
+            withdrawal, 1, 2, 9
+            dispute, 1, 1,
+            chargeback, 1, 1,",
+        );
+        assert_eq!(
+            changes,
+            [
+                serde_json::json!({"client": 1, "tx": 1, "available_delta": "5", "held_delta": "0",
+                    "total_delta": "5", "available": "5", "held": "0", "total": "5", "locked": false}),
+                serde_json::json!({"client": 1, "tx": 1, "available_delta": "-5", "held_delta": "5",
+                    "total_delta": "0", "available": "0", "held": "5", "total": "5", "locked": false}),
+                serde_json::json!({"client": 1, "tx": 1, "available_delta": "0", "held_delta": "-5",
+                    "total_delta": "-5", "available": "0", "held": "0", "total": "0", "locked": true}),
+            ]
+        );
+    }
+
+    #[test]
+    fn rolled_back_batch() {
+        let changes = stream(
+            "type, client, tx, amount, batch
+            deposit, 1, 1, 5,
+            deposit, 1, 2, 1, 7
+            deposit, 2, 3, 2, 7
+            withdrawal, 1, 4, 9, 7",
+        );
+        let summary = changes
+            .iter()
+            .map(|c| {
+                (
+                    c["client"].clone(),
+                    c["tx"].clone(),
+                    c["total_delta"].clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (1.into(), 1.into(), "5".into()),
+                (1.into(), 2.into(), "1".into()),
+                (2.into(), 3.into(), "2".into()),
+                // Compensations, caused by the failed transaction
+                (1.into(), 4.into(), "-1".into()),
+                (2.into(), 4.into(), "-2".into()),
+            ]
+        );
+        assert_eq!(changes[3]["total"], "5");
+    }
+}
@@ -0,0 +1,226 @@
+//! Fault injection for testing the guarantees of the pipeline, built with the `chaos` feature.
+//!
+//! `Chaos` wraps the parsed input and, driven by a seed so that failures reproduce, injects
+//! I/O errors, delivers records late or twice, and crashes (panics) mid-run. `CrashingWriter`
+//! crashes in the middle of a write, e.g. of a record of a transaction log, see `txlog`.
+use std::{collections::VecDeque, io::Write};
+
+use crate::{error::Error, transaction::Transaction};
+
+/// Which faults to inject, and how often
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Faults {
+    pub seed: u64,
+    /// Probability of a read failing before a record, which is delivered by the next read
+    pub io_error: f64,
+    /// Probability of a record being delivered late, after up to `max_delay` other records
+    pub delay: f64,
+    pub max_delay: usize,
+    /// Probability of a record being delivered twice
+    pub duplicate: f64,
+    /// Crash after delivering this many records
+    pub crash_after: Option<usize>,
+}
+
+/// The faults injected so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Injected {
+    pub io_errors: usize,
+    pub delays: usize,
+    pub duplicates: usize,
+}
+
+/// The input with faults, see the module documentation
+#[derive(Debug)]
+pub struct Chaos<I> {
+    input: I,
+    faults: Faults,
+    rng: fastrand::Rng,
+    /// Records read from the input
+    read: usize,
+    delivered: usize,
+    /// Delayed records with the number of reads after which they're delivered
+    delayed: Vec<(usize, Transaction)>,
+    ready: VecDeque<Result<Transaction, Error>>,
+    injected: Injected,
+}
+
+impl<I> Chaos<I> {
+    pub fn new(input: I, faults: Faults) -> Self {
+        Self {
+            input,
+            rng: fastrand::Rng::with_seed(faults.seed),
+            faults,
+            read: 0,
+            delivered: 0,
+            delayed: Vec::new(),
+            ready: VecDeque::new(),
+            injected: Injected::default(),
+        }
+    }
+
+    pub fn injected(&self) -> Injected {
+        self.injected
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.f64() < probability
+    }
+}
+
+impl<I: Iterator<Item = Result<Transaction, Error>>> Iterator for Chaos<I> {
+    type Item = Result<Transaction, Error>;
+
+    // Crashing is the fault injected
+    #[allow(clippy::panic)]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.ready.pop_front() {
+                if self.faults.crash_after == Some(self.delivered) {
+                    panic!("injected crash after {} records", self.delivered);
+                }
+                self.delivered += usize::from(item.is_ok());
+                return Some(item);
+            }
+            if let Some(due) = self.delayed.iter().position(|(at, _)| *at <= self.read) {
+                let (_, transaction) = self.delayed.remove(due);
+                self.ready.push_back(Ok(transaction));
+                continue;
+            }
+            let transaction = match self.input.next() {
+                Some(Ok(transaction)) => transaction,
+                Some(Err(error)) => return Some(Err(error)),
+                None if self.delayed.is_empty() => return None,
+                None => {
+                    let (_, transaction) = self.delayed.remove(0);
+                    self.ready.push_back(Ok(transaction));
+                    continue;
+                }
+            };
+            self.read += 1;
+            if self.chance(self.faults.io_error) {
+                self.injected.io_errors += 1;
+                self.ready
+                    .push_back(Err(Error::ParsingFailure("injected I/O error".to_string())));
+            }
+            if self.chance(self.faults.delay) && self.faults.max_delay > 0 {
+                self.injected.delays += 1;
+                let at = self.read + self.rng.usize(1..=self.faults.max_delay);
+                self.delayed.push((at, transaction));
+                continue;
+            }
+            if self.chance(self.faults.duplicate) {
+                self.injected.duplicates += 1;
+                self.ready.push_back(Ok(transaction.clone()));
+            }
+            self.ready.push_back(Ok(transaction));
+        }
+    }
+}
+
+/// A writer crashing (panicking) once it wrote `limit` bytes, tearing the write it's in the
+/// middle of
+#[derive(Debug)]
+pub struct CrashingWriter<W> {
+    output: W,
+    limit: usize,
+}
+
+impl<W> CrashingWriter<W> {
+    pub fn new(output: W, limit: usize) -> Self {
+        Self { output, limit }
+    }
+}
+
+impl<W: Write> Write for CrashingWriter<W> {
+    // Crashing is the fault injected
+    #[allow(clippy::panic)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.limit == 0 {
+            panic!("injected crash mid-write");
+        }
+        let written = self.output.write(&buf[..buf.len().min(self.limit)])?;
+        self.limit -= written;
+        self.output.flush()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use std::io::Write;
+
+    use super::{Chaos, CrashingWriter, Faults};
+    use crate::{parser::transaction, transaction::Transaction};
+
+    fn input(n: u32) -> Vec<Transaction> {
+        (1..=n)
+            .map(|tx| transaction("deposit", 1, tx, Some(dec!(1)), None).unwrap())
+            .collect()
+    }
+
+    fn ids(
+        chaos: Chaos<impl Iterator<Item = Result<Transaction, crate::error::Error>>>,
+    ) -> Vec<u32> {
+        chaos.filter_map(Result::ok).map(|t| t.op.id).collect()
+    }
+
+    #[test]
+    fn delivers_everything() {
+        let faults = Faults {
+            seed: 7,
+            delay: 0.2,
+            max_delay: 3,
+            duplicate: 0.2,
+            io_error: 0.2,
+            ..Faults::default()
+        };
+        let mut chaos = Chaos::new(input(100).into_iter().map(Ok), faults.clone());
+        let mut delivered = chaos
+            .by_ref()
+            .filter_map(Result::ok)
+            .map(|t| t.op.id)
+            .collect::<Vec<_>>();
+        let injected = chaos.injected();
+        assert!(injected.delays > 0 && injected.duplicates > 0 && injected.io_errors > 0);
+        assert_eq!(delivered.len(), 100 + injected.duplicates);
+        assert_ne!(delivered, (1..=100).collect::<Vec<_>>());
+        delivered.sort();
+        delivered.dedup();
+        assert_eq!(delivered, (1..=100).collect::<Vec<_>>());
+
+        // Reproducible
+        assert_eq!(
+            ids(Chaos::new(input(100).into_iter().map(Ok), faults.clone())),
+            ids(Chaos::new(input(100).into_iter().map(Ok), faults))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "injected crash after 3 records")]
+    fn crashes() {
+        let faults = Faults {
+            crash_after: Some(3),
+            ..Faults::default()
+        };
+        ids(Chaos::new(input(5).into_iter().map(Ok), faults));
+    }
+
+    #[test]
+    fn crashes_mid_write() {
+        let mut output = Vec::new();
+        let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut writer = CrashingWriter::new(&mut output, 5);
+            writer.write_all(b"abc").unwrap();
+            writer.write_all(b"defg").unwrap();
+        }));
+        assert!(crashed.is_err());
+        assert_eq!(output, b"abcde");
+    }
+}
@@ -0,0 +1,129 @@
+//! Verification of input files against sidecar checksum files, catching truncated or
+//! corrupted transfers before they're processed into wrong balances.
+//!
+//! The checksum of `transactions.csv` is read from `transactions.csv.sha256`, in the format
+//! of `sha256sum`: the hex encoded SHA-256 hash, optionally followed by the file name.
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, signature::to_hex};
+
+/// How to treat the checksum of an input file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumMode {
+    /// Not verified
+    #[default]
+    Off,
+    /// Verified if there's a sidecar file, a mismatch is only a warning
+    Warn,
+    /// The sidecar file is required and must match
+    Require,
+}
+
+impl FromStr for ChecksumMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ChecksumMode::Off),
+            "warn" => Ok(ChecksumMode::Warn),
+            "require" => Ok(ChecksumMode::Require),
+            other => Err(format!(
+                "unknown checksum mode `{}`, expected `off`, `warn` or `require`",
+                other
+            )),
+        }
+    }
+}
+
+/// The SHA-256 hash of the file at `path`, hex encoded
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn sidecar_path(input: &Path) -> PathBuf {
+    let mut path = input.as_os_str().to_owned();
+    path.push(".sha256");
+    path.into()
+}
+
+/// Verify `input` against its sidecar checksum file.
+/// A missing sidecar file is only an error when it's required.
+pub fn verify(input: &Path, mode: ChecksumMode) -> Result<(), Box<dyn std::error::Error>> {
+    if mode == ChecksumMode::Off {
+        return Ok(());
+    }
+    let name = input.display().to_string();
+    let expected = match std::fs::read_to_string(sidecar_path(input)) {
+        Ok(sidecar) => sidecar
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return match mode {
+                ChecksumMode::Require => Err(Error::ChecksumMissing(name).into()),
+                _ => Ok(()),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let actual = sha256_file(input)?;
+    if actual != expected {
+        return Err(Error::ChecksumMismatch {
+            name,
+            expected,
+            actual,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sidecar_path, verify, ChecksumMode};
+    use crate::error::Error;
+
+    #[test]
+    fn sidecar_files() {
+        let dir = std::env::temp_dir().join(format!("payments-checksum-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("transactions.csv");
+        std::fs::write(&input, "abc").unwrap();
+        let error = |mode| {
+            verify(&input, mode)
+                .err()
+                .map(|e| e.downcast::<Error>().unwrap())
+        };
+
+        assert!(error(ChecksumMode::Warn).is_none());
+        assert!(matches!(
+            error(ChecksumMode::Require).as_deref(),
+            Some(Error::ChecksumMissing(_))
+        ));
+
+        // As written by `sha256sum`
+        std::fs::write(
+            sidecar_path(&input),
+            "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD  transactions.csv\n",
+        )
+        .unwrap();
+        assert!(error(ChecksumMode::Require).is_none());
+
+        std::fs::write(&input, "ab").unwrap();
+        assert!(matches!(
+            error(ChecksumMode::Warn).as_deref(),
+            Some(Error::ChecksumMismatch { .. })
+        ));
+        assert!(error(ChecksumMode::Off).is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,21 +1,34 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    mem::size_of,
+};
 
+use itertools::Itertools;
 use rust_decimal::Decimal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    arena::OperationArena,
+    counterparty::Counterparty,
+    dedup::DedupScope,
     error::Error,
-    transaction::{Operation, OperationType, TransactionId},
+    event::Event,
+    money::{Currency, Money},
+    redact::{self, Redacted},
+    subaccount::SubAccount,
+    transaction::{valid_amount, Operation, OperationType, TransactionId},
 };
 
-/// Represents possible states of an operation,
+/// Represents possible states of an operation, a transfer or a fee,
 /// along with all allowed transitions.
 /// Allowed state transitions:
 /// New -> InDispute
 /// InDispute -> Resolved | Chargedback
 /// Assumption: it is not possible to dispute a given transaction twice,
 /// hence there is no `Resolved -> InDispute` state transition.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum OperationState {
     New,
     InDispute,
@@ -23,52 +36,279 @@ pub enum OperationState {
     Chargedback,
 }
 
+impl OperationState {
+    /// The state `to` of transaction `id` in this state, if it can be moved there
+    fn transition(self, id: TransactionId, to: OperationState) -> Result<Self, Error> {
+        match (self, to) {
+            (OperationState::New, OperationState::InDispute) => Ok(to),
+            (OperationState::InDispute, OperationState::Resolved) => Ok(to),
+            (OperationState::InDispute, OperationState::Chargedback) => Ok(to),
+            (from, to) if from == to => Ok(from),
+            (from, to) => Err(Error::InvalidTransactionStateChange { id, from, to }),
+        }
+    }
+}
+
+impl fmt::Display for OperationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OperationState::New => "new",
+            OperationState::InDispute => "in_dispute",
+            OperationState::Resolved => "resolved",
+            OperationState::Chargedback => "chargedback",
+        })
+    }
+}
+
+/// A deposit or withdrawal of a client and how its dispute stands, see `Client::operations`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct OperationStatus {
+    pub tx: TransactionId,
+    /// Negative for a withdrawal
+    pub amount: Decimal,
+    pub state: OperationState,
+    /// Funds held while it's disputed
+    pub held: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<Counterparty>,
+}
+
+/// Who bears a charged back withdrawal: the funds left already, so there's nothing to hold
+/// while it's disputed, and a chargeback either credits them back to the client or books
+/// them as a loss of the house.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WithdrawalChargeback {
+    #[default]
+    CreditClient,
+    WriteOff,
+}
+
+impl std::str::FromStr for WithdrawalChargeback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "credit" => Ok(WithdrawalChargeback::CreditClient),
+            "write-off" => Ok(WithdrawalChargeback::WriteOff),
+            other => Err(format!(
+                "unknown withdrawal chargeback policy `{}`, expected `credit` or `write-off`",
+                other
+            )),
+        }
+    }
+}
+
+/// What a dispute of a transfer does when the account the funds went to doesn't have them
+/// anymore, e.g. moved on to another account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputePolicy {
+    /// Only hold funds the account has: the dispute fails
+    #[default]
+    Hold,
+    /// Hold them anyway, taking the account below zero
+    NegativeBalance,
+    /// Hold what the account has, a chargeback books the rest as a loss of the house
+    WriteOff,
+}
+
+impl std::str::FromStr for DisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hold" => Ok(DisputePolicy::Hold),
+            "negative" => Ok(DisputePolicy::NegativeBalance),
+            "write-off" => Ok(DisputePolicy::WriteOff),
+            other => Err(format!(
+                "unknown transfer dispute policy `{}`, expected `hold`, `negative` or `write-off`",
+                other
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 struct StatefulOperation {
     id: TransactionId,
     amount: Decimal,
     state: OperationState,
+    /// The transaction this one originates from
+    ref_tx: Option<TransactionId>,
+    counterparty: Option<Counterparty>,
+    /// The funds of a deposit which aren't available yet, see `Event::FundsPending`
+    pending: bool,
 }
 
 impl StatefulOperation {
-    fn new(id: TransactionId, amount: Decimal) -> Self {
+    fn new(
+        id: TransactionId,
+        amount: Decimal,
+        ref_tx: Option<TransactionId>,
+        counterparty: Option<Counterparty>,
+    ) -> Self {
         StatefulOperation {
             id,
             amount,
             state: OperationState::New,
+            ref_tx,
+            counterparty,
+            pending: false,
         }
     }
 
-    fn state_transition(&mut self, new_state: OperationState) -> Result<(), Error> {
-        self.state = match (self.state, new_state) {
-            (OperationState::New, OperationState::InDispute) => Ok(new_state),
-            (OperationState::InDispute, OperationState::Resolved) => Ok(new_state),
-            (OperationState::InDispute, OperationState::Chargedback) => Ok(new_state),
-            (from, to) if from == to => Ok(from),
-            (from, to) => Err(Error::InvalidTransactionStateChange {
-                id: self.id,
-                from,
-                to,
-            }),
-        }?;
-        Ok(())
+    /// Withdrawals are kept with a negative amount
+    fn is_withdrawal(&self) -> bool {
+        self.amount.is_sign_negative()
+    }
+
+    /// Funds held while the operation is disputed, none for a withdrawal
+    fn held_amount(&self) -> Decimal {
+        self.amount.max(Decimal::ZERO)
+    }
+}
+
+/// A transfer between the accounts of a client, kept for its disputes
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transfer {
+    amount: Decimal,
+    from: Option<SubAccount>,
+    to: Option<SubAccount>,
+    state: OperationState,
+    /// Funds held by its dispute, see `DisputePolicy`
+    held: Decimal,
+}
+
+/// A fee posted with an ID, kept for its disputes
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fee {
+    amount: Decimal,
+    state: OperationState,
+}
+
+/// A transaction of a client which can be disputed
+#[derive(Debug, Clone, Copy)]
+enum Disputable {
+    Operation(StatefulOperation),
+    Transfer(Transfer),
+    Fee(Fee),
+}
+
+impl Disputable {
+    fn state(&self) -> OperationState {
+        match self {
+            Disputable::Operation(op) => op.state,
+            Disputable::Transfer(transfer) => transfer.state,
+            Disputable::Fee(fee) => fee.state,
+        }
+    }
+
+    /// Funds held while it's disputed: those of a deposit or what a transfer's dispute held,
+    /// none of a withdrawal or a fee, which left already
+    fn held_amount(&self) -> Decimal {
+        match self {
+            Disputable::Operation(op) => op.held_amount(),
+            Disputable::Transfer(transfer) => transfer.held,
+            Disputable::Fee(_) => Decimal::ZERO,
+        }
     }
 }
 
 pub type ClientId = u16;
 
-#[derive(Debug, Default, Serialize, PartialEq)]
+/// How far a client's withdrawals may go, see `reserve` and `credit`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Available funds a withdrawal can't take the client below, on top of its credit line
+    pub reserve: Decimal,
+    /// How far below zero a withdrawal can take the available funds
+    pub credit_limit: Decimal,
+}
+
+#[derive(Default, Clone, Serialize, PartialEq)]
 pub struct Client {
     #[serde(rename = "client")]
     pub id: ClientId,
     #[serde(skip_serializing)]
-    // Assumption: it is not required to keep track of the order of transactions,
-    // hence using a hashmap here
-    operations: HashMap<TransactionId, StatefulOperation>,
+    operations: OperationArena<StatefulOperation>,
+    /// Deposits or withdrawals reusing the ID of an operation of the other type, see
+    /// `DedupScope::ClientOperation`
+    #[serde(skip_serializing)]
+    reused_legs: HashMap<TransactionId, StatefulOperation>,
+    /// Available funds of the sub-accounts, see `subaccount`
+    #[serde(skip_serializing)]
+    sub_accounts: BTreeMap<SubAccount, Decimal>,
+    /// The transfers between the accounts, kept for disputes and to reject their duplicates
+    #[serde(skip_serializing)]
+    transfers: HashMap<TransactionId, Transfer>,
+    /// Fees posted with an ID, see `Event::FeeCharged`
+    #[serde(skip_serializing)]
+    fees: HashMap<TransactionId, Fee>,
     available: Decimal,
     held: Decimal,
+    /// Deposited funds not available until their value date, see `Event::FundsPending`
+    #[serde(skip_serializing)]
+    pending: Decimal,
     total: Decimal,
     locked: bool,
+    /// See `lifecycle`
+    #[serde(skip_serializing)]
+    closed: bool,
+    /// See `quarantine`
+    #[serde(skip_serializing)]
+    quarantined: bool,
+    /// Of all the balances of the account, see `money`
+    #[serde(skip_serializing)]
+    currency: Currency,
+}
+
+/// The balances of a client in its currency, see `Client::balance`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    pub available: Money,
+    pub held: Money,
+    pub pending: Money,
+    pub total: Money,
+}
+
+/// Leaves out the operations and sub-accounts while redacting, see `redact`
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut client = f.debug_struct("Client");
+        client
+            .field("id", &self.id)
+            .field("available", &Redacted(self.available))
+            .field("held", &Redacted(self.held))
+            .field("pending", &Redacted(self.pending))
+            .field("total", &Redacted(self.total))
+            .field("locked", &self.locked)
+            .field("closed", &self.closed)
+            .field("quarantined", &self.quarantined)
+            .field("currency", &self.currency);
+        if redact::enabled() {
+            return client.finish_non_exhaustive();
+        }
+        client
+            .field("operations", &self.operations)
+            .field("reused_legs", &self.reused_legs)
+            .field("sub_accounts", &self.sub_accounts)
+            .field("transfers", &self.transfers)
+            .field("fees", &self.fees)
+            .finish()
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {}: available {}, held {}, total {}, locked {}",
+            self.id,
+            Redacted(self.available),
+            Redacted(self.held),
+            Redacted(self.total),
+            self.locked
+        )
+    }
 }
 
 impl Client {
@@ -79,52 +319,419 @@ impl Client {
         }
     }
 
-    fn try_deposit(&mut self, id: TransactionId, amount: Decimal) -> Result<(), Error> {
-        if self.operations.contains_key(&id) {
+    /// A client with an account in `currency`
+    pub fn with_currency(id: ClientId, currency: Currency) -> Self {
+        Self {
+            id,
+            currency,
+            ..Self::default()
+        }
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// The balances in the currency of the account
+    pub fn balance(&self) -> Balance {
+        let money = |amount| Money::new(amount, self.currency);
+        Balance {
+            available: money(self.available),
+            held: money(self.held),
+            pending: money(self.pending),
+            total: money(self.total),
+        }
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn pending(&self) -> Decimal {
+        self.pending
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn quarantined(&self) -> bool {
+        self.quarantined
+    }
+
+    /// Available funds of the account, `None` being the main one
+    pub fn account_available(&self, account: Option<SubAccount>) -> Decimal {
+        match account {
+            Some(account) => self.sub_accounts.get(&account).copied().unwrap_or_default(),
+            None => self.available - self.sub_accounts.values().sum::<Decimal>(),
+        }
+    }
+
+    /// Credit drawn by withdrawals beyond the available funds, see `credit`
+    pub fn credit_drawn(&self) -> Decimal {
+        (-self.account_available(None)).max(Decimal::ZERO)
+    }
+
+    /// The sub-accounts funds were ever transferred to, with their available funds
+    pub fn sub_accounts(&self) -> impl Iterator<Item = (SubAccount, Decimal)> + '_ {
+        self.sub_accounts
+            .iter()
+            .map(|(&account, &available)| (account, available))
+    }
+
+    /// Whether the client has no funds, no lock and no recorded operations,
+    /// e.g. when created by a dispute of an unknown transaction
+    pub fn is_empty(&self) -> bool {
+        self.total.is_zero() && self.held.is_zero() && !self.locked && self.operation_count() == 0
+    }
+
+    /// Number of deposits, withdrawals, transfers and fees kept for disputes and deduplication
+    pub fn operation_count(&self) -> usize {
+        self.operations.len() + self.reused_legs.len() + self.transfers.len() + self.fees.len()
+    }
+
+    /// Approximate heap memory used by the client, in bytes
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.operations.memory_usage()
+            + self.reused_legs.capacity() * (size_of::<(TransactionId, StatefulOperation)>() + 1)
+            + self.sub_accounts.len() * size_of::<(SubAccount, Decimal)>()
+            + self.transfers.capacity() * (size_of::<(TransactionId, Transfer)>() + 1)
+            + self.fees.capacity() * (size_of::<(TransactionId, Fee)>() + 1)
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.operations.shrink_to_fit();
+        self.reused_legs.shrink_to_fit();
+        self.transfers.shrink_to_fit();
+        self.fees.shrink_to_fit();
+    }
+
+    fn all_operations(&self) -> impl Iterator<Item = &StatefulOperation> {
+        self.operations.values().chain(self.reused_legs.values())
+    }
+
+    /// The operation disputes of `id` are of: the deposit, if its ID is shared by
+    /// a deposit and a withdrawal
+    fn operation(&self, id: TransactionId) -> Option<&StatefulOperation> {
+        match (self.operations.get(&id), self.reused_legs.get(&id)) {
+            (Some(op), Some(leg)) if op.is_withdrawal() => Some(leg),
+            (op, _) => op,
+        }
+    }
+
+    fn operation_mut(&mut self, id: TransactionId) -> Option<&mut StatefulOperation> {
+        let reused = self
+            .operations
+            .get(&id)
+            .is_some_and(|op| op.is_withdrawal())
+            && self.reused_legs.contains_key(&id);
+        match reused {
+            true => self.reused_legs.get_mut(&id),
+            false => self.operations.get_mut(&id),
+        }
+    }
+
+    /// The transaction `id` disputes are of, see `operation`
+    fn disputable(&self, id: TransactionId) -> Option<Disputable> {
+        self.operation(id)
+            .copied()
+            .map(Disputable::Operation)
+            .or_else(|| self.transfers.get(&id).copied().map(Disputable::Transfer))
+            .or_else(|| self.fees.get(&id).copied().map(Disputable::Fee))
+    }
+
+    /// Whether any transaction `id` was recorded
+    pub(crate) fn has_transaction(&self, id: TransactionId) -> bool {
+        self.operations.contains_key(&id)
+            || self.reused_legs.contains_key(&id)
+            || self.transfers.contains_key(&id)
+            || self.fees.contains_key(&id)
+    }
+
+    /// Whether a deposit (or withdrawal) `id` was recorded already, in `scope`. Transfers
+    /// and fees share the IDs with both.
+    fn is_duplicate(&self, id: TransactionId, withdrawal: bool, scope: &DedupScope) -> bool {
+        if self.transfers.contains_key(&id) || self.fees.contains_key(&id) {
+            return true;
+        }
+        match scope.allows_reused_legs() {
+            true => [self.operations.get(&id), self.reused_legs.get(&id)]
+                .into_iter()
+                .flatten()
+                .any(|op| op.is_withdrawal() == withdrawal),
+            false => self.operations.contains_key(&id),
+        }
+    }
+
+    /// The deposits and withdrawals kept for disputes, by transaction ID
+    pub fn operations(&self) -> impl Iterator<Item = OperationStatus> + '_ {
+        self.all_operations()
+            .map(|op| OperationStatus {
+                tx: op.id,
+                amount: op.amount,
+                state: op.state,
+                held: match op.state {
+                    OperationState::InDispute => op.held_amount(),
+                    _ => Decimal::ZERO,
+                },
+                counterparty: op.counterparty,
+            })
+            .sorted_by_key(|op| op.tx)
+    }
+
+    /// The operations, transfers and fees in dispute with their amounts (negative for a
+    /// withdrawal or a fee), by transaction ID
+    pub fn open_disputes(&self) -> impl Iterator<Item = (TransactionId, Decimal)> + '_ {
+        let operations = self
+            .operations()
+            .filter(|op| op.state == OperationState::InDispute)
+            .map(|op| (op.tx, op.amount));
+        let transfers = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.state == OperationState::InDispute)
+            .map(|(&tx, transfer)| (tx, transfer.amount));
+        let fees = self
+            .fees
+            .iter()
+            .filter(|(_, fee)| fee.state == OperationState::InDispute)
+            .map(|(&tx, fee)| (tx, -fee.amount));
+        operations
+            .chain(transfers)
+            .chain(fees)
+            .sorted_by_key(|&(tx, _)| tx)
+    }
+
+    /// Transactions this client received which reference the transaction `id`
+    pub fn linked(&self, id: TransactionId) -> impl Iterator<Item = TransactionId> + '_ {
+        self.all_operations()
+            .filter(move |op| op.ref_tx == Some(id))
+            .map(|op| op.id)
+    }
+
+    /// Referential integrity: a new operation may only reference an existing,
+    /// different transaction of the same client
+    fn check_reference(
+        &self,
+        id: TransactionId,
+        ref_tx: Option<TransactionId>,
+    ) -> Result<(), Error> {
+        match ref_tx {
+            Some(ref_tx) if ref_tx == id || !self.operations.contains_key(&ref_tx) => {
+                Err(Error::InvalidReference { id, ref_tx })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn try_deposit(
+        &self,
+        id: TransactionId,
+        amount: Decimal,
+        (ref_tx, counterparty): (Option<TransactionId>, Option<Counterparty>),
+        scope: &DedupScope,
+    ) -> Result<Vec<Event>, Error> {
+        if self.is_duplicate(id, false, scope) {
             return Err(Error::DuplicatedTransaction(id));
         }
-        self.operations
-            .insert(id, StatefulOperation::new(id, amount));
-        self.total += amount;
-        self.available += amount;
-        Ok(())
+        self.check_reference(id, ref_tx)?;
+        let amount = valid_amount(id, amount)?;
+        Ok(vec![Event::FundsDeposited {
+            tx: id,
+            amount,
+            ref_tx,
+            counterparty,
+        }])
     }
 
-    fn try_withdraw(&mut self, id: TransactionId, amount: Decimal) -> Result<(), Error> {
-        if self.operations.contains_key(&id) {
+    /// A withdrawal may draw on the client's credit line, but mustn't take the available funds
+    /// below its reserve
+    fn try_withdraw(
+        &self,
+        id: TransactionId,
+        amount: Decimal,
+        (ref_tx, counterparty): (Option<TransactionId>, Option<Counterparty>),
+        scope: &DedupScope,
+        limits: Limits,
+    ) -> Result<Vec<Event>, Error> {
+        if self.is_duplicate(id, true, scope) {
             return Err(Error::DuplicatedTransaction(id));
         }
-        if self.available < amount {
+        self.check_reference(id, ref_tx)?;
+        let amount = valid_amount(id, amount)?;
+        let available = self.account_available(None) + limits.credit_limit;
+        if available < amount {
             return Err(Error::InsufficientFunds {
                 id,
-                available: self.available,
+                available,
                 requested: amount,
             });
         }
-        self.operations
-            .insert(id, StatefulOperation::new(id, -amount));
-        self.total -= amount;
-        self.available -= amount;
-        Ok(())
+        if available - amount < limits.reserve {
+            return Err(Error::BelowReserve {
+                id,
+                available,
+                requested: amount,
+                reserve: limits.reserve,
+            });
+        }
+        Ok(vec![Event::FundsWithdrawn {
+            tx: id,
+            amount,
+            ref_tx,
+            counterparty,
+        }])
+    }
+
+    /// Move available funds between two accounts of the client
+    fn try_transfer(
+        &self,
+        id: TransactionId,
+        amount: Decimal,
+        (from, to): (Option<SubAccount>, Option<SubAccount>),
+    ) -> Result<Vec<Event>, Error> {
+        if self.has_transaction(id) {
+            return Err(Error::DuplicatedTransaction(id));
+        }
+        let amount = valid_amount(id, amount)?;
+        let available = self.account_available(from);
+        if available < amount {
+            return Err(Error::InsufficientFunds {
+                id,
+                available,
+                requested: amount,
+            });
+        }
+        Ok(vec![Event::FundsTransferred {
+            tx: id,
+            amount,
+            from,
+            to,
+        }])
+    }
+
+    /// An amendment corrects the amount of a deposit or withdrawal which was never disputed,
+    /// changing the available and total funds by the difference. Taking funds away, it's
+    /// checked like a withdrawal of the difference. Delivered again, it changes nothing.
+    fn try_amend(
+        &self,
+        id: TransactionId,
+        amount: Decimal,
+        limits: Limits,
+    ) -> Result<Vec<Event>, Error> {
+        let op = self.operation(id).ok_or(Error::TransactionNotFound(id))?;
+        if op.state != OperationState::New {
+            return Err(Error::AmendmentOfDisputed(id));
+        }
+        if op.pending {
+            return Err(Error::AmendmentOfPending(id));
+        }
+        let amount = valid_amount(id, amount)?;
+        if amount.is_zero() {
+            return Err(Error::InvalidAmount(id));
+        }
+        let current = op.amount.abs();
+        if amount == current {
+            return Ok(Vec::new());
+        }
+        let delta = match op.is_withdrawal() {
+            true => current - amount,
+            false => amount - current,
+        };
+        if delta.is_sign_negative() {
+            let (available, requested) =
+                (self.account_available(None) + limits.credit_limit, -delta);
+            if available < requested {
+                return Err(Error::InsufficientFunds {
+                    id,
+                    available,
+                    requested,
+                });
+            }
+            if available - requested < limits.reserve {
+                return Err(Error::BelowReserve {
+                    id,
+                    available,
+                    requested,
+                    reserve: limits.reserve,
+                });
+            }
+        }
+        Ok(vec![Event::TransactionAmended {
+            tx: id,
+            amount,
+            delta,
+        }])
+    }
+
+    /// Whether transaction `id` is in `state` already: a dispute, resolve or chargeback
+    /// finding it in the state it moves it to is a repeated delivery, e.g. a retry, which
+    /// mustn't hold or release the funds again
+    fn is_in_state(&self, id: TransactionId, state: OperationState) -> bool {
+        self.disputable(id).is_some_and(|tx| tx.state() == state)
+    }
+
+    /// Find a transaction to be disputed (or resolved/charged back) and check
+    /// that it can be moved to `new_state`.
+    fn disputed(&self, id: TransactionId, new_state: OperationState) -> Result<Disputable, Error> {
+        let disputed = self.disputable(id).ok_or(Error::TransactionNotFound(id))?;
+        disputed.state().transition(id, new_state)?;
+        Ok(disputed)
     }
 
     /// A dispute represents a client's claim that a transaction was erroneous and should be reversed.
     /// The transaction shouldn't be reversed yet but the associated funds should be held. This means
     /// that the clients available funds should decrease by the amount disputed, their held funds should
     /// increase by the amount disputed, while their total funds should remain the same.
-    fn try_dispute(&mut self, id: TransactionId) -> Result<(), Error> {
-        if let Entry::Occupied(mut op) = self.operations.entry(id) {
-            let op = op.get_mut();
-            if self.available < op.amount {
+    /// A deposit whose funds are pending holds them instead, they stay pending once released.
+    /// A disputed transfer holds its funds in the account they went to, see `DisputePolicy`.
+    fn try_dispute(&self, id: TransactionId, policy: DisputePolicy) -> Result<Vec<Event>, Error> {
+        let disputed = self.disputable(id).ok_or(Error::TransactionNotFound(id))?;
+        if self.is_in_state(id, OperationState::InDispute) {
+            return Ok(Vec::new());
+        }
+        let pending = matches!(disputed, Disputable::Operation(op) if op.pending);
+        let amount = match disputed {
+            Disputable::Transfer(transfer) => self.transfer_hold(id, &transfer, policy)?,
+            _ if !pending && self.account_available(None) < disputed.held_amount() => {
                 return Err(Error::FailedDisputeNotEnoughFunds(id));
             }
+            _ => disputed.held_amount(),
+        };
+        self.disputed(id, OperationState::InDispute)?;
+        Ok(vec![Event::FundsHeld {
+            tx: id,
+            amount,
+            pending,
+        }])
+    }
 
-            op.state_transition(OperationState::InDispute)?;
-            self.available -= op.amount;
-            self.held += op.amount;
-            Ok(())
-        } else {
-            Err(Error::TransactionNotFound(id))
+    /// Funds a dispute of `transfer` holds in the account they went to, under `policy`
+    fn transfer_hold(
+        &self,
+        id: TransactionId,
+        transfer: &Transfer,
+        policy: DisputePolicy,
+    ) -> Result<Decimal, Error> {
+        let available = self.account_available(transfer.to);
+        match policy {
+            DisputePolicy::Hold if available < transfer.amount => {
+                Err(Error::FailedDisputeNotEnoughFunds(id))
+            }
+            DisputePolicy::Hold | DisputePolicy::NegativeBalance => Ok(transfer.amount),
+            DisputePolicy::WriteOff => Ok(transfer.amount.min(available).max(Decimal::ZERO)),
         }
     }
 
@@ -132,47 +739,361 @@ impl Client {
     /// were previously disputed are no longer disputed. This means that the clients held funds should
     /// decrease by the amount no longer disputed, their available funds should increase by the
     /// amount no longer disputed, and their total funds should remain the same.
-    fn try_resolve(&mut self, id: TransactionId) -> Result<(), Error> {
-        if let Entry::Occupied(mut op) = self.operations.entry(id) {
-            let op = op.get_mut();
-            op.state_transition(OperationState::Resolved)?;
-            self.available += op.amount;
-            self.held -= op.amount;
-            Ok(())
-        } else {
-            Err(Error::TransactionNotFound(id))
+    fn try_resolve(&self, id: TransactionId) -> Result<Vec<Event>, Error> {
+        if self.is_in_state(id, OperationState::Resolved) {
+            return Ok(Vec::new());
         }
+        let disputed = self.disputed(id, OperationState::Resolved)?;
+        Ok(vec![Event::FundsReleased {
+            tx: id,
+            amount: disputed.held_amount(),
+            pending: matches!(disputed, Disputable::Operation(op) if op.pending),
+        }])
     }
 
     /// A chargeback is the final state of a dispute and represents the client reversing a transaction.
     /// Funds that were held have now been withdrawn. This means that the clients held funds and
     /// total funds should decrease by the amount previously disputed. If a chargeback occurs the
     /// client's account should be immediately frozen.
-    fn try_chargeback(&mut self, id: TransactionId) -> Result<(), Error> {
-        if let Entry::Occupied(mut op) = self.operations.entry(id) {
-            let op = op.get_mut();
-            op.state_transition(OperationState::Chargedback)?;
-            self.held -= op.amount;
-            self.total -= op.amount;
-            self.locked = true;
-            Ok(())
-        } else {
-            Err(Error::TransactionNotFound(id))
+    /// A charged back withdrawal is settled according to `policy`.
+    /// A charged back transfer is clawed back from the account it went to and credited back
+    /// to the one it came from, what its dispute didn't hold being written off, and a charged
+    /// back fee is refunded. Neither locks the account, the funds never left the client or
+    /// were taken by the house.
+    fn try_chargeback(
+        &self,
+        id: TransactionId,
+        policy: WithdrawalChargeback,
+    ) -> Result<Vec<Event>, Error> {
+        if self.is_in_state(id, OperationState::Chargedback) {
+            return Ok(Vec::new());
+        }
+        let disputed = self.disputed(id, OperationState::Chargedback)?;
+        let mut events = self.clear(id);
+        events.push(Event::FundsChargedBack {
+            tx: id,
+            amount: disputed.held_amount(),
+        });
+        match disputed {
+            Disputable::Operation(op) => {
+                if op.is_withdrawal() {
+                    let amount = -op.amount;
+                    events.push(match policy {
+                        WithdrawalChargeback::CreditClient => {
+                            Event::WithdrawalReversed { tx: id, amount }
+                        }
+                        WithdrawalChargeback::WriteOff => {
+                            Event::WithdrawalWrittenOff { tx: id, amount }
+                        }
+                    });
+                }
+                events.push(Event::AccountLocked { tx: id });
+            }
+            Disputable::Transfer(transfer) => {
+                events.push(Event::TransferReversed {
+                    tx: id,
+                    amount: transfer.amount,
+                });
+                let shortfall = transfer.amount - transfer.held;
+                if !shortfall.is_zero() {
+                    events.push(Event::TransferWrittenOff {
+                        tx: id,
+                        amount: shortfall,
+                    });
+                }
+            }
+            Disputable::Fee(fee) => events.push(Event::FeeRefunded {
+                tx: id,
+                amount: fee.amount,
+            }),
+        }
+        Ok(events)
+    }
+
+    /// Make the pending funds of the deposit `id` available. Settling one whose funds are
+    /// available already changes nothing.
+    fn try_settle(&self, id: TransactionId) -> Result<Vec<Event>, Error> {
+        self.operation(id).ok_or(Error::TransactionNotFound(id))?;
+        Ok(self.clear(id))
+    }
+
+    /// Clear the pending funds of the deposit `id`, see `Payments::advance_to`. While a dispute
+    /// holds them, it's only marked cleared, their release making them available.
+    pub(crate) fn clear(&self, id: TransactionId) -> Vec<Event> {
+        match self.operation(id) {
+            Some(op) if op.pending => vec![Event::FundsCleared {
+                tx: id,
+                amount: match op.state {
+                    OperationState::InDispute => Decimal::ZERO,
+                    _ => op.amount,
+                },
+            }],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Resolve the dispute of `id` even if the account is locked, see `Payments::force_resolve`
+    pub(crate) fn force_resolve(&self, id: TransactionId) -> Result<Vec<Event>, Error> {
+        self.try_resolve(id)
+    }
+
+    /// Move the transaction `id` to `state`, returning the transfer it is, if any
+    fn set_state(&mut self, id: TransactionId, state: OperationState) -> Option<Transfer> {
+        if let Some(op) = self.operation_mut(id) {
+            op.state = state;
+        } else if let Some(transfer) = self.transfers.get_mut(&id) {
+            transfer.state = state;
+            return Some(*transfer);
+        } else if let Some(fee) = self.fees.get_mut(&id) {
+            fee.state = state;
+        }
+        None
+    }
+
+    /// Add `amount` to the available funds of `account`, none being the main one
+    fn credit_account(&mut self, account: Option<SubAccount>, amount: Decimal) {
+        if let Some(account) = account {
+            *self.sub_accounts.entry(account).or_default() += amount;
+        }
+    }
+
+    /// Record a deposit or withdrawal, as a reused leg if its ID is taken by one of the other type
+    fn insert_operation(&mut self, op: StatefulOperation) {
+        match self.operations.get(&op.id) {
+            Some(taken) if taken.is_withdrawal() != op.is_withdrawal() => {
+                self.reused_legs.insert(op.id, op);
+            }
+            _ => self.operations.insert(op.id, op),
+        }
+    }
+
+    /// Fold a single event into the client's state.
+    /// Events are facts that already happened, so the only failure is `Error::Overflow` when a
+    /// balance after `event` doesn't fit, in which case the client is left unchanged.
+    pub fn evolve(&mut self, event: &Event) -> Result<(), Error> {
+        let (available, held, total) = self.balances_after([event])?;
+        match *event {
+            Event::FundsDeposited {
+                tx,
+                amount,
+                ref_tx,
+                counterparty,
+            } => {
+                self.insert_operation(StatefulOperation::new(tx, amount, ref_tx, counterparty));
+            }
+            Event::FundsWithdrawn {
+                tx,
+                amount,
+                ref_tx,
+                counterparty,
+            } => {
+                self.insert_operation(StatefulOperation::new(tx, -amount, ref_tx, counterparty));
+            }
+            Event::FundsHeld {
+                tx,
+                amount,
+                pending,
+            } => {
+                if pending {
+                    self.pending -= amount;
+                }
+                if let Some(transfer) = self.set_state(tx, OperationState::InDispute) {
+                    self.credit_account(transfer.to, -amount);
+                    if let Some(transfer) = self.transfers.get_mut(&tx) {
+                        transfer.held = amount;
+                    }
+                }
+            }
+            Event::FundsReleased {
+                tx,
+                amount,
+                pending,
+            } => {
+                if pending {
+                    self.pending += amount;
+                }
+                if let Some(transfer) = self.set_state(tx, OperationState::Resolved) {
+                    self.credit_account(transfer.to, amount);
+                }
+            }
+            Event::FundsChargedBack { tx, .. } => {
+                self.set_state(tx, OperationState::Chargedback);
+            }
+            Event::TransferReversed { tx, amount } => {
+                if let Some(transfer) = self.transfers.get(&tx) {
+                    self.credit_account(transfer.from, amount);
+                }
+            }
+            Event::FundsTransferred {
+                tx,
+                amount,
+                from,
+                to,
+            } => {
+                let transfer = Transfer {
+                    amount,
+                    from,
+                    to,
+                    state: OperationState::New,
+                    held: Decimal::ZERO,
+                };
+                self.transfers.insert(tx, transfer);
+                self.credit_account(from, -amount);
+                self.credit_account(to, amount);
+            }
+            Event::FeeCharged {
+                amount,
+                tx: Some(tx),
+            } => {
+                let fee = Fee {
+                    amount,
+                    state: OperationState::New,
+                };
+                self.fees.insert(tx, fee);
+            }
+            Event::TransactionAmended { tx, amount, .. } => {
+                if let Some(op) = self.operation_mut(tx) {
+                    op.amount = match op.is_withdrawal() {
+                        true => -amount,
+                        false => amount,
+                    };
+                }
+            }
+            Event::FundsPending { tx, amount, .. } => {
+                self.pending += amount;
+                if let Some(op) = self.operation_mut(tx) {
+                    op.pending = true;
+                }
+            }
+            Event::FundsCleared { tx, amount } => {
+                self.pending -= amount;
+                if let Some(op) = self.operation_mut(tx) {
+                    op.pending = false;
+                }
+            }
+            Event::AccountLocked { .. } => self.locked = true,
+            Event::AccountUnlocked => self.locked = false,
+            Event::AccountClosed => self.closed = true,
+            Event::ClientQuarantined { .. } => self.quarantined = true,
+            Event::QuarantineLifted => self.quarantined = false,
+            Event::TransactionParked { .. }
+            | Event::TransactionReleased { .. }
+            | Event::ClientCreated
+            | Event::ApprovalRequested { .. }
+            | Event::ActionApproved { .. }
+            | Event::WithdrawalReversed { .. }
+            | Event::WithdrawalWrittenOff { .. }
+            | Event::TransferWrittenOff { .. }
+            | Event::BalanceWrittenOff { .. }
+            | Event::FeeCharged { tx: None, .. }
+            | Event::FeeRefunded { .. }
+            | Event::InterestPaid { .. } => {}
         }
+        self.available = available;
+        self.held = held;
+        self.total = total;
+        Ok(())
     }
 
-    pub fn apply(&mut self, op: Operation) -> Result<(), Error> {
+    /// The available, held and total funds after `events`, `Error::Overflow` if they don't fit
+    pub(crate) fn balances_after<'a>(
+        &self,
+        events: impl IntoIterator<Item = &'a Event>,
+    ) -> Result<(Decimal, Decimal, Decimal), Error> {
+        events.into_iter().try_fold(
+            (self.available, self.held, self.total),
+            |(available, held, total), event| {
+                let (available_delta, held_delta, total_delta) = event.balance_deltas();
+                match (
+                    available.checked_add(available_delta),
+                    held.checked_add(held_delta),
+                    total.checked_add(total_delta),
+                ) {
+                    (Some(available), Some(held), Some(total)) => Ok((available, held, total)),
+                    _ => Err(Error::Overflow(format!("balance of client `{}`", self.id))),
+                }
+            },
+        )
+    }
+
+    /// Validate an operation against the current state and emit the resulting events,
+    /// without changing the state. IDs unique across clients are up to the caller to check.
+    /// Charged back withdrawals are settled according to `withdrawals` and transfers are
+    /// disputed according to `transfers`.
+    pub fn decide(
+        &self,
+        op: &Operation,
+        (withdrawals, transfers): (WithdrawalChargeback, DisputePolicy),
+        scope: &DedupScope,
+        limits: Limits,
+    ) -> Result<Vec<Event>, Error> {
+        // Funded by the acquirer, whatever the state of the account
+        if op.kind == OperationType::Settle {
+            return self.try_settle(op.id);
+        }
+        if self.closed {
+            return Err(Error::AccountClosed(op.id));
+        }
         if self.locked {
             return Err(Error::AccountLocked(op.id));
         }
         match op.kind {
-            OperationType::Deposit { amount } => self.try_deposit(op.id, amount),
-            OperationType::Withdrawal { amount } => self.try_withdraw(op.id, amount),
-            OperationType::Dispute => self.try_dispute(op.id),
+            OperationType::Deposit {
+                amount,
+                ref_tx,
+                counterparty,
+            } => self.try_deposit(op.id, amount, (ref_tx, counterparty), scope),
+            OperationType::Withdrawal {
+                amount,
+                ref_tx,
+                counterparty,
+            } => self.try_withdraw(op.id, amount, (ref_tx, counterparty), scope, limits),
+            OperationType::Dispute => self.try_dispute(op.id, transfers),
             OperationType::Resolve => self.try_resolve(op.id),
-            OperationType::Chargeback => self.try_chargeback(op.id),
+            OperationType::Chargeback => self.try_chargeback(op.id, withdrawals),
+            OperationType::Transfer { amount, from, to } => {
+                self.try_transfer(op.id, amount, (from, to))
+            }
+            OperationType::Amend { amount } => self.try_amend(op.id, amount, limits),
+            OperationType::Settle => self.try_settle(op.id),
+        }
+    }
+
+    /// A copy of the balances and lock, without the operations
+    pub(crate) fn balances(&self) -> Client {
+        Client {
+            id: self.id,
+            operations: OperationArena::default(),
+            reused_legs: HashMap::new(),
+            sub_accounts: self.sub_accounts.clone(),
+            transfers: HashMap::new(),
+            fees: HashMap::new(),
+            available: self.available,
+            held: self.held,
+            pending: self.pending,
+            total: self.total,
+            locked: self.locked,
+            closed: self.closed,
+            quarantined: self.quarantined,
+            currency: self.currency,
         }
     }
+
+    /// Validate an operation against the current state and emit the resulting events,
+    /// which are then folded into the state.
+    pub fn apply(&mut self, op: Operation) -> Result<Vec<Event>, Error> {
+        let events = self.decide(
+            &op,
+            (WithdrawalChargeback::default(), DisputePolicy::default()),
+            &DedupScope::default(),
+            Limits::default(),
+        )?;
+        self.balances_after(&events)?;
+        for event in &events {
+            self.evolve(event)?;
+        }
+        Ok(events)
+    }
 }
 
 #[cfg(test)]
@@ -180,9 +1101,8 @@ mod test {
 
     /// Test all possible Operation state changes
     mod operation_state_changes {
-        use crate::client::{OperationState, StatefulOperation};
+        use crate::client::OperationState;
         use crate::error::Error;
-        use rust_decimal_macros::dec;
 
         macro_rules! test_allowed_operation_state_changes {
             ($(OperationState::$from:ident => OperationState::$to:ident,)*) => {
@@ -191,13 +1111,8 @@ mod test {
                 #[test]
                 fn [<$from:lower _to_  $to:lower>]() {
                     assert_eq!(
-                        Ok(()),
-                        StatefulOperation {
-                            id: 0,
-                            amount: dec!(0),
-                            state: OperationState::$from,
-                        }
-                        .state_transition(OperationState::$to)
+                        Ok(OperationState::$to),
+                        OperationState::$from.transition(0, OperationState::$to)
                     );
                 }
             }
@@ -213,12 +1128,7 @@ mod test {
                 fn [<$from:lower _to_  $to:lower>]() {
                     assert_eq!(
                         Err(Error::InvalidTransactionStateChange { id: 0, from: OperationState::$from, to: OperationState::$to }),
-                        StatefulOperation {
-                            id: 0,
-                            amount: dec!(0),
-                            state: OperationState::$from,
-                        }
-                        .state_transition(OperationState::$to)
+                        OperationState::$from.transition(0, OperationState::$to)
                     );
                 }
             }
@@ -250,10 +1160,14 @@ mod test {
     }
     mod applying_transactions {
         use crate::{
-            client::Client,
+            client::{Client, DisputePolicy, Limits, OperationState, WithdrawalChargeback},
+            dedup::DedupScope,
             error::Error,
+            event::Event,
+            money::{Currency, Money},
             transaction::{Operation, OperationType},
         };
+        use rust_decimal::Decimal;
         use rust_decimal_macros::dec;
 
         macro_rules! check_balance {
@@ -268,31 +1182,101 @@ mod test {
         fn new_deposit() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1.25) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1.25 held:0 total:1.25);
             assert!(!client.locked);
         }
 
+        #[test]
+        fn overflowing_balances() {
+            let mut client = Client::new(0);
+            let deposit = |tx| Event::FundsDeposited {
+                tx,
+                amount: Decimal::MAX,
+                ref_tx: None,
+                counterparty: None,
+            };
+            client.evolve(&deposit(0)).unwrap();
+            assert!(matches!(
+                client.evolve(&deposit(1)),
+                Err(Error::Overflow(_))
+            ));
+            // Left as it was
+            assert_eq!(client.total, Decimal::MAX);
+            assert!(!client.has_transaction(1));
+        }
+
+        #[test]
+        fn balance_in_currency() {
+            let mut client = Client::with_currency(0, Currency::USD);
+            client
+                .apply(Operation {
+                    id: 0,
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None,
+                    },
+                })
+                .unwrap();
+            let balance = client.balance();
+            assert_eq!(balance.available, Money::new(dec!(1.25), Currency::USD));
+            assert_eq!(
+                balance.total.checked_sub(balance.available),
+                Ok(Money::zero(Currency::USD))
+            );
+            assert_eq!(
+                balance.total.amount_in(Currency::EUR),
+                Err(Error::CurrencyMismatch {
+                    expected: Currency::EUR,
+                    actual: Currency::USD
+                })
+            );
+            assert_eq!(Client::new(1).currency(), Currency::EUR);
+        }
+
         #[test]
         fn duplicated_deposit_id() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1.25) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             assert_eq!(
                 Err(Error::DuplicatedTransaction(0)),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1.25) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1.25 held:0 total:1.25);
@@ -303,17 +1287,30 @@ mod test {
         fn dispute() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1.25) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1.25 held:0 total:1.25);
             assert!(!client.locked);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsHeld {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    pending: false,
+                }]),
                 client.apply(Operation {
                     id: 0,
                     kind: OperationType::Dispute
@@ -327,19 +1324,37 @@ mod test {
         fn dispute_below_balance() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1 held:0 total:1);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsWithdrawn {
+                    tx: 1,
+                    amount: dec!(1),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 1,
-                    kind: OperationType::Withdrawal { amount: dec!(1) }
+                    kind: OperationType::Withdrawal {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:0 held:0 total:0);
@@ -359,17 +1374,30 @@ mod test {
         fn resolve() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1.25) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1.25 held:0 total:1.25);
             assert!(!client.locked);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsHeld {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    pending: false,
+                }]),
                 client.apply(Operation {
                     id: 0,
                     kind: OperationType::Dispute
@@ -379,7 +1407,11 @@ mod test {
             assert!(!client.locked);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsReleased {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    pending: false,
+                }]),
                 client.apply(Operation {
                     id: 0,
                     kind: OperationType::Resolve
@@ -389,20 +1421,119 @@ mod test {
             assert!(!client.locked);
         }
 
+        #[test]
+        fn repeated_dispute_operations() {
+            let mut client = Client::new(0);
+            let apply = |client: &mut Client, id, kind| client.apply(Operation { id, kind });
+            for id in [0, 1] {
+                let deposit = OperationType::Deposit {
+                    amount: dec!(2),
+                    ref_tx: None,
+                    counterparty: None,
+                };
+                apply(&mut client, id, deposit).unwrap();
+            }
+            apply(&mut client, 0, OperationType::Dispute).unwrap();
+            // Delivered again, the funds are held once
+            assert_eq!(Ok(vec![]), apply(&mut client, 0, OperationType::Dispute));
+            check_balance!(client has available:2 held:2 total:4);
+
+            apply(&mut client, 0, OperationType::Resolve).unwrap();
+            assert_eq!(Ok(vec![]), apply(&mut client, 0, OperationType::Resolve));
+            check_balance!(client has available:4 held:0 total:4);
+            // Unlike a repeated delivery, a dispute of a resolved transaction is refused
+            assert_eq!(
+                Err(Error::InvalidTransactionStateChange {
+                    id: 0,
+                    from: OperationState::Resolved,
+                    to: OperationState::InDispute
+                }),
+                apply(&mut client, 0, OperationType::Dispute)
+            );
+
+            apply(&mut client, 1, OperationType::Dispute).unwrap();
+            apply(&mut client, 1, OperationType::Chargeback).unwrap();
+            // The account is locked and refuses it again, the funds are charged back once
+            assert_eq!(
+                Err(Error::AccountLocked(1)),
+                apply(&mut client, 1, OperationType::Chargeback)
+            );
+            check_balance!(client has available:2 held:0 total:2);
+            assert!(client.locked);
+        }
+
+        #[test]
+        fn reused_ids_across_operation_types() {
+            let mut client = Client::new(0);
+            let mut apply = |id, kind| {
+                let events = client.decide(
+                    &Operation { id, kind },
+                    (WithdrawalChargeback::default(), DisputePolicy::default()),
+                    &DedupScope::ClientOperation,
+                    Limits::default(),
+                )?;
+                events
+                    .iter()
+                    .for_each(|event| client.evolve(event).unwrap());
+                Ok::<_, Error>(events)
+            };
+            let deposit = |amount| OperationType::Deposit {
+                amount,
+                ref_tx: None,
+                counterparty: None,
+            };
+            let withdrawal = OperationType::Withdrawal {
+                amount: dec!(1),
+                ref_tx: None,
+                counterparty: None,
+            };
+            apply(7, deposit(dec!(3))).unwrap();
+            apply(8, deposit(dec!(1))).unwrap();
+            apply(7, withdrawal).unwrap();
+            assert_eq!(
+                Err(Error::DuplicatedTransaction(7)),
+                apply(7, deposit(dec!(3)))
+            );
+            assert_eq!(Err(Error::DuplicatedTransaction(7)), apply(7, withdrawal));
+            // The deposit is disputed
+            assert_eq!(
+                Ok(vec![Event::FundsHeld {
+                    tx: 7,
+                    amount: dec!(3),
+                    pending: false,
+                }]),
+                apply(7, OperationType::Dispute)
+            );
+            check_balance!(client has available:0 held:3 total:3);
+        }
+
         #[test]
         fn chargeback() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1.25) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1.25 held:0 total:1.25);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsHeld {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    pending: false,
+                }]),
                 client.apply(Operation {
                     id: 0,
                     kind: OperationType::Dispute
@@ -411,7 +1542,13 @@ mod test {
             check_balance!(client has available:0 held:1.25 total:1.25);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![
+                    Event::FundsChargedBack {
+                        tx: 0,
+                        amount: dec!(1.25)
+                    },
+                    Event::AccountLocked { tx: 0 }
+                ]),
                 client.apply(Operation {
                     id: 0,
                     kind: OperationType::Chargeback
@@ -424,7 +1561,11 @@ mod test {
             assert_eq!(
                 client.apply(Operation {
                     id: 1,
-                    kind: OperationType::Deposit { amount: dec!(1) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 }),
                 Err(Error::AccountLocked(1))
             );
@@ -435,19 +1576,37 @@ mod test {
         fn withdraw() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1.25),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1.25) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1.25 held:0 total:1.25);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsWithdrawn {
+                    tx: 1,
+                    amount: dec!(.25),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 1,
-                    kind: OperationType::Withdrawal { amount: dec!(.25) }
+                    kind: OperationType::Withdrawal {
+                        amount: dec!(.25),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1 held:0 total:1);
@@ -465,7 +1624,11 @@ mod test {
                 }),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Withdrawal { amount: dec!(1) }
+                    kind: OperationType::Withdrawal {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:0 held:0 total:0);
@@ -476,10 +1639,19 @@ mod test {
         fn cannot_withdraw_below_balance_non_zero() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1 held:0 total:1);
@@ -492,7 +1664,11 @@ mod test {
                 }),
                 client.apply(Operation {
                     id: 1,
-                    kind: OperationType::Withdrawal { amount: dec!(2) }
+                    kind: OperationType::Withdrawal {
+                        amount: dec!(2),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1 held:0 total:1);
@@ -502,16 +1678,29 @@ mod test {
         fn cannot_withdraw_held() {
             let mut client = Client::new(0);
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(1),
+                    ref_tx: None,
+                    counterparty: None
+                }]),
                 client.apply(Operation {
                     id: 0,
-                    kind: OperationType::Deposit { amount: dec!(1) }
+                    kind: OperationType::Deposit {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:1 held:0 total:1);
 
             assert_eq!(
-                Ok(()),
+                Ok(vec![Event::FundsHeld {
+                    tx: 0,
+                    amount: dec!(1),
+                    pending: false,
+                }]),
                 client.apply(Operation {
                     id: 0,
                     kind: OperationType::Dispute
@@ -527,10 +1716,477 @@ mod test {
                 }),
                 client.apply(Operation {
                     id: 2,
-                    kind: OperationType::Withdrawal { amount: dec!(1) }
+                    kind: OperationType::Withdrawal {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None
+                    }
                 })
             );
             check_balance!(client has available:0 held:1 total:1);
         }
+
+        fn disputed_withdrawal() -> Client {
+            let mut client = Client::new(0);
+            for kind in [
+                OperationType::Deposit {
+                    amount: dec!(10),
+                    ref_tx: None,
+                    counterparty: None,
+                },
+                OperationType::Withdrawal {
+                    amount: dec!(4),
+                    ref_tx: None,
+                    counterparty: None,
+                },
+            ] {
+                let id = client.operations.len() as u32;
+                assert!(client.apply(Operation { id, kind }).is_ok());
+            }
+            // Nothing is held, the funds left already
+            assert_eq!(
+                Ok(vec![Event::FundsHeld {
+                    tx: 1,
+                    amount: dec!(0),
+                    pending: false,
+                }]),
+                client.apply(Operation {
+                    id: 1,
+                    kind: OperationType::Dispute
+                })
+            );
+            check_balance!(client has available:6 held:0 total:6);
+            assert_eq!(client.open_disputes().collect::<Vec<_>>(), [(1, dec!(-4))]);
+            client
+        }
+
+        #[test]
+        fn withdrawal_chargeback_credits_client() {
+            let mut client = disputed_withdrawal();
+            assert_eq!(
+                Ok(vec![
+                    Event::FundsChargedBack {
+                        tx: 1,
+                        amount: dec!(0)
+                    },
+                    Event::WithdrawalReversed {
+                        tx: 1,
+                        amount: dec!(4)
+                    },
+                    Event::AccountLocked { tx: 1 }
+                ]),
+                client.apply(Operation {
+                    id: 1,
+                    kind: OperationType::Chargeback
+                })
+            );
+            check_balance!(client has available:10 held:0 total:10);
+            assert!(client.locked);
+        }
+
+        #[test]
+        fn withdrawal_chargeback_written_off() {
+            let mut client = disputed_withdrawal();
+            let chargeback = Operation {
+                id: 1,
+                kind: OperationType::Chargeback,
+            };
+            let events = client
+                .decide(
+                    &chargeback,
+                    (WithdrawalChargeback::WriteOff, DisputePolicy::default()),
+                    &DedupScope::default(),
+                    Limits::default(),
+                )
+                .unwrap();
+            assert_eq!(
+                events[1],
+                Event::WithdrawalWrittenOff {
+                    tx: 1,
+                    amount: dec!(4)
+                }
+            );
+            events
+                .iter()
+                .for_each(|event| client.evolve(event).unwrap());
+            check_balance!(client has available:6 held:0 total:6);
+            assert!(client.locked);
+        }
+
+        #[test]
+        fn withdrawal_dispute_resolved() {
+            let mut client = disputed_withdrawal();
+            assert!(client
+                .apply(Operation {
+                    id: 1,
+                    kind: OperationType::Resolve
+                })
+                .is_ok());
+            check_balance!(client has available:6 held:0 total:6);
+            assert!(!client.locked);
+        }
+
+        #[test]
+        fn linked_transactions() {
+            let mut client = Client::new(0);
+            assert!(client
+                .apply(Operation {
+                    id: 0,
+                    kind: OperationType::Deposit {
+                        amount: dec!(10),
+                        ref_tx: None,
+                        counterparty: None
+                    }
+                })
+                .is_ok());
+            assert_eq!(
+                Ok(vec![Event::FundsWithdrawn {
+                    tx: 1,
+                    amount: dec!(2),
+                    ref_tx: Some(0),
+                    counterparty: None
+                }]),
+                client.apply(Operation {
+                    id: 1,
+                    kind: OperationType::Withdrawal {
+                        amount: dec!(2),
+                        ref_tx: Some(0),
+                        counterparty: None
+                    }
+                })
+            );
+            assert_eq!(
+                Err(Error::InvalidReference { id: 2, ref_tx: 7 }),
+                client.apply(Operation {
+                    id: 2,
+                    kind: OperationType::Deposit {
+                        amount: dec!(2),
+                        ref_tx: Some(7),
+                        counterparty: None
+                    }
+                })
+            );
+            assert_eq!(
+                Err(Error::InvalidReference { id: 3, ref_tx: 3 }),
+                client.apply(Operation {
+                    id: 3,
+                    kind: OperationType::Deposit {
+                        amount: dec!(2),
+                        ref_tx: Some(3),
+                        counterparty: None
+                    }
+                })
+            );
+            check_balance!(client has available:8 held:0 total:8);
+            assert_eq!(client.linked(0).collect::<Vec<_>>(), vec![1]);
+            assert_eq!(client.linked(1).count(), 0);
+        }
+
+        #[test]
+        fn withdrawal_keeps_reserve() {
+            let mut client = Client::new(0);
+            let withdraw = |client: &Client, id, amount| {
+                let op = Operation {
+                    id,
+                    kind: OperationType::Withdrawal {
+                        amount,
+                        ref_tx: None,
+                        counterparty: None,
+                    },
+                };
+                client.decide(
+                    &op,
+                    (WithdrawalChargeback::default(), DisputePolicy::default()),
+                    &DedupScope::default(),
+                    Limits {
+                        reserve: dec!(3),
+                        credit_limit: dec!(0),
+                    },
+                )
+            };
+            assert!(client
+                .apply(Operation {
+                    id: 0,
+                    kind: OperationType::Deposit {
+                        amount: dec!(10),
+                        ref_tx: None,
+                        counterparty: None
+                    }
+                })
+                .is_ok());
+            assert_eq!(
+                Err(Error::BelowReserve {
+                    id: 1,
+                    available: dec!(10),
+                    requested: dec!(8),
+                    reserve: dec!(3)
+                }),
+                withdraw(&client, 1, dec!(8))
+            );
+            // Insufficient funds are reported as such
+            assert!(matches!(
+                withdraw(&client, 1, dec!(11)),
+                Err(Error::InsufficientFunds { .. })
+            ));
+            assert!(withdraw(&client, 1, dec!(7)).is_ok());
+        }
+
+        #[test]
+        fn withdrawal_draws_on_credit_line() {
+            let mut client = Client::new(0);
+            let limits = Limits {
+                reserve: dec!(0),
+                credit_limit: dec!(5),
+            };
+            let mut withdraw = |id, amount| {
+                let op = Operation {
+                    id,
+                    kind: OperationType::Withdrawal {
+                        amount,
+                        ref_tx: None,
+                        counterparty: None,
+                    },
+                };
+                let events = client.decide(
+                    &op,
+                    (WithdrawalChargeback::default(), DisputePolicy::default()),
+                    &DedupScope::default(),
+                    limits,
+                )?;
+                events
+                    .iter()
+                    .for_each(|event| client.evolve(event).unwrap());
+                Ok::<_, Error>(client.credit_drawn())
+            };
+            assert_eq!(withdraw(0, dec!(3)), Ok(dec!(3)));
+            assert_eq!(withdraw(1, dec!(2)), Ok(dec!(5)));
+            assert_eq!(
+                withdraw(2, dec!(0.01)),
+                Err(Error::InsufficientFunds {
+                    id: 2,
+                    available: dec!(0),
+                    requested: dec!(0.01)
+                })
+            );
+            check_balance!(client has available:-5 held:0 total:-5);
+
+            // Deposits pay the credit back first
+            assert!(client
+                .apply(Operation {
+                    id: 3,
+                    kind: OperationType::Deposit {
+                        amount: dec!(7),
+                        ref_tx: None,
+                        counterparty: None
+                    }
+                })
+                .is_ok());
+            assert_eq!(client.credit_drawn(), dec!(0));
+            check_balance!(client has available:2 held:0 total:2);
+        }
+
+        #[test]
+        fn transfers_between_sub_accounts() {
+            let mut client = Client::new(0);
+            let savings = "savings".parse().ok();
+            let transfer = |id, amount, from, to| Operation {
+                id,
+                kind: OperationType::Transfer { amount, from, to },
+            };
+            assert!(client
+                .apply(Operation {
+                    id: 0,
+                    kind: OperationType::Deposit {
+                        amount: dec!(10),
+                        ref_tx: None,
+                        counterparty: None
+                    }
+                })
+                .is_ok());
+            assert_eq!(
+                Ok(vec![Event::FundsTransferred {
+                    tx: 1,
+                    amount: dec!(7),
+                    from: None,
+                    to: savings
+                }]),
+                client.apply(transfer(1, dec!(7), None, savings))
+            );
+            check_balance!(client has available:10 held:0 total:10);
+            assert_eq!(client.account_available(None), dec!(3));
+            assert_eq!(client.account_available(savings), dec!(7));
+
+            // Funds in a sub-account can't be withdrawn or held before moving them back
+            assert!(matches!(
+                client.apply(Operation {
+                    id: 2,
+                    kind: OperationType::Withdrawal {
+                        amount: dec!(4),
+                        ref_tx: None,
+                        counterparty: None
+                    }
+                }),
+                Err(Error::InsufficientFunds { id: 2, .. })
+            ));
+            assert_eq!(
+                Err(Error::FailedDisputeNotEnoughFunds(0)),
+                client.apply(Operation {
+                    id: 0,
+                    kind: OperationType::Dispute
+                })
+            );
+            assert!(matches!(
+                client.apply(transfer(3, dec!(8), savings, None)),
+                Err(Error::InsufficientFunds { id: 3, .. })
+            ));
+            assert_eq!(
+                Err(Error::DuplicatedTransaction(1)),
+                client.apply(transfer(1, dec!(7), savings, None))
+            );
+
+            assert!(client.apply(transfer(3, dec!(5), savings, None)).is_ok());
+            assert_eq!(
+                client.sub_accounts().collect::<Vec<_>>(),
+                vec![(savings.unwrap(), dec!(2))]
+            );
+            assert_eq!(client.account_available(None), dec!(8));
+            check_balance!(client has available:10 held:0 total:10);
+        }
+
+        #[test]
+        fn disputed_transfers() {
+            let mut client = Client::new(0);
+            let savings = "savings".parse().ok();
+            let op = |id, kind| Operation { id, kind };
+            let transfer = |amount, from, to| OperationType::Transfer { amount, from, to };
+            client
+                .apply(op(
+                    0,
+                    OperationType::Deposit {
+                        amount: dec!(10),
+                        ref_tx: None,
+                        counterparty: None,
+                    },
+                ))
+                .unwrap();
+            client
+                .apply(op(1, transfer(dec!(7), None, savings)))
+                .unwrap();
+            client
+                .apply(op(2, transfer(dec!(2), None, savings)))
+                .unwrap();
+
+            // Held in the account the funds went to
+            assert_eq!(
+                Ok(vec![Event::FundsHeld {
+                    tx: 1,
+                    amount: dec!(7),
+                    pending: false,
+                }]),
+                client.apply(op(1, OperationType::Dispute))
+            );
+            check_balance!(client has available:3 held:7 total:10);
+            assert_eq!(client.account_available(savings), dec!(2));
+            assert_eq!(client.account_available(None), dec!(1));
+            assert_eq!(client.open_disputes().collect::<Vec<_>>(), [(1, dec!(7))]);
+            client
+                .apply(op(3, transfer(dec!(2), savings, None)))
+                .unwrap();
+            assert_eq!(
+                Err(Error::FailedDisputeNotEnoughFunds(2)),
+                client.apply(op(2, OperationType::Dispute))
+            );
+
+            client.apply(op(1, OperationType::Resolve)).unwrap();
+            check_balance!(client has available:10 held:0 total:10);
+            assert_eq!(client.account_available(savings), dec!(7));
+
+            // Clawed back from the account the funds went to, without locking the account
+            client.apply(op(2, OperationType::Dispute)).unwrap();
+            assert_eq!(
+                Ok(vec![
+                    Event::FundsChargedBack {
+                        tx: 2,
+                        amount: dec!(2)
+                    },
+                    Event::TransferReversed {
+                        tx: 2,
+                        amount: dec!(2)
+                    }
+                ]),
+                client.apply(op(2, OperationType::Chargeback))
+            );
+            check_balance!(client has available:10 held:0 total:10);
+            assert_eq!(client.account_available(savings), dec!(5));
+            assert_eq!(client.account_available(None), dec!(5));
+            assert!(!client.locked);
+            assert_eq!(
+                Err(Error::InvalidTransactionStateChange {
+                    id: 2,
+                    from: OperationState::Chargedback,
+                    to: OperationState::InDispute
+                }),
+                client.apply(op(2, OperationType::Dispute))
+            );
+        }
+
+        #[test]
+        fn disputed_fees() {
+            let mut client = Client::new(0);
+            let op = |id, kind| Operation { id, kind };
+            client
+                .evolve(&Event::FundsDeposited {
+                    tx: 0,
+                    amount: dec!(10),
+                    ref_tx: None,
+                    counterparty: None,
+                })
+                .unwrap();
+            client
+                .evolve(&Event::FeeCharged {
+                    amount: dec!(1),
+                    tx: None,
+                })
+                .unwrap();
+            client
+                .evolve(&Event::FeeCharged {
+                    amount: dec!(2),
+                    tx: Some(1),
+                })
+                .unwrap();
+            check_balance!(client has available:7 held:0 total:7);
+            assert_eq!(
+                Err(Error::DuplicatedTransaction(1)),
+                client.apply(op(
+                    1,
+                    OperationType::Deposit {
+                        amount: dec!(1),
+                        ref_tx: None,
+                        counterparty: None,
+                    }
+                ))
+            );
+
+            // Nothing to hold, the fee was taken already
+            client.apply(op(1, OperationType::Dispute)).unwrap();
+            check_balance!(client has available:7 held:0 total:7);
+            assert_eq!(client.open_disputes().collect::<Vec<_>>(), [(1, dec!(-2))]);
+            assert_eq!(
+                Ok(vec![
+                    Event::FundsChargedBack {
+                        tx: 1,
+                        amount: dec!(0)
+                    },
+                    Event::FeeRefunded {
+                        tx: 1,
+                        amount: dec!(2)
+                    }
+                ]),
+                client.apply(op(1, OperationType::Chargeback))
+            );
+            check_balance!(client has available:9 held:0 total:9);
+            assert!(!client.locked);
+            assert_eq!(Ok(vec![]), client.apply(op(1, OperationType::Chargeback)));
+        }
     }
 }
@@ -0,0 +1,475 @@
+//! The end-of-day close: expiring stale holds, posting fees and interest, statements of the
+//! day's activity and a sealed snapshot to open the next day with.
+//!
+//! The snapshot is the full event log as JSON lines, next to a seal holding the number of
+//! events and a checksum of the file. Opening the next day verifies the seal, so a truncated
+//! or edited snapshot is refused instead of silently producing wrong balances.
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{NaiveDate, NaiveTime};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::ClientId,
+    error::Error,
+    event::Event,
+    fx::{Converted, Currency, FxRates},
+    payments::{Config, Marker, Payments},
+    snapshot,
+    transaction::{Timestamp, TransactionId},
+};
+
+/// Postings of the close
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postings {
+    /// Flat fee charged to every unlocked client
+    pub fee: Decimal,
+    /// ID of the fees, making them disputable, see `Payments::post_daily`
+    pub fee_tx: Option<TransactionId>,
+    /// Interest paid on the available funds of every unlocked client, e.g. `0.0001`
+    pub interest_rate: Decimal,
+}
+
+/// A client's activity over the day
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Statement {
+    pub client: ClientId,
+    pub opening_available: Decimal,
+    pub opening_held: Decimal,
+    pub opening_total: Decimal,
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub charged_back: Decimal,
+    pub fees: Decimal,
+    pub interest: Decimal,
+    /// Negative balance written off, see `Payments::write_off`
+    pub written_off: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub date: NaiveDate,
+    pub clients: usize,
+    pub transactions: usize,
+    pub rejected: usize,
+    /// Holds released by the close. Those expired earlier in the day were already
+    /// released by the transactions following their expiry.
+    pub expired_holds: usize,
+    pub fees: Decimal,
+    pub interest: Decimal,
+    pub total_funds: Decimal,
+    pub held_funds: Decimal,
+    pub written_off: Decimal,
+    /// Events in the sealed snapshot
+    pub events: usize,
+    pub checksum: String,
+    /// The totals in the reporting currency, see `Summary::convert`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub converted: Option<Converted>,
+}
+
+impl Summary {
+    /// Add the totals converted from the currency of the accounts to the reporting one,
+    /// at the rate of the day
+    pub fn convert(&mut self, fx: &FxRates, currencies: (Currency, Currency)) -> Result<(), Error> {
+        let totals = [
+            ("fees", self.fees),
+            ("interest", self.interest),
+            ("total_funds", self.total_funds),
+            ("held_funds", self.held_funds),
+            ("written_off", self.written_off),
+        ];
+        self.converted = Some(Converted::new(fx, totals, currencies, self.date)?);
+        Ok(())
+    }
+}
+
+/// The close of a day
+#[derive(Debug, Clone, PartialEq)]
+pub struct Close {
+    pub statements: Vec<Statement>,
+    pub summary: Summary,
+    /// The event log to open the next day with
+    pub snapshot: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Seal {
+    date: NaiveDate,
+    events: usize,
+    checksum: String,
+}
+
+/// The last instant of `date` (UTC)
+pub fn end_of_day(date: NaiveDate) -> Timestamp {
+    // A valid time, the fallback isn't taken
+    let last = NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999).unwrap_or(NaiveTime::MIN);
+    date.and_time(last).and_utc()
+}
+
+/// FNV-1a, 64 bits
+fn checksum(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Close the day `date` of `payments`, which was opened at `opening` and has applied
+/// `transactions`, of which `rejected` failed
+pub fn close_day(
+    payments: &mut Payments,
+    opening: Marker,
+    date: NaiveDate,
+    postings: Postings,
+    (transactions, rejected): (usize, usize),
+) -> Result<Close, Box<dyn std::error::Error>> {
+    let end = end_of_day(date);
+    let expired_holds = payments.advance_to(end);
+    payments.post_daily((postings.fee, postings.fee_tx), postings.interest_rate, end)?;
+
+    let mut statements = payments
+        .clients()
+        .map(|client| {
+            (
+                client.id,
+                Statement {
+                    client: client.id,
+                    opening_available: client.available(),
+                    opening_held: client.held(),
+                    opening_total: client.total(),
+                    available: client.available(),
+                    held: client.held(),
+                    total: client.total(),
+                    locked: client.locked(),
+                    ..Statement::default()
+                },
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    for event in payments.events_since(opening) {
+        let statement = statements
+            .get_mut(&event.client)
+            .ok_or(Error::ClientNotFound(event.client))?;
+        let (available, held, total) = event.event.balance_deltas();
+        statement.opening_available -= available;
+        statement.opening_held -= held;
+        statement.opening_total -= total;
+        match event.event {
+            Event::FundsDeposited { amount, .. } => statement.deposited += amount,
+            Event::FundsWithdrawn { amount, .. } => statement.withdrawn += amount,
+            Event::FundsChargedBack { amount, .. } => statement.charged_back += amount,
+            Event::FeeCharged { amount, .. } => statement.fees += amount,
+            Event::FeeRefunded { amount, .. } => statement.fees -= amount,
+            Event::InterestPaid { amount } => statement.interest += amount,
+            Event::BalanceWrittenOff { amount } => statement.written_off += amount,
+            _ => {}
+        }
+    }
+    let statements = statements
+        .into_values()
+        .map(|statement| Statement {
+            opening_available: statement.opening_available.normalize(),
+            opening_held: statement.opening_held.normalize(),
+            opening_total: statement.opening_total.normalize(),
+            ..statement
+        })
+        .collect::<Vec<_>>();
+
+    let mut snapshot = Vec::new();
+    payments.export_events(&mut snapshot)?;
+    let summary = Summary {
+        date,
+        clients: statements.len(),
+        transactions,
+        rejected,
+        expired_holds,
+        fees: statements.iter().map(|s| s.fees).sum(),
+        interest: statements.iter().map(|s| s.interest).sum(),
+        total_funds: statements.iter().map(|s| s.total).sum(),
+        held_funds: statements.iter().map(|s| s.held).sum(),
+        written_off: payments.written_off(),
+        events: payments.events().len(),
+        checksum: checksum(&snapshot),
+        converted: None,
+    };
+    Ok(Close {
+        statements,
+        summary,
+        snapshot,
+    })
+}
+
+impl Close {
+    /// Write the statements, the summary and the sealed snapshot to `dir`, returning
+    /// the path of the snapshot
+    pub fn write(&self, dir: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        fs::create_dir_all(dir)?;
+        let date = self.summary.date;
+        let mut writer = csv::Writer::from_path(dir.join(format!("statements-{}.csv", date)))?;
+        for statement in &self.statements {
+            writer.serialize(statement)?;
+        }
+        writer.flush()?;
+        fs::write(
+            dir.join(format!("summary-{}.json", date)),
+            serde_json::to_vec_pretty(&self.summary)?,
+        )?;
+
+        let snapshot = dir.join(format!("events-{}.jsonl", date));
+        fs::write(&snapshot, &self.snapshot)?;
+        let seal = Seal {
+            date,
+            events: self.summary.events,
+            checksum: self.summary.checksum.clone(),
+        };
+        fs::write(seal_path(&snapshot), serde_json::to_vec(&seal)?)?;
+        Ok(snapshot)
+    }
+}
+
+fn seal_path(snapshot: &Path) -> PathBuf {
+    let mut path = snapshot.as_os_str().to_owned();
+    path.push(".seal");
+    path.into()
+}
+
+/// Open a day with the state sealed by the previous close, see `Close::write`
+pub fn open_sealed(
+    snapshot: &Path,
+    config: Config,
+) -> Result<Payments, Box<dyn std::error::Error>> {
+    let broken = || Error::BrokenSeal(snapshot.display().to_string());
+    let seal: Seal =
+        serde_json::from_slice(&fs::read(seal_path(snapshot))?).map_err(|_| broken())?;
+    let data = fs::read(snapshot)?;
+    if checksum(&data) != seal.checksum {
+        return Err(broken().into());
+    }
+    let payments = Payments::import_events_with(config, data.as_slice())?;
+    if payments.events().len() != seal.events {
+        return Err(broken().into());
+    }
+    Ok(payments)
+}
+
+/// Whether `snapshot` is sealed, see `Close::write`
+pub fn is_sealed(snapshot: &Path) -> bool {
+    seal_path(snapshot).exists()
+}
+
+/// Rewrite a sealed snapshot in the current version once its seal is verified, sealing it
+/// anew, returning the version it was in. The seal is written after the snapshot, so an
+/// interrupted migration leaves the seal broken.
+pub fn migrate_sealed(path: &Path) -> Result<u32, Box<dyn std::error::Error>> {
+    open_sealed(path, Config::default())?;
+    let old = snapshot::read(fs::read(path)?.as_slice())?;
+    if old.version == snapshot::VERSION {
+        return Ok(old.version);
+    }
+    let mut data = Vec::new();
+    snapshot::write(None, &old.events, &mut data)?;
+    let mut seal: Seal = serde_json::from_slice(&fs::read(seal_path(path))?)?;
+    seal.checksum = checksum(&data);
+    fs::write(path, data)?;
+    fs::write(seal_path(path), serde_json::to_vec(&seal)?)?;
+    Ok(old.version)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use rust_decimal_macros::dec;
+
+    use super::{checksum, close_day, migrate_sealed, open_sealed, seal_path, Postings, Seal};
+    use crate::{
+        error::Error,
+        fx::{Currency, FxRates},
+        parser::parse,
+        payments::Payments,
+        transaction::Transaction,
+    };
+
+    const DAY: &str = "type, client, tx, amount, timestamp
+        deposit, 1, 1, 100, 2024-03-01T09:00:00Z
+        deposit, 2, 2, 10, 2024-03-01T09:30:00Z
+        withdrawal, 1, 3, 30, 2024-03-01T10:00:00Z
+        dispute, 2, 2, , 2024-03-01T11:00:00Z";
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 1).unwrap()
+    }
+
+    fn closed(payments: &mut Payments, postings: Postings) -> super::Close {
+        let opening = payments.marker();
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(DAY.as_bytes());
+        for trans in parse(rdr) {
+            payments.apply(trans.unwrap()).unwrap();
+        }
+        close_day(payments, opening, date(), postings, (4, 0)).unwrap()
+    }
+
+    #[test]
+    fn statements_and_summary() {
+        let mut payments = Payments::default();
+        let close = closed(
+            &mut payments,
+            Postings {
+                fee: dec!(1),
+                interest_rate: dec!(0.001),
+                ..Postings::default()
+            },
+        );
+        let first = &close.statements[0];
+        assert_eq!(
+            (first.opening_total, first.deposited, first.withdrawn),
+            (dec!(0), dec!(100), dec!(30))
+        );
+        assert_eq!(
+            (first.interest, first.fees, first.total),
+            (dec!(0.07), dec!(1), dec!(69.07))
+        );
+        // The held funds earn no interest
+        let second = &close.statements[1];
+        assert_eq!(
+            (second.interest, second.fees, second.held),
+            (dec!(0), dec!(0), dec!(10))
+        );
+        assert_eq!(close.summary.fees, dec!(1));
+        assert_eq!(close.summary.total_funds, dec!(79.07));
+        assert_eq!(close.summary.held_funds, dec!(10));
+        assert_eq!(close.summary.checksum, checksum(&close.snapshot));
+    }
+
+    #[test]
+    fn totals_in_reporting_currency() {
+        let mut payments = Payments::default();
+        let mut summary = closed(&mut payments, Postings::default()).summary;
+        let mut fx = FxRates::default();
+        fx.insert(date(), Currency::EUR, Currency::USD, dec!(1.25));
+        assert!(summary
+            .convert(&fx, (Currency::USD, Currency::GBP))
+            .is_err());
+
+        summary
+            .convert(&fx, (Currency::USD, Currency::EUR))
+            .unwrap();
+        let converted = summary.converted.unwrap();
+        assert_eq!(
+            (converted.currency.as_str(), converted.rate),
+            ("EUR", dec!(0.8))
+        );
+        assert_eq!(converted.totals["total_funds"], dec!(64));
+        assert_eq!(converted.totals["held_funds"], dec!(8));
+
+        let jpy = "JPY".parse().unwrap();
+        fx.insert(date(), Currency::USD, jpy, rust_decimal::Decimal::MAX);
+        let mut summary = closed(&mut Payments::default(), Postings::default()).summary;
+        assert!(matches!(
+            summary.convert(&fx, (Currency::USD, jpy)),
+            Err(Error::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn out_of_range_interest() {
+        let mut payments = Payments::default();
+        let opening = payments.marker();
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(DAY.as_bytes());
+        for trans in parse(rdr) {
+            payments.apply(trans.unwrap()).unwrap();
+        }
+        let events = payments.events().len();
+        let postings = Postings {
+            fee: dec!(1),
+            interest_rate: rust_decimal::Decimal::MAX,
+            ..Postings::default()
+        };
+        let error = close_day(&mut payments, opening, date(), postings, (4, 0)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&Error::Overflow("interest of client `1`".to_string()))
+        );
+        assert_eq!(payments.events().len(), events);
+    }
+
+    #[test]
+    fn disputed_fees() {
+        let mut payments = Payments::default();
+        let postings = Postings {
+            fee: dec!(1),
+            fee_tx: Some(100),
+            ..Postings::default()
+        };
+        closed(&mut payments, postings);
+        assert_eq!(payments.client(1).unwrap().available(), dec!(69));
+        payments.apply(Transaction::dispute(1, 100)).unwrap();
+        assert_eq!(payments.client(1).unwrap().open_disputes().count(), 1);
+        payments.apply(Transaction::chargeback(1, 100)).unwrap();
+        let client = payments.client(1).unwrap();
+        assert_eq!((client.available(), client.locked()), (dec!(70), false));
+
+        let opening = payments.marker();
+        let close = close_day(&mut payments, opening, date(), postings, (0, 0));
+        assert!(matches!(
+            close.unwrap_err().downcast_ref(),
+            Some(Error::DuplicatedTransaction(100))
+        ));
+    }
+
+    #[test]
+    fn sealed_snapshot_opens_next_day() {
+        let dir = std::env::temp_dir().join(format!("payments-close-{}", std::process::id()));
+        let mut payments = Payments::default();
+        let close = closed(&mut payments, Postings::default());
+        let snapshot = close.write(&dir).unwrap();
+
+        let opened = open_sealed(&snapshot, Default::default()).unwrap();
+        assert_eq!(opened.client(1), payments.client(1));
+        assert_eq!(opened.client(2), payments.client(2));
+
+        std::fs::write(&snapshot, &close.snapshot[1..]).unwrap();
+        let error = open_sealed(&snapshot, Default::default()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::BrokenSeal(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_sealed_snapshot() {
+        let dir = std::env::temp_dir().join(format!("payments-migrate-{}", std::process::id()));
+        let mut payments = Payments::default();
+        let close = closed(&mut payments, Postings::default());
+        let snapshot = close.write(&dir).unwrap();
+        // Sealed before snapshots had a header
+        let data = String::from_utf8(close.snapshot.clone()).unwrap();
+        let version_1 = data.split_once('\n').unwrap().1;
+        let seal = std::fs::read(seal_path(&snapshot)).unwrap();
+        let mut seal: Seal = serde_json::from_slice(&seal).unwrap();
+        seal.checksum = checksum(version_1.as_bytes());
+        std::fs::write(&snapshot, version_1).unwrap();
+        std::fs::write(seal_path(&snapshot), serde_json::to_vec(&seal).unwrap()).unwrap();
+
+        assert_eq!(migrate_sealed(&snapshot).unwrap(), 1);
+        assert_eq!(std::fs::read(&snapshot).unwrap(), close.snapshot);
+        assert_eq!(migrate_sealed(&snapshot).unwrap(), crate::snapshot::VERSION);
+        let opened = open_sealed(&snapshot, Default::default()).unwrap();
+        assert_eq!(opened.client(1), payments.client(1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! Dual runs: comparing the final accounts of two runs over the same input, e.g. with two
+//! configurations or with two versions of the engine whose accounts were saved, to validate
+//! an upgrade before cutover.
+//!
+//! The accounts are compared as written (CSV keyed by the `client` column), so files of
+//! versions with other columns compare too: a column only one side has differs wherever
+//! it's set. Amounts are equal by value, `1.50` being `1.5`.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Read,
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{client::ClientId, error::Error};
+
+/// A field of a client's account differing between the runs, empty where it's missing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    pub client: ClientId,
+    pub field: String,
+    pub a: String,
+    pub b: String,
+}
+
+type Accounts = BTreeMap<ClientId, BTreeMap<String, String>>;
+
+fn accounts(input: impl Read) -> Result<Accounts, Error> {
+    let failure = |e: csv::Error| Error::ParsingFailure(e.to_string());
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let headers = rdr.headers().map_err(failure)?.clone();
+    let Some(key) = headers.iter().position(|header| header == "client") else {
+        return Err(Error::ParsingFailure(
+            "accounts without a `client` column".into(),
+        ));
+    };
+    let mut accounts = Accounts::new();
+    for record in rdr.records() {
+        let record = record.map_err(failure)?;
+        let client = record[key]
+            .parse()
+            .map_err(|_| Error::ParsingFailure(format!("invalid client `{}`", &record[key])))?;
+        let fields = headers
+            .iter()
+            .zip(&record)
+            .filter(|(header, _)| *header != "client")
+            .map(|(header, value)| (header.to_string(), value.to_string()));
+        accounts.insert(client, fields.collect());
+    }
+    Ok(accounts)
+}
+
+fn same(a: &str, b: &str) -> bool {
+    match (a.parse::<Decimal>(), b.parse::<Decimal>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// The differences between the accounts (CSV) of two runs, by client and field
+pub fn compare(a: impl Read, b: impl Read) -> Result<Vec<Difference>, Error> {
+    let (a, b) = (accounts(a)?, accounts(b)?);
+    let none = BTreeMap::new();
+    let clients = a.keys().chain(b.keys()).copied().collect::<BTreeSet<_>>();
+    let mut differences = Vec::new();
+    for client in clients {
+        let (fields_a, fields_b) = (
+            a.get(&client).unwrap_or(&none),
+            b.get(&client).unwrap_or(&none),
+        );
+        let fields = fields_a
+            .keys()
+            .chain(fields_b.keys())
+            .collect::<BTreeSet<_>>();
+        for field in fields {
+            let value =
+                |fields: &BTreeMap<String, String>| fields.get(field).cloned().unwrap_or_default();
+            let (value_a, value_b) = (value(fields_a), value(fields_b));
+            if !same(&value_a, &value_b) {
+                differences.push(Difference {
+                    client,
+                    field: field.clone(),
+                    a: value_a,
+                    b: value_b,
+                });
+            }
+        }
+    }
+    Ok(differences)
+}
+
+/// Write the differences as CSV
+pub fn write_differences(
+    differences: &[Difference],
+    output: impl std::io::Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(output);
+    if differences.is_empty() {
+        writer.write_record(["client", "field", "a", "b"])?;
+    }
+    for difference in differences {
+        writer.serialize(difference)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, Difference};
+
+    #[test]
+    fn differing_fields() {
+        let a = "client,available,held,total,locked\n1,1.50,0,1.5,false\n2,2,0,2,false\n";
+        let b =
+            "client,available,held,total,locked,risk_score\n1,1.5,0,1.5,false,3\n3,1,0,1,false,\n";
+        let difference = |client, field: &str, a: &str, b: &str| Difference {
+            client,
+            field: field.to_string(),
+            a: a.to_string(),
+            b: b.to_string(),
+        };
+        assert_eq!(
+            compare(a.as_bytes(), b.as_bytes()).unwrap(),
+            [
+                difference(1, "risk_score", "", "3"),
+                difference(2, "available", "2", ""),
+                difference(2, "held", "0", ""),
+                difference(2, "locked", "false", ""),
+                difference(2, "total", "2", ""),
+                difference(3, "available", "", "1"),
+                difference(3, "held", "", "0"),
+                difference(3, "locked", "", "false"),
+                difference(3, "total", "", "1"),
+            ]
+        );
+        assert_eq!(compare(a.as_bytes(), a.as_bytes()).unwrap(), []);
+    }
+}
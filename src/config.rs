@@ -0,0 +1,511 @@
+//! The configuration file, e.g. `payments.toml`, holding the settings otherwise passed as
+//! command line flags. Every setting is optional, and a flag given on the command line
+//! overrides the value of the file.
+//!
+//! ```toml
+//! [input]
+//! checksum = "require"
+//! manifest = "processed.jsonl"
+//!
+//! [disputes]
+//! timeout_days = 30
+//! withdrawal_chargeback = "write-off"
+//! transfer_dispute = "write-off"
+//!
+//! [deposits]
+//! settlement_delay_days = 2
+//!
+//! [limits]
+//! max_memory = "2G"
+//! max_risk_score = 80.0
+//!
+//! [output]
+//! dir = "out"
+//! log_format = "json"
+//! ```
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use serde::{de, Deserialize, Deserializer};
+
+use chrono::Duration;
+use rust_decimal::Decimal;
+
+use crate::{
+    access::ClientList,
+    checksum::ChecksumMode,
+    client::{DisputePolicy, WithdrawalChargeback},
+    credit::CreditLimits,
+    dedup::DedupScope,
+    log::LogFormat,
+    parser,
+    payments::{Config, Payments},
+    signature::SigningKey,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub input: InputConfig,
+    pub disputes: DisputeConfig,
+    pub deposits: DepositConfig,
+    pub limits: LimitConfig,
+    pub dedup: DedupFileConfig,
+    pub accounts: AccountConfig,
+    pub output: OutputConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub mmap: Option<bool>,
+    pub lenient_quotes: Option<bool>,
+    pub flexible: Option<bool>,
+    pub reorder_window_secs: Option<i64>,
+    pub schedule: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    pub checksum: Option<ChecksumMode>,
+    pub manifest: Option<PathBuf>,
+    pub allow_duplicate_files: Option<bool>,
+    pub signing_key_file: Option<PathBuf>,
+    pub tenants: Option<bool>,
+    pub default_tenant: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisputeConfig {
+    pub timeout_days: Option<i64>,
+    #[serde(deserialize_with = "parsed")]
+    pub withdrawal_chargeback: Option<WithdrawalChargeback>,
+    #[serde(deserialize_with = "parsed")]
+    pub transfer_dispute: Option<DisputePolicy>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DepositConfig {
+    pub settlement_delay_days: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    /// A number of bytes, e.g. `"512M"`, see `parse_size`
+    #[serde(deserialize_with = "size")]
+    pub max_memory: Option<usize>,
+    pub max_risk_score: Option<f64>,
+    pub quarantine_risk_score: Option<f64>,
+    /// An amount, e.g. `"10000.0"`, see `approval`
+    #[serde(deserialize_with = "parsed")]
+    pub approval_threshold: Option<Decimal>,
+    /// An amount, e.g. `"100.0"`, see `reserve`
+    #[serde(deserialize_with = "parsed")]
+    pub reserve: Option<Decimal>,
+    pub reserves: Option<PathBuf>,
+    pub credit_limits: Option<PathBuf>,
+    pub denied_clients: Option<PathBuf>,
+    pub allowed_clients: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupFileConfig {
+    /// `client`, `client-operation` or `global`, see `DedupScope`
+    #[serde(deserialize_with = "parsed")]
+    pub scope: Option<DedupScope>,
+    pub global_tx_ids: Option<bool>,
+    pub capacity: Option<usize>,
+    pub false_positive_rate: Option<f64>,
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    pub create_clients_on_success: Option<bool>,
+    pub skip_empty_accounts: Option<bool>,
+    pub risk_score_column: Option<bool>,
+    pub reserve_column: Option<bool>,
+    pub pending_column: Option<bool>,
+    pub include_dispute_columns: Option<bool>,
+    pub include_sub_account_columns: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub dir: Option<PathBuf>,
+    pub partition_by: Option<String>,
+    pub shards: Option<u16>,
+    #[serde(deserialize_with = "parsed")]
+    pub log_format: Option<LogFormat>,
+    pub stats: Option<bool>,
+    pub redact: Option<bool>,
+    pub cdc: Option<String>,
+    pub export_events: Option<String>,
+    pub disputes: Option<String>,
+    pub dispute_history: Option<String>,
+    pub chargeback_losses: Option<String>,
+    pub audit_log: Option<PathBuf>,
+    pub transaction_log: Option<PathBuf>,
+    pub merkle_root: Option<String>,
+    pub client_features: Option<String>,
+    pub transaction_features: Option<String>,
+    pub emit_every: Option<usize>,
+    pub snapshot_dir: Option<PathBuf>,
+    pub sign_output: Option<bool>,
+}
+
+/// A value given as a string and parsed with `FromStr`
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_size(&value).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// A number of bytes with an optional `K`, `M` or `G` (binary) suffix
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, shift) = match value.trim().to_ascii_uppercase() {
+        v if v.ends_with('K') => (v[..v.len() - 1].to_string(), 10),
+        v if v.ends_with('M') => (v[..v.len() - 1].to_string(), 20),
+        v if v.ends_with('G') => (v[..v.len() - 1].to_string(), 30),
+        v => (v, 0),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: `{}`", value))
+}
+
+impl FileConfig {
+    /// Parse a configuration file, failing on unknown settings and invalid values
+    pub fn parse(toml: &str) -> Result<Self, String> {
+        toml::from_str(toml).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let toml = std::fs::read_to_string(path)?;
+        Self::parse(&toml).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Override the processing settings of `config` with the ones of the file
+    pub fn apply_to(&self, config: &mut Config) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(days) = self.disputes.timeout_days {
+            config.dispute_timeout =
+                Some(Duration::try_days(days).ok_or("disputes.timeout_days is out of range")?);
+        }
+        if let Some(policy) = self.disputes.withdrawal_chargeback {
+            config.withdrawal_chargeback = policy;
+        }
+        if let Some(policy) = self.disputes.transfer_dispute {
+            config.transfer_dispute = policy;
+        }
+        if let Some(days) = self.deposits.settlement_delay_days {
+            config.settlement_delay = Some(
+                Duration::try_days(days).ok_or("deposits.settlement_delay_days is out of range")?,
+            );
+        }
+        if let Some(limit) = self.limits.max_memory {
+            config.max_memory = Some(limit);
+        }
+        if let Some(score) = self.limits.max_risk_score {
+            config.max_risk_score = Some(score);
+        }
+        if let Some(score) = self.limits.quarantine_risk_score {
+            config.quarantine_risk_score = Some(score);
+        }
+        if let Some(amount) = self.limits.approval_threshold {
+            config.approval_threshold = Some(amount);
+        }
+        if let Some(reserve) = self.limits.reserve {
+            config.reserves.default = reserve;
+        }
+        if let Some(path) = &self.limits.reserves {
+            config.reserves.load(path)?;
+        }
+        if let Some(path) = &self.limits.credit_limits {
+            config.credit_limits = CreditLimits::load(path)?;
+        }
+        if let Some(path) = &self.limits.denied_clients {
+            config.access.denied = ClientList::load(path)?;
+        }
+        if let Some(path) = &self.limits.allowed_clients {
+            config.access.allowed = Some(ClientList::load(path)?);
+        }
+        if let Some(path) = &self.input.signing_key_file {
+            config.signing_key = Some(SigningKey::from_file(path)?);
+        }
+        if let Some(on_success) = self.accounts.create_clients_on_success {
+            config.create_clients_on_success = on_success;
+        }
+        if let Some(skip) = self.accounts.skip_empty_accounts {
+            config.skip_empty_accounts = skip;
+        }
+        if let Some(column) = self.accounts.risk_score_column {
+            config.risk_score_column = column;
+        }
+        if let Some(column) = self.accounts.reserve_column {
+            config.reserve_column = column;
+        }
+        if let Some(column) = self.accounts.pending_column {
+            config.pending_column = column;
+        }
+        if let Some(columns) = self.accounts.include_dispute_columns {
+            config.dispute_columns = columns;
+        }
+        if let Some(columns) = self.accounts.include_sub_account_columns {
+            config.sub_account_columns = columns;
+        }
+        Ok(())
+    }
+
+    /// Problems of settings which are well-formed, but can't work
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+        check(
+            self.disputes.timeout_days.is_none_or(|days| days >= 0),
+            "disputes.timeout_days must not be negative",
+        );
+        check(
+            self.deposits
+                .settlement_delay_days
+                .is_none_or(|days| days >= 0),
+            "deposits.settlement_delay_days must not be negative",
+        );
+        check(
+            self.input.reorder_window_secs.is_none_or(|secs| secs >= 0),
+            "input.reorder_window_secs must not be negative",
+        );
+        check(
+            self.limits
+                .max_risk_score
+                .is_none_or(|score| (0.0..=100.0).contains(&score)),
+            "limits.max_risk_score must be between 0 and 100",
+        );
+        check(
+            self.limits
+                .quarantine_risk_score
+                .is_none_or(|score| (0.0..=100.0).contains(&score)),
+            "limits.quarantine_risk_score must be between 0 and 100",
+        );
+        check(
+            self.limits
+                .reserve
+                .is_none_or(|reserve| !reserve.is_sign_negative()),
+            "limits.reserve must not be negative",
+        );
+        check(
+            self.limits
+                .approval_threshold
+                .is_none_or(|amount| !amount.is_sign_negative()),
+            "limits.approval_threshold must not be negative",
+        );
+        check(
+            self.dedup.capacity != Some(0),
+            "dedup.capacity must be positive",
+        );
+        check(
+            self.dedup
+                .false_positive_rate
+                .is_none_or(|rate| rate > 0.0 && rate < 1.0),
+            "dedup.false_positive_rate must be between 0 and 1",
+        );
+        check(
+            self.output.shards != Some(0),
+            "output.shards must be positive",
+        );
+        check(
+            self.output.emit_every != Some(0),
+            "output.emit_every must be positive",
+        );
+        check(
+            matches!(
+                self.output.partition_by.as_deref(),
+                None | Some("client-shard" | "locked" | "currency")
+            ),
+            "output.partition_by must be `client-shard`, `locked` or `currency`",
+        );
+        check(
+            self.output.partition_by.is_none() || self.output.dir.is_some(),
+            "output.partition_by requires output.dir",
+        );
+        check(
+            self.input
+                .default_tenant
+                .as_deref()
+                .is_none_or(|tenant| parser::tenant(tenant).is_ok()),
+            "input.default_tenant may only contain letters, digits, `-` and `_`",
+        );
+        check(
+            self.input.tenants != Some(true) || self.output.dir.is_some(),
+            "input.tenants requires output.dir",
+        );
+        check(
+            self.input
+                .signing_key_file
+                .as_ref()
+                .is_none_or(|path| path.is_file()),
+            "input.signing_key_file doesn't exist",
+        );
+        problems
+    }
+}
+
+/// Notices changes of a configuration file, so long-running modes can reload it
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    /// Watch the file at `path`, as it is now
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let modified = modified(&path);
+        Self { path, modified }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The settings of the file if it changed since the last call. A file with problems
+    /// is an error, so that the settings in effect can be kept.
+    pub fn changed(&mut self) -> Result<Option<FileConfig>, Box<dyn std::error::Error>> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return Ok(None);
+        }
+        self.modified = modified;
+        let config = FileConfig::load(&self.path)?;
+        if let Some(problem) = config.problems().into_iter().next() {
+            return Err(problem.into());
+        }
+        Ok(Some(config))
+    }
+
+    /// Apply the settings of the file to `payments` if it changed, keeping the state. Returns
+    /// whether it did, an error keeps the settings in effect.
+    pub fn reload(&mut self, payments: &mut Payments) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(file) = self.changed()? else {
+            return Ok(false);
+        };
+        let mut config = payments.config().clone();
+        file.apply_to(&mut config)?;
+        payments.reconfigure(config);
+        Ok(true)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, FileConfig};
+    use crate::{
+        checksum::ChecksumMode,
+        client::{DisputePolicy, WithdrawalChargeback},
+    };
+
+    #[test]
+    fn parse() {
+        let config = FileConfig::parse(
+            r#"
+            [input]
+            checksum = "require"
+
+            [disputes]
+            timeout_days = 30
+            withdrawal_chargeback = "write-off"
+            transfer_dispute = "negative"
+
+            [limits]
+            max_memory = "2G"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.input.checksum, Some(ChecksumMode::Require));
+        assert_eq!(config.disputes.timeout_days, Some(30));
+        assert_eq!(
+            config.disputes.withdrawal_chargeback,
+            Some(WithdrawalChargeback::WriteOff)
+        );
+        assert_eq!(
+            config.disputes.transfer_dispute,
+            Some(DisputePolicy::NegativeBalance)
+        );
+        assert_eq!(config.limits.max_memory, Some(2 << 30));
+        assert_eq!(config.output, Default::default());
+        assert!(config.problems().is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(FileConfig::parse("[limits]\nmax_memroy = \"1G\"")
+            .unwrap_err()
+            .contains("unknown field `max_memroy`"));
+        assert!(FileConfig::parse("[input]\nchecksum = \"always\"")
+            .unwrap_err()
+            .contains("unknown checksum mode"));
+
+        let config = FileConfig::parse("[output]\nshards = 0\npartition_by = \"locked\"").unwrap();
+        assert_eq!(
+            config.problems(),
+            [
+                "output.shards must be positive",
+                "output.partition_by requires output.dir"
+            ]
+        );
+    }
+
+    #[test]
+    fn partitions() {
+        for partition in ["client-shard", "locked", "currency"] {
+            let config = FileConfig::parse(&format!(
+                "[output]\ndir = \"out\"\npartition_by = \"{partition}\""
+            ))
+            .unwrap();
+            assert!(config.problems().is_empty(), "{partition}");
+        }
+
+        let config =
+            FileConfig::parse("[output]\ndir = \"out\"\npartition_by = \"tenant\"").unwrap();
+        assert_eq!(
+            config.problems(),
+            ["output.partition_by must be `client-shard`, `locked` or `currency`"]
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert!(parse_size("lots").is_err());
+    }
+}
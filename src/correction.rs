@@ -0,0 +1,280 @@
+//! Historical corrections (`payments backfill`): deposits and withdrawals deleted or amended
+//! after the fact, e.g. a duplicate payout or a mistyped amount, with the reason:
+//!
+//! ```text
+//! action, client, tx, amount, reason
+//! delete, 1, 3, , paid out twice
+//! amend, 2, 5, 10.5, amount typed as 105
+//! ```
+//!
+//! The corrections are made to the event log, so the disputes of a deleted transaction go
+//! with it and those of an amended one hold its new amount, replacing its amendments (see
+//! `OperationType::Amend`), and the state is replayed from it. No transaction is applied anew, so a correction may leave a client with a negative
+//! balance, to be settled as usual, e.g. by writing it off. `Impact` reports the balances of
+//! every corrected client before and after.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{Client, ClientId},
+    error::Error,
+    event::{ClientEvent, Event},
+    payments::Payments,
+    transaction::TransactionId,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Delete,
+    Amend,
+}
+
+/// A row of the corrections file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Correction {
+    pub action: Action,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// The new amount of an amended transaction
+    #[serde(default)]
+    pub amount: Option<Decimal>,
+    pub reason: String,
+}
+
+impl Correction {
+    pub fn load(path: &std::path::Path) -> Result<Vec<Self>, Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| Error::ParsingFailure(format!("{}: {}", path.display(), e)))?;
+        Self::parse(rdr)
+    }
+
+    pub fn parse<R: std::io::Read>(mut rdr: csv::Reader<R>) -> Result<Vec<Self>, Error> {
+        rdr.deserialize()
+            .map(|record| record.map_err(|e| Error::ParsingFailure(e.to_string())))
+            .collect()
+    }
+
+    /// `tx:action:reason`
+    fn describe(&self) -> String {
+        let action = match self.action {
+            Action::Delete => "delete".to_string(),
+            Action::Amend => format!("amend to {}", self.amount.unwrap_or_default()),
+        };
+        format!("{}:{}:{}", self.tx, action, self.reason)
+    }
+}
+
+/// A row of the impact report, the balances of a corrected client before and after
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Impact {
+    pub client: ClientId,
+    pub available_before: Decimal,
+    pub available_after: Decimal,
+    pub held_before: Decimal,
+    pub held_after: Decimal,
+    pub total_before: Decimal,
+    pub total_after: Decimal,
+    pub locked_before: bool,
+    pub locked_after: bool,
+    /// The client's corrections, `tx:action:reason` separated by `; `
+    pub corrections: String,
+}
+
+/// Replace the amount of an event of a corrected transaction
+fn amend(event: &mut Event, new: Decimal) {
+    match event {
+        Event::FundsDeposited { amount, .. }
+        | Event::FundsWithdrawn { amount, .. }
+        | Event::FundsHeld { amount, .. }
+        | Event::FundsReleased { amount, .. }
+        | Event::FundsChargedBack { amount, .. }
+        | Event::WithdrawalReversed { amount, .. }
+        | Event::WithdrawalWrittenOff { amount, .. }
+        | Event::FundsPending { amount, .. }
+        | Event::FundsCleared { amount, .. } => *amount = new,
+        _ => {}
+    }
+}
+
+/// The state of `payments` with `corrections` made in order, and their impact by client.
+/// A correction of a transaction which isn't a deposit or withdrawal of its client, or was
+/// deleted by an earlier one, fails with `TransactionNotFound`, an amendment without a
+/// positive amount with `InvalidAmount`.
+pub fn backfill(
+    payments: &Payments,
+    corrections: &[Correction],
+) -> Result<(Payments, Vec<Impact>), Error> {
+    let operations = payments
+        .events()
+        .iter()
+        .filter(|e| {
+            matches!(
+                e.event,
+                Event::FundsDeposited { .. } | Event::FundsWithdrawn { .. }
+            )
+        })
+        .filter_map(|e| Some((e.client, e.event.tx()?)))
+        .collect::<HashSet<_>>();
+    // The new amount of every corrected transaction, none if it's deleted
+    let mut corrected = HashMap::<(ClientId, TransactionId), Option<Decimal>>::new();
+    for correction in corrections {
+        let key = (correction.client, correction.tx);
+        if !operations.contains(&key) || corrected.get(&key) == Some(&None) {
+            return Err(Error::TransactionNotFound(correction.tx));
+        }
+        let amount = match correction.action {
+            Action::Delete => None,
+            Action::Amend => match correction.amount {
+                Some(amount) if amount > Decimal::ZERO => Some(amount),
+                _ => return Err(Error::InvalidAmount(correction.tx)),
+            },
+        };
+        corrected.insert(key, amount);
+    }
+
+    let events = payments
+        .events()
+        .iter()
+        .filter_map(|e| {
+            // Operator actions and postings of the day's close aren't tied to a transaction
+            let Some(tx) = e.event.tx() else {
+                return Some(*e);
+            };
+            match corrected.get(&(e.client, tx)) {
+                None => Some(*e),
+                Some(None) => None,
+                // Replaced by the correction
+                Some(_) if matches!(e.event, Event::TransactionAmended { .. }) => None,
+                Some(&Some(amount)) => {
+                    let mut e = *e;
+                    amend(&mut e.event, amount);
+                    Some(e)
+                }
+            }
+        })
+        .collect::<Vec<ClientEvent>>();
+    let after = Payments::replay_with(payments.config().clone(), events)?;
+
+    let mut by_client = BTreeMap::<ClientId, Vec<String>>::new();
+    for correction in corrections {
+        by_client
+            .entry(correction.client)
+            .or_default()
+            .push(correction.describe());
+    }
+    let impact = by_client
+        .into_iter()
+        .map(|(id, corrections)| {
+            let none = Client::new(id);
+            let before = payments.client(id).unwrap_or(&none);
+            let after = after.client(id).unwrap_or(&none);
+            Impact {
+                client: id,
+                available_before: before.available(),
+                available_after: after.available(),
+                held_before: before.held(),
+                held_after: after.held(),
+                total_before: before.total(),
+                total_after: after.total(),
+                locked_before: before.locked(),
+                locked_after: after.locked(),
+                corrections: corrections.join("; "),
+            }
+        })
+        .collect();
+    Ok((after, impact))
+}
+
+pub fn write_impact(
+    impact: &[Impact],
+    output: impl std::io::Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(output);
+    for row in impact {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{backfill, Correction};
+    use crate::{error::Error, testing::process};
+
+    fn corrections(input: &str) -> Vec<Correction> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        Correction::parse(rdr).unwrap()
+    }
+
+    #[test]
+    fn corrections_of_the_event_log() {
+        let payments = process(
+            "type, client, tx, amount
+            deposit, 1, 1, 10
+            deposit, 1, 2, 10
+            withdrawal, 1, 3, 15
+            deposit, 2, 4, 105
+            dispute, 2, 4,
+            deposit, 3, 5, 1",
+        );
+        let (after, impact) = backfill(
+            &payments,
+            &corrections(
+                "action, client, tx, amount, reason
+                delete, 1, 2, , paid in twice
+                amend, 2, 4, 10.5, amount typed as 105",
+            ),
+        )
+        .unwrap();
+
+        // A correction isn't applied anew, the balance may go negative
+        let client = after.client(1).unwrap();
+        assert_eq!((client.available(), client.total()), (dec!(-5), dec!(-5)));
+        // The dispute holds the amended amount
+        let client = after.client(2).unwrap();
+        assert_eq!((client.available(), client.held()), (dec!(0), dec!(10.5)));
+        assert_eq!(after.client(3), payments.client(3));
+
+        assert_eq!(impact.len(), 2);
+        assert_eq!(
+            (
+                impact[0].client,
+                impact[0].total_before,
+                impact[0].total_after
+            ),
+            (1, dec!(5), dec!(-5))
+        );
+        assert_eq!(impact[1].corrections, "4:amend to 10.5:amount typed as 105");
+        assert_eq!(impact[1].held_before, dec!(105));
+
+        let error = backfill(
+            &payments,
+            &corrections(
+                "action, client, tx, amount, reason
+                delete, 1, 2, , paid in twice
+                amend, 1, 2, 1, again",
+            ),
+        );
+        assert!(matches!(error, Err(Error::TransactionNotFound(2))));
+        let error = backfill(
+            &payments,
+            &corrections("action, client, tx, amount, reason\namend, 2, 4, , no amount"),
+        );
+        assert!(matches!(error, Err(Error::InvalidAmount(4))));
+        let error = backfill(
+            &payments,
+            &corrections("action, client, tx, amount, reason\ndelete, 2, 1, , not theirs"),
+        );
+        assert!(matches!(error, Err(Error::TransactionNotFound(1))));
+    }
+}
@@ -0,0 +1,92 @@
+//! Counterparties of deposits and withdrawals, e.g. the bank or the merchant on the other
+//! side, from the optional `counterparty` column. They're kept on the operations, and the
+//! exposure to each across all clients is reported by `Payments::serialize_exposure`.
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::redact::Redacted;
+
+/// Longest name of a counterparty, in bytes
+pub const MAX_NAME_LEN: usize = 24;
+
+/// Name of a counterparty: letters, digits, `-`, `_` and `.`, stored inline so events
+/// stay `Copy`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Counterparty {
+    len: u8,
+    name: [u8; MAX_NAME_LEN],
+}
+
+impl Counterparty {
+    pub fn as_str(&self) -> &str {
+        // Validated ASCII, see `from_str`
+        std::str::from_utf8(&self.name[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Counterparty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = !s.is_empty()
+            && s.len() <= MAX_NAME_LEN
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+        if !valid {
+            return Err(format!(
+                "invalid counterparty `{}`, expected up to {} letters, digits, `-`, `_` and `.`",
+                s, MAX_NAME_LEN
+            ));
+        }
+        let mut name = [0; MAX_NAME_LEN];
+        name[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self {
+            len: s.len() as u8,
+            name,
+        })
+    }
+}
+
+impl fmt::Display for Counterparty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Redacted with `redact`, the `Display` is the name in the outputs
+impl fmt::Debug for Counterparty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Counterparty({:?})", Redacted(self.as_str()))
+    }
+}
+
+impl Serialize for Counterparty {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Counterparty {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Counterparty;
+
+    #[test]
+    fn names() {
+        let bank: Counterparty = "bank-a.uk".parse().unwrap();
+        assert_eq!(bank.to_string(), "bank-a.uk");
+        for invalid in ["", "a b", "x".repeat(25).as_str(), "żółw"] {
+            assert!(invalid.parse::<Counterparty>().is_err(), "{}", invalid);
+        }
+        let json = serde_json::to_string(&bank).unwrap();
+        assert_eq!(serde_json::from_str::<Counterparty>(&json).unwrap(), bank);
+    }
+}
@@ -0,0 +1,90 @@
+//! Credit lines: clients allowed to overdraw their account, with withdrawals taking the
+//! available funds of their main account below zero, down to minus their credit limit.
+//!
+//! Credit drawn is what the available funds are below zero, and deposits pay it back first.
+//! The credit limits of the clients having a credit line are read from a file:
+//!
+//! ```text
+//! client, credit_limit
+//! 1, 500.0
+//! ```
+use std::{collections::HashMap, path::Path};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{client::ClientId, error::Error};
+
+#[derive(Debug, Deserialize)]
+struct CreditLimitRecord {
+    client: ClientId,
+    credit_limit: Decimal,
+}
+
+/// See the module documentation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CreditLimits {
+    clients: HashMap<ClientId, Decimal>,
+}
+
+impl CreditLimits {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| Error::ParsingFailure(format!("{}: {}", path.display(), e)))?;
+        Self::parse(rdr)
+    }
+
+    pub fn parse<R: std::io::Read>(mut rdr: csv::Reader<R>) -> Result<Self, Error> {
+        let mut limits = Self::default();
+        for record in rdr.deserialize() {
+            let CreditLimitRecord {
+                client,
+                credit_limit,
+            } = record.map_err(|e| Error::ParsingFailure(e.to_string()))?;
+            if credit_limit.is_sign_negative() {
+                return Err(Error::ParsingFailure(format!(
+                    "invalid credit limit of client {}: {}",
+                    client, credit_limit
+                )));
+            }
+            limits.insert(client, credit_limit);
+        }
+        Ok(limits)
+    }
+
+    pub fn insert(&mut self, client: ClientId, credit_limit: Decimal) {
+        self.clients.insert(client, credit_limit);
+    }
+
+    /// Whether any client has a credit line
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// The credit limit of `client`, zero without a credit line
+    pub fn limit(&self, client: ClientId) -> Decimal {
+        self.clients.get(&client).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::CreditLimits;
+
+    #[test]
+    fn limits_of_configured_clients() {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("client, credit_limit\n1, 500.0".as_bytes());
+        let limits = CreditLimits::parse(rdr).unwrap();
+        assert_eq!(limits.limit(1), dec!(500));
+        assert_eq!(limits.limit(2), dec!(0));
+
+        let rdr = csv::Reader::from_reader("client,credit_limit\n1,-1".as_bytes());
+        assert!(CreditLimits::parse(rdr).is_err());
+    }
+}
@@ -0,0 +1,1744 @@
+//! The daemon mode: a long-running ingestion service picking up transactions files from a
+//! directory, with the operational endpoints of `server`:
+//!
+//! - `/healthz`: the process is alive
+//! - `/readyz`: the opening state is loaded and files are being picked up
+//! - `/metrics`: counters and the in-memory state, in the Prometheus text format
+//!
+//! `Daemon` is the service, set up by a `DaemonConfig`. `Daemon::catch_up` applies the files
+//! not seen yet, `Daemon::run` does so for good.
+//!
+//! A `Watchdog` tells sources which stopped delivering files from ones without traffic.
+//!
+//! With an admin token, operational actions are served under `/admin`, see `AdminCommand`.
+//! They're carried out by the ingestion loop between files. With `--tenants`, they act on the
+//! state of the tenant given as `tenant=`, of the default tenant without one. Those changing the
+//! state are recorded in a `journal` to carry them out again after a restart.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Write,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    approval::{AdminAction, Approval, OperatorId, PendingApproval},
+    audit::{Actor, AuditLog},
+    client::ClientId,
+    config::ConfigWatcher,
+    error::Error,
+    idempotency::{Claim, Responses},
+    journal::{Journal, JournalEntry},
+    log::{LogEvent, Logger},
+    parser,
+    payments::{Payments, Stats},
+    pipeline::{
+        load, load_tenants, snapshot_file, write_accounts, write_snapshot, LoadOptions, Sinks,
+    },
+    provenance::OutputKey,
+    ratelimit::Throttle,
+    schedule::Schedule,
+    server::{Request, Response},
+    source::{Rejection, SourceStats},
+    tenant::{self, Tenants},
+    transaction::{TenantId, TransactionId},
+};
+
+/// How long an admin request waits for the ingestion loop, which may be busy with a file
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the endpoints report, updated by the ingestion loop
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Status {
+    pub ready: bool,
+    pub files: usize,
+    pub failed_files: usize,
+    pub transactions: usize,
+    pub rejected: usize,
+    /// Of the state, without `--tenants`
+    pub stats: Stats,
+    /// Of the state of every tenant, with `--tenants`, see `tenant`
+    pub tenants: BTreeMap<String, Stats>,
+    /// Seconds since every watched source delivered its last file, see `Watchdog`
+    pub source_idle_secs: BTreeMap<String, u64>,
+    pub stalled_sources: Vec<String>,
+    /// The transactions of every source, see `source`
+    pub sources: BTreeMap<String, SourceStats>,
+    /// The clients holding the most funds, of every state, most first, see `TOP_HELD`
+    pub top_held: Vec<HeldFunds>,
+    /// The latest rejected transactions of every state, see `Payments::recent_rejections`
+    pub recent_rejections: Vec<Rejection>,
+}
+
+/// How many of the clients holding the most funds `Status` reports
+pub const TOP_HELD: usize = 10;
+
+/// The funds held by a client, of a tenant with `--tenants`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeldFunds {
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    pub held: Decimal,
+}
+
+impl Status {
+    /// Account for a processed file
+    pub fn processed(&mut self, transactions: usize, rejected: usize) {
+        self.files += 1;
+        self.transactions += transactions;
+        self.rejected += rejected;
+    }
+
+    /// Update the transactions of the sources, adding up those of a source in several states,
+    /// e.g. of tenants
+    pub fn attribute<'a>(&mut self, sources: impl Iterator<Item = (&'a str, &'a SourceStats)>) {
+        self.sources.clear();
+        for (source, stats) in sources {
+            let total = self.sources.entry(source.to_string()).or_default();
+            total.transactions += stats.transactions;
+            total.rejected += stats.rejected;
+            for (kind, count) in &stats.rejections {
+                *total.rejections.entry(kind).or_default() += count;
+            }
+        }
+    }
+
+    /// The Prometheus text exposition of the status. The metrics of the state are labeled by
+    /// tenant with `--tenants`.
+    pub fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = write!(
+                metrics,
+                "# HELP payments_{name} {help}\n# TYPE payments_{name} {kind}\npayments_{name} {value}\n"
+            );
+        };
+        metric(
+            "files_total",
+            "counter",
+            "Input files processed",
+            self.files.to_string(),
+        );
+        metric(
+            "failed_files_total",
+            "counter",
+            "Input files which failed and were rolled back",
+            self.failed_files.to_string(),
+        );
+        metric(
+            "transactions_total",
+            "counter",
+            "Transactions applied or rejected",
+            self.transactions.to_string(),
+        );
+        metric(
+            "rejected_total",
+            "counter",
+            "Transactions rejected",
+            self.rejected.to_string(),
+        );
+        metric(
+            "stalled_sources",
+            "gauge",
+            "Sources which delivered no file for longer than the watchdog timeout",
+            self.stalled_sources.len().to_string(),
+        );
+        let states = match self.tenants.is_empty() {
+            true => vec![(String::new(), &self.stats)],
+            false => self
+                .tenants
+                .iter()
+                .map(|(tenant, stats)| (format!("tenant=\"{}\"", tenant), stats))
+                .collect(),
+        };
+        let mut state = |name: &str, kind: &str, help: &str, value: fn(&Stats) -> String| {
+            let _ = write!(
+                metrics,
+                "# HELP payments_{name} {help}\n# TYPE payments_{name} {kind}\n"
+            );
+            for (labels, stats) in &states {
+                let labels = match labels.is_empty() {
+                    true => String::new(),
+                    false => format!("{{{}}}", labels),
+                };
+                let _ = writeln!(metrics, "payments_{name}{labels} {}", value(stats));
+            }
+        };
+        state("clients", "gauge", "Client accounts", |stats| {
+            stats.clients.to_string()
+        });
+        state("events", "gauge", "Events in the event log", |stats| {
+            stats.events.to_string()
+        });
+        state(
+            "open_disputes",
+            "gauge",
+            "Disputes not resolved or charged back",
+            |stats| stats.open_disputes.to_string(),
+        );
+        state(
+            "written_off",
+            "gauge",
+            "Charged back withdrawals and negative balances written off",
+            |stats| stats.written_off.to_string(),
+        );
+        state(
+            "blocked_total",
+            "counter",
+            "Transactions rejected as their client is blocked",
+            |stats| stats.blocked.to_string(),
+        );
+        state(
+            "sequence_gaps_total",
+            "counter",
+            "Records missing upstream by the sequence numbers of the clients",
+            |stats| stats.sequence_gaps.to_string(),
+        );
+        state(
+            "sequence_regressions_total",
+            "counter",
+            "Transactions whose sequence number didn't increase",
+            |stats| stats.sequence_regressions.to_string(),
+        );
+        state(
+            "quarantined_clients",
+            "gauge",
+            "Clients whose transactions are parked for review",
+            |stats| stats.quarantined.to_string(),
+        );
+        state(
+            "parked_transactions",
+            "gauge",
+            "Transactions of quarantined clients waiting for review",
+            |stats| stats.parked.to_string(),
+        );
+        state(
+            "locked_clients",
+            "gauge",
+            "Accounts locked by a chargeback",
+            |stats| stats.locked.to_string(),
+        );
+        state(
+            "pending_approvals",
+            "gauge",
+            "Operator actions waiting for the approval of a second operator",
+            |stats| stats.pending_approvals.to_string(),
+        );
+        state(
+            "memory_bytes",
+            "gauge",
+            "Approximate memory used by the accounts and the event log",
+            |stats| stats.memory_bytes.to_string(),
+        );
+        if !self.source_idle_secs.is_empty() {
+            let _ = write!(
+                metrics,
+                "# HELP payments_source_idle_seconds Time since a source delivered its last file\n\
+                 # TYPE payments_source_idle_seconds gauge\n"
+            );
+        }
+        for (source, idle) in &self.source_idle_secs {
+            let _ = writeln!(
+                metrics,
+                "payments_source_idle_seconds{{source=\"{}\"}} {}",
+                source, idle
+            );
+        }
+        if !self.sources.is_empty() {
+            let _ = write!(
+                metrics,
+                "# HELP payments_source_transactions_total Transactions applied or rejected, by source\n\
+                 # TYPE payments_source_transactions_total counter\n"
+            );
+        }
+        for (source, stats) in &self.sources {
+            let _ = writeln!(
+                metrics,
+                "payments_source_transactions_total{{source=\"{}\"}} {}",
+                source, stats.transactions
+            );
+        }
+        if self.sources.values().any(|stats| stats.rejected > 0) {
+            let _ = write!(
+                metrics,
+                "# HELP payments_source_rejected_total Transactions rejected, by source and error\n\
+                 # TYPE payments_source_rejected_total counter\n"
+            );
+        }
+        for (source, stats) in &self.sources {
+            for (error, count) in &stats.rejections {
+                let _ = writeln!(
+                    metrics,
+                    "payments_source_rejected_total{{source=\"{}\",error=\"{}\"}} {}",
+                    source, error, count
+                );
+            }
+        }
+        let _ = write!(
+            metrics,
+            "# HELP payments_latency_seconds Time to parse and apply a transaction, by operation type\n\
+             # TYPE payments_latency_seconds summary\n"
+        );
+        for (labels, stats) in &states {
+            let labels = match labels.is_empty() {
+                true => String::new(),
+                false => format!("{},", labels),
+            };
+            for latency in stats.latencies.summary() {
+                for (quantile, nanos) in [("0.5", latency.p50_ns), ("0.99", latency.p99_ns)] {
+                    let _ = writeln!(
+                        metrics,
+                        "payments_latency_seconds{{{}kind=\"{}\",quantile=\"{}\"}} {}",
+                        labels,
+                        latency.kind,
+                        quantile,
+                        nanos as f64 / 1e9
+                    );
+                }
+                let _ = writeln!(
+                    metrics,
+                    "payments_latency_seconds_count{{{}kind=\"{}\"}} {}",
+                    labels, latency.kind, latency.count
+                );
+            }
+        }
+        metrics
+    }
+}
+
+/// An operational action of the admin endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum AdminCommand {
+    /// `POST /admin/unlock?client=&operator=`, `POST /admin/writeoff?client=&operator=` and
+    /// `POST /admin/resolve?client=&tx=&operator=`, see `Payments::act`
+    Act {
+        client: ClientId,
+        action: AdminAction,
+        operator: OperatorId,
+    },
+    /// `POST /admin/close?client=&operator=`, see `Payments::close_account`
+    Close {
+        client: ClientId,
+        operator: OperatorId,
+    },
+    /// `POST /admin/quarantine?client=&operator=`, see `Payments::quarantine`
+    Quarantine {
+        client: ClientId,
+        operator: OperatorId,
+    },
+    /// `POST /admin/release?client=&operator=`, see `Payments::lift_quarantine`, or with `&tx=`
+    /// a single transaction, see `Payments::release`
+    Release {
+        client: ClientId,
+        tx: Option<TransactionId>,
+        operator: OperatorId,
+    },
+    /// `GET /admin/parked`, see `Payments::parked`
+    Parked,
+    /// `GET /admin/approvals`, see `Payments::pending_approvals`
+    Approvals,
+    /// `POST /admin/snapshot`, write a snapshot of the accounts, of every tenant
+    Snapshot,
+    /// `POST /admin/compact`, see `Payments::compact`, of every tenant
+    Compact,
+}
+
+impl AdminCommand {
+    /// Whether it changes the state, see `journal`
+    pub fn changes_state(&self) -> bool {
+        matches!(
+            self,
+            Self::Act { .. } | Self::Close { .. } | Self::Quarantine { .. } | Self::Release { .. }
+        )
+    }
+}
+
+/// A command for the ingestion loop, with the tenant it's for, given as `tenant=` (the
+/// default one if none, see `tenant`), and where to send the response to
+pub type AdminRequest = (AdminCommand, Option<TenantId>, mpsc::Sender<Response>);
+
+/// The admin endpoints, enabled by a token
+#[derive(Debug, Clone)]
+pub struct Admin {
+    /// Expected as `Authorization: Bearer <token>`
+    pub token: String,
+    pub commands: mpsc::Sender<AdminRequest>,
+    /// Of the requests with an `Idempotency-Key`, see `idempotency`
+    pub responses: Arc<Mutex<Responses>>,
+}
+
+impl Admin {
+    /// Compared in constant time
+    fn authorized(&self, request: &Request) -> bool {
+        let given = request
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        given.len() == self.token.len()
+            && given
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn handle(&self, request: &Request, status: &Status) -> Response {
+        if !self.authorized(request) {
+            return Response::text(401, "unauthorized\n");
+        }
+        let command = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/admin/stats") => {
+                return Response::json(200, serde_json::to_string(status).unwrap_or_default())
+            }
+            ("POST", "/admin/unlock") => act(request, Some(AdminAction::Unlock)),
+            ("POST", "/admin/writeoff") => act(request, Some(AdminAction::WriteOff)),
+            ("POST", "/admin/close") => {
+                operated(request).map(|(client, operator)| AdminCommand::Close { client, operator })
+            }
+            ("POST", "/admin/resolve") => act(
+                request,
+                param(request, "tx").map(|tx| AdminAction::ForceResolve { tx }),
+            ),
+            ("POST", "/admin/quarantine") => operated(request)
+                .map(|(client, operator)| AdminCommand::Quarantine { client, operator }),
+            ("POST", "/admin/release") => {
+                operated(request).and_then(|(client, operator)| match request.query.get("tx") {
+                    Some(_) => param(request, "tx").map(|tx| AdminCommand::Release {
+                        client,
+                        tx: Some(tx),
+                        operator,
+                    }),
+                    None => Some(AdminCommand::Release {
+                        client,
+                        tx: None,
+                        operator,
+                    }),
+                })
+            }
+            ("GET", "/admin/parked") => Some(AdminCommand::Parked),
+            ("GET", "/admin/approvals") => Some(AdminCommand::Approvals),
+            ("POST", "/admin/snapshot") => Some(AdminCommand::Snapshot),
+            ("POST", "/admin/compact") => Some(AdminCommand::Compact),
+            (
+                _,
+                "/admin/stats" | "/admin/unlock" | "/admin/writeoff" | "/admin/close"
+                | "/admin/resolve" | "/admin/quarantine" | "/admin/release" | "/admin/parked"
+                | "/admin/approvals" | "/admin/snapshot" | "/admin/compact",
+            ) => return Response::text(405, "method not allowed\n"),
+            _ => return Response::not_found(),
+        };
+        let command = match command {
+            Some(command) => command,
+            None => {
+                return Response::text(400, "missing or invalid `client`, `tx` or `operator`\n")
+            }
+        };
+        let tenant = match request.query.get("tenant").map(|name| parser::tenant(name)) {
+            Some(Ok(tenant)) => Some(tenant),
+            Some(Err(error)) => return Response::text(400, format!("{}\n", error)),
+            None => None,
+        };
+        let key = match request.method.as_str() {
+            "POST" => request.headers.get("idempotency-key").map(String::as_str),
+            _ => None,
+        };
+        if let Some(key) = key {
+            match self.responses().claim(key, request) {
+                Claim::New => {}
+                Claim::Replay(response) => return response,
+                Claim::Wait(response) => return self.wait(response, Some(key)),
+                Claim::InProgress => {
+                    return Response::text(409, "a request with this key is in progress\n")
+                }
+                Claim::Mismatch => {
+                    return Response::text(422, "key already used for another request\n")
+                }
+            }
+        }
+        let (reply, response) = mpsc::channel();
+        if self.commands.send((command, tenant, reply)).is_err() {
+            if let Some(key) = key {
+                self.responses().release(key);
+            }
+            return Response::text(503, "not processing\n");
+        }
+        self.wait(response, key)
+    }
+
+    fn responses(&self) -> std::sync::MutexGuard<'_, Responses> {
+        self.responses.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The response to a command, kept under the request's idempotency `key`
+    fn wait(&self, response: mpsc::Receiver<Response>, key: Option<&str>) -> Response {
+        match (response.recv_timeout(ADMIN_TIMEOUT), key) {
+            (Ok(response), Some(key)) => {
+                self.responses().settle(key, response.clone());
+                response
+            }
+            (Ok(response), None) => response,
+            (Err(mpsc::RecvTimeoutError::Timeout), Some(key)) => {
+                // Carried out later still, a retry gets the response
+                self.responses().defer(key, response);
+                Response::text(503, "busy, try again\n")
+            }
+            (Err(mpsc::RecvTimeoutError::Disconnected), Some(key)) => {
+                self.responses().release(key);
+                Response::text(503, "busy, try again\n")
+            }
+            (Err(_), None) => Response::text(503, "busy, try again\n"),
+        }
+    }
+}
+
+fn param<T: std::str::FromStr>(request: &Request, name: &str) -> Option<T> {
+    request.query.get(name).and_then(|value| value.parse().ok())
+}
+
+/// The `client` of `request` and the `operator` acting on it
+fn operated(request: &Request) -> Option<(ClientId, OperatorId)> {
+    Some((param(request, "client")?, param(request, "operator")?))
+}
+
+/// `action` on the `client` of `request` by its `operator`
+fn act(request: &Request, action: Option<AdminAction>) -> Option<AdminCommand> {
+    let (client, operator) = operated(request)?;
+    Some(AdminCommand::Act {
+        client,
+        action: action?,
+        operator,
+    })
+}
+
+/// Route requests to the operational endpoints, and to the admin ones if enabled
+pub fn routes(
+    status: Arc<Mutex<Status>>,
+    admin: Option<Admin>,
+) -> impl Fn(&Request) -> Response + Send + 'static {
+    move |request| {
+        let status = status.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let (Some(admin), true) = (&admin, request.path.starts_with("/admin/")) {
+            return admin.handle(request, &status);
+        }
+        if request.method != "GET" {
+            return Response::text(405, "method not allowed\n");
+        }
+        match request.path.as_str() {
+            "/healthz" => Response::text(200, "ok\n"),
+            "/readyz" if status.ready => Response::text(200, "ready\n"),
+            "/readyz" if !status.stalled_sources.is_empty() => Response::text(
+                503,
+                format!("stalled sources: {}\n", status.stalled_sources.join(", ")),
+            ),
+            "/readyz" => Response::text(503, "starting\n"),
+            "/metrics" => Response {
+                content_type: "text/plain; version=0.0.4",
+                ..Response::text(200, status.metrics())
+            },
+            _ => Response::not_found(),
+        }
+    }
+}
+
+/// The source of the files directly in the watched directory
+pub const DEFAULT_SOURCE: &str = "default";
+
+/// The sources with a subdirectory in `dir`, in name order
+pub fn sources(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut sources = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let (true, Some(name)) = (path.is_dir(), path.file_name()) {
+            sources.push(name.to_string_lossy().into_owned());
+        }
+    }
+    sources.sort();
+    Ok(sources)
+}
+
+/// Detects sources which stopped delivering files: a source is stalled once it delivered
+/// none for `timeout`, counting from when it was first watched, until it delivers again.
+/// Files are looked for again at every poll, so there's nothing to reconnect to; a stalled
+/// source is alerted on once and, with `fail_readiness`, makes the service unready.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    timeout: Duration,
+    pub fail_readiness: bool,
+    /// When every source delivered its last file, or was first watched
+    last_seen: BTreeMap<String, Instant>,
+    stalled: BTreeSet<String>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration, fail_readiness: bool) -> Self {
+        Self {
+            timeout,
+            fail_readiness,
+            last_seen: BTreeMap::new(),
+            stalled: BTreeSet::new(),
+        }
+    }
+
+    /// Watch `source` from `now` on, unless it's watched already
+    pub fn watch(&mut self, source: &str, now: Instant) {
+        if !self.last_seen.contains_key(source) {
+            self.last_seen.insert(source.to_string(), now);
+        }
+    }
+
+    /// `source` delivered a file at `now`. Returns whether it was stalled.
+    pub fn delivered(&mut self, source: &str, now: Instant) -> bool {
+        self.last_seen.insert(source.to_string(), now);
+        self.stalled.remove(source)
+    }
+
+    /// The sources stalled since the last check at `now`, with how long they've been idle
+    pub fn check(&mut self, now: Instant) -> Vec<(String, Duration)> {
+        let mut stalled = Vec::new();
+        for (source, &last) in &self.last_seen {
+            let idle = now.saturating_duration_since(last);
+            if idle >= self.timeout && self.stalled.insert(source.clone()) {
+                stalled.push((source.clone(), idle));
+            }
+        }
+        stalled
+    }
+
+    pub fn stalled(&self) -> impl Iterator<Item = &str> {
+        self.stalled.iter().map(String::as_str)
+    }
+
+    /// Update the sources of `status` at `now`
+    pub fn report(&self, status: &mut Status, now: Instant) {
+        status.source_idle_secs = self
+            .last_seen
+            .iter()
+            .map(|(source, &last)| {
+                (
+                    source.clone(),
+                    now.saturating_duration_since(last).as_secs(),
+                )
+            })
+            .collect();
+        status.stalled_sources = self.stalled().map(str::to_string).collect();
+    }
+}
+
+/// The `*.csv` files in `dir` not `seen` yet, in name order
+fn csv_files(dir: &Path, seen: &HashSet<PathBuf>) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|path| {
+        path.extension().is_some_and(|ext| {
+            ext == "csv"
+                || (cfg!(feature = "msgpack") && ext == "msgpack")
+                || (cfg!(feature = "fix") && ext == "fix")
+        }) && path.is_file()
+            && !seen.contains(path)
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// The `*.csv` (and with the `msgpack` and `fix` features `*.msgpack` and `*.fix`) files in
+/// `dir` not `seen` yet with their source. Every subdirectory of `dir` is a source, the files
+/// directly in `dir` are of `DEFAULT_SOURCE`. Sources take turns, in
+/// name order, so that one producer dropping many files doesn't hold up the others.
+/// Producers should write a file under another name, e.g. `*.csv.part`, and rename it
+/// when complete, so that it's never picked up half-written.
+pub fn pending_files(
+    dir: &Path,
+    seen: &HashSet<PathBuf>,
+) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut sources = BTreeMap::new();
+    sources.insert(DEFAULT_SOURCE.to_string(), csv_files(dir, seen)?);
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let source = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            sources
+                .entry(source)
+                .or_insert_with(Vec::new)
+                .extend(csv_files(&path, seen)?);
+        }
+    }
+    let mut sources = sources
+        .into_iter()
+        .map(|(source, files)| (source, files.into_iter()))
+        .collect::<Vec<_>>();
+    let mut pending = Vec::new();
+    loop {
+        let turn = sources
+            .iter_mut()
+            .filter_map(|(source, files)| Some((source.clone(), files.next()?)))
+            .collect::<Vec<_>>();
+        if turn.is_empty() {
+            return Ok(pending);
+        }
+        pending.extend(turn);
+    }
+}
+
+/// Snapshots to write at once: views of the accounts (see `Payments::view`) and their files,
+/// and the number of transactions so far
+type SnapshotJob = (Vec<(Payments, PathBuf)>, usize);
+
+/// Writes snapshots of the accounts on a background thread, so that applying transactions
+/// doesn't wait for them
+struct SnapshotWriter {
+    /// One snapshot is written at a time, and one more may wait
+    jobs: mpsc::SyncSender<SnapshotJob>,
+}
+
+impl SnapshotWriter {
+    fn spawn(log: Logger, key: Option<OutputKey>) -> Self {
+        let (jobs, receiver) = mpsc::sync_channel::<SnapshotJob>(1);
+        std::thread::spawn(move || {
+            for (snapshots, transactions) in receiver {
+                for (payments, path) in snapshots {
+                    match write_snapshot(&payments, &path, key.as_ref()) {
+                        Ok(()) => log.log(LogEvent::Checkpoint {
+                            transactions,
+                            path: &path,
+                        }),
+                        Err(error) => log.log(LogEvent::SnapshotFailed {
+                            path: &path,
+                            error: error.to_string(),
+                        }),
+                    }
+                }
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Write snapshots of the states to their paths in the background, false if others are
+    /// already waiting to be written
+    fn queue(&self, snapshots: Vec<(&Payments, PathBuf)>, transactions: usize) -> bool {
+        let snapshots = snapshots
+            .into_iter()
+            .map(|(payments, path)| (payments.view(), path))
+            .collect();
+        self.jobs.try_send((snapshots, transactions)).is_ok()
+    }
+}
+
+/// How the service runs, see `Daemon`
+pub struct DaemonConfig {
+    /// Where transactions files are dropped
+    pub dir: PathBuf,
+    pub poll: Duration,
+    pub output_dir: Option<PathBuf>,
+    pub snapshot_dir: PathBuf,
+    /// Snapshot the accounts this often
+    pub snapshot_every: Option<Duration>,
+    /// Requests of the admin endpoints, if enabled
+    pub commands: Option<mpsc::Receiver<AdminRequest>>,
+    /// The admin commands which changed the state, carried out again on start
+    pub journal: Option<Journal>,
+    pub throttle: Throttle,
+    /// Standing orders, materialized as the files' time passes them
+    pub schedule: Option<Schedule>,
+    pub audit: Option<AuditLog<File>>,
+    /// Sign the accounts with this key
+    pub output_key: Option<OutputKey>,
+    pub watchdog: Option<Watchdog>,
+    /// The configuration file, applied again between files when it changes
+    pub watcher: Option<ConfigWatcher>,
+    /// Transactions go to the state of their tenant, otherwise all to the default one
+    pub tenanted: bool,
+}
+
+/// The ingestion service
+pub struct Daemon {
+    config: DaemonConfig,
+    snapshots: SnapshotWriter,
+    last_snapshot: Instant,
+    status: Arc<Mutex<Status>>,
+    /// The file applied last, relative to `dir`
+    last_file: Option<String>,
+    /// The files applied so far
+    seen: HashSet<PathBuf>,
+}
+
+impl Daemon {
+    /// The service of `config`, writing the snapshots in the background
+    pub fn new(config: DaemonConfig, log: Logger) -> Self {
+        Self {
+            snapshots: SnapshotWriter::spawn(log, config.output_key.clone()),
+            config,
+            status: Default::default(),
+            last_snapshot: Instant::now(),
+            last_file: None,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// What the endpoints report, see `routes`
+    pub fn shared_status(&self) -> Arc<Mutex<Status>> {
+        self.status.clone()
+    }
+
+    /// Apply the files dropped into `dir` for good. The state is rebuilt from the files in
+    /// `dir` on start, and the service is ready once it caught up with them.
+    /// A failing file is rolled back and skipped, running out of memory is fatal.
+    pub fn run(
+        &mut self,
+        mut tenants: Tenants,
+        options: &LoadOptions,
+        log: Logger,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Published, and addressed by the admin commands, from the start
+        tenants.default_mut();
+        self.replay(&mut tenants, |journal| journal.replay_after(None))?;
+        loop {
+            self.catch_up(&mut tenants, options, log)?;
+            self.serve_commands(&mut tenants, Some(self.config.poll))?;
+            self.snapshot_if_due(&tenants);
+        }
+    }
+
+    /// Apply the files not seen yet, carrying out the admin commands between them, and check
+    /// the sources once caught up
+    pub fn catch_up(
+        &mut self,
+        tenants: &mut Tenants,
+        options: &LoadOptions,
+        log: Logger,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (source, path) in pending_files(&self.config.dir, &self.seen)? {
+            self.seen.insert(path.clone());
+            self.reload(tenants, log);
+            self.delivered(&source, log);
+            let filename = path.display().to_string();
+            let markers = tenants.markers();
+            tenants.set_source(Some(&source));
+            let throttle = Some((&mut self.config.throttle, source.as_str()));
+            let schedule = self.config.schedule.clone();
+            let loaded = match self.config.tenanted {
+                true => load_tenants(
+                    tenants,
+                    &filename,
+                    options,
+                    log,
+                    throttle,
+                    self.config.schedule.as_mut(),
+                ),
+                false => load(
+                    tenants.default_mut(),
+                    &filename,
+                    options,
+                    log,
+                    &mut Sinks::default(),
+                    throttle,
+                    self.config.schedule.as_mut(),
+                ),
+            };
+            match loaded {
+                Ok((transactions, rejected)) => {
+                    self.audit(tenants, &markers, Actor::Transaction)?;
+                    self.status().processed(transactions, rejected);
+                    self.status().attribute(
+                        tenants
+                            .iter()
+                            .flat_map(|(_, payments)| payments.source_stats()),
+                    );
+                    self.publish(tenants)?;
+                }
+                Err(error) => {
+                    if matches!(
+                        error.downcast_ref(),
+                        Some(Error::MemoryLimitExceeded { .. })
+                    ) {
+                        return Err(error);
+                    }
+                    tenants.rollback_to(&markers);
+                    // The file's standing orders come due again with the next one
+                    self.config.schedule = schedule;
+                    log.log(LogEvent::Failed {
+                        input: &filename,
+                        error: error.to_string(),
+                    });
+                    self.status().failed_files += 1;
+                }
+            }
+            let name = path.strip_prefix(&self.config.dir).unwrap_or(&path);
+            self.last_file = Some(name.display().to_string());
+            let after = self.last_file.clone();
+            self.replay(tenants, |journal| journal.replay_after(after.as_deref()))?;
+            self.serve_commands(tenants, None)?;
+            self.snapshot_if_due(tenants);
+        }
+        // Caught up with the files present on start
+        self.replay(tenants, Journal::replay_rest)?;
+        self.reload(tenants, log);
+        self.check_sources(log)
+    }
+
+    /// Carry out the admin commands taken from the journal again, as they were when recorded
+    fn replay(
+        &mut self,
+        tenants: &mut Tenants,
+        take: impl FnOnce(&mut Journal) -> Vec<JournalEntry>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(journal) = &mut self.config.journal else {
+            return Ok(());
+        };
+        for entry in take(journal) {
+            self.execute(tenants, entry.tenant, entry.command)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the configuration file if it changed, keeping the settings in effect if it has
+    /// problems
+    fn reload(&mut self, tenants: &mut Tenants, log: Logger) {
+        let Some(watcher) = &mut self.config.watcher else {
+            return;
+        };
+        let reloaded = watcher.changed().and_then(|changed| {
+            changed
+                .map(|file| {
+                    let mut config = tenants.config().clone();
+                    file.apply_to(&mut config)?;
+                    tenants.reconfigure(config);
+                    Ok(())
+                })
+                .transpose()
+        });
+        match reloaded {
+            Ok(Some(())) => log.log(LogEvent::Reloaded {
+                path: watcher.path(),
+            }),
+            Ok(None) => {}
+            Err(error) => log.log(LogEvent::ReloadFailed {
+                path: watcher.path(),
+                error: error.to_string(),
+            }),
+        }
+    }
+
+    /// Append what's audited since `markers` were taken to the audit log, if any
+    fn audit(
+        &mut self,
+        tenants: &Tenants,
+        markers: &tenant::Markers,
+        actor: Actor,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(audit) = &mut self.config.audit else {
+            return Ok(());
+        };
+        for (tenant, payments) in tenants.iter() {
+            let marker = markers.get(tenant).copied().unwrap_or_default();
+            audit.record_tenant(
+                payments,
+                self.config.tenanted.then_some(tenant),
+                marker,
+                actor,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Where the files of the state of `tenant` go in `dir`
+    fn tenant_dir(&self, dir: &Path, tenant: &str) -> PathBuf {
+        match self.config.tenanted {
+            true => dir.join(tenant),
+            false => dir.to_path_buf(),
+        }
+    }
+
+    /// Account for a file of `source` with the watchdog
+    fn delivered(&mut self, source: &str, log: Logger) {
+        let Some(watchdog) = &mut self.config.watchdog else {
+            return;
+        };
+        if watchdog.delivered(source, Instant::now()) {
+            log.log(LogEvent::SourceResumed { source });
+        }
+    }
+
+    /// Once caught up with the files, the service is ready, unless a source is stalled and
+    /// that fails readiness, see `Watchdog`
+    fn check_sources(&mut self, log: Logger) -> Result<(), Box<dyn std::error::Error>> {
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(watchdog) = &mut self.config.watchdog else {
+            status.ready = true;
+            return Ok(());
+        };
+        let now = Instant::now();
+        for source in sources(&self.config.dir)? {
+            watchdog.watch(&source, now);
+        }
+        for (source, idle) in watchdog.check(now) {
+            log.log(LogEvent::SourceStalled {
+                source: &source,
+                idle_secs: idle.as_secs(),
+            });
+        }
+        watchdog.report(&mut status, now);
+        status.ready = !watchdog.fail_readiness || status.stalled_sources.is_empty();
+        Ok(())
+    }
+
+    /// Carry out a command on the states of every tenant
+    fn execute_on_all(
+        &self,
+        tenants: &mut Tenants,
+        command: AdminCommand,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let memory_usage =
+            |tenants: &Tenants| -> usize { tenants.iter().map(|(_, p)| p.memory_usage()).sum() };
+        let body = match command {
+            AdminCommand::Snapshot => {
+                let Some(mut paths) = self.snapshot(tenants) else {
+                    return Ok(Response::text(
+                        409,
+                        "snapshots are being written, try again\n",
+                    ));
+                };
+                // Written in the background, the files appear once they're complete
+                match self.config.tenanted {
+                    true => serde_json::json!({ "paths": paths }),
+                    false => serde_json::json!({ "path": paths.pop() }),
+                }
+            }
+            _ => {
+                let before = memory_usage(tenants);
+                for (_, payments) in tenants.iter_mut() {
+                    payments.compact();
+                }
+                self.publish(tenants)?;
+                serde_json::json!({
+                    "memory_bytes_before": before,
+                    "memory_bytes_after": memory_usage(tenants),
+                })
+            }
+        };
+        Ok(Response::json(200, body.to_string()))
+    }
+
+    /// Start writing a periodic snapshot if one is due. It's skipped while the last one is
+    /// still being written, and is due again after the next file or poll.
+    fn snapshot_if_due(&mut self, tenants: &Tenants) {
+        let Some(every) = self.config.snapshot_every else {
+            return;
+        };
+        if self.last_snapshot.elapsed() >= every && self.snapshot(tenants).is_some() {
+            self.last_snapshot = Instant::now();
+        }
+    }
+
+    /// Start writing a snapshot of the accounts of every tenant, returning the files, unless
+    /// the last one is still being written
+    fn snapshot(&self, tenants: &Tenants) -> Option<Vec<PathBuf>> {
+        let transactions = self.status().transactions;
+        let snapshots = tenants
+            .iter()
+            .map(|(tenant, payments)| {
+                let dir = self.tenant_dir(&self.config.snapshot_dir, tenant);
+                (payments, snapshot_file(&dir, transactions))
+            })
+            .collect::<Vec<_>>();
+        let paths = snapshots.iter().map(|(_, path)| path.clone()).collect();
+        self.snapshots
+            .queue(snapshots, transactions)
+            .then_some(paths)
+    }
+
+    fn status(&self) -> MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Write the accounts of every tenant to the output directory and update the stats
+    fn publish(&self, tenants: &Tenants) -> Result<(), Box<dyn std::error::Error>> {
+        {
+            let mut status = self.status();
+            match self.config.tenanted {
+                true => {
+                    status.tenants = tenants
+                        .stats()
+                        .into_iter()
+                        .map(|(tenant, stats)| (tenant.to_string(), stats))
+                        .collect()
+                }
+                false => {
+                    if let Some(payments) = tenants.tenant(tenants.default_tenant()) {
+                        status.stats = payments.stats();
+                    }
+                }
+            }
+            status.top_held = tenants
+                .iter()
+                .flat_map(|(tenant, payments)| {
+                    let tenant = self.config.tenanted.then(|| tenant.to_string());
+                    payments
+                        .top_held(TOP_HELD)
+                        .into_iter()
+                        .map(move |(client, held)| HeldFunds {
+                            tenant: tenant.clone(),
+                            client,
+                            held,
+                        })
+                })
+                .sorted_by(|a, b| b.held.cmp(&a.held))
+                .take(TOP_HELD)
+                .collect();
+            status.recent_rejections = tenants
+                .iter()
+                .flat_map(|(_, payments)| payments.recent_rejections().cloned())
+                .collect();
+        }
+        let Some(dir) = &self.config.output_dir else {
+            return Ok(());
+        };
+        for (tenant, payments) in tenants.iter() {
+            let dir = self.tenant_dir(dir, tenant);
+            std::fs::create_dir_all(&dir)?;
+            write_accounts(payments, &dir, self.config.output_key.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Carry out the pending admin commands, and wait for more up to `wait`
+    fn serve_commands(
+        &mut self,
+        tenants: &mut Tenants,
+        wait: Option<Duration>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.commands.is_none() {
+            std::thread::sleep(wait.unwrap_or_default());
+            return Ok(());
+        }
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        loop {
+            let Some(commands) = &self.config.commands else {
+                return Ok(());
+            };
+            let request = match deadline {
+                Some(deadline) => commands
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .ok(),
+                None => commands.try_recv().ok(),
+            };
+            let (command, tenant, reply) = match request {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            let markers = tenants.markers();
+            let response = self.execute(tenants, tenant.clone(), command)?;
+            self.audit(tenants, &markers, Actor::Operator)?;
+            if let Some(journal) = &mut self.config.journal {
+                if command.changes_state() && (200..300).contains(&response.status) {
+                    journal.record(&JournalEntry {
+                        after: self.last_file.clone(),
+                        tenant,
+                        command,
+                    })?;
+                }
+            }
+            // The client may have given up waiting
+            let _ = reply.send(response);
+        }
+    }
+
+    fn execute(
+        &self,
+        tenants: &mut Tenants,
+        tenant: Option<TenantId>,
+        command: AdminCommand,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        // Without `--tenants`, everything is the default tenant's
+        let tenant = match (self.config.tenanted, tenant) {
+            (true, Some(tenant)) => tenant,
+            _ => tenants.default_tenant().to_string(),
+        };
+        let Some(payments) = tenants.get_mut(&tenant) else {
+            return Ok(Response::text(
+                404,
+                format!("tenant `{}` not found\n", tenant),
+            ));
+        };
+        let result = match command {
+            AdminCommand::Act {
+                client,
+                action,
+                operator,
+            } => match payments.act(client, action, operator, None) {
+                Ok(Approval::Pending { amount }) => {
+                    self.publish(tenants)?;
+                    let pending = PendingApproval {
+                        client,
+                        action,
+                        amount,
+                        requested_by: operator,
+                        since: None,
+                    };
+                    return Ok(Response::json(202, serde_json::json!(pending).to_string()));
+                }
+                Ok(Approval::Taken { amount }) => Ok(match action {
+                    AdminAction::Unlock => serde_json::json!({ "client": client, "locked": false }),
+                    AdminAction::WriteOff => {
+                        serde_json::json!({ "client": client, "written_off": amount })
+                    }
+                    AdminAction::ForceResolve { tx } => {
+                        serde_json::json!({ "client": client, "tx": tx, "resolved": true })
+                    }
+                }),
+                Err(error) => Err(error),
+            },
+            AdminCommand::Close { client, operator } => payments
+                .close_account(client, Some(operator), None)
+                .map(|()| serde_json::json!({ "client": client, "status": "closed" })),
+            AdminCommand::Quarantine { client, operator } => payments
+                .quarantine(client, Some(operator), None)
+                .map(|()| serde_json::json!({ "client": client, "quarantined": true })),
+            AdminCommand::Release {
+                client,
+                tx: None,
+                operator,
+            } => payments
+                .lift_quarantine(client, Some(operator), None)
+                .map(|released| {
+                    let released = released
+                        .into_iter()
+                        .map(|(tx, result)| match result {
+                            Ok(()) => serde_json::json!({ "tx": tx, "applied": true }),
+                            Err(error) => serde_json::json!({
+                                "tx": tx,
+                                "applied": false,
+                                "error": error.to_string(),
+                            }),
+                        })
+                        .collect::<Vec<_>>();
+                    serde_json::json!({
+                        "client": client,
+                        "quarantined": false,
+                        "released": released,
+                    })
+                }),
+            AdminCommand::Release {
+                client,
+                tx: Some(tx),
+                operator,
+            } => payments
+                .release(client, tx, Some(operator))
+                .map(|()| serde_json::json!({ "client": client, "tx": tx, "applied": true })),
+            AdminCommand::Parked => Ok(serde_json::json!(payments.parked().collect::<Vec<_>>())),
+            AdminCommand::Approvals => Ok(serde_json::json!(payments
+                .pending_approvals()
+                .collect::<Vec<_>>())),
+            AdminCommand::Snapshot | AdminCommand::Compact => {
+                return self.execute_on_all(tenants, command)
+            }
+        };
+        self.publish(tenants)?;
+        Ok(match result {
+            Ok(body) => Response::json(200, body.to_string()),
+            Err(error @ Error::ClientNotFound(_)) => Response::text(404, format!("{}\n", error)),
+            Err(error) => Response::text(409, format!("{}\n", error)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashSet},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        pending_files, routes, sources, Admin, AdminCommand, AdminRequest, Daemon, DaemonConfig,
+        HeldFunds, Status, Watchdog,
+    };
+    use crate::{
+        approval::AdminAction,
+        log::{LogFormat, Logger},
+        payments::{Config, Payments},
+        pipeline::LoadOptions,
+        server::{Request, Response},
+        tenant::{Tenants, DEFAULT_TENANT},
+        transaction::OperationType,
+    };
+    use rust_decimal_macros::dec;
+
+    fn request(method: &str, target: &str, token: &str) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            headers: [("authorization".to_string(), format!("Bearer {}", token))].into(),
+            ..Default::default()
+        }
+    }
+
+    fn get(path: &str) -> Request {
+        request("GET", path, "")
+    }
+
+    #[test]
+    fn endpoints() {
+        let status = Arc::new(Mutex::new(Status::default()));
+        let routes = routes(status.clone(), None);
+        assert_eq!(routes(&get("/healthz")).status, 200);
+        assert_eq!(routes(&get("/readyz")).status, 503);
+        assert_eq!(routes(&get("/nope")).status, 404);
+
+        let mut payments = Payments::default();
+        payments.set_source(Some("bank"));
+        crate::repl::run(
+            &mut payments,
+            "deposit 1 1 5\nwithdrawal 1 2 10\n".as_bytes(),
+            std::io::sink(),
+        )
+        .unwrap();
+        payments.record_latency(&OperationType::Dispute, Duration::from_micros(3));
+        {
+            let mut status = status.lock().unwrap();
+            status.ready = true;
+            status.processed(2, 1);
+            status.stats = payments.stats();
+            status.attribute(payments.source_stats());
+        }
+        assert_eq!(routes(&get("/readyz")).status, 200);
+        let metrics = routes(&get("/metrics")).body;
+        assert!(metrics.contains("# TYPE payments_transactions_total counter\n"));
+        assert!(metrics.contains("\npayments_transactions_total 2\n"));
+        assert!(metrics.contains("\npayments_rejected_total 1\n"));
+        assert!(metrics.contains("\npayments_clients 1\n"));
+        assert!(metrics.contains("\npayments_source_transactions_total{source=\"bank\"} 2\n"));
+        assert!(metrics.contains(
+            "\npayments_source_rejected_total{source=\"bank\",error=\"insufficient_funds\"} 1\n"
+        ));
+        assert!(metrics.contains(
+            "\npayments_latency_seconds{kind=\"dispute\",quantile=\"0.99\"} 0.000004095\n"
+        ));
+        assert!(metrics.contains("\npayments_latency_seconds_count{kind=\"dispute\"} 1\n"));
+        // Admin endpoints are disabled without a token
+        assert_eq!(routes(&get("/admin/stats")).status, 404);
+    }
+
+    #[test]
+    fn tenant_metrics() {
+        let status = Arc::new(Mutex::new(Status::default()));
+        let routes = routes(status.clone(), None);
+        let mut acme = Payments::default();
+        crate::repl::run(
+            &mut acme,
+            "deposit 1 1 5
+"
+            .as_bytes(),
+            std::io::sink(),
+        )
+        .unwrap();
+        acme.record_latency(&OperationType::Dispute, Duration::from_micros(3));
+        let mut globex = Payments::default();
+        crate::repl::run(
+            &mut globex,
+            "deposit 1 1 5
+deposit 2 2 5
+"
+            .as_bytes(),
+            std::io::sink(),
+        )
+        .unwrap();
+        {
+            let mut status = status.lock().unwrap();
+            status.processed(3, 0);
+            status.tenants = BTreeMap::from([
+                ("acme".to_string(), acme.stats()),
+                ("globex".to_string(), globex.stats()),
+            ]);
+        }
+        let metrics = routes(&get("/metrics")).body;
+        assert!(metrics.contains(
+            "
+payments_transactions_total 3
+"
+        ));
+        assert!(metrics.contains(
+            "
+payments_clients{tenant=\"acme\"} 1
+"
+        ));
+        assert!(metrics.contains(
+            "
+payments_clients{tenant=\"globex\"} 2
+"
+        ));
+        assert!(!metrics.contains(
+            "
+payments_clients "
+        ));
+        assert!(metrics.contains(
+            "
+payments_latency_seconds_count{tenant=\"acme\",kind=\"dispute\"} 1
+"
+        ));
+    }
+
+    #[test]
+    fn watchdog() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(Duration::from_secs(60), true);
+        watchdog.watch("bank", start);
+        watchdog.delivered("psp", start + Duration::from_secs(30));
+        assert!(watchdog.check(start + Duration::from_secs(59)).is_empty());
+        assert_eq!(
+            watchdog.check(start + Duration::from_secs(61)),
+            [("bank".to_string(), Duration::from_secs(61))]
+        );
+        // Alerted on once
+        assert!(watchdog.check(start + Duration::from_secs(62)).is_empty());
+        // Watching it again doesn't reset its idle time
+        watchdog.watch("bank", start + Duration::from_secs(62));
+        let now = start + Duration::from_secs(90);
+        assert_eq!(watchdog.check(now).len(), 1);
+        assert_eq!(watchdog.stalled().collect::<Vec<_>>(), ["bank", "psp"]);
+
+        let status = Arc::new(Mutex::new(Status::default()));
+        watchdog.report(&mut status.lock().unwrap(), now);
+        let routes = routes(status.clone(), None);
+        let ready = routes(&get("/readyz"));
+        assert_eq!(
+            (ready.status, ready.body.as_str()),
+            (503, "stalled sources: bank, psp\n")
+        );
+        let metrics = routes(&get("/metrics")).body;
+        assert!(metrics.contains("\npayments_stalled_sources 2\n"));
+        assert!(metrics.contains("\npayments_source_idle_seconds{source=\"bank\"} 90\n"));
+        assert!(metrics.contains("\npayments_source_idle_seconds{source=\"psp\"} 60\n"));
+
+        assert!(watchdog.delivered("bank", now));
+        assert!(!watchdog.delivered("bank", now));
+        watchdog.report(&mut status.lock().unwrap(), now);
+        assert_eq!(status.lock().unwrap().stalled_sources, ["psp"]);
+    }
+
+    #[test]
+    fn admin_endpoints() {
+        let (commands, received) = std::sync::mpsc::channel();
+        let admin = Admin {
+            token: "secret".to_string(),
+            commands,
+            responses: Default::default(),
+        };
+        let routes = routes(Arc::new(Mutex::new(Status::default())), Some(admin));
+        let worker = std::thread::spawn(move || {
+            received
+                .iter()
+                .map(|(command, tenant, reply): AdminRequest| {
+                    reply.send(Response::json(200, "{}")).unwrap();
+                    (command, tenant)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(routes(&request("GET", "/admin/stats", "wrong")).status, 401);
+        assert_eq!(routes(&request("GET", "/admin/stats", "secre")).status, 401);
+        let stats = routes(&request("GET", "/admin/stats", "secret"));
+        assert_eq!(stats.status, 200);
+        assert!(stats.body.contains("\"ready\":false"));
+        assert_eq!(
+            routes(&request(
+                "GET",
+                "/admin/unlock?client=1&operator=7",
+                "secret"
+            ))
+            .status,
+            405
+        );
+        assert_eq!(
+            routes(&request(
+                "POST",
+                "/admin/resolve?client=1&operator=7",
+                "secret"
+            ))
+            .status,
+            400
+        );
+        assert_eq!(
+            routes(&request("POST", "/admin/unlock?client=1", "secret")).status,
+            400
+        );
+        // Not lifting the quarantine by mistake
+        assert_eq!(
+            routes(&request(
+                "POST",
+                "/admin/release?client=1&tx=x&operator=7",
+                "secret"
+            ))
+            .status,
+            400
+        );
+        assert_eq!(
+            routes(&request("POST", "/admin/close?client=1", "secret")).status,
+            400
+        );
+        assert_eq!(
+            routes(&request(
+                "POST",
+                "/admin/unlock?client=1&operator=7&tenant=a/b",
+                "secret"
+            ))
+            .status,
+            400
+        );
+        for target in [
+            "/admin/unlock?client=1&operator=7&tenant=acme",
+            "/admin/writeoff?client=1&operator=7",
+            "/admin/close?client=1&operator=7",
+            "/admin/resolve?client=1&tx=2&operator=8",
+            "/admin/quarantine?client=1&operator=8",
+            "/admin/release?client=1&tx=2&operator=7",
+            "/admin/release?client=1&operator=7",
+            "/admin/snapshot",
+            "/admin/compact",
+        ] {
+            assert_eq!(routes(&request("POST", target, "secret")).status, 200);
+        }
+        for target in ["/admin/parked", "/admin/approvals"] {
+            assert_eq!(routes(&request("GET", target, "secret")).status, 200);
+        }
+        drop(routes);
+        let (commands, tenants): (Vec<_>, Vec<_>) = worker.join().unwrap().into_iter().unzip();
+        assert_eq!(tenants[0].as_deref(), Some("acme"));
+        assert!(tenants[1..].iter().all(Option::is_none));
+        assert_eq!(
+            commands,
+            [
+                AdminCommand::Act {
+                    client: 1,
+                    action: AdminAction::Unlock,
+                    operator: 7
+                },
+                AdminCommand::Act {
+                    client: 1,
+                    action: AdminAction::WriteOff,
+                    operator: 7
+                },
+                AdminCommand::Close {
+                    client: 1,
+                    operator: 7
+                },
+                AdminCommand::Act {
+                    client: 1,
+                    action: AdminAction::ForceResolve { tx: 2 },
+                    operator: 8
+                },
+                AdminCommand::Quarantine {
+                    client: 1,
+                    operator: 8
+                },
+                AdminCommand::Release {
+                    client: 1,
+                    tx: Some(2),
+                    operator: 7
+                },
+                AdminCommand::Release {
+                    client: 1,
+                    tx: None,
+                    operator: 7
+                },
+                AdminCommand::Snapshot,
+                AdminCommand::Compact,
+                AdminCommand::Parked,
+                AdminCommand::Approvals
+            ]
+        );
+    }
+
+    #[test]
+    fn idempotent_admin_requests() {
+        let (commands, received) = std::sync::mpsc::channel();
+        let admin = Admin {
+            token: "secret".to_string(),
+            commands,
+            responses: Default::default(),
+        };
+        let routes = routes(Arc::new(Mutex::new(Status::default())), Some(admin));
+        let worker = std::thread::spawn(move || {
+            let mut sent = 0;
+            for (_, _, reply) in received.iter() {
+                reply.send(Response::json(200, sent.to_string())).unwrap();
+                sent += 1;
+            }
+            sent
+        });
+        let keyed = |method, target, key: &str| {
+            let mut request = request(method, target, "secret");
+            request
+                .headers
+                .insert("idempotency-key".to_string(), key.to_string());
+            routes(&request)
+        };
+
+        let first = keyed("POST", "/admin/writeoff?client=1&operator=7", "a");
+        assert_eq!(first, Response::json(200, "0"));
+        // A retry gets the first response, without the command being sent again
+        let retry = keyed("POST", "/admin/writeoff?operator=7&client=1", "a");
+        assert_eq!(retry, first);
+        assert_eq!(
+            keyed("POST", "/admin/writeoff?client=2&operator=7", "a").status,
+            422
+        );
+        // Refused requests don't use the key
+        assert_eq!(keyed("POST", "/admin/writeoff?client=2", "b").status, 400);
+        assert_eq!(
+            keyed("POST", "/admin/writeoff?client=2&operator=7", "b"),
+            Response::json(200, "1")
+        );
+        // Only the responses to POST requests are kept
+        assert_eq!(keyed("GET", "/admin/parked", "c").body, "2");
+        assert_eq!(keyed("GET", "/admin/parked", "c").body, "3");
+        assert_eq!(
+            routes(&request("POST", "/admin/compact", "secret")).body,
+            "4"
+        );
+        drop(routes);
+        assert_eq!(worker.join().unwrap(), 5);
+    }
+
+    #[test]
+    fn picks_up_csv_files_in_order() {
+        let dir = std::env::temp_dir().join(format!("payments-daemon-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("bank")).unwrap();
+        for name in [
+            "b.csv",
+            "a.csv",
+            "c.csv.part",
+            "a.csv.sha256",
+            "bank/1.csv",
+            "bank/2.csv",
+            "bank/3.csv",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let pending = |seen: &HashSet<_>| {
+            pending_files(&dir, seen)
+                .unwrap()
+                .into_iter()
+                .map(|(source, path)| (source, path.strip_prefix(&dir).unwrap().to_owned()))
+                .collect::<Vec<_>>()
+        };
+        let file = |source: &str, path: &str| (source.to_string(), path.into());
+        // Sources take turns
+        assert_eq!(
+            pending(&HashSet::new()),
+            [
+                file("bank", "bank/1.csv"),
+                file("default", "a.csv"),
+                file("bank", "bank/2.csv"),
+                file("default", "b.csv"),
+                file("bank", "bank/3.csv"),
+            ]
+        );
+        let seen = HashSet::from([dir.join("a.csv"), dir.join("bank/1.csv")]);
+        assert_eq!(
+            pending(&seen),
+            [
+                file("bank", "bank/2.csv"),
+                file("default", "b.csv"),
+                file("bank", "bank/3.csv"),
+            ]
+        );
+        assert_eq!(sources(&dir).unwrap(), ["bank"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn catches_up_with_new_files() {
+        let dir = std::env::temp_dir().join(format!("payments-catch-up-{}", std::process::id()));
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::write(
+            input.join("1.csv"),
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\n",
+        )
+        .unwrap();
+        std::fs::write(
+            input.join("2.csv"),
+            "type,client,tx,amount\ndeposit,1,3,1\nfoo,1,4,1\n",
+        )
+        .unwrap();
+        let mut daemon = Daemon::new(
+            DaemonConfig {
+                dir: input.clone(),
+                poll: Duration::ZERO,
+                output_dir: Some(output.clone()),
+                snapshot_dir: dir.join("snapshots"),
+                snapshot_every: None,
+                commands: None,
+                journal: None,
+                throttle: Default::default(),
+                schedule: None,
+                audit: None,
+                output_key: None,
+                watchdog: None,
+                watcher: None,
+                tenanted: false,
+            },
+            Logger::new(LogFormat::Text),
+        );
+        let status = daemon.shared_status();
+        let mut tenants = Tenants::new(Config::default(), DEFAULT_TENANT.to_string());
+        let options = LoadOptions::default();
+        let log = Logger::new(LogFormat::Text);
+        daemon.catch_up(&mut tenants, &options, log).unwrap();
+        {
+            let status = status.lock().unwrap();
+            assert!(status.ready);
+            // The malformed row fails its file, which is rolled back
+            assert_eq!((status.files, status.failed_files), (1, 1));
+            assert_eq!(status.transactions, 2);
+            assert_eq!(status.stats.clients, 2);
+        }
+        let accounts = std::fs::read_to_string(output.join("accounts.csv")).unwrap();
+        assert!(accounts.contains("1,10"));
+
+        // Only the new file is applied
+        std::fs::write(
+            input.join("3.csv"),
+            "type,client,tx,amount\ndeposit,3,4,1\nwithdrawal,3,5,2\ndispute,1,1,\n",
+        )
+        .unwrap();
+        daemon.catch_up(&mut tenants, &options, log).unwrap();
+        let status = status.lock().unwrap();
+        assert_eq!((status.files, status.transactions), (2, 5));
+        assert_eq!(status.stats.clients, 3);
+        assert_eq!(
+            status.top_held,
+            [HeldFunds {
+                tenant: None,
+                client: 1,
+                held: dec!(10)
+            }]
+        );
+        let rejected = status
+            .recent_rejections
+            .iter()
+            .map(|rejection| (rejection.source.as_deref(), rejection.client, rejection.tx))
+            .collect::<Vec<_>>();
+        assert_eq!(rejected, [(Some("default"), 3, 5)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
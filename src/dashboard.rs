@@ -0,0 +1,200 @@
+//! A terminal dashboard of the daemon mode (`serve --tui`), for operators watching the
+//! ingestion, e.g. during an incident: the throughput, the clients holding the most funds,
+//! the latest rejected transactions and the number of locked accounts.
+//!
+//! It redraws the terminal from the `Status` which the ingestion loop updates after every
+//! file, see `daemon`. The amounts are redacted with `--redact`, see `redact`.
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{daemon::Status, redact::Redacted};
+
+/// Clears the terminal and moves the cursor to its top left corner
+const CLEAR: &str = "\x1b[2J\x1b[H";
+
+/// Renders frames of the status, keeping what it takes to tell the throughput
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    /// When the last frame was rendered, and the transactions by then
+    last: Option<(Instant, usize)>,
+    /// Transactions per second between the last two frames
+    throughput: f64,
+}
+
+impl Dashboard {
+    /// A frame of `status` as of `now`
+    pub fn render(&mut self, status: &Status, now: Instant) -> String {
+        if let Some((then, transactions)) = self.last {
+            let elapsed = now.saturating_duration_since(then).as_secs_f64();
+            if elapsed > 0.0 {
+                let processed = status.transactions.saturating_sub(transactions);
+                self.throughput = processed as f64 / elapsed;
+            }
+        }
+        self.last = Some((now, status.transactions));
+        let locked = match status.tenants.is_empty() {
+            true => status.stats.locked,
+            false => status.tenants.values().map(|stats| stats.locked).sum(),
+        };
+
+        let mut frame = String::new();
+        let _ = writeln!(
+            frame,
+            "payments: {}",
+            match status.ready {
+                true => "ready",
+                false => "catching up",
+            }
+        );
+        let _ = writeln!(
+            frame,
+            "files: {} ({} failed), transactions: {} ({} rejected), throughput: {:.1}/s",
+            status.files,
+            status.failed_files,
+            status.transactions,
+            status.rejected,
+            self.throughput
+        );
+        let _ = writeln!(frame, "locked accounts: {}", locked);
+
+        let _ = writeln!(frame, "\ntop clients by held funds");
+        if status.top_held.is_empty() {
+            let _ = writeln!(frame, "  none");
+        }
+        for funds in &status.top_held {
+            let tenant = match &funds.tenant {
+                Some(tenant) => format!("{}/", tenant),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                frame,
+                "  {}{:<10} {}",
+                tenant,
+                funds.client,
+                Redacted(funds.held)
+            );
+        }
+
+        let _ = writeln!(frame, "\nrecent rejections");
+        if status.recent_rejections.is_empty() {
+            let _ = writeln!(frame, "  none");
+        }
+        // Newest first
+        for rejection in status.recent_rejections.iter().rev() {
+            let _ = writeln!(
+                frame,
+                "  [{}] client {}, tx {}: {}",
+                rejection.source.as_deref().unwrap_or("-"),
+                rejection.client,
+                rejection.tx,
+                rejection.error
+            );
+        }
+        frame
+    }
+}
+
+/// Redraw the dashboard of `status` on the terminal (standard output) every `every`, on a
+/// background thread
+pub fn spawn(status: Arc<Mutex<Status>>, every: Duration) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut dashboard = Dashboard::default();
+        loop {
+            let frame = {
+                let status = status.lock().unwrap_or_else(|e| e.into_inner());
+                dashboard.render(&status, Instant::now())
+            };
+            let mut stdout = std::io::stdout().lock();
+            // The ingestion goes on without a terminal
+            let _ = write!(stdout, "{}{}", CLEAR, frame).and_then(|()| stdout.flush());
+            drop(stdout);
+            std::thread::sleep(every);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use rust_decimal_macros::dec;
+
+    use super::Dashboard;
+    use crate::{
+        daemon::{HeldFunds, Status},
+        source::Rejection,
+    };
+
+    #[test]
+    fn renders_the_status() {
+        let mut status = Status {
+            ready: true,
+            files: 2,
+            transactions: 100,
+            rejected: 1,
+            top_held: vec![
+                HeldFunds {
+                    tenant: None,
+                    client: 7,
+                    held: dec!(50),
+                },
+                HeldFunds {
+                    tenant: None,
+                    client: 3,
+                    held: dec!(5.5),
+                },
+            ],
+            recent_rejections: vec![
+                Rejection {
+                    source: Some("bank".to_string()),
+                    client: 1,
+                    tx: 4,
+                    error: "not enough funds".to_string(),
+                },
+                Rejection {
+                    source: None,
+                    client: 2,
+                    tx: 9,
+                    error: "account locked".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        status.stats.locked = 3;
+        let mut dashboard = Dashboard::default();
+        let start = Instant::now();
+        dashboard.render(&status, start);
+        status.transactions = 300;
+        let frame = dashboard.render(&status, start + Duration::from_secs(2));
+        assert_eq!(
+            frame,
+            "payments: ready\n\
+             files: 2 (0 failed), transactions: 300 (1 rejected), throughput: 100.0/s\n\
+             locked accounts: 3\n\
+             \n\
+             top clients by held funds\n  \
+             7          50\n  \
+             3          5.5\n\
+             \n\
+             recent rejections\n  \
+             [-] client 2, tx 9: account locked\n  \
+             [bank] client 1, tx 4: not enough funds\n"
+        );
+    }
+
+    #[test]
+    fn counts_locked_accounts_of_every_tenant() {
+        let mut status = Status::default();
+        for (tenant, locked) in [("a", 1), ("b", 2)] {
+            let stats = status.tenants.entry(tenant.to_string()).or_default();
+            stats.locked = locked;
+        }
+        let frame = Dashboard::default().render(&status, Instant::now());
+        assert!(frame.contains("locked accounts: 3\n"));
+        assert!(frame.starts_with("payments: catching up\n"));
+    }
+}
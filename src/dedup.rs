@@ -0,0 +1,729 @@
+//! Duplicate transaction detection: the scope transaction IDs have to be unique in, and for
+//! IDs unique across all clients, an index of the seen ones.
+//!
+//! Seen IDs are kept in a Bloom filter sized for the expected number of IDs and a target
+//! false positive rate, so memory stays bounded no matter how many IDs are seen. A filter hit
+//! is either a real duplicate or a false positive: with a confirmation directory, every ID is
+//! also written to a hash table on disk and hits are confirmed by reading the page of its
+//! slot, otherwise hits are reported as duplicates as they are. IDs are held in memory until
+//! their batch is committed and written a page at a time, and forgotten ones are marked in
+//! the table. Every index of a process keeps its table in a directory of its own under the
+//! confirmation directory, cleared when the index is created and removed with it, so IDs
+//! seen by an earlier run or by another index, e.g. of another shard, aren't mistaken for
+//! its own. Processes mustn't share the directory.
+//!
+//! A cloned index shares the filter copy-on-write and only reads the confirmation files,
+//! keeping the IDs inserted into it in memory.
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::error::Error;
+
+/// Slots of the confirmation table read or written at once
+const PAGE_SLOTS: usize = 512;
+const SLOT_BYTES: u64 = 8;
+const PAGE_BYTES: usize = PAGE_SLOTS * SLOT_BYTES as usize;
+/// Slots of a new confirmation table, a multiple of `PAGE_SLOTS`
+const INITIAL_SLOTS: u64 = 1 << 16;
+/// A free slot of the confirmation table
+const EMPTY: u64 = 0;
+/// The slot of a forgotten key, which lookups probe past
+const TOMBSTONE: u64 = u64::MAX;
+/// Keys inserted into the confirmation store which are held in memory before they're written
+/// anyway, e.g. of a large batch
+const PENDING_LIMIT: usize = 1 << 16;
+/// Forgotten keys an index without a confirmation store remembers
+const FORGOTTEN_LIMIT: usize = 1 << 16;
+
+/// Indexes created by the process so far, numbering their confirmation directories
+static INDEXES: AtomicUsize = AtomicUsize::new(0);
+
+/// Where a deposit or withdrawal ID has to be unique
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum DedupScope {
+    /// Among the client's deposits and withdrawals
+    #[default]
+    Client,
+    /// Among the client's deposits, and among its withdrawals: a deposit and a withdrawal may
+    /// share an ID, e.g. the legs of a transfer. Disputes of such an ID are of the deposit.
+    ClientOperation,
+    /// Across all clients, the seen IDs are kept in a `DedupIndex` sized by the config
+    Global(DedupConfig),
+}
+
+impl DedupScope {
+    /// The deposit and withdrawal of a client may share an ID
+    pub fn allows_reused_legs(&self) -> bool {
+        matches!(self, DedupScope::ClientOperation)
+    }
+}
+
+impl std::str::FromStr for DedupScope {
+    type Err = String;
+
+    /// A global scope is sized with the default `DedupConfig`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(DedupScope::Client),
+            "client-operation" => Ok(DedupScope::ClientOperation),
+            "global" => Ok(DedupScope::Global(DedupConfig::default())),
+            other => Err(format!(
+                "unknown dedup scope `{}`, expected `client`, `client-operation` or `global`",
+                other
+            )),
+        }
+    }
+}
+
+/// Sizing of the duplicate detector
+#[derive(Debug, Clone, PartialEq)]
+pub struct DedupConfig {
+    /// How many IDs the filter is sized for, beyond that the false positive rate degrades
+    pub expected_items: usize,
+    pub false_positive_rate: f64,
+    /// Where to keep the seen IDs for confirming filter hits
+    pub confirmation_dir: Option<PathBuf>,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            expected_items: 1_000_000,
+            false_positive_rate: 1e-6,
+            confirmation_dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let items = expected_items.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let bits = (-items * rate.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hashes = ((bits as f64 / items) * ln2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes,
+        }
+    }
+
+    /// Bit positions of `key`, by double hashing
+    fn positions(&self, key: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let (h1, h2) = (hash(key, 0), hash(key, 1) | 1);
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, key: u64) {
+        for bit in self.positions(key).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+}
+
+/// FNV-1a of `seed` and `key`, stable across runs and builds, unlike `DefaultHasher`
+fn hash(key: u64, seed: u64) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    seed.to_le_bytes()
+        .into_iter()
+        .chain(key.to_le_bytes())
+        .fold(OFFSET, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        })
+}
+
+/// The confirmation directory of an index, removed with the last store using it
+#[derive(Debug)]
+struct StoreDir(PathBuf);
+
+impl Drop for StoreDir {
+    fn drop(&mut self) {
+        // Nothing to do about a directory which can't be removed
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Seen IDs on disk, in a hash table of fixed slots with linear probing, kept at most half
+/// full so that a confirmation reads about one page of it. A slot holds a key plus one,
+/// `EMPTY` or `TOMBSTONE`: transaction IDs are 32-bit, so keys never collide with those.
+#[derive(Debug)]
+struct ConfirmationStore {
+    dir: Arc<StoreDir>,
+    /// The table, created by the first commit
+    table: Option<File>,
+    /// Slots of the table, a multiple of `PAGE_SLOTS`
+    slots: u64,
+    /// Slots taken, including those of forgotten keys
+    used: u64,
+    /// Keys inserted since the last commit, not in the table yet
+    pending: HashSet<u64>,
+    /// IDs inserted into a clone, which leaves the files to the original
+    forked: Option<HashSet<u64>>,
+    /// Whether a clone was cleared, so it no longer reads the files
+    detached: bool,
+}
+
+impl Clone for ConfirmationStore {
+    fn clone(&self) -> Self {
+        let mut forked = self.forked.clone().unwrap_or_default();
+        forked.extend(&self.pending);
+        Self {
+            dir: self.dir.clone(),
+            table: self
+                .table
+                .as_ref()
+                .and_then(|_| File::open(self.table_path()).ok()),
+            slots: self.slots,
+            used: self.used,
+            pending: HashSet::new(),
+            forked: Some(forked),
+            detached: self.detached,
+        }
+    }
+}
+
+impl ConfirmationStore {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir: Arc::new(StoreDir(dir)),
+            table: None,
+            slots: 0,
+            used: 0,
+            pending: HashSet::new(),
+            forked: None,
+            detached: false,
+        }
+    }
+
+    fn table_path(&self) -> PathBuf {
+        self.dir.0.join("ids.table")
+    }
+
+    fn contains(&self, key: u64) -> io::Result<bool> {
+        if self.forked.as_ref().is_some_and(|ids| ids.contains(&key)) {
+            return Ok(true);
+        }
+        if self.detached {
+            return Ok(false);
+        }
+        if self.pending.contains(&key) {
+            return Ok(true);
+        }
+        match &self.table {
+            Some(table) => Ok(find(table, self.slots, key)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    fn insert(&mut self, key: u64) -> io::Result<()> {
+        if let Some(ids) = &mut self.forked {
+            ids.insert(key);
+            return Ok(());
+        }
+        self.pending.insert(key);
+        // Written anyway when a batch holds that many
+        if self.pending.len() >= PENDING_LIMIT {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Write the pending keys to the table, growing it to keep it at most half full
+    fn commit(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let keys = self.pending.drain().collect::<Vec<_>>();
+        let added = keys.len() as u64;
+        let mut slots = match self.slots {
+            0 => INITIAL_SLOTS,
+            slots => slots,
+        };
+        while (self.used + added) * 2 > slots {
+            slots *= 2;
+        }
+        if slots != self.slots {
+            self.resize(slots)?;
+        }
+        if let Some(table) = &self.table {
+            self.used += place(table, self.slots, keys)?;
+        }
+        Ok(())
+    }
+
+    /// Move the keys to a new table of `slots`, dropping the forgotten ones
+    fn resize(&mut self, slots: u64) -> io::Result<()> {
+        fs::create_dir_all(&self.dir.0)?;
+        let path = self.table_path();
+        let mut resized = path.clone().into_os_string();
+        resized.push(".new");
+        let table = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&resized)?;
+        table.set_len(slots * SLOT_BYTES)?;
+        let mut used = 0;
+        if let Some(old) = &self.table {
+            let mut reader = BufReader::new(old);
+            reader.seek(SeekFrom::Start(0))?;
+            let mut page = vec![0; PAGE_BYTES];
+            for _ in 0..self.slots / PAGE_SLOTS as u64 {
+                reader.read_exact(&mut page)?;
+                let keys = (0..PAGE_SLOTS)
+                    .map(|i| slot_at(&page, i))
+                    .filter(|&value| value != EMPTY && value != TOMBSTONE)
+                    .map(|value| value - 1);
+                used += place(&table, slots, keys)?;
+            }
+        }
+        fs::rename(&resized, &path)?;
+        self.table = Some(table);
+        self.slots = slots;
+        self.used = used;
+        Ok(())
+    }
+
+    /// Forget `key`, returning whether that's recorded: a clone doesn't write the files
+    fn forget(&mut self, key: u64) -> io::Result<bool> {
+        if let Some(ids) = &mut self.forked {
+            ids.remove(&key);
+            return Ok(false);
+        }
+        if self.pending.remove(&key) {
+            return Ok(true);
+        }
+        let Some(mut table) = self.table.as_ref() else {
+            return Ok(true);
+        };
+        if let Some(slot) = find(table, self.slots, key)? {
+            table.seek(SeekFrom::Start(slot * SLOT_BYTES))?;
+            table.write_all(&TOMBSTONE.to_le_bytes())?;
+        }
+        Ok(true)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        if let Some(ids) = &mut self.forked {
+            ids.clear();
+            self.detached = true;
+            return Ok(());
+        }
+        self.table = None;
+        self.slots = 0;
+        self.used = 0;
+        self.pending.clear();
+        match fs::remove_dir_all(&self.dir.0) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn slot_at(page: &[u8], index: usize) -> u64 {
+    let mut slot = [0; 8];
+    slot.copy_from_slice(&page[index * 8..index * 8 + 8]);
+    u64::from_le_bytes(slot)
+}
+
+/// The slot `key` is probed from in a table of `slots`
+fn home(key: u64, slots: u64) -> u64 {
+    hash(key, 2) % slots
+}
+
+/// Read the slots of `table` from `slot` to the end of its page
+fn read_page(mut table: &File, slot: u64) -> io::Result<Vec<u8>> {
+    let count = PAGE_SLOTS - (slot % PAGE_SLOTS as u64) as usize;
+    let mut page = vec![0; count * 8];
+    table.seek(SeekFrom::Start(slot * SLOT_BYTES))?;
+    table.read_exact(&mut page)?;
+    Ok(page)
+}
+
+/// The slot of `key` in `table` of `slots`, if it's there
+fn find(table: &File, slots: u64, key: u64) -> io::Result<Option<u64>> {
+    let value = key.wrapping_add(1);
+    let mut slot = home(key, slots);
+    // The table is never full, the bound only guards against a corrupt file
+    for _ in 0..=slots / PAGE_SLOTS as u64 {
+        let page = read_page(table, slot)?;
+        for index in 0..page.len() / 8 {
+            match slot_at(&page, index) {
+                found if found == value => return Ok(Some(slot + index as u64)),
+                EMPTY => return Ok(None),
+                _ => {}
+            }
+        }
+        slot = (slot + (page.len() / 8) as u64) % slots;
+    }
+    Ok(None)
+}
+
+/// Write `keys` into free slots of `table` of `slots`, a page at a time, returning how many
+/// weren't there yet
+fn place(mut table: &File, slots: u64, keys: impl IntoIterator<Item = u64>) -> io::Result<u64> {
+    let mut pages = BTreeMap::<u64, Vec<u8>>::new();
+    let mut placed = 0;
+    for key in keys {
+        let value = key.wrapping_add(1);
+        let mut slot = home(key, slots);
+        loop {
+            let number = slot / PAGE_SLOTS as u64;
+            let page = match pages.entry(number) {
+                Entry::Occupied(page) => page.into_mut(),
+                Entry::Vacant(page) => page.insert(read_page(table, number * PAGE_SLOTS as u64)?),
+            };
+            let index = (slot % PAGE_SLOTS as u64) as usize;
+            match slot_at(page, index) {
+                found if found == value => break,
+                EMPTY => {
+                    page[index * 8..index * 8 + 8].copy_from_slice(&value.to_le_bytes());
+                    placed += 1;
+                    break;
+                }
+                _ => slot = (slot + 1) % slots,
+            }
+        }
+    }
+    for (number, page) in pages {
+        table.seek(SeekFrom::Start(number * PAGE_SLOTS as u64 * SLOT_BYTES))?;
+        table.write_all(&page)?;
+    }
+    Ok(placed)
+}
+
+#[derive(Debug, Clone)]
+pub struct DedupIndex {
+    filter: Arc<BloomFilter>,
+    store: Option<ConfirmationStore>,
+    /// Keys forgotten since they were inserted, which the confirmation store didn't record,
+    /// as the filter can't drop a key. At most `FORGOTTEN_LIMIT`, the oldest are dropped.
+    forgotten: HashSet<u64>,
+    /// The forgotten keys, oldest first, including some inserted again since
+    forgotten_order: VecDeque<u64>,
+    /// The first I/O error of the confirmation store, the index can't be trusted after it
+    failure: Option<String>,
+}
+
+impl DedupIndex {
+    /// An index without keys, clearing its part of the confirmation directory
+    pub fn new(config: &DedupConfig) -> Self {
+        let mut index = Self {
+            filter: Arc::new(BloomFilter::new(
+                config.expected_items,
+                config.false_positive_rate,
+            )),
+            store: config.confirmation_dir.as_ref().map(|dir| {
+                let number = INDEXES.fetch_add(1, Ordering::Relaxed);
+                ConfirmationStore::new(dir.join(format!("index-{}", number)))
+            }),
+            forgotten: HashSet::new(),
+            forgotten_order: VecDeque::new(),
+            failure: None,
+        };
+        index.clear();
+        index
+    }
+
+    /// Whether `key` was inserted before. Without a confirmation store,
+    /// a new key is reported as seen with the configured false positive rate.
+    pub fn contains(&self, key: u64) -> Result<bool, Error> {
+        if let Some(failure) = &self.failure {
+            return Err(Error::DedupFailure(failure.clone()));
+        }
+        if !self.filter.contains(key) || self.forgotten.contains(&key) {
+            return Ok(false);
+        }
+        match &self.store {
+            Some(store) => store
+                .contains(key)
+                .map_err(|e| Error::DedupFailure(e.to_string())),
+            None => Ok(true),
+        }
+    }
+
+    /// Insert `key`, written to the confirmation store by the next `commit`
+    pub fn insert(&mut self, key: u64) {
+        self.forgotten.remove(&key);
+        Arc::make_mut(&mut self.filter).insert(key);
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.insert(key) {
+                self.failure.get_or_insert(e.to_string());
+            }
+        }
+    }
+
+    /// Write the keys inserted since the last commit to the confirmation store, e.g. once
+    /// their batch can't be rolled back anymore
+    pub fn commit(&mut self) {
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.commit() {
+                self.failure.get_or_insert(e.to_string());
+            }
+        }
+    }
+
+    /// Forget `key`, e.g. of a rolled back transaction. The confirmation store records it,
+    /// without one a key forgotten long ago may be reported as seen as a false positive is.
+    pub fn forget(&mut self, key: u64) {
+        if let Some(store) = &mut self.store {
+            match store.forget(key) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => {
+                    self.failure.get_or_insert(e.to_string());
+                    return;
+                }
+            }
+        }
+        if !self.forgotten.insert(key) {
+            return;
+        }
+        self.forgotten_order.push_back(key);
+        if self.forgotten.len() > FORGOTTEN_LIMIT {
+            while let Some(oldest) = self.forgotten_order.pop_front() {
+                if self.forgotten.remove(&oldest) {
+                    break;
+                }
+            }
+        }
+        // Drop the keys inserted again since they were forgotten
+        if self.forgotten_order.len() > 2 * FORGOTTEN_LIMIT {
+            let forgotten = &self.forgotten;
+            self.forgotten_order.retain(|key| forgotten.contains(key));
+        }
+    }
+
+    /// Forget all keys, including the ones in the confirmation store
+    pub fn clear(&mut self) {
+        Arc::make_mut(&mut self.filter).clear();
+        self.forgotten.clear();
+        self.forgotten_order.clear();
+        self.failure = None;
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.clear() {
+                self.failure = Some(e.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{hash, DedupConfig, DedupIndex, FORGOTTEN_LIMIT, INITIAL_SLOTS};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("payments-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        let mut index = DedupIndex::new(&DedupConfig {
+            expected_items: 1000,
+            ..DedupConfig::default()
+        });
+        for key in 0..1000 {
+            assert_eq!(index.contains(key), Ok(false));
+            index.insert(key);
+        }
+        assert!((0..1000).all(|key| index.contains(key) == Ok(true)));
+
+        index.clear();
+        assert_eq!(index.contains(1), Ok(false));
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let mut index = DedupIndex::new(&DedupConfig {
+            expected_items: 10_000,
+            false_positive_rate: 0.01,
+            confirmation_dir: None,
+        });
+        (0..10_000).for_each(|key| index.insert(key));
+        let false_positives = (10_000..20_000)
+            .filter(|&key| index.contains(key) == Ok(true))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn confirmation_store() {
+        let dir = temp_dir("dedup");
+        let config = DedupConfig {
+            // Overloaded filter, nearly every lookup is a hit
+            expected_items: 10,
+            false_positive_rate: 0.5,
+            confirmation_dir: Some(dir.clone()),
+        };
+        let mut index = DedupIndex::new(&config);
+        (0..500).for_each(|key| index.insert(key));
+        index.commit();
+        // Pending until the next commit
+        (500..1000).for_each(|key| index.insert(key));
+        assert!((0..1000).all(|key| index.contains(key) == Ok(true)));
+        assert!((1000..2000).all(|key| index.contains(key) == Ok(false)));
+
+        index.clear();
+        assert_eq!(index.contains(1), Ok(false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn forget() {
+        let dir = temp_dir("dedup-forget");
+        let mut index = DedupIndex::new(&DedupConfig {
+            confirmation_dir: Some(dir.clone()),
+            ..DedupConfig::default()
+        });
+        (0..100).for_each(|key| index.insert(key));
+        index.commit();
+        (100..200).for_each(|key| index.insert(key));
+        index.forget(7);
+        index.forget(107);
+        assert_eq!(index.contains(7), Ok(false));
+        assert_eq!(index.contains(107), Ok(false));
+        assert_eq!(index.contains(8), Ok(true));
+        // Recorded in the store rather than in memory
+        assert!(index.forgotten.is_empty());
+        index.insert(7);
+        index.commit();
+        assert_eq!(index.contains(7), Ok(true));
+        assert_eq!(index.contains(107), Ok(false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn forgotten_keys_without_store_are_bounded() {
+        let mut index = DedupIndex::new(&DedupConfig::default());
+        let keys = FORGOTTEN_LIMIT as u64 + 10;
+        for key in 0..keys {
+            index.insert(key);
+            index.forget(key);
+        }
+        assert_eq!(index.forgotten.len(), FORGOTTEN_LIMIT);
+        assert_eq!(index.contains(keys - 1), Ok(false));
+        // The oldest are possible false positives again
+        assert_eq!(index.contains(0), Ok(true));
+    }
+
+    #[test]
+    fn table_grows() {
+        let dir = temp_dir("dedup-grow");
+        let mut index = DedupIndex::new(&DedupConfig {
+            expected_items: 10,
+            false_positive_rate: 0.5,
+            confirmation_dir: Some(dir.clone()),
+        });
+        let keys = INITIAL_SLOTS;
+        for chunk in (0..keys).collect::<Vec<_>>().chunks(1000) {
+            chunk.iter().for_each(|&key| index.insert(key));
+            index.commit();
+        }
+        assert!(index.store.as_ref().unwrap().slots > INITIAL_SLOTS);
+        assert!((0..keys).all(|key| index.contains(key) == Ok(true)));
+        assert!((keys..keys + 1000).all(|key| index.contains(key) == Ok(false)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn index_directories_are_removed() {
+        let dir = temp_dir("dedup-remove");
+        let config = DedupConfig {
+            confirmation_dir: Some(dir.clone()),
+            ..DedupConfig::default()
+        };
+        let mut index = DedupIndex::new(&config);
+        index.insert(1);
+        index.commit();
+        let fork = index.clone();
+        drop(index);
+        // Still read by the clone
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        drop(fork);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let mut index = DedupIndex::new(&config);
+        index.insert(1);
+        index.commit();
+        index.clear();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stable_hash() {
+        // Filters and tables must not depend on the build
+        assert_eq!(hash(42, 1), 0x8f94_ef2a_5d59_ed4e);
+    }
+
+    #[test]
+    fn indexes_sharing_a_directory() {
+        let dir = temp_dir("dedup-shared");
+        let config = DedupConfig {
+            expected_items: 10,
+            false_positive_rate: 0.5,
+            confirmation_dir: Some(dir.clone()),
+        };
+        let mut first = DedupIndex::new(&config);
+        (0..100).for_each(|key| first.insert(key));
+        first.commit();
+        let mut second = DedupIndex::new(&config);
+        assert!((0..100).all(|key| second.contains(key) == Ok(false)));
+        second.insert(100);
+        assert_eq!(first.contains(100), Ok(false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn clone_leaves_files_to_original() {
+        let dir = temp_dir("dedup-clone");
+        let mut index = DedupIndex::new(&DedupConfig {
+            expected_items: 10,
+            false_positive_rate: 0.5,
+            confirmation_dir: Some(dir.clone()),
+        });
+        (0..50).for_each(|key| index.insert(key));
+        index.commit();
+        (50..100).for_each(|key| index.insert(key));
+        let mut fork = index.clone();
+        (100..200).for_each(|key| fork.insert(key));
+        assert!((0..200).all(|key| fork.contains(key) == Ok(true)));
+        assert!((100..200).all(|key| index.contains(key) == Ok(false)));
+
+        fork.clear();
+        assert_eq!(fork.contains(1), Ok(false));
+        assert_eq!(index.contains(1), Ok(true));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,7 +1,15 @@
+use chrono::NaiveDate;
 use rust_decimal::Decimal;
 use thiserror::Error;
 
-use crate::{client::OperationState, transaction::TransactionId};
+use crate::{
+    approval::OperatorId,
+    client::{ClientId, OperationState},
+    counterparty::Counterparty,
+    money::Currency,
+    redact::Redacted,
+    transaction::{BatchId, TransactionId},
+};
 
 #[derive(Error, Debug, PartialEq)]
 pub enum Error {
@@ -11,12 +19,19 @@ pub enum Error {
     DuplicatedTransaction(TransactionId),
     #[error("transaction ID `{0}` (for Dispute/Resolve/ChargeBack) not found")]
     TransactionNotFound(TransactionId),
-    #[error("withdrawal transaction ID `{id:?}` of {requested:?} failed because of insufficient funds: {available:?}")]
+    #[error("withdrawal transaction ID `{id:?}` of {:?} failed because of insufficient funds: {:?}", Redacted(.requested), Redacted(.available))]
     InsufficientFunds {
         id: TransactionId,
         available: Decimal,
         requested: Decimal,
     },
+    #[error("withdrawal transaction ID `{id}` of {} failed as it would leave {} available, below the reserve of {}", Redacted(.requested), Redacted(.available), Redacted(.reserve))]
+    BelowReserve {
+        id: TransactionId,
+        available: Decimal,
+        requested: Decimal,
+        reserve: Decimal,
+    },
     #[error("invalid transaction state transition for ID `{id:?}` ({from:?} -> {to:?})")]
     InvalidTransactionStateChange {
         id: TransactionId,
@@ -25,9 +40,148 @@ pub enum Error {
     },
     #[error("transaction ID `{0}` was tried on a locked account")]
     AccountLocked(TransactionId),
+    #[error("client `{0}` not found")]
+    ClientNotFound(ClientId),
+    #[error("account of client `{0}` is not locked")]
+    AccountNotLocked(ClientId),
+    #[error("operator `{operator}` can't approve an action on client `{client}` they asked for")]
+    SameOperator {
+        client: ClientId,
+        operator: OperatorId,
+    },
+    #[error("action on client `{client}` is about {}, more than the {} asked for", Redacted(.amount), Redacted(.requested))]
+    ApprovalExceeded {
+        client: ClientId,
+        requested: Decimal,
+        amount: Decimal,
+    },
+    #[error("client `{0}` is not quarantined")]
+    NotQuarantined(ClientId),
+    #[error("account of client `{0}` has no negative balance to write off")]
+    BalanceNotNegative(ClientId),
+    #[error("transactions of client `{0}` are blocked")]
+    ClientBlocked(ClientId),
+    #[error("transaction ID `{0}` was tried on a closed account")]
+    AccountClosed(TransactionId),
+    #[error("account of client `{0}` has funds and can't be closed")]
+    AccountNotEmpty(ClientId),
+    #[error("hash chain of the log is broken at record `{0}`")]
+    BrokenChain(u64),
+    #[error(
+        "amount of transaction ID `{0}` must be non-negative, at most 10^15 and with at most four decimal places"
+    )]
+    InvalidAmount(TransactionId),
+    #[error("transaction ID `{0}` can't be amended as it was disputed")]
+    AmendmentOfDisputed(TransactionId),
+    #[error("transaction ID `{0}` can't be amended before its funds are settled")]
+    AmendmentOfPending(TransactionId),
+    #[error("snapshot version `{0}` is newer than this version of payments supports")]
+    UnsupportedSnapshotVersion(u32),
 
     #[error(
         "failed to dispute transaction ID `{0}` as it would result in negative account balance"
     )]
     FailedDisputeNotEnoughFunds(TransactionId),
+    #[error("transaction ID `{id}` references unknown transaction ID `{ref_tx}`")]
+    InvalidReference {
+        id: TransactionId,
+        ref_tx: TransactionId,
+    },
+    #[error("withdrawal transaction ID `{id}` blocked, client's risk score {} is too high", Redacted(.score))]
+    RiskScoreExceeded { id: TransactionId, score: f64 },
+    #[error("duplicate transaction detection failed: {0}")]
+    DedupFailure(String),
+    #[error("memory usage of {used} bytes exceeds the limit of {limit} bytes")]
+    MemoryLimitExceeded { used: usize, limit: usize },
+    #[error("transaction ID `{0}` rejected as its source or client is over the rate limit")]
+    RateLimited(TransactionId),
+    #[error("transaction ID `{0}` has a missing or invalid signature")]
+    InvalidSignature(TransactionId),
+    #[error("input file `{0}` has no checksum file")]
+    ChecksumMissing(String),
+    #[error("checksum of input file `{name}` is {actual}, expected {expected}")]
+    ChecksumMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("input file `{name}` was already processed as `{processed_as}`")]
+    DuplicateFile { name: String, processed_as: String },
+    #[error("sealed state `{0}` is incomplete or was modified")]
+    BrokenSeal(String),
+    #[error("batch `{batch}` rolled back, reason: {reason}")]
+    BatchRolledBack { batch: BatchId, reason: Box<Error> },
+    #[error("transaction ID `{id}` skipped as batch `{batch}` was rolled back")]
+    BatchAborted { batch: BatchId, id: TransactionId },
+    #[error("transaction ID `{tx}` of client `{client}` rejected, reason: {reason}")]
+    Rejected {
+        client: ClientId,
+        tx: TransactionId,
+        reason: Box<Error>,
+    },
+    #[error("no FX rate of {from}/{to} as of {date}")]
+    FxRateNotFound {
+        from: String,
+        to: String,
+        date: NaiveDate,
+    },
+    #[error("no bank account of counterparty `{}`", Redacted(.0))]
+    BankAccountNotFound(Counterparty),
+    #[error("{0} is out of range")]
+    Overflow(String),
+    #[error("i/o failure: {0}")]
+    Io(String),
+    #[error("amount in {actual} where {expected} is expected")]
+    CurrencyMismatch {
+        expected: Currency,
+        actual: Currency,
+    },
+}
+
+impl Error {
+    /// A stable name of the kind of error, e.g. to count rejections by it
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::ParsingFailure(_) => "parsing_failure",
+            Error::DuplicatedTransaction(_) => "duplicated_transaction",
+            Error::TransactionNotFound(_) => "transaction_not_found",
+            Error::InsufficientFunds { .. } => "insufficient_funds",
+            Error::BelowReserve { .. } => "below_reserve",
+            Error::InvalidTransactionStateChange { .. } => "invalid_transaction_state_change",
+            Error::AccountLocked(_) => "account_locked",
+            Error::ClientNotFound(_) => "client_not_found",
+            Error::AccountNotLocked(_) => "account_not_locked",
+            Error::NotQuarantined(_) => "not_quarantined",
+            Error::SameOperator { .. } => "same_operator",
+            Error::ApprovalExceeded { .. } => "approval_exceeded",
+            Error::BalanceNotNegative(_) => "balance_not_negative",
+            Error::ClientBlocked(_) => "client_blocked",
+            Error::AccountClosed(_) => "account_closed",
+            Error::AccountNotEmpty(_) => "account_not_empty",
+            Error::BrokenChain(_) => "broken_chain",
+            Error::InvalidAmount(_) => "invalid_amount",
+            Error::AmendmentOfDisputed(_) => "amendment_of_disputed",
+            Error::AmendmentOfPending(_) => "amendment_of_pending",
+            Error::UnsupportedSnapshotVersion(_) => "unsupported_snapshot_version",
+            Error::FailedDisputeNotEnoughFunds(_) => "failed_dispute_not_enough_funds",
+            Error::InvalidReference { .. } => "invalid_reference",
+            Error::RiskScoreExceeded { .. } => "risk_score_exceeded",
+            Error::DedupFailure(_) => "dedup_failure",
+            Error::MemoryLimitExceeded { .. } => "memory_limit_exceeded",
+            Error::RateLimited(_) => "rate_limited",
+            Error::InvalidSignature(_) => "invalid_signature",
+            Error::ChecksumMissing(_) => "checksum_missing",
+            Error::ChecksumMismatch { .. } => "checksum_mismatch",
+            Error::DuplicateFile { .. } => "duplicate_file",
+            Error::BrokenSeal(_) => "broken_seal",
+            Error::BatchRolledBack { .. } => "batch_rolled_back",
+            Error::BatchAborted { .. } => "batch_aborted",
+            Error::Rejected { .. } => "rejected",
+            Error::FxRateNotFound { .. } => "fx_rate_not_found",
+            Error::BankAccountNotFound(_) => "bank_account_not_found",
+            Error::Overflow(_) => "overflow",
+            Error::Io(_) => "io",
+            Error::CurrencyMismatch { .. } => "currency_mismatch",
+        }
+    }
 }
@@ -0,0 +1,263 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    approval::{AdminAction, OperatorId},
+    client::ClientId,
+    counterparty::Counterparty,
+    subaccount::SubAccount,
+    transaction::{OperationType, Timestamp, TransactionId},
+};
+
+/// Domain events emitted by `Client::apply`.
+/// They are the single source of truth of a client's state - balances,
+/// operations and their dispute states are derived by folding events
+/// with `Client::evolve`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    /// A client created by a failed first transaction, which is kept, see
+    /// `Config::create_clients_on_success`. Other clients are created by their first event.
+    ClientCreated,
+    FundsDeposited {
+        tx: TransactionId,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ref_tx: Option<TransactionId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Counterparty>,
+    },
+    FundsWithdrawn {
+        tx: TransactionId,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ref_tx: Option<TransactionId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Counterparty>,
+    },
+    /// With `pending`, the funds of a pending deposit are held instead of available ones
+    FundsHeld {
+        tx: TransactionId,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pending: bool,
+    },
+    /// With `pending`, the funds are released back to pending, see `FundsHeld`
+    FundsReleased {
+        tx: TransactionId,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pending: bool,
+    },
+    FundsChargedBack {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    AccountLocked {
+        tx: TransactionId,
+    },
+    /// Lifted by an operator, see `Payments::unlock`
+    AccountUnlocked,
+    /// By an operator, see `Payments::close_account`
+    AccountClosed,
+    /// A charged back withdrawal credited back to the client
+    WithdrawalReversed {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    /// A charged back withdrawal booked as a loss of the house, not the client's
+    WithdrawalWrittenOff {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    /// Funds moved between the client's accounts, `None` being the main one
+    FundsTransferred {
+        tx: TransactionId,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<SubAccount>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<SubAccount>,
+    },
+    /// The amount of the deposit or withdrawal `tx` corrected to `amount`, changing the
+    /// available and total funds by `delta`
+    TransactionAmended {
+        tx: TransactionId,
+        amount: Decimal,
+        delta: Decimal,
+    },
+    /// The funds of deposit `tx` set aside until its value date or settlement, counted in the
+    /// total but not available, see `Payments::apply`. Without `until`, until it's settled.
+    FundsPending {
+        tx: TransactionId,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<Timestamp>,
+    },
+    /// The pending funds of deposit `tx` made available
+    FundsCleared {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    /// A negative balance zeroed by an operator and booked as a loss of the house,
+    /// see `Payments::write_off`
+    BalanceWrittenOff {
+        amount: Decimal,
+    },
+    /// Posted at the close of a day, see `close`. With a `tx`, the fee can be disputed.
+    FeeCharged {
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tx: Option<TransactionId>,
+    },
+    /// A charged back fee `tx` refunded to the client
+    FeeRefunded {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    /// The funds of a charged back transfer `tx` credited back to the account they were
+    /// transferred from
+    TransferReversed {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    /// What the dispute of a charged back transfer couldn't hold, booked as a loss of the
+    /// house, see `DisputePolicy`
+    TransferWrittenOff {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    InterestPaid {
+        amount: Decimal,
+    },
+    /// Transactions of the client are parked from now on, see `quarantine`. With a `tx`, by
+    /// the rule the transaction triggered, by an operator otherwise.
+    ClientQuarantined {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tx: Option<TransactionId>,
+    },
+    /// By an operator, releasing the parked transactions, see `Payments::lift_quarantine`
+    QuarantineLifted,
+    /// Transaction `tx` of a quarantined client accepted, but not applied. Carries what's
+    /// needed to apply it once released, see `quarantine`; events recorded before it did
+    /// have none, so their transactions can't be released.
+    TransactionParked {
+        tx: TransactionId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        operation: Option<OperationType>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value_date: Option<NaiveDate>,
+    },
+    /// By an operator, the parked transaction `tx` taken to be applied, keeping the
+    /// quarantine, see `Payments::release`
+    TransactionReleased {
+        tx: TransactionId,
+    },
+    /// An operator action above `Config::approval_threshold`, waiting for the approval of
+    /// another operator, see `approval`
+    ApprovalRequested {
+        #[serde(flatten)]
+        action: AdminAction,
+        amount: Decimal,
+    },
+    /// The pending action taken, approved by another operator
+    ActionApproved {
+        #[serde(flatten)]
+        action: AdminAction,
+    },
+}
+
+impl Event {
+    /// The transaction which caused the event, none for operator actions and postings of the
+    /// day's close other than disputable fees
+    pub fn tx(&self) -> Option<TransactionId> {
+        match *self {
+            Event::FundsDeposited { tx, .. }
+            | Event::FundsWithdrawn { tx, .. }
+            | Event::FundsHeld { tx, .. }
+            | Event::FundsReleased { tx, .. }
+            | Event::FundsChargedBack { tx, .. }
+            | Event::AccountLocked { tx }
+            | Event::WithdrawalReversed { tx, .. }
+            | Event::WithdrawalWrittenOff { tx, .. }
+            | Event::FundsTransferred { tx, .. }
+            | Event::TransactionAmended { tx, .. }
+            | Event::FundsPending { tx, .. }
+            | Event::FundsCleared { tx, .. }
+            | Event::FeeRefunded { tx, .. }
+            | Event::TransferReversed { tx, .. }
+            | Event::TransferWrittenOff { tx, .. }
+            | Event::TransactionParked { tx, .. }
+            | Event::TransactionReleased { tx } => Some(tx),
+            Event::FeeCharged { tx, .. } | Event::ClientQuarantined { tx } => tx,
+            Event::ClientCreated
+            | Event::InterestPaid { .. }
+            | Event::QuarantineLifted
+            | Event::ApprovalRequested { .. }
+            | Event::ActionApproved { .. }
+            | Event::AccountUnlocked
+            | Event::AccountClosed
+            | Event::BalanceWrittenOff { .. } => None,
+        }
+    }
+
+    /// Changes of the available, held and total funds. Pending funds are the difference of
+    /// the total and the other two.
+    pub fn balance_deltas(&self) -> (Decimal, Decimal, Decimal) {
+        let zero = Decimal::ZERO;
+        match *self {
+            Event::FundsDeposited { amount, .. } => (amount, zero, amount),
+            Event::FundsWithdrawn { amount, .. } => (-amount, zero, -amount),
+            Event::FundsHeld {
+                amount,
+                pending: false,
+                ..
+            } => (-amount, amount, zero),
+            Event::FundsReleased {
+                amount,
+                pending: false,
+                ..
+            } => (amount, -amount, zero),
+            Event::FundsHeld { amount, .. } => (zero, amount, zero),
+            Event::FundsReleased { amount, .. } => (zero, -amount, zero),
+            Event::FundsChargedBack { amount, .. } => (zero, -amount, -amount),
+            Event::WithdrawalReversed { amount, .. }
+            | Event::InterestPaid { amount }
+            | Event::BalanceWrittenOff { amount }
+            | Event::FeeRefunded { amount, .. }
+            | Event::TransferReversed { amount, .. } => (amount, zero, amount),
+            Event::FeeCharged { amount, .. } => (-amount, zero, -amount),
+            Event::TransactionAmended { delta, .. } => (delta, zero, delta),
+            Event::FundsPending { amount, .. } => (-amount, zero, zero),
+            Event::FundsCleared { amount, .. } => (amount, zero, zero),
+            Event::ClientCreated
+            | Event::AccountLocked { .. }
+            | Event::AccountUnlocked
+            | Event::AccountClosed
+            | Event::WithdrawalWrittenOff { .. }
+            | Event::TransferWrittenOff { .. }
+            | Event::FundsTransferred { .. }
+            | Event::ClientQuarantined { .. }
+            | Event::QuarantineLifted
+            | Event::TransactionParked { .. }
+            | Event::TransactionReleased { .. }
+            | Event::ApprovalRequested { .. }
+            | Event::ActionApproved { .. } => (zero, zero, zero),
+        }
+    }
+}
+
+/// An entry of the `Payments` event log.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClientEvent {
+    pub client: ClientId,
+    /// Timestamp of the transaction which caused the event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    /// Who caused the event, if it was an operator, see `Payments::act`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<OperatorId>,
+    #[serde(flatten)]
+    pub event: Event,
+}
@@ -0,0 +1,572 @@
+//! Feature vectors for training fraud models, derived from the event log.
+//!
+//! Two tables are produced:
+//! - one row per client: activity aggregates, dispute/chargeback ratios and recency,
+//! - one row per deposit/withdrawal: the amount in the context of the client's history
+//!   up to that moment, labelled with whether it was later disputed or charged back.
+use std::{collections::HashMap, io::Write};
+
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::Serialize;
+
+use crate::{
+    client::{Client, ClientId},
+    event::{ClientEvent, Event},
+    transaction::{Timestamp, TransactionId},
+};
+
+/// Output format of the feature tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl Format {
+    /// Pick the format by file extension, CSV unless it's `.parquet`
+    pub fn from_path(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.ends_with(".parquet") {
+            return Ok(Format::Csv);
+        }
+        #[cfg(feature = "parquet")]
+        return Ok(Format::Parquet);
+        #[cfg(not(feature = "parquet"))]
+        Err("Parquet output requires building with `--features parquet`".into())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientFeatures {
+    pub client: ClientId,
+    pub deposits: u32,
+    pub withdrawals: u32,
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub mean_amount: Decimal,
+    pub max_amount: Decimal,
+    pub disputes: u32,
+    pub chargebacks: u32,
+    /// Disputes per deposit/withdrawal
+    pub dispute_rate: f64,
+    /// Chargebacks per dispute
+    pub chargeback_ratio: f64,
+    pub first_seen: Option<Timestamp>,
+    pub last_seen: Option<Timestamp>,
+    /// Whole days from the last activity to the latest timestamp seen
+    pub days_since_last_seen: Option<i64>,
+    pub locked: bool,
+}
+
+impl ClientFeatures {
+    fn new(client: ClientId) -> Self {
+        Self {
+            client,
+            deposits: 0,
+            withdrawals: 0,
+            deposited: Decimal::ZERO,
+            withdrawn: Decimal::ZERO,
+            mean_amount: Decimal::ZERO,
+            max_amount: Decimal::ZERO,
+            disputes: 0,
+            chargebacks: 0,
+            dispute_rate: 0.0,
+            chargeback_ratio: 0.0,
+            first_seen: None,
+            last_seen: None,
+            days_since_last_seen: None,
+            locked: false,
+        }
+    }
+
+    fn transactions(&self) -> u32 {
+        self.deposits + self.withdrawals
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransactionFeatures {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub amount: Decimal,
+    pub timestamp: Option<Timestamp>,
+    /// Number of the client's deposits/withdrawals before this one
+    pub previous_transactions: u32,
+    /// The amount relative to the mean of the client's previous amounts
+    pub amount_to_mean: Option<f64>,
+    pub seconds_since_previous: Option<i64>,
+    pub available_before: Decimal,
+    /// Labels: what happened to the transaction later on
+    pub disputed: bool,
+    pub charged_back: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Features {
+    /// Sorted by client ID
+    pub clients: Vec<ClientFeatures>,
+    /// In the order the transactions were applied
+    pub transactions: Vec<TransactionFeatures>,
+}
+
+impl Features {
+    /// Fold the event log into feature rows, measuring recency up to `now`
+    pub fn extract(events: &[ClientEvent], now: Option<Timestamp>) -> Self {
+        let mut balances = HashMap::<ClientId, Client>::new();
+        let mut clients = HashMap::<ClientId, ClientFeatures>::new();
+        let mut transactions = Vec::<TransactionFeatures>::new();
+        let mut rows = HashMap::<(ClientId, TransactionId), usize>::new();
+
+        for event in events {
+            let balance = balances
+                .entry(event.client)
+                .or_insert_with(|| Client::new(event.client));
+            let features = clients
+                .entry(event.client)
+                .or_insert_with(|| ClientFeatures::new(event.client));
+
+            match event.event {
+                Event::FundsDeposited { tx, amount, .. }
+                | Event::FundsWithdrawn { tx, amount, .. } => {
+                    let previous = features.transactions();
+                    let kind = if matches!(event.event, Event::FundsDeposited { .. }) {
+                        features.deposits += 1;
+                        features.deposited += amount;
+                        "deposit"
+                    } else {
+                        features.withdrawals += 1;
+                        features.withdrawn += amount;
+                        "withdrawal"
+                    };
+                    // Disputes of an ID shared by a deposit and a withdrawal are of the deposit
+                    let row = rows.entry((event.client, tx)).or_insert(transactions.len());
+                    if kind == "deposit" {
+                        *row = transactions.len();
+                    }
+                    transactions.push(TransactionFeatures {
+                        client: event.client,
+                        tx,
+                        kind,
+                        amount,
+                        timestamp: event.timestamp,
+                        previous_transactions: previous,
+                        amount_to_mean: (previous > 0)
+                            .then(|| ratio(amount, features.mean_amount))
+                            .flatten(),
+                        seconds_since_previous: event
+                            .timestamp
+                            .zip(features.last_seen)
+                            .map(|(now, last)| (now - last).num_seconds()),
+                        available_before: balance.available(),
+                        disputed: false,
+                        charged_back: false,
+                    });
+                    features.mean_amount = (features.deposited + features.withdrawn)
+                        / Decimal::from(features.transactions());
+                    features.max_amount = features.max_amount.max(amount);
+                }
+                Event::FundsHeld { tx, .. } => {
+                    features.disputes += 1;
+                    if let Some(&row) = rows.get(&(event.client, tx)) {
+                        transactions[row].disputed = true;
+                    }
+                }
+                Event::FundsChargedBack { tx, .. } => {
+                    features.chargebacks += 1;
+                    if let Some(&row) = rows.get(&(event.client, tx)) {
+                        transactions[row].charged_back = true;
+                    }
+                }
+                // Features of the amount as corrected
+                Event::TransactionAmended { tx, amount, .. } => {
+                    if let Some(&row) = rows.get(&(event.client, tx)) {
+                        transactions[row].amount = amount;
+                    }
+                }
+                Event::FundsReleased { .. }
+                | Event::AccountLocked { .. }
+                | Event::AccountUnlocked
+                | Event::AccountClosed
+                | Event::WithdrawalReversed { .. }
+                | Event::WithdrawalWrittenOff { .. }
+                | Event::BalanceWrittenOff { .. }
+                | Event::FeeCharged { .. }
+                | Event::FeeRefunded { .. }
+                | Event::TransferReversed { .. }
+                | Event::TransferWrittenOff { .. }
+                | Event::InterestPaid { .. }
+                | Event::FundsTransferred { .. }
+                | Event::FundsPending { .. }
+                | Event::FundsCleared { .. }
+                | Event::ClientQuarantined { .. }
+                | Event::QuarantineLifted
+                | Event::TransactionParked { .. }
+                | Event::TransactionReleased { .. }
+                | Event::ClientCreated
+                | Event::ApprovalRequested { .. }
+                | Event::ActionApproved { .. } => {}
+            }
+            if event.timestamp.is_some() {
+                features.first_seen = features.first_seen.or(event.timestamp);
+                features.last_seen = event.timestamp;
+            }
+            // Only the lock is read, whatever the balances
+            let _ = balance.evolve(&event.event);
+            features.locked = balance.locked();
+        }
+
+        let mut clients = clients.into_values().collect::<Vec<_>>();
+        clients.sort_by_key(|c| c.client);
+        for features in &mut clients {
+            let rate = |count: u32, total: u32| f64::from(count) / f64::from(total.max(1));
+            features.dispute_rate = rate(features.disputes, features.transactions());
+            features.chargeback_ratio = rate(features.chargebacks, features.disputes);
+            features.days_since_last_seen = features
+                .last_seen
+                .zip(now)
+                .map(|(last, now)| (now - last).num_days());
+        }
+        Self {
+            clients,
+            transactions,
+        }
+    }
+
+    /// Write the per-client table
+    pub fn write_clients(
+        &self,
+        format: Format,
+        output: impl Write + Send,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match format {
+            Format::Csv => write_csv(&self.clients, output),
+            #[cfg(feature = "parquet")]
+            Format::Parquet => parquet::write(parquet::clients(&self.clients)?, output),
+        }
+    }
+
+    /// Write the per-transaction table
+    pub fn write_transactions(
+        &self,
+        format: Format,
+        output: impl Write + Send,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match format {
+            Format::Csv => write_csv(&self.transactions, output),
+            #[cfg(feature = "parquet")]
+            Format::Parquet => parquet::write(parquet::transactions(&self.transactions)?, output),
+        }
+    }
+}
+
+fn ratio(amount: Decimal, mean: Decimal) -> Option<f64> {
+    amount.checked_div(mean)?.to_f64()
+}
+
+fn write_csv<T: Serialize>(
+    rows: &[T],
+    output: impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(output);
+    for row in rows {
+        writer.serialize(row)?
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+mod parquet {
+    use std::{io::Write, sync::Arc};
+
+    use arrow_array::{
+        ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+        TimestampMicrosecondArray, UInt16Array, UInt32Array,
+    };
+    use parquet::arrow::ArrowWriter;
+    use rust_decimal::{prelude::ToPrimitive, Decimal};
+
+    use super::{ClientFeatures, TransactionFeatures};
+    use crate::transaction::Timestamp;
+
+    // Models consume amounts as floats
+    fn amounts<T>(rows: &[T], field: impl Fn(&T) -> Decimal) -> ArrayRef {
+        Arc::new(Float64Array::from_iter(
+            rows.iter().map(|row| field(row).to_f64()),
+        ))
+    }
+
+    fn timestamps<T>(rows: &[T], field: impl Fn(&T) -> Option<Timestamp>) -> ArrayRef {
+        Arc::new(
+            TimestampMicrosecondArray::from_iter(
+                rows.iter()
+                    .map(|row| field(row).map(|t| t.timestamp_micros())),
+            )
+            .with_timezone("UTC"),
+        )
+    }
+
+    fn counts<T>(rows: &[T], field: impl Fn(&T) -> u32) -> ArrayRef {
+        Arc::new(UInt32Array::from_iter_values(rows.iter().map(field)))
+    }
+
+    pub(super) fn clients(
+        rows: &[ClientFeatures],
+    ) -> Result<RecordBatch, Box<dyn std::error::Error>> {
+        Ok(RecordBatch::try_from_iter([
+            (
+                "client",
+                Arc::new(UInt16Array::from_iter_values(rows.iter().map(|r| r.client))) as ArrayRef,
+            ),
+            ("deposits", counts(rows, |r| r.deposits)),
+            ("withdrawals", counts(rows, |r| r.withdrawals)),
+            ("deposited", amounts(rows, |r| r.deposited)),
+            ("withdrawn", amounts(rows, |r| r.withdrawn)),
+            ("mean_amount", amounts(rows, |r| r.mean_amount)),
+            ("max_amount", amounts(rows, |r| r.max_amount)),
+            ("disputes", counts(rows, |r| r.disputes)),
+            ("chargebacks", counts(rows, |r| r.chargebacks)),
+            (
+                "dispute_rate",
+                Arc::new(Float64Array::from_iter_values(
+                    rows.iter().map(|r| r.dispute_rate),
+                )),
+            ),
+            (
+                "chargeback_ratio",
+                Arc::new(Float64Array::from_iter_values(
+                    rows.iter().map(|r| r.chargeback_ratio),
+                )),
+            ),
+            ("first_seen", timestamps(rows, |r| r.first_seen)),
+            ("last_seen", timestamps(rows, |r| r.last_seen)),
+            (
+                "days_since_last_seen",
+                Arc::new(Int64Array::from_iter(
+                    rows.iter().map(|r| r.days_since_last_seen),
+                )),
+            ),
+            (
+                "locked",
+                Arc::new(BooleanArray::from_iter(rows.iter().map(|r| Some(r.locked)))),
+            ),
+        ])?)
+    }
+
+    pub(super) fn transactions(
+        rows: &[TransactionFeatures],
+    ) -> Result<RecordBatch, Box<dyn std::error::Error>> {
+        Ok(RecordBatch::try_from_iter([
+            (
+                "client",
+                Arc::new(UInt16Array::from_iter_values(rows.iter().map(|r| r.client))) as ArrayRef,
+            ),
+            ("tx", counts(rows, |r| r.tx)),
+            (
+                "type",
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.kind))),
+            ),
+            ("amount", amounts(rows, |r| r.amount)),
+            ("timestamp", timestamps(rows, |r| r.timestamp)),
+            (
+                "previous_transactions",
+                counts(rows, |r| r.previous_transactions),
+            ),
+            (
+                "amount_to_mean",
+                Arc::new(Float64Array::from_iter(
+                    rows.iter().map(|r| r.amount_to_mean),
+                )),
+            ),
+            (
+                "seconds_since_previous",
+                Arc::new(Int64Array::from_iter(
+                    rows.iter().map(|r| r.seconds_since_previous),
+                )),
+            ),
+            ("available_before", amounts(rows, |r| r.available_before)),
+            (
+                "disputed",
+                Arc::new(BooleanArray::from_iter(
+                    rows.iter().map(|r| Some(r.disputed)),
+                )),
+            ),
+            (
+                "charged_back",
+                Arc::new(BooleanArray::from_iter(
+                    rows.iter().map(|r| Some(r.charged_back)),
+                )),
+            ),
+        ])?)
+    }
+
+    pub(super) fn write(
+        batch: RecordBatch,
+        output: impl Write + Send,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = ArrowWriter::try_new(output, batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    use super::{Features, Format};
+    use crate::event::{ClientEvent, Event};
+
+    fn events() -> Vec<ClientEvent> {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let at = |hours| Some(start + Duration::hours(hours));
+        [
+            (
+                1,
+                at(0),
+                Event::FundsDeposited {
+                    tx: 1,
+                    amount: dec!(10),
+                    ref_tx: None,
+                    counterparty: None,
+                },
+            ),
+            (
+                1,
+                at(1),
+                Event::FundsDeposited {
+                    tx: 2,
+                    amount: dec!(30),
+                    ref_tx: None,
+                    counterparty: None,
+                },
+            ),
+            (
+                2,
+                at(2),
+                Event::FundsDeposited {
+                    tx: 3,
+                    amount: dec!(5),
+                    ref_tx: None,
+                    counterparty: None,
+                },
+            ),
+            (
+                1,
+                at(3),
+                Event::FundsWithdrawn {
+                    tx: 4,
+                    amount: dec!(40),
+                    ref_tx: None,
+                    counterparty: None,
+                },
+            ),
+            (
+                2,
+                at(4),
+                Event::FundsHeld {
+                    tx: 3,
+                    amount: dec!(5),
+                    pending: false,
+                },
+            ),
+            (
+                2,
+                at(5),
+                Event::FundsChargedBack {
+                    tx: 3,
+                    amount: dec!(5),
+                },
+            ),
+            (2, at(5), Event::AccountLocked { tx: 3 }),
+        ]
+        .into_iter()
+        .map(|(client, timestamp, event)| ClientEvent {
+            client,
+            timestamp,
+            operator: None,
+            event,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn client_features() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 3, 12, 0, 0).unwrap();
+        let features = Features::extract(&events(), Some(now));
+        assert_eq!(features.clients.len(), 2);
+
+        let first = &features.clients[0];
+        assert_eq!((first.deposits, first.withdrawals), (2, 1));
+        assert_eq!((first.deposited, first.withdrawn), (dec!(40), dec!(40)));
+        assert_eq!(first.mean_amount, dec!(80) / dec!(3));
+        assert_eq!(first.max_amount, dec!(40));
+        assert_eq!(first.days_since_last_seen, Some(2));
+        assert!(!first.locked);
+
+        let second = &features.clients[1];
+        assert_eq!((second.disputes, second.chargebacks), (1, 1));
+        assert_eq!((second.dispute_rate, second.chargeback_ratio), (1.0, 1.0));
+        assert!(second.locked);
+    }
+
+    #[test]
+    fn transaction_features() {
+        let features = Features::extract(&events(), None);
+        let rows = &features.transactions;
+        assert_eq!(
+            rows.iter().map(|r| (r.tx, r.kind)).collect::<Vec<_>>(),
+            [
+                (1, "deposit"),
+                (2, "deposit"),
+                (3, "deposit"),
+                (4, "withdrawal")
+            ]
+        );
+
+        assert_eq!(rows[0].amount_to_mean, None);
+        assert_eq!(rows[0].seconds_since_previous, None);
+        assert_eq!(rows[1].previous_transactions, 1);
+        assert_eq!(rows[1].amount_to_mean, Some(3.0));
+        assert_eq!(rows[1].seconds_since_previous, Some(3600));
+        assert_eq!(rows[3].available_before, dec!(40));
+
+        assert!(rows[2].disputed && rows[2].charged_back);
+        assert!(!rows[3].disputed);
+    }
+
+    #[test]
+    fn csv_output() {
+        let features = Features::extract(&events()[..1], None);
+        let mut output = Vec::new();
+        features
+            .write_transactions(Format::Csv, &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,tx,type,amount,timestamp,previous_transactions,amount_to_mean,\
+             seconds_since_previous,available_before,disputed,charged_back\n\
+             1,1,deposit,10,2024-01-01T00:00:00Z,0,,,0,false,false\n"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_output() {
+        let features = Features::extract(&events(), None);
+        let (mut clients, mut transactions) = (Vec::new(), Vec::new());
+        features
+            .write_clients(Format::Parquet, &mut clients)
+            .unwrap();
+        features
+            .write_transactions(Format::Parquet, &mut transactions)
+            .unwrap();
+        for output in [clients, transactions] {
+            assert!(output.starts_with(b"PAR1") && output.ends_with(b"PAR1"));
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! C API for embedding the engine.
+//!
+//! Amounts cross the boundary as a fixed-point `PaymentsAmount` (`mantissa * 10^-scale`)
+//! so no precision is lost. Every call returns a `PaymentsStatus`, the engine itself
+//! is an opaque pointer created by `payments_new` and released with `payments_free`.
+//! The header is generated into `include/payments.h` when building with `--features ffi`.
+use std::{os::raw::c_char, ptr, slice};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    client::ClientId,
+    error::Error,
+    payments::Payments,
+    transaction::{Operation, OperationType, Transaction, TransactionId},
+};
+
+/// Opaque engine handle.
+pub struct PaymentsEngine(Payments);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaymentsStatus {
+    Ok = 0,
+    NullPointer,
+    InvalidAmount,
+    ClientNotFound,
+    BufferTooSmall,
+    SerializationFailure,
+    ParsingFailure,
+    DuplicatedTransaction,
+    TransactionNotFound,
+    InsufficientFunds,
+    InvalidTransactionStateChange,
+    AccountLocked,
+    FailedDisputeNotEnoughFunds,
+    BatchRolledBack,
+    BatchAborted,
+    InvalidReference,
+    RiskScoreExceeded,
+    DedupFailure,
+    MemoryLimitExceeded,
+    ChecksumMissing,
+    ChecksumMismatch,
+    DuplicateFile,
+    BrokenSeal,
+    InvalidSignature,
+    AccountNotLocked,
+    RateLimited,
+    FxRateNotFound,
+    BelowReserve,
+    BalanceNotNegative,
+    ClientBlocked,
+    BankAccountNotFound,
+    AccountClosed,
+    AccountNotEmpty,
+    BrokenChain,
+    UnsupportedSnapshotVersion,
+    AmendmentOfDisputed,
+    AmendmentOfPending,
+    Overflow,
+    Io,
+    CurrencyMismatch,
+    NotQuarantined,
+    SameOperator,
+    ApprovalExceeded,
+    Rejected,
+}
+
+impl From<&Error> for PaymentsStatus {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::ParsingFailure(_) => PaymentsStatus::ParsingFailure,
+            Error::DuplicatedTransaction(_) => PaymentsStatus::DuplicatedTransaction,
+            Error::TransactionNotFound(_) => PaymentsStatus::TransactionNotFound,
+            Error::InsufficientFunds { .. } => PaymentsStatus::InsufficientFunds,
+            Error::InvalidTransactionStateChange { .. } => {
+                PaymentsStatus::InvalidTransactionStateChange
+            }
+            Error::AccountLocked(_) => PaymentsStatus::AccountLocked,
+            Error::ClientNotFound(_) => PaymentsStatus::ClientNotFound,
+            Error::AccountNotLocked(_) => PaymentsStatus::AccountNotLocked,
+            Error::FailedDisputeNotEnoughFunds(_) => PaymentsStatus::FailedDisputeNotEnoughFunds,
+            Error::BatchRolledBack { .. } => PaymentsStatus::BatchRolledBack,
+            Error::BatchAborted { .. } => PaymentsStatus::BatchAborted,
+            Error::InvalidReference { .. } => PaymentsStatus::InvalidReference,
+            Error::RiskScoreExceeded { .. } => PaymentsStatus::RiskScoreExceeded,
+            Error::DedupFailure(_) => PaymentsStatus::DedupFailure,
+            Error::MemoryLimitExceeded { .. } => PaymentsStatus::MemoryLimitExceeded,
+            Error::ChecksumMissing(_) => PaymentsStatus::ChecksumMissing,
+            Error::ChecksumMismatch { .. } => PaymentsStatus::ChecksumMismatch,
+            Error::DuplicateFile { .. } => PaymentsStatus::DuplicateFile,
+            Error::BrokenSeal(_) => PaymentsStatus::BrokenSeal,
+            Error::InvalidSignature(_) => PaymentsStatus::InvalidSignature,
+            Error::RateLimited(_) => PaymentsStatus::RateLimited,
+            Error::FxRateNotFound { .. } => PaymentsStatus::FxRateNotFound,
+            Error::BelowReserve { .. } => PaymentsStatus::BelowReserve,
+            Error::BalanceNotNegative(_) => PaymentsStatus::BalanceNotNegative,
+            Error::ClientBlocked(_) => PaymentsStatus::ClientBlocked,
+            Error::BankAccountNotFound(_) => PaymentsStatus::BankAccountNotFound,
+            Error::AccountClosed(_) => PaymentsStatus::AccountClosed,
+            Error::AccountNotEmpty(_) => PaymentsStatus::AccountNotEmpty,
+            Error::BrokenChain(_) => PaymentsStatus::BrokenChain,
+            Error::InvalidAmount(_) => PaymentsStatus::InvalidAmount,
+            Error::UnsupportedSnapshotVersion(_) => PaymentsStatus::UnsupportedSnapshotVersion,
+            Error::AmendmentOfDisputed(_) => PaymentsStatus::AmendmentOfDisputed,
+            Error::AmendmentOfPending(_) => PaymentsStatus::AmendmentOfPending,
+            Error::Overflow(_) => PaymentsStatus::Overflow,
+            Error::Io(_) => PaymentsStatus::Io,
+            Error::CurrencyMismatch { .. } => PaymentsStatus::CurrencyMismatch,
+            Error::NotQuarantined(_) => PaymentsStatus::NotQuarantined,
+            Error::SameOperator { .. } => PaymentsStatus::SameOperator,
+            Error::ApprovalExceeded { .. } => PaymentsStatus::ApprovalExceeded,
+            Error::Rejected { .. } => PaymentsStatus::Rejected,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaymentsOperationType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Correct the amount of a deposit or withdrawal which wasn't disputed
+    Amend,
+    /// Make the pending funds of a deposit available
+    Settle,
+}
+
+/// Fixed-point decimal: `mantissa * 10^-scale`, `scale` must not exceed 28.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaymentsAmount {
+    pub mantissa: i64,
+    pub scale: u32,
+}
+
+impl TryFrom<PaymentsAmount> for Decimal {
+    type Error = PaymentsStatus;
+
+    fn try_from(amount: PaymentsAmount) -> Result<Self, Self::Error> {
+        Decimal::try_from_i128_with_scale(amount.mantissa.into(), amount.scale)
+            .map_err(|_| PaymentsStatus::InvalidAmount)
+    }
+}
+
+impl TryFrom<Decimal> for PaymentsAmount {
+    type Error = PaymentsStatus;
+
+    fn try_from(amount: Decimal) -> Result<Self, Self::Error> {
+        Ok(PaymentsAmount {
+            mantissa: amount
+                .mantissa()
+                .try_into()
+                .map_err(|_| PaymentsStatus::InvalidAmount)?,
+            scale: amount.scale(),
+        })
+    }
+}
+
+/// A transaction to apply. `amount` is ignored for disputes, resolves and chargebacks, it's
+/// the corrected amount of an amendment.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaymentsTransaction {
+    pub kind: PaymentsOperationType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: PaymentsAmount,
+}
+
+impl TryFrom<&PaymentsTransaction> for Transaction {
+    type Error = PaymentsStatus;
+
+    fn try_from(trans: &PaymentsTransaction) -> Result<Self, Self::Error> {
+        Ok(Transaction {
+            client_id: trans.client,
+            timestamp: None,
+            batch: None,
+            tenant: None,
+            signature: None,
+            value_date: None,
+            seq: None,
+            op: Operation {
+                id: trans.tx,
+                kind: match trans.kind {
+                    PaymentsOperationType::Deposit => OperationType::Deposit {
+                        amount: trans.amount.try_into()?,
+                        ref_tx: None,
+                        counterparty: None,
+                    },
+                    PaymentsOperationType::Withdrawal => OperationType::Withdrawal {
+                        amount: trans.amount.try_into()?,
+                        ref_tx: None,
+                        counterparty: None,
+                    },
+                    PaymentsOperationType::Dispute => OperationType::Dispute,
+                    PaymentsOperationType::Resolve => OperationType::Resolve,
+                    PaymentsOperationType::Chargeback => OperationType::Chargeback,
+                    PaymentsOperationType::Amend => OperationType::Amend {
+                        amount: trans.amount.try_into()?,
+                    },
+                    PaymentsOperationType::Settle => OperationType::Settle,
+                },
+            },
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaymentsBalance {
+    pub available: PaymentsAmount,
+    pub held: PaymentsAmount,
+    pub total: PaymentsAmount,
+    pub locked: bool,
+}
+
+/// Create a new, empty engine. Must be released with `payments_free`.
+#[no_mangle]
+pub extern "C" fn payments_new() -> *mut PaymentsEngine {
+    Box::into_raw(Box::new(PaymentsEngine(Payments::default())))
+}
+
+/// Release an engine created by `payments_new`. Passing NULL is a no-op.
+///
+/// # Safety
+/// `engine` must be NULL or a pointer returned by `payments_new` that wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn payments_free(engine: *mut PaymentsEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+/// Apply a single transaction.
+///
+/// # Safety
+/// `engine` must be a valid engine pointer and `transaction` must point to a valid struct.
+#[no_mangle]
+pub unsafe extern "C" fn payments_apply(
+    engine: *mut PaymentsEngine,
+    transaction: *const PaymentsTransaction,
+) -> PaymentsStatus {
+    let (engine, transaction) = match (engine.as_mut(), transaction.as_ref()) {
+        (Some(engine), Some(transaction)) => (engine, transaction),
+        _ => return PaymentsStatus::NullPointer,
+    };
+    let transaction = match Transaction::try_from(transaction) {
+        Ok(transaction) => transaction,
+        Err(status) => return status,
+    };
+    match engine.0.apply(transaction) {
+        Ok(()) => PaymentsStatus::Ok,
+        Err(error) => (&error).into(),
+    }
+}
+
+/// Query balance of a client.
+///
+/// # Safety
+/// `engine` must be a valid engine pointer and `balance` must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn payments_balance(
+    engine: *const PaymentsEngine,
+    client: ClientId,
+    balance: *mut PaymentsBalance,
+) -> PaymentsStatus {
+    let engine = match engine.as_ref() {
+        Some(engine) if !balance.is_null() => engine,
+        _ => return PaymentsStatus::NullPointer,
+    };
+    let client = match engine.0.client(client) {
+        Some(client) => client,
+        None => return PaymentsStatus::ClientNotFound,
+    };
+    let result = (|| {
+        Ok(PaymentsBalance {
+            available: client.available().try_into()?,
+            held: client.held().try_into()?,
+            total: client.total().try_into()?,
+            locked: client.locked(),
+        })
+    })();
+    match result {
+        Ok(result) => {
+            ptr::write(balance, result);
+            PaymentsStatus::Ok
+        }
+        Err(status) => status,
+    }
+}
+
+/// Serialize all accounts to CSV (not NUL-terminated) into `buffer`.
+/// The number of bytes required is always stored in `written`; if `buffer` is NULL
+/// or `capacity` is too small, nothing is copied and `BufferTooSmall` is returned.
+///
+/// # Safety
+/// `engine` must be a valid engine pointer, `written` must point to writable memory and
+/// `buffer` must be NULL or point to at least `capacity` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn payments_serialize(
+    engine: *const PaymentsEngine,
+    buffer: *mut c_char,
+    capacity: usize,
+    written: *mut usize,
+) -> PaymentsStatus {
+    let engine = match engine.as_ref() {
+        Some(engine) if !written.is_null() => engine,
+        _ => return PaymentsStatus::NullPointer,
+    };
+    let mut output = Vec::<u8>::new();
+    if engine.0.serialize(&mut output).is_err() {
+        return PaymentsStatus::SerializationFailure;
+    }
+    ptr::write(written, output.len());
+    if buffer.is_null() || capacity < output.len() {
+        return PaymentsStatus::BufferTooSmall;
+    }
+    slice::from_raw_parts_mut(buffer as *mut u8, output.len()).copy_from_slice(&output);
+    PaymentsStatus::Ok
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    fn amount(mantissa: i64, scale: u32) -> PaymentsAmount {
+        PaymentsAmount { mantissa, scale }
+    }
+
+    fn apply(
+        engine: *mut PaymentsEngine,
+        kind: PaymentsOperationType,
+        tx: TransactionId,
+        amount: PaymentsAmount,
+    ) -> PaymentsStatus {
+        let transaction = PaymentsTransaction {
+            kind,
+            client: 1,
+            tx,
+            amount,
+        };
+        unsafe { payments_apply(engine, &transaction) }
+    }
+
+    #[test]
+    fn apply_and_query_balance() {
+        let engine = payments_new();
+        let zero = amount(0, 0);
+        assert_eq!(
+            PaymentsStatus::Ok,
+            apply(engine, PaymentsOperationType::Deposit, 1, amount(125, 2))
+        );
+        assert_eq!(
+            PaymentsStatus::InsufficientFunds,
+            apply(engine, PaymentsOperationType::Withdrawal, 2, amount(2, 0))
+        );
+        assert_eq!(
+            PaymentsStatus::Ok,
+            apply(engine, PaymentsOperationType::Dispute, 1, zero)
+        );
+
+        let mut balance = PaymentsBalance {
+            available: zero,
+            held: zero,
+            total: zero,
+            locked: true,
+        };
+        unsafe {
+            assert_eq!(
+                PaymentsStatus::Ok,
+                payments_balance(engine, 1, &mut balance)
+            );
+            assert_eq!(
+                PaymentsStatus::ClientNotFound,
+                payments_balance(engine, 2, &mut balance)
+            );
+            payments_free(engine);
+        }
+        assert_eq!(
+            PaymentsBalance {
+                available: amount(0, 2),
+                held: amount(125, 2),
+                total: amount(125, 2),
+                locked: false,
+            },
+            balance
+        );
+    }
+
+    #[test]
+    fn invalid_amount() {
+        let engine = payments_new();
+        assert_eq!(
+            PaymentsStatus::InvalidAmount,
+            apply(engine, PaymentsOperationType::Deposit, 1, amount(1, 29))
+        );
+        unsafe { payments_free(engine) };
+    }
+
+    #[test]
+    fn serialize_to_buffer() {
+        let engine = payments_new();
+        apply(engine, PaymentsOperationType::Deposit, 1, amount(1, 0));
+
+        let mut written = 0;
+        let status = unsafe { payments_serialize(engine, ptr::null_mut(), 0, &mut written) };
+        assert_eq!(PaymentsStatus::BufferTooSmall, status);
+
+        let mut buffer = vec![0 as c_char; written];
+        let status =
+            unsafe { payments_serialize(engine, buffer.as_mut_ptr(), buffer.len(), &mut written) };
+        assert_eq!(PaymentsStatus::Ok, status);
+        let csv: Vec<u8> = buffer.iter().map(|&c| c as u8).collect();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1,0,1,false\n",
+            String::from_utf8(csv).unwrap()
+        );
+        unsafe { payments_free(engine) };
+    }
+}
@@ -0,0 +1,181 @@
+//! Adapter for FIX drop-copy sessions of the brokerage (feature `fix`): the fills of
+//! execution reports (`35=8` with `150=F`, or `1`/`2` before FIX 4.4) become transactions of
+//! the client of the `Account` (1): a sell is a deposit of its proceeds, a buy a
+//! withdrawal of its cost, `LastQty` (32) times `LastPx` (31) rounded to `AMOUNT_SCALE`.
+//!
+//! The `ExecID` (17) is the transaction ID, so it has to be numeric, and a fill resent by the
+//! session is rejected as a duplicate. `TransactTime` (60) is the timestamp. Other messages,
+//! e.g. heartbeats, and reports without a fill are skipped. Messages are read as they're
+//! logged by the session, fields delimited by SOH, and their checksums (10) are verified.
+use std::io::BufRead;
+
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+
+use crate::{
+    error::Error,
+    transaction::{Transaction, AMOUNT_SCALE},
+};
+
+const SOH: u8 = 0x01;
+
+/// Fields of a message, in order
+type Message = Vec<(u32, String)>;
+
+fn malformed(reason: impl std::fmt::Display) -> Error {
+    Error::ParsingFailure(format!("FIX: {}", reason))
+}
+
+/// Read the next message, none at the end of `input`
+fn read_message(input: &mut impl BufRead) -> Result<Option<Message>, Error> {
+    let mut message = Message::new();
+    let mut sum = 0u32;
+    let mut field = Vec::new();
+    loop {
+        field.clear();
+        if input.read_until(SOH, &mut field).map_err(malformed)? == 0 {
+            return match message.is_empty() {
+                true => Ok(None),
+                false => Err(malformed("message without a checksum")),
+            };
+        }
+        // Messages may be logged one per line
+        let start = field.iter().position(|b| !b.is_ascii_whitespace());
+        let Some(start) = start else {
+            continue;
+        };
+        let raw = &field[start..];
+        let text = std::str::from_utf8(raw).map_err(malformed)?;
+        let text = text.strip_suffix(SOH as char).unwrap_or(text);
+        let (tag, value) = text
+            .split_once('=')
+            .ok_or_else(|| malformed(format!("field without a tag `{}`", text)))?;
+        let tag = tag
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("invalid tag `{}`", tag)))?;
+        if tag == 10 {
+            if value.parse::<u32>().ok() != Some(sum % 256) {
+                return Err(malformed(format!("checksum `{}` doesn't match", value)));
+            }
+            return Ok(Some(message));
+        }
+        sum += raw.iter().map(|&b| u32::from(b)).sum::<u32>();
+        message.push((tag, value.to_string()));
+    }
+}
+
+fn field(message: &Message, tag: u32) -> Option<&str> {
+    message
+        .iter()
+        .find(|(t, _)| *t == tag)
+        .map(|(_, value)| value.as_str())
+}
+
+fn required(message: &Message, tag: u32) -> Result<&str, Error> {
+    field(message, tag).ok_or_else(|| malformed(format!("fill without the field {}", tag)))
+}
+
+fn decimal(message: &Message, tag: u32) -> Result<Decimal, Error> {
+    let value = required(message, tag)?;
+    value
+        .parse()
+        .map_err(|_| malformed(format!("invalid decimal `{}` of the field {}", value, tag)))
+}
+
+/// The transaction of the fill reported by `message`, if it is one
+fn fill(message: &Message) -> Result<Option<Transaction>, Error> {
+    let is_fill = field(message, 35) == Some("8")
+        && matches!(field(message, 150), Some("F" | "1" | "2"))
+        && !decimal(message, 32)?.is_zero();
+    if !is_fill {
+        return Ok(None);
+    }
+    let account = required(message, 1)?;
+    let client = account
+        .parse()
+        .map_err(|_| malformed(format!("account `{}` isn't a client ID", account)))?;
+    let exec_id = required(message, 17)?;
+    let tx = exec_id
+        .parse()
+        .map_err(|_| malformed(format!("ExecID `{}` isn't a transaction ID", exec_id)))?;
+    let amount = (decimal(message, 32)? * decimal(message, 31)?).round_dp(AMOUNT_SCALE);
+    let transaction = match required(message, 54)? {
+        "1" => Transaction::withdrawal(client, tx, amount)?,
+        "2" => Transaction::deposit(client, tx, amount)?,
+        side => return Err(malformed(format!("unsupported side `{}`", side))),
+    };
+    Ok(Some(match field(message, 60) {
+        Some(time) => {
+            let time = NaiveDateTime::parse_from_str(time, "%Y%m%d-%H:%M:%S%.f")
+                .map_err(|_| malformed(format!("invalid TransactTime `{}`", time)))?;
+            transaction.with_timestamp(time.and_utc())
+        }
+        None => transaction,
+    }))
+}
+
+/// The transactions of the fills in a drop-copy session's messages, up to the end of
+/// `input` or the first malformed message
+pub fn read_fills(mut input: impl BufRead) -> impl Iterator<Item = Result<Transaction, Error>> {
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        while !failed {
+            let transaction = match read_message(&mut input) {
+                Ok(None) => return None,
+                Ok(Some(message)) => match fill(&message).transpose() {
+                    Some(transaction) => transaction,
+                    None => continue,
+                },
+                Err(error) => Err(error),
+            };
+            failed = transaction.is_err();
+            return Some(transaction);
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::read_fills;
+    use crate::{error::Error, transaction::Transaction};
+
+    /// A message of `body` with its header and checksum, the fields delimited by `|`
+    fn message(body: &str) -> String {
+        let body = body.replace('|', "\x01");
+        let head = format!("8=FIX.4.4\x019={}\x01", body.len());
+        let sum = (head.clone() + &body).bytes().map(u32::from).sum::<u32>() % 256;
+        format!("{}{}10={:03}\x01\n", head, body, sum)
+    }
+
+    #[test]
+    fn fills() {
+        let session = [
+            message("35=0|"),
+            message("35=8|150=0|1=7|17=100|54=1|32=0|31=0|"),
+            message("35=8|150=F|1=7|17=101|54=2|32=10|31=1.23456|60=20240331-12:00:00.000|"),
+            message("35=8|150=F|1=7|17=102|54=1|32=3|31=2|"),
+        ]
+        .concat();
+        let fills = read_fills(session.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            fills,
+            [
+                Transaction::deposit(7, 101, dec!(12.3456))
+                    .unwrap()
+                    .with_timestamp("2024-03-31T12:00:00Z".parse().unwrap()),
+                Transaction::withdrawal(7, 102, dec!(6)).unwrap(),
+            ]
+        );
+
+        let corrupted = message("35=8|150=F|1=7|17=101|54=2|32=10|31=1|").replace("32=10", "32=90");
+        assert!(matches!(
+            read_fills(corrupted.as_bytes()).next(),
+            Some(Err(Error::ParsingFailure(reason))) if reason.contains("checksum")
+        ));
+    }
+}
@@ -0,0 +1,240 @@
+//! Conversion of amounts to a reporting currency, by a table of FX rates.
+//!
+//! The table is a CSV file of `date, pair, rate` rows, where the pair `EUR/USD` with the rate
+//! `1.08` means one euro is worth 1.08 US dollars. Amounts are converted at the latest rate
+//! dated on or before the day of the conversion, of the pair or its inverse.
+//!
+//! ```text
+//! date, pair, rate
+//! 2024-03-01, EUR/USD, 1.0850
+//! 2024-03-01, GBP/USD, 1.2650
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+pub use crate::money::Currency;
+use crate::{error::Error, money::Money};
+
+/// Precision of converted amounts, the one of the input
+const DECIMAL_PLACES: u32 = 4;
+
+#[derive(Debug, Deserialize)]
+struct RateRecord {
+    date: NaiveDate,
+    pair: String,
+    rate: Decimal,
+}
+
+/// See the module documentation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FxRates {
+    /// Rates of `(base, quote)` pairs by day
+    rates: HashMap<(Currency, Currency), BTreeMap<NaiveDate, Decimal>>,
+}
+
+/// A currency code, three letters
+pub fn currency(code: &str) -> Result<Currency, String> {
+    code.parse()
+}
+
+impl FxRates {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| Error::ParsingFailure(format!("{}: {}", path.display(), e)))?;
+        Self::parse(rdr)
+    }
+
+    pub fn parse<R: std::io::Read>(mut rdr: csv::Reader<R>) -> Result<Self, Error> {
+        let mut fx = Self::default();
+        for record in rdr.deserialize() {
+            let RateRecord { date, pair, rate } =
+                record.map_err(|e| Error::ParsingFailure(e.to_string()))?;
+            let (base, quote) = pair
+                .split_once('/')
+                .and_then(|(base, quote)| Some((currency(base).ok()?, currency(quote).ok()?)))
+                .ok_or_else(|| {
+                    Error::ParsingFailure(format!("invalid currency pair: `{}`", pair))
+                })?;
+            if rate <= Decimal::ZERO {
+                return Err(Error::ParsingFailure(format!(
+                    "invalid rate of {} on {}: {}",
+                    pair, date, rate
+                )));
+            }
+            fx.insert(date, base, quote, rate);
+        }
+        Ok(fx)
+    }
+
+    /// Set the rate of `base` in `quote` as of `date`
+    pub fn insert(&mut self, date: NaiveDate, base: Currency, quote: Currency, rate: Decimal) {
+        self.rates
+            .entry((base, quote))
+            .or_default()
+            .insert(date, rate);
+    }
+
+    fn latest(
+        &self,
+        base: Currency,
+        quote: Currency,
+        date: NaiveDate,
+    ) -> Option<(NaiveDate, Decimal)> {
+        let rates = self.rates.get(&(base, quote))?;
+        rates
+            .range(..=date)
+            .next_back()
+            .map(|(&day, &rate)| (day, rate))
+    }
+
+    /// How much of `to` one unit of `from` is worth on `date`. Of a pair quoted both ways,
+    /// the more recent rate is used.
+    pub fn rate(&self, from: Currency, to: Currency, date: NaiveDate) -> Result<Decimal, Error> {
+        if from == to {
+            return Ok(Decimal::ONE);
+        }
+        let direct = self.latest(from, to, date);
+        let inverse = self
+            .latest(to, from, date)
+            .and_then(|(day, rate)| Some((day, Decimal::ONE.checked_div(rate)?)));
+        match (direct, inverse) {
+            (Some(direct), Some(inverse)) => Ok(direct.max(inverse).1),
+            (Some((_, rate)), None) | (None, Some((_, rate))) => Ok(rate),
+            (None, None) => Err(Error::FxRateNotFound {
+                from: from.to_string(),
+                to: to.to_string(),
+                date,
+            }),
+        }
+    }
+
+    /// `amount` of `from` in `to` on `date`, rounded to the precision of the input
+    pub fn convert(
+        &self,
+        amount: Decimal,
+        from: Currency,
+        to: Currency,
+        date: NaiveDate,
+    ) -> Result<Decimal, Error> {
+        amount
+            .checked_mul(self.rate(from, to, date)?)
+            .map(|amount| amount.round_dp(DECIMAL_PLACES).normalize())
+            .ok_or_else(|| Error::Overflow(format!("conversion from {} to {}", from, to)))
+    }
+
+    /// `money` in `to` on `date`, see `convert`
+    pub fn convert_money(
+        &self,
+        money: Money,
+        to: Currency,
+        date: NaiveDate,
+    ) -> Result<Money, Error> {
+        let amount = self.convert(money.amount, money.currency, to, date)?;
+        Ok(Money::new(amount, to))
+    }
+}
+
+/// Totals of a report converted to the reporting currency
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Converted {
+    pub currency: Currency,
+    /// The rate the totals were converted at
+    pub rate: Decimal,
+    pub totals: BTreeMap<&'static str, Decimal>,
+}
+
+impl Converted {
+    /// Convert `totals` in `from` to `to` on `date`
+    pub fn new(
+        fx: &FxRates,
+        totals: impl IntoIterator<Item = (&'static str, Decimal)>,
+        (from, to): (Currency, Currency),
+        date: NaiveDate,
+    ) -> Result<Self, Error> {
+        let rate = fx.rate(from, to, date)?;
+        Ok(Self {
+            currency: to,
+            rate,
+            totals: totals
+                .into_iter()
+                .map(|(name, amount)| Ok((name, fx.convert(amount, from, to, date)?)))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use rust_decimal_macros::dec;
+
+    use super::{Currency, FxRates};
+    use crate::{error::Error, money::Money};
+
+    const EUR: Currency = Currency::EUR;
+    const USD: Currency = Currency::USD;
+    const GBP: Currency = Currency::GBP;
+
+    fn rates() -> FxRates {
+        let input = "date, pair, rate
+            2024-03-01, EUR/USD, 1.25
+            2024-03-04, EUR/USD, 1.5
+            2024-03-02, USD/GBP, 0.8";
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        FxRates::parse(rdr).unwrap()
+    }
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, day).unwrap()
+    }
+
+    #[test]
+    fn latest_rate_of_the_pair_or_its_inverse() {
+        let fx = rates();
+        assert_eq!(fx.rate(EUR, USD, day(3)), Ok(dec!(1.25)));
+        assert_eq!(fx.rate(EUR, USD, day(4)), Ok(dec!(1.5)));
+        assert_eq!(fx.rate(USD, EUR, day(1)), Ok(dec!(0.8)));
+        assert_eq!(fx.rate(GBP, USD, day(2)), Ok(dec!(1.25)));
+        let chf = "CHF".parse().unwrap();
+        assert_eq!(fx.rate(chf, chf, day(1)), Ok(dec!(1)));
+        assert_eq!(
+            fx.rate(USD, GBP, day(1)),
+            Err(Error::FxRateNotFound {
+                from: "USD".to_string(),
+                to: "GBP".to_string(),
+                date: day(1)
+            })
+        );
+        assert_eq!(
+            fx.convert(dec!(10.00005), EUR, USD, day(1)),
+            Ok(dec!(12.5001))
+        );
+        assert_eq!(
+            fx.convert_money(Money::new(dec!(10), EUR), USD, day(1)),
+            Ok(Money::new(dec!(12.5), USD))
+        );
+    }
+
+    #[test]
+    fn invalid_rates() {
+        for input in [
+            "date,pair,rate\n2024-03-01,EURUSD,1.1",
+            "date,pair,rate\n2024-03-01,EUR/US,1.1",
+            "date,pair,rate\n2024-03-01,EUR/USD,0",
+            "date,pair,rate\n2024-03-41,EUR/USD,1.1",
+        ] {
+            let rdr = csv::Reader::from_reader(input.as_bytes());
+            assert!(FxRates::parse(rdr).is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Hash chains making append-only logs tamper-evident. Every record is a JSON line carrying
+//! its sequence number, the hash of the record before it and its own hash, the SHA-256 of
+//! the line without it:
+//!
+//! ```text
+//! {"seq":0,"prev":"00..00",...,"hash":"5f..c1"}
+//! {"seq":1,"prev":"5f..c1",...,"hash":"a9..07"}
+//! ```
+//!
+//! Altering, reordering, inserting or removing a record breaks the chain from there on.
+//! Removing records from the end doesn't, so the hash of the last record (`Chain::head`) should
+//! be kept apart from the log to compare against.
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, signature::to_hex};
+
+/// The `prev` of the first record
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize)]
+struct Linked<'a, T> {
+    seq: u64,
+    prev: &'a str,
+    #[serde(flatten)]
+    record: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Link {
+    seq: u64,
+    prev: String,
+}
+
+/// The end of a chain, which records are appended to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chain {
+    seq: u64,
+    head: String,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self {
+            seq: 0,
+            head: GENESIS.to_string(),
+        }
+    }
+}
+
+fn sha256(body: &str) -> String {
+    to_hex(&Sha256::digest(body.as_bytes()))
+}
+
+/// The body of `line`, without the hash, and the hash
+fn split(line: &str) -> Option<(String, &str)> {
+    let (body, hash) = line.strip_suffix("\"}")?.rsplit_once(",\"hash\":\"")?;
+    Some((format!("{}}}", body), hash))
+}
+
+impl Chain {
+    /// Continue the chain of the log at `path`, verifying it, or start one if there's none
+    pub fn resume(path: &Path) -> Result<Self, Error> {
+        match std::fs::File::open(path) {
+            Ok(file) => Self::verify(std::io::BufReader::new(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::ParsingFailure(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    /// Check the chain of a log, returning its end
+    pub fn verify(input: impl BufRead) -> Result<Self, Error> {
+        Self::default().extend(input)
+    }
+
+    /// Check that a log continues this chain, e.g. a log truncated when it was compacted
+    /// (see `txlog::compact`), returning its end. See `Continuation` for the records of the
+    /// chain the log may start with.
+    pub fn extend(self, input: impl BufRead) -> Result<Self, Error> {
+        let mut continuation = Continuation::new(self);
+        for line in input.lines() {
+            let line = line.map_err(|_| Error::BrokenChain(continuation.chain.seq))?;
+            if !line.trim().is_empty() {
+                continuation.follow(&line)?;
+            }
+        }
+        continuation.finish()
+    }
+
+    /// Extend the chain by `line`, the next record of its log, returning the record without
+    /// its hash
+    pub fn follow(&mut self, line: &str) -> Result<String, Error> {
+        let broken = || Error::BrokenChain(self.seq);
+        let (body, hash) = split(line).ok_or_else(broken)?;
+        let link = serde_json::from_str::<Link>(&body).map_err(|_| broken())?;
+        if link.seq != self.seq || link.prev != self.head || sha256(&body) != hash {
+            return Err(broken());
+        }
+        self.seq += 1;
+        self.head = hash.to_string();
+        Ok(body)
+    }
+
+    /// Records in the chain
+    pub fn len(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seq == 0
+    }
+
+    /// Hash of the last record
+    pub fn head(&self) -> &str {
+        &self.head
+    }
+
+    /// Write `record` as the next line of the log
+    pub fn append<T: Serialize>(
+        &mut self,
+        record: &T,
+        mut output: impl Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let body = serde_json::to_string(&Linked {
+            seq: self.seq,
+            prev: &self.head,
+            record,
+        })?;
+        let hash = sha256(&body);
+        let body = body.strip_suffix('}').ok_or("record isn't an object")?;
+        writeln!(output, "{},\"hash\":\"{}\"}}", body, hash)?;
+        self.seq += 1;
+        self.head = hash;
+        Ok(())
+    }
+}
+
+/// Follows a log continuing a chain, e.g. from the checkpoint of a compaction. The log may
+/// start with records of the chain, left by a compaction interrupted before truncating it.
+/// They're skipped once they're checked to be a chain of their own ending where this one does.
+/// Any other record has to continue the chain.
+#[derive(Debug, Clone)]
+pub struct Continuation {
+    chain: Chain,
+    /// The records of the chain the log starts with, while they're being skipped
+    leading: Option<Chain>,
+    started: bool,
+}
+
+impl Continuation {
+    pub fn new(chain: Chain) -> Self {
+        Self {
+            chain,
+            leading: None,
+            started: false,
+        }
+    }
+
+    /// Follow `line`, the next record of the log, returning the record without its hash, or
+    /// none if it's skipped
+    pub fn follow(&mut self, line: &str) -> Result<Option<String>, Error> {
+        if !self.started {
+            let link = split(line).and_then(|(body, _)| serde_json::from_str::<Link>(&body).ok());
+            match link {
+                Some(link) if link.seq < self.chain.seq => {
+                    let leading = self.leading.get_or_insert(Chain {
+                        seq: link.seq,
+                        head: link.prev,
+                    });
+                    leading.follow(line)?;
+                    return Ok(None);
+                }
+                _ => self.start()?,
+            }
+        }
+        self.chain.follow(line).map(Some)
+    }
+
+    /// Stop skipping, checking the skipped records
+    fn start(&mut self) -> Result<(), Error> {
+        self.started = true;
+        match self.leading.take() {
+            Some(leading) if leading != self.chain => Err(Error::BrokenChain(leading.seq)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The chain up to the last record followed
+    pub fn chain(&self) -> &Chain {
+        &self.chain
+    }
+
+    /// Check the end of the log, returning the chain
+    pub fn finish(mut self) -> Result<Chain, Error> {
+        self.start()?;
+        Ok(self.chain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Chain, Continuation};
+    use crate::error::Error;
+
+    #[test]
+    fn tampering_breaks_the_chain() {
+        let mut chain = Chain::default();
+        let mut log = Vec::new();
+        for amount in [1, 2, 3] {
+            chain
+                .append(&json!({ "amount": amount }), &mut log)
+                .unwrap();
+        }
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(Chain::verify(log.as_bytes()), Ok(chain.clone()));
+        assert_eq!(chain.len(), 3);
+
+        let altered = log.replace("\"amount\":2", "\"amount\":20");
+        assert_eq!(
+            Chain::verify(altered.as_bytes()),
+            Err(Error::BrokenChain(1))
+        );
+        let lines = log.lines().collect::<Vec<_>>();
+        let removed = [lines[0], lines[2]].join("\n");
+        assert_eq!(
+            Chain::verify(removed.as_bytes()),
+            Err(Error::BrokenChain(1))
+        );
+        // Removed from the end, only the head tells
+        let truncated = lines[..2].join("\n");
+        assert_ne!(
+            Chain::verify(truncated.as_bytes()).unwrap().head(),
+            chain.head()
+        );
+        // A forged record of an earlier sequence number isn't skipped
+        let forged = [
+            lines[0],
+            lines[1],
+            r#"{"seq":0,"prev":"x","hash":"y"}"#,
+            lines[2],
+        ];
+        assert_eq!(
+            Chain::verify(forged.join("\n").as_bytes()),
+            Err(Error::BrokenChain(2))
+        );
+    }
+
+    #[test]
+    fn continues_a_checkpoint() {
+        let mut chain = Chain::default();
+        let mut log = Vec::new();
+        for amount in [1, 2, 3] {
+            chain
+                .append(&json!({ "amount": amount }), &mut log)
+                .unwrap();
+        }
+        let log = String::from_utf8(log).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        let checkpoint = Chain::verify(lines[..2].join("\n").as_bytes()).unwrap();
+
+        // Truncated by the compaction, or not yet
+        assert_eq!(
+            checkpoint.clone().extend(lines[2].as_bytes()),
+            Ok(chain.clone())
+        );
+        assert_eq!(checkpoint.clone().extend(log.as_bytes()), Ok(chain.clone()));
+        let mut continuation = Continuation::new(checkpoint.clone());
+        assert_eq!(continuation.follow(lines[1]), Ok(None));
+        assert!(continuation.follow(lines[2]).unwrap().is_some());
+
+        // Only the leading records of the checkpoint are skipped
+        let forged = r#"{"seq":0,"prev":"x","hash":"y"}"#;
+        let after = [lines[0], lines[1], lines[2], forged].join("\n");
+        assert_eq!(
+            checkpoint.clone().extend(after.as_bytes()),
+            Err(Error::BrokenChain(3))
+        );
+        let before = [forged, lines[2]].join("\n");
+        assert_eq!(
+            checkpoint.clone().extend(before.as_bytes()),
+            Err(Error::BrokenChain(0))
+        );
+        // They have to end where the checkpoint does
+        let gap = [lines[0], lines[2]].join("\n");
+        assert_eq!(
+            checkpoint.extend(gap.as_bytes()),
+            Err(Error::BrokenChain(1))
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Idempotency keys of the admin endpoints, so that a client retrying a request, e.g. after a
+//! timeout, gets the outcome of the first one instead of taking the action twice or failing
+//! because it was already taken.
+//!
+//! A request with an `Idempotency-Key` header claims the key. Once its command is carried out,
+//! the response is kept under the key, and a request with the same key gets it again without
+//! the command being sent. A key can't be reused for another request, and a request arriving
+//! while the first one with its key is still being carried out is refused. Responses are kept
+//! in memory, the oldest forgotten beyond `CAPACITY` keys.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::mpsc::Receiver,
+};
+
+use crate::server::{Request, Response};
+
+/// Keys remembered by default
+pub const CAPACITY: usize = 10_000;
+
+/// What to do with a request with an idempotency key
+#[derive(Debug)]
+pub enum Claim {
+    /// The key is new, carry out the request
+    New,
+    /// Answer with the response to the first request with the key
+    Replay(Response),
+    /// The first request timed out waiting for its response, wait for it again
+    Wait(Receiver<Response>),
+    /// The first request with the key is still being carried out
+    InProgress,
+    /// The key was used for another request
+    Mismatch,
+}
+
+#[derive(Debug)]
+enum State {
+    InProgress,
+    Waiting(Receiver<Response>),
+    Done(Response),
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// See `fingerprint`
+    request: String,
+    state: State,
+}
+
+/// The responses to the requests with an idempotency key
+#[derive(Debug)]
+pub struct Responses {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    /// Keys in the order they were claimed
+    order: VecDeque<String>,
+}
+
+impl Default for Responses {
+    fn default() -> Self {
+        Self::new(CAPACITY)
+    }
+}
+
+/// The method, path and query of `request`, which a retry has to repeat
+fn fingerprint(request: &Request) -> String {
+    let mut query = request
+        .query
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>();
+    query.sort();
+    format!("{} {}?{}", request.method, request.path, query.join("&"))
+}
+
+impl Responses {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Claim `key` for `request`. Unless it's `Claim::New`, the request mustn't be carried out.
+    /// The claim is settled by `Responses::settle` or given up by `Responses::release`.
+    pub fn claim(&mut self, key: &str, request: &Request) -> Claim {
+        let fingerprint = fingerprint(request);
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    request: fingerprint,
+                    state: State::InProgress,
+                },
+            );
+            self.order.push_back(key.to_string());
+            while self.order.len() > self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+            return Claim::New;
+        };
+        if entry.request != fingerprint {
+            return Claim::Mismatch;
+        }
+        match std::mem::replace(&mut entry.state, State::InProgress) {
+            State::InProgress => Claim::InProgress,
+            State::Waiting(receiver) => Claim::Wait(receiver),
+            State::Done(response) => {
+                entry.state = State::Done(response.clone());
+                Claim::Replay(response)
+            }
+        }
+    }
+
+    /// Settle the claim of `key` with the `response` to its request
+    pub fn settle(&mut self, key: &str, response: Response) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.state = State::Done(response);
+        }
+    }
+
+    /// Settle the claim of `key` once its response comes from `receiver`, by a retry
+    pub fn defer(&mut self, key: &str, receiver: Receiver<Response>) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.state = State::Waiting(receiver);
+        }
+    }
+
+    /// Give up the claim of `key`, as its request wasn't carried out
+    pub fn release(&mut self, key: &str) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|claimed| claimed != key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{Claim, Responses};
+    use crate::server::{Request, Response};
+
+    fn request(target: &str) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Request::default()
+        }
+    }
+
+    #[test]
+    fn replays_settled_responses() {
+        let mut responses = Responses::default();
+        let unlock = request("/admin/unlock?client=1&operator=7");
+        assert!(matches!(responses.claim("a", &unlock), Claim::New));
+        assert!(matches!(responses.claim("a", &unlock), Claim::InProgress));
+        responses.settle("a", Response::json(200, "{}"));
+        // The same query in another order
+        let retry = request("/admin/unlock?operator=7&client=1");
+        match responses.claim("a", &retry) {
+            Claim::Replay(response) => assert_eq!(response, Response::json(200, "{}")),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(responses.claim("a", &retry), Claim::Replay(_)));
+        let other = request("/admin/unlock?client=2&operator=7");
+        assert!(matches!(responses.claim("a", &other), Claim::Mismatch));
+
+        assert!(matches!(responses.claim("b", &other), Claim::New));
+        responses.release("b");
+        assert!(matches!(responses.claim("b", &other), Claim::New));
+    }
+
+    #[test]
+    fn waits_for_deferred_responses() {
+        let mut responses = Responses::default();
+        let close = request("/admin/close?client=1&operator=7");
+        assert!(matches!(responses.claim("a", &close), Claim::New));
+        let (reply, receiver) = mpsc::channel();
+        responses.defer("a", receiver);
+        reply.send(Response::json(200, "{}")).unwrap();
+        match responses.claim("a", &close) {
+            Claim::Wait(receiver) => {
+                let response = receiver.recv().unwrap();
+                responses.settle("a", response);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(responses.claim("a", &close), Claim::Replay(_)));
+    }
+
+    #[test]
+    fn forgets_the_oldest_keys() {
+        let mut responses = Responses::new(2);
+        let compact = request("/admin/compact");
+        for key in ["a", "b", "c"] {
+            assert!(matches!(responses.claim(key, &compact), Claim::New));
+            responses.settle(key, Response::json(200, "{}"));
+        }
+        assert!(matches!(responses.claim("a", &compact), Claim::New));
+        assert!(matches!(responses.claim("c", &compact), Claim::Replay(_)));
+    }
+}
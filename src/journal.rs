@@ -0,0 +1,142 @@
+//! The journal of the admin actions of the daemon mode which changed the state.
+//!
+//! The daemon rebuilds its state from the files in the watched directory on start, which
+//! alone would lose the actions of operators, e.g. unlocks. Every such action is recorded as
+//! a JSON line with the file applied last before it, relative to the watched directory, and
+//! carried out again right after that file on the next start:
+//!
+//! ```text
+//! {"after":"bank/day1.csv","command":"act","client":1,"action":{"action":"unlock"},"operator":7}
+//! ```
+//!
+//! Actions recorded after files which are gone are carried out once the files present on
+//! start are applied.
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{daemon::AdminCommand, transaction::TenantId};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// The file applied last before the action, none if it came before any
+    pub after: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    #[serde(flatten)]
+    pub command: AdminCommand,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    /// The recorded actions yet to be carried out again, in order
+    replay: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, which is created by the first `record`
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.into();
+        let replay = match File::open(&path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?))
+                .collect::<Result<_, Box<dyn std::error::Error>>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, replay })
+    }
+
+    /// Take the recorded actions to carry out again after the file `after`, or before any
+    pub fn replay_after(&mut self, after: Option<&str>) -> Vec<JournalEntry> {
+        let (taken, kept) = std::mem::take(&mut self.replay)
+            .into_iter()
+            .partition(|entry| entry.after.as_deref() == after);
+        self.replay = kept;
+        taken
+    }
+
+    /// Take the recorded actions left to carry out again, their files being gone
+    pub fn replay_rest(&mut self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.replay)
+    }
+
+    /// Record an action
+    pub fn record(&mut self, entry: &JournalEntry) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        serde_json::to_writer(&mut file, entry)?;
+        writeln!(file)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Journal, JournalEntry};
+    use crate::{approval::AdminAction, daemon::AdminCommand};
+
+    #[test]
+    fn replays_after_their_files() {
+        let dir = std::env::temp_dir().join(format!("payments-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let entries = [
+            JournalEntry {
+                after: None,
+                tenant: None,
+                command: AdminCommand::Quarantine {
+                    client: 2,
+                    operator: 7,
+                },
+            },
+            JournalEntry {
+                after: Some("bank/day1.csv".to_string()),
+                tenant: Some("acme".to_string()),
+                command: AdminCommand::Act {
+                    client: 1,
+                    action: AdminAction::ForceResolve { tx: 3 },
+                    operator: 7,
+                },
+            },
+            JournalEntry {
+                after: Some("gone.csv".to_string()),
+                tenant: None,
+                command: AdminCommand::Release {
+                    client: 2,
+                    tx: None,
+                    operator: 8,
+                },
+            },
+        ];
+        let mut journal = Journal::open(&path).unwrap();
+        for entry in &entries {
+            journal.record(entry).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().lines().nth(1),
+            Some(
+                r#"{"after":"bank/day1.csv","tenant":"acme","command":"act","client":1,"action":{"action":"force-resolve","tx":3},"operator":7}"#
+            )
+        );
+
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.replay_after(None), entries[..1]);
+        assert_eq!(journal.replay_after(Some("bank/day2.csv")), []);
+        assert_eq!(journal.replay_after(Some("bank/day1.csv")), entries[1..2]);
+        assert_eq!(journal.replay_rest(), entries[2..]);
+        assert_eq!(journal.replay_rest(), []);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//! Latency histograms of processing transactions, parsing and applying them, per operation
+//! type, so slowdowns can be attributed to specific paths, e.g. dispute lookups.
+//!
+//! Latencies are counted in power-of-two buckets of nanoseconds, which keeps the histograms
+//! fixed-size. A percentile is the upper bound of the bucket it falls in, so it's accurate to
+//! within a factor of two.
+use std::time::Duration;
+
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+use crate::transaction::OperationType;
+
+/// A bucket per bit length of the latency in nanoseconds, including zero
+const BUCKETS: usize = u64::BITS as usize + 1;
+
+/// Operation types, in the order of their histograms
+pub const KINDS: [&str; 8] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "transfer",
+    "amend",
+    "settle",
+];
+
+fn kind_index(kind: &OperationType) -> usize {
+    match kind {
+        OperationType::Deposit { .. } => 0,
+        OperationType::Withdrawal { .. } => 1,
+        OperationType::Dispute => 2,
+        OperationType::Resolve => 3,
+        OperationType::Chargeback => 4,
+        OperationType::Transfer { .. } => 5,
+        OperationType::Amend { .. } => 6,
+        OperationType::Settle => 7,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[(u64::BITS - nanos.leading_zeros()) as usize] += 1;
+    }
+
+    /// Add the latencies recorded by `other`
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The `p`th percentile (0-100), none if nothing was recorded
+    pub fn percentile(&self, p: u64) -> Option<Duration> {
+        let rank = (self.count() * p.min(100)).div_ceil(100).max(1);
+        let mut seen = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .find_map(|(bucket, &count)| {
+                seen += count;
+                // Values of `bucket` bits at most, none in the first
+                let bound = u64::MAX.checked_shr(u64::BITS - bucket as u32).unwrap_or(0);
+                (seen >= rank).then(|| Duration::from_nanos(bound))
+            })
+    }
+}
+
+/// p50 and p99 of the latencies of an operation type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LatencySummary {
+    #[serde(skip)]
+    pub kind: &'static str,
+    pub count: u64,
+    pub p50_ns: u64,
+    pub p99_ns: u64,
+}
+
+/// A histogram per operation type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latencies {
+    kinds: [Histogram; KINDS.len()],
+}
+
+impl Latencies {
+    pub fn record(&mut self, kind: &OperationType, latency: Duration) {
+        self.kinds[kind_index(kind)].record(latency);
+    }
+
+    /// Add the latencies recorded by `other`, e.g. of another shard
+    pub fn merge(&mut self, other: &Latencies) {
+        for (histogram, other) in self.kinds.iter_mut().zip(&other.kinds) {
+            histogram.merge(other);
+        }
+    }
+
+    /// The histograms of every operation type
+    pub fn histograms(&self) -> &[Histogram] {
+        &self.kinds
+    }
+
+    /// The operation types with any latencies recorded
+    pub fn summary(&self) -> impl Iterator<Item = LatencySummary> + '_ {
+        KINDS
+            .iter()
+            .zip(&self.kinds)
+            .filter_map(|(&kind, histogram)| {
+                Some(LatencySummary {
+                    kind,
+                    count: histogram.count(),
+                    p50_ns: histogram.percentile(50)?.as_nanos() as u64,
+                    p99_ns: histogram.percentile(99)?.as_nanos() as u64,
+                })
+            })
+    }
+}
+
+impl Serialize for Latencies {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for summary in self.summary() {
+            map.serialize_entry(summary.kind, &summary)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Histogram, Latencies};
+    use crate::transaction::OperationType;
+
+    #[test]
+    fn percentiles_are_bucket_upper_bounds() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(50), None);
+        for nanos in [0, 1, 3, 100, 1000] {
+            histogram.record(Duration::from_nanos(nanos));
+        }
+        histogram.record(Duration::MAX);
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.percentile(0), Some(Duration::ZERO));
+        assert_eq!(histogram.percentile(50), Some(Duration::from_nanos(3)));
+        assert_eq!(histogram.percentile(80), Some(Duration::from_nanos(1023)));
+        assert_eq!(
+            histogram.percentile(100),
+            Some(Duration::from_nanos(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn summary_per_operation_type() {
+        let mut latencies = Latencies::default();
+        latencies.record(&OperationType::Dispute, Duration::from_nanos(1000));
+        latencies.record(&OperationType::Dispute, Duration::from_nanos(5000));
+        let summary = latencies.summary().collect::<Vec<_>>();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(
+            (
+                summary[0].kind,
+                summary[0].count,
+                summary[0].p50_ns,
+                summary[0].p99_ns
+            ),
+            ("dispute", 2, 1023, 8191)
+        );
+        assert_eq!(
+            serde_json::to_string(&latencies).unwrap(),
+            r#"{"dispute":{"count":2,"p50_ns":1023,"p99_ns":8191}}"#
+        );
+    }
+}
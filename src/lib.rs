@@ -1,5 +1,89 @@
+// The library mustn't panic on any input, see `pipeline`
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::unreachable
+    )
+)]
+
+pub mod access;
+pub mod approval;
+mod arena;
+pub mod audit;
+pub mod bench;
+pub mod builder;
+pub mod cdc;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod checksum;
 pub mod client;
+pub mod close;
+pub mod compare;
+pub mod config;
+pub mod correction;
+pub mod counterparty;
+pub mod credit;
+pub mod daemon;
+pub mod dashboard;
+pub mod dedup;
 pub mod error;
+pub mod event;
+pub mod features;
+#[cfg(feature = "fix")]
+pub mod fix;
+pub mod fx;
+pub mod hashchain;
+pub mod idempotency;
+pub mod journal;
+pub mod latency;
+pub mod lifecycle;
+pub mod log;
+pub mod manifest;
+pub mod merkle;
+pub mod mmap;
+pub mod money;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod ordering;
+pub mod parallel;
 pub mod parser;
 pub mod payments;
+pub mod pipeline;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+pub mod provenance;
+pub mod quarantine;
+pub mod query;
+pub mod quoting;
+pub mod ratelimit;
+pub mod redact;
+pub mod reference;
+pub mod reorder;
+pub mod repl;
+pub mod reserve;
+pub mod risk;
+pub mod schedule;
+pub mod server;
+pub mod settlement;
+pub mod signature;
+#[cfg(feature = "simd")]
+pub mod simd;
+pub mod snapshot;
+pub mod sort;
+pub mod source;
+#[cfg(feature = "stress")]
+pub mod stress;
+pub mod subaccount;
+pub mod tenant;
+pub mod testing;
 pub mod transaction;
+pub mod txlog;
+
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
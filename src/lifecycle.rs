@@ -0,0 +1,83 @@
+//! Lifecycle of client accounts: active, dormant without activity for longer than a
+//! threshold, or closed by an operator, see `Payments::close_account`. Transactions of closed
+//! accounts are rejected.
+//!
+//! Activity is moving funds: deposits, withdrawals and transfers. Postings of the day's
+//! close, disputes and operator actions don't count. It's tracked in the timestamped mode
+//! only, so without timestamps accounts are never dormant.
+use std::fmt;
+
+use chrono::Duration;
+use serde::Serialize;
+
+use crate::{client::ClientId, event::Event, transaction::Timestamp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Active,
+    Dormant,
+    Closed,
+}
+
+impl Status {
+    /// Of an open account last active at `last_activity`, as of `now`
+    pub fn of(
+        last_activity: Option<Timestamp>,
+        now: Option<Timestamp>,
+        dormant_after: Duration,
+    ) -> Self {
+        match (last_activity, now) {
+            (Some(last_activity), Some(now)) if now - last_activity > dormant_after => {
+                Status::Dormant
+            }
+            _ => Status::Active,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Active => "active",
+            Status::Dormant => "dormant",
+            Status::Closed => "closed",
+        })
+    }
+}
+
+/// Whether `event` is activity of the client, see the module documentation
+pub fn is_activity(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::FundsDeposited { .. }
+            | Event::FundsWithdrawn { .. }
+            | Event::FundsTransferred { .. }
+    )
+}
+
+/// A row of the lifecycle report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LifecycleRecord {
+    pub client: ClientId,
+    pub status: Status,
+    pub last_activity: Option<Timestamp>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::Status;
+
+    #[test]
+    fn dormant_beyond_threshold() {
+        let at = |day| Some(Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap());
+        let after = Duration::days(7);
+        assert_eq!(Status::of(at(1), at(8), after), Status::Active);
+        assert_eq!(Status::of(at(1), at(9), after), Status::Dormant);
+        // Without timestamps there's no telling
+        assert_eq!(Status::of(None, at(9), after), Status::Active);
+        assert_eq!(Status::of(at(1), None, after), Status::Active);
+    }
+}
@@ -0,0 +1,322 @@
+//! Diagnostics of a run on standard error, as free text or as JSON lines for log ingestion.
+//!
+//! In the text format only rejected transactions and warnings are reported, while the
+//! JSON format also marks the start, the snapshot checkpoints and the finish of a run.
+use std::{path::Path, str::FromStr};
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::{
+    client::ClientId, error::Error, ordering::Sequence, payments::Stats, reorder::LateArrival,
+    source::SourceStats, transaction::TransactionId,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "unknown log format `{}`, expected `text` or `json`",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LogEvent<'a> {
+    Start {
+        input: &'a str,
+    },
+    Rejected {
+        client: ClientId,
+        tx: TransactionId,
+        error: String,
+    },
+    /// A row which couldn't be parsed, skipped with lenient quoting, see `quoting`
+    Malformed {
+        input: &'a str,
+        error: String,
+    },
+    LateArrival(&'a LateArrival),
+    /// The checksum of an input file doesn't match, which is only a warning as configured
+    ChecksumMismatch {
+        input: &'a str,
+        error: String,
+    },
+    /// An input file processed before, which is processed again as allowed
+    DuplicateFile {
+        input: &'a str,
+        processed_as: &'a str,
+    },
+    /// An input file failed in the daemon mode, its transactions are rolled back
+    Failed {
+        input: &'a str,
+        error: String,
+    },
+    /// A snapshot of the accounts was written
+    Checkpoint {
+        transactions: usize,
+        path: &'a Path,
+    },
+    /// A snapshot written in the background failed, see `Daemon`
+    SnapshotFailed {
+        path: &'a Path,
+        error: String,
+    },
+    /// A source of the daemon mode delivered no file for too long, see `daemon::Watchdog`
+    SourceStalled {
+        source: &'a str,
+        idle_secs: u64,
+    },
+    /// A stalled source delivered a file again
+    SourceResumed {
+        source: &'a str,
+    },
+    /// The configuration file of the daemon mode changed and was applied, see
+    /// `config::ConfigWatcher`
+    Reloaded {
+        path: &'a Path,
+    },
+    /// The configuration file changed, but has problems, the settings in effect are kept
+    ReloadFailed {
+        path: &'a Path,
+        error: String,
+    },
+    Finish {
+        transactions: usize,
+        rejected: usize,
+    },
+    /// What's kept in memory at the end of a run with `--stats`, by tenant with `--tenants`
+    Stats {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<&'a str>,
+        #[serde(flatten)]
+        stats: &'a Stats,
+    },
+    /// A client whose sequence numbers show records missing upstream, see `ordering`
+    SequenceGaps {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<&'a str>,
+        client: ClientId,
+        #[serde(flatten)]
+        sequence: Sequence,
+    },
+    /// The transactions and rejections of a source at the end of a run, see `source`
+    SourceStats {
+        source: &'a str,
+        #[serde(flatten)]
+        stats: &'a SourceStats,
+    },
+}
+
+impl LogEvent<'_> {
+    pub fn rejected(client: ClientId, tx: TransactionId, error: &Error) -> Self {
+        LogEvent::Rejected {
+            client,
+            tx,
+            error: error.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a LogEvent<'a>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Logger {
+    format: LogFormat,
+}
+
+impl Logger {
+    pub fn new(format: LogFormat) -> Self {
+        Self { format }
+    }
+
+    /// The line to log for `event`, if any
+    fn line(&self, event: &LogEvent, time: String) -> Option<String> {
+        match (self.format, event) {
+            (LogFormat::Json, event) => serde_json::to_string(&Record { time, event }).ok(),
+            (LogFormat::Text, LogEvent::Rejected { error, .. }) => {
+                Some(format!("Transaction failed: '{}'", error))
+            }
+            (LogFormat::Text, LogEvent::Malformed { input, error }) => {
+                Some(format!("Row of `{}` skipped: {}", input, error))
+            }
+            (LogFormat::Text, LogEvent::LateArrival(late)) => Some(format!("Warning: {}", late)),
+            (LogFormat::Text, LogEvent::ChecksumMismatch { error, .. }) => {
+                Some(format!("Warning: {}", error))
+            }
+            (
+                LogFormat::Text,
+                LogEvent::DuplicateFile {
+                    input,
+                    processed_as,
+                },
+            ) => Some(format!(
+                "Warning: input file `{}` was already processed as `{}`",
+                input, processed_as
+            )),
+            (LogFormat::Text, LogEvent::Failed { input, error }) => {
+                Some(format!("Error: input file `{}` failed: {}", input, error))
+            }
+            (LogFormat::Text, LogEvent::SnapshotFailed { path, error }) => Some(format!(
+                "Error: snapshot `{}` failed: {}",
+                path.display(),
+                error
+            )),
+            (LogFormat::Text, LogEvent::SourceStalled { source, idle_secs }) => Some(format!(
+                "Warning: source `{}` delivered no file for {}s",
+                source, idle_secs
+            )),
+            (LogFormat::Text, LogEvent::SourceResumed { source }) => {
+                Some(format!("Source `{}` delivers files again", source))
+            }
+            (LogFormat::Text, LogEvent::Reloaded { path }) => {
+                Some(format!("Reloaded `{}`", path.display()))
+            }
+            (LogFormat::Text, LogEvent::ReloadFailed { path, error }) => Some(format!(
+                "Warning: not reloading `{}`: {}",
+                path.display(),
+                error
+            )),
+            (LogFormat::Text, LogEvent::Stats { tenant, stats }) => Some(match tenant {
+                Some(tenant) => format!("{}: {}", tenant, stats),
+                None => stats.to_string(),
+            }),
+            (
+                LogFormat::Text,
+                LogEvent::SequenceGaps {
+                    tenant,
+                    client,
+                    sequence,
+                },
+            ) => Some(format!(
+                "{}client {}: {} missing, {} out of order",
+                tenant
+                    .map(|tenant| format!("{}: ", tenant))
+                    .unwrap_or_default(),
+                client,
+                sequence.missing,
+                sequence.regressions
+            )),
+            (LogFormat::Text, LogEvent::SourceStats { source, stats }) => {
+                Some(format!("source {}: {}", source, stats))
+            }
+            (LogFormat::Text, _) => None,
+        }
+    }
+
+    pub fn log(&self, event: LogEvent) {
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        if let Some(line) = self.line(&event, time) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{LogEvent, LogFormat, Logger};
+    use crate::{error::Error, ordering::Sequence};
+
+    const TIME: &str = "2024-01-01T00:00:00.000Z";
+
+    fn line(format: LogFormat, event: LogEvent) -> Option<String> {
+        Logger::new(format).line(&event, TIME.to_string())
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            line(
+                LogFormat::Text,
+                LogEvent::rejected(1, 2, &Error::TransactionNotFound(2))
+            ),
+            Some(format!(
+                "Transaction failed: '{}'",
+                Error::TransactionNotFound(2)
+            ))
+        );
+        assert_eq!(
+            line(LogFormat::Text, LogEvent::Start { input: "in.csv" }),
+            None
+        );
+        assert_eq!(
+            line(
+                LogFormat::Text,
+                LogEvent::ReloadFailed {
+                    path: Path::new("payments.toml"),
+                    error: "limits.max_risk_score must be positive".to_string()
+                }
+            )
+            .unwrap(),
+            "Warning: not reloading `payments.toml`: limits.max_risk_score must be positive"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let rejected = line(
+            LogFormat::Json,
+            LogEvent::rejected(1, 2, &Error::TransactionNotFound(2)),
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&rejected).unwrap(),
+            serde_json::json!({
+                "time": TIME,
+                "event": "rejected",
+                "client": 1,
+                "tx": 2,
+                "error": Error::TransactionNotFound(2).to_string(),
+            })
+        );
+        assert_eq!(
+            line(
+                LogFormat::Json,
+                LogEvent::Checkpoint {
+                    transactions: 10,
+                    path: Path::new("snapshots/accounts.csv")
+                }
+            )
+            .unwrap(),
+            r#"{"time":"2024-01-01T00:00:00.000Z","event":"checkpoint","transactions":10,"path":"snapshots/accounts.csv"}"#
+        );
+        let sequence = Sequence {
+            last: 9,
+            missing: 2,
+            regressions: 1,
+        };
+        let gaps = || LogEvent::SequenceGaps {
+            tenant: Some("acme"),
+            client: 3,
+            sequence,
+        };
+        assert_eq!(
+            line(LogFormat::Text, gaps()).unwrap(),
+            "acme: client 3: 2 missing, 1 out of order"
+        );
+        assert_eq!(
+            line(LogFormat::Json, gaps()).unwrap(),
+            r#"{"time":"2024-01-01T00:00:00.000Z","event":"sequence_gaps","tenant":"acme","client":3,"last":9,"missing":2,"regressions":1}"#
+        );
+    }
+}
@@ -1,25 +1,1502 @@
-use clap::Parser;
-use payments::{parser::parse, payments::Payments};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use payments::{
+    access::{Access, ClientList},
+    audit::{Actor, AuditLog},
+    bench::{self, Profile},
+    cdc::ChangeStream,
+    checksum::ChecksumMode,
+    client::{DisputePolicy, WithdrawalChargeback},
+    close::{self, close_day, end_of_day, open_sealed, Postings},
+    compare::{compare, write_differences},
+    config::{parse_size, ConfigWatcher, FileConfig},
+    correction::{backfill, write_impact, Correction},
+    credit::CreditLimits,
+    daemon::{self, Admin, Daemon, DaemonConfig, Watchdog},
+    dashboard,
+    dedup::{DedupConfig, DedupScope},
+    features::Format,
+    fx::{self, Currency, FxRates},
+    journal::Journal,
+    log::{LogEvent, LogFormat, Logger},
+    manifest::ManifestGuard,
+    merkle::MerkleTree,
+    parallel::{diverging_clients, process_sharded},
+    parser::{tenant, ParseOptions},
+    payments::{Config, ErrorPolicy, Marker, Observers, Partition, Payments},
+    pipeline::{load, load_tenants, read, write_accounts, LoadOptions, Sinks},
+    provenance::{verify_file, OutputKey},
+    query,
+    ratelimit::{Overload, RateLimiter, Throttle},
+    redact, repl,
+    reserve::Reserves,
+    schedule::Schedule,
+    server,
+    settlement::{
+        instructions, write_csv, write_pain001, BankAccount, BankAccounts, Pain001,
+        SettlementFormat,
+    },
+    signature::SigningKey,
+    snapshot,
+    sort::{sort, SortKey},
+    tenant::{Tenants, DEFAULT_TENANT},
+    testing::{replay_corpus, Normalize},
+    transaction::{Timestamp, TransactionId},
+    txlog::{self, TransactionLog, Until},
+};
+use rust_decimal::Decimal;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, arg_required_else_help = true)]
 struct Cli {
-    input: String,
+    input: Option<String>,
+    /// Read settings from this TOML file, flags given on the command line take precedence
+    #[clap(long)]
+    config: Option<std::path::PathBuf>,
+    /// Write the event log (JSON lines) to this file
+    #[clap(long)]
+    export_events: Option<String>,
+    /// Write a stream of balance changes (JSON lines) to this file as they happen
+    #[clap(long)]
+    cdc: Option<String>,
+    /// Write the register of open disputes (CSV) to this file
+    #[clap(long)]
+    disputes: Option<String>,
+    /// Write every disputed transaction with the state of its dispute (CSV) to this file
+    #[clap(long)]
+    dispute_history: Option<String>,
+    /// Automatically resolve disputes open for longer than this many days
+    #[clap(long)]
+    dispute_timeout_days: Option<i64>,
+    /// Deposits are pending for this many days, or until a `settle` row of them comes
+    #[clap(long)]
+    settlement_delay_days: Option<i64>,
+    /// Reject withdrawals of clients whose risk score (0-100) exceeds this
+    #[clap(long)]
+    max_risk_score: Option<f64>,
+    /// Quarantine clients whose risk score (0-100) a transaction raises above this, parking
+    /// their transactions for review
+    #[clap(long)]
+    quarantine_risk_score: Option<f64>,
+    /// Unlocks, write-offs and forced resolutions of a larger amount need the approval of a
+    /// second operator
+    #[clap(long)]
+    approval_threshold: Option<Decimal>,
+    /// Add the `risk_score` column to the output
+    #[clap(long)]
+    risk_score_column: bool,
+    /// Reject withdrawals which would leave a client with less available than this
+    #[clap(long)]
+    reserve: Option<Decimal>,
+    /// Reserves of individual clients (CSV of `client, reserve`), overriding `--reserve`
+    #[clap(long)]
+    reserves: Option<std::path::PathBuf>,
+    /// Add the `reserved` column to the output: the reserve of the client
+    #[clap(long)]
+    reserve_column: bool,
+    /// Add the `pending` column to the output: deposited funds waiting for their value date
+    #[clap(long)]
+    pending_column: bool,
+    /// Credit limits of clients allowed to overdraw their accounts (CSV of
+    /// `client, credit_limit`)
+    #[clap(long)]
+    credit_limits: Option<std::path::PathBuf>,
+    /// Clients whose transactions are rejected (CSV of `client`), e.g. sanctioned accounts
+    #[clap(long)]
+    denied_clients: Option<std::path::PathBuf>,
+    /// Only accept transactions of these clients (CSV of `client`)
+    #[clap(long)]
+    allowed_clients: Option<std::path::PathBuf>,
+    /// Add the `disputes` column to the output: the client's disputed transactions,
+    /// their state and the funds they hold
+    #[clap(long)]
+    include_dispute_columns: bool,
+    /// Add the `sub_accounts` column to the output: the available funds of the client's
+    /// sub-accounts
+    #[clap(long)]
+    include_sub_account_columns: bool,
+    /// Where deposit and withdrawal IDs have to be unique: per `client`, per client and
+    /// `client-operation` type, or `global` across all clients
+    #[clap(long, default_value = "client")]
+    dedup_scope: DedupScope,
+    /// Require transaction IDs to be unique across all clients, `--dedup-scope global`
+    #[clap(long)]
+    global_tx_ids: bool,
+    /// Number of transaction IDs the duplicate detector is sized for
+    #[clap(long, default_value_t = 1_000_000)]
+    dedup_capacity: usize,
+    /// False positive rate of the duplicate detector, without `--dedup-dir`
+    /// a fresh transaction is rejected as a duplicate with this probability
+    #[clap(long, default_value_t = 1e-6)]
+    dedup_false_positive_rate: f64,
+    /// Directory to keep seen transaction IDs in, for confirming possible duplicates
+    #[clap(long)]
+    dedup_dir: Option<std::path::PathBuf>,
+    /// Write what chargebacks cost the house (CSV), per client and in total, to this file
+    #[clap(long)]
+    chargeback_losses: Option<String>,
+    /// Append locks, unlocks, reversals and write-offs to this hash-chained audit log
+    #[clap(long)]
+    audit_log: Option<std::path::PathBuf>,
+    /// Append the accepted transactions to this hash-chained log
+    #[clap(long)]
+    transaction_log: Option<std::path::PathBuf>,
+    /// Write the Merkle root of the accepted transactions (JSON) to this file, to compare
+    /// runs over the same input
+    #[clap(long)]
+    merkle_root: Option<String>,
+    /// Write per-client fraud model features to this file (CSV, or Parquet for `.parquet`)
+    #[clap(long)]
+    client_features: Option<String>,
+    /// Write per-transaction fraud model features to this file (CSV, or Parquet for `.parquet`)
+    #[clap(long)]
+    transaction_features: Option<String>,
+    /// Write the accounts to files in this directory instead of standard output
+    #[clap(long)]
+    output_dir: Option<std::path::PathBuf>,
+    /// Split the accounts into files by client shard, by locked status or by currency
+    #[clap(long, requires = "output-dir", possible_values = ["client-shard", "locked", "currency"])]
+    partition_by: Option<String>,
+    /// Number of client shards, for `--partition-by client-shard`, `--verify-parallel` and
+    /// `--export-sharded`
+    #[clap(long, default_value_t = 16)]
+    shards: u16,
+    /// Also process the input in parallel shards and fail unless the final states are equal
+    #[clap(long)]
+    verify_parallel: bool,
+    /// Export the events into a directory of `--shards` files, written in parallel
+    #[clap(long, requires = "export-events")]
+    export_sharded: bool,
+    /// Write a snapshot of the accounts every this many transactions
+    #[clap(long)]
+    emit_every: Option<usize>,
+    /// Directory for the `--emit-every` snapshots
+    #[clap(long, default_value = "snapshots")]
+    snapshot_dir: std::path::PathBuf,
+    /// Sign the accounts written to `--output-dir` and the snapshots with the ed25519 key in
+    /// `PAYMENTS_OUTPUT_SIGNING_KEY`, see `provenance`
+    #[clap(long)]
+    sign_output: bool,
+    /// Memory-map the input file and parse it in parallel
+    #[clap(long)]
+    mmap: bool,
+    /// Take unescaped quotes in quoted fields literally, and reject malformed rows one by one
+    /// instead of stopping at the first
+    #[clap(long, conflicts_with = "mmap")]
+    lenient_quotes: bool,
+    /// Accept rows with more or fewer fields than the header, the missing ones empty
+    #[clap(long, conflicts_with = "mmap")]
+    flexible: bool,
+    /// Sort transactions arriving up to this many seconds out of timestamp order
+    #[clap(long)]
+    reorder_window_secs: Option<i64>,
+    /// Standing orders (CSV) to add to the timestamped input as they come due
+    #[clap(long, conflicts_with = "verify-parallel")]
+    schedule: Option<std::path::PathBuf>,
+    /// Approximate memory limit for the accounts and the event log, e.g. `512M` or `2G`
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_memory: Option<usize>,
+    /// Don't create accounts for clients whose first transaction fails
+    #[clap(long)]
+    create_clients_on_success: bool,
+    /// Settle charged back withdrawals by crediting the client (`credit`)
+    /// or as a loss of the house (`write-off`)
+    #[clap(long, default_value = "credit")]
+    withdrawal_chargeback: WithdrawalChargeback,
+    /// What disputes of transfers whose funds moved on do: fail (`hold`), hold them anyway
+    /// (`negative`) or write off what can't be held once charged back (`write-off`)
+    #[clap(long, default_value = "hold")]
+    transfer_dispute: DisputePolicy,
+    /// Leave accounts without funds, lock or transactions out of the output
+    #[clap(long)]
+    skip_empty_accounts: bool,
+    /// `text`, or `json` for one JSON object per rejected transaction and per
+    /// start, checkpoint and finish of the run
+    #[clap(long, default_value = "text")]
+    log_format: LogFormat,
+    /// Print memory usage statistics to standard error at the end
+    #[clap(long)]
+    stats: bool,
+    /// Attribute the transactions of the input to this source, e.g. the partner feed it comes
+    /// from, broken down by `--stats`. In daemon mode, it's the subdirectory of every file.
+    #[clap(long)]
+    source: Option<String>,
+    /// Leave amounts and counterparties out of the logs, the stats and panic messages, see
+    /// `redact`
+    #[clap(long)]
+    redact: bool,
+    /// Verify the input against its `.sha256` file: `off`, `warn` on a mismatch,
+    /// or `require` a matching one
+    #[clap(long, default_value = "off")]
+    checksum: ChecksumMode,
+    /// Record processed input files in this manifest (JSON lines) and refuse
+    /// files processed before
+    #[clap(long)]
+    manifest: Option<std::path::PathBuf>,
+    /// Only warn about input files processed before
+    #[clap(long, requires = "manifest")]
+    allow_duplicate_files: bool,
+    /// Reject rows without a valid `signature`, an HMAC made with the key in this file
+    #[clap(long)]
+    signing_key_file: Option<std::path::PathBuf>,
+    /// Keep a separate state per tenant (the `tenant` column), writing the accounts
+    /// of each to `<output-dir>/<tenant>/accounts.csv`
+    #[clap(
+        long,
+        requires = "output-dir",
+        conflicts_with_all = &["export-events", "cdc", "disputes", "dispute-history",
+            "chargeback-losses", "audit-log", "transaction-log", "merkle-root", "client-features", "transaction-features", "partition-by",
+            "verify-parallel", "emit-every", "schedule"]
+    )]
+    tenants: bool,
+    /// The tenant of transactions without the `tenant` column, e.g. per input source
+    #[clap(long, requires = "tenants", default_value = DEFAULT_TENANT, parse(try_from_str = tenant))]
+    default_tenant: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let filename = Cli::parse().input;
-    let mut payments = Payments::default();
+#[derive(Subcommand)]
+enum Command {
+    /// Work with configuration files
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    /// Work with persisted states: exported events and snapshots
+    Snapshot {
+        #[clap(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Interactively apply transactions and inspect balances
+    Repl {
+        /// Transactions file to apply before accepting commands
+        #[clap(long)]
+        load: Option<String>,
+        /// Settings file, which is reloaded whenever it changes
+        #[clap(long)]
+        config: Option<std::path::PathBuf>,
+    },
+    /// Report balances as of a historical instant (requires the `timestamp` column)
+    #[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Report {
+        #[clap(subcommand)]
+        report: Option<ReportKind>,
+        #[clap(required = true)]
+        input: Option<String>,
+        /// Date (inclusive, e.g. `2024-03-31`) or RFC 3339 timestamp
+        #[clap(long, required = true, parse(try_from_str = parse_as_of))]
+        as_of: Option<Timestamp>,
+    },
+    /// Settle the net positions with the counterparties: instructions to collect from those
+    /// which deposited more than was withdrawn to them and to pay the others
+    Settle {
+        input: String,
+        /// `csv`, or `pain001` for an ISO 20022 credit transfer initiation of the payments
+        #[clap(long, default_value = "csv")]
+        format: SettlementFormat,
+        /// Bank accounts of the counterparties (CSV of `counterparty, name, iban, bic`)
+        #[clap(long, required_if_eq("format", "pain001"))]
+        bank_accounts: Option<std::path::PathBuf>,
+        /// Name of the account payments are made from
+        #[clap(long, required_if_eq("format", "pain001"))]
+        debtor_name: Option<String>,
+        #[clap(long, required_if_eq("format", "pain001"))]
+        debtor_iban: Option<String>,
+        #[clap(long)]
+        debtor_bic: Option<String>,
+        /// Currency of the payments
+        #[clap(long, default_value = "EUR", parse(try_from_str = fx::currency))]
+        currency: Currency,
+        /// Requested execution date of the payments, today by default
+        #[clap(long, parse(try_from_str = parse_date))]
+        execution_date: Option<NaiveDate>,
+    },
+    /// Compare the final accounts of two runs over the same input, e.g. to validate an
+    /// upgrade or a configuration change before cutover: the differing fields per client
+    /// (CSV), failing if there are any
+    Compare {
+        /// Transactions to process for the runs without saved accounts
+        input: Option<String>,
+        /// Settings file of the first run
+        #[clap(long, conflicts_with = "accounts-a")]
+        config_a: Option<std::path::PathBuf>,
+        /// Settings file of the second run
+        #[clap(long, conflicts_with = "accounts-b")]
+        config_b: Option<std::path::PathBuf>,
+        /// Accounts saved by the first run instead, e.g. by another version
+        #[clap(long)]
+        accounts_a: Option<std::path::PathBuf>,
+        /// Accounts saved by the second run instead
+        #[clap(long)]
+        accounts_b: Option<std::path::PathBuf>,
+    },
+    /// Replay a regression corpus, e.g. inputs minimized from fuzzing or incidents: every
+    /// `<name>.csv` of the directory through the same processing as the main command,
+    /// failing if the accounts differ from `<name>.accounts.csv`
+    TestCorpus {
+        dir: std::path::PathBuf,
+        /// Write the accounts as the expected ones, to add inputs or accept a change
+        #[clap(long)]
+        update: bool,
+    },
+    /// Close a day: expire stale holds, post fees and interest, write the day's statements,
+    /// summary and a sealed snapshot to open the next day with
+    CloseDay {
+        /// The day's transactions (requires the `timestamp` column)
+        input: String,
+        #[clap(long, parse(try_from_str = parse_date))]
+        date: NaiveDate,
+        /// Sealed snapshot of the previous close to open the day with
+        #[clap(long)]
+        opening: Option<std::path::PathBuf>,
+        /// Holds of disputes open for longer than this are released
+        #[clap(long)]
+        dispute_timeout_days: Option<i64>,
+        /// Flat fee charged to every unlocked account
+        #[clap(long, default_value = "0")]
+        daily_fee: Decimal,
+        /// Transaction ID of the fees, making them disputable by the clients
+        #[clap(long)]
+        daily_fee_tx: Option<TransactionId>,
+        /// Interest paid on the available funds of every unlocked account
+        #[clap(long, default_value = "0")]
+        daily_interest_rate: Decimal,
+        #[clap(long, default_value = ".")]
+        out_dir: std::path::PathBuf,
+        /// Verify the input against its `.sha256` file: `off`, `warn` or `require`
+        #[clap(long, default_value = "off")]
+        checksum: ChecksumMode,
+        /// Record processed input files in this manifest and refuse files processed before
+        #[clap(long)]
+        manifest: Option<std::path::PathBuf>,
+        /// Only warn about input files processed before
+        #[clap(long, requires = "manifest")]
+        allow_duplicate_files: bool,
+        /// FX rates (CSV of `date, pair, rate`) to convert the summary's totals with
+        #[clap(long, requires_all = &["currency", "reporting-currency"])]
+        fx_rates: Option<std::path::PathBuf>,
+        /// Currency of the accounts, e.g. `USD`
+        #[clap(long, parse(try_from_str = fx::currency))]
+        currency: Option<Currency>,
+        /// Currency to add the summary's totals in, e.g. `EUR`
+        #[clap(long, requires = "fx-rates", parse(try_from_str = fx::currency))]
+        reporting_currency: Option<Currency>,
+    },
+    /// Run as an ingestion service applying the transactions files dropped into a directory,
+    /// with `/healthz`, `/readyz` and `/metrics` endpoints
+    Serve {
+        /// Directory to pick up transactions files (`*.csv`) from, in name order
+        watch_dir: std::path::PathBuf,
+        /// Address to serve the endpoints on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Seconds between looks for new files
+        #[clap(long, default_value_t = 5)]
+        poll_secs: u64,
+        /// Write the accounts to `accounts.csv` in this directory after every file
+        #[clap(long)]
+        output_dir: Option<std::path::PathBuf>,
+        /// Settings file
+        #[clap(long)]
+        config: Option<std::path::PathBuf>,
+        /// Verify every file against its `.sha256` file: `off`, `warn` or `require`
+        #[clap(long, default_value = "off")]
+        checksum: ChecksumMode,
+        /// Serve the admin endpoints under `/admin`, authenticated by the token in this file
+        #[clap(long)]
+        admin_token_file: Option<std::path::PathBuf>,
+        /// Record the admin actions changing the state in this journal, to carry them out
+        /// again when the state is rebuilt on start, see `journal`
+        #[clap(long, default_value = "admin-journal.jsonl")]
+        admin_journal: std::path::PathBuf,
+        /// Directory for the snapshots of `POST /admin/snapshot` and `--snapshot-every-secs`
+        #[clap(long, default_value = "snapshots")]
+        snapshot_dir: std::path::PathBuf,
+        /// Snapshot the accounts this often, in the background
+        #[clap(long)]
+        snapshot_every_secs: Option<u64>,
+        /// Sign the accounts and the snapshots with the ed25519 key in
+        /// `PAYMENTS_OUTPUT_SIGNING_KEY`, see `provenance`
+        #[clap(long)]
+        sign_output: bool,
+        /// Transactions per second of every source, a subdirectory of the watched one
+        #[clap(long, parse(try_from_str = parse_rate))]
+        source_rate_limit: Option<f64>,
+        /// Transactions per second of every client
+        #[clap(long, parse(try_from_str = parse_rate))]
+        client_rate_limit: Option<f64>,
+        /// Over a rate limit, `shed` transactions as rejected, or `queue` them until
+        /// they're within it, holding up every source and client
+        #[clap(long, default_value = "shed")]
+        overload: Overload,
+        /// Standing orders (CSV) to add to the files' transactions as they come due
+        #[clap(long)]
+        schedule: Option<std::path::PathBuf>,
+        /// Append locks, unlocks, reversals, write-offs, closures and forced resolutions
+        /// to this hash-chained audit log
+        #[clap(long)]
+        audit_log: Option<std::path::PathBuf>,
+        /// Alert on a source, a subdirectory of the watched one, which delivered no file for
+        /// this long
+        #[clap(long)]
+        source_timeout_secs: Option<u64>,
+        /// Fail `/readyz` while a source is stalled, see `--source-timeout-secs`
+        #[clap(long, requires = "source-timeout-secs")]
+        stalled_source_unready: bool,
+        /// Keep a separate state per tenant (the `tenant` column), writing the accounts of
+        /// each to `<output-dir>/<tenant>/accounts.csv` and labeling the metrics by tenant
+        #[clap(long)]
+        tenants: bool,
+        /// The tenant of transactions without the `tenant` column
+        #[clap(long, requires = "tenants", default_value = DEFAULT_TENANT, parse(try_from_str = tenant))]
+        default_tenant: String,
+        /// Show a dashboard of the ingestion on the terminal, see `dashboard`
+        #[clap(long)]
+        tui: bool,
+    },
+    /// Serve read-only queries of the accounts and their history in a snapshot over HTTP, see
+    /// `query`
+    ServeSnapshot {
+        /// Exported events (sharded or not), a sealed snapshot of a close or a transaction
+        /// log's snapshot
+        state: std::path::PathBuf,
+        /// Address to serve the queries on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
+    /// Verify the hash chain of an audit or transaction log, printing its length and the
+    /// hash of its last record
+    VerifyLog {
+        path: std::path::PathBuf,
+        /// The expected hash of the last record, kept apart from the log, to tell records
+        /// removed from its end
+        #[clap(long)]
+        head: Option<String>,
+    },
+    /// Print the public key of `PAYMENTS_OUTPUT_SIGNING_KEY`, to verify signed output with
+    OutputKey,
+    /// Verify a file written with `--sign-output` against its `.sig` file
+    VerifyOutput {
+        path: std::path::PathBuf,
+        /// The public key of the job which wrote it, see `output-key`
+        #[clap(long)]
+        public_key: String,
+    },
+    /// Rebuild the accounts from a transaction log alone, e.g. as they were at an incident,
+    /// writing them to standard output
+    Replay {
+        log: std::path::PathBuf,
+        /// Stop at this record offset or after the records written by this time
+        #[clap(long)]
+        until: Option<Until>,
+        /// Accounts saved at that point, failing if the replayed ones differ
+        #[clap(long)]
+        snapshot: Option<std::path::PathBuf>,
+        /// Settings file of the run which wrote the log
+        #[clap(long)]
+        config: Option<std::path::PathBuf>,
+    },
+    /// Delete or amend past deposits and withdrawals of exported events, writing the corrected
+    /// events and reporting the impact on the balances (CSV) to standard output, see
+    /// `correction`
+    Backfill {
+        /// Exported events, sharded or not
+        state: std::path::PathBuf,
+        /// Corrections: `action` (delete or amend), `client`, `tx`, `amount` and `reason`
+        #[clap(long)]
+        corrections: std::path::PathBuf,
+        /// Where to write the corrected events, in the form of `state`
+        #[clap(long)]
+        output: std::path::PathBuf,
+        /// Settings file of the run which exported the events
+        #[clap(long)]
+        config: Option<std::path::PathBuf>,
+    },
+    /// Run a built-in synthetic workload and report the throughput, p99 latency and memory,
+    /// see `bench`
+    Bench {
+        #[clap(long, default_value = "mixed", possible_values = ["deposits-heavy", "dispute-heavy", "mixed"])]
+        profile: Profile,
+        #[clap(long, default_value_t = 1_000_000)]
+        transactions: usize,
+        #[clap(long, default_value_t = 10_000)]
+        clients: u16,
+        /// Seed of the workload, runs with the same seed apply the same transactions
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+        /// Apply the transactions in this many parallel shards by client
+        #[clap(long, default_value_t = 1)]
+        shards: usize,
+        /// Append the accepted transactions to this transaction log
+        #[clap(long)]
+        transaction_log: Option<std::path::PathBuf>,
+        /// Settings file to benchmark
+        #[clap(long)]
+        config: Option<std::path::PathBuf>,
+        /// Report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Fold a transaction log into its snapshot, `<log>.snapshot`, and truncate it, e.g.
+    /// periodically to bound its size. Runs appending to the log mustn't overlap with it.
+    CompactLog {
+        log: std::path::PathBuf,
+        /// Settings file of the runs which wrote the log
+        #[clap(long)]
+        config: Option<std::path::PathBuf>,
+    },
+    /// Sort a transactions file, which may be larger than memory, to standard output
+    Sort {
+        input: String,
+        /// `timestamp` or `client`, the sort is stable
+        #[clap(long, default_value = "timestamp")]
+        by: SortKey,
+        /// Rows sorted in memory at once
+        #[clap(long, default_value_t = 1_000_000)]
+        chunk_rows: usize,
+        /// Directory for the sorted chunks, the system one by default
+        #[clap(long)]
+        tmp_dir: Option<std::path::PathBuf>,
+    },
+}
 
-    let rdr = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_path(filename)
-        .expect("opening transactions input file");
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate: `{}`", value)),
+    }
+}
 
-    for trans in parse(rdr) {
-        if let Err(error) = payments.apply(trans?) {
-            eprintln!("Transaction failed: '{}'", error);
-        }
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| format!("invalid date: `{}`", value))
+}
+
+#[derive(Subcommand)]
+enum ReportKind {
+    /// Summarize the disputes per client and overall: how many were opened, resolved and
+    /// charged back, their average amount and (with timestamps) how long they took to settle
+    Disputes { input: String },
+    /// Summarize the exposure to each counterparty across all clients: the funds deposited
+    /// from and withdrawn to it, gross and net
+    Counterparties { input: String },
+    /// List the clients with their lifecycle status, active, dormant or closed, and latest
+    /// activity (requires the `timestamp` column)
+    Lifecycle {
+        input: String,
+        /// Clients without activity for longer than this are dormant
+        #[clap(long, default_value_t = 365)]
+        dormant_after_days: i64,
+        /// Date (inclusive) or RFC 3339 timestamp to tell dormancy as of, the latest
+        /// timestamp of the input by default
+        #[clap(long, parse(try_from_str = parse_as_of))]
+        as_of: Option<Timestamp>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Check a configuration file, failing on unknown settings and invalid values
+    Validate { path: std::path::PathBuf },
+}
+
+#[derive(Subcommand)]
+enum SnapshotAction {
+    /// Rewrite an exported event log (every shard of a sharded one), a sealed snapshot of a
+    /// close (sealing it anew) or the snapshot of a compacted transaction log in the current
+    /// version
+    Migrate { path: std::path::PathBuf },
+}
+
+/// Take the settings of `file` which aren't given on the command line
+fn apply_file_config(cli: &mut Cli, file: FileConfig, matches: &ArgMatches) {
+    macro_rules! set {
+        ($field:ident, $value:expr) => {
+            if let Some(value) = $value {
+                if matches.occurrences_of(stringify!($field).replace('_', "-").as_str()) == 0 {
+                    cli.$field = value.into();
+                }
+            }
+        };
     }
+    let FileConfig {
+        input,
+        disputes,
+        deposits,
+        limits,
+        dedup,
+        accounts,
+        output,
+    } = file;
+    set!(mmap, input.mmap);
+    set!(lenient_quotes, input.lenient_quotes);
+    set!(flexible, input.flexible);
+    set!(reorder_window_secs, input.reorder_window_secs);
+    set!(schedule, input.schedule);
+    set!(checksum, input.checksum);
+    set!(manifest, input.manifest);
+    set!(allow_duplicate_files, input.allow_duplicate_files);
+    set!(signing_key_file, input.signing_key_file);
+    set!(tenants, input.tenants);
+    set!(default_tenant, input.default_tenant);
+    set!(dispute_timeout_days, disputes.timeout_days);
+    set!(withdrawal_chargeback, disputes.withdrawal_chargeback);
+    set!(transfer_dispute, disputes.transfer_dispute);
+    set!(settlement_delay_days, deposits.settlement_delay_days);
+    set!(max_memory, limits.max_memory);
+    set!(max_risk_score, limits.max_risk_score);
+    set!(quarantine_risk_score, limits.quarantine_risk_score);
+    set!(approval_threshold, limits.approval_threshold);
+    set!(reserve, limits.reserve);
+    set!(reserves, limits.reserves);
+    set!(credit_limits, limits.credit_limits);
+    set!(denied_clients, limits.denied_clients);
+    set!(allowed_clients, limits.allowed_clients);
+    set!(dedup_scope, dedup.scope);
+    set!(global_tx_ids, dedup.global_tx_ids);
+    set!(dedup_capacity, dedup.capacity);
+    set!(dedup_false_positive_rate, dedup.false_positive_rate);
+    set!(dedup_dir, dedup.dir);
+    set!(
+        create_clients_on_success,
+        accounts.create_clients_on_success
+    );
+    set!(skip_empty_accounts, accounts.skip_empty_accounts);
+    set!(risk_score_column, accounts.risk_score_column);
+    set!(reserve_column, accounts.reserve_column);
+    set!(pending_column, accounts.pending_column);
+    set!(include_dispute_columns, accounts.include_dispute_columns);
+    set!(
+        include_sub_account_columns,
+        accounts.include_sub_account_columns
+    );
+    set!(output_dir, output.dir);
+    set!(partition_by, output.partition_by);
+    set!(shards, output.shards);
+    set!(log_format, output.log_format);
+    set!(stats, output.stats);
+    set!(redact, output.redact);
+    set!(cdc, output.cdc);
+    set!(export_events, output.export_events);
+    set!(disputes, output.disputes);
+    set!(dispute_history, output.dispute_history);
+    set!(chargeback_losses, output.chargeback_losses);
+    set!(audit_log, output.audit_log);
+    set!(transaction_log, output.transaction_log);
+    set!(merkle_root, output.merkle_root);
+    set!(client_features, output.client_features);
+    set!(transaction_features, output.transaction_features);
+    set!(emit_every, output.emit_every);
+    set!(snapshot_dir, output.snapshot_dir);
+    set!(sign_output, output.sign_output);
+}
+
+/// The configuration file at `path`, failing on its first problem
+fn load_config(path: &std::path::Path) -> Result<FileConfig, Box<dyn std::error::Error>> {
+    let file = FileConfig::load(path)?;
+    if let Some(problem) = file.problems().into_iter().next() {
+        return Err(format!("{}: {}", path.display(), problem).into());
+    }
+    Ok(file)
+}
+
+/// Print the problems of the configuration file at `path`, failing if there are any
+fn validate_config(path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let problems = FileConfig::load(path)?.problems();
+    for problem in &problems {
+        eprintln!("{}: {}", path.display(), problem);
+    }
+    if !problems.is_empty() {
+        return Err(format!("{} invalid setting(s)", problems.len()).into());
+    }
+    println!("{}: OK", path.display());
+    Ok(())
+}
+
+/// A plain date means the very end of that day (UTC).
+fn parse_as_of(value: &str) -> Result<Timestamp, String> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(end_of_day)
+        .map_err(|_| format!("invalid date or timestamp: `{}`", value))
+}
+
+/// Report the transactions and rejections of every source, see `source`
+fn log_source_stats(payments: &Payments, log: Logger) {
+    for (source, stats) in payments.source_stats() {
+        log.log(LogEvent::SourceStats { source, stats });
+    }
+}
+
+/// Report the clients whose sequence numbers show records missing upstream, see `ordering`
+fn log_sequence_gaps(payments: &Payments, tenant: Option<&str>, log: Logger) {
+    for (client, sequence) in payments.sequence_gaps() {
+        log.log(LogEvent::SequenceGaps {
+            tenant,
+            client,
+            sequence,
+        });
+    }
+}
+
+/// The token in the file at `path`, without surrounding whitespace
+fn read_token(path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    let token = std::fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err(format!("token file `{}` is empty", path.display()).into());
+    }
+    Ok(token)
+}
 
-    payments.serialize(std::io::stdout())
+/// `n` days, failing rather than panicking out of the range of `Duration`
+fn days(n: i64) -> Result<Duration, String> {
+    Duration::try_days(n).ok_or_else(|| format!("{} days are out of range", n))
+}
+
+fn seconds(n: i64) -> Result<Duration, String> {
+    Duration::try_seconds(n).ok_or_else(|| format!("{} seconds are out of range", n))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    if let Some(path) = &cli.config {
+        let file = load_config(path)?;
+        apply_file_config(&mut cli, file, &matches);
+    }
+    redact::set(cli.redact);
+    let options = LoadOptions {
+        reorder_window: cli.reorder_window_secs.map(seconds).transpose()?,
+        mmap: cli.mmap,
+        parse: ParseOptions {
+            lenient_quotes: cli.lenient_quotes,
+            flexible: cli.flexible,
+        },
+        snapshots: cli
+            .emit_every
+            .map(|every| (every, cli.snapshot_dir.clone())),
+        checksum: cli.checksum,
+        output_key: cli.sign_output.then(OutputKey::from_env).transpose()?,
+    };
+    if cli.sign_output
+        && cli.command.is_none()
+        && (cli.output_dir.is_none() || cli.partition_by.is_some() || cli.tenants)
+    {
+        return Err("`--sign-output` signs the accounts of `--output-dir`, \
+            without `--partition-by` or `--tenants`"
+            .into());
+    }
+    let dedup = DedupConfig {
+        expected_items: cli.dedup_capacity,
+        false_positive_rate: cli.dedup_false_positive_rate,
+        confirmation_dir: cli.dedup_dir,
+    };
+    let mut reserves = Reserves::new(cli.reserve.unwrap_or_default());
+    if let Some(path) = &cli.reserves {
+        reserves.load(path)?;
+    }
+    let config = Config {
+        dispute_timeout: cli.dispute_timeout_days.map(days).transpose()?,
+        settlement_delay: cli.settlement_delay_days.map(days).transpose()?,
+        max_risk_score: cli.max_risk_score,
+        quarantine_risk_score: cli.quarantine_risk_score,
+        approval_threshold: cli.approval_threshold,
+        risk_score_column: cli.risk_score_column,
+        reserves,
+        reserve_column: cli.reserve_column,
+        pending_column: cli.pending_column,
+        credit_limits: match &cli.credit_limits {
+            Some(path) => CreditLimits::load(path)?,
+            None => CreditLimits::default(),
+        },
+        access: Access {
+            denied: match &cli.denied_clients {
+                Some(path) => ClientList::load(path)?,
+                None => ClientList::default(),
+            },
+            allowed: cli
+                .allowed_clients
+                .as_deref()
+                .map(ClientList::load)
+                .transpose()?,
+        },
+        dispute_columns: cli.include_dispute_columns,
+        sub_account_columns: cli.include_sub_account_columns,
+        dedup_scope: match cli.dedup_scope {
+            DedupScope::Global(_) => DedupScope::Global(dedup),
+            _ if cli.global_tx_ids => DedupScope::Global(dedup),
+            scope => scope,
+        },
+        max_memory: cli.max_memory,
+        create_clients_on_success: cli.create_clients_on_success,
+        skip_empty_accounts: cli.skip_empty_accounts,
+        withdrawal_chargeback: cli.withdrawal_chargeback,
+        transfer_dispute: cli.transfer_dispute,
+        currency: Currency::default(),
+        signing_key: match &cli.signing_key_file {
+            Some(path) => Some(SigningKey::from_file(path)?),
+            None => None,
+        },
+        // Rejections are logged and the run goes on
+        error_policy: ErrorPolicy::Skip,
+        observers: Observers::default(),
+    };
+    let mut payments = Payments::with_config(config.clone());
+    let log = Logger::new(cli.log_format);
+    let mut sinks = Sinks {
+        changes: match &cli.cdc {
+            Some(path) => Some(ChangeStream::new(std::io::BufWriter::new(
+                std::fs::File::create(path)?,
+            ))),
+            None => None,
+        },
+        transactions: cli
+            .transaction_log
+            .as_deref()
+            .map(TransactionLog::open)
+            .transpose()?,
+        merkle: cli.merkle_root.as_ref().map(|_| MerkleTree::default()),
+        ..Default::default()
+    };
+    let mut manifest = ManifestGuard::open(cli.manifest, cli.allow_duplicate_files)?;
+
+    match (cli.command, cli.input) {
+        (
+            Some(Command::Config {
+                action: ConfigAction::Validate { path },
+            }),
+            _,
+        ) => validate_config(&path),
+        (
+            Some(Command::Snapshot {
+                action: SnapshotAction::Migrate { path },
+            }),
+            _,
+        ) => {
+            let version = match (close::is_sealed(&path), path.is_dir()) {
+                (true, _) => close::migrate_sealed(&path)?,
+                (false, true) => snapshot::shard_files(&path)?
+                    .iter()
+                    .map(|shard| snapshot::migrate_file(shard))
+                    .try_fold(snapshot::VERSION, |oldest, version| {
+                        version.map(|version| oldest.min(version))
+                    })?,
+                (false, false) => snapshot::migrate_file(&path)?,
+            };
+            match version {
+                snapshot::VERSION => println!("{} is version {}", path.display(), version),
+                _ => println!(
+                    "{} migrated from version {} to {}",
+                    path.display(),
+                    version,
+                    snapshot::VERSION
+                ),
+            }
+            Ok(())
+        }
+        (
+            Some(Command::Repl {
+                load: filename,
+                config: config_file,
+            }),
+            _,
+        ) => {
+            let watcher = match config_file {
+                Some(path) => {
+                    let watcher = ConfigWatcher::new(&path);
+                    let mut config = config;
+                    load_config(&path)?.apply_to(&mut config)?;
+                    payments = Payments::with_config(config);
+                    Some(watcher)
+                }
+                None => None,
+            };
+            if let Some(filename) = filename {
+                load(
+                    &mut payments,
+                    &filename,
+                    &options,
+                    log,
+                    &mut sinks,
+                    None,
+                    None,
+                )?;
+            }
+            repl::run_watched(
+                &mut payments,
+                std::io::stdin().lock(),
+                std::io::stdout(),
+                watcher,
+            )
+        }
+        (
+            Some(Command::Report {
+                report: Some(ReportKind::Disputes { input }),
+                ..
+            }),
+            _,
+        ) => {
+            load(&mut payments, &input, &options, log, &mut sinks, None, None)?;
+            payments.serialize_dispute_report(std::io::stdout())
+        }
+        (
+            Some(Command::Report {
+                report: Some(ReportKind::Counterparties { input }),
+                ..
+            }),
+            _,
+        ) => {
+            load(&mut payments, &input, &options, log, &mut sinks, None, None)?;
+            payments.serialize_exposure(std::io::stdout())
+        }
+        (
+            Some(Command::Report {
+                report:
+                    Some(ReportKind::Lifecycle {
+                        input,
+                        dormant_after_days,
+                        as_of,
+                    }),
+                ..
+            }),
+            _,
+        ) => {
+            load(&mut payments, &input, &options, log, &mut sinks, None, None)?;
+            let dormant_after = days(dormant_after_days)?;
+            payments.serialize_lifecycle(std::io::stdout(), as_of, dormant_after)
+        }
+        (
+            Some(Command::Report {
+                report: None,
+                input: Some(input),
+                as_of: Some(as_of),
+            }),
+            _,
+        ) => {
+            load(&mut payments, &input, &options, log, &mut sinks, None, None)?;
+            payments.as_of(as_of)?.serialize(std::io::stdout())
+        }
+        (
+            Some(Command::Compare {
+                input,
+                config_a,
+                config_b,
+                accounts_a,
+                accounts_b,
+            }),
+            _,
+        ) => {
+            // The accounts of a run, saved or of processing the input with its settings
+            let run = |accounts: Option<std::path::PathBuf>,
+                       settings: Option<std::path::PathBuf>|
+             -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+                if let Some(path) = accounts {
+                    return Ok(std::fs::read(path)?);
+                }
+                let input = input
+                    .as_deref()
+                    .ok_or("either an input or saved accounts")?;
+                let mut config = config.clone();
+                if let Some(path) = settings {
+                    load_config(&path)?.apply_to(&mut config)?;
+                }
+                let mut payments = Payments::with_config(config);
+                let sinks = &mut Sinks::default();
+                load(&mut payments, input, &options, log, sinks, None, None)?;
+                let mut accounts = Vec::new();
+                payments.serialize(&mut accounts)?;
+                Ok(accounts)
+            };
+            let (a, b) = (run(accounts_a, config_a)?, run(accounts_b, config_b)?);
+            let differences = compare(a.as_slice(), b.as_slice())?;
+            write_differences(&differences, std::io::stdout())?;
+            match differences.len() {
+                0 => Ok(()),
+                n => Err(format!("the runs differ in {} fields", n).into()),
+            }
+        }
+        (Some(Command::TestCorpus { dir, update }), _) => {
+            // A failure is part of the output, e.g. of a malformed input
+            let mismatches = replay_corpus(&dir, Normalize::default(), update, |input| {
+                let replay = || -> Result<String, Box<dyn std::error::Error>> {
+                    let mut payments = Payments::with_config(config.clone());
+                    let filename = input.to_string_lossy();
+                    let sinks = &mut Sinks::default();
+                    load(&mut payments, &filename, &options, log, sinks, None, None)?;
+                    let mut accounts = Vec::new();
+                    payments.serialize(&mut accounts)?;
+                    Ok(String::from_utf8(accounts)?)
+                };
+                replay().unwrap_or_else(|e| format!("error: {}\n", e))
+            })?;
+            for mismatch in &mismatches {
+                eprintln!("{}:\n{}", mismatch.input.display(), mismatch.diff);
+            }
+            match mismatches.len() {
+                0 => Ok(()),
+                n => Err(format!("{} corpus inputs changed their output", n).into()),
+            }
+        }
+        (
+            Some(Command::CloseDay {
+                input,
+                date,
+                opening,
+                dispute_timeout_days,
+                daily_fee,
+                daily_fee_tx,
+                daily_interest_rate,
+                out_dir,
+                manifest,
+                allow_duplicate_files,
+                checksum,
+                fx_rates,
+                currency,
+                reporting_currency,
+            }),
+            _,
+        ) => {
+            let fx = fx_rates.as_deref().map(FxRates::load).transpose()?;
+            let options = LoadOptions {
+                checksum,
+                ..options
+            };
+            let mut manifest = ManifestGuard::open(manifest, allow_duplicate_files)?;
+            if let Some(manifest) = &mut manifest {
+                manifest.check(&input, log)?;
+            }
+            let config = Config {
+                dispute_timeout: dispute_timeout_days.map(days).transpose()?,
+                currency: currency.unwrap_or(config.currency),
+                ..config
+            };
+            let mut payments = match opening {
+                Some(snapshot) => open_sealed(&snapshot, config)?,
+                None => Payments::with_config(config),
+            };
+            let opening = payments.marker();
+            let counts = load(&mut payments, &input, &options, log, &mut sinks, None, None)?;
+            let postings = Postings {
+                fee: daily_fee,
+                fee_tx: daily_fee_tx,
+                interest_rate: daily_interest_rate,
+            };
+            let mut close = close_day(&mut payments, opening, date, postings, counts)?;
+            if let (Some(fx), Some(from), Some(to)) = (&fx, &currency, &reporting_currency) {
+                close.summary.convert(fx, (*from, *to))?;
+            }
+            close.write(&out_dir)?;
+            if let Some(manifest) = &mut manifest {
+                manifest.record(close.summary.transactions)?;
+            }
+            println!("{}", serde_json::to_string_pretty(&close.summary)?);
+            Ok(())
+        }
+        (
+            Some(Command::Serve {
+                watch_dir,
+                listen,
+                poll_secs,
+                output_dir,
+                config: config_file,
+                checksum,
+                admin_token_file,
+                admin_journal,
+                snapshot_dir,
+                snapshot_every_secs,
+                sign_output,
+                source_rate_limit,
+                client_rate_limit,
+                overload,
+                schedule,
+                audit_log,
+                source_timeout_secs,
+                stalled_source_unready,
+                tenants: tenanted,
+                tui,
+                default_tenant,
+            }),
+            _,
+        ) => {
+            let mut config = config;
+            let watcher = match config_file {
+                Some(path) => {
+                    let watcher = ConfigWatcher::new(&path);
+                    load_config(&path)?.apply_to(&mut config)?;
+                    Some(watcher)
+                }
+                None => None,
+            };
+            let output_key = sign_output.then(OutputKey::from_env).transpose()?;
+            let options = LoadOptions {
+                checksum,
+                output_key: output_key.clone(),
+                ..options
+            };
+            let (admin, commands, journal) = match admin_token_file {
+                Some(path) => {
+                    let (sender, receiver) = std::sync::mpsc::channel();
+                    let admin = Admin {
+                        token: read_token(&path)?,
+                        commands: sender,
+                        responses: Default::default(),
+                    };
+                    (
+                        Some(admin),
+                        Some(receiver),
+                        Some(Journal::open(admin_journal)?),
+                    )
+                }
+                None => (None, None, None),
+            };
+            let settings = DaemonConfig {
+                dir: watch_dir,
+                poll: std::time::Duration::from_secs(poll_secs),
+                output_dir,
+                snapshot_dir,
+                snapshot_every: snapshot_every_secs.map(std::time::Duration::from_secs),
+                commands,
+                journal,
+                throttle: Throttle {
+                    sources: source_rate_limit.map(RateLimiter::new).transpose()?,
+                    clients: client_rate_limit.map(RateLimiter::new).transpose()?,
+                    overload,
+                },
+                schedule: schedule.as_deref().map(Schedule::load).transpose()?,
+                audit: audit_log.as_deref().map(AuditLog::open).transpose()?,
+                output_key,
+                watchdog: source_timeout_secs.map(|secs| {
+                    Watchdog::new(std::time::Duration::from_secs(secs), stalled_source_unready)
+                }),
+                watcher,
+                tenanted,
+            };
+            let mut daemon = Daemon::new(settings, log);
+            let (addr, _) = server::spawn(&listen, daemon::routes(daemon.shared_status(), admin))?;
+            eprintln!("serving on http://{}", addr);
+            if tui {
+                dashboard::spawn(daemon.shared_status(), std::time::Duration::from_secs(1));
+            }
+            daemon.run(Tenants::new(config, default_tenant), &options, log)
+        }
+        (Some(Command::ServeSnapshot { state, listen }), _) => {
+            let payments = std::sync::Arc::new(query::open(&state)?);
+            let (addr, thread) = server::spawn(&listen, query::routes(payments))?;
+            eprintln!("serving {} on http://{}", state.display(), addr);
+            thread.join().map_err(|_| "the server stopped")?;
+            Ok(())
+        }
+        (
+            Some(Command::Settle {
+                input,
+                format,
+                bank_accounts,
+                debtor_name,
+                debtor_iban,
+                debtor_bic,
+                currency,
+                execution_date,
+            }),
+            _,
+        ) => {
+            load(&mut payments, &input, &options, log, &mut sinks, None, None)?;
+            let instructions = instructions(payments.net_positions());
+            match (format, bank_accounts, debtor_name, debtor_iban) {
+                (SettlementFormat::Pain001, Some(accounts), Some(name), Some(iban)) => {
+                    let accounts = BankAccounts::load(&accounts)?;
+                    let created = Utc::now();
+                    let message = Pain001 {
+                        debtor: BankAccount {
+                            name,
+                            iban,
+                            bic: debtor_bic,
+                        },
+                        currency,
+                        accounts: &accounts,
+                        created,
+                        execution_date: execution_date.unwrap_or(created.date_naive()),
+                    };
+                    write_pain001(&instructions, &message, std::io::stdout())
+                }
+                (SettlementFormat::Pain001, ..) => {
+                    Err("pain.001 requires the debtor's name, IBAN and BIC".into())
+                }
+                (SettlementFormat::Csv, ..) => write_csv(&instructions, std::io::stdout()),
+            }
+        }
+        (
+            Some(Command::Replay {
+                log: path,
+                until,
+                snapshot,
+                config: config_file,
+            }),
+            _,
+        ) => {
+            let mut config = config;
+            if let Some(path) = config_file {
+                load_config(&path)?.apply_to(&mut config)?;
+            }
+            let (from, mut payments) = txlog::open_snapshot(&path, config)?;
+            let input = std::io::BufReader::new(std::fs::File::open(path)?);
+            let end = txlog::replay(&mut payments, &from, input, until)?;
+            let mut accounts = Vec::new();
+            payments.serialize(&mut accounts)?;
+            std::io::Write::write_all(&mut std::io::stdout(), &accounts)?;
+            let Some(snapshot) = snapshot else {
+                return Ok(());
+            };
+            let differences = compare(std::fs::File::open(snapshot)?, accounts.as_slice())?;
+            if differences.is_empty() {
+                return Ok(());
+            }
+            write_differences(&differences, std::io::stderr())?;
+            Err(format!(
+                "the snapshot differs from the {} replayed records in {} fields",
+                end.chain.len(),
+                differences.len()
+            )
+            .into())
+        }
+        (
+            Some(Command::Backfill {
+                state,
+                corrections,
+                output,
+                config: config_file,
+            }),
+            _,
+        ) => {
+            let mut config = config;
+            if let Some(path) = config_file {
+                load_config(&path)?.apply_to(&mut config)?;
+            }
+            let corrections = Correction::load(&corrections)?;
+            let payments = match state.is_dir() {
+                true => Payments::import_sharded_with(config, &state)?,
+                false => Payments::import_events_with(
+                    config,
+                    std::io::BufReader::new(std::fs::File::open(&state)?),
+                )?,
+            };
+            let (corrected, impact) = backfill(&payments, &corrections)?;
+            match state.is_dir() {
+                true => {
+                    let shards = snapshot::shard_files(&state)?.len();
+                    corrected.export_sharded(&output, shards.try_into()?)?
+                }
+                false => corrected
+                    .export_events(std::io::BufWriter::new(std::fs::File::create(&output)?))?,
+            }
+            write_impact(&impact, std::io::stdout())
+        }
+        (
+            Some(Command::Bench {
+                profile,
+                transactions,
+                clients,
+                seed,
+                shards,
+                transaction_log,
+                config: config_file,
+                json,
+            }),
+            _,
+        ) => {
+            let mut config = config;
+            if let Some(path) = config_file {
+                load_config(&path)?.apply_to(&mut config)?;
+            }
+            let options = bench::Options {
+                transactions,
+                clients,
+                seed,
+                shards,
+                transaction_log,
+                config,
+            };
+            let report = bench::run(profile, &options)?;
+            match json {
+                true => println!("{}", serde_json::to_string(&report)?),
+                false => println!("{}", report),
+            }
+            Ok(())
+        }
+        (
+            Some(Command::CompactLog {
+                log: path,
+                config: config_file,
+            }),
+            _,
+        ) => {
+            let mut config = config;
+            if let Some(path) = config_file {
+                load_config(&path)?.apply_to(&mut config)?;
+            }
+            let end = txlog::compact(&path, config)?;
+            println!(
+                "{} records compacted into {}, head {}",
+                end.chain.len(),
+                txlog::snapshot_path(&path).display(),
+                end.chain.head()
+            );
+            Ok(())
+        }
+        (Some(Command::OutputKey), _) => {
+            println!("{}", OutputKey::from_env()?.public_key());
+            Ok(())
+        }
+        (Some(Command::VerifyOutput { path, public_key }), _) => {
+            verify_file(&path, &public_key)?;
+            println!("{}: OK", path.display());
+            Ok(())
+        }
+        (Some(Command::VerifyLog { path, head }), _) => {
+            // A compacted transaction log continues the chain of its snapshot
+            let input = std::io::BufReader::new(std::fs::File::open(&path)?);
+            let chain = txlog::checkpoint(&path)?.chain.extend(input)?;
+            println!("{} records, head {}", chain.len(), chain.head());
+            match head {
+                Some(head) if head != chain.head() => Err(format!(
+                    "the log ends at `{}` instead of `{}`, records were removed",
+                    chain.head(),
+                    head
+                )
+                .into()),
+                _ => Ok(()),
+            }
+        }
+        (
+            Some(Command::Sort {
+                input,
+                by,
+                chunk_rows,
+                tmp_dir,
+            }),
+            _,
+        ) => sort(
+            std::fs::File::open(input)?,
+            std::io::BufWriter::new(std::io::stdout()),
+            by,
+            chunk_rows,
+            &tmp_dir.unwrap_or_else(std::env::temp_dir),
+        ),
+        (None, Some(filename)) if cli.tenants => {
+            if let Some(manifest) = &mut manifest {
+                manifest.check(&filename, log)?;
+            }
+            let mut tenants = Tenants::new(config, cli.default_tenant);
+            let (transactions, _) =
+                load_tenants(&mut tenants, &filename, &options, log, None, None)?;
+            if cli.stats {
+                for (tenant, stats) in tenants.stats() {
+                    log.log(LogEvent::Stats {
+                        tenant: Some(tenant),
+                        stats: &stats,
+                    });
+                }
+                for (tenant, payments) in tenants.iter() {
+                    log_sequence_gaps(payments, Some(tenant), log);
+                }
+            }
+            let dir = cli.output_dir.ok_or("--tenants requires --output-dir")?;
+            tenants.serialize(&dir)?;
+            if let Some(manifest) = &mut manifest {
+                manifest.record(transactions)?;
+            }
+            Ok(())
+        }
+        (None, Some(filename)) => {
+            if let Some(manifest) = &mut manifest {
+                manifest.check(&filename, log)?;
+            }
+            let mut schedule = cli.schedule.as_deref().map(Schedule::load).transpose()?;
+            payments.set_source(cli.source.as_deref());
+            let (transactions, _) = load(
+                &mut payments,
+                &filename,
+                &options,
+                log,
+                &mut sinks,
+                None,
+                schedule.as_mut(),
+            )?;
+            if let Some(path) = &cli.audit_log {
+                AuditLog::open(path)?.record(&payments, Marker::default(), Actor::Transaction)?;
+            }
+            if let (Some(path), Some(merkle)) = (&cli.merkle_root, &sinks.merkle) {
+                let output = std::fs::File::create(path)?;
+                serde_json::to_writer_pretty(output, &merkle.summary())?;
+            }
+            if cli.stats {
+                let stats = payments.stats();
+                log.log(LogEvent::Stats {
+                    tenant: None,
+                    stats: &stats,
+                });
+                log_sequence_gaps(&payments, None, log);
+                log_source_stats(&payments, log);
+            }
+            if cli.verify_parallel {
+                let transactions = read(&filename, &options, |_| {})?;
+                let sharded = process_sharded(transactions, &config, cli.shards.into())?;
+                let diverging = diverging_clients(&payments, &sharded);
+                if !diverging.is_empty() {
+                    return Err(format!(
+                        "parallel processing diverged for clients {:?}",
+                        diverging
+                    )
+                    .into());
+                }
+            }
+            if let Some(events) = cli.export_events {
+                match cli.export_sharded {
+                    true => payments.export_sharded(events.as_ref(), cli.shards)?,
+                    false => payments
+                        .export_events(std::io::BufWriter::new(std::fs::File::create(events)?))?,
+                }
+            }
+            if let Some(disputes) = cli.disputes {
+                payments.serialize_disputes(std::fs::File::create(disputes)?)?;
+            }
+            if let Some(history) = cli.dispute_history {
+                payments.serialize_dispute_history(std::fs::File::create(history)?)?;
+            }
+            if let Some(losses) = cli.chargeback_losses {
+                payments.serialize_chargeback_losses(std::fs::File::create(losses)?)?;
+            }
+            if cli.client_features.is_some() || cli.transaction_features.is_some() {
+                let features = payments.features();
+                if let Some(path) = cli.client_features {
+                    let output = std::fs::File::create(&path)?;
+                    features.write_clients(Format::from_path(&path)?, output)?;
+                }
+                if let Some(path) = cli.transaction_features {
+                    let output = std::fs::File::create(&path)?;
+                    features.write_transactions(Format::from_path(&path)?, output)?;
+                }
+            }
+            let partition = match cli.partition_by.as_deref() {
+                Some("client-shard") => Some(Partition::ClientShard(cli.shards)),
+                Some("locked") => Some(Partition::Locked),
+                Some("currency") => Some(Partition::Currency),
+                _ => None,
+            };
+            match (cli.output_dir, partition) {
+                (Some(dir), Some(partition)) => payments.serialize_partitioned(&dir, partition),
+                (Some(dir), None) => write_accounts(&payments, &dir, options.output_key.as_ref()),
+                (None, _) => payments.serialize(std::io::stdout()),
+            }?;
+            if let Some(manifest) = &mut manifest {
+                manifest.record(transactions)?;
+            }
+            Ok(())
+        }
+        (None, None) => Err("either an input file or a subcommand is required".into()),
+        (Some(Command::Report { .. }), _) => {
+            Err("report requires an input file and --as-of".into())
+        }
+    }
 }
@@ -0,0 +1,204 @@
+//! The manifest of processed input files, protecting against processing a file twice.
+//!
+//! Every processed file is recorded as a JSON line with its name, SHA-256 hash and number of
+//! rows. Files are recognized by their content, so a file dropped again under another name
+//! is still caught.
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use chrono::Utc;
+
+use crate::{
+    checksum::sha256_file,
+    error::Error,
+    log::{LogEvent, Logger},
+    transaction::Timestamp,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub sha256: String,
+    pub rows: usize,
+    pub processed_at: Timestamp,
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Open the manifest at `path`, which is created by the first `record`
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.into();
+        let entries = match File::open(&path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?))
+                .collect::<Result<_, Box<dyn std::error::Error>>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, entries })
+    }
+
+    /// The earlier processing of a file with this hash
+    pub fn find(&self, sha256: &str) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|entry| entry.sha256 == sha256)
+    }
+
+    /// Fails if a file named `name` with this hash was processed before
+    pub fn check(&self, name: &str, sha256: &str) -> Result<(), Error> {
+        match self.find(sha256) {
+            Some(seen) => Err(Error::DuplicateFile {
+                name: name.to_string(),
+                processed_as: seen.name.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Record a processed file
+    pub fn record(&mut self, entry: ManifestEntry) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        serde_json::to_writer(&mut file, &entry)?;
+        writeln!(file)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+/// Input files already processed, checked before a run and recorded after it
+#[derive(Debug)]
+pub struct ManifestGuard {
+    manifest: Manifest,
+    /// Only warn about files processed before
+    allow_duplicates: bool,
+    /// The checked file and its hash, to record once it's processed
+    checked: Option<(String, String)>,
+}
+
+impl ManifestGuard {
+    /// The guard of the manifest at `path`, if any
+    pub fn open(
+        path: Option<PathBuf>,
+        allow_duplicates: bool,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        path.map(|path| {
+            Ok(Self {
+                manifest: Manifest::open(path)?,
+                allow_duplicates,
+                checked: None,
+            })
+        })
+        .transpose()
+    }
+
+    /// Refuse `filename` if it was processed before
+    pub fn check(&mut self, filename: &str, log: Logger) -> Result<(), Box<dyn std::error::Error>> {
+        let sha256 = sha256_file(std::path::Path::new(filename))?;
+        match self.manifest.check(filename, &sha256) {
+            Err(Error::DuplicateFile { processed_as, .. }) if self.allow_duplicates => {
+                log.log(LogEvent::DuplicateFile {
+                    input: filename,
+                    processed_as: &processed_as,
+                })
+            }
+            result => result?,
+        }
+        self.checked = Some((filename.to_string(), sha256));
+        Ok(())
+    }
+
+    /// Record the checked file as processed, with its number of rows
+    pub fn record(&mut self, rows: usize) -> Result<(), Box<dyn std::error::Error>> {
+        match self.checked.take() {
+            Some((name, sha256)) => self.manifest.record(ManifestEntry {
+                name,
+                sha256,
+                rows,
+                processed_at: Utc::now(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{Manifest, ManifestEntry, ManifestGuard};
+    use crate::{
+        checksum::sha256_file,
+        error::Error,
+        log::{LogFormat, Logger},
+    };
+
+    #[test]
+    fn refuses_known_content() {
+        let dir = std::env::temp_dir().join(format!("payments-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, copy) = (dir.join("day1.csv"), dir.join("day1-copy.csv"));
+        std::fs::write(&input, "type, client, tx, amount\ndeposit, 1, 1, 5\n").unwrap();
+        std::fs::copy(&input, &copy).unwrap();
+
+        let path = dir.join("manifest.jsonl");
+        let mut manifest = Manifest::open(&path).unwrap();
+        let sha256 = sha256_file(&input).unwrap();
+        assert_eq!(manifest.check("day1.csv", &sha256), Ok(()));
+        manifest
+            .record(ManifestEntry {
+                name: "day1.csv".to_string(),
+                sha256,
+                rows: 1,
+                processed_at: Utc::now(),
+            })
+            .unwrap();
+
+        let reopened = Manifest::open(&path).unwrap();
+        assert_eq!(
+            reopened.check("day1-copy.csv", &sha256_file(&copy).unwrap()),
+            Err(Error::DuplicateFile {
+                name: "day1-copy.csv".to_string(),
+                processed_as: "day1.csv".to_string()
+            })
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn guard_records_processed_files() {
+        let dir = std::env::temp_dir().join(format!("payments-guard-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("day1.csv");
+        std::fs::write(&input, "type, client, tx, amount\ndeposit, 1, 1, 5\n").unwrap();
+        let (path, filename) = (dir.join("manifest.jsonl"), input.display().to_string());
+        let log = Logger::new(LogFormat::Text);
+        assert!(ManifestGuard::open(None, false).unwrap().is_none());
+
+        let mut guard = ManifestGuard::open(Some(path.clone()), false)
+            .unwrap()
+            .unwrap();
+        guard.check(&filename, log).unwrap();
+        // Checked again as the file wasn't processed yet
+        guard.check(&filename, log).unwrap();
+        guard.record(1).unwrap();
+        assert!(guard.check(&filename, log).is_err());
+
+        let mut lenient = ManifestGuard::open(Some(path), true).unwrap().unwrap();
+        assert!(lenient.check(&filename, log).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
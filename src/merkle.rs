@@ -0,0 +1,122 @@
+//! Merkle root of the accepted transactions, so that two runs over the same input, e.g. in
+//! different data centers, can prove they processed identical data by comparing roots.
+//!
+//! The tree is that of RFC 6962 over the canonical forms of the transactions (see
+//! `signature::canonical`) in the order they were applied: leaves are hashed as
+//! `SHA-256(0x00 || transaction)` and nodes as `SHA-256(0x01 || left || right)`. It's built
+//! as the transactions come, keeping only the roots of the complete subtrees.
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::signature::to_hex;
+
+type Hash = [u8; 32];
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MerkleTree {
+    leaves: u64,
+    /// Roots of the complete subtrees with their heights, the highest first
+    peaks: Vec<(u32, Hash)>,
+}
+
+/// The root of a tree with how many leaves it has
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MerkleSummary {
+    pub transactions: u64,
+    pub root: String,
+}
+
+impl MerkleTree {
+    pub fn push(&mut self, transaction: &str) {
+        let mut hasher = Sha256::new();
+        hasher.update([0]);
+        hasher.update(transaction.as_bytes());
+        let mut peak = (0, hasher.finalize().into());
+        while let Some(&(height, left)) = self.peaks.last() {
+            if height != peak.0 {
+                break;
+            }
+            self.peaks.pop();
+            peak = (height + 1, node(&left, &peak.1));
+        }
+        self.peaks.push(peak);
+        self.leaves += 1;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.leaves
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves == 0
+    }
+
+    /// Hex of the root, that of no data for an empty tree
+    pub fn root(&self) -> String {
+        let root = self
+            .peaks
+            .iter()
+            .rev()
+            .map(|(_, hash)| *hash)
+            .reduce(|right, left| node(&left, &right))
+            .unwrap_or_else(|| Sha256::digest([]).into());
+        to_hex(&root)
+    }
+
+    pub fn summary(&self) -> MerkleSummary {
+        MerkleSummary {
+            transactions: self.leaves,
+            root: self.root(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MerkleTree;
+
+    fn root(leaves: &[&str]) -> String {
+        let mut tree = MerkleTree::default();
+        for leaf in leaves {
+            tree.push(leaf);
+        }
+        tree.root()
+    }
+
+    #[test]
+    fn rfc6962_roots() {
+        assert_eq!(
+            root(&[]),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            root(&[""]),
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d"
+        );
+        // Unbalanced trees split at the largest power of two
+        let leaves = ["a", "b", "c", "d", "e", "f", "g"];
+        let expected = {
+            let mut first = MerkleTree::default();
+            leaves[..4].iter().for_each(|leaf| first.push(leaf));
+            let mut second = MerkleTree::default();
+            leaves[4..].iter().for_each(|leaf| second.push(leaf));
+            let hash = |hex: String| -> [u8; 32] {
+                crate::signature::from_hex(&hex)
+                    .unwrap()
+                    .try_into()
+                    .unwrap()
+            };
+            crate::signature::to_hex(&super::node(&hash(first.root()), &hash(second.root())))
+        };
+        assert_eq!(root(&leaves), expected);
+        assert_ne!(root(&["a", "b"]), root(&["b", "a"]));
+    }
+}
@@ -0,0 +1,168 @@
+//! Parsing of a memory-mapped input file.
+//!
+//! The mapping is split into chunks at record boundaries, which are parsed in parallel a round
+//! of `threads` chunks at a time, so only a bounded number of parsed transactions is kept in memory.
+//! Assumption: records don't contain quoted line breaks, so every line break ends a record.
+//! Positions in parsing errors are relative to the chunk.
+//! With the `simd` feature, chunks are parsed by the SIMD tokenizer, see `simd`.
+use std::{collections::VecDeque, fs::File, io::Read, path::Path};
+
+use memmap2::Mmap;
+
+use crate::{error::Error, parser::parse, transaction::Transaction};
+
+/// Bytes of input parsed by one thread at a time
+const CHUNK_SIZE: usize = 16 << 20;
+
+/// Transactions parsed from a memory-mapped file, in the order of the file
+pub struct MappedTransactions {
+    map: Mmap,
+    /// Length of the header line, including the line break
+    header: usize,
+    /// Where the next round of chunks starts
+    offset: usize,
+    threads: usize,
+    chunk_size: usize,
+    parsed: VecDeque<Result<Transaction, Error>>,
+}
+
+impl MappedTransactions {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the input file must not be modified while it's being processed,
+        // same as when it's read in any other way
+        let map = unsafe { Mmap::map(&file)? };
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Ok(Self::new(map, threads, CHUNK_SIZE))
+    }
+
+    fn new(map: Mmap, threads: usize, chunk_size: usize) -> Self {
+        let header = record_end(&map, 0);
+        Self {
+            map,
+            header,
+            offset: header,
+            threads: threads.max(1),
+            chunk_size: chunk_size.max(1),
+            parsed: VecDeque::new(),
+        }
+    }
+
+    /// Parse the next `threads` chunks in parallel
+    fn parse_round(&mut self) {
+        let data = &self.map[..];
+        let header = &data[..self.header];
+        let mut chunks = Vec::with_capacity(self.threads);
+        while chunks.len() < self.threads && self.offset < data.len() {
+            let end = record_end(data, self.offset + self.chunk_size);
+            chunks.push(&data[self.offset..end]);
+            self.offset = end;
+        }
+        let rounds = std::thread::scope(|scope| {
+            let workers = chunks
+                .into_iter()
+                .map(|chunk| scope.spawn(move || parse_chunk(header, chunk)))
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker.join().unwrap_or_else(|_| {
+                        vec![Err(Error::ParsingFailure(
+                            "parser thread panicked".to_string(),
+                        ))]
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        self.parsed.extend(rounds.into_iter().flatten());
+    }
+}
+
+impl Iterator for MappedTransactions {
+    type Item = Result<Transaction, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.parsed.is_empty() && self.offset < self.map.len() {
+            self.parse_round();
+        }
+        self.parsed.pop_front()
+    }
+}
+
+/// The end of the record containing `from`, just after its line break
+fn record_end(data: &[u8], from: usize) -> usize {
+    match data.get(from..) {
+        Some(rest) => rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(data.len(), |pos| from + pos + 1),
+        None => data.len(),
+    }
+}
+
+fn parse_chunk(header: &[u8], chunk: &[u8]) -> Vec<Result<Transaction, Error>> {
+    #[cfg(feature = "simd")]
+    if crate::simd::is_supported(header) && crate::simd::is_supported(chunk) {
+        return crate::simd::parse(header, chunk).collect();
+    }
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(header.chain(chunk));
+    parse(rdr).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use memmap2::MmapMut;
+
+    use super::{record_end, MappedTransactions};
+    use crate::parser::parse;
+
+    const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+dispute, 1, 1,
+withdrawal, 2, 3, 1.5
+deposit, 3, 4, 4.0
+resolve, 1, 1,
+";
+
+    fn mapped(input: &str, threads: usize, chunk_size: usize) -> MappedTransactions {
+        let mut map = MmapMut::map_anon(input.len()).unwrap();
+        (&mut map[..]).write_all(input.as_bytes()).unwrap();
+        MappedTransactions::new(map.make_read_only().unwrap(), threads, chunk_size)
+    }
+
+    #[test]
+    fn record_boundaries() {
+        let data = b"a,b\nc,d\ne,f";
+        assert_eq!(record_end(data, 0), 4);
+        assert_eq!(record_end(data, 4), 8);
+        assert_eq!(record_end(data, 9), data.len());
+        assert_eq!(record_end(data, 100), data.len());
+    }
+
+    #[test]
+    fn same_as_sequential() {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(INPUT.as_bytes());
+        let expected = parse(rdr).collect::<Vec<_>>();
+        for (threads, chunk_size) in [(1, 1), (2, 10), (3, 25), (8, 1 << 20)] {
+            assert_eq!(
+                mapped(INPUT, threads, chunk_size).collect::<Vec<_>>(),
+                expected,
+                "{} threads, {} byte chunks",
+                threads,
+                chunk_size
+            );
+        }
+        // Without the trailing line break
+        assert_eq!(
+            mapped(INPUT.trim_end(), 2, 10).collect::<Vec<_>>(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Amounts with their currency.
+//!
+//! An account holds a single currency, `Config::currency`, and the engine keeps its balances
+//! as plain decimals. `Money` is how amounts cross the boundary of an account, e.g. the
+//! balances of `Client::balance` or the amount of `Transaction::money`, so that an amount of
+//! one currency can't be added to one of another, or to a decimal of no currency: `Money` has
+//! no arithmetic with `Decimal`, and its checked arithmetic fails with
+//! `Error::CurrencyMismatch` across currencies, which have no order either.
+//!
+//! ```
+//! use payments::money::{Currency, Money};
+//! use rust_decimal_macros::dec;
+//!
+//! let eur = Money::new(dec!(10.5), Currency::EUR);
+//! assert_eq!(eur.checked_add(eur).unwrap().to_string(), "21.0 EUR");
+//! assert!(eur.checked_add(Money::new(dec!(1), "USD".parse().unwrap())).is_err());
+//! ```
+use std::{cmp::Ordering, fmt, ops::Neg, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{error::Error, redact::Redacted};
+
+/// ISO 4217 code, e.g. `EUR`, stored inline so amounts stay `Copy`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const USD: Currency = Currency(*b"USD");
+    pub const GBP: Currency = Currency(*b"GBP");
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+/// The currency of accounts unless configured, see `Config::currency`
+impl Default for Currency {
+    fn default() -> Self {
+        Self::EUR
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    /// Three letters, in any case
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match <[u8; 3]>::try_from(code.as_bytes()) {
+            Ok(code) if code.iter().all(u8::is_ascii_alphabetic) => {
+                Ok(Self(code.map(|b| b.to_ascii_uppercase())))
+            }
+            _ => Err(format!("invalid currency code: `{}`", code)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Currency({})", self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// An amount of `currency`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Money {
+    pub amount: Decimal,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: Decimal, currency: Currency) -> Self {
+        Self { amount, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self::new(Decimal::ZERO, currency)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.amount.is_zero()
+    }
+
+    /// The amount of `self`, failing unless it's of `currency`
+    pub fn amount_in(self, currency: Currency) -> Result<Decimal, Error> {
+        match self.currency == currency {
+            true => Ok(self.amount),
+            false => Err(Error::CurrencyMismatch {
+                expected: currency,
+                actual: self.currency,
+            }),
+        }
+    }
+
+    /// The sum, failing across currencies or out of range
+    pub fn checked_add(self, other: Money) -> Result<Money, Error> {
+        let amount = self
+            .amount
+            .checked_add(other.amount_in(self.currency)?)
+            .ok_or_else(|| Error::Overflow(format!("sum of {} amounts", self.currency)))?;
+        Ok(Self::new(amount, self.currency))
+    }
+
+    /// The difference, failing across currencies or out of range
+    pub fn checked_sub(self, other: Money) -> Result<Money, Error> {
+        self.checked_add(-other)
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Self::new(-self.amount, self.currency)
+    }
+}
+
+/// Amounts of different currencies are unordered
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self.currency == other.currency).then(|| self.amount.cmp(&other.amount))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Redacted(self.amount), self.currency)
+    }
+}
+
+impl fmt::Debug for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Money({:?} {})", Redacted(self.amount), self.currency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{Currency, Money};
+    use crate::error::Error;
+
+    #[test]
+    fn currencies() {
+        assert_eq!("eur".parse::<Currency>(), Ok(Currency::EUR));
+        assert!("EU".parse::<Currency>().is_err());
+        assert!("EU1".parse::<Currency>().is_err());
+        assert!("EURO".parse::<Currency>().is_err());
+        assert_eq!(Currency::default().to_string(), "EUR");
+        let json = serde_json::to_string(&Currency::USD).unwrap();
+        assert_eq!(json, r#""USD""#);
+        assert_eq!(
+            serde_json::from_str::<Currency>(&json).unwrap(),
+            Currency::USD
+        );
+        assert!(serde_json::from_str::<Currency>(r#""US""#).is_err());
+    }
+
+    #[test]
+    fn arithmetic() {
+        let (eur, usd) = (
+            Money::new(dec!(10.5), Currency::EUR),
+            Money::new(dec!(2), Currency::USD),
+        );
+        assert_eq!(
+            eur.checked_sub(Money::new(dec!(0.5), Currency::EUR)),
+            Ok(Money::new(dec!(10), Currency::EUR))
+        );
+        assert_eq!(
+            eur.checked_add(usd),
+            Err(Error::CurrencyMismatch {
+                expected: Currency::EUR,
+                actual: Currency::USD
+            })
+        );
+        assert!(matches!(
+            Money::new(rust_decimal::Decimal::MAX, Currency::EUR).checked_add(eur),
+            Err(Error::Overflow(_))
+        ));
+        assert!(eur > Money::zero(Currency::EUR));
+        assert_eq!(eur.partial_cmp(&usd), None);
+        assert_eq!(usd.amount_in(Currency::USD), Ok(dec!(2)));
+        assert_eq!(format!("{} {:?}", -usd, eur), "-2 USD Money(10.5 EUR)");
+        assert_eq!(
+            serde_json::to_string(&eur).unwrap(),
+            r#"{"amount":"10.5","currency":"EUR"}"#
+        );
+    }
+}
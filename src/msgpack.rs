@@ -0,0 +1,113 @@
+//! MessagePack, a compact binary alternative to the CSV input and the accounts output where
+//! their parsing overhead shows at high message rates (feature `msgpack`).
+//!
+//! Transactions are a stream of maps named as the input's columns, see `Transaction`'s
+//! serde, and account snapshots an array of maps named as the accounts output's columns.
+//! Amounts are strings, so they're exact.
+use std::io::{BufRead, Write};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::ClientId, error::Error, payments::Payments, transaction::Transaction};
+
+fn failure(error: impl std::fmt::Display) -> Error {
+    Error::ParsingFailure(format!("msgpack: {}", error))
+}
+
+pub fn write_transaction(transaction: &Transaction, output: &mut impl Write) -> Result<(), Error> {
+    rmp_serde::encode::write_named(output, transaction).map_err(failure)
+}
+
+/// The transactions of a stream, up to its end
+pub fn read_transactions(
+    mut input: impl BufRead,
+) -> impl Iterator<Item = Result<Transaction, Error>> {
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let transaction = match input.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => rmp_serde::decode::from_read(&mut input).map_err(failure),
+            Err(error) => Err(failure(error)),
+        };
+        failed = transaction.is_err();
+        Some(transaction)
+    })
+}
+
+/// A client's balances in an account snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Write the balances of the clients, sorted by ID
+pub fn write_accounts(payments: &Payments, output: &mut impl Write) -> Result<(), Error> {
+    let accounts = payments
+        .clients()
+        .map(|client| Account {
+            client: client.id,
+            available: client.available(),
+            held: client.held(),
+            total: client.total(),
+            locked: client.locked(),
+        })
+        .collect::<Vec<_>>();
+    rmp_serde::encode::write_named(output, &accounts).map_err(failure)
+}
+
+pub fn read_accounts(input: impl std::io::Read) -> Result<Vec<Account>, Error> {
+    rmp_serde::decode::from_read(input).map_err(failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{read_accounts, read_transactions, write_accounts, write_transaction};
+    use crate::{testing::process, transaction::Transaction};
+
+    #[test]
+    fn round_trips() {
+        let transactions = [
+            Transaction::deposit(1, 1, dec!(1.5)).unwrap().with_batch(2),
+            Transaction::withdrawal(1, 2, dec!(0.25)).unwrap(),
+            Transaction::dispute(1, 1),
+        ];
+        let mut stream = Vec::new();
+        for transaction in &transactions {
+            write_transaction(transaction, &mut stream).unwrap();
+        }
+        let read = read_transactions(stream.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read, transactions);
+        // A truncated stream ends with an error
+        let truncated = read_transactions(&stream[..stream.len() - 1]).collect::<Vec<_>>();
+        assert_eq!(truncated.len(), 3);
+        assert!(truncated[2].is_err());
+
+        let payments = process(
+            "type, client, tx, amount
+            deposit, 2, 1, 5
+            deposit, 1, 2, 3",
+        );
+        let mut snapshot = Vec::new();
+        write_accounts(&payments, &mut snapshot).unwrap();
+        let accounts = read_accounts(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|a| (a.client, a.total))
+                .collect::<Vec<_>>(),
+            [(1, dec!(3)), (2, dec!(5))]
+        );
+    }
+}
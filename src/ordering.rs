@@ -0,0 +1,161 @@
+//! The ordering contract of `Payments::apply_ordered`: the transactions of every client come
+//! in the order they happened, while those of different clients may interleave in any way.
+//! The engine relies on it, e.g. a dispute has to come after its deposit, but can't tell a
+//! misordered input from a valid one: a dispute of a deposit still to come is rejected as
+//! if the deposit didn't exist.
+//!
+//! `ClientOrdered` marks a stream of transactions meeting the contract. In debug builds, it
+//! checks the `seq` column of the transactions carrying one: the sequence numbers of every
+//! client have to increase, which catches ordering bugs upstream early.
+//!
+//! In any build, `Payments` keeps track of the sequence numbers of every client as a
+//! `Sequence`, so that records missing upstream show in the stats rather than going
+//! unnoticed in the balances, see `Payments::sequence_gaps`.
+//!
+//! ```
+//! use payments::{ordering::ClientOrdered, payments::Payments, transaction::Transaction};
+//! use rust_decimal_macros::dec;
+//!
+//! let transactions = [
+//!     Transaction::deposit(1, 1, dec!(10)).unwrap().with_seq(1),
+//!     Transaction::deposit(2, 2, dec!(5)).unwrap().with_seq(1),
+//!     Transaction::dispute(1, 1).with_seq(2),
+//! ];
+//! let mut payments = Payments::default();
+//! let results = payments.apply_ordered(ClientOrdered::new(transactions));
+//! assert!(results.iter().all(Result::is_ok));
+//! ```
+use serde::Serialize;
+
+use crate::transaction::Transaction;
+
+/// The sequence numbers of a client's transactions seen so far. Sequences may start at
+/// any number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Sequence {
+    /// The highest sequence number seen
+    pub last: u64,
+    /// Sequence numbers skipped, of records missing upstream
+    pub missing: u64,
+    /// Transactions whose sequence number didn't increase, out of order or repeated. A
+    /// skipped record arriving late counts as both missing and out of order.
+    pub regressions: u64,
+}
+
+impl Sequence {
+    pub fn new(first: u64) -> Self {
+        Self {
+            last: first,
+            ..Self::default()
+        }
+    }
+
+    /// Account for the next transaction of the client, numbered `seq`
+    pub fn observe(&mut self, seq: u64) {
+        match seq.checked_sub(self.last) {
+            Some(0) | None => self.regressions = self.regressions.saturating_add(1),
+            Some(step) => {
+                self.missing = self.missing.saturating_add(step - 1);
+                self.last = seq;
+            }
+        }
+    }
+
+    pub fn has_gaps(&self) -> bool {
+        self.missing > 0 || self.regressions > 0
+    }
+}
+
+/// Transactions in the order of the ordering contract, see the module documentation
+#[derive(Debug, Clone)]
+pub struct ClientOrdered<I> {
+    transactions: I,
+    /// The latest sequence number of every client
+    #[cfg(debug_assertions)]
+    last_seq: std::collections::HashMap<crate::client::ClientId, u64>,
+}
+
+impl<I: Iterator<Item = Transaction>> ClientOrdered<I> {
+    /// `transactions`, whose producer guarantees that the transactions of every client are
+    /// in the order they happened
+    pub fn new(transactions: impl IntoIterator<IntoIter = I>) -> Self {
+        Self {
+            transactions: transactions.into_iter(),
+            #[cfg(debug_assertions)]
+            last_seq: Default::default(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Transaction>> Iterator for ClientOrdered<I> {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        let transaction = self.transactions.next()?;
+        #[cfg(debug_assertions)]
+        if let Some(seq) = transaction.seq {
+            let last = self.last_seq.insert(transaction.client_id, seq);
+            debug_assert!(
+                last.is_none_or(|last| last < seq),
+                "transaction ID `{}` of client `{}` out of order: sequence number {} after {}",
+                transaction.op.id,
+                transaction.client_id,
+                seq,
+                last.unwrap_or_default()
+            );
+        }
+        Some(transaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientOrdered, Sequence};
+    use crate::{payments::Payments, transaction::Transaction};
+
+    #[test]
+    fn sequence_gaps() {
+        let mut sequence = Sequence::new(5);
+        sequence.observe(6);
+        assert!(!sequence.has_gaps());
+        sequence.observe(9);
+        sequence.observe(8);
+        sequence.observe(9);
+        assert_eq!(
+            sequence,
+            Sequence {
+                last: 9,
+                missing: 2,
+                regressions: 2
+            }
+        );
+    }
+
+    #[test]
+    fn interleaved_clients() {
+        let transactions = [
+            Transaction::deposit(1, 1, 10.into()).unwrap().with_seq(1),
+            Transaction::deposit(2, 2, 5.into()).unwrap().with_seq(7),
+            // Without a sequence number, nothing is checked
+            Transaction::deposit(2, 3, 1.into()).unwrap(),
+            Transaction::dispute(1, 2).with_seq(3),
+            Transaction::dispute(2, 2).with_seq(8),
+        ];
+        let mut payments = Payments::default();
+        let results = payments.apply_ordered(ClientOrdered::new(transactions));
+        assert_eq!(results.len(), 5);
+        assert!(results[3].is_err());
+        assert_eq!(payments.client(2).unwrap().held(), 5.into());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "transaction ID `2` of client `1` out of order")]
+    fn out_of_order() {
+        let transactions = [
+            Transaction::deposit(1, 1, 10.into()).unwrap().with_seq(2),
+            Transaction::dispute(1, 2).with_seq(2),
+        ];
+        Payments::default().apply_ordered(ClientOrdered::new(transactions));
+    }
+}
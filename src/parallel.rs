@@ -0,0 +1,133 @@
+//! Sharded parallel processing.
+//!
+//! Clients are independent of each other, so transactions can be split into shards by
+//! client and every shard processed by its own `Payments` in a separate thread.
+//! Features depending on the order of transactions across clients - the dispute timeout
+//! and risk scores driven by the latest timestamp seen, globally unique transaction IDs and
+//! batches spanning multiple clients - may behave differently than in a single thread,
+//! `diverging_clients` tells whether that's the case for a given input.
+use std::sync::mpsc;
+
+use itertools::Itertools;
+
+use crate::{
+    client::ClientId,
+    error::Error,
+    payments::{Config, Payments},
+    transaction::Transaction,
+};
+
+/// How many transactions may be queued for a shard
+const SHARD_QUEUE: usize = 1024;
+
+/// Apply transactions in `shards` parallel shards by client ID and merge the results.
+/// Stops at the first input error.
+pub fn process_sharded(
+    transactions: impl IntoIterator<Item = Result<Transaction, Error>>,
+    config: &Config,
+    shards: usize,
+) -> Result<Payments, Error> {
+    let shards = shards.max(1);
+    let parts = std::thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..shards)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel::<Transaction>(SHARD_QUEUE);
+                let config = config.clone();
+                let worker = scope.spawn(move || {
+                    let mut payments = Payments::with_config(config);
+                    for transaction in receiver {
+                        // Rejections are reported by the single-threaded run
+                        let _ = payments.apply(transaction);
+                    }
+                    payments
+                });
+                (sender, worker)
+            })
+            .unzip();
+
+        let dispatched = transactions.into_iter().try_for_each(|transaction| {
+            let transaction = transaction?;
+            let shard = usize::from(transaction.client_id) % shards;
+            // A shard stops early only when it panics, which joining it relays
+            let _ = senders[shard].send(transaction);
+            Ok(())
+        });
+        drop(senders);
+        let parts = workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>();
+        dispatched.map(|()| parts)
+    })?;
+    Ok(Payments::merge(config.clone(), parts))
+}
+
+/// IDs of clients whose state differs between `a` and `b`
+pub fn diverging_clients(a: &Payments, b: &Payments) -> Vec<ClientId> {
+    a.clients()
+        .chain(b.clients())
+        .map(|client| client.id)
+        .unique()
+        .filter(|&id| a.client(id) != b.client(id))
+        .sorted()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::{diverging_clients, process_sharded};
+    use crate::{
+        parser::parse,
+        payments::{Config, Payments},
+    };
+
+    fn run(input: &str, config: Config) -> (Payments, Payments) {
+        let reader = || {
+            csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input.as_bytes())
+        };
+        let mut single = Payments::with_config(config.clone());
+        for transaction in parse(reader()) {
+            let _ = single.apply(transaction.unwrap());
+        }
+        let sharded = process_sharded(parse(reader()), &config, 3).unwrap();
+        (single, sharded)
+    }
+
+    #[test]
+    fn same_state() {
+        let input = "type, client, tx, amount
+            deposit, 1, 1, 10
+            deposit, 2, 2, 5
+            withdrawal, 1, 3, 20
+            dispute, 2, 2,
+            deposit, 4, 4, 1
+            withdrawal, 1, 5, 1
+            chargeback, 2, 2,";
+        let (single, sharded) = run(input, Config::default());
+        assert_eq!(diverging_clients(&single, &sharded), Vec::<u16>::new());
+        assert_eq!(sharded.events().len(), single.events().len());
+    }
+
+    #[test]
+    fn divergence() {
+        // Only the single-threaded run sees the clock moving past the dispute's timeout
+        let input = "type, client, tx, amount, timestamp
+            deposit, 1, 1, 10, 2024-01-01T00:00:00Z
+            dispute, 1, 1, , 2024-01-01T00:00:00Z
+            deposit, 2, 2, 5, 2024-01-05T00:00:00Z";
+        let config = Config {
+            dispute_timeout: Some(Duration::days(1)),
+            ..Config::default()
+        };
+        let (single, sharded) = run(input, config);
+        assert_eq!(diverging_clients(&single, &sharded), [1]);
+    }
+}
@@ -1,9 +1,16 @@
+use chrono::NaiveDate;
 use rust_decimal::Decimal;
 use serde::Deserialize;
 
 use crate::{
+    client::ClientId,
+    counterparty::Counterparty,
     error::Error,
-    transaction::{Operation, OperationType, Transaction},
+    quoting::Records,
+    subaccount,
+    transaction::{
+        BatchId, Operation, OperationType, TenantId, Timestamp, Transaction, TransactionId,
+    },
 };
 
 #[derive(Debug, Deserialize, PartialEq)]
@@ -14,6 +21,9 @@ enum ParsedTransactionKind {
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+    Amend,
+    Settle,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
@@ -23,53 +33,254 @@ struct ParsedTransaction {
     client: u16,
     tx: u32,
     amount: Option<Decimal>,
+    /// Optional column, RFC 3339 formatted
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+    /// Optional column, consecutive rows with the same batch ID settle together
+    #[serde(default)]
+    batch: Option<BatchId>,
+    /// Optional column, the transaction a deposit or withdrawal originates from
+    #[serde(default)]
+    ref_tx: Option<TransactionId>,
+    /// Optional column, the tenant the transaction belongs to
+    #[serde(default)]
+    tenant: Option<String>,
+    /// Optional column, the other side of a deposit or withdrawal, see `counterparty`
+    #[serde(default)]
+    counterparty: Option<String>,
+    /// Optional column, hex encoded HMAC of the other fields
+    #[serde(default)]
+    signature: Option<String>,
+    /// Optional column, `YYYY-MM-DD`, the day the funds of a deposit become available
+    #[serde(default)]
+    value_date: Option<NaiveDate>,
+    /// Optional column, the position of the transaction among its client's, see `ordering`
+    #[serde(default)]
+    seq: Option<u64>,
+    /// Optional column, the sub-account a transfer moves funds from, see `subaccount`
+    #[serde(default)]
+    from_account: Option<String>,
+    /// Optional column, the sub-account a transfer moves funds to
+    #[serde(default)]
+    to_account: Option<String>,
+}
+
+/// A tenant name, which is also used for naming its output
+pub fn tenant(name: &str) -> Result<TenantId, Error> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(Error::ParsingFailure(format!(
+            "invalid tenant `{}`, expected letters, digits, `-` and `_`",
+            name
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// A counterparty name
+pub(crate) fn counterparty(name: &str) -> Result<Counterparty, Error> {
+    name.parse().map_err(Error::ParsingFailure)
+}
+
+/// A hex encoded signature
+pub(crate) fn signature(hex: &str) -> Result<Vec<u8>, Error> {
+    crate::signature::from_hex(hex)
+        .ok_or_else(|| Error::ParsingFailure(format!("invalid signature `{}`", hex)))
+}
+
+/// Build a transfer between the accounts `from` and `to` of a client, empty or `main`
+/// for the main account
+pub(crate) fn transfer(
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+    (from, to): (&str, &str),
+) -> Result<Transaction, Error> {
+    let amount = amount.ok_or_else(|| {
+        Error::ParsingFailure("transfer transaction must have amount".to_string())
+    })?;
+    let from = subaccount::account(from).map_err(Error::ParsingFailure)?;
+    let to = subaccount::account(to).map_err(Error::ParsingFailure)?;
+    if from == to {
+        return Err(Error::ParsingFailure(format!(
+            "transfer transaction `{}` must be between different accounts",
+            tx
+        )));
+    }
+    Ok(Transaction {
+        client_id: client,
+        timestamp: None,
+        batch: None,
+        tenant: None,
+        signature: None,
+        value_date: None,
+        seq: None,
+        op: Operation {
+            id: tx,
+            kind: OperationType::Transfer { amount, from, to },
+        },
+    })
+}
+
+/// Build a transaction from its textual parts, e.g. coming from interactive input
+/// or language bindings rather than from a CSV record.
+pub(crate) fn transaction(
+    kind: &str,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+    ref_tx: Option<TransactionId>,
+) -> Result<Transaction, Error> {
+    let amount = || {
+        amount
+            .ok_or_else(|| Error::ParsingFailure(format!("{} transaction must have amount", kind)))
+    };
+    let kind = match kind {
+        "deposit" => OperationType::Deposit {
+            amount: amount()?,
+            ref_tx,
+            counterparty: None,
+        },
+        "withdrawal" => OperationType::Withdrawal {
+            amount: amount()?,
+            ref_tx,
+            counterparty: None,
+        },
+        "dispute" => OperationType::Dispute,
+        "resolve" => OperationType::Resolve,
+        "chargeback" => OperationType::Chargeback,
+        "amend" => OperationType::Amend { amount: amount()? },
+        "settle" => OperationType::Settle,
+        other => {
+            return Err(Error::ParsingFailure(format!(
+                "unknown transaction type `{}`",
+                other
+            )))
+        }
+    };
+    Ok(Transaction {
+        client_id: client,
+        timestamp: None,
+        batch: None,
+        tenant: None,
+        signature: None,
+        value_date: None,
+        seq: None,
+        op: Operation { id: tx, kind },
+    })
 }
 
 pub fn parse<R>(rdr: csv::Reader<R>) -> impl Iterator<Item = Result<Transaction, Error>>
 where
     R: std::io::Read,
 {
-    rdr.into_deserialize::<ParsedTransaction>().map(|trans| {
-        let trans = trans.map_err(|e| Error::ParsingFailure(e.to_string()))?;
-
-        // The intermediate representation is required as `csv` crate doesn't
-        // support serde's internally tagged enums.
-        // We want to guarantee on a type-level that Deposit and Withdrawal have amounts specified.
-        Ok(Transaction {
-            client_id: trans.client,
-            op: Operation {
-                id: trans.tx,
-                kind: match trans.kind {
-                    ParsedTransactionKind::Deposit => OperationType::Deposit {
-                        amount: trans.amount.ok_or_else(|| {
-                            Error::ParsingFailure(
-                                "deposit transaction must have amount".to_string(),
-                            )
-                        })?,
-                    },
-                    ParsedTransactionKind::Withdrawal => OperationType::Withdrawal {
-                        amount: trans.amount.ok_or_else(|| {
-                            Error::ParsingFailure(
-                                "withdrawal transaction must have amount".to_string(),
-                            )
-                        })?,
-                    },
-                    ParsedTransactionKind::Dispute => OperationType::Dispute,
-                    ParsedTransactionKind::Resolve => OperationType::Resolve,
-                    ParsedTransactionKind::Chargeback => OperationType::Chargeback,
+    rdr.into_deserialize::<ParsedTransaction>()
+        .map(|trans| from_parsed(trans.map_err(|e| Error::ParsingFailure(e.to_string()))?))
+}
+
+/// How lenient `parse_quoted` is with malformed input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Take unescaped quotes in quoted fields literally, see `quoting`
+    pub lenient_quotes: bool,
+    /// Accept records with more or fewer fields than the header, the missing ones empty
+    pub flexible: bool,
+}
+
+/// Same as `parse`, for input split into records by `quoting::Records`: quoted fields may
+/// follow whitespace, as in the `, ` separated input. A malformed record fails alone, the
+/// following ones are parsed still.
+pub fn parse_quoted<R>(
+    input: R,
+    options: ParseOptions,
+) -> impl Iterator<Item = Result<Transaction, Error>>
+where
+    R: std::io::BufRead,
+{
+    let mut records = Records::new(input, options);
+    let (headers, failed) = match records.next().transpose() {
+        Ok(headers) => (headers, None),
+        Err(e) => (None, Some(Err(e))),
+    };
+    let transactions = headers.map(|headers| {
+        records.map(move |record| {
+            let trans = record?
+                .deserialize(Some(&headers))
+                .map_err(|e| Error::ParsingFailure(e.to_string()))?;
+            from_parsed(trans)
+        })
+    });
+    failed.into_iter().chain(transactions.into_iter().flatten())
+}
+
+fn from_parsed(trans: ParsedTransaction) -> Result<Transaction, Error> {
+    // The intermediate representation is required as `csv` crate doesn't
+    // support serde's internally tagged enums.
+    // We want to guarantee on a type-level that Deposit and Withdrawal have amounts specified.
+    let counterparty = trans
+        .counterparty
+        .as_deref()
+        .map(counterparty)
+        .transpose()?;
+    Ok(Transaction {
+        client_id: trans.client,
+        timestamp: trans.timestamp,
+        batch: trans.batch,
+        tenant: trans.tenant.as_deref().map(tenant).transpose()?,
+        signature: trans.signature.as_deref().map(signature).transpose()?,
+        value_date: trans.value_date,
+        seq: trans.seq,
+        op: Operation {
+            id: trans.tx,
+            kind: match trans.kind {
+                ParsedTransactionKind::Deposit => OperationType::Deposit {
+                    amount: trans.amount.ok_or_else(|| {
+                        Error::ParsingFailure("deposit transaction must have amount".to_string())
+                    })?,
+                    ref_tx: trans.ref_tx,
+                    counterparty,
                 },
+                ParsedTransactionKind::Withdrawal => OperationType::Withdrawal {
+                    amount: trans.amount.ok_or_else(|| {
+                        Error::ParsingFailure("withdrawal transaction must have amount".to_string())
+                    })?,
+                    ref_tx: trans.ref_tx,
+                    counterparty,
+                },
+                ParsedTransactionKind::Dispute => OperationType::Dispute,
+                ParsedTransactionKind::Resolve => OperationType::Resolve,
+                ParsedTransactionKind::Chargeback => OperationType::Chargeback,
+                ParsedTransactionKind::Amend => OperationType::Amend {
+                    amount: trans.amount.ok_or_else(|| {
+                        Error::ParsingFailure("amend transaction must have amount".to_string())
+                    })?,
+                },
+                ParsedTransactionKind::Settle => OperationType::Settle,
+                ParsedTransactionKind::Transfer => {
+                    let accounts = (
+                        trans.from_account.as_deref().unwrap_or_default(),
+                        trans.to_account.as_deref().unwrap_or_default(),
+                    );
+                    transfer(trans.client, trans.tx, trans.amount, accounts)?
+                        .op
+                        .kind
+                }
             },
-        })
+        },
     })
 }
 
 #[cfg(test)]
 mod tests {
     mod parsing {
+        use chrono::{TimeZone, Utc};
         use rust_decimal_macros::dec;
 
         use crate::error::Error;
-        use crate::parser::parse;
+        use crate::parser::{parse, parse_quoted, ParseOptions};
         use crate::transaction::{Operation, OperationType, Transaction};
 
         macro_rules! parse {
@@ -88,9 +299,19 @@ mod tests {
                 parse!("deposit, 1, 1, 1.0"),
                 vec![Ok(Transaction {
                     client_id: 1,
+                    timestamp: None,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date: None,
+                    seq: None,
                     op: Operation {
                         id: 1,
-                        kind: OperationType::Deposit { amount: dec!(1.0) }
+                        kind: OperationType::Deposit {
+                            amount: dec!(1.0),
+                            ref_tx: None,
+                            counterparty: None
+                        }
                     }
                 })]
             );
@@ -100,14 +321,78 @@ mod tests {
             ));
         }
         #[test]
+        fn parse_timestamp() {
+            let input = "type, client, tx, amount, timestamp\n\
+                         deposit, 1, 1, 1.0, 2024-03-31T12:00:00Z\n\
+                         dispute, 1, 1, ,";
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input.as_bytes());
+            assert_eq!(
+                parse(rdr).collect::<Vec<Result<Transaction, _>>>(),
+                vec![
+                    Ok(Transaction {
+                        client_id: 1,
+                        timestamp: Some(Utc.with_ymd_and_hms(2024, 3, 31, 12, 0, 0).unwrap()),
+                        batch: None,
+                        tenant: None,
+                        signature: None,
+                        value_date: None,
+                        seq: None,
+                        op: Operation {
+                            id: 1,
+                            kind: OperationType::Deposit {
+                                amount: dec!(1.0),
+                                ref_tx: None,
+                                counterparty: None
+                            }
+                        }
+                    }),
+                    Ok(Transaction {
+                        client_id: 1,
+                        timestamp: None,
+                        batch: None,
+                        tenant: None,
+                        signature: None,
+                        value_date: None,
+                        seq: None,
+                        op: Operation {
+                            id: 1,
+                            kind: OperationType::Dispute
+                        }
+                    })
+                ]
+            );
+
+            let input = "type, client, tx, amount, timestamp\n\
+                         deposit, 1, 1, 1.0, yesterday";
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input.as_bytes());
+            assert!(matches!(
+                parse(rdr).collect::<Vec<_>>()[..],
+                [Err(Error::ParsingFailure(_))]
+            ));
+        }
+        #[test]
         fn parse_withdrawal() {
             assert_eq!(
                 parse!("withdrawal, 1, 1, 1.0"),
                 vec![Ok(Transaction {
                     client_id: 1,
+                    timestamp: None,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date: None,
+                    seq: None,
                     op: Operation {
                         id: 1,
-                        kind: OperationType::Withdrawal { amount: dec!(1.0) }
+                        kind: OperationType::Withdrawal {
+                            amount: dec!(1.0),
+                            ref_tx: None,
+                            counterparty: None
+                        }
                     }
                 })]
             );
@@ -117,11 +402,73 @@ mod tests {
             ));
         }
         #[test]
+        fn parse_ref_tx() {
+            let input = "type, client, tx, amount, timestamp, batch, ref_tx\n\
+                         withdrawal, 1, 2, 1.0, , , 1";
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input.as_bytes());
+            assert_eq!(
+                parse(rdr).collect::<Vec<Result<Transaction, _>>>(),
+                vec![Ok(Transaction {
+                    client_id: 1,
+                    timestamp: None,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date: None,
+                    seq: None,
+                    op: Operation {
+                        id: 2,
+                        kind: OperationType::Withdrawal {
+                            amount: dec!(1.0),
+                            ref_tx: Some(1),
+                            counterparty: None
+                        }
+                    }
+                })]
+            );
+        }
+        #[test]
+        fn parse_counterparty() {
+            let input = "type, client, tx, amount, counterparty\n\
+                         deposit, 1, 1, 1.0, bank-a\n\
+                         withdrawal, 1, 2, 1.0,\n\
+                         deposit, 1, 3, 1.0, bank a";
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input.as_bytes());
+            let parsed = parse(rdr).collect::<Vec<Result<Transaction, _>>>();
+            assert_eq!(
+                parsed[0].as_ref().unwrap().op.kind,
+                OperationType::Deposit {
+                    amount: dec!(1.0),
+                    ref_tx: None,
+                    counterparty: Some("bank-a".parse().unwrap())
+                }
+            );
+            assert_eq!(
+                parsed[1].as_ref().unwrap().op.kind,
+                OperationType::Withdrawal {
+                    amount: dec!(1.0),
+                    ref_tx: None,
+                    counterparty: None
+                }
+            );
+            assert!(matches!(parsed[2], Err(Error::ParsingFailure(_))));
+        }
+        #[test]
         fn parse_dispute() {
             assert_eq!(
                 parse!("dispute, 1, 1,"),
                 vec![Ok(Transaction {
                     client_id: 1,
+                    timestamp: None,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date: None,
+                    seq: None,
                     op: Operation {
                         id: 1,
                         kind: OperationType::Dispute
@@ -132,6 +479,12 @@ mod tests {
                 parse!("dispute, 1, 1, 1"),
                 vec![Ok(Transaction {
                     client_id: 1,
+                    timestamp: None,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date: None,
+                    seq: None,
                     op: Operation {
                         id: 1,
                         kind: OperationType::Dispute
@@ -145,6 +498,12 @@ mod tests {
                 parse!("resolve, 1, 1,"),
                 vec![Ok(Transaction {
                     client_id: 1,
+                    timestamp: None,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date: None,
+                    seq: None,
                     op: Operation {
                         id: 1,
                         kind: OperationType::Resolve
@@ -158,6 +517,12 @@ mod tests {
                 parse!("chargeback, 1, 1,"),
                 vec![Ok(Transaction {
                     client_id: 1,
+                    timestamp: None,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date: None,
+                    seq: None,
                     op: Operation {
                         id: 1,
                         kind: OperationType::Chargeback
@@ -165,5 +530,107 @@ mod tests {
                 })]
             );
         }
+        #[test]
+        fn parse_transfer() {
+            let input = "type, client, tx, amount, from_account, to_account\n\
+                         transfer, 1, 1, 2.5, main, savings\n\
+                         transfer, 1, 2, 1, savings, main\n\
+                         transfer, 1, 3, 1, , main\n\
+                         transfer, 1, 4, , savings,\n\
+                         transfer, 1, 5, 1, sav ings,";
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input.as_bytes());
+            let parsed = parse(rdr).collect::<Vec<_>>();
+            let savings = "savings".parse().ok();
+            assert_eq!(
+                parsed[0].as_ref().unwrap().op.kind,
+                OperationType::Transfer {
+                    amount: dec!(2.5),
+                    from: None,
+                    to: savings
+                }
+            );
+            assert_eq!(
+                parsed[1].as_ref().unwrap().op.kind,
+                OperationType::Transfer {
+                    amount: dec!(1),
+                    from: savings,
+                    to: None
+                }
+            );
+            for invalid in &parsed[2..] {
+                assert!(matches!(invalid, Err(Error::ParsingFailure(_))));
+            }
+        }
+
+        #[test]
+        fn malformed_rows_fail_alone() {
+            let input = b"type, client, tx, amount\n\
+                          deposit, 1, 1\n\
+                          deposit, 1, 2, 1.0, 3\n\
+                          deposit, 1, 3, \xff\n\
+                          deposit, 1, 4, 1.0\n";
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input.as_slice());
+            let parsed = parse(rdr).collect::<Vec<_>>();
+            assert_eq!(parsed.len(), 4);
+            assert!(parsed[..3].iter().all(Result::is_err));
+            assert_eq!(parsed[3].as_ref().unwrap().op.id, 4);
+
+            let ids = |flexible| {
+                let options = ParseOptions {
+                    flexible,
+                    ..ParseOptions::default()
+                };
+                parse_quoted(input.as_slice(), options)
+                    .map(|trans| trans.ok().map(|trans| trans.op.id))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(ids(false), [None, None, None, Some(4)]);
+            // The missing amount is still missing
+            assert_eq!(ids(true), [None, Some(2), None, Some(4)]);
+        }
+
+        #[test]
+        fn parse_quoted_fields() {
+            // The `csv` reader takes quotes right after the comma
+            let input = "type,client,tx,amount,memo\n\
+                         deposit,1,1,\"2.5\",\"rent, March\"";
+            let parsed = parse(csv::Reader::from_reader(input.as_bytes())).collect::<Vec<_>>();
+            assert!(matches!(
+                parsed[0].as_ref().unwrap().op.kind,
+                OperationType::Deposit { amount, .. } if amount == dec!(2.5)
+            ));
+
+            let input = "type, client, tx, amount, memo\n\
+                         deposit, 1, 1, \"2.5\", \"rent, \"\"March\"\"\"\n\
+                         deposit, 1, 2, 1.0, \"12\" screen\"\n\
+                         deposit, 1, 3, 1.0.0,\n\
+                         withdrawal, 1, 4, \"1\",";
+            let amounts = |lenient_quotes| {
+                let options = ParseOptions {
+                    lenient_quotes,
+                    ..ParseOptions::default()
+                };
+                parse_quoted(input.as_bytes(), options)
+                    .map(|trans| match trans?.op.kind {
+                        OperationType::Deposit { amount, .. }
+                        | OperationType::Withdrawal { amount, .. } => Ok(amount),
+                        _ => unreachable!(),
+                    })
+                    .collect::<Vec<Result<_, Error>>>()
+            };
+            let strict = amounts(false);
+            assert_eq!(strict[0], Ok(dec!(2.5)));
+            assert!(strict[1].is_err());
+            assert!(strict[2].is_err());
+            assert_eq!(strict[3], Ok(dec!(1)));
+            let lenient = amounts(true);
+            assert_eq!(lenient[1], Ok(dec!(1)));
+            assert!(lenient[2].is_err());
+            assert_eq!(lenient.len(), 4);
+        }
     }
 }
@@ -1,29 +1,2189 @@
+use chrono::{Duration, NaiveTime};
 use itertools::Itertools;
-use std::collections::HashMap;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    fs::File,
+    mem::size_of,
+    path::Path,
+    sync::Arc,
+};
 
 use crate::{
-    client::{Client, ClientId},
+    access::Access,
+    approval::{AdminAction, Approval, OperatorId, PendingApproval},
+    builder::PaymentsBuilder,
+    cdc::{self, BalanceChange},
+    client::{
+        Client, ClientId, DisputePolicy, Limits, OperationState, OperationStatus,
+        WithdrawalChargeback,
+    },
+    counterparty::Counterparty,
+    credit::CreditLimits,
+    dedup::{DedupIndex, DedupScope},
     error::Error,
-    transaction::Transaction,
+    event::{ClientEvent, Event},
+    features::Features,
+    latency::Latencies,
+    lifecycle::{self, LifecycleRecord, Status},
+    money::Currency,
+    ordering::{ClientOrdered, Sequence},
+    quarantine::{Parked, Released},
+    redact::Redacted,
+    reserve::Reserves,
+    risk::RiskProfile,
+    signature::SigningKey,
+    snapshot,
+    source::{Rejection, SourceStats, Sources},
+    transaction::{
+        shift, BatchId, Operation, OperationType, Timestamp, Transaction, TransactionId,
+    },
 };
 
+/// Tunable behavior of `Payments`
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Disputes open for longer than this are automatically resolved,
+    /// releasing the held funds back to available (requires timestamps)
+    pub dispute_timeout: Option<Duration>,
+    /// Deposits are pending for this long before their funds are available, or until a
+    /// `settle` row of them comes, see `OperationType::Settle`. Without a timestamp,
+    /// only the `settle` row makes them available.
+    pub settlement_delay: Option<Duration>,
+    /// Withdrawals of clients with a risk score above this are rejected
+    pub max_risk_score: Option<f64>,
+    /// Clients whose risk score a transaction raises above this are quarantined, see
+    /// `quarantine`
+    pub quarantine_risk_score: Option<f64>,
+    /// Operator actions of a larger amount need the approval of a second operator, see
+    /// `approval`
+    pub approval_threshold: Option<Decimal>,
+    /// Add the `risk_score` column to the accounts output
+    pub risk_score_column: bool,
+    /// Add the `disputes` column to the accounts output, the disputed transactions of
+    /// the client as `tx:state:held` separated by spaces
+    pub dispute_columns: bool,
+    /// Add the `sub_accounts` column to the accounts output, the sub-accounts of the client
+    /// as `name:available` separated by spaces, see `subaccount`
+    pub sub_account_columns: bool,
+    /// Minimum balances withdrawals can't take the clients below, see `reserve`
+    pub reserves: Reserves,
+    /// Add the `reserved` column to the accounts output, the reserve of the client
+    pub reserve_column: bool,
+    /// Add the `pending` column to the accounts output, deposited funds not available until
+    /// their value date or settlement
+    pub pending_column: bool,
+    /// Clients allowed to overdraw their accounts, see `credit`. The accounts output gets
+    /// the `credit_limit` and `credit_drawn` columns if there are any.
+    pub credit_limits: CreditLimits,
+    /// Clients whose transactions are rejected, see `access`
+    pub access: Access,
+    /// Where transaction IDs have to be unique, see `dedup`
+    pub dedup_scope: DedupScope,
+    /// Approximate memory limit in bytes, see `Payments::memory_usage`
+    pub max_memory: Option<usize>,
+    /// Only keep a client created by a transaction if the transaction succeeds,
+    /// so failed first transactions don't leave empty accounts behind
+    pub create_clients_on_success: bool,
+    /// Leave empty accounts out of the output, see `Client::is_empty`
+    pub skip_empty_accounts: bool,
+    pub withdrawal_chargeback: WithdrawalChargeback,
+    /// What disputes of transfers do when their funds moved on, see `DisputePolicy`
+    pub transfer_dispute: DisputePolicy,
+    /// Only apply transactions signed with this key, see `signature`
+    pub signing_key: Option<SigningKey>,
+    /// Currency of the accounts, see `money`
+    pub currency: Currency,
+    /// Whether a rejected transaction fails a run, see `pipeline::apply_all`
+    pub error_policy: ErrorPolicy,
+    /// Called with the events of every applied transaction
+    pub observers: Observers,
+}
+
+/// What a rejected transaction does to a run. `Payments::apply` returns the rejection
+/// either way and the state stays as it was before the transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Count it and go on with the next transaction
+    #[default]
+    Skip,
+    /// Fail the run with `Error::Rejected`
+    Strict,
+}
+
+/// Callbacks for the events of applied transactions, e.g. to export or monitor them. They
+/// also see the events of batches rolled back later, see `Payments::rollback_to`, but not
+/// the ones of a replay or a merge.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<Observer>>);
+
+pub type Observer = dyn Fn(&ClientEvent) + Send + Sync;
+
+impl Observers {
+    pub fn push(&mut self, observer: impl Fn(&ClientEvent) + Send + Sync + 'static) {
+        self.0.push(Arc::new(observer));
+    }
+
+    fn notify(&self, event: &ClientEvent) {
+        self.0.iter().for_each(|observer| observer(event));
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+/// The balances a client would have after a transaction, see `Payments::preview`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalancePreview {
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    /// What the transaction would record
+    pub events: Vec<Event>,
+}
+
+/// How often `Config::max_memory` is checked, in transactions
+const MEMORY_CHECK_INTERVAL: usize = 1024;
+
+/// What's kept in memory, see `Payments::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub clients: usize,
+    pub operations: usize,
+    pub events: usize,
+    pub open_disputes: usize,
+    /// Charged back withdrawals and transfers and negative balances written off, see
+    /// `WithdrawalChargeback`, `DisputePolicy` and `Payments::write_off`
+    pub written_off: Decimal,
+    /// Approximate, see `Payments::memory_usage`
+    pub memory_bytes: usize,
+    /// Transactions rejected as their client is blocked, see `access`
+    pub blocked: usize,
+    /// Records missing upstream by the sequence numbers of the clients, see `ordering`
+    pub sequence_gaps: u64,
+    /// Transactions whose sequence number didn't increase, see `ordering`
+    pub sequence_regressions: u64,
+    /// Clients in quarantine and the transactions they parked, see `quarantine`
+    pub quarantined: usize,
+    pub parked: usize,
+    /// Accounts locked by a chargeback
+    pub locked: usize,
+    /// Operator actions waiting for approval, see `approval`
+    pub pending_approvals: usize,
+    /// See `Payments::record_latency`
+    pub latencies: Latencies,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "clients: {}, operations: {}, events: {}, open disputes: {}, written off: {}, blocked: {}, sequence gaps: {}, sequence regressions: {}, quarantined: {}, parked: {}, locked: {}, pending approvals: {}, memory: {:.1} MiB",
+            self.clients,
+            self.operations,
+            self.events,
+            self.open_disputes,
+            Redacted(self.written_off),
+            self.blocked,
+            self.sequence_gaps,
+            self.sequence_regressions,
+            self.quarantined,
+            self.parked,
+            self.locked,
+            self.pending_approvals,
+            self.memory_bytes as f64 / (1 << 20) as f64
+        )?;
+        for latency in self.latencies.summary() {
+            write!(
+                f,
+                ", {} p50: {:?}, p99: {:?}",
+                latency.kind,
+                std::time::Duration::from_nanos(latency.p50_ns),
+                std::time::Duration::from_nanos(latency.p99_ns)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How to split the accounts output into files, see `Payments::serialize_partitioned`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partition {
+    /// `shard-<n>.csv`, by client ID modulo the number of shards
+    ClientShard(u16),
+    /// `locked.csv` and `unlocked.csv`
+    Locked,
+    /// `<currency>.csv`, e.g. `EUR.csv`, by the currency of the account
+    Currency,
+}
+
+impl Partition {
+    fn file_name(self, client: &Client) -> String {
+        match self {
+            Partition::ClientShard(shards) => {
+                format!("shard-{:03}.csv", client.id % shards.max(1))
+            }
+            Partition::Locked if client.locked() => "locked.csv".to_string(),
+            Partition::Locked => "unlocked.csv".to_string(),
+            Partition::Currency => format!("{}.csv", client.currency()),
+        }
+    }
+}
+
+/// A position in the event log to roll back to, see `Payments::marker`. The default is the
+/// start of the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Marker(usize);
+
+/// The batch transactions are currently applied in
+#[derive(Debug, Clone, Copy)]
+struct OpenBatch {
+    id: BatchId,
+    start: Marker,
+    rolled_back: bool,
+}
+
+/// Set the funds of a deposit aside until `until`, see `Payments::pending_until`
+fn hold(until: Option<Option<Timestamp>>, events: &mut Vec<Event>) {
+    if let (Some(until), Some(&Event::FundsDeposited { tx, amount, .. })) = (until, events.first())
+    {
+        events.push(Event::FundsPending { tx, amount, until });
+    }
+}
+
+/// A dispute which was neither resolved nor charged back yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OpenDispute {
+    amount: Decimal,
+    since: Option<Timestamp>,
+}
+
+/// A deposit whose funds aren't available until its value date or settlement
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PendingDeposit {
+    amount: Decimal,
+    /// None until settled
+    until: Option<Timestamp>,
+}
+
+/// A row of the accounts output
+#[derive(Debug, Serialize)]
+struct AccountRecord {
+    client: ClientId,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    risk_score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disputes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub_accounts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserved: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_drawn: Option<Decimal>,
+}
+
+/// A row of the dispute register export
+#[derive(Debug, Serialize)]
+struct DisputeRecord {
+    client: ClientId,
+    tx: TransactionId,
+    amount: Decimal,
+    held_since: Option<Timestamp>,
+    age_days: Option<i64>,
+}
+
+/// A row of the dispute history export
+#[derive(Debug, Serialize)]
+struct DisputeHistoryRecord {
+    client: ClientId,
+    tx: TransactionId,
+    amount: Decimal,
+    state: OperationState,
+    held: Decimal,
+}
+
+/// A row of the chargeback loss report
+#[derive(Debug, Clone, Default, Serialize)]
+struct ChargebackLossRecord {
+    /// None for the total of the run
+    client: Option<ClientId>,
+    chargebacks: usize,
+    /// Deposits charged back
+    charged_back: Decimal,
+    /// Charged back withdrawals credited back to the client
+    reversed: Decimal,
+    /// Charged back withdrawals and negative balances written off
+    written_off: Decimal,
+    /// How far the client's total is below zero
+    negative_total: Decimal,
+    /// What the house lost: `reversed + written_off + negative_total`
+    lost: Decimal,
+}
+
+/// A row of the dispute outcome report
+#[derive(Debug, Clone, Serialize)]
+struct DisputeReportRecord {
+    /// None for the whole run
+    client: Option<ClientId>,
+    opened: usize,
+    resolved: usize,
+    charged_back: usize,
+    /// Mean amount of the disputed transactions
+    average_amount: Option<Decimal>,
+    /// Seconds from opening a dispute to resolving or charging it back, of the disputes
+    /// with timestamps
+    latency_p50: Option<i64>,
+    latency_p90: Option<i64>,
+    latency_p99: Option<i64>,
+}
+
+/// Dispute outcomes of a client or the whole run
 #[derive(Debug, Default)]
+struct DisputeOutcomes {
+    opened: usize,
+    resolved: usize,
+    charged_back: usize,
+    amount: Decimal,
+    latencies: Vec<i64>,
+}
+
+impl DisputeOutcomes {
+    fn add(&mut self, other: &Self) {
+        self.opened += other.opened;
+        self.resolved += other.resolved;
+        self.charged_back += other.charged_back;
+        self.amount += other.amount;
+        self.latencies.extend_from_slice(&other.latencies);
+    }
+
+    fn record(mut self, client: Option<ClientId>) -> DisputeReportRecord {
+        self.latencies.sort_unstable();
+        DisputeReportRecord {
+            client,
+            opened: self.opened,
+            resolved: self.resolved,
+            charged_back: self.charged_back,
+            average_amount: (self.opened > 0).then(|| {
+                (self.amount / Decimal::from(self.opened))
+                    .round_dp(4)
+                    .normalize()
+            }),
+            latency_p50: percentile(&self.latencies, 50),
+            latency_p90: percentile(&self.latencies, 90),
+            latency_p99: percentile(&self.latencies, 99),
+        }
+    }
+}
+
+/// The nearest-rank `p`th percentile of `sorted`
+fn percentile(sorted: &[i64], p: usize) -> Option<i64> {
+    let rank = (sorted.len() * p).div_ceil(100);
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+/// Operations of `client` which were disputed, by transaction ID
+fn disputed(client: &Client) -> impl Iterator<Item = OperationStatus> + '_ {
+    client
+        .operations()
+        .filter(|op| op.state != OperationState::New)
+}
+
+/// A row of the counterparty exposure report
+#[derive(Debug, Clone, Default, Serialize)]
+struct ExposureRecord {
+    /// None for the total of the run
+    counterparty: Option<Counterparty>,
+    /// Clients with any operations with the counterparty
+    clients: usize,
+    deposited: Decimal,
+    withdrawn: Decimal,
+    /// `deposited + withdrawn`
+    gross: Decimal,
+    /// `deposited - withdrawn`
+    net: Decimal,
+}
+
+impl ExposureRecord {
+    fn add(&mut self, amount: Decimal) {
+        match amount.is_sign_negative() {
+            true => self.withdrawn -= amount,
+            false => self.deposited += amount,
+        }
+        self.gross = self.deposited + self.withdrawn;
+        self.net = self.deposited - self.withdrawn;
+    }
+}
+
+/// Cloning forks the state for speculative processing: clients and the event log are
+/// shared copy-on-write, so a clone costs a pointer per client, and a client or the
+/// event log is only copied once it changes in either branch.
+#[derive(Debug, Default, Clone)]
 pub struct Payments {
-    clients: HashMap<ClientId, Client>,
+    config: Config,
+    clients: HashMap<ClientId, Arc<Client>>,
+    /// Every event emitted by clients, in the order they happened
+    events: Arc<Vec<ClientEvent>>,
+    /// Offsets in `events` at which each applied transaction's events start
+    applied: Arc<Vec<usize>>,
+    batch: Option<OpenBatch>,
+    /// Disputes in progress, a read model derived from the event log
+    disputes: BTreeMap<(ClientId, TransactionId), OpenDispute>,
+    /// The disputes in progress with a timestamp, by the time they were opened, so the
+    /// expired ones come first, see `Config::dispute_timeout`
+    expiring: BTreeSet<(Timestamp, ClientId, TransactionId)>,
+    /// Deposits waiting for their value date, a read model derived from the event log
+    pending: BTreeMap<(ClientId, TransactionId), PendingDeposit>,
+    /// Operator actions waiting for approval, a read model derived from the event log
+    approvals: BTreeMap<(ClientId, AdminAction), PendingApproval>,
+    /// Transaction IDs seen in the global uniqueness mode, derived from the event log
+    dedup: Option<DedupIndex>,
+    /// Risk statistics of clients, derived from the event log
+    risk: HashMap<ClientId, RiskProfile>,
+    /// Latest activity of clients, see `lifecycle`, derived from the event log
+    last_activity: HashMap<ClientId, Timestamp>,
+    /// The house loss account: charged back withdrawals and negative balances written off,
+    /// derived from the event log
+    written_off: Decimal,
+    /// The latest timestamp seen so far
+    clock: Option<Timestamp>,
+    /// The clock before every move forward, with the length of the event log then
+    clock_history: Arc<Vec<(usize, Option<Timestamp>)>>,
+    /// Offsets in `events` of the events of every client, to roll back client by client
+    by_client: Arc<HashMap<ClientId, Vec<usize>>>,
+    /// Transactions applied since the memory usage was last checked
+    unchecked: usize,
+    /// Transactions rejected by `Config::access`
+    blocked: usize,
+    /// Sequence numbers of the clients' transactions, see `ordering`. Not derived from the
+    /// event log, transactions which were rejected or rolled back were received still.
+    sequences: HashMap<ClientId, Sequence>,
+    latencies: Latencies,
+    /// Where the transactions came from, see `source`
+    sources: Sources,
+    /// Transactions of quarantined clients, see `quarantine`
+    parked: Parked,
 }
 
 impl Payments {
+    /// Compose the configuration, see `builder`
+    pub fn builder() -> PaymentsBuilder {
+        PaymentsBuilder::new()
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            dedup: match &config.dedup_scope {
+                DedupScope::Global(dedup) => Some(DedupIndex::new(dedup)),
+                DedupScope::Client | DedupScope::ClientOperation => None,
+            },
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Change the configuration, keeping the state. The duplicate detection is set up
+    /// once, changes of `dedup_scope` are ignored.
+    pub fn reconfigure(&mut self, config: Config) {
+        self.config = Config {
+            dedup_scope: std::mem::take(&mut self.config.dedup_scope),
+            ..config
+        };
+    }
+
     /// Apply a transaction
+    /// A batch is a run of consecutive transactions with the same batch ID. If one of them
+    /// fails, the effects of the whole batch are rolled back and the rest of it is skipped.
     pub fn apply(&mut self, transaction: Transaction) -> Result<(), Error> {
+        let (client, tx) = (transaction.client_id, transaction.op.id);
+        let result = self.apply_batched(transaction);
+        self.sources.received(result.as_ref().err());
+        if let Err(error) = &result {
+            self.sources.rejected(client, tx, error);
+        }
+        result
+    }
+
+    /// Write the transaction IDs seen since the last commit to the dedup confirmation store
+    fn commit_ids(&mut self) {
+        if let Some(dedup) = &mut self.dedup {
+            dedup.commit();
+        }
+    }
+
+    fn apply_batched(&mut self, transaction: Transaction) -> Result<(), Error> {
+        if let Some(seq) = transaction.seq {
+            self.sequences
+                .entry(transaction.client_id)
+                .and_modify(|sequence| sequence.observe(seq))
+                .or_insert_with(|| Sequence::new(seq));
+        }
+        if let Some(limit) = self.config.max_memory {
+            self.check_memory(limit)?;
+        }
+        if let Some(now) = transaction.timestamp {
+            self.advance_to(now);
+        }
+        let batch = match transaction.batch {
+            Some(batch) => batch,
+            None => {
+                self.batch = None;
+                let result = self.apply_signed(transaction);
+                self.commit_ids();
+                return result;
+            }
+        };
+        let start = match self.batch {
+            Some(open) if open.id == batch && open.rolled_back => {
+                return Err(Error::BatchAborted {
+                    batch,
+                    id: transaction.op.id,
+                })
+            }
+            Some(open) if open.id == batch => open.start,
+            _ => {
+                // The previous batch can't be rolled back anymore
+                self.commit_ids();
+                self.marker()
+            }
+        };
+        let result = self.apply_signed(transaction);
+        if let Err(reason) = result {
+            self.rollback_to(start);
+            self.batch = Some(OpenBatch {
+                id: batch,
+                start,
+                rolled_back: true,
+            });
+            return Err(Error::BatchRolledBack {
+                batch,
+                reason: Box::new(reason),
+            });
+        }
+        self.batch = Some(OpenBatch {
+            id: batch,
+            start,
+            rolled_back: false,
+        });
+        Ok(())
+    }
+
+    /// Apply transactions meeting the ordering contract, see `ordering`. Returns the result of
+    /// every transaction, in order.
+    pub fn apply_ordered<I>(&mut self, transactions: ClientOrdered<I>) -> Vec<Result<(), Error>>
+    where
+        I: Iterator<Item = Transaction>,
+    {
+        transactions
+            .map(|transaction| self.apply(transaction))
+            .collect()
+    }
+
+    /// The effect `transaction` would have on its client, without applying it.
+    /// Disputes expiring by the transaction's timestamp and batches aren't taken into account.
+    pub fn preview(&self, transaction: &Transaction) -> Result<BalancePreview, Error> {
+        self.config.access.check(transaction.client_id)?;
+        self.check(transaction)?;
+        let new = Client::with_currency(transaction.client_id, self.config.currency);
+        let client = self.client(transaction.client_id).unwrap_or(&new);
+        let mut events = client.decide(
+            &transaction.op,
+            (
+                self.config.withdrawal_chargeback,
+                self.config.transfer_dispute,
+            ),
+            &self.config.dedup_scope,
+            self.limits(transaction.client_id),
+        )?;
+        hold(self.pending_until(transaction), &mut events);
+        let mut after = client.balances();
+        for event in &events {
+            after.evolve(event)?;
+        }
+        Ok(BalancePreview {
+            client: after.id,
+            available: after.available(),
+            held: after.held(),
+            total: after.total(),
+            locked: after.locked(),
+            events,
+        })
+    }
+
+    /// Whether the funds of a deposit `transaction` are pending, and until when. A deposit with
+    /// a value date after the day of its timestamp is pending until the start of the value
+    /// date (UTC), with `Config::settlement_delay` at least the delay after its timestamp, and
+    /// without a timestamp until it's settled. Without a timestamp, the value date is ignored.
+    fn pending_until(&self, transaction: &Transaction) -> Option<Option<Timestamp>> {
+        let value_date =
+            transaction
+                .value_date
+                .zip(transaction.timestamp)
+                .and_then(|(date, timestamp)| {
+                    let start = date.and_time(NaiveTime::MIN).and_utc();
+                    (start > timestamp).then_some(start)
+                });
+        match self.config.settlement_delay {
+            Some(delay) => Some(
+                transaction
+                    .timestamp
+                    .map(|timestamp| shift(timestamp, delay).max(value_date.unwrap_or(timestamp))),
+            ),
+            None => value_date.map(Some),
+        }
+    }
+
+    /// How far withdrawals of `client` may go
+    fn limits(&self, client: ClientId) -> Limits {
+        Limits {
+            reserve: self.config.reserves.reserve(client),
+            credit_limit: self.config.credit_limits.limit(client),
+        }
+    }
+
+    /// Checks of a transaction beyond the client's own
+    fn check(&self, transaction: &Transaction) -> Result<(), Error> {
+        if let (OperationType::Withdrawal { .. }, Some(max)) =
+            (&transaction.op.kind, self.config.max_risk_score)
+        {
+            let score = self.risk_score(transaction.client_id);
+            if score > max {
+                return Err(Error::RiskScoreExceeded {
+                    id: transaction.op.id,
+                    score,
+                });
+            }
+        }
+
+        if let (
+            OperationType::Deposit { .. }
+            | OperationType::Withdrawal { .. }
+            | OperationType::Transfer { .. },
+            Some(dedup),
+        ) = (&transaction.op.kind, &self.dedup)
+        {
+            if dedup.contains(transaction.op.id.into())? {
+                return Err(Error::DuplicatedTransaction(transaction.op.id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a transaction of the input, unless its client is blocked, verifying its
+    /// signature if required
+    fn apply_signed(&mut self, transaction: Transaction) -> Result<(), Error> {
+        if let Err(blocked) = self.config.access.check(transaction.client_id) {
+            self.blocked += 1;
+            return Err(blocked);
+        }
+        if let Some(key) = &self.config.signing_key {
+            key.verify(&transaction)?;
+        }
+        let (client, id, timestamp) = (
+            transaction.client_id,
+            transaction.op.id,
+            transaction.timestamp,
+        );
+        if self.client(client).is_some_and(Client::quarantined) {
+            let parked = Event::TransactionParked {
+                tx: id,
+                operation: Some(transaction.op.kind),
+                value_date: transaction.value_date,
+            };
+            self.post_events(client, vec![parked], timestamp, None)?;
+            return Ok(());
+        }
+        let Some(max) = self.config.quarantine_risk_score else {
+            return self.apply_one(transaction, None);
+        };
+        let before = self.risk_score(client);
+        self.apply_one(transaction, None)?;
+        if before <= max && self.risk_score(client) > max {
+            let events = vec![Event::ClientQuarantined { tx: Some(id) }];
+            self.post_events(client, events, timestamp, None)?;
+        }
+        Ok(())
+    }
+
+    /// Apply `transaction`, recording its events as caused by `operator`, if it was one
+    fn apply_one(
+        &mut self,
+        transaction: Transaction,
+        operator: Option<OperatorId>,
+    ) -> Result<(), Error> {
+        self.check(&transaction)?;
+        let limits = self.limits(transaction.client_id);
+        let pending_until = self.pending_until(&transaction);
+        let is_new = !self.clients.contains_key(&transaction.client_id);
+        let currency = self.config.currency;
         let client = self
             .clients
             .entry(transaction.client_id)
-            .or_insert_with(|| Client::new(transaction.client_id));
+            .or_insert_with(|| Arc::new(Client::with_currency(transaction.client_id, currency)));
+
+        // By default, a client created by a failed transaction is kept, see README
+        let mut events = match client.decide(
+            &transaction.op,
+            (
+                self.config.withdrawal_chargeback,
+                self.config.transfer_dispute,
+            ),
+            &self.config.dedup_scope,
+            limits,
+        ) {
+            Err(error) if is_new && self.config.create_clients_on_success => {
+                self.clients.remove(&transaction.client_id);
+                return Err(error);
+            }
+            Err(error) if is_new => {
+                let events = vec![Event::ClientCreated];
+                self.post_events(
+                    transaction.client_id,
+                    events,
+                    transaction.timestamp,
+                    operator,
+                )?;
+                return Err(error);
+            }
+            result => result?,
+        };
+        hold(pending_until, &mut events);
+        client.balances_after(&events)?;
+        // Copied here if shared with a clone
+        let client = Arc::make_mut(client);
+        for event in &events {
+            client.evolve(event)?;
+        }
+        Arc::make_mut(&mut self.applied).push(self.events.len());
+        self.sources
+            .applied(transaction.client_id, transaction.op.id, self.events.len());
+        for event in events {
+            self.emit(ClientEvent {
+                client: transaction.client_id,
+                timestamp: transaction.timestamp,
+                operator,
+                event,
+            });
+        }
+        Ok(())
+    }
+
+    /// Every `MEMORY_CHECK_INTERVAL` transactions, compact the state if it uses more
+    /// than `limit` bytes. Fails if that doesn't help, and keeps failing until it does.
+    fn check_memory(&mut self, limit: usize) -> Result<(), Error> {
+        self.unchecked += 1;
+        if self.unchecked < MEMORY_CHECK_INTERVAL {
+            return Ok(());
+        }
+        if self.memory_usage() > limit {
+            self.compact();
+            let used = self.memory_usage();
+            if used > limit {
+                return Err(Error::MemoryLimitExceeded { used, limit });
+            }
+        }
+        self.unchecked = 0;
+        Ok(())
+    }
+
+    /// Release memory reserved for future growth
+    /// Shared parts are left as they are, compacting them would copy them.
+    pub fn compact(&mut self) {
+        if let Some(events) = Arc::get_mut(&mut self.events) {
+            events.shrink_to_fit();
+        }
+        if let Some(applied) = Arc::get_mut(&mut self.applied) {
+            applied.shrink_to_fit();
+        }
+        if let Some(history) = Arc::get_mut(&mut self.clock_history) {
+            history.shrink_to_fit();
+        }
+        if let Some(by_client) = Arc::get_mut(&mut self.by_client) {
+            by_client.shrink_to_fit();
+            by_client.values_mut().for_each(Vec::shrink_to_fit);
+        }
+        self.clients.shrink_to_fit();
+        self.clients
+            .values_mut()
+            .filter_map(Arc::get_mut)
+            .for_each(Client::shrink_to_fit);
+        self.risk.shrink_to_fit();
+        self.sources.shrink_to_fit();
+    }
+
+    /// A fork to read from, e.g. to write a snapshot on another thread while transactions are
+    /// applied to this one. Unlike `clone`, it leaves out the index of duplicates, which isn't
+    /// shared copy-on-write and which only applying transactions needs.
+    pub fn view(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            clients: self.clients.clone(),
+            events: Arc::clone(&self.events),
+            applied: Arc::clone(&self.applied),
+            batch: None,
+            disputes: self.disputes.clone(),
+            expiring: self.expiring.clone(),
+            pending: self.pending.clone(),
+            approvals: self.approvals.clone(),
+            dedup: None,
+            risk: self.risk.clone(),
+            last_activity: self.last_activity.clone(),
+            written_off: self.written_off,
+            clock: self.clock,
+            clock_history: Arc::clone(&self.clock_history),
+            by_client: Arc::clone(&self.by_client),
+            unchecked: self.unchecked,
+            blocked: self.blocked,
+            sequences: self.sequences.clone(),
+            latencies: self.latencies,
+            sources: self.sources.clone(),
+            parked: self.parked.clone(),
+        }
+    }
+
+    /// Approximate heap memory used, in bytes: the clients, their operations,
+    /// the event log and the read models derived from it. Parts shared with
+    /// clones are counted in full.
+    pub fn memory_usage(&self) -> usize {
+        // Hash maps keep a control byte per bucket, B-tree nodes are mostly full
+        let clients = self.clients.capacity() * (size_of::<(ClientId, Arc<Client>)>() + 1)
+            + self
+                .clients
+                .values()
+                .map(|client| size_of::<Client>() + client.memory_usage())
+                .sum::<usize>();
+        let events = self.events.capacity() * size_of::<ClientEvent>()
+            + self.applied.capacity() * size_of::<usize>()
+            + self.clock_history.capacity() * size_of::<(usize, Option<Timestamp>)>()
+            + self.by_client.capacity() * (size_of::<(ClientId, Vec<usize>)>() + 1)
+            + self
+                .by_client
+                .values()
+                .map(|offsets| offsets.capacity() * size_of::<usize>())
+                .sum::<usize>();
+        let disputes =
+            self.disputes.len() * size_of::<((ClientId, TransactionId), OpenDispute)>() * 3 / 2;
+        let pending = self.pending.len()
+            * size_of::<((ClientId, TransactionId), PendingDeposit)>()
+            * 3
+            / 2
+            + self.approvals.len() * size_of::<((ClientId, AdminAction), PendingApproval)>() * 3
+                / 2;
+        let risk = self.risk.capacity() * (size_of::<(ClientId, RiskProfile)>() + 1);
+        let activity = self.last_activity.capacity() * (size_of::<(ClientId, Timestamp)>() + 1);
+        let sequences = self.sequences.capacity() * (size_of::<(ClientId, Sequence)>() + 1);
+        clients
+            + events
+            + disputes
+            + pending
+            + risk
+            + activity
+            + sequences
+            + self.sources.memory_usage()
+            + self.parked.memory_usage()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            clients: self.clients.len(),
+            operations: self
+                .clients
+                .values()
+                .map(|client| client.operation_count())
+                .sum(),
+            events: self.events.len(),
+            open_disputes: self.disputes.len(),
+            written_off: self.written_off,
+            memory_bytes: self.memory_usage(),
+            blocked: self.blocked,
+            sequence_gaps: self
+                .sequences
+                .values()
+                .fold(0, |gaps, s| gaps.saturating_add(s.missing)),
+            sequence_regressions: self.sequences.values().fold(0, |regressions, s| {
+                regressions.saturating_add(s.regressions)
+            }),
+            quarantined: self
+                .clients
+                .values()
+                .filter(|client| client.quarantined())
+                .count(),
+            parked: self.parked.len(),
+            locked: self
+                .clients
+                .values()
+                .filter(|client| client.locked())
+                .count(),
+            pending_approvals: self.approvals.len(),
+            latencies: self.latencies,
+        }
+    }
+
+    /// The clients whose sequence numbers have gaps or regressions, sorted by ID, see
+    /// `ordering`
+    pub fn sequence_gaps(&self) -> impl Iterator<Item = (ClientId, Sequence)> + '_ {
+        self.sequences
+            .iter()
+            .filter(|(_, sequence)| sequence.has_gaps())
+            .map(|(&client, &sequence)| (client, sequence))
+            .sorted_by_key(|&(client, _)| client)
+    }
+
+    /// Attribute the transactions applied from now on to `source`, e.g. the partner feed they
+    /// come from, or to none, see `source`
+    pub fn set_source(&mut self, source: Option<&str>) {
+        self.sources.set(source);
+    }
+
+    /// Account for a malformed row of the input skipped, as a rejection of the current source
+    pub fn record_malformed(&mut self, error: &Error) {
+        self.sources.received(Some(error));
+    }
+
+    /// The source the transactions are attributed to
+    pub fn source(&self) -> Option<&str> {
+        self.sources.current()
+    }
+
+    /// The source transaction `tx` of `client` was applied from, if it was attributed to one
+    pub fn source_of(&self, client: ClientId, tx: TransactionId) -> Option<&str> {
+        self.sources.source_of(client, tx)
+    }
+
+    /// The transactions received from every source, in the order the sources were first set
+    pub fn source_stats(&self) -> impl Iterator<Item = (&str, &SourceStats)> {
+        self.sources.stats()
+    }
+
+    /// The latest rejected transactions, oldest first, see `source::RECENT_REJECTIONS`. Like
+    /// the counts of `source_stats`, they include transactions which were rolled back.
+    pub fn recent_rejections(&self) -> impl Iterator<Item = &Rejection> {
+        self.sources.recent()
+    }
+
+    /// Account for the time it took to parse and apply a transaction of `kind`, reported
+    /// by `stats`
+    pub fn record_latency(&mut self, kind: &OperationType, latency: std::time::Duration) {
+        self.latencies.record(kind, latency);
+    }
+
+    /// Record `event` of an applied transaction, telling the observers
+    fn emit(&mut self, event: ClientEvent) {
+        self.config.observers.notify(&event);
+        self.record(event);
+    }
+
+    fn record(&mut self, event: ClientEvent) {
+        let offset = self.events.len();
+        self.observe(&event, offset);
+        match event.event {
+            Event::FundsDeposited { tx, .. }
+            | Event::FundsWithdrawn { tx, .. }
+            | Event::FundsTransferred { tx, .. } => {
+                if let Some(dedup) = &mut self.dedup {
+                    dedup.insert(tx.into());
+                }
+            }
+            Event::WithdrawalWrittenOff { amount, .. }
+            | Event::TransferWrittenOff { amount, .. }
+            | Event::BalanceWrittenOff { amount } => self.written_off += amount,
+            _ => {}
+        }
+        self.advance_clock(event.timestamp);
+        Arc::make_mut(&mut self.by_client)
+            .entry(event.client)
+            .or_default()
+            .push(offset);
+        Arc::make_mut(&mut self.events).push(event);
+    }
+
+    /// Move the clock forward to `timestamp`, keeping where it was to restore on a rollback
+    fn advance_clock(&mut self, timestamp: Option<Timestamp>) {
+        if timestamp > self.clock {
+            Arc::make_mut(&mut self.clock_history).push((self.events.len(), self.clock));
+            self.clock = timestamp;
+        }
+    }
+
+    /// Keep the read models of the event's client up to date with the event at `offset`
+    fn observe(&mut self, event: &ClientEvent, offset: usize) {
+        match event.event {
+            Event::FundsHeld { tx, amount, .. } => {
+                let dispute = OpenDispute {
+                    amount,
+                    since: event.timestamp,
+                };
+                self.close_dispute(event.client, tx);
+                self.disputes.insert((event.client, tx), dispute);
+                if let Some(since) = dispute.since {
+                    self.expiring.insert((since, event.client, tx));
+                }
+            }
+            Event::FundsReleased { tx, .. } | Event::FundsChargedBack { tx, .. } => {
+                self.close_dispute(event.client, tx);
+            }
+            Event::FundsPending { tx, amount, until } => {
+                self.pending
+                    .insert((event.client, tx), PendingDeposit { amount, until });
+            }
+            Event::FundsCleared { tx, .. } => {
+                self.pending.remove(&(event.client, tx));
+            }
+            Event::ApprovalRequested { action, amount } => {
+                if let Some(requested_by) = event.operator {
+                    self.approvals.insert(
+                        (event.client, action),
+                        PendingApproval {
+                            client: event.client,
+                            action,
+                            amount,
+                            requested_by,
+                            since: event.timestamp,
+                        },
+                    );
+                }
+            }
+            Event::ActionApproved { action, .. } => {
+                self.approvals.remove(&(event.client, action));
+            }
+            Event::TransactionParked {
+                tx,
+                operation: Some(kind),
+                value_date,
+            } => {
+                let transaction = Transaction {
+                    op: Operation { id: tx, kind },
+                    client_id: event.client,
+                    timestamp: event.timestamp,
+                    batch: None,
+                    tenant: None,
+                    signature: None,
+                    value_date,
+                    seq: None,
+                };
+                self.parked
+                    .park(offset, transaction, self.sources.current_index());
+            }
+            Event::TransactionReleased { tx } => {
+                self.parked.take(event.client, Some(tx));
+            }
+            Event::QuarantineLifted => {
+                self.parked.take(event.client, None);
+            }
+            _ => {}
+        }
+        self.risk
+            .entry(event.client)
+            .or_default()
+            .observe(&event.event, event.timestamp);
+        if let (true, Some(timestamp)) = (lifecycle::is_activity(&event.event), event.timestamp) {
+            let last = self.last_activity.entry(event.client).or_insert(timestamp);
+            *last = (*last).max(timestamp);
+        }
+    }
+
+    /// Forget the dispute of `tx` of `client` in the read models, if it's open
+    fn close_dispute(&mut self, client: ClientId, tx: TransactionId) {
+        if let Some(OpenDispute {
+            since: Some(since), ..
+        }) = self.disputes.remove(&(client, tx))
+        {
+            self.expiring.remove(&(since, client, tx));
+        }
+    }
+
+    /// Resolve disputes which are open for longer than the configured timeout.
+    /// The release is recorded at the moment the dispute expired, unless that
+    /// would put it before already recorded events. Like `post_daily`, the releases aren't
+    /// transactions. Returns how many were released.
+    fn release_expired_disputes(&mut self, now: Timestamp) -> usize {
+        let Some(timeout) = self.config.dispute_timeout else {
+            return 0;
+        };
+        let expired = self
+            .expiring
+            .iter()
+            .map(|&(since, client, tx)| (shift(since, timeout), client, tx))
+            .take_while(|&(expiry, ..)| expiry < now)
+            .collect::<Vec<_>>();
+        let mut released = 0;
+        for (expiry, client, tx) in expired {
+            let timestamp = self.clock.map_or(expiry, |clock| clock.max(expiry));
+            // A locked account rejects the release, its funds stay held
+            let Some(events) = self
+                .clients
+                .get(&client)
+                .filter(|state| !state.locked())
+                .and_then(|state| state.force_resolve(tx).ok())
+            else {
+                continue;
+            };
+            // Nor are funds released which don't fit the balances
+            if self
+                .post_events(client, events, Some(timestamp), None)
+                .is_ok()
+            {
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// Make the funds of the deposits pending until `now` available. Like the release of a
+    /// dispute, clearing is recorded when they were due or at the latest timestamp.
+    fn clear_pending(&mut self, now: Timestamp) {
+        let due = self
+            .pending
+            .iter()
+            .filter_map(|(&(client, tx), pending)| {
+                let until = pending.until.filter(|&until| until <= now)?;
+                Some((client, tx, until))
+            })
+            .collect::<Vec<_>>();
+        for (client, tx, until) in due {
+            let timestamp = self.clock.map_or(until, |clock| clock.max(until));
+            let Some(state) = self.clients.get(&client) else {
+                continue;
+            };
+            let events = state.clear(tx);
+            // Funds which don't fit the balances stay pending
+            let _ = self.post_events(client, events, Some(timestamp), None);
+        }
+    }
 
-        // TODO: what if:
-        // The client has just been inserted (it's a new one) AND
-        // the operation failed.
-        client.apply(transaction.op)
+    /// Move the clock forward to `now` without a transaction, releasing the disputes
+    /// expired by then and clearing the deposits due by then. Returns how many
+    /// disputes were released.
+    pub fn advance_to(&mut self, now: Timestamp) -> usize {
+        self.clear_pending(now);
+        let released = self.release_expired_disputes(now);
+        self.advance_clock(Some(now));
+        released
+    }
+
+    /// Charge every unlocked client a flat `fee` and pay `interest_rate` on its available
+    /// funds, both at `timestamp`. Interest is rounded to 4 decimal places and the fee is
+    /// capped by the available funds after it, so no account is overdrawn by it.
+    /// The postings aren't transactions, they can only be undone with `rollback_to`, but fees
+    /// posted with an ID `fee_tx` can be disputed. Nothing is posted if the interest of a client
+    /// is out of range, or if a client has a transaction `fee_tx` already.
+    pub fn post_daily(
+        &mut self,
+        (fee, fee_tx): (Decimal, Option<TransactionId>),
+        interest_rate: Decimal,
+        timestamp: Timestamp,
+    ) -> Result<(), Error> {
+        let ids = self
+            .clients
+            .values()
+            .filter(|client| !client.locked())
+            .map(|client| client.id)
+            .sorted()
+            .collect::<Vec<_>>();
+        if let Some(tx) = fee_tx {
+            let taken = |id| {
+                self.clients
+                    .get(id)
+                    .is_some_and(|client| client.has_transaction(tx))
+            };
+            if ids.iter().any(taken) {
+                return Err(Error::DuplicatedTransaction(tx));
+            }
+        }
+        let interests = ids
+            .iter()
+            .map(|id| {
+                let client = self.clients.get(id).ok_or(Error::ClientNotFound(*id))?;
+                client
+                    .available()
+                    .checked_mul(interest_rate)
+                    .map(|interest| interest.round_dp(4).max(Decimal::ZERO))
+                    .filter(|&interest| client.total().checked_add(interest).is_some())
+                    .ok_or_else(|| Error::Overflow(format!("interest of client `{}`", id)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (id, interest) in ids.into_iter().zip(interests) {
+            let client = self.clients.get(&id).ok_or(Error::ClientNotFound(id))?;
+            // Both are booked to the main account, see `subaccount`
+            let fee = fee
+                .min(client.account_available(None) + interest)
+                .max(Decimal::ZERO);
+            let events = [
+                (!interest.is_zero()).then_some(Event::InterestPaid { amount: interest }),
+                (!fee.is_zero()).then_some(Event::FeeCharged {
+                    amount: fee,
+                    tx: fee_tx,
+                }),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+            self.post_events(id, events, Some(timestamp), None)?;
+        }
+        Ok(())
+    }
+
+    /// Unlock the account of `client`, locked by a chargeback, e.g. once the claim is settled
+    /// outside of the engine, recording the `operator` doing it. Taken through `act`.
+    pub(crate) fn unlock(
+        &mut self,
+        client: ClientId,
+        operator: Option<OperatorId>,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let locked = self
+            .client(client)
+            .ok_or(Error::ClientNotFound(client))?
+            .locked();
+        if !locked {
+            return Err(Error::AccountNotLocked(client));
+        }
+        self.post_events(client, vec![Event::AccountUnlocked], timestamp, operator)?;
+        Ok(())
+    }
+
+    /// Zero the negative available funds of `client`, e.g. left by a chargeback of funds it
+    /// already spent, booking them as a loss of the house. Returns the amount written off.
+    /// Taken through `act`.
+    pub(crate) fn write_off(
+        &mut self,
+        client: ClientId,
+        operator: Option<OperatorId>,
+        timestamp: Option<Timestamp>,
+    ) -> Result<Decimal, Error> {
+        let amount = -self
+            .client(client)
+            .ok_or(Error::ClientNotFound(client))?
+            .account_available(None);
+        if amount <= Decimal::ZERO {
+            return Err(Error::BalanceNotNegative(client));
+        }
+        let events = vec![Event::BalanceWrittenOff { amount }];
+        self.post_events(client, events, timestamp, operator)?;
+        Ok(amount)
+    }
+
+    /// Close the account of `client`, e.g. on the client's request, rejecting its transactions
+    /// from then on. Only an account without funds can be closed, closing a closed one does
+    /// nothing. Like `post_daily`, this isn't a transaction.
+    pub fn close_account(
+        &mut self,
+        client: ClientId,
+        operator: Option<OperatorId>,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let state = self.client(client).ok_or(Error::ClientNotFound(client))?;
+        if state.closed() {
+            return Ok(());
+        }
+        if !state.total().is_zero() || !state.held().is_zero() {
+            return Err(Error::AccountNotEmpty(client));
+        }
+        self.post_events(client, vec![Event::AccountClosed], timestamp, operator)?;
+        Ok(())
+    }
+
+    /// Take `action` on `client` as `operator`, unless its amount is above
+    /// `Config::approval_threshold`: then it's taken once another operator asks for it as well,
+    /// see `approval`. An approval is refused if the amount grew above the one asked for, the
+    /// operator who asked for it can ask again for the new amount. Like `post_daily`, this
+    /// isn't a transaction.
+    pub fn act(
+        &mut self,
+        client: ClientId,
+        action: AdminAction,
+        operator: OperatorId,
+        timestamp: Option<Timestamp>,
+    ) -> Result<Approval, Error> {
+        let amount = self.action_amount(client, action)?;
+        let needs_approval = self
+            .config
+            .approval_threshold
+            .is_some_and(|threshold| amount > threshold);
+        match self.approvals.get(&(client, action)) {
+            Some(pending) if amount > pending.amount && pending.requested_by != operator => {
+                Err(Error::ApprovalExceeded {
+                    client,
+                    requested: pending.amount,
+                    amount,
+                })
+            }
+            Some(pending) if needs_approval && pending.requested_by == operator => {
+                match amount > pending.amount {
+                    true => self.request_approval(client, action, amount, operator, timestamp),
+                    false => Err(Error::SameOperator { client, operator }),
+                }
+            }
+            Some(_) => {
+                let amount = self.take_action(client, action, operator, timestamp)?;
+                let events = vec![Event::ActionApproved { action }];
+                self.post_events(client, events, timestamp, Some(operator))?;
+                Ok(Approval::Taken { amount })
+            }
+            None if needs_approval => {
+                self.request_approval(client, action, amount, operator, timestamp)
+            }
+            None => Ok(Approval::Taken {
+                amount: self.take_action(client, action, operator, timestamp)?,
+            }),
+        }
+    }
+
+    /// Record that `operator` asks for `action` on `client` of `amount`, waiting for approval
+    fn request_approval(
+        &mut self,
+        client: ClientId,
+        action: AdminAction,
+        amount: Decimal,
+        operator: OperatorId,
+        timestamp: Option<Timestamp>,
+    ) -> Result<Approval, Error> {
+        let events = vec![Event::ApprovalRequested { action, amount }];
+        self.post_events(client, events, timestamp, Some(operator))?;
+        Ok(Approval::Pending { amount })
+    }
+
+    /// What `action` on `client` is about, failing if it can't be taken
+    fn action_amount(&self, client: ClientId, action: AdminAction) -> Result<Decimal, Error> {
+        let state = self.client(client).ok_or(Error::ClientNotFound(client))?;
+        match action {
+            AdminAction::Unlock if !state.locked() => Err(Error::AccountNotLocked(client)),
+            AdminAction::Unlock => Ok(state.total().abs()),
+            AdminAction::WriteOff => match -state.account_available(None) {
+                amount if amount > Decimal::ZERO => Ok(amount),
+                _ => Err(Error::BalanceNotNegative(client)),
+            },
+            AdminAction::ForceResolve { tx } => Ok(state
+                .force_resolve(tx)?
+                .iter()
+                .map(|event| match event {
+                    Event::FundsReleased { amount, .. } => *amount,
+                    _ => Decimal::ZERO,
+                })
+                .sum()),
+        }
+    }
+
+    /// Take `action` on `client` as `operator`, returning its amount
+    fn take_action(
+        &mut self,
+        client: ClientId,
+        action: AdminAction,
+        operator: OperatorId,
+        timestamp: Option<Timestamp>,
+    ) -> Result<Decimal, Error> {
+        let amount = self.action_amount(client, action)?;
+        let operator = Some(operator);
+        match action {
+            AdminAction::Unlock => self.unlock(client, operator, timestamp)?,
+            AdminAction::WriteOff => {
+                self.write_off(client, operator, timestamp)?;
+            }
+            AdminAction::ForceResolve { tx } => {
+                self.force_resolve(client, tx, operator, timestamp)?
+            }
+        }
+        Ok(amount)
+    }
+
+    /// Operator actions waiting for the approval of a second operator, by client, see
+    /// `approval`
+    pub fn pending_approvals(&self) -> impl Iterator<Item = &PendingApproval> {
+        self.approvals.values()
+    }
+
+    /// Park the transactions of `client` from now on, instead of applying them, see
+    /// `quarantine`. Quarantining a quarantined client does nothing. Like `post_daily`, this
+    /// isn't a transaction.
+    pub fn quarantine(
+        &mut self,
+        client: ClientId,
+        operator: Option<OperatorId>,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let state = self.client(client).ok_or(Error::ClientNotFound(client))?;
+        if !state.quarantined() {
+            let events = vec![Event::ClientQuarantined { tx: None }];
+            self.post_events(client, events, timestamp, operator)?;
+        }
+        Ok(())
+    }
+
+    /// Lift the quarantine of `client`, applying its parked transactions in the order they
+    /// came, as released by `operator`. Returns the result of every one of them.
+    pub fn lift_quarantine(
+        &mut self,
+        client: ClientId,
+        operator: Option<OperatorId>,
+        timestamp: Option<Timestamp>,
+    ) -> Result<Released, Error> {
+        let state = self.client(client).ok_or(Error::ClientNotFound(client))?;
+        if !state.quarantined() {
+            return Err(Error::NotQuarantined(client));
+        }
+        let parked = self.parked.take(client, None);
+        self.post_events(client, vec![Event::QuarantineLifted], timestamp, operator)?;
+        Ok(parked
+            .into_iter()
+            .map(|(transaction, source)| {
+                let tx = transaction.op.id;
+                (tx, self.release_one(transaction, source, operator))
+            })
+            .collect())
+    }
+
+    /// Apply the first parked transaction `tx` of `client`, keeping the quarantine. Fails
+    /// with `TransactionNotFound` if there's none, or with why the transaction was rejected.
+    /// Its events are recorded as caused by `operator`.
+    pub fn release(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        operator: Option<OperatorId>,
+    ) -> Result<(), Error> {
+        let (transaction, source) = self
+            .parked
+            .take(client, Some(tx))
+            .pop()
+            .ok_or(Error::TransactionNotFound(tx))?;
+        let events = vec![Event::TransactionReleased { tx }];
+        self.post_events(client, events, transaction.timestamp, operator)?;
+        self.release_one(transaction, source, operator)
+    }
+
+    /// Apply a parked `transaction`, attributed to the source it came from
+    fn release_one(
+        &mut self,
+        transaction: Transaction,
+        source: Option<usize>,
+        operator: Option<OperatorId>,
+    ) -> Result<(), Error> {
+        let current = self.sources.replace(source);
+        let result = self.apply_one(transaction, operator);
+        self.sources.replace(current);
+        result
+    }
+
+    /// The transactions parked by quarantined clients, in the order they came, see
+    /// `quarantine`
+    pub fn parked(&self) -> impl Iterator<Item = &Transaction> {
+        self.parked.iter()
+    }
+
+    /// Latest activity of `client`, see `lifecycle`
+    pub fn last_activity(&self, client: ClientId) -> Option<Timestamp> {
+        self.last_activity.get(&client).copied()
+    }
+
+    /// Lifecycle status of `client` as of `now`, the latest timestamp seen by default
+    pub fn status(
+        &self,
+        client: ClientId,
+        now: Option<Timestamp>,
+        dormant_after: Duration,
+    ) -> Option<Status> {
+        let closed = self.client(client)?.closed();
+        Some(match closed {
+            true => Status::Closed,
+            false => Status::of(
+                self.last_activity(client),
+                now.or(self.clock),
+                dormant_after,
+            ),
+        })
+    }
+
+    /// Resolve the dispute of transaction `tx`, even if the account is locked, releasing
+    /// the held funds. Taken through `act`.
+    pub(crate) fn force_resolve(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        operator: Option<OperatorId>,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let events = self
+            .client(client)
+            .ok_or(Error::ClientNotFound(client))?
+            .force_resolve(tx)?;
+        self.post_events(client, events, timestamp, operator)?;
+        Ok(())
+    }
+
+    /// Apply and record events on `client` which aren't caused by a transaction, but by the
+    /// engine or by `operator`. Callers check that the client exists, nothing is posted for
+    /// an unknown one, nor if the balances after them don't fit.
+    fn post_events(
+        &mut self,
+        client: ClientId,
+        events: Vec<Event>,
+        timestamp: Option<Timestamp>,
+        operator: Option<OperatorId>,
+    ) -> Result<(), Error> {
+        let Some(state) = self.clients.get_mut(&client) else {
+            return Ok(());
+        };
+        state.balances_after(&events)?;
+        let state = Arc::make_mut(state);
+        for event in &events {
+            state.evolve(event)?;
+        }
+        for event in events {
+            self.emit(ClientEvent {
+                client,
+                timestamp,
+                operator,
+                event,
+            });
+        }
+        Ok(())
+    }
+
+    /// Mark the current position, so that everything applied afterwards
+    /// can be undone with `rollback_to`
+    pub fn marker(&self) -> Marker {
+        Marker(self.events.len())
+    }
+
+    /// Undo everything applied after `marker` was taken.
+    /// Events recorded after the marker are dropped from the log and the affected
+    /// clients are rebuilt from their remaining events, so it takes time in proportion to
+    /// what's undone and the history of the affected clients, not the whole log.
+    pub fn rollback_to(&mut self, marker: Marker) {
+        if marker.0 >= self.events.len() {
+            return;
+        }
+        let undone = Arc::make_mut(&mut self.events).split_off(marker.0);
+        let applied = Arc::make_mut(&mut self.applied);
+        applied.truncate(applied.partition_point(|&start| start < marker.0));
+        self.sources.truncate(
+            marker.0,
+            undone
+                .iter()
+                .filter_map(|e| Some((e.client, e.event.tx()?))),
+        );
+        self.parked.truncate(marker.0);
+        let history = Arc::make_mut(&mut self.clock_history);
+        let kept = history.partition_point(|&(offset, _)| offset < marker.0);
+        if let Some(&(_, clock)) = history.get(kept) {
+            self.clock = clock;
+        }
+        history.truncate(kept);
+
+        // Undo what the events added to the read models of all clients
+        for event in &undone {
+            match event.event {
+                Event::FundsDeposited { tx, .. }
+                | Event::FundsWithdrawn { tx, .. }
+                | Event::FundsTransferred { tx, .. } => {
+                    if let Some(dedup) = &mut self.dedup {
+                        dedup.forget(tx.into());
+                    }
+                }
+                Event::WithdrawalWrittenOff { amount, .. }
+                | Event::TransferWrittenOff { amount, .. }
+                | Event::BalanceWrittenOff { amount } => self.written_off -= amount,
+                _ => {}
+            }
+        }
+
+        // Rebuild the affected clients and their read models from their events left. Parked
+        // transactions are kept by offset, parking them again keeps those still parked as
+        // they are.
+        let affected = undone.iter().map(|e| e.client).collect::<HashSet<_>>();
+        self.disputes
+            .retain(|(client, _), _| !affected.contains(client));
+        self.expiring
+            .retain(|(_, client, _)| !affected.contains(client));
+        self.pending
+            .retain(|(client, _), _| !affected.contains(client));
+        self.approvals
+            .retain(|(client, _), _| !affected.contains(client));
+        for id in affected {
+            self.risk.remove(&id);
+            self.last_activity.remove(&id);
+            let offsets = match Arc::make_mut(&mut self.by_client).get_mut(&id) {
+                Some(offsets) => {
+                    offsets.truncate(offsets.partition_point(|&offset| offset < marker.0));
+                    std::mem::take(offsets)
+                }
+                None => Vec::new(),
+            };
+            // Clients without events left were created after the marker
+            if offsets.is_empty() {
+                Arc::make_mut(&mut self.by_client).remove(&id);
+                self.clients.remove(&id);
+                continue;
+            }
+            let mut client = Client::with_currency(id, self.config.currency);
+            for &offset in &offsets {
+                let Some(&event) = self.events.get(offset) else {
+                    continue;
+                };
+                // Folded before the rollback, so the balances fit
+                let _ = client.evolve(&event.event);
+                self.observe(&event, offset);
+            }
+            Arc::make_mut(&mut self.by_client).insert(id, offsets);
+            self.clients.insert(id, Arc::new(client));
+        }
+    }
+
+    /// Undo the last `n` successfully applied transactions.
+    /// Returns how many were actually undone - history replayed from an
+    /// event log cannot be rolled back this way, only with `rollback_to`.
+    pub fn rollback(&mut self, n: usize) -> usize {
+        let n = n.min(self.applied.len());
+        if n > 0 {
+            let start = self.applied[self.applied.len() - n];
+            self.rollback_to(Marker(start));
+        }
+        n
+    }
+
+    /// Rebuild the state by folding a previously recorded event log, failing if the balances
+    /// of a client don't fit, e.g. of a log which was modified
+    pub fn replay(events: impl IntoIterator<Item = ClientEvent>) -> Result<Self, Error> {
+        Self::replay_with(Config::default(), events)
+    }
+
+    /// Same as `replay`, with `config` for what's applied afterwards
+    pub fn replay_with(
+        config: Config,
+        events: impl IntoIterator<Item = ClientEvent>,
+    ) -> Result<Self, Error> {
+        let currency = config.currency;
+        let mut payments = Payments::with_config(config);
+        for event in events {
+            let client = payments
+                .clients
+                .entry(event.client)
+                .or_insert_with(|| Arc::new(Client::with_currency(event.client, currency)));
+            Arc::make_mut(client).evolve(&event.event)?;
+            payments.record(event);
+        }
+        Ok(payments)
+    }
+
+    /// Combine the states of disjoint sets of clients, e.g. processed in parallel shards.
+    /// The event logs are appended one after another, so the result can't be rolled back
+    /// with `rollback`, only with `rollback_to`.
+    pub fn merge(config: Config, parts: impl IntoIterator<Item = Payments>) -> Self {
+        let mut merged = Self::with_config(config);
+        for part in parts {
+            merged.clients.extend(part.clients);
+            for event in Arc::unwrap_or_clone(part.events) {
+                merged.record(event);
+            }
+        }
+        merged
+    }
+
+    /// Balance changes recorded after `marker` was taken, one per client and transaction,
+    /// see `cdc`. Changes undone by a rollback since then aren't reported.
+    pub fn changes_since(&self, marker: Marker) -> Vec<BalanceChange> {
+        let events = self.events.get(marker.0..).unwrap_or_default();
+        // Walking back from the current balances
+        let mut balances = HashMap::new();
+        let mut changes = events
+            .chunk_by(|a, b| (a.client, a.event.tx()) == (b.client, b.event.tx()))
+            .rev()
+            .map(|group| {
+                let (client, first) = (group[0].client, group[0]);
+                let after = balances
+                    .entry(client)
+                    .or_insert_with(|| cdc::balances(self.client(client)));
+                let (available_delta, held_delta, total_delta) =
+                    group.iter().map(|e| e.event.balance_deltas()).fold(
+                        Default::default(),
+                        |(a, h, t): (Decimal, Decimal, Decimal), d| (a + d.0, h + d.1, t + d.2),
+                    );
+                let change = BalanceChange {
+                    client,
+                    tx: first.event.tx(),
+                    timestamp: first.timestamp,
+                    available_delta,
+                    held_delta,
+                    total_delta,
+                    available: after.0,
+                    held: after.1,
+                    total: after.2,
+                    locked: after.3,
+                };
+                let locks = group
+                    .iter()
+                    .any(|e| matches!(e.event, Event::AccountLocked { .. }));
+                let unlocks = group
+                    .iter()
+                    .any(|e| matches!(e.event, Event::AccountUnlocked));
+                *after = (
+                    after.0 - available_delta,
+                    after.1 - held_delta,
+                    after.2 - total_delta,
+                    (after.3 && !locks) || unlocks,
+                );
+                change
+            })
+            .collect::<Vec<_>>();
+        changes.reverse();
+        changes
+    }
+
+    /// The event log, in the order events happened
+    pub fn events(&self) -> &[ClientEvent] {
+        &self.events[..]
+    }
+
+    /// Events recorded after `marker` was taken
+    pub fn events_since(&self, marker: Marker) -> &[ClientEvent] {
+        self.events.get(marker.0..).unwrap_or_default()
+    }
+
+    /// Events which happened up to (and including) `timestamp`.
+    /// Assumption: transactions are fed in chronological order. Events without
+    /// a timestamp are considered to happen together with the preceding ones.
+    fn events_until(&self, timestamp: Timestamp) -> impl Iterator<Item = &ClientEvent> {
+        self.events
+            .iter()
+            .take_while(move |e| e.timestamp.is_none_or(|t| t <= timestamp))
+    }
+
+    /// Reconstruct the whole state as it was at `timestamp`
+    pub fn as_of(&self, timestamp: Timestamp) -> Result<Payments, Error> {
+        Self::replay_with(self.config.clone(), self.events_until(timestamp).copied())
+    }
+
+    /// Reconstruct a single client's balance as it was at `timestamp`
+    pub fn balance_at(&self, client: ClientId, timestamp: Timestamp) -> Result<Client, Error> {
+        let mut snapshot = Client::with_currency(client, self.config.currency);
+        for event in self.events_until(timestamp).filter(|e| e.client == client) {
+            snapshot.evolve(&event.event)?;
+        }
+        Ok(snapshot)
+    }
+
+    /// Export the event log as JSON lines, a snapshot of the current version (see `snapshot`)
+    pub fn export_events(
+        &self,
+        output: impl std::io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        snapshot::write(None, self.events.iter(), output)
+    }
+
+    /// Rebuild the state from an event log exported with `export_events`, by any version
+    pub fn import_events(input: impl std::io::BufRead) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::import_events_with(Config::default(), input)
+    }
+
+    /// Same as `import_events`, with `config` for what's applied afterwards
+    pub fn import_events_with(
+        config: Config,
+        input: impl std::io::BufRead,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::replay_with(config, snapshot::read(input)?.events)?)
+    }
+
+    /// Export the event log as a snapshot sharded by client into `dir`, written in parallel,
+    /// see `snapshot::write_sharded`
+    pub fn export_sharded(
+        &self,
+        dir: &Path,
+        shards: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        snapshot::write_sharded(&self.events, dir, shards)
+    }
+
+    /// Rebuild the state from a snapshot exported with `export_sharded`, reading and replaying
+    /// every shard in its own thread. The events of the shards are interleaved by timestamp,
+    /// so the result can't be rolled back with `rollback`, only with `rollback_to`.
+    pub fn import_sharded_with(
+        config: Config,
+        dir: &Path,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let files = snapshot::shard_files(dir)?;
+        let parts = std::thread::scope(|scope| {
+            let readers = files
+                .iter()
+                .map(|path| {
+                    let config = config.clone();
+                    scope.spawn(move || {
+                        let read = || -> Result<Self, Box<dyn std::error::Error>> {
+                            let input = std::io::BufReader::new(File::open(path)?);
+                            Self::import_events_with(config, input)
+                        };
+                        read().map_err(|e| format!("{}: {}", path.display(), e))
+                    })
+                })
+                .collect::<Vec<_>>();
+            readers
+                .into_iter()
+                .map(|reader| {
+                    reader
+                        .join()
+                        .unwrap_or_else(|_| Err("shard reader panicked".to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        let mut merged = Self::with_config(config);
+        let mut shards = Vec::new();
+        for part in parts {
+            merged.clients.extend(part.clients);
+            // Events without a timestamp happen together with the preceding ones
+            let mut last = None;
+            shards.push(Arc::unwrap_or_clone(part.events).into_iter().map(
+                move |event: ClientEvent| {
+                    last = event.timestamp.or(last);
+                    (last, event)
+                },
+            ));
+        }
+        for (_, event) in shards.into_iter().kmerge_by(|a, b| a.0 < b.0) {
+            merged.record(event);
+        }
+        Ok(merged)
+    }
+
+    /// Risk score (0-100) of a client as of the latest timestamp seen, see `risk`
+    pub fn risk_score(&self, client: ClientId) -> f64 {
+        self.risk
+            .get(&client)
+            .map_or(0.0, |profile| profile.score(self.clock))
+    }
+
+    /// Feature vectors of clients and their transactions for fraud models, see `features`
+    pub fn features(&self) -> Features {
+        Features::extract(&self.events, self.clock)
+    }
+
+    /// Total of the charged back withdrawals and negative balances written off as a loss
+    /// of the house
+    pub fn written_off(&self) -> Decimal {
+        self.written_off
+    }
+
+    /// Look up a client by ID
+    pub fn client(&self, id: ClientId) -> Option<&Client> {
+        self.clients.get(&id).map(Arc::as_ref)
+    }
+
+    /// The disputes in progress with their amounts (negative for a withdrawal), by client
+    /// and transaction ID
+    pub fn open_disputes(&self) -> impl Iterator<Item = (ClientId, TransactionId, Decimal)> + '_ {
+        self.clients().flat_map(|client| {
+            client
+                .open_disputes()
+                .map(move |(tx, amount)| (client.id, tx, amount))
+        })
+    }
+
+    /// Iterate over clients sorted by ID
+    pub fn clients(&self) -> impl Iterator<Item = &Client> {
+        self.clients
+            .values()
+            .map(Arc::as_ref)
+            .sorted_by_key(|c| c.id)
+    }
+
+    /// The `n` clients holding the most funds, most first, of those holding any
+    pub fn top_held(&self, n: usize) -> Vec<(ClientId, Decimal)> {
+        self.clients
+            .values()
+            .filter(|client| client.held() > Decimal::ZERO)
+            .map(|client| (client.id, client.held()))
+            .sorted_by(|(a, held_a), (b, held_b)| held_b.cmp(held_a).then(a.cmp(b)))
+            .take(n)
+            .collect()
+    }
+
+    /// Number of clients
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn contains(&self, id: ClientId) -> bool {
+        self.clients.contains_key(&id)
+    }
+
+    /// Clients to output, sorted by ID
+    fn accounts(&self) -> impl Iterator<Item = &Client> {
+        self.clients()
+            .filter(|client| !(self.config.skip_empty_accounts && client.is_empty()))
+    }
+
+    /// Serialize the register of open disputes to CSV. The age is measured
+    /// in whole days, up to the latest timestamp seen.
+    pub fn serialize_disputes(
+        &self,
+        output: impl std::io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = csv::Writer::from_writer(output);
+        for (&(client, tx), dispute) in &self.disputes {
+            writer.serialize(DisputeRecord {
+                client,
+                tx,
+                amount: dispute.amount,
+                held_since: dispute.since,
+                age_days: dispute
+                    .since
+                    .zip(self.clock)
+                    .map(|(since, now)| (now - since).num_days()),
+            })?
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn account_record(&self, client: &Client) -> AccountRecord {
+        let credit = !self.config.credit_limits.is_empty();
+        AccountRecord {
+            client: client.id,
+            available: client.available(),
+            held: client.held(),
+            total: client.total(),
+            locked: client.locked(),
+            risk_score: self
+                .config
+                .risk_score_column
+                .then(|| self.risk_score(client.id)),
+            disputes: self.config.dispute_columns.then(|| {
+                disputed(client)
+                    .map(|op| format!("{}:{}:{}", op.tx, op.state, op.held))
+                    .join(" ")
+            }),
+            sub_accounts: self.config.sub_account_columns.then(|| {
+                client
+                    .sub_accounts()
+                    .map(|(account, available)| format!("{}:{}", account, available))
+                    .join(" ")
+            }),
+            reserved: self
+                .config
+                .reserve_column
+                .then(|| self.config.reserves.reserve(client.id)),
+            pending: self.config.pending_column.then(|| client.pending()),
+            credit_limit: credit.then(|| self.config.credit_limits.limit(client.id)),
+            credit_drawn: credit.then(|| client.credit_drawn()),
+        }
+    }
+
+    /// Serialize every deposit and withdrawal which was ever disputed to CSV, with the
+    /// state of its dispute and the funds it holds
+    pub fn serialize_dispute_history(
+        &self,
+        output: impl std::io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = csv::Writer::from_writer(output);
+        for client in self.clients() {
+            for op in disputed(client) {
+                writer.serialize(DisputeHistoryRecord {
+                    client: client.id,
+                    tx: op.tx,
+                    amount: op.amount,
+                    state: op.state,
+                    held: op.held,
+                })?
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Serialize a summary of the disputes' outcomes to CSV: a row per client with any,
+    /// followed by a row of the whole run without a client
+    pub fn serialize_dispute_report(
+        &self,
+        output: impl std::io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let amounts = self
+            .clients()
+            .flat_map(|client| disputed(client).map(|op| ((client.id, op.tx), op.amount.abs())))
+            .collect::<HashMap<_, _>>();
+        let mut outcomes = BTreeMap::<ClientId, DisputeOutcomes>::new();
+        let mut open = HashMap::<(ClientId, TransactionId), Option<Timestamp>>::new();
+        for event in self.events.iter() {
+            match event.event {
+                Event::FundsHeld { tx, amount, .. } => {
+                    let client = outcomes.entry(event.client).or_default();
+                    client.opened += 1;
+                    client.amount += amounts.get(&(event.client, tx)).copied().unwrap_or(amount);
+                    open.insert((event.client, tx), event.timestamp);
+                }
+                Event::FundsReleased { tx, .. } | Event::FundsChargedBack { tx, .. } => {
+                    let Some(opened_at) = open.remove(&(event.client, tx)) else {
+                        continue;
+                    };
+                    let client = outcomes.entry(event.client).or_default();
+                    if matches!(event.event, Event::FundsReleased { .. }) {
+                        client.resolved += 1;
+                    } else {
+                        client.charged_back += 1;
+                    }
+                    if let (Some(opened_at), Some(closed_at)) = (opened_at, event.timestamp) {
+                        client.latencies.push((closed_at - opened_at).num_seconds());
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut writer = csv::Writer::from_writer(output);
+        let mut total = DisputeOutcomes::default();
+        for (client, outcomes) in outcomes {
+            total.add(&outcomes);
+            writer.serialize(outcomes.record(Some(client)))?;
+        }
+        writer.serialize(total.record(None))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The exposure to each counterparty across all clients and the total of it. Charged
+    /// back deposits and withdrawals don't count, their funds having been returned.
+    fn exposure(&self) -> (BTreeMap<Counterparty, ExposureRecord>, ExposureRecord) {
+        let mut exposure = BTreeMap::<Counterparty, ExposureRecord>::new();
+        let mut total = ExposureRecord::default();
+        for client in self.clients() {
+            let mut seen = HashSet::new();
+            for op in client.operations() {
+                let Some(counterparty) = op.counterparty else {
+                    continue;
+                };
+                if op.state == OperationState::Chargedback {
+                    continue;
+                }
+                let record = exposure
+                    .entry(counterparty)
+                    .or_insert_with(|| ExposureRecord {
+                        counterparty: Some(counterparty),
+                        ..Default::default()
+                    });
+                record.add(op.amount);
+                total.add(op.amount);
+                if seen.insert(counterparty) {
+                    record.clients += 1;
+                }
+            }
+            total.clients += usize::from(!seen.is_empty());
+        }
+        (exposure, total)
+    }
+
+    /// Net position with each counterparty, deposited less withdrawn, see `settlement`
+    pub fn net_positions(&self) -> BTreeMap<Counterparty, Decimal> {
+        let (exposure, _) = self.exposure();
+        exposure
+            .into_iter()
+            .map(|(counterparty, record)| (counterparty, record.net))
+            .collect()
+    }
+
+    /// Serialize the lifecycle status and latest activity of every client to CSV, see
+    /// `status`
+    pub fn serialize_lifecycle(
+        &self,
+        output: impl std::io::Write,
+        now: Option<Timestamp>,
+        dormant_after: Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = csv::Writer::from_writer(output);
+        for client in self.clients() {
+            writer.serialize(LifecycleRecord {
+                client: client.id,
+                status: self
+                    .status(client.id, now, dormant_after)
+                    .ok_or(Error::ClientNotFound(client.id))?,
+                last_activity: self.last_activity(client.id),
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Serialize the exposure to each counterparty across all clients to CSV: a row per
+    /// counterparty, followed by a row of the totals without one, see `exposure`
+    pub fn serialize_exposure(
+        &self,
+        output: impl std::io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (exposure, total) = self.exposure();
+        let mut writer = csv::Writer::from_writer(output);
+        for record in exposure.values() {
+            writer.serialize(record)?;
+        }
+        writer.serialize(total)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Serialize what the chargebacks cost the house to CSV: a row per client with any,
+    /// followed by a row of the totals without a client. Negative balances written off
+    /// count once the client had a chargeback.
+    pub fn serialize_chargeback_losses(
+        &self,
+        output: impl std::io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut losses = BTreeMap::<ClientId, ChargebackLossRecord>::new();
+        for event in self.events.iter() {
+            let charged_back = matches!(event.event, Event::FundsChargedBack { .. });
+            if !charged_back && !losses.contains_key(&event.client) {
+                continue;
+            }
+            let loss = losses
+                .entry(event.client)
+                .or_insert_with(|| ChargebackLossRecord {
+                    client: Some(event.client),
+                    ..ChargebackLossRecord::default()
+                });
+            match event.event {
+                Event::FundsChargedBack { amount, .. } => {
+                    loss.chargebacks += 1;
+                    loss.charged_back += amount;
+                }
+                Event::WithdrawalReversed { amount, .. } => loss.reversed += amount,
+                Event::WithdrawalWrittenOff { amount, .. }
+                | Event::TransferWrittenOff { amount, .. }
+                | Event::BalanceWrittenOff { amount } => loss.written_off += amount,
+                _ => {}
+            }
+        }
+        let mut writer = csv::Writer::from_writer(output);
+        let mut total = ChargebackLossRecord::default();
+        for (client, mut loss) in losses {
+            let client_total = self.client(client).map(Client::total).unwrap_or_default();
+            loss.negative_total = (-client_total).max(Decimal::ZERO);
+            loss.lost = loss.reversed + loss.written_off + loss.negative_total;
+            total.chargebacks += loss.chargebacks;
+            total.charged_back += loss.charged_back;
+            total.reversed += loss.reversed;
+            total.written_off += loss.written_off;
+            total.negative_total += loss.negative_total;
+            total.lost += loss.lost;
+            writer.serialize(loss)?;
+        }
+        writer.serialize(total)?;
+        writer.flush()?;
+        Ok(())
     }
 
     /// Serialize the payments' client database to CSV
@@ -32,10 +2192,36 @@ impl Payments {
     /// a consistent outcome.
     pub fn serialize(&self, output: impl std::io::Write) -> Result<(), Box<dyn std::error::Error>> {
         let mut writer = csv::Writer::from_writer(output);
-        for client in self.clients.values().sorted_by_key(|c| c.id) {
-            writer.serialize(client)?
+        for client in self.accounts() {
+            writer.serialize(self.account_record(client))?
         }
         writer.flush()?;
         Ok(())
     }
+
+    /// Serialize the client database to CSV files in `dir`, one per partition.
+    /// Only partitions with at least one client get a file.
+    pub fn serialize_partitioned(
+        &self,
+        dir: &Path,
+        partition: Partition,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(dir)?;
+        let mut writers = HashMap::<String, csv::Writer<File>>::new();
+        for client in self.accounts() {
+            let file_name = partition.file_name(client);
+            let writer = match writers.entry(file_name) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let writer = csv::Writer::from_path(dir.join(entry.key()))?;
+                    entry.insert(writer)
+                }
+            };
+            writer.serialize(self.account_record(client))?
+        }
+        for writer in writers.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,712 @@
+//! The processing of an input, shared by the command line and library use: transactions are
+//! read, applied and counted, and the accounts written, every failure returned as an `Error`
+//! rather than a panic or an exit.
+//!
+//! Invalid transactions are rejected one by one, unless `Config::error_policy` is strict and
+//! the first fails the run. Malformed input fails the run unless `Policy::skip_malformed`,
+//! and so does exceeding the memory limit. `apply_all` is the loop
+//! of a run, for callers hooking into every transaction, e.g. the command line logging and
+//! exporting them, see `Hooks`. `load` is such a run of a file, as the command line and the
+//! daemon mode do it, writing what happens to `Sinks`.
+//!
+//! ```
+//! use payments::pipeline::{run, RunConfig};
+//!
+//! let input = "type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 5\n";
+//! let mut accounts = Vec::new();
+//! let report = run(RunConfig::default(), input.as_bytes(), &mut accounts)?;
+//! assert_eq!((report.counts.transactions, report.counts.rejected), (2, 1));
+//! assert_eq!(
+//!     String::from_utf8(accounts).unwrap(),
+//!     "client,available,held,total,locked\n1,2.5,0,2.5,false\n"
+//! );
+//! # Ok::<(), payments::error::Error>(())
+//! ```
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+
+use crate::{
+    cdc::ChangeStream,
+    checksum::{self, ChecksumMode},
+    client::ClientId,
+    error::Error,
+    log::{LogEvent, Logger},
+    merkle::MerkleTree,
+    mmap::MappedTransactions,
+    parser::{parse_quoted, ParseOptions},
+    payments::{Config, ErrorPolicy, Marker, Payments},
+    provenance::{signature_path, OutputKey},
+    ratelimit::Throttle,
+    reorder::{reordered, LateArrival},
+    schedule::Schedule,
+    signature::canonical,
+    tenant::Tenants,
+    transaction::{BatchId, Transaction, TransactionId},
+    txlog::{Accepted, TransactionLog},
+};
+
+/// What fails a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Count malformed input and go on, rather than failing
+    pub skip_malformed: bool,
+}
+
+/// How a run reads and processes its input
+#[derive(Debug, Clone, Default)]
+pub struct RunConfig {
+    pub config: Config,
+    pub parse: ParseOptions,
+    pub policy: Policy,
+}
+
+/// What a run went through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// Transactions applied, including the rejected ones
+    pub transactions: usize,
+    pub rejected: usize,
+    /// Input skipped as malformed, see `Policy::skip_malformed`
+    pub malformed: usize,
+}
+
+/// A completed run
+#[derive(Debug)]
+pub struct RunReport {
+    pub counts: Counts,
+    /// The state after the run, e.g. for its `stats`
+    pub payments: Payments,
+}
+
+/// A transaction of a run once applied
+#[derive(Debug)]
+pub struct Applied<'a> {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub batch: Option<BatchId>,
+    pub result: &'a Result<(), Error>,
+    /// Transactions applied so far, this one included
+    pub transactions: usize,
+}
+
+/// Callbacks of `apply_all` for every transaction, doing nothing by default
+pub trait Hooks {
+    type Error: From<Error>;
+
+    /// Transactions due before `transaction`, e.g. standing orders, applied first
+    fn due(&mut self, _transaction: &Transaction) -> Vec<Transaction> {
+        Vec::new()
+    }
+
+    /// Before `transaction` is applied to `payments`, rejecting it with an error, e.g. over
+    /// a rate limit
+    fn admit(&mut self, _payments: &Payments, _transaction: &Transaction) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// After a transaction is applied, unless it exceeded the memory limit
+    fn applied(&mut self, _payments: &Payments, _applied: Applied) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Malformed input skipped, see `Policy::skip_malformed`
+    fn malformed(&mut self, _error: &Error) {}
+}
+
+/// No hooks
+impl Hooks for () {
+    type Error = Error;
+}
+
+/// Apply `transactions` to `payments` under `policy`, recording the latency of each, from
+/// reading it to having applied it. A rejection fails it under `ErrorPolicy::Strict`.
+pub fn apply_all<H: Hooks>(
+    payments: &mut Payments,
+    mut transactions: impl Iterator<Item = Result<Transaction, Error>>,
+    policy: Policy,
+    hooks: &mut H,
+) -> Result<Counts, H::Error> {
+    let mut counts = Counts::default();
+    loop {
+        let started = Instant::now();
+        let Some(transaction) = transactions.next() else {
+            break;
+        };
+        let transaction = match transaction {
+            Err(error) if policy.skip_malformed => {
+                counts.malformed += 1;
+                payments.record_malformed(&error);
+                hooks.malformed(&error);
+                continue;
+            }
+            transaction => transaction?,
+        };
+        let parsed = started.elapsed();
+        for due in hooks.due(&transaction) {
+            apply(payments, hooks, &mut counts, due, Duration::ZERO)?;
+        }
+        apply(payments, hooks, &mut counts, transaction, parsed)?;
+    }
+    Ok(counts)
+}
+
+/// Apply `transaction`, read in `parsed`
+fn apply<H: Hooks>(
+    payments: &mut Payments,
+    hooks: &mut H,
+    counts: &mut Counts,
+    transaction: Transaction,
+    parsed: Duration,
+) -> Result<(), H::Error> {
+    let (client, tx, batch) = (transaction.client_id, transaction.op.id, transaction.batch);
+    let result = hooks.admit(payments, &transaction).and_then(|()| {
+        let kind = transaction.op.kind;
+        let started = Instant::now();
+        let result = payments.apply(transaction);
+        payments.record_latency(&kind, parsed + started.elapsed());
+        result
+    });
+    if let Err(error @ Error::MemoryLimitExceeded { .. }) = result {
+        return Err(error.into());
+    }
+    counts.transactions += 1;
+    counts.rejected += usize::from(result.is_err());
+    let applied = Applied {
+        client,
+        tx,
+        batch,
+        result: &result,
+        transactions: counts.transactions,
+    };
+    hooks.applied(payments, applied)?;
+    match result {
+        Err(reason) if payments.config().error_policy == ErrorPolicy::Strict => {
+            Err(Error::Rejected {
+                client,
+                tx,
+                reason: Box::new(reason),
+            }
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Read the transactions of `source` (CSV), apply them and write the accounts to `sink`
+pub fn run(config: RunConfig, source: impl BufRead, sink: impl Write) -> Result<RunReport, Error> {
+    let mut payments = Payments::with_config(config.config);
+    let transactions = parse_quoted(source, config.parse);
+    let counts = apply_all(&mut payments, transactions, config.policy, &mut ())?;
+    payments
+        .serialize(sink)
+        .map_err(|e| Error::Io(format!("writing the accounts: {}", e)))?;
+    Ok(RunReport { counts, payments })
+}
+
+/// How to read the input
+#[derive(Default)]
+pub struct LoadOptions {
+    pub reorder_window: Option<chrono::Duration>,
+    /// Memory-map the input and parse it in parallel chunks
+    pub mmap: bool,
+    pub parse: ParseOptions,
+    /// Snapshot the accounts to this directory every N transactions
+    pub snapshots: Option<(usize, PathBuf)>,
+    /// Verification of the input against its `.sha256` file
+    pub checksum: ChecksumMode,
+    /// Sign the snapshots with this key
+    pub output_key: Option<OutputKey>,
+}
+
+/// A file in `dir` for the accounts, named after the current time and the number of
+/// transactions so far
+pub fn snapshot_file(dir: &Path, transactions: usize) -> PathBuf {
+    dir.join(format!(
+        "accounts-{}-{}.csv",
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        transactions
+    ))
+}
+
+/// Write the accounts to `path`, which appears once it's complete. With a key, its signature
+/// is written before, see `provenance`.
+pub fn write_snapshot(
+    payments: &Payments,
+    path: &Path,
+    key: Option<&OutputKey>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut accounts = Vec::new();
+    payments.serialize(&mut accounts)?;
+    if let Some(key) = key {
+        std::fs::write(signature_path(path), key.sign(&accounts))?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    std::fs::write(&partial, accounts)?;
+    std::fs::rename(partial, path)?;
+    Ok(())
+}
+
+/// Write the accounts to a file in `dir`, see `snapshot_file`
+pub fn snapshot(
+    payments: &Payments,
+    dir: &Path,
+    transactions: usize,
+    key: Option<&OutputKey>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = snapshot_file(dir, transactions);
+    write_snapshot(payments, &path, key)?;
+    Ok(path)
+}
+
+pub type Transactions = Box<dyn Iterator<Item = Result<Transaction, Error>>>;
+
+/// Read the transactions of `filename`, reordered if requested
+pub fn read(
+    filename: &str,
+    options: &LoadOptions,
+    on_late: impl FnMut(LateArrival) + 'static,
+) -> Result<Transactions, Box<dyn std::error::Error>> {
+    let transactions: Transactions = if options.mmap {
+        Box::new(MappedTransactions::open(filename)?)
+    } else {
+        parse_file(filename, options)?
+    };
+    Ok(match options.reorder_window {
+        Some(window) => Box::new(reordered(transactions, window, on_late)),
+        None => transactions,
+    })
+}
+
+/// The transactions of `filename`, CSV or, with the `msgpack` feature, MessagePack for
+/// `*.msgpack`, and with the `fix` feature the fills of a FIX drop-copy session for `*.fix`
+fn parse_file(
+    filename: &str,
+    options: &LoadOptions,
+) -> Result<Transactions, Box<dyn std::error::Error>> {
+    let input = std::io::BufReader::new(std::fs::File::open(filename)?);
+    #[cfg(feature = "msgpack")]
+    if filename.ends_with(".msgpack") {
+        return Ok(Box::new(crate::msgpack::read_transactions(input)));
+    }
+    #[cfg(feature = "fix")]
+    if filename.ends_with(".fix") {
+        return Ok(Box::new(crate::fix::read_fills(input)));
+    }
+    Ok(Box::new(parse_quoted(input, options.parse)))
+}
+
+/// Verify the checksum of `filename` before it's processed, see `checksum`
+fn verify_checksum(
+    filename: &str,
+    mode: ChecksumMode,
+    log: Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match checksum::verify(Path::new(filename), mode) {
+        Err(error) if mode == ChecksumMode::Warn => {
+            log.log(LogEvent::ChecksumMismatch {
+                input: filename,
+                error: error.to_string(),
+            });
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+pub type Changes = ChangeStream<std::io::BufWriter<std::fs::File>>;
+
+/// Where `load` writes what happens as the transactions are applied
+#[derive(Default)]
+pub struct Sinks {
+    pub changes: Option<Changes>,
+    pub accepted: Accepted,
+    pub transactions: Option<TransactionLog<std::fs::File>>,
+    pub merkle: Option<MerkleTree>,
+}
+
+impl Sinks {
+    /// Whether the accepted transactions are written anywhere
+    fn wants_accepted(&self) -> bool {
+        self.transactions.is_some() || self.merkle.is_some()
+    }
+
+    fn write_accepted(&mut self, accepted: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+        for entry in accepted {
+            if let Some(merkle) = &mut self.merkle {
+                merkle.push(&entry);
+            }
+            if let Some(transactions) = &mut self.transactions {
+                transactions.append(entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The hooks of `load`: rate limiting, standing orders, and logging and writing to the sinks
+/// what happens
+struct LoadHooks<'a> {
+    filename: &'a str,
+    options: &'a LoadOptions,
+    log: Logger,
+    sinks: &'a mut Sinks,
+    throttle: Option<(&'a mut Throttle, &'a str)>,
+    schedule: Option<&'a mut Schedule>,
+    /// The state before the transaction being applied, and its canonical form if the
+    /// accepted transactions are written
+    marker: Marker,
+    entry: Option<String>,
+}
+
+impl Hooks for LoadHooks<'_> {
+    type Error = Box<dyn std::error::Error>;
+
+    fn due(&mut self, transaction: &Transaction) -> Vec<Transaction> {
+        match (self.schedule.as_deref_mut(), transaction.timestamp) {
+            (Some(schedule), Some(now)) => schedule.due(now),
+            _ => Vec::new(),
+        }
+    }
+
+    fn admit(&mut self, payments: &Payments, transaction: &Transaction) -> Result<(), Error> {
+        self.marker = payments.marker();
+        self.entry = self.sinks.wants_accepted().then(|| canonical(transaction));
+        match self.throttle.as_mut() {
+            Some((throttle, source)) => throttle.admit(source, transaction),
+            None => Ok(()),
+        }
+    }
+
+    fn applied(
+        &mut self,
+        payments: &Payments,
+        applied: Applied,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(changes) = &mut self.sinks.changes {
+            changes.record(
+                payments,
+                self.marker,
+                applied.tx,
+                applied.batch,
+                applied.result,
+            )?;
+        }
+        if let Some(entry) = self.entry.take() {
+            let accepted = self
+                .sinks
+                .accepted
+                .record(entry, applied.batch, applied.result);
+            self.sinks.write_accepted(accepted)?;
+        }
+        if let Err(error) = applied.result {
+            self.log
+                .log(LogEvent::rejected(applied.client, applied.tx, error));
+        }
+        if let Some((every, dir)) = &self.options.snapshots {
+            if applied.transactions.is_multiple_of((*every).max(1)) {
+                let key = self.options.output_key.as_ref();
+                let path = snapshot(payments, dir, applied.transactions, key)?;
+                self.log.log(LogEvent::Checkpoint {
+                    transactions: applied.transactions,
+                    path: &path,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn malformed(&mut self, error: &Error) {
+        self.log.log(LogEvent::Malformed {
+            input: self.filename,
+            error: error.to_string(),
+        });
+    }
+}
+
+/// Apply the transactions of `filename`, returning how many there were and how many failed.
+/// With a throttle, they're rate limited as transactions of its source.
+pub fn load(
+    payments: &mut Payments,
+    filename: &str,
+    options: &LoadOptions,
+    log: Logger,
+    sinks: &mut Sinks,
+    throttle: Option<(&mut Throttle, &str)>,
+    schedule: Option<&mut Schedule>,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    verify_checksum(filename, options.checksum, log)?;
+    log.log(LogEvent::Start { input: filename });
+    let input = read(filename, options, move |late| {
+        log.log(LogEvent::LateArrival(&late))
+    })?;
+    let policy = Policy {
+        skip_malformed: options.parse.lenient_quotes,
+    };
+    let mut hooks = LoadHooks {
+        filename,
+        options,
+        log,
+        sinks,
+        throttle,
+        schedule,
+        marker: Marker::default(),
+        entry: None,
+    };
+    let counts = apply_all(payments, input, policy, &mut hooks)?;
+    if let Some(changes) = &mut sinks.changes {
+        changes.flush()?;
+    }
+    let accepted = sinks.accepted.finish();
+    sinks.write_accepted(accepted)?;
+    if let Some(transactions) = &mut sinks.transactions {
+        transactions.flush()?;
+    }
+    let transactions = counts.transactions + counts.malformed;
+    let rejected = counts.rejected + counts.malformed;
+    log.log(LogEvent::Finish {
+        transactions,
+        rejected,
+    });
+    Ok((transactions, rejected))
+}
+
+/// Apply the transactions of `filename` to the states of their tenants as `load` does, a run
+/// of consecutive transactions of a tenant at a time. Malformed rows and standing orders go
+/// to the default tenant, the orders coming due with its transactions.
+pub fn load_tenants(
+    tenants: &mut Tenants,
+    filename: &str,
+    options: &LoadOptions,
+    log: Logger,
+    mut throttle: Option<(&mut Throttle, &str)>,
+    mut schedule: Option<&mut Schedule>,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    verify_checksum(filename, options.checksum, log)?;
+    log.log(LogEvent::Start { input: filename });
+    let mut input = read(filename, options, move |late| {
+        log.log(LogEvent::LateArrival(&late))
+    })?
+    .peekable();
+    let policy = Policy {
+        skip_malformed: options.parse.lenient_quotes,
+    };
+    let default = tenants.default_tenant().to_string();
+    let tenant_of = |transaction: &Result<Transaction, Error>| match transaction {
+        Ok(Transaction {
+            tenant: Some(tenant),
+            ..
+        }) => tenant.clone(),
+        _ => default.clone(),
+    };
+    let mut sinks = Sinks::default();
+    let (mut transactions, mut rejected) = (0, 0);
+    while let Some(next) = input.peek() {
+        let tenant = tenant_of(next);
+        let mut hooks = LoadHooks {
+            filename,
+            options,
+            log,
+            sinks: &mut sinks,
+            throttle: throttle
+                .as_mut()
+                .map(|(throttle, source)| (&mut **throttle, *source)),
+            schedule: match tenant == default {
+                true => schedule.as_deref_mut(),
+                false => None,
+            },
+            marker: Marker::default(),
+            entry: None,
+        };
+        let run = std::iter::from_fn(|| input.next_if(|next| tenant_of(next) == tenant));
+        let counts = apply_all(tenants.tenant_mut(tenant.clone()), run, policy, &mut hooks)?;
+        transactions += counts.transactions + counts.malformed;
+        rejected += counts.rejected + counts.malformed;
+    }
+    log.log(LogEvent::Finish {
+        transactions,
+        rejected,
+    });
+    Ok((transactions, rejected))
+}
+
+/// Write the accounts to `dir/accounts.csv`, replacing the file at once so readers never
+/// see it half-written, signed with `key` if any
+pub fn write_accounts(
+    payments: &Payments,
+    dir: &Path,
+    key: Option<&OutputKey>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_snapshot(payments, &dir.join("accounts.csv"), key)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{apply_all, run, Applied, Counts, Hooks, Policy, RunConfig};
+    use crate::{
+        error::Error,
+        parser::ParseOptions,
+        payments::{Config, ErrorPolicy, Payments},
+        transaction::Transaction,
+    };
+
+    const INPUT: &str = "type, client, tx, amount
+        deposit, 1, 1, 2.5
+        withdrawal, 1, 2, 5
+        deposit, 1, 3, 50000000000000000000000000000.0
+        deposit, \"2, 4, 1
+        deposit, 2, 5, 1";
+
+    #[test]
+    fn runs() {
+        let error = run(RunConfig::default(), INPUT.as_bytes(), std::io::sink()).unwrap_err();
+        assert!(matches!(error, Error::ParsingFailure(_)));
+
+        let config = RunConfig {
+            parse: ParseOptions {
+                lenient_quotes: true,
+                ..ParseOptions::default()
+            },
+            policy: Policy {
+                skip_malformed: true,
+            },
+            ..RunConfig::default()
+        };
+        let mut accounts = Vec::new();
+        let report = run(config, INPUT.as_bytes(), &mut accounts).unwrap();
+        assert_eq!(
+            report.counts,
+            Counts {
+                transactions: 4,
+                rejected: 2,
+                malformed: 1
+            }
+        );
+        assert_eq!(
+            String::from_utf8(accounts).unwrap(),
+            "client,available,held,total,locked\n1,2.5,0,2.5,false\n2,1,0,1,false\n"
+        );
+        assert_eq!(report.payments.stats().clients, 2);
+    }
+
+    /// Refuses client 2, applies a deposit of client 3 before every transaction and records
+    /// the results
+    #[derive(Default)]
+    struct Recorder {
+        results: Vec<(u32, bool, usize)>,
+        malformed: usize,
+    }
+
+    impl Hooks for Recorder {
+        type Error = Error;
+
+        fn due(&mut self, transaction: &Transaction) -> Vec<Transaction> {
+            vec![Transaction::deposit(3, transaction.op.id + 100, dec!(1)).unwrap()]
+        }
+
+        fn admit(&mut self, _payments: &Payments, transaction: &Transaction) -> Result<(), Error> {
+            match transaction.client_id {
+                2 => Err(Error::ClientBlocked(2)),
+                _ => Ok(()),
+            }
+        }
+
+        fn applied(&mut self, _payments: &Payments, applied: Applied) -> Result<(), Error> {
+            self.results
+                .push((applied.tx, applied.result.is_ok(), applied.transactions));
+            Ok(())
+        }
+
+        fn malformed(&mut self, _error: &Error) {
+            self.malformed += 1;
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let transactions = vec![
+            Transaction::deposit(1, 1, dec!(1)),
+            Err(Error::ParsingFailure("malformed".to_string())),
+            Transaction::deposit(2, 2, dec!(1)),
+        ];
+        let mut payments = Payments::default();
+        let mut recorder = Recorder::default();
+        let policy = Policy {
+            skip_malformed: true,
+        };
+        let counts = apply_all(
+            &mut payments,
+            transactions.into_iter(),
+            policy,
+            &mut recorder,
+        );
+        assert_eq!(
+            counts,
+            Ok(Counts {
+                transactions: 4,
+                rejected: 1,
+                malformed: 1
+            })
+        );
+        assert_eq!(
+            recorder.results,
+            [(101, true, 1), (1, true, 2), (102, true, 3), (2, false, 4)]
+        );
+        assert_eq!(recorder.malformed, 1);
+        assert_eq!(payments.client(3).unwrap().total(), dec!(2));
+        assert!(payments.client(2).is_none());
+    }
+
+    #[test]
+    fn memory_limit() {
+        let config = Config {
+            max_memory: Some(1),
+            ..Config::default()
+        };
+        let mut payments = Payments::with_config(config);
+        let transactions = (1..10_000).map(|tx| Transaction::deposit(1, tx, dec!(1)));
+        let error = apply_all(&mut payments, transactions, Policy::default(), &mut ()).unwrap_err();
+        assert!(matches!(error, Error::MemoryLimitExceeded { .. }));
+    }
+
+    #[test]
+    fn strict_error_policy() {
+        let mut payments = Payments::builder()
+            .error_policy(ErrorPolicy::Strict)
+            .build();
+        let transactions = vec![
+            Transaction::deposit(1, 1, dec!(1)),
+            Transaction::withdrawal(1, 2, dec!(5)),
+            Transaction::deposit(1, 3, dec!(1)),
+        ];
+        let mut recorder = Recorder::default();
+        let error = apply_all(
+            &mut payments,
+            transactions.into_iter(),
+            Policy::default(),
+            &mut recorder,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Rejected {
+                client: 1,
+                tx: 2,
+                ..
+            }
+        ));
+        // Seen by the hooks before the run failed
+        assert_eq!(recorder.results.last(), Some(&(2, false, 4)));
+        assert_eq!(payments.client(1).unwrap().total(), dec!(1));
+    }
+}
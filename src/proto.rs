@@ -0,0 +1,217 @@
+//! Protobuf messages of transactions and accounts (feature `protobuf`), generated from
+//! `proto/payments.proto`, with conversions from and into `transaction::Transaction` and
+//! `Client`:
+//!
+//! ```ignore
+//! use prost::Message;
+//!
+//! let bytes = proto::Transaction::from(&transaction).encode_to_vec();
+//! let transaction = Transaction::try_from(proto::Transaction::decode(bytes.as_slice())?)?;
+//! ```
+use rust_decimal::Decimal;
+
+use crate::{
+    client::Client,
+    error::Error,
+    payments::Payments,
+    transaction::{self, Operation, OperationType},
+};
+
+#[allow(clippy::all)]
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/payments.rs"));
+}
+
+pub use generated::{transaction::Type, Account, Accounts, Transaction};
+
+fn invalid(field: &str, value: impl std::fmt::Display) -> Error {
+    Error::ParsingFailure(format!("invalid {} `{}`", field, value))
+}
+
+/// A counterparty or sub-account
+fn named<T: std::str::FromStr>(field: &str, value: &Option<String>) -> Result<Option<T>, Error> {
+    value
+        .as_deref()
+        .map(|name| name.parse().map_err(|_| invalid(field, name)))
+        .transpose()
+}
+
+impl From<&transaction::Transaction> for Transaction {
+    fn from(trans: &transaction::Transaction) -> Self {
+        let mut message = Transaction {
+            client: trans.client_id.into(),
+            tx: trans.op.id,
+            timestamp: trans.timestamp.map(|timestamp| timestamp.to_rfc3339()),
+            batch: trans.batch,
+            tenant: trans.tenant.clone(),
+            signature: trans.signature.clone(),
+            value_date: trans.value_date.map(|date| date.to_string()),
+            seq: trans.seq,
+            ..Default::default()
+        };
+        let (kind, amount) = match &trans.op.kind {
+            OperationType::Deposit {
+                amount,
+                ref_tx,
+                counterparty,
+            }
+            | OperationType::Withdrawal {
+                amount,
+                ref_tx,
+                counterparty,
+            } => {
+                message.ref_tx = *ref_tx;
+                message.counterparty = counterparty.map(|c| c.to_string());
+                let kind = match trans.op.kind {
+                    OperationType::Deposit { .. } => Type::Deposit,
+                    _ => Type::Withdrawal,
+                };
+                (kind, Some(amount))
+            }
+            OperationType::Dispute => (Type::Dispute, None),
+            OperationType::Resolve => (Type::Resolve, None),
+            OperationType::Chargeback => (Type::Chargeback, None),
+            OperationType::Transfer { amount, from, to } => {
+                message.from_account = from.map(|account| account.to_string());
+                message.to_account = to.map(|account| account.to_string());
+                (Type::Transfer, Some(amount))
+            }
+            OperationType::Amend { amount } => (Type::Amend, Some(amount)),
+            OperationType::Settle => (Type::Settle, None),
+        };
+        message.set_type(kind);
+        message.amount = amount.map(Decimal::to_string).unwrap_or_default();
+        message
+    }
+}
+
+impl TryFrom<Transaction> for transaction::Transaction {
+    type Error = Error;
+
+    fn try_from(message: Transaction) -> Result<Self, Self::Error> {
+        let amount = || {
+            message
+                .amount
+                .parse::<Decimal>()
+                .map_err(|_| invalid("amount", &message.amount))
+        };
+        let kind = match message.r#type() {
+            Type::Deposit => OperationType::Deposit {
+                amount: amount()?,
+                ref_tx: message.ref_tx,
+                counterparty: named("counterparty", &message.counterparty)?,
+            },
+            Type::Withdrawal => OperationType::Withdrawal {
+                amount: amount()?,
+                ref_tx: message.ref_tx,
+                counterparty: named("counterparty", &message.counterparty)?,
+            },
+            Type::Dispute => OperationType::Dispute,
+            Type::Resolve => OperationType::Resolve,
+            Type::Chargeback => OperationType::Chargeback,
+            Type::Transfer => OperationType::Transfer {
+                amount: amount()?,
+                from: named("from_account", &message.from_account)?,
+                to: named("to_account", &message.to_account)?,
+            },
+            Type::Amend => OperationType::Amend { amount: amount()? },
+            Type::Settle => OperationType::Settle,
+            Type::Unspecified => return Err(invalid("type", message.r#type)),
+        };
+        Ok(transaction::Transaction {
+            op: Operation {
+                id: message.tx,
+                kind,
+            },
+            client_id: message
+                .client
+                .try_into()
+                .map_err(|_| invalid("client", message.client))?,
+            timestamp: message
+                .timestamp
+                .as_deref()
+                .map(|timestamp| {
+                    timestamp
+                        .parse()
+                        .map_err(|_| invalid("timestamp", timestamp))
+                })
+                .transpose()?,
+            batch: message.batch,
+            tenant: message.tenant,
+            signature: message.signature,
+            value_date: message
+                .value_date
+                .as_deref()
+                .map(|date| date.parse().map_err(|_| invalid("value_date", date)))
+                .transpose()?,
+            seq: message.seq,
+        })
+    }
+}
+
+impl From<&Client> for Account {
+    fn from(client: &Client) -> Self {
+        Account {
+            client: client.id.into(),
+            available: client.available().to_string(),
+            held: client.held().to_string(),
+            total: client.total().to_string(),
+            locked: client.locked(),
+        }
+    }
+}
+
+impl From<&Payments> for Accounts {
+    fn from(payments: &Payments) -> Self {
+        Accounts {
+            accounts: payments.clients().map(Account::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use rust_decimal_macros::dec;
+
+    use super::{Accounts, Transaction, Type};
+    use crate::{error::Error, testing::process, transaction};
+
+    #[test]
+    fn round_trips() {
+        let transactions = [
+            transaction::Transaction::deposit(1, 1, dec!(1.5))
+                .unwrap()
+                .with_timestamp("2024-03-31T12:00:00Z".parse().unwrap())
+                .with_batch(2),
+            transaction::Transaction::transfer(
+                1,
+                2,
+                dec!(1),
+                None,
+                Some("savings".parse().unwrap()),
+            )
+            .unwrap(),
+            transaction::Transaction::chargeback(1, 1),
+        ];
+        for trans in transactions {
+            let bytes = Transaction::from(&trans).encode_to_vec();
+            let decoded = Transaction::decode(bytes.as_slice()).unwrap();
+            assert_eq!(transaction::Transaction::try_from(decoded).unwrap(), trans);
+        }
+
+        let mut message = Transaction {
+            client: 70_000,
+            ..Default::default()
+        };
+        message.set_type(Type::Dispute);
+        assert_eq!(
+            transaction::Transaction::try_from(message),
+            Err(Error::ParsingFailure("invalid client `70000`".into()))
+        );
+
+        let accounts = Accounts::from(&process("type, client, tx, amount\ndeposit, 2, 1, 5"));
+        assert_eq!(accounts.accounts[0].client, 2);
+        assert_eq!(accounts.accounts[0].total, "5");
+    }
+}
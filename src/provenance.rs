@@ -0,0 +1,133 @@
+//! Detached signatures of the output, so that consumers can verify that a balance file
+//! comes from the authorized processing job.
+//!
+//! The job signs with an ed25519 key, the hex encoded 32-byte secret in the environment
+//! variable `PAYMENTS_OUTPUT_SIGNING_KEY`. The signature of `accounts.csv` is written to
+//! `accounts.csv.sig` before the file itself appears, hex encoded, and is verified against
+//! the public key of the job, see `OutputKey::public_key`.
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::signature::{from_hex, to_hex};
+
+/// The environment variable holding the key
+pub const KEY_VAR: &str = "PAYMENTS_OUTPUT_SIGNING_KEY";
+
+/// The key the output is signed with
+#[derive(Clone)]
+pub struct OutputKey(SigningKey);
+
+impl OutputKey {
+    /// The key of the hex encoded 32-byte secret
+    pub fn from_hex(secret: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let secret = from_hex(secret.trim())
+            .and_then(|secret| <[u8; 32]>::try_from(secret).ok())
+            .ok_or("an output signing key is 32 hex encoded bytes")?;
+        Ok(Self(SigningKey::from_bytes(&secret)))
+    }
+
+    /// The key in `PAYMENTS_OUTPUT_SIGNING_KEY`
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let secret = std::env::var(KEY_VAR).map_err(|_| format!("`{}` isn't set", KEY_VAR))?;
+        Self::from_hex(&secret)
+    }
+
+    /// The hex encoded public key to verify signatures with
+    pub fn public_key(&self) -> String {
+        to_hex(self.0.verifying_key().as_bytes())
+    }
+
+    /// The hex encoded signature of `data`
+    pub fn sign(&self, data: &[u8]) -> String {
+        to_hex(&self.0.sign(data).to_bytes())
+    }
+}
+
+/// Keeps the key out of logs
+impl fmt::Debug for OutputKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OutputKey(..)")
+    }
+}
+
+/// Where the signature of the file at `path` is written
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(".sig");
+    signature.into()
+}
+
+/// Fails unless `signature` (hex encoded) is the signature of `data` by the key of
+/// `public_key` (hex encoded)
+pub fn verify(
+    public_key: &str,
+    data: &[u8],
+    signature: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let public_key = from_hex(public_key.trim())
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .ok_or("a public key is 32 hex encoded bytes")?;
+    let public_key = VerifyingKey::from_bytes(&public_key)?;
+    let signature = from_hex(signature.trim())
+        .and_then(|signature| <[u8; 64]>::try_from(signature).ok())
+        .ok_or("a signature is 64 hex encoded bytes")?;
+    public_key
+        .verify(data, &Signature::from_bytes(&signature))
+        .map_err(|_| "the signature doesn't match".into())
+}
+
+/// Verify the file at `path` against its signature file, see `signature_path`
+pub fn verify_file(path: &Path, public_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let signature_path = signature_path(path);
+    let signature = std::fs::read_to_string(&signature_path)
+        .map_err(|e| format!("{}: {}", signature_path.display(), e))?;
+    verify(public_key, &std::fs::read(path)?, &signature)
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{signature_path, verify, verify_file, OutputKey};
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    #[test]
+    fn signatures() {
+        let key = OutputKey::from_hex(SECRET).unwrap();
+        // RFC 8032, test 1
+        assert_eq!(
+            key.public_key(),
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        );
+        let accounts = b"client,available,held,total,locked\n1,1.5,0,1.5,false\n";
+        let signature = key.sign(accounts);
+        assert!(verify(&key.public_key(), accounts, &signature).is_ok());
+        assert!(verify(&key.public_key(), b"client,available\n", &signature).is_err());
+
+        let other = OutputKey::from_hex(&SECRET.replace('9', "8")).unwrap();
+        assert!(verify(&other.public_key(), accounts, &signature).is_err());
+        assert!(OutputKey::from_hex("9d61").is_err());
+        assert_eq!(format!("{:?}", key), "OutputKey(..)");
+    }
+
+    #[test]
+    fn signed_files() {
+        let dir = std::env::temp_dir().join(format!("payments-provenance-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.csv");
+        let key = OutputKey::from_hex(SECRET).unwrap();
+        let accounts = "client,available,held,total,locked\n1,1.5,0,1.5,false\n";
+        std::fs::write(&path, accounts).unwrap();
+        assert!(verify_file(&path, &key.public_key()).is_err());
+
+        std::fs::write(signature_path(&path), key.sign(accounts.as_bytes())).unwrap();
+        verify_file(&path, &key.public_key()).unwrap();
+        std::fs::write(&path, accounts.replace("1.5", "15")).unwrap();
+        assert!(verify_file(&path, &key.public_key()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
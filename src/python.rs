@@ -0,0 +1,169 @@
+//! Python bindings (`payments-py`).
+//!
+//! Exposes the `Payments` engine as a Python class. Records are plain dicts
+//! with the same keys as the CSV input (`type`, `client`, `tx`, `amount`),
+//! so `DataFrame.to_dict("records")` can be fed in directly.
+use std::str::FromStr;
+
+use pyo3::{
+    create_exception,
+    exceptions::PyException,
+    prelude::*,
+    types::{PyDict, PyFloat, PyList},
+};
+use rust_decimal::Decimal;
+
+use crate::{
+    client::ClientId, error::Error, parser::transaction, payments::Payments,
+    transaction::TransactionId,
+};
+
+create_exception!(payments, PaymentsError, PyException);
+
+impl From<Error> for PyErr {
+    fn from(error: Error) -> Self {
+        PaymentsError::new_err(error.to_string())
+    }
+}
+
+/// Converts a Python number, string or `decimal.Decimal` into a `Decimal`.
+/// `None` and `NaN` (pandas' representation of a missing value) map to `None`.
+fn to_decimal(value: Option<&Bound<'_, PyAny>>) -> PyResult<Option<Decimal>> {
+    let value = match value {
+        Some(value) if !value.is_none() => value,
+        _ => return Ok(None),
+    };
+    if let Ok(float) = value.downcast::<PyFloat>() {
+        if float.value().is_nan() {
+            return Ok(None);
+        }
+    }
+    let repr = value.str()?.to_string();
+    Decimal::from_str(&repr)
+        .or_else(|_| Decimal::from_scientific(&repr))
+        .map(Some)
+        .map_err(|e| Error::ParsingFailure(format!("invalid amount `{}`: {}", repr, e)).into())
+}
+
+/// Extracts an optional transaction ID. pandas turns integer columns with missing
+/// values into floats, so integral floats are accepted and `NaN` maps to `None`.
+fn to_tx(value: Option<&Bound<'_, PyAny>>) -> PyResult<Option<TransactionId>> {
+    let value = match value {
+        Some(value) if !value.is_none() => value,
+        _ => return Ok(None),
+    };
+    if let Ok(float) = value.downcast::<PyFloat>() {
+        let float = float.value();
+        if float.is_nan() {
+            return Ok(None);
+        }
+        if float.fract() == 0.0 && float >= 0.0 && float <= TransactionId::MAX as f64 {
+            return Ok(Some(float as TransactionId));
+        }
+    }
+    value.extract().map(Some)
+}
+
+fn to_py_decimal(py: Python<'_>, value: Decimal) -> PyResult<Py<PyAny>> {
+    let decimal = py.import("decimal")?.getattr("Decimal")?;
+    Ok(decimal.call1((value.to_string(),))?.unbind())
+}
+
+#[pyclass(name = "Payments")]
+#[derive(Default)]
+struct PyPayments {
+    inner: Payments,
+}
+
+#[pymethods]
+impl PyPayments {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a single transaction. Raises `PaymentsError` if it is rejected.
+    #[pyo3(signature = (kind, client, tx, amount=None, ref_tx=None))]
+    fn apply(
+        &mut self,
+        kind: &str,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<&Bound<'_, PyAny>>,
+        ref_tx: Option<TransactionId>,
+    ) -> PyResult<()> {
+        let transaction = transaction(kind, client, tx, to_decimal(amount)?, ref_tx)?;
+        Ok(self.inner.apply(transaction)?)
+    }
+
+    /// Apply an iterable of record dicts. Rejected transactions don't stop the batch,
+    /// instead they are returned as a list of dicts (`index`, `client`, `tx`, `error`).
+    fn apply_records(
+        &mut self,
+        py: Python<'_>,
+        records: &Bound<'_, PyAny>,
+    ) -> PyResult<Py<PyList>> {
+        let rejected = PyList::empty(py);
+        for (index, record) in records.try_iter()?.enumerate() {
+            let record = record?;
+            let client: ClientId = record.get_item("client")?.extract()?;
+            let tx: TransactionId = record.get_item("tx")?.extract()?;
+            let kind: String = record.get_item("type")?.extract()?;
+            let amount = record.get_item("amount").ok();
+            let ref_tx = record.get_item("ref_tx").ok();
+
+            let result = to_decimal(amount.as_ref())
+                .and_then(|amount| Ok((amount, to_tx(ref_tx.as_ref())?)))
+                .map_err(|e| Error::ParsingFailure(e.to_string()))
+                .and_then(|(amount, ref_tx)| transaction(kind.trim(), client, tx, amount, ref_tx))
+                .and_then(|transaction| self.inner.apply(transaction));
+            if let Err(error) = result {
+                let entry = PyDict::new(py);
+                entry.set_item("index", index)?;
+                entry.set_item("client", client)?;
+                entry.set_item("tx", tx)?;
+                entry.set_item("error", error.to_string())?;
+                rejected.append(entry)?;
+            }
+        }
+        Ok(rejected.unbind())
+    }
+
+    /// Export all accounts (sorted by client ID) as a list of dicts.
+    fn accounts(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        let accounts = PyList::empty(py);
+        for client in self.inner.clients() {
+            let account = PyDict::new(py);
+            account.set_item("client", client.id)?;
+            account.set_item("available", to_py_decimal(py, client.available())?)?;
+            account.set_item("held", to_py_decimal(py, client.held())?)?;
+            account.set_item("total", to_py_decimal(py, client.total())?)?;
+            account.set_item("locked", client.locked())?;
+            accounts.append(account)?;
+        }
+        Ok(accounts.unbind())
+    }
+
+    /// Export all accounts as a `pandas.DataFrame` (requires pandas to be installed).
+    fn to_dataframe(&self, py: Python<'_>) -> PyResult<Py<PyAny>> {
+        let pandas = py.import("pandas")?;
+        let frame = pandas.getattr("DataFrame")?.call1((self.accounts(py)?,))?;
+        Ok(frame.unbind())
+    }
+
+    /// Serialize accounts to CSV, same as the command line tool's output.
+    fn to_csv(&self) -> PyResult<String> {
+        let mut output = Vec::<u8>::new();
+        self.inner
+            .serialize(&mut output)
+            .map_err(|e| PaymentsError::new_err(e.to_string()))?;
+        String::from_utf8(output).map_err(|e| PaymentsError::new_err(e.to_string()))
+    }
+}
+
+#[pymodule]
+fn payments(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPayments>()?;
+    m.add("PaymentsError", m.py().get_type::<PaymentsError>())?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Quarantine of suspicious clients. Unlike a locked account, which rejects transactions, a
+//! quarantined one accepts them, but parks them instead of applying them, until they're
+//! reviewed.
+//!
+//! A client is quarantined by an operator, see `Payments::quarantine`, or by a rule: a
+//! transaction raising the client's risk score above `Config::quarantine_risk_score`. Once the
+//! quarantine is lifted, the rule only applies again after the score dropped below it. The
+//! parked transactions are listed by `Payments::parked`. `Payments::release` applies one of
+//! them, keeping the quarantine, and `Payments::lift_quarantine` lifts it, applying the rest in
+//! the order they came. Released transactions are checked as they're applied, like any other,
+//! and are applied one by one, outside of their batch.
+//!
+//! Parking is recorded in the event log as `Event::TransactionParked`, carrying the
+//! transaction, and releasing as `Event::TransactionReleased` or `Event::QuarantineLifted`, so a
+//! state rebuilt from the event log, e.g. a snapshot, has the same transactions to release.
+//! They're released outside of the source they came from then.
+//!
+//! ```
+//! use payments::{payments::Payments, transaction::Transaction};
+//! use rust_decimal_macros::dec;
+//!
+//! let mut payments = Payments::default();
+//! payments.apply(Transaction::deposit(1, 1, dec!(10)).unwrap()).unwrap();
+//! payments.quarantine(1, None, None).unwrap();
+//! payments.apply(Transaction::withdrawal(1, 2, dec!(4)).unwrap()).unwrap();
+//! assert_eq!(payments.client(1).unwrap().available(), dec!(10));
+//! assert_eq!(payments.parked().count(), 1);
+//!
+//! let released = payments.lift_quarantine(1, None, None).unwrap();
+//! assert_eq!(released, [(2, Ok(()))]);
+//! assert_eq!(payments.client(1).unwrap().available(), dec!(6));
+//! ```
+use std::{collections::BTreeMap, mem::size_of};
+
+use crate::{
+    client::ClientId,
+    error::Error,
+    transaction::{Transaction, TransactionId},
+};
+
+/// The result of every transaction released by `Payments::lift_quarantine`, in order
+pub type Released = Vec<(TransactionId, Result<(), Error>)>;
+
+/// The transactions parked by quarantined clients
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Parked {
+    /// By the offset of their `Event::TransactionParked` in the event log, with the source
+    /// they were attributed to, see `source`
+    transactions: BTreeMap<usize, (Transaction, Option<usize>)>,
+}
+
+impl Parked {
+    /// Parking again what's parked at `offset` already, as its event is recorded again after a
+    /// rollback, keeps its source
+    pub fn park(&mut self, offset: usize, transaction: Transaction, source: Option<usize>) {
+        self.transactions
+            .entry(offset)
+            .or_insert((transaction, source));
+    }
+
+    /// In the order they came
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions
+            .values()
+            .map(|(transaction, _)| transaction)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Remove the parked transactions of `client`, or only the first one of ID `tx`
+    pub fn take(
+        &mut self,
+        client: ClientId,
+        tx: Option<TransactionId>,
+    ) -> Vec<(Transaction, Option<usize>)> {
+        let offsets = self
+            .transactions
+            .iter()
+            .filter(|(_, (transaction, _))| {
+                transaction.client_id == client && tx.is_none_or(|tx| transaction.op.id == tx)
+            })
+            .map(|(&offset, _)| offset)
+            .take(if tx.is_some() { 1 } else { usize::MAX })
+            .collect::<Vec<_>>();
+        offsets
+            .into_iter()
+            .filter_map(|offset| self.transactions.remove(&offset))
+            .collect()
+    }
+
+    /// Forget the transactions parked from offset `end` on, as they were rolled back
+    pub fn truncate(&mut self, end: usize) {
+        self.transactions.split_off(&end);
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.transactions.len() * size_of::<(usize, (Transaction, Option<usize>))>() * 3 / 2
+    }
+}
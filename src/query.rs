@@ -0,0 +1,148 @@
+//! Read-only HTTP queries over a snapshot of the accounts (`payments serve-snapshot`), e.g.
+//! for support staff to inspect last night's state without any way to change it:
+//!
+//! - `GET /accounts`: every account
+//! - `GET /accounts/<client>`: an account, as of an RFC 3339 time with `?as_of=`
+//! - `GET /accounts/<client>/operations`: its deposits and withdrawals with their disputes
+//! - `GET /accounts/<client>/events`: its history, the events its balances are folded from
+//!
+//! Other methods are refused.
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    client::ClientId,
+    close,
+    payments::{Config, Payments},
+    server::{Request, Response},
+    snapshot,
+    transaction::Timestamp,
+};
+
+/// The accounts of a snapshot: exported events (sharded or not), a sealed snapshot of a
+/// close (verifying its seal) or the snapshot of a compacted transaction log, of any version
+pub fn open(path: &Path) -> Result<Payments, Box<dyn std::error::Error>> {
+    if close::is_sealed(path) {
+        return close::open_sealed(path, Config::default());
+    }
+    if path.is_dir() {
+        return Payments::import_sharded_with(Config::default(), path);
+    }
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    Ok(Payments::replay(snapshot::read(file)?.events)?)
+}
+
+fn json(value: &impl serde::Serialize) -> Response {
+    match serde_json::to_string(value) {
+        Ok(body) => Response::json(200, body),
+        Err(e) => Response::text(500, format!("{}\n", e)),
+    }
+}
+
+/// Route the queries to `payments`
+pub fn routes(payments: Arc<Payments>) -> impl Fn(&Request) -> Response + Send + 'static {
+    move |request| {
+        if request.method != "GET" {
+            return Response::text(405, "read-only\n");
+        }
+        let segments = request
+            .path
+            .trim_matches('/')
+            .split('/')
+            .collect::<Vec<_>>();
+        let client = match segments[..] {
+            ["healthz"] => return Response::text(200, "ok\n"),
+            ["accounts"] => return json(&payments.clients().collect::<Vec<_>>()),
+            ["accounts", id, ..] => id
+                .parse::<ClientId>()
+                .ok()
+                .and_then(|id| payments.client(id)),
+            _ => None,
+        };
+        let Some(client) = client else {
+            return Response::not_found();
+        };
+        match segments[2..] {
+            [] => match request
+                .query
+                .get("as_of")
+                .map(|as_of| as_of.parse::<Timestamp>())
+            {
+                None => json(client),
+                Some(Ok(as_of)) => match payments.balance_at(client.id, as_of) {
+                    Ok(balance) => json(&balance),
+                    Err(e) => Response::text(500, format!("{}\n", e)),
+                },
+                Some(Err(_)) => Response::text(400, "`as_of` must be an RFC 3339 time\n"),
+            },
+            ["operations"] => json(&client.operations().collect::<Vec<_>>()),
+            ["events"] => json(
+                &payments
+                    .events()
+                    .iter()
+                    .filter(|event| event.client == client.id)
+                    .collect::<Vec<_>>(),
+            ),
+            _ => Response::not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use super::routes;
+    use crate::{server::Request, testing::process};
+
+    fn request(method: &str, target: &str) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let routes = routes(Arc::new(process(
+            "type, client, tx, amount, timestamp
+            deposit, 1, 1, 5, 2024-03-01T00:00:00Z
+            deposit, 1, 2, 2, 2024-03-02T00:00:00Z
+            dispute, 1, 1, , 2024-03-03T00:00:00Z",
+        )));
+        let get = |target: &str| {
+            let response = routes(&request("GET", target));
+            assert_eq!(response.status, 200, "{}", target);
+            serde_json::from_str::<Value>(&response.body).unwrap()
+        };
+
+        assert_eq!(get("/accounts").as_array().unwrap().len(), 1);
+        let account = get("/accounts/1");
+        assert_eq!(
+            (&account["available"], &account["held"]),
+            (&json!("2"), &json!("5"))
+        );
+        let as_of = get("/accounts/1?as_of=2024-03-01T12:00:00Z");
+        assert_eq!(as_of["available"], json!("5"));
+        assert_eq!(
+            get("/accounts/1/operations")[0]["state"],
+            json!("in_dispute")
+        );
+        assert_eq!(get("/accounts/1/events").as_array().unwrap().len(), 3);
+
+        assert_eq!(routes(&request("GET", "/accounts/2")).status, 404);
+        assert_eq!(
+            routes(&request("GET", "/accounts/1?as_of=soon")).status,
+            400
+        );
+        assert_eq!(routes(&request("POST", "/accounts/1")).status, 405);
+    }
+}
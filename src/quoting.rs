@@ -0,0 +1,251 @@
+//! Splitting CSV input into records per RFC 4180, including what the `csv` reader can't
+//! take: quoted fields after whitespace, e.g. `deposit, 1, 1, "1.0", "lunch, dinner"`, and
+//! in the lenient mode, the unescaped quotes some partners emit, e.g. `"12" screen"`.
+//!
+//! Every record is split on its own, so a malformed one fails alone instead of throwing the
+//! reader off for the rest of the input: one with a stray quote, invalid UTF-8 or, unless
+//! flexible, more or fewer fields than the header. In the lenient mode a quote which doesn't
+//! close its field is taken literally, and so is one which isn't closed on the same line, so
+//! quoted fields don't span lines there.
+use std::io::BufRead;
+
+use csv::{Position, StringRecord};
+
+use crate::{error::Error, parser::ParseOptions};
+
+const BLANK: [char; 2] = [' ', '\t'];
+
+/// The records of CSV input, the header being the first
+pub struct Records<R> {
+    input: R,
+    options: ParseOptions,
+    /// Fields of the header, once read
+    fields: Option<usize>,
+    line: u64,
+    byte: u64,
+    record: u64,
+}
+
+impl<R: BufRead> Records<R> {
+    pub fn new(input: R, options: ParseOptions) -> Self {
+        Self {
+            input,
+            options,
+            fields: None,
+            line: 0,
+            byte: 0,
+            record: 0,
+        }
+    }
+
+    /// Append the next line to `text`, false at the end of the input
+    fn read_line(&mut self, text: &mut String) -> Result<bool, String> {
+        let mut line = Vec::new();
+        let read = self
+            .input
+            .read_until(b'\n', &mut line)
+            .map_err(|e| e.to_string())?;
+        self.line += 1;
+        self.byte += read as u64;
+        text.push_str(&String::from_utf8(line).map_err(|_| "invalid UTF-8".to_string())?);
+        Ok(read > 0)
+    }
+
+    /// Check the number of fields of `record` against the header's, or if flexible, pad
+    /// or cut it to that
+    fn fit(&mut self, mut record: StringRecord) -> Result<StringRecord, String> {
+        let fields = *self.fields.get_or_insert(record.len());
+        if record.len() != fields && !self.options.flexible {
+            return Err(format!(
+                "found record with {} fields, but the header has {}",
+                record.len(),
+                fields
+            ));
+        }
+        record.truncate(fields);
+        while record.len() < fields {
+            record.push_field("");
+        }
+        Ok(record)
+    }
+
+    /// Split the record starting with the line `text`, reading the following lines of
+    /// quoted fields spanning them
+    fn split(&mut self, mut text: String) -> Result<StringRecord, String> {
+        let mut record = StringRecord::new();
+        let mut pos = 0;
+        loop {
+            pos = text.len() - text[pos..].trim_start_matches(BLANK).len();
+            let field_start = pos;
+            let mut field = String::new();
+            let mut quoted = text[pos..].starts_with('"');
+            if quoted {
+                pos += 1;
+                loop {
+                    let Some(quote) = text[pos..].find('"').map(|i| pos + i) else {
+                        if self.options.lenient_quotes {
+                            // Never closed, so it's just a quote
+                            (quoted, pos) = (false, field_start);
+                            break;
+                        }
+                        if !self.read_line(&mut text)? {
+                            return Err("unterminated quoted field".to_string());
+                        }
+                        continue;
+                    };
+                    field.push_str(&text[pos..quote]);
+                    if text[quote + 1..].starts_with('"') {
+                        field.push('"');
+                        pos = quote + 2;
+                        continue;
+                    }
+                    let after = text[quote + 1..].trim_start_matches(BLANK);
+                    if after.is_empty() || after.starts_with([',', '\r', '\n']) {
+                        pos = text.len() - after.len();
+                        break;
+                    }
+                    if !self.options.lenient_quotes {
+                        return Err(format!("unescaped quote in field `{}`", field));
+                    }
+                    field.push('"');
+                    pos = quote + 1;
+                }
+            }
+            if !quoted {
+                let end = text[pos..]
+                    .find([',', '\r', '\n'])
+                    .map_or(text.len(), |i| pos + i);
+                field = text[pos..end].trim_end_matches(BLANK).to_string();
+                pos = end;
+            }
+            record.push_field(&field);
+            match text[pos..].starts_with(',') {
+                true => pos += 1,
+                false => return Ok(record),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = Result<StringRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut text = String::new();
+        let start = loop {
+            text.clear();
+            let start = self.byte;
+            match self.read_line(&mut text) {
+                Ok(true) if text.trim().is_empty() => continue,
+                Ok(true) => break start,
+                Ok(false) => return None,
+                Err(e) => {
+                    let error = format!("line {}: {}", self.line, e);
+                    return Some(Err(Error::ParsingFailure(error)));
+                }
+            }
+        };
+        let line = self.line;
+        let mut position = Position::new();
+        position
+            .set_byte(start)
+            .set_line(line)
+            .set_record(self.record);
+        self.record += 1;
+        Some(
+            self.split(text)
+                .and_then(|record| self.fit(record))
+                .map(|mut record| {
+                    record.set_position(Some(position));
+                    record
+                })
+                .map_err(|e| Error::ParsingFailure(format!("line {}: {}", line, e))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Records;
+    use crate::parser::ParseOptions;
+
+    fn split(input: &str, lenient_quotes: bool) -> Vec<Result<Vec<String>, String>> {
+        let options = ParseOptions {
+            lenient_quotes,
+            flexible: true,
+        };
+        Records::new(input.as_bytes(), options)
+            .map(|record| {
+                record
+                    .map(|record| record.iter().map(str::to_string).collect())
+                    .map_err(|e| e.to_string())
+            })
+            .collect()
+    }
+
+    fn fields(fields: &[&str]) -> Result<Vec<String>, String> {
+        Ok(fields.iter().map(|field| field.to_string()).collect())
+    }
+
+    #[test]
+    fn rfc_4180() {
+        let input = "a, b ,c\r\n\n \"1,0\" , \"say \"\"hi\"\"\",\n\"two\nlines\",x,\"\"\n";
+        assert_eq!(
+            split(input, false),
+            [
+                fields(&["a", "b", "c"]),
+                fields(&["1,0", "say \"hi\"", ""]),
+                fields(&["two\nlines", "x", ""]),
+            ]
+        );
+    }
+
+    #[test]
+    fn field_counts() {
+        let input = b"a, b\n1\n1, 2, 3\n\xff, 4\n5, 6\n".as_slice();
+        let fields = |flexible| {
+            let options = ParseOptions {
+                lenient_quotes: false,
+                flexible,
+            };
+            Records::new(input, options)
+                .map(|record| record.map(|record| record.iter().collect::<Vec<_>>().join("|")))
+                .map(|record| record.map_err(|e| e.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let strict = fields(false);
+        assert!(strict[1].as_ref().unwrap_err().contains("1 fields"));
+        assert!(strict[2].as_ref().unwrap_err().contains("3 fields"));
+        assert!(strict[3]
+            .as_ref()
+            .unwrap_err()
+            .contains("line 4: invalid UTF-8"));
+        assert_eq!(strict[4], Ok("5|6".to_string()));
+        let flexible = fields(true);
+        assert_eq!(flexible[1], Ok("1|".to_string()));
+        assert_eq!(flexible[2], Ok("1|2".to_string()));
+        assert!(flexible[3].is_err());
+        assert_eq!(flexible[4], Ok("5|6".to_string()));
+    }
+
+    #[test]
+    fn malformed_records_fail_alone() {
+        let input = "\"12\" screen\", 1\nok, 2\n\"open, 3\nok, 4";
+        let strict = split(input, false);
+        assert!(strict[0].is_err());
+        assert_eq!(strict[1], fields(&["ok", "2"]));
+        // The unterminated quote takes the rest of the input
+        assert_eq!(strict.len(), 3);
+        assert!(strict[2].is_err());
+
+        assert_eq!(
+            split(input, true),
+            [
+                fields(&["12\" screen", "1"]),
+                fields(&["ok", "2"]),
+                fields(&["\"open", "3"]),
+                fields(&["ok", "4"]),
+            ]
+        );
+    }
+}
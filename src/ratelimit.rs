@@ -0,0 +1,189 @@
+//! Rate limits of the daemon mode, per input source and per client, so that a misbehaving
+//! producer or client can't starve the others.
+//!
+//! Limits are token buckets refilled at the limit's rate, holding up to a second's worth of
+//! transactions. A transaction over a limit is either rejected (`Overload::Shed`), or waits
+//! until it's within the limit (`Overload::Queue`), which holds up the input of every source
+//! and client as the transactions are applied one by one.
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{client::ClientId, error::Error, transaction::Transaction};
+
+/// What to do with transactions over a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overload {
+    /// Reject them with `Error::RateLimited`
+    #[default]
+    Shed,
+    /// Wait until they're within the limit, holding up the whole input
+    Queue,
+}
+
+impl FromStr for Overload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shed" => Ok(Overload::Shed),
+            "queue" => Ok(Overload::Queue),
+            other => Err(format!(
+                "unknown overload behavior `{}`, expected `shed` or `queue`",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets by key, e.g. by client
+#[derive(Debug, Clone)]
+pub struct RateLimiter<K> {
+    /// Per second
+    rate: f64,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// Allow `rate` transactions per second for every key, which has to be positive
+    pub fn new(rate: f64) -> Result<Self, String> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(format!("invalid rate: `{}`", rate));
+        }
+        Ok(Self {
+            rate,
+            buckets: HashMap::new(),
+        })
+    }
+
+    fn burst(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    /// Take a token for `key` at `now`, or how long to wait until one is available
+    pub fn acquire(&mut self, key: K, now: Instant) -> Result<(), Duration> {
+        let (rate, burst) = (self.rate, self.burst());
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = bucket.updated.max(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / rate;
+            Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+        }
+    }
+
+    /// Take a token for `key`, waiting for one when queueing. False if it's shed.
+    fn take(&mut self, key: K, overload: Overload) -> bool
+    where
+        K: Clone,
+    {
+        loop {
+            match (self.acquire(key.clone(), Instant::now()), overload) {
+                (Ok(()), _) => return true,
+                (Err(_), Overload::Shed) => return false,
+                (Err(wait), Overload::Queue) => std::thread::sleep(wait),
+            }
+        }
+    }
+}
+
+/// The rate limits of the input
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    pub sources: Option<RateLimiter<String>>,
+    pub clients: Option<RateLimiter<ClientId>>,
+    pub overload: Overload,
+}
+
+impl Throttle {
+    /// Admit a transaction of `source`, waiting as long as needed when queueing.
+    /// A shed transaction may still have taken a token of its source.
+    pub fn admit(&mut self, source: &str, transaction: &Transaction) -> Result<(), Error> {
+        let admitted = self
+            .sources
+            .as_mut()
+            .is_none_or(|sources| sources.take(source.to_string(), self.overload))
+            && self
+                .clients
+                .as_mut()
+                .is_none_or(|clients| clients.take(transaction.client_id, self.overload));
+        if !admitted {
+            return Err(Error::RateLimited(transaction.op.id));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use rust_decimal_macros::dec;
+
+    use super::{Overload, RateLimiter, Throttle};
+    use crate::{error::Error, parser::transaction};
+
+    #[test]
+    fn token_buckets() {
+        let mut limiter = RateLimiter::new(2.0).unwrap();
+        let start = Instant::now();
+        assert_eq!(limiter.acquire(1, start), Ok(()));
+        assert_eq!(limiter.acquire(1, start), Ok(()));
+        assert_eq!(limiter.acquire(1, start), Err(Duration::from_millis(500)));
+        // Other keys have their own bucket
+        assert_eq!(limiter.acquire(2, start), Ok(()));
+        assert_eq!(
+            limiter.acquire(1, start + Duration::from_millis(500)),
+            Ok(())
+        );
+        // Buckets hold a second's worth at most
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.acquire(1, later), Ok(()));
+        assert_eq!(limiter.acquire(1, later), Ok(()));
+        assert!(limiter.acquire(1, later).is_err());
+    }
+
+    #[test]
+    fn shed_over_limit() {
+        let mut throttle = Throttle {
+            clients: Some(RateLimiter::new(0.001).unwrap()),
+            overload: Overload::Shed,
+            ..Throttle::default()
+        };
+        let first = transaction("deposit", 1, 1, Some(dec!(1)), None).unwrap();
+        let second = transaction("deposit", 1, 2, Some(dec!(1)), None).unwrap();
+        let other = transaction("deposit", 2, 3, Some(dec!(1)), None).unwrap();
+        assert_eq!(throttle.admit("a", &first), Ok(()));
+        assert_eq!(throttle.admit("a", &second), Err(Error::RateLimited(2)));
+        assert_eq!(throttle.admit("a", &other), Ok(()));
+    }
+
+    #[test]
+    fn rates() {
+        assert!(RateLimiter::<u16>::new(0.0).is_err());
+        assert!(RateLimiter::<u16>::new(-1.0).is_err());
+        assert!(RateLimiter::<u16>::new(f64::NAN).is_err());
+        assert!(RateLimiter::<u16>::new(f64::INFINITY).is_err());
+        // The wait for a tiny rate doesn't fit a duration
+        let mut limiter = RateLimiter::new(f64::MIN_POSITIVE).unwrap();
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(1, now), Ok(()));
+        assert_eq!(limiter.acquire(1, now), Err(Duration::MAX));
+    }
+}
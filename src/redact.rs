@@ -0,0 +1,131 @@
+//! Redaction of balance data in diagnostics, so that logs and panic messages of production
+//! runs don't leak it into log aggregation.
+//!
+//! With redaction enabled (`--redact`, or `redact` in the `[output]` section of the settings),
+//! amounts and counterparties are written as `[redacted]` by the `Debug` and `Display` of
+//! clients, the messages of errors, e.g. of rejected transactions, and the stats. It's
+//! process-wide, as formatting has no access to the configuration of an engine. The outputs,
+//! e.g. the accounts, aren't affected.
+//!
+//! ```
+//! use payments::{client::Client, redact};
+//!
+//! redact::set(true);
+//! assert_eq!(
+//!     Client::new(1).to_string(),
+//!     "client 1: available [redacted], held [redacted], total [redacted], locked false"
+//! );
+//! # redact::set(false);
+//! ```
+use std::fmt;
+
+#[cfg(not(test))]
+static ENABLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// Per thread in the unit tests, which run in parallel
+#[cfg(test)]
+thread_local! {
+    static ENABLED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Enable or disable redaction for the whole process
+pub fn set(enabled: bool) {
+    #[cfg(not(test))]
+    ENABLED.store(enabled, std::sync::atomic::Ordering::Relaxed);
+    #[cfg(test)]
+    ENABLED.with(|cell| cell.set(enabled));
+}
+
+pub fn enabled() -> bool {
+    #[cfg(not(test))]
+    return ENABLED.load(std::sync::atomic::Ordering::Relaxed);
+    #[cfg(test)]
+    ENABLED.with(std::cell::Cell::get)
+}
+
+/// A value formatted as `[redacted]` while redaction is enabled
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Redacted<T>(pub T);
+
+const REDACTED: &str = "[redacted]";
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match enabled() {
+            true => f.write_str(REDACTED),
+            false => self.0.fmt(f),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match enabled() {
+            true => f.write_str(REDACTED),
+            false => self.0.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{set, Redacted};
+    use crate::{
+        client::Client, counterparty::Counterparty, error::Error, payments::Payments,
+        transaction::Transaction,
+    };
+
+    #[test]
+    fn redaction() {
+        let mut payments = Payments::default();
+        payments
+            .apply(Transaction::deposit(1, 1, dec!(10.5)).unwrap())
+            .unwrap();
+        let rejected = Error::InsufficientFunds {
+            id: 2,
+            available: dec!(10.5),
+            requested: dec!(20),
+        };
+        let counterparty = "ACME-Ltd".parse::<Counterparty>().unwrap();
+        let client = payments.client(1).unwrap();
+        assert_eq!(
+            client.to_string(),
+            "client 1: available 10.5, held 0, total 10.5, locked false"
+        );
+        assert!(format!("{:?}", client).contains("available: 10.5"));
+        assert!(format!("{:?}", client).contains("operations"));
+
+        set(true);
+        let formatted = [
+            client.to_string(),
+            format!("{:?}", client),
+            rejected.to_string(),
+            format!("{:?}", counterparty),
+            payments.stats().to_string(),
+            format!("{} {:?}", Redacted(1.5), Redacted("ACME Ltd")),
+        ];
+        set(false);
+        assert_eq!(
+            formatted[0],
+            "client 1: available [redacted], held [redacted], total [redacted], locked false"
+        );
+        assert!(!formatted[1].contains("operations"));
+        assert_eq!(
+            formatted[2],
+            "withdrawal transaction ID `2` of [redacted] failed because of insufficient funds: [redacted]"
+        );
+        assert_eq!(formatted[3], "Counterparty([redacted])");
+        assert!(formatted[4].contains("written off: [redacted]"));
+        assert_eq!(formatted[5], "[redacted] [redacted]");
+        assert!(formatted
+            .iter()
+            .all(|f| !f.contains("10.5") && !f.contains("ACME")));
+
+        assert_eq!(
+            Client::new(2).to_string(),
+            "client 2: available 0, held 0, total 0, locked false"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! A slow reference implementation of the engine, to check `Payments` against.
+//!
+//! It keeps nothing but the log of accepted operations, and recomputes a client's state
+//! from scratch to decide every transaction. Only the default `Config` is covered.
+use std::collections::{BTreeMap, HashMap};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    client::{ClientId, OperationState},
+    error::Error,
+    subaccount::SubAccount,
+    transaction::{Operation, OperationType, Transaction, TransactionId, AMOUNT_SCALE},
+};
+
+/// Balances of a client
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Account {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// See the module documentation
+#[derive(Debug, Clone, Default)]
+pub struct Reference {
+    /// Accepted operations by client, in the order they were applied
+    log: BTreeMap<ClientId, Vec<Operation>>,
+}
+
+/// A client's state: the balances, the amount (negative for withdrawals) and the
+/// state of every deposit and withdrawal, and the available funds of the sub-accounts
+type State = (
+    Account,
+    HashMap<TransactionId, (Decimal, OperationState)>,
+    HashMap<SubAccount, Decimal>,
+);
+
+impl Reference {
+    /// Apply a transaction, a client is created even if it fails
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        let (account, operations, sub_accounts) = self.state(transaction.client_id);
+        let log = self.log.entry(transaction.client_id).or_default();
+        let op = &transaction.op;
+        let id = op.id;
+        // Nothing is pending with the default `Config`, a settlement changes nothing
+        if op.kind == OperationType::Settle {
+            operations.get(&id).ok_or(Error::TransactionNotFound(id))?;
+            return Ok(());
+        }
+        if account.locked {
+            return Err(Error::AccountLocked(id));
+        }
+        let duplicate = operations.contains_key(&id)
+            || log.iter().any(|logged| {
+                logged.id == id && matches!(logged.kind, OperationType::Transfer { .. })
+            });
+        // Of the main account, unless a sub-account is given
+        let available = |sub_account: Option<SubAccount>| match sub_account {
+            Some(sub_account) => sub_accounts.get(&sub_account).copied().unwrap_or_default(),
+            None => account.available - sub_accounts.values().sum::<Decimal>(),
+        };
+        let (amount, state) = match op.kind {
+            OperationType::Deposit { amount, ref_tx, .. }
+            | OperationType::Withdrawal { amount, ref_tx, .. } => {
+                if duplicate {
+                    return Err(Error::DuplicatedTransaction(id));
+                }
+                if let Some(ref_tx) = ref_tx.filter(|r| *r == id || !operations.contains_key(r)) {
+                    return Err(Error::InvalidReference { id, ref_tx });
+                }
+                let withdrawal = matches!(op.kind, OperationType::Withdrawal { .. });
+                if withdrawal && available(None) < amount {
+                    return Err(Error::InsufficientFunds {
+                        id,
+                        available: available(None),
+                        requested: amount,
+                    });
+                }
+                log.push(op.clone());
+                return Ok(());
+            }
+            OperationType::Transfer { amount, from, .. } => {
+                if duplicate {
+                    return Err(Error::DuplicatedTransaction(id));
+                }
+                if available(from) < amount {
+                    return Err(Error::InsufficientFunds {
+                        id,
+                        available: available(from),
+                        requested: amount,
+                    });
+                }
+                log.push(op.clone());
+                return Ok(());
+            }
+            OperationType::Amend { amount } => {
+                let (current, state) =
+                    *operations.get(&id).ok_or(Error::TransactionNotFound(id))?;
+                if state != OperationState::New {
+                    return Err(Error::AmendmentOfDisputed(id));
+                }
+                if amount <= Decimal::ZERO || amount.normalize().scale() > AMOUNT_SCALE {
+                    return Err(Error::InvalidAmount(id));
+                }
+                let amended = match current.is_sign_negative() {
+                    true => -amount,
+                    false => amount,
+                };
+                let taken = current - amended;
+                if taken > Decimal::ZERO && available(None) < taken {
+                    return Err(Error::InsufficientFunds {
+                        id,
+                        available: available(None),
+                        requested: taken,
+                    });
+                }
+                if amended != current {
+                    log.push(op.clone());
+                }
+                return Ok(());
+            }
+            _ => *operations.get(&id).ok_or(Error::TransactionNotFound(id))?,
+        };
+        let to = match op.kind {
+            OperationType::Dispute => OperationState::InDispute,
+            OperationType::Resolve => OperationState::Resolved,
+            _ => OperationState::Chargedback,
+        };
+        // Delivered again, nothing changes
+        if state == to {
+            return Ok(());
+        }
+        if to == OperationState::InDispute && available(None) < amount.max(Decimal::ZERO) {
+            return Err(Error::FailedDisputeNotEnoughFunds(id));
+        }
+        let allowed = match to {
+            OperationState::InDispute => state == OperationState::New,
+            _ => state == OperationState::InDispute,
+        };
+        if !allowed {
+            return Err(Error::InvalidTransactionStateChange {
+                id,
+                from: state,
+                to,
+            });
+        }
+        log.push(op.clone());
+        Ok(())
+    }
+
+    /// Clients created so far
+    pub fn clients(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.log.keys().copied()
+    }
+
+    pub fn account(&self, client: ClientId) -> Account {
+        self.state(client).0
+    }
+
+    /// Recompute the state of `client` from the log
+    fn state(&self, client: ClientId) -> State {
+        let mut account = Account::default();
+        let mut operations = HashMap::new();
+        let mut sub_accounts = HashMap::new();
+        for op in self.log.get(&client).into_iter().flatten() {
+            match op.kind {
+                OperationType::Deposit { amount, .. } => {
+                    account.available += amount;
+                    account.total += amount;
+                    operations.insert(op.id, (amount, OperationState::New));
+                }
+                OperationType::Withdrawal { amount, .. } => {
+                    account.available -= amount;
+                    account.total -= amount;
+                    operations.insert(op.id, (-amount, OperationState::New));
+                }
+                OperationType::Transfer { amount, from, to } => {
+                    if let Some(from) = from {
+                        *sub_accounts.entry(from).or_default() -= amount;
+                    }
+                    if let Some(to) = to {
+                        *sub_accounts.entry(to).or_default() += amount;
+                    }
+                }
+                OperationType::Amend { amount } => {
+                    // Only operations of the client are amended
+                    let Some((current, _)) = operations.get_mut(&op.id) else {
+                        continue;
+                    };
+                    let amended = match current.is_sign_negative() {
+                        true => -amount,
+                        false => amount,
+                    };
+                    account.available += amended - *current;
+                    account.total += amended - *current;
+                    *current = amended;
+                }
+                OperationType::Settle => {}
+                OperationType::Dispute | OperationType::Resolve | OperationType::Chargeback => {
+                    // Only operations of the client are accepted
+                    let Some((amount, state)) = operations.get_mut(&op.id) else {
+                        continue;
+                    };
+                    let amount = *amount;
+                    // Nothing is held for a withdrawal, the funds already left
+                    let held = amount.max(Decimal::ZERO);
+                    match op.kind {
+                        OperationType::Dispute => {
+                            account.available -= held;
+                            account.held += held;
+                            *state = OperationState::InDispute;
+                        }
+                        OperationType::Resolve => {
+                            account.available += held;
+                            account.held -= held;
+                            *state = OperationState::Resolved;
+                        }
+                        _ => {
+                            account.held -= held;
+                            account.total -= held;
+                            // A charged back withdrawal is credited back
+                            if amount.is_sign_negative() {
+                                account.available -= amount;
+                                account.total -= amount;
+                            }
+                            account.locked = true;
+                            *state = OperationState::Chargedback;
+                        }
+                    }
+                }
+            }
+        }
+        (account, operations, sub_accounts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{Account, Reference};
+    use crate::{error::Error, parser::transaction};
+
+    #[test]
+    fn disputes() {
+        let mut reference = Reference::default();
+        let mut apply =
+            |kind, tx, amount| reference.apply(&transaction(kind, 1, tx, amount, None).unwrap());
+        assert_eq!(apply("deposit", 1, Some(dec!(10))), Ok(()));
+        assert_eq!(apply("withdrawal", 2, Some(dec!(4))), Ok(()));
+        assert_eq!(
+            apply("dispute", 1, None),
+            Err(Error::FailedDisputeNotEnoughFunds(1))
+        );
+        assert_eq!(apply("dispute", 2, None), Ok(()));
+        assert_eq!(apply("dispute", 2, None), Ok(()));
+        assert_eq!(apply("chargeback", 2, None), Ok(()));
+        assert_eq!(
+            apply("deposit", 3, Some(dec!(1))),
+            Err(Error::AccountLocked(3))
+        );
+        assert_eq!(
+            reference.account(1),
+            Account {
+                available: dec!(10),
+                held: dec!(0),
+                total: dec!(10),
+                locked: true
+            }
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Reordering of approximately ordered input, e.g. merged from multiple sources.
+//!
+//! Transactions are held back until the input has moved more than a time window past
+//! their timestamp, and released in timestamp order. A transaction older than that arrives
+//! too late to be put in place: it is released right away, with a warning.
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use chrono::Duration;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    error::Error,
+    transaction::{shift, Timestamp, Transaction, TransactionId},
+};
+
+#[derive(Error, Debug, PartialEq, Serialize)]
+#[error(
+    "transaction ID `{id}` at {timestamp} arrived after {watermark}, outside the reorder window"
+)]
+pub struct LateArrival {
+    #[serde(rename = "tx")]
+    pub id: TransactionId,
+    pub timestamp: Timestamp,
+    /// The latest timestamp seen when the transaction arrived
+    pub watermark: Timestamp,
+}
+
+/// A buffered transaction, ordered by timestamp and then by arrival
+#[derive(Debug)]
+struct Pending {
+    timestamp: Option<Timestamp>,
+    seq: u64,
+    transaction: Transaction,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.seq).cmp(&(other.timestamp, other.seq))
+    }
+}
+
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    window: Duration,
+    pending: BinaryHeap<Reverse<Pending>>,
+    /// The latest timestamp seen
+    watermark: Option<Timestamp>,
+    /// Timestamp of the previous transaction, for the ones without a timestamp
+    previous: Option<Timestamp>,
+    seq: u64,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: BinaryHeap::new(),
+            watermark: None,
+            previous: None,
+            seq: 0,
+        }
+    }
+
+    /// Buffer a transaction. A transaction without a timestamp stays right after
+    /// the one preceding it in the input.
+    pub fn push(&mut self, transaction: Transaction) -> Result<(), LateArrival> {
+        let timestamp = transaction.timestamp.or(self.previous);
+        self.previous = timestamp;
+        self.seq += 1;
+
+        let late = match (transaction.timestamp, self.watermark) {
+            (Some(timestamp), Some(watermark)) if timestamp < shift(watermark, -self.window) => {
+                Err(LateArrival {
+                    id: transaction.op.id,
+                    timestamp,
+                    watermark,
+                })
+            }
+            _ => Ok(()),
+        };
+        self.watermark = self.watermark.max(transaction.timestamp);
+        self.pending.push(Reverse(Pending {
+            timestamp,
+            seq: self.seq,
+            transaction,
+        }));
+        late
+    }
+
+    /// The next transaction which can no longer be preceded by one still to come
+    pub fn pop_ready(&mut self) -> Option<Transaction> {
+        let Reverse(next) = self.pending.peek()?;
+        let ready = match (next.timestamp, self.watermark) {
+            (Some(timestamp), Some(watermark)) => timestamp <= shift(watermark, -self.window),
+            _ => true,
+        };
+        if !ready {
+            return None;
+        }
+        self.pending.pop().map(|Reverse(next)| next.transaction)
+    }
+
+    /// Release all buffered transactions, at the end of the input
+    pub fn drain(mut self) -> impl Iterator<Item = Transaction> {
+        std::iter::from_fn(move || self.pending.pop().map(|Reverse(next)| next.transaction))
+    }
+}
+
+/// Reorder a stream of transactions with a `ReorderBuffer`, reporting late arrivals to `on_late`
+pub fn reordered(
+    transactions: impl IntoIterator<Item = Result<Transaction, Error>>,
+    window: Duration,
+    mut on_late: impl FnMut(LateArrival),
+) -> impl Iterator<Item = Result<Transaction, Error>> {
+    let mut buffer = ReorderBuffer::new(window);
+    let mut input = transactions.into_iter();
+    std::iter::from_fn(move || loop {
+        if let Some(transaction) = buffer.pop_ready() {
+            return Some(Ok(transaction));
+        }
+        match input.next() {
+            Some(Ok(transaction)) => {
+                if let Err(late) = buffer.push(transaction) {
+                    on_late(late);
+                }
+            }
+            Some(Err(error)) => return Some(Err(error)),
+            None => {
+                return buffer
+                    .pending
+                    .pop()
+                    .map(|Reverse(next)| Ok(next.transaction))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::{reordered, LateArrival, ReorderBuffer};
+    use crate::transaction::{Operation, OperationType, Timestamp, Transaction};
+
+    fn at(minutes: i64) -> Option<Timestamp> {
+        Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + Duration::minutes(minutes))
+    }
+
+    fn transaction(id: u32, timestamp: Option<Timestamp>) -> Transaction {
+        Transaction {
+            client_id: 1,
+            timestamp,
+            batch: None,
+            tenant: None,
+            signature: None,
+            value_date: None,
+            seq: None,
+            op: Operation {
+                id,
+                kind: OperationType::Dispute,
+            },
+        }
+    }
+
+    fn ids(transactions: impl IntoIterator<Item = Transaction>) -> Vec<u32> {
+        transactions.into_iter().map(|t| t.op.id).collect()
+    }
+
+    #[test]
+    fn reorders_within_window() {
+        let mut buffer = ReorderBuffer::new(Duration::minutes(10));
+        let mut released = Vec::new();
+        for t in [
+            transaction(1, at(0)),
+            transaction(3, at(5)),
+            transaction(2, at(3)),
+            transaction(4, None),
+            transaction(5, at(12)),
+            transaction(6, at(20)),
+        ] {
+            buffer.push(t).unwrap();
+            released.extend(std::iter::from_fn(|| buffer.pop_ready()));
+        }
+        // Up to 10 minutes before the latest timestamp
+        assert_eq!(ids(released), [1, 2, 4, 3]);
+        assert_eq!(ids(buffer.drain()), [5, 6]);
+    }
+
+    #[test]
+    fn reordered_stream() {
+        let input = [
+            transaction(2, at(5)),
+            transaction(1, at(0)),
+            transaction(3, at(30)),
+            transaction(4, at(1)),
+        ];
+        let mut late = Vec::new();
+        let output = reordered(input.map(Ok), Duration::minutes(10), |l| late.push(l.id))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(ids(output), [1, 2, 4, 3]);
+        assert_eq!(late, [4]);
+    }
+
+    #[test]
+    fn late_arrivals() {
+        let mut buffer = ReorderBuffer::new(Duration::minutes(10));
+        buffer.push(transaction(1, at(0))).unwrap();
+        buffer.push(transaction(2, at(30))).unwrap();
+        assert_eq!(buffer.pop_ready().map(|t| t.op.id), Some(1));
+        assert_eq!(buffer.pop_ready(), None);
+
+        assert_eq!(
+            buffer.push(transaction(3, at(15))),
+            Err(LateArrival {
+                id: 3,
+                timestamp: at(15).unwrap(),
+                watermark: at(30).unwrap(),
+            })
+        );
+        // Released right away, ahead of the buffered ones
+        assert_eq!(buffer.pop_ready().map(|t| t.op.id), Some(3));
+        assert_eq!(ids(buffer.drain()), [2]);
+    }
+}
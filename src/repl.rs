@@ -0,0 +1,296 @@
+//! Interactive mode for reproducing edge cases by hand.
+//!
+//! Every line is a single command:
+//! - `deposit|withdrawal|amend <client> <tx> <amount>`
+//! - `dispute|resolve|chargeback|settle <client> <tx>`
+//! - `balance <client>`
+//! - `undo [<count>]`
+//! - `dump`
+//! - `help`
+//! - `quit`
+//!
+//! With a configuration file, changes of it are applied before the next command, see
+//! `config::ConfigWatcher`.
+use std::{
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    client::ClientId, config::ConfigWatcher, error::Error, parser::transaction, payments::Payments,
+    transaction::Transaction,
+};
+
+const HELP: &str = "\
+commands:
+  deposit <client> <tx> <amount>
+  withdrawal <client> <tx> <amount>
+  amend <client> <tx> <amount>
+  dispute <client> <tx>
+  resolve <client> <tx>
+  chargeback <client> <tx>
+  settle <client> <tx>
+  balance <client>
+  undo [<count>]
+  dump
+  help
+  quit";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Apply(Transaction),
+    Balance(ClientId),
+    Undo(usize),
+    Dump,
+    Help,
+    Quit,
+}
+
+fn argument<T: FromStr>(args: &[&str], idx: usize, name: &str) -> Result<T, Error> {
+    let arg = args
+        .get(idx)
+        .ok_or_else(|| Error::ParsingFailure(format!("missing <{}>", name)))?;
+    arg.parse()
+        .map_err(|_| Error::ParsingFailure(format!("invalid <{}>: `{}`", name, arg)))
+}
+
+fn parse_command(line: &str) -> Result<Option<Command>, Error> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (command, args) = match words.split_first() {
+        Some((command, args)) => (*command, args),
+        None => return Ok(None),
+    };
+    let command = match command {
+        "deposit" | "withdrawal" | "amend" => Command::Apply(transaction(
+            command,
+            argument(args, 0, "client")?,
+            argument(args, 1, "tx")?,
+            Some(argument::<Decimal>(args, 2, "amount")?),
+            None,
+        )?),
+        "dispute" | "resolve" | "chargeback" | "settle" => Command::Apply(transaction(
+            command,
+            argument(args, 0, "client")?,
+            argument(args, 1, "tx")?,
+            None,
+            None,
+        )?),
+        "balance" => Command::Balance(argument(args, 0, "client")?),
+        "undo" if args.is_empty() => Command::Undo(1),
+        "undo" => Command::Undo(argument(args, 0, "count")?),
+        "dump" => Command::Dump,
+        "help" => Command::Help,
+        "quit" | "exit" => Command::Quit,
+        other => {
+            return Err(Error::ParsingFailure(format!(
+                "unknown command `{}`, try `help`",
+                other
+            )))
+        }
+    };
+    Ok(Some(command))
+}
+
+/// Read commands from `input` until EOF or `quit`, writing responses to `output`.
+pub fn run(
+    payments: &mut Payments,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_watched(payments, input, output, None)
+}
+
+/// Same as `run`, reloading the configuration when the file of `watcher` changes
+pub fn run_watched(
+    payments: &mut Payments,
+    input: impl BufRead,
+    mut output: impl Write,
+    mut watcher: Option<ConfigWatcher>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write!(output, "> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        if let Some(watcher) = &mut watcher {
+            reload(payments, watcher, &mut output)?;
+        }
+        match parse_command(&line) {
+            Ok(None) => {}
+            Ok(Some(Command::Apply(trans))) => match payments.apply(trans) {
+                Ok(()) => writeln!(output, "ok")?,
+                Err(error) => writeln!(output, "rejected: {}", error)?,
+            },
+            Ok(Some(Command::Balance(id))) => match payments.client(id) {
+                Some(client) => writeln!(
+                    output,
+                    "available: {}, held: {}, total: {}, locked: {}",
+                    client.available(),
+                    client.held(),
+                    client.total(),
+                    client.locked()
+                )?,
+                None => writeln!(output, "client `{}` not found", id)?,
+            },
+            Ok(Some(Command::Undo(count))) => {
+                writeln!(output, "undone {} transaction(s)", payments.rollback(count))?
+            }
+            Ok(Some(Command::Dump)) => payments.serialize(&mut output)?,
+            Ok(Some(Command::Help)) => writeln!(output, "{}", HELP)?,
+            Ok(Some(Command::Quit)) => return Ok(()),
+            Err(error) => writeln!(output, "{}", error)?,
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Apply the configuration of `watcher` if it changed, keeping the current one if it's invalid
+fn reload(
+    payments: &mut Payments,
+    watcher: &mut ConfigWatcher,
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    let path = watcher.path().display().to_string();
+    match watcher.reload(payments) {
+        Ok(true) => writeln!(output, "reloaded `{}`", path),
+        Ok(false) => Ok(()),
+        Err(error) => writeln!(output, "not reloading `{}`: {}", path, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{parse_command, reload, run, Command};
+    use crate::{
+        config::ConfigWatcher,
+        error::Error,
+        payments::Payments,
+        transaction::{Operation, OperationType, Transaction},
+    };
+
+    fn session(input: &str) -> String {
+        let mut output = Vec::<u8>::new();
+        run(&mut Payments::default(), input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse_command("deposit 1 100 5.0"),
+            Ok(Some(Command::Apply(Transaction {
+                client_id: 1,
+                timestamp: None,
+                batch: None,
+                tenant: None,
+                signature: None,
+                value_date: None,
+                seq: None,
+                op: Operation {
+                    id: 100,
+                    kind: OperationType::Deposit {
+                        amount: dec!(5.0),
+                        ref_tx: None,
+                        counterparty: None
+                    }
+                }
+            })))
+        );
+        assert_eq!(
+            parse_command("  dispute 1 100 "),
+            Ok(Some(Command::Apply(Transaction {
+                client_id: 1,
+                timestamp: None,
+                batch: None,
+                tenant: None,
+                signature: None,
+                value_date: None,
+                seq: None,
+                op: Operation {
+                    id: 100,
+                    kind: OperationType::Dispute
+                }
+            })))
+        );
+        assert_eq!(parse_command("balance 7"), Ok(Some(Command::Balance(7))));
+        assert_eq!(parse_command("undo"), Ok(Some(Command::Undo(1))));
+        assert_eq!(parse_command("undo 3"), Ok(Some(Command::Undo(3))));
+        assert_eq!(parse_command(""), Ok(None));
+        assert!(matches!(
+            parse_command("withdrawal 1 100"),
+            Err(Error::ParsingFailure(_))
+        ));
+        assert!(matches!(
+            parse_command("deposit x 100 1"),
+            Err(Error::ParsingFailure(_))
+        ));
+        assert!(matches!(
+            parse_command("transfer 1 2 3"),
+            Err(Error::ParsingFailure(_))
+        ));
+    }
+
+    #[test]
+    fn interactive_session() {
+        assert_eq!(
+            session(
+                "deposit 1 100 5.0\n\
+                 withdrawal 1 101 10\n\
+                 dispute 1 100\n\
+                 balance 1\n\
+                 balance 2\n\
+                 deposit 2 102 1\n\
+                 undo 5\n\
+                 dump\n\
+                 quit\n\
+                 deposit 1 102 1\n"
+            ),
+            [
+                "> ok",
+                "> rejected: withdrawal transaction ID `101` of 10 failed because of insufficient funds: 5.0",
+                "> ok",
+                "> available: 0.0, held: 5.0, total: 5.0, locked: false",
+                "> client `2` not found",
+                "> ok",
+                "> undone 3 transaction(s)",
+                // Nothing to dump, the clients were created by the undone transactions
+                "> > ",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn reloads_changed_config() {
+        let dir = std::env::temp_dir().join(format!("payments-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("payments.toml");
+        let mut watcher = ConfigWatcher::new(&path);
+        let mut payments = Payments::default();
+        let mut reloaded = |payments: &mut Payments| {
+            let mut output = Vec::<u8>::new();
+            reload(payments, &mut watcher, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(reloaded(&mut payments), "");
+
+        std::fs::write(&path, "[limits]\nmax_risk_score = 50.0\n").unwrap();
+        assert!(reloaded(&mut payments).starts_with("reloaded"));
+        assert_eq!(payments.config().max_risk_score, Some(50.0));
+        assert_eq!(reloaded(&mut payments), "");
+
+        // An invalid file keeps the settings in effect
+        std::fs::write(&path, "[limits]\nmax_risk_score = 500.0\n").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert!(reloaded(&mut payments).starts_with("not reloading"));
+        assert_eq!(payments.config().max_risk_score, Some(50.0));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Reserve requirements: the minimum balance a client has to keep, which withdrawals can't
+//! take the available funds of its main account below.
+//!
+//! Every client keeps the default reserve, unless the reserves file sets its own, e.g. the
+//! one of its tier:
+//!
+//! ```text
+//! client, reserve
+//! 1, 100.0
+//! 2, 0
+//! ```
+use std::{collections::HashMap, path::Path};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{client::ClientId, error::Error};
+
+#[derive(Debug, Deserialize)]
+struct ReserveRecord {
+    client: ClientId,
+    reserve: Decimal,
+}
+
+/// See the module documentation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reserves {
+    pub default: Decimal,
+    clients: HashMap<ClientId, Decimal>,
+}
+
+impl Reserves {
+    pub fn new(default: Decimal) -> Self {
+        Self {
+            default,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Read the reserves of the clients from the file at `path`
+    pub fn load(&mut self, path: &Path) -> Result<(), Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| Error::ParsingFailure(format!("{}: {}", path.display(), e)))?;
+        self.parse(rdr)
+    }
+
+    pub fn parse<R: std::io::Read>(&mut self, mut rdr: csv::Reader<R>) -> Result<(), Error> {
+        for record in rdr.deserialize() {
+            let ReserveRecord { client, reserve } =
+                record.map_err(|e| Error::ParsingFailure(e.to_string()))?;
+            if reserve.is_sign_negative() {
+                return Err(Error::ParsingFailure(format!(
+                    "invalid reserve of client {}: {}",
+                    client, reserve
+                )));
+            }
+            self.insert(client, reserve);
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, client: ClientId, reserve: Decimal) {
+        self.clients.insert(client, reserve);
+    }
+
+    /// The reserve `client` has to keep
+    pub fn reserve(&self, client: ClientId) -> Decimal {
+        self.clients.get(&client).copied().unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::Reserves;
+
+    #[test]
+    fn client_reserves_override_default() {
+        let mut reserves = Reserves::new(dec!(10));
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("client, reserve\n1, 100.0\n2, 0".as_bytes());
+        reserves.parse(rdr).unwrap();
+        assert_eq!(reserves.reserve(1), dec!(100));
+        assert_eq!(reserves.reserve(2), dec!(0));
+        assert_eq!(reserves.reserve(3), dec!(10));
+
+        let rdr = csv::Reader::from_reader("client,reserve\n1,-1".as_bytes());
+        assert!(reserves.parse(rdr).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Per-client risk scoring.
+//!
+//! The score is a number between 0 (no risk) and 100, a weighted sum of:
+//! - dispute rate: disputes per deposit/withdrawal (weight 0.3),
+//! - chargeback ratio: chargebacks per dispute (weight 0.4),
+//! - withdrawal velocity: withdrawals in the last 24 hours, saturating at 10 (weight 0.2),
+//! - amount outliers: share of amounts more than 3 standard deviations above
+//!   the client's running mean (weight 0.1).
+use std::collections::VecDeque;
+
+use chrono::Duration;
+use rust_decimal::prelude::ToPrimitive;
+
+use crate::{
+    event::Event,
+    transaction::{shift, Timestamp},
+};
+
+const DISPUTE_WEIGHT: f64 = 0.3;
+const CHARGEBACK_WEIGHT: f64 = 0.4;
+const VELOCITY_WEIGHT: f64 = 0.2;
+const OUTLIER_WEIGHT: f64 = 0.1;
+
+const VELOCITY_WINDOW_HOURS: i64 = 24;
+const VELOCITY_SATURATION: usize = 10;
+
+const OUTLIER_MIN_SAMPLES: u32 = 5;
+const OUTLIER_STDDEVS: f64 = 3.0;
+
+/// Statistics of a client's activity, folded from its events
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RiskProfile {
+    transactions: u32,
+    disputes: u32,
+    chargebacks: u32,
+    outliers: u32,
+    /// Running mean and sum of squared differences of amounts (Welford's algorithm)
+    mean: f64,
+    m2: f64,
+    /// Timestamps of withdrawals within the velocity window
+    recent_withdrawals: VecDeque<Timestamp>,
+}
+
+impl RiskProfile {
+    pub fn observe(&mut self, event: &Event, timestamp: Option<Timestamp>) {
+        match *event {
+            Event::FundsDeposited { amount, .. } => self.observe_amount(amount.to_f64()),
+            Event::FundsWithdrawn { amount, .. } => {
+                self.observe_amount(amount.to_f64());
+                if let Some(timestamp) = timestamp {
+                    self.recent_withdrawals.push_back(timestamp);
+                    self.expire_withdrawals(timestamp);
+                }
+            }
+            Event::FundsHeld { .. } => self.disputes += 1,
+            Event::FundsChargedBack { .. } => self.chargebacks += 1,
+            Event::FundsReleased { .. }
+            | Event::AccountLocked { .. }
+            | Event::AccountUnlocked
+            | Event::AccountClosed
+            | Event::WithdrawalReversed { .. }
+            | Event::WithdrawalWrittenOff { .. }
+            | Event::BalanceWrittenOff { .. }
+            | Event::FeeCharged { .. }
+            | Event::FeeRefunded { .. }
+            | Event::TransferReversed { .. }
+            | Event::TransferWrittenOff { .. }
+            | Event::InterestPaid { .. }
+            | Event::FundsTransferred { .. }
+            | Event::TransactionAmended { .. }
+            | Event::FundsPending { .. }
+            | Event::FundsCleared { .. }
+            | Event::ClientQuarantined { .. }
+            | Event::QuarantineLifted
+            | Event::TransactionParked { .. }
+            | Event::TransactionReleased { .. }
+            | Event::ClientCreated
+            | Event::ApprovalRequested { .. }
+            | Event::ActionApproved { .. } => {}
+        }
+    }
+
+    fn observe_amount(&mut self, amount: Option<f64>) {
+        let amount = match amount {
+            Some(amount) => amount,
+            None => return,
+        };
+        if self.transactions >= OUTLIER_MIN_SAMPLES {
+            let stddev = (self.m2 / f64::from(self.transactions)).sqrt();
+            if amount > self.mean + OUTLIER_STDDEVS * stddev {
+                self.outliers += 1;
+            }
+        }
+        self.transactions += 1;
+        let delta = amount - self.mean;
+        self.mean += delta / f64::from(self.transactions);
+        self.m2 += delta * (amount - self.mean);
+    }
+
+    fn expire_withdrawals(&mut self, now: Timestamp) {
+        let window_start = shift(now, -Duration::hours(VELOCITY_WINDOW_HOURS));
+        while matches!(self.recent_withdrawals.front(), Some(&t) if t <= window_start) {
+            self.recent_withdrawals.pop_front();
+        }
+    }
+
+    /// The risk score as of `now` (which only matters for withdrawal velocity)
+    pub fn score(&self, now: Option<Timestamp>) -> f64 {
+        let ratio = |count: u32, total: u32| f64::from(count) / f64::from(total.max(1));
+        let recent_withdrawals = match now {
+            Some(now) => {
+                let window_start = shift(now, -Duration::hours(VELOCITY_WINDOW_HOURS));
+                self.recent_withdrawals
+                    .iter()
+                    .filter(|&&t| t > window_start)
+                    .count()
+            }
+            None => 0,
+        };
+        let velocity =
+            recent_withdrawals.min(VELOCITY_SATURATION) as f64 / VELOCITY_SATURATION as f64;
+
+        let score = DISPUTE_WEIGHT * ratio(self.disputes, self.transactions).min(1.0)
+            + CHARGEBACK_WEIGHT * ratio(self.chargebacks, self.disputes)
+            + VELOCITY_WEIGHT * velocity
+            + OUTLIER_WEIGHT * ratio(self.outliers, self.transactions);
+        (score * 100.0 * 100.0).round() / 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    use super::RiskProfile;
+    use crate::event::Event;
+
+    fn deposit(tx: u32, amount: rust_decimal::Decimal) -> Event {
+        Event::FundsDeposited {
+            tx,
+            amount,
+            ref_tx: None,
+            counterparty: None,
+        }
+    }
+
+    #[test]
+    fn no_activity_no_risk() {
+        assert_eq!(RiskProfile::default().score(None), 0.0);
+    }
+
+    #[test]
+    fn disputes_and_chargebacks() {
+        let mut profile = RiskProfile::default();
+        profile.observe(&deposit(1, dec!(10)), None);
+        profile.observe(&deposit(2, dec!(10)), None);
+        profile.observe(
+            &Event::FundsHeld {
+                tx: 1,
+                amount: dec!(10),
+                pending: false,
+            },
+            None,
+        );
+        // 1 dispute per 2 transactions
+        assert_eq!(profile.score(None), 15.0);
+
+        profile.observe(
+            &Event::FundsChargedBack {
+                tx: 1,
+                amount: dec!(10),
+            },
+            None,
+        );
+        // ... and every dispute charged back
+        assert_eq!(profile.score(None), 55.0);
+    }
+
+    #[test]
+    fn withdrawal_velocity() {
+        let mut profile = RiskProfile::default();
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        for i in 0..5 {
+            let event = Event::FundsWithdrawn {
+                tx: i,
+                amount: dec!(1),
+                ref_tx: None,
+                counterparty: None,
+            };
+            profile.observe(&event, Some(start + Duration::hours(i.into())));
+        }
+        assert_eq!(profile.score(Some(start + Duration::hours(4))), 10.0);
+        // Only the last withdrawal is within the window
+        assert_eq!(profile.score(Some(start + Duration::hours(27))), 2.0);
+    }
+
+    #[test]
+    fn amount_outliers() {
+        let mut profile = RiskProfile::default();
+        for tx in 0..9 {
+            profile.observe(&deposit(tx, dec!(10)), None);
+        }
+        profile.observe(&deposit(9, dec!(1000)), None);
+        assert_eq!(profile.score(None), 1.0);
+    }
+}
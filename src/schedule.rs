@@ -0,0 +1,262 @@
+//! Standing orders: recurring deposits and withdrawals from a schedule file, materialized
+//! into the stream of timestamped transactions as its time passes their occurrences.
+//!
+//! ```text
+//! type, client, amount, cadence, start, end
+//! deposit, 1, 100.0, monthly, 2024-03-01T00:00:00Z,
+//! withdrawal, 2, 5.0, weekly, 2024-03-04T09:00:00Z, 2024-06-30T00:00:00Z
+//! ```
+//!
+//! Occurrences are due at `start` and every `cadence` (`daily`, `weekly` or `monthly`) after,
+//! up to `end` if given. They get transaction IDs counting up from `FIRST_TX`, in the order
+//! they're materialized, so the same input always yields the same IDs.
+use std::{path::Path, str::FromStr};
+
+use chrono::{Duration, Months};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    client::ClientId,
+    error::Error,
+    transaction::{Timestamp, Transaction, TransactionId},
+};
+
+/// Transaction IDs from this one up are taken by scheduled transactions
+pub const FIRST_TX: TransactionId = 4_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cadence {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl FromStr for Cadence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Cadence::Daily),
+            "weekly" => Ok(Cadence::Weekly),
+            "monthly" => Ok(Cadence::Monthly),
+            other => Err(format!(
+                "unknown cadence `{}`, expected `daily`, `weekly` or `monthly`",
+                other
+            )),
+        }
+    }
+}
+
+impl Cadence {
+    /// The `n`th occurrence after `start`, counted from the start so monthly ones don't
+    /// drift after short months
+    fn nth(self, start: Timestamp, n: u32) -> Option<Timestamp> {
+        match self {
+            Cadence::Daily => start.checked_add_signed(Duration::days(n.into())),
+            Cadence::Weekly => start.checked_add_signed(Duration::weeks(n.into())),
+            Cadence::Monthly => start.checked_add_months(Months::new(n)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StandingOrderRecord {
+    #[serde(rename = "type")]
+    kind: String,
+    client: ClientId,
+    amount: Decimal,
+    cadence: String,
+    start: Timestamp,
+    #[serde(default)]
+    end: Option<Timestamp>,
+}
+
+/// A recurring deposit or withdrawal
+#[derive(Debug, Clone, PartialEq)]
+pub struct StandingOrder {
+    /// `deposit` or `withdrawal`
+    pub kind: String,
+    pub client: ClientId,
+    pub amount: Decimal,
+    pub cadence: Cadence,
+    pub start: Timestamp,
+    pub end: Option<Timestamp>,
+}
+
+/// The standing orders and their next occurrences
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    /// The orders with the number of their next occurrence
+    orders: Vec<(StandingOrder, u32)>,
+    next_tx: TransactionId,
+}
+
+impl StandingOrder {
+    /// An occurrence of the order as transaction `tx`, failing unless it's a deposit or a
+    /// withdrawal of a valid amount
+    fn transaction(&self, tx: TransactionId) -> Result<Transaction, Error> {
+        if self.amount <= Decimal::ZERO {
+            return Err(Error::ParsingFailure(format!(
+                "invalid standing order amount: {}",
+                self.amount
+            )));
+        }
+        match self.kind.as_str() {
+            "deposit" => Transaction::deposit(self.client, tx, self.amount),
+            "withdrawal" => Transaction::withdrawal(self.client, tx, self.amount),
+            other => Err(Error::ParsingFailure(format!(
+                "standing orders are deposits or withdrawals, not `{}`",
+                other
+            ))),
+        }
+    }
+}
+
+impl Schedule {
+    /// Fails on an order which isn't a deposit or a withdrawal of a valid amount
+    pub fn new(orders: Vec<StandingOrder>) -> Result<Self, Error> {
+        for order in &orders {
+            order.transaction(FIRST_TX)?;
+        }
+        Ok(Self {
+            orders: orders.into_iter().map(|order| (order, 0)).collect(),
+            next_tx: FIRST_TX,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| Error::ParsingFailure(format!("{}: {}", path.display(), e)))?;
+        Self::parse(rdr)
+    }
+
+    pub fn parse<R: std::io::Read>(mut rdr: csv::Reader<R>) -> Result<Self, Error> {
+        let orders = rdr
+            .deserialize()
+            .map(|record| {
+                let record: StandingOrderRecord =
+                    record.map_err(|e| Error::ParsingFailure(e.to_string()))?;
+                Ok(StandingOrder {
+                    kind: record.kind,
+                    client: record.client,
+                    amount: record.amount,
+                    cadence: record.cadence.parse().map_err(Error::ParsingFailure)?,
+                    start: record.start,
+                    end: record.end,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Self::new(orders)
+    }
+
+    /// The next occurrence of every order, unless it ended
+    fn next(&self) -> impl Iterator<Item = (usize, Timestamp)> + '_ {
+        self.orders
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (order, n))| {
+                let at = order.cadence.nth(order.start, *n)?;
+                order.end.is_none_or(|end| at <= end).then_some((i, at))
+            })
+    }
+
+    /// Take the occurrences due by `now`, in time order (and the order of the file at the
+    /// same time)
+    pub fn due(&mut self, now: Timestamp) -> Vec<Transaction> {
+        let mut due = Vec::new();
+        while let Some((i, at)) = self
+            .next()
+            .filter(|(_, at)| *at <= now)
+            .min_by_key(|&(i, at)| (at, i))
+        {
+            let (order, n) = &mut self.orders[i];
+            *n += 1;
+            let scheduled = order.transaction(self.next_tx);
+            self.next_tx = self.next_tx.wrapping_add(1);
+            // Validated by `new`
+            if let Ok(scheduled) = scheduled {
+                due.push(scheduled.with_timestamp(at));
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use rust_decimal_macros::dec;
+
+    use super::{Cadence, Schedule, StandingOrder, FIRST_TX};
+    use crate::transaction::OperationType;
+
+    fn schedule() -> Schedule {
+        let input = "type, client, amount, cadence, start, end
+            deposit, 1, 100, monthly, 2024-01-31T00:00:00Z,
+            withdrawal, 2, 5, weekly, 2024-02-01T00:00:00Z, 2024-02-15T00:00:00Z";
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        Schedule::parse(rdr).unwrap()
+    }
+
+    #[test]
+    fn occurrences_in_time_order() {
+        let mut schedule = schedule();
+        let at = |m, d| Utc.with_ymd_and_hms(2024, m, d, 0, 0, 0).unwrap();
+        assert!(schedule.due(at(1, 30)).is_empty());
+        let due = schedule.due(at(3, 31));
+        let occurrences = due
+            .iter()
+            .map(|t| (t.op.id - FIRST_TX, t.client_id, t.timestamp.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            occurrences,
+            [
+                (0, 1, at(1, 31)),
+                (1, 2, at(2, 1)),
+                (2, 2, at(2, 8)),
+                (3, 2, at(2, 15)),
+                (4, 1, at(2, 29)),
+                (5, 1, at(3, 31)),
+            ]
+        );
+        assert!(matches!(due[1].op.kind, OperationType::Withdrawal { .. }));
+        // Taken once
+        assert!(schedule.due(at(3, 31)).is_empty());
+        assert_eq!(schedule.due(at(4, 30)).len(), 1);
+    }
+
+    #[test]
+    fn invalid_orders() {
+        for input in [
+            "type,client,amount,cadence,start\ndispute,1,1,daily,2024-01-01T00:00:00Z",
+            "type,client,amount,cadence,start\ndeposit,1,-1,daily,2024-01-01T00:00:00Z",
+            "type,client,amount,cadence,start\ndeposit,1,1,hourly,2024-01-01T00:00:00Z",
+            "type,client,amount,cadence,start\ndeposit,1,1,daily,yesterday",
+        ] {
+            let rdr = csv::Reader::from_reader(input.as_bytes());
+            assert!(Schedule::parse(rdr).is_err(), "{}", input);
+        }
+        // Orders built in code are validated as well
+        let order = StandingOrder {
+            kind: "deposit".to_string(),
+            client: 1,
+            amount: dec!(1.00001),
+            cadence: Cadence::Daily,
+            start: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            end: None,
+        };
+        assert!(Schedule::new(vec![order.clone()]).is_err());
+        let order = StandingOrder {
+            kind: "settle".to_string(),
+            amount: dec!(1),
+            ..order
+        };
+        assert!(Schedule::new(vec![order]).is_err());
+    }
+}
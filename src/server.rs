@@ -0,0 +1,258 @@
+//! A minimal HTTP/1.1 server for the operational endpoints of the daemon mode.
+//!
+//! Every connection is handled on its own thread, up to `MAX_CONNECTIONS` at once, so a slow
+//! request (e.g. an admin action waiting for the ingestion loop) doesn't hold up probes and
+//! metrics scrapes. A connection serves one request (`Connection: close`). That's plenty for
+//! operational endpoints, and keeps the server free of dependencies. Request bodies are read
+//! up to `MAX_BODY` bytes.
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+const MAX_BODY: usize = 64 << 10;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections handled at once, more are answered 503 right away
+const MAX_CONNECTIONS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Request {
+    pub method: String,
+    /// The path without the query string
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Header names are lowercase
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "not found\n")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            422 => "Unprocessable Entity",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+
+    fn write_to(&self, mut output: impl Write) -> std::io::Result<()> {
+        write!(
+            output,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len(),
+            self.body
+        )?;
+        output.flush()
+    }
+}
+
+/// `a=1&b=2`, without percent-decoding
+fn query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (pair.to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// Read a request, `None` if it's malformed
+fn read_request(input: impl Read) -> std::io::Result<Option<Request>> {
+    let mut input = BufReader::new(input);
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Ok(None),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, q)) => (path.to_string(), self::query(q)),
+        None => (target.to_string(), HashMap::new()),
+    };
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        return Ok(None);
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    }))
+}
+
+fn handle(stream: TcpStream, handler: &impl Fn(&Request) -> Response) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let response = match read_request(&stream)? {
+        Some(request) => handler(&request),
+        None => Response::text(400, "bad request\n"),
+    };
+    response.write_to(&stream)
+}
+
+/// Serve requests on `addr` with `handler` on a background thread, returning the bound
+/// address, e.g. to find the port when binding port 0
+pub fn spawn(
+    addr: &str,
+    handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    let handler = Arc::new(handler);
+    let connections = Arc::new(AtomicUsize::new(0));
+    let thread = std::thread::spawn(move || {
+        // A broken connection only affects its client
+        for stream in listener.incoming().flatten() {
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let _ = Response::text(503, "busy, try again\n").write_to(&stream);
+                continue;
+            }
+            let handler = handler.clone();
+            let connections = connections.clone();
+            std::thread::spawn(move || {
+                let _ = handle(stream, handler.as_ref());
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok((local, thread))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::{read_request, spawn, Response};
+
+    #[test]
+    fn parse_request() {
+        let request = read_request(
+            "POST /admin/unlock?client=7&force HTTP/1.1\r\nAuthorization: Bearer x\r\nContent-Length: 2\r\n\r\nhi"
+                .as_bytes(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/admin/unlock");
+        assert_eq!(request.query["client"], "7");
+        assert_eq!(request.query["force"], "");
+        assert_eq!(request.headers["authorization"], "Bearer x");
+        assert_eq!(request.body, b"hi");
+        assert_eq!(read_request("\r\n".as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let (addr, _) = spawn("127.0.0.1:0", |request| match request.path.as_str() {
+            "/hello" => Response::text(200, "hello\n"),
+            _ => Response::not_found(),
+        })
+        .unwrap();
+        let get = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let hello = get("/hello");
+        assert!(hello.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(hello.ends_with("\r\n\r\nhello\n"));
+        assert!(get("/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn slow_requests_dont_block_others() {
+        let (addr, _) = spawn("127.0.0.1:0", |request| match request.path.as_str() {
+            "/slow" => {
+                std::thread::sleep(std::time::Duration::from_secs(2));
+                Response::text(200, "slow\n")
+            }
+            _ => Response::text(200, "fast\n"),
+        })
+        .unwrap();
+        let get = move |path: &str| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let slow = std::thread::spawn(move || get("/slow"));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let start = std::time::Instant::now();
+        assert!(get("/healthz").ends_with("fast\n"));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        assert!(slow.join().unwrap().ends_with("slow\n"));
+    }
+}
@@ -0,0 +1,336 @@
+//! Settlement of the net positions with counterparties: the funds of deposits from a
+//! counterparty are collected from it and those of withdrawals to it are paid to it, so one
+//! with more deposited than withdrawn owes the difference (a collection), and one with more
+//! withdrawn is owed it (a payment).
+//!
+//! Instructions are written as CSV, or as an ISO 20022 `pain.001.001.03` credit transfer
+//! initiation of the payments for the bank. Collections aren't part of the latter, being
+//! initiated by the counterparties. It needs the bank accounts of the counterparties, a CSV
+//! file of:
+//!
+//! ```text
+//! counterparty, name, iban, bic
+//! bank-a, Bank A Ltd, GB33BUKB20201555555555, BUKBGB22
+//! ```
+use std::{collections::HashMap, fmt::Write as _, io::Write, path::Path, str::FromStr};
+
+use chrono::NaiveDate;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+
+use crate::{counterparty::Counterparty, error::Error, fx::Currency, transaction::Timestamp};
+
+/// Payments are in cents
+const DECIMAL_PLACES: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettlementFormat {
+    Csv,
+    Pain001,
+}
+
+impl FromStr for SettlementFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(SettlementFormat::Csv),
+            "pain001" => Ok(SettlementFormat::Pain001),
+            other => Err(format!(
+                "unknown settlement format `{}`, expected `csv` or `pain001`",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The counterparty is paid
+    Pay,
+    /// The counterparty pays
+    Collect,
+}
+
+/// What to settle with a counterparty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Instruction {
+    pub counterparty: Counterparty,
+    pub direction: Direction,
+    pub amount: Decimal,
+}
+
+/// Instructions settling the net positions, see `Payments::net_positions`. Settled
+/// positions are left out.
+pub fn instructions(
+    positions: impl IntoIterator<Item = (Counterparty, Decimal)>,
+) -> Vec<Instruction> {
+    positions
+        .into_iter()
+        .filter(|(_, net)| !net.is_zero())
+        .map(|(counterparty, net)| Instruction {
+            counterparty,
+            direction: match net.is_sign_positive() {
+                true => Direction::Collect,
+                false => Direction::Pay,
+            },
+            amount: net.abs(),
+        })
+        .collect()
+}
+
+pub fn write_csv(
+    instructions: &[Instruction],
+    output: impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(output);
+    for instruction in instructions {
+        writer.serialize(instruction)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BankAccount {
+    pub name: String,
+    pub iban: String,
+    #[serde(default)]
+    pub bic: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BankAccountRecord {
+    counterparty: Counterparty,
+    #[serde(flatten)]
+    account: BankAccount,
+}
+
+/// Bank accounts of the counterparties, see the module documentation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BankAccounts {
+    accounts: HashMap<Counterparty, BankAccount>,
+}
+
+impl BankAccounts {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| Error::ParsingFailure(format!("{}: {}", path.display(), e)))?;
+        Self::parse(rdr)
+    }
+
+    pub fn parse<R: std::io::Read>(mut rdr: csv::Reader<R>) -> Result<Self, Error> {
+        let mut accounts = Self::default();
+        for record in rdr.deserialize() {
+            let BankAccountRecord {
+                counterparty,
+                account,
+            } = record.map_err(|e| Error::ParsingFailure(e.to_string()))?;
+            accounts.insert(counterparty, account);
+        }
+        Ok(accounts)
+    }
+
+    pub fn insert(&mut self, counterparty: Counterparty, account: BankAccount) {
+        self.accounts.insert(counterparty, account);
+    }
+
+    fn get(&self, counterparty: Counterparty) -> Result<&BankAccount, Error> {
+        self.accounts
+            .get(&counterparty)
+            .ok_or(Error::BankAccountNotFound(counterparty))
+    }
+}
+
+/// Parameters of a pain.001 message
+#[derive(Debug, Clone)]
+pub struct Pain001<'a> {
+    /// The account paying, of the clients' funds
+    pub debtor: BankAccount,
+    pub currency: Currency,
+    pub accounts: &'a BankAccounts,
+    pub created: Timestamp,
+    pub execution_date: NaiveDate,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// The financial institution element of an optional BIC
+fn agent(bic: Option<&str>) -> String {
+    match bic.filter(|bic| !bic.is_empty()) {
+        Some(bic) => format!("<FinInstnId><BIC>{}</BIC></FinInstnId>", escape(bic)),
+        None => "<FinInstnId><Othr><Id>NOTPROVIDED</Id></Othr></FinInstnId>".to_string(),
+    }
+}
+
+/// Write the payments of `instructions` as a pain.001 credit transfer initiation, rounded
+/// to cents, half away from zero
+pub fn write_pain001(
+    instructions: &[Instruction],
+    message: &Pain001,
+    mut output: impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let message_id = format!("SETTLE-{}", message.created.format("%Y%m%d%H%M%S"));
+    let payments = instructions
+        .iter()
+        .filter(|instruction| instruction.direction == Direction::Pay)
+        .map(|instruction| {
+            let amount = instruction
+                .amount
+                .round_dp_with_strategy(DECIMAL_PLACES, RoundingStrategy::MidpointAwayFromZero);
+            Ok((
+                instruction,
+                amount,
+                message.accounts.get(instruction.counterparty)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let total = payments
+        .iter()
+        .map(|(_, amount, _)| amount)
+        .sum::<Decimal>();
+
+    let mut transfers = String::new();
+    for (n, (instruction, amount, account)) in payments.iter().enumerate() {
+        let _ = write!(
+            transfers,
+            "      <CdtTrfTxInf>\n\
+             \x20       <PmtId><EndToEndId>{id}-{n}</EndToEndId></PmtId>\n\
+             \x20       <Amt><InstdAmt Ccy=\"{ccy}\">{amount:.2}</InstdAmt></Amt>\n\
+             \x20       <CdtrAgt>{agent}</CdtrAgt>\n\
+             \x20       <Cdtr><Nm>{name}</Nm></Cdtr>\n\
+             \x20       <CdtrAcct><Id><IBAN>{iban}</IBAN></Id></CdtrAcct>\n\
+             \x20       <RmtInf><Ustrd>Settlement {counterparty}</Ustrd></RmtInf>\n\
+             \x20     </CdtTrfTxInf>\n",
+            id = message_id,
+            n = n + 1,
+            ccy = escape(message.currency.as_str()),
+            amount = amount,
+            agent = agent(account.bic.as_deref()),
+            name = escape(&account.name),
+            iban = escape(&account.iban),
+            counterparty = instruction.counterparty,
+        );
+    }
+    let debtor = &message.debtor;
+    write!(
+        output,
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:pain.001.001.03\">\n\
+         \x20 <CstmrCdtTrfInitn>\n\
+         \x20   <GrpHdr>\n\
+         \x20     <MsgId>{id}</MsgId>\n\
+         \x20     <CreDtTm>{created}</CreDtTm>\n\
+         \x20     <NbOfTxs>{count}</NbOfTxs>\n\
+         \x20     <CtrlSum>{total:.2}</CtrlSum>\n\
+         \x20     <InitgPty><Nm>{name}</Nm></InitgPty>\n\
+         \x20   </GrpHdr>\n\
+         \x20   <PmtInf>\n\
+         \x20     <PmtInfId>{id}</PmtInfId>\n\
+         \x20     <PmtMtd>TRF</PmtMtd>\n\
+         \x20     <NbOfTxs>{count}</NbOfTxs>\n\
+         \x20     <CtrlSum>{total:.2}</CtrlSum>\n\
+         \x20     <ReqdExctnDt>{date}</ReqdExctnDt>\n\
+         \x20     <Dbtr><Nm>{name}</Nm></Dbtr>\n\
+         \x20     <DbtrAcct><Id><IBAN>{iban}</IBAN></Id></DbtrAcct>\n\
+         \x20     <DbtrAgt>{agent}</DbtrAgt>\n\
+         {transfers}\
+         \x20   </PmtInf>\n\
+         \x20 </CstmrCdtTrfInitn>\n\
+         </Document>\n",
+        id = message_id,
+        created = message.created.format("%Y-%m-%dT%H:%M:%S"),
+        count = payments.len(),
+        total = total,
+        name = escape(&debtor.name),
+        date = message.execution_date,
+        iban = escape(&debtor.iban),
+        agent = agent(debtor.bic.as_deref()),
+        transfers = transfers,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    use super::{
+        instructions, write_pain001, BankAccount, BankAccounts, Direction, Instruction, Pain001,
+    };
+    use crate::{error::Error, fx::Currency};
+
+    #[test]
+    fn net_positions_to_instructions() {
+        let positions = [
+            ("a".parse().unwrap(), dec!(10)),
+            ("b".parse().unwrap(), dec!(0)),
+            ("c".parse().unwrap(), dec!(-2.5)),
+        ];
+        assert_eq!(
+            instructions(positions),
+            [
+                Instruction {
+                    counterparty: "a".parse().unwrap(),
+                    direction: Direction::Collect,
+                    amount: dec!(10),
+                },
+                Instruction {
+                    counterparty: "c".parse().unwrap(),
+                    direction: Direction::Pay,
+                    amount: dec!(2.5),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pain001_of_payments() {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("counterparty, name, iban, bic\na, A & Co, GB11A, AAAAGB22".as_bytes());
+        let accounts = BankAccounts::parse(rdr).unwrap();
+        let message = Pain001 {
+            debtor: BankAccount {
+                name: "Payments".to_string(),
+                iban: "GB00X".to_string(),
+                bic: None,
+            },
+            currency: Currency::EUR,
+            accounts: &accounts,
+            created: Utc.with_ymd_and_hms(2024, 3, 31, 12, 0, 0).unwrap(),
+            execution_date: NaiveDate::from_ymd_opt(2024, 4, 1).unwrap(),
+        };
+        let settle = [
+            ("a".parse().unwrap(), dec!(-10.125)),
+            ("c".parse().unwrap(), dec!(2)),
+        ];
+        let mut xml = Vec::new();
+        write_pain001(&instructions(settle), &message, &mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        // The collection from `c` isn't a credit transfer
+        assert_eq!(xml.matches("<CdtTrfTxInf>").count(), 1);
+        assert!(xml.contains("<CtrlSum>10.13</CtrlSum>"));
+        assert!(xml.contains("<InstdAmt Ccy=\"EUR\">10.13</InstdAmt>"));
+        assert!(xml.contains("<Cdtr><Nm>A &amp; Co</Nm></Cdtr>"));
+        assert!(xml.contains("<EndToEndId>SETTLE-20240331120000-1</EndToEndId>"));
+        assert!(xml.contains("<ReqdExctnDt>2024-04-01</ReqdExctnDt>"));
+
+        let unknown = [("d".parse().unwrap(), dec!(-1))];
+        let error = write_pain001(&instructions(unknown), &message, Vec::new()).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&Error::BankAccountNotFound("d".parse().unwrap()))
+        );
+    }
+}
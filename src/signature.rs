@@ -0,0 +1,251 @@
+//! Verification of signed input rows.
+//!
+//! A signature is an HMAC-SHA256, keyed with a secret shared with the producer of the input,
+//! over the canonical form of the row: its `type, client, tx, amount, timestamp, batch,
+//! ref_tx, tenant` fields separated by commas, absent fields empty, for a transfer its
+//! `from_account, to_account` after them (`main` for the main account), and for a deposit or
+//! withdrawal with a counterparty, the `counterparty` after them, and for another row than a
+//! transfer with a value date, the `counterparty` (possibly empty) and `value_date` after
+//! them. Fields are canonical as parsed, so formatting of the input doesn't matter: amounts
+//! are written without trailing zeros (`1.5`, not `1.50`) and timestamps in UTC
+//! (`2024-03-31T12:00:00Z`). The `seq` column isn't signed, see `ordering`.
+use std::{fmt, path::Path};
+
+use chrono::SecondsFormat;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    error::Error,
+    parser::parse,
+    subaccount::MAIN,
+    transaction::{OperationType, Transaction},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The secret signatures are made with
+#[derive(Clone, PartialEq, Eq)]
+pub struct SigningKey(Vec<u8>);
+
+impl SigningKey {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self(key.into())
+    }
+
+    /// The key in the file at `path`, without the line break the file may end with
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut key = std::fs::read(path)?;
+        while matches!(key.last(), Some(b'\n' | b'\r')) {
+            key.pop();
+        }
+        if key.is_empty() {
+            return Err(format!("signing key file `{}` is empty", path.display()).into());
+        }
+        Ok(Self(key))
+    }
+
+    /// HMAC accepts keys of any length, so this doesn't fail in practice
+    fn mac(&self, transaction: &Transaction) -> Result<HmacSha256, Error> {
+        let mut mac = HmacSha256::new_from_slice(&self.0)
+            .map_err(|_| Error::InvalidSignature(transaction.op.id))?;
+        mac.update(canonical(transaction).as_bytes());
+        Ok(mac)
+    }
+
+    /// The signature of `transaction`
+    pub fn sign(&self, transaction: &Transaction) -> Result<Vec<u8>, Error> {
+        Ok(self.mac(transaction)?.finalize().into_bytes().to_vec())
+    }
+
+    /// Fails unless `transaction` carries its valid signature
+    pub fn verify(&self, transaction: &Transaction) -> Result<(), Error> {
+        let signature = transaction
+            .signature
+            .as_deref()
+            .ok_or(Error::InvalidSignature(transaction.op.id))?;
+        // Constant time comparison
+        self.mac(transaction)?
+            .verify_slice(signature)
+            .map_err(|_| Error::InvalidSignature(transaction.op.id))
+    }
+}
+
+/// Keeps the key out of logs
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+/// The signed form of a transaction, see the module documentation
+pub fn canonical(transaction: &Transaction) -> String {
+    let (kind, amount, ref_tx) = match &transaction.op.kind {
+        OperationType::Deposit { amount, ref_tx, .. } => ("deposit", Some(amount), ref_tx.as_ref()),
+        OperationType::Withdrawal { amount, ref_tx, .. } => {
+            ("withdrawal", Some(amount), ref_tx.as_ref())
+        }
+        OperationType::Dispute => ("dispute", None, None),
+        OperationType::Resolve => ("resolve", None, None),
+        OperationType::Chargeback => ("chargeback", None, None),
+        OperationType::Transfer { amount, .. } => ("transfer", Some(amount), None),
+        OperationType::Amend { amount } => ("amend", Some(amount), None),
+        OperationType::Settle => ("settle", None, None),
+    };
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let mut fields = vec![
+        kind.to_string(),
+        transaction.client_id.to_string(),
+        transaction.op.id.to_string(),
+        optional(amount.map(|amount| amount.normalize().to_string())),
+        optional(
+            transaction
+                .timestamp
+                .map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        ),
+        optional(transaction.batch.map(|batch| batch.to_string())),
+        optional(ref_tx.map(|ref_tx| ref_tx.to_string())),
+        optional(transaction.tenant.clone()),
+    ];
+    if let OperationType::Transfer { from, to, .. } = transaction.op.kind {
+        fields
+            .extend([from, to].map(|account| account.map_or(MAIN.to_string(), |a| a.to_string())));
+    }
+    if let OperationType::Deposit {
+        counterparty: Some(counterparty),
+        ..
+    }
+    | OperationType::Withdrawal {
+        counterparty: Some(counterparty),
+        ..
+    } = transaction.op.kind
+    {
+        fields.push(counterparty.to_string());
+    }
+    let transfer = matches!(transaction.op.kind, OperationType::Transfer { .. });
+    if let (Some(value_date), false) = (transaction.value_date, transfer) {
+        if fields.len() == 8 {
+            fields.push(String::new());
+        }
+        fields.push(value_date.to_string());
+    }
+    fields.join(",")
+}
+
+/// The transaction of its canonical form, e.g. as recorded by `txlog`
+pub fn from_canonical(form: &str) -> Result<Transaction, Error> {
+    let trailing = match form.starts_with("transfer,") {
+        true => "from_account,to_account",
+        false => "counterparty,value_date",
+    };
+    let input = format!(
+        "type,client,tx,amount,timestamp,batch,ref_tx,tenant,{}\n{}",
+        trailing, form
+    );
+    let rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let transaction = parse(rdr).next();
+    transaction.unwrap_or_else(|| Err(Error::ParsingFailure("empty canonical form".into())))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Serde of signatures as hex, as in the `signature` column
+pub(crate) mod hex_signature {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        signature: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match signature {
+            Some(signature) => serializer.serialize_some(&super::to_hex(signature)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|hex| super::from_hex(&hex).ok_or_else(|| serde::de::Error::custom("invalid hex")))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical, from_canonical, from_hex, to_hex, SigningKey};
+    use crate::{error::Error, parser::parse, transaction::Transaction};
+
+    fn parsed(input: &str) -> Vec<Transaction> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        parse(rdr).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn canonical_form() {
+        let transactions = parsed(
+            "type, client, tx, amount, timestamp, batch, tenant, counterparty
+            deposit, 1, 2, 1.50, 2024-03-31T14:00:00+02:00, 7, brand-a,
+            dispute, 1, 2, , , , ,
+            withdrawal, 1, 3, 1, , , , bank-a",
+        );
+        assert_eq!(
+            canonical(&transactions[0]),
+            "deposit,1,2,1.5,2024-03-31T12:00:00Z,7,,brand-a"
+        );
+        assert_eq!(canonical(&transactions[1]), "dispute,1,2,,,,,");
+        assert_eq!(canonical(&transactions[2]), "withdrawal,1,3,1,,,,,bank-a");
+        let deposit = Transaction::deposit(1, 5, 3.into())
+            .unwrap()
+            .with_value_date("2024-04-02".parse().unwrap());
+        assert_eq!(canonical(&deposit), "deposit,1,5,3,,,,,,2024-04-02");
+        for transaction in transactions.into_iter().chain([deposit]) {
+            assert_eq!(from_canonical(&canonical(&transaction)), Ok(transaction));
+        }
+        let transfer =
+            Transaction::transfer(1, 4, 2.into(), None, Some("savings".parse().unwrap()));
+        let transfer = transfer.unwrap();
+        assert_eq!(from_canonical(&canonical(&transfer)), Ok(transfer));
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SigningKey::new("secret");
+        let mut transaction = parsed("type, client, tx, amount\ndeposit, 1, 2, 1.5").remove(0);
+        assert_eq!(key.verify(&transaction), Err(Error::InvalidSignature(2)));
+
+        transaction.signature = Some(key.sign(&transaction).unwrap());
+        assert_eq!(key.verify(&transaction), Ok(()));
+        assert_eq!(
+            SigningKey::new("other").verify(&transaction),
+            Err(Error::InvalidSignature(2))
+        );
+        transaction.client_id = 3;
+        assert_eq!(key.verify(&transaction), Err(Error::InvalidSignature(2)));
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00AB10"), Some(vec![0, 0xab, 0x10]));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("abc"), None);
+    }
+}
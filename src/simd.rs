@@ -0,0 +1,286 @@
+//! SIMD-accelerated tokenizer for plain (unquoted) transaction CSV.
+//!
+//! Line breaks and separators are located with `memchr`, which scans many bytes per
+//! instruction, and fields are converted straight into transactions without going through
+//! serde. The result is the same as of `parser::parse` with trimmed fields, including the
+//! quirks of `csv`'s type inference for amounts. Input containing quotes isn't supported,
+//! callers fall back to `parser::parse` for it (see `is_supported`).
+use std::str::FromStr;
+
+use memchr::{memchr, memchr_iter};
+use rust_decimal::Decimal;
+
+use crate::{
+    error::Error,
+    parser::{counterparty, signature, tenant, transaction, transfer},
+    transaction::{OperationType, Timestamp, Transaction},
+};
+
+/// Positions of the known columns in a record
+#[derive(Debug, Clone, Copy)]
+struct Columns {
+    len: usize,
+    kind: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    batch: Option<usize>,
+    ref_tx: Option<usize>,
+    tenant: Option<usize>,
+    counterparty: Option<usize>,
+    signature: Option<usize>,
+    from_account: Option<usize>,
+    to_account: Option<usize>,
+}
+
+impl Columns {
+    fn new(header: &[u8]) -> Result<Self, Error> {
+        let names = fields(trim_line(header))
+            .map(|name| std::str::from_utf8(name).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let position = |column| names.iter().position(|&name| name == column);
+        let required = |column| {
+            position(column)
+                .ok_or_else(|| Error::ParsingFailure(format!("missing field `{}`", column)))
+        };
+        Ok(Self {
+            len: names.len(),
+            kind: required("type")?,
+            client: required("client")?,
+            tx: required("tx")?,
+            amount: position("amount"),
+            timestamp: position("timestamp"),
+            batch: position("batch"),
+            ref_tx: position("ref_tx"),
+            tenant: position("tenant"),
+            counterparty: position("counterparty"),
+            signature: position("signature"),
+            from_account: position("from_account"),
+            to_account: position("to_account"),
+        })
+    }
+}
+
+/// Whether the SIMD tokenizer can parse `data`
+pub fn is_supported(data: &[u8]) -> bool {
+    memchr(b'"', data).is_none()
+}
+
+fn trim_line(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Trimmed fields of a line
+fn fields(line: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut start = 0;
+    memchr_iter(b',', line)
+        .chain(std::iter::once(line.len()))
+        .map(move |end| {
+            let field = &line[start..end];
+            start = end + 1;
+            field.trim_ascii()
+        })
+}
+
+fn field<T: FromStr>(value: &str, name: &str) -> Result<Option<T>, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| Error::ParsingFailure(format!("field `{}`: {}", name, e)))
+}
+
+fn required<T: FromStr>(value: &str, name: &str) -> Result<T, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    field(value, name)?.ok_or_else(|| Error::ParsingFailure(format!("field `{}` is empty", name)))
+}
+
+/// Same as `csv` inferring the type for serde: integers are exact, other numbers go through `f64`
+fn amount(value: &str) -> Result<Option<Decimal>, Error> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let decimal = if let Ok(n) = value.parse::<u64>() {
+        Ok(Decimal::from(n))
+    } else if let Ok(n) = value.parse::<i64>() {
+        Ok(Decimal::from(n))
+    } else if let Ok(n) = value.parse::<f64>() {
+        Decimal::from_str(&n.to_string())
+    } else {
+        Decimal::from_str(value).or_else(|_| Decimal::from_scientific(value))
+    };
+    decimal
+        .map(Some)
+        .map_err(|e| Error::ParsingFailure(format!("field `amount`: {}", e)))
+}
+
+fn record<'a>(
+    columns: &Columns,
+    line: &'a [u8],
+    values: &mut Vec<&'a str>,
+) -> Result<Transaction, Error> {
+    values.clear();
+    for value in fields(line) {
+        values.push(
+            std::str::from_utf8(value)
+                .map_err(|e| Error::ParsingFailure(format!("invalid UTF-8: {}", e)))?,
+        );
+    }
+    if values.len() != columns.len {
+        return Err(Error::ParsingFailure(format!(
+            "found record with {} fields, but the header has {} fields",
+            values.len(),
+            columns.len
+        )));
+    }
+    let get = |column: Option<usize>| column.map_or("", |idx| values[idx]);
+
+    let amount = amount(get(columns.amount))?;
+    let client = required(values[columns.client], "client")?;
+    let tx = required(values[columns.tx], "tx")?;
+    let mut trans = match values[columns.kind] {
+        "transfer" => transfer(
+            client,
+            tx,
+            amount,
+            (get(columns.from_account), get(columns.to_account)),
+        )?,
+        kind => transaction(
+            kind,
+            client,
+            tx,
+            amount,
+            field(get(columns.ref_tx), "ref_tx")?,
+        )?,
+    };
+    trans.timestamp = field::<Timestamp>(get(columns.timestamp), "timestamp")?;
+    trans.batch = field(get(columns.batch), "batch")?;
+    trans.tenant = Some(get(columns.tenant))
+        .filter(|name| !name.is_empty())
+        .map(tenant)
+        .transpose()?;
+    trans.signature = Some(get(columns.signature))
+        .filter(|hex| !hex.is_empty())
+        .map(signature)
+        .transpose()?;
+    if let OperationType::Deposit {
+        counterparty: side, ..
+    }
+    | OperationType::Withdrawal {
+        counterparty: side, ..
+    } = &mut trans.op.kind
+    {
+        *side = Some(get(columns.counterparty))
+            .filter(|name| !name.is_empty())
+            .map(counterparty)
+            .transpose()?;
+    }
+    Ok(trans)
+}
+
+/// Parse the records of `data`, described by the `header` line
+pub fn parse<'a>(
+    header: &[u8],
+    data: &'a [u8],
+) -> impl Iterator<Item = Result<Transaction, Error>> + 'a {
+    let (columns, mut error) = match Columns::new(header) {
+        Ok(columns) => (Some(columns), None),
+        Err(error) => (None, Some(error)),
+    };
+    let mut values = Vec::new();
+    let mut start = 0;
+    std::iter::from_fn(move || loop {
+        let columns = match &columns {
+            Some(columns) => columns,
+            None => return error.take().map(Err),
+        };
+        if start >= data.len() {
+            return None;
+        }
+        let end = memchr(b'\n', &data[start..]).map_or(data.len(), |pos| start + pos);
+        let line = trim_line(&data[start..end]);
+        start = end + 1;
+        if !line.is_empty() {
+            return Some(record(columns, line, &mut values));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::{error::Error, parser};
+
+    fn csv(input: &str) -> Vec<Result<crate::transaction::Transaction, Error>> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        parser::parse(rdr).collect()
+    }
+
+    fn simd(input: &str) -> Vec<Result<crate::transaction::Transaction, Error>> {
+        let (header, data) = input.split_once('\n').unwrap();
+        parse(header.as_bytes(), data.as_bytes()).collect()
+    }
+
+    #[test]
+    fn same_as_csv() {
+        let input = "type, client, tx, amount, timestamp, batch, ref_tx\r
+deposit, 1, 1, 1.100, 2024-01-01T00:00:00Z, ,\r
+deposit,2,2,5,,7,\r
+\r
+withdrawal, 1, 3, 0.5, 2024-01-01T01:00:00+02:00, , 1\r
+dispute, 1, 1, , , ,\r
+resolve, 1, 1,,,,\r
+chargeback, 2, 2, , , ,";
+        assert_eq!(simd(input), csv(input));
+    }
+
+    #[test]
+    fn transfers_same_as_csv() {
+        let input = "type, client, tx, amount, from_account, to_account
+deposit, 1, 1, 10, ,
+transfer, 1, 2, 4, , savings
+transfer, 1, 3, 1.5, savings, main
+transfer, 1, 4, 1, savings, savings
+transfer, 1, 5, 1, , no pockets";
+        let simd = simd(input);
+        assert_eq!(simd, csv(input));
+        assert!(simd[..3].iter().all(Result::is_ok));
+        assert!(simd[3..].iter().all(Result::is_err));
+    }
+
+    #[test]
+    fn column_order_and_errors() {
+        let input = "client,type,amount,tx
+1,deposit,2.5,1
+1,withdrawal,,2
+1,dispute,1
+x,dispute,,3
+1,transfer,1,4";
+        let simd = simd(input);
+        let csv = csv(input);
+        assert_eq!(simd.len(), csv.len());
+        assert_eq!(simd[0], csv[0]);
+        for (simd, csv) in simd.iter().zip(&csv).skip(1) {
+            assert!(matches!(simd, Err(Error::ParsingFailure(_))));
+            assert!(matches!(csv, Err(Error::ParsingFailure(_))));
+        }
+    }
+
+    #[test]
+    fn missing_column() {
+        assert_eq!(
+            simd("type, client, amount\ndeposit, 1, 1\n"),
+            [Err(Error::ParsingFailure("missing field `tx`".to_string()))]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Versions of the persisted state: the event logs of `Payments::export_events`, which are also
+//! the sealed snapshots of `close`, and the snapshots of compacted transaction logs (`txlog`).
+//!
+//! Since version 2 a snapshot starts with a header, then the events follow, one per line:
+//!
+//! ```text
+//! {"snapshot_version":2,"checkpoint":{"seq":3,"head":"5f..c1","recorded_at":"..."}}
+//! {"client":1,"event":"FundsDeposited","tx":1,"amount":"5"}
+//! ```
+//!
+//! Version 1 has no header, and a transaction log's snapshot has the checkpoint on the first
+//! line instead. Snapshots of older versions are migrated as they're read, one version at a
+//! time, so upgrading the crate doesn't orphan them; `migrate_file` rewrites one in the
+//! current version. Changing the schema bumps `VERSION` and adds a step to `MIGRATIONS`.
+//!
+//! A large state may be persisted sharded instead: a directory of snapshots, `shard-<n>.jsonl`,
+//! of the clients by their ID modulo the number of shards, written and read in parallel.
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::Error, event::ClientEvent, txlog::Checkpoint};
+
+/// The version snapshots are written in
+pub const VERSION: u32 = 2;
+
+/// The migration of an event from version `n + 1` to `n + 2` is the `n`th
+const MIGRATIONS: [fn(Value) -> Value; VERSION as usize - 1] = [
+    // Version 2 added the header, the events are the same
+    |event| event,
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub snapshot_version: u32,
+    /// Of the snapshot of a compacted transaction log, see `txlog::compact`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Checkpoint>,
+}
+
+/// A snapshot migrated to `VERSION`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// The version it was read in
+    pub version: u32,
+    pub checkpoint: Option<Checkpoint>,
+    pub events: Vec<ClientEvent>,
+}
+
+/// The header of a snapshot starting with `line`, whether `line` is the header itself (an
+/// event otherwise)
+fn header(line: &str) -> Result<(Header, bool), Error> {
+    if let Ok(header) = serde_json::from_str::<Header>(line) {
+        if header.snapshot_version == 0 || header.snapshot_version > VERSION {
+            return Err(Error::UnsupportedSnapshotVersion(header.snapshot_version));
+        }
+        return Ok((header, true));
+    }
+    let checkpoint = serde_json::from_str::<Checkpoint>(line).ok();
+    let is_checkpoint = checkpoint.is_some();
+    let header = Header {
+        snapshot_version: 1,
+        checkpoint,
+    };
+    Ok((header, is_checkpoint))
+}
+
+/// The header of the snapshot read from `input`, without reading its events
+pub fn read_header(input: impl BufRead) -> Result<Header, Box<dyn std::error::Error>> {
+    match input.lines().next().transpose()? {
+        Some(line) => Ok(header(&line)?.0),
+        None => Ok(Header {
+            snapshot_version: VERSION,
+            checkpoint: None,
+        }),
+    }
+}
+
+/// Read a snapshot of any version up to `VERSION`, migrating it
+pub fn read(input: impl BufRead) -> Result<Snapshot, Box<dyn std::error::Error>> {
+    let mut snapshot = Snapshot {
+        version: VERSION,
+        checkpoint: None,
+        events: Vec::new(),
+    };
+    let mut first = true;
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if std::mem::take(&mut first) {
+            let (header, is_header) = header(&line)?;
+            snapshot.version = header.snapshot_version;
+            snapshot.checkpoint = header.checkpoint;
+            if is_header {
+                continue;
+            }
+        }
+        let event = match snapshot.version {
+            VERSION => serde_json::from_str(&line)?,
+            version => {
+                let event = MIGRATIONS[version as usize - 1..]
+                    .iter()
+                    .fold(serde_json::from_str(&line)?, |event, migrate| {
+                        migrate(event)
+                    });
+                serde_json::from_value(event)?
+            }
+        };
+        snapshot.events.push(event);
+    }
+    Ok(snapshot)
+}
+
+/// Write a snapshot of `events` in `VERSION`
+pub fn write<'a>(
+    checkpoint: Option<&Checkpoint>,
+    events: impl IntoIterator<Item = &'a ClientEvent>,
+    mut output: impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let header = Header {
+        snapshot_version: VERSION,
+        checkpoint: checkpoint.cloned(),
+    };
+    serde_json::to_writer(&mut output, &header)?;
+    writeln!(output)?;
+    for event in events {
+        serde_json::to_writer(&mut output, event)?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// The file of `shard` of a sharded snapshot in `dir`
+fn shard_path(dir: &Path, shard: usize) -> PathBuf {
+    dir.join(format!("shard-{:03}.jsonl", shard))
+}
+
+/// The files of the shards of a sharded snapshot in `dir`, in order
+pub fn shard_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        if name.is_some_and(|name| name.starts_with("shard-") && name.ends_with(".jsonl")) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Write `events` as a snapshot sharded into `shards` files by client ID, each by its own
+/// thread. It's written next to `dir` first, replacing it once every shard is complete.
+pub fn write_sharded(
+    events: &[ClientEvent],
+    dir: &Path,
+    shards: u16,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let shards = usize::from(shards.max(1));
+    let mut parts = vec![Vec::new(); shards];
+    for event in events {
+        parts[usize::from(event.client) % shards].push(event);
+    }
+    let mut partial = dir.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    std::fs::create_dir_all(&partial)?;
+    std::thread::scope(|scope| {
+        let writers = parts
+            .iter()
+            .enumerate()
+            .map(|(shard, events)| {
+                let path = shard_path(&partial, shard);
+                scope.spawn(move || {
+                    let write = || -> Result<(), Box<dyn std::error::Error>> {
+                        let mut output = std::io::BufWriter::new(std::fs::File::create(path)?);
+                        write(None, events.iter().copied(), &mut output)?;
+                        output.into_inner()?.sync_all()?;
+                        Ok(())
+                    };
+                    write().map_err(|e| e.to_string())
+                })
+            })
+            .collect::<Vec<_>>();
+        writers.into_iter().try_for_each(|writer| {
+            writer
+                .join()
+                .unwrap_or_else(|_| Err("shard writer panicked".to_string()))
+        })
+    })?;
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::rename(&partial, dir)?;
+    Ok(())
+}
+
+/// Rewrite the snapshot at `path` in `VERSION`, replacing it atomically, returning the
+/// version it was in
+pub fn migrate_file(path: &Path) -> Result<u32, Box<dyn std::error::Error>> {
+    let snapshot = read(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    if snapshot.version == VERSION {
+        return Ok(VERSION);
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let mut output = std::io::BufWriter::new(std::fs::File::create(&partial)?);
+    write(snapshot.checkpoint.as_ref(), &snapshot.events, &mut output)?;
+    output.into_inner()?.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(snapshot.version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, read_header, write, VERSION};
+    use crate::{error::Error, testing::process, txlog::Checkpoint};
+
+    /// Events as exported before the header, of a deposit and its dispute
+    const VERSION_1: &str = r#"{"client":1,"event":"FundsDeposited","tx":1,"amount":"5"}
+{"client":1,"event":"FundsHeld","tx":1,"amount":"5"}
+"#;
+
+    #[test]
+    fn migrates_version_1() {
+        let payments = process("type, client, tx, amount\ndeposit, 1, 1, 5\ndispute, 1, 1,");
+        let snapshot = read(VERSION_1.as_bytes()).unwrap();
+        assert_eq!(snapshot.version, 1);
+        assert_eq!(snapshot.checkpoint, None);
+        assert_eq!(snapshot.events, payments.events());
+
+        let mut migrated = Vec::new();
+        write(None, &snapshot.events, &mut migrated).unwrap();
+        let snapshot = read(migrated.as_slice()).unwrap();
+        assert_eq!(snapshot.version, VERSION);
+        assert_eq!(snapshot.events, payments.events());
+
+        // Of a compacted transaction log, the checkpoint first
+        let checkpoint = Checkpoint::default();
+        let log = format!(
+            "{}\n{}",
+            serde_json::to_string(&checkpoint).unwrap(),
+            VERSION_1
+        );
+        let snapshot = read(log.as_bytes()).unwrap();
+        assert_eq!(
+            (snapshot.version, snapshot.checkpoint.as_ref()),
+            (1, Some(&checkpoint))
+        );
+        assert_eq!(snapshot.events, payments.events());
+        let header = read_header(log.as_bytes()).unwrap();
+        assert_eq!(header.checkpoint, Some(checkpoint));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let error = read(r#"{"snapshot_version":3}"#.as_bytes()).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&Error::UnsupportedSnapshotVersion(3))
+        );
+        let error = read(r#"{"snapshot_version":0}"#.as_bytes()).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&Error::UnsupportedSnapshotVersion(0))
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! External merge sort of transaction files larger than memory.
+//!
+//! The input is split into chunks of at most `chunk_rows` rows, each sorted in memory and
+//! spilled to a temporary file, then the chunks are merged. The sort is stable, so sorting by
+//! client keeps every client's transactions in their original order.
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::DateTime;
+use csv::StringRecord;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Rows without a timestamp stay right after the preceding row
+    Timestamp,
+    Client,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timestamp" => Ok(SortKey::Timestamp),
+            "client" => Ok(SortKey::Client),
+            other => Err(format!(
+                "unknown sort key `{}`, expected `timestamp` or `client`",
+                other
+            )),
+        }
+    }
+}
+
+impl SortKey {
+    fn column(self) -> &'static str {
+        match self {
+            SortKey::Timestamp => "timestamp",
+            SortKey::Client => "client",
+        }
+    }
+}
+
+/// A temporary file with a sorted chunk, removed on drop
+struct Chunk(PathBuf);
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Numbering of chunk files, unique within the process
+static NEXT_CHUNK: AtomicUsize = AtomicUsize::new(0);
+
+/// A row with its sort key and position in the input
+type Row = (i64, u64, StringRecord);
+
+/// Sort key and position in the input of a chunk's next row, with the chunk's index
+type HeapEntry = Reverse<(i64, u64, usize)>;
+
+fn sort_key(key: SortKey, value: &str, previous: i64) -> Result<i64, Error> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        Error::ParsingFailure(format!("invalid {} `{}`: {}", key.column(), value, e))
+    };
+    match key {
+        SortKey::Timestamp if value.is_empty() => Ok(previous),
+        SortKey::Timestamp => DateTime::parse_from_rfc3339(value)
+            .map(|t| t.timestamp_micros())
+            .map_err(|e| invalid(&e)),
+        SortKey::Client => value.parse().map_err(|e| invalid(&e)),
+    }
+}
+
+fn spill(
+    rows: &mut Vec<Row>,
+    dir: &Path,
+    chunks: &mut Vec<Chunk>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    rows.sort_by_key(|&(key, seq, _)| (key, seq));
+    let chunk = Chunk(dir.join(format!(
+        "payments-sort-{}-{}.csv",
+        std::process::id(),
+        NEXT_CHUNK.fetch_add(1, Ordering::Relaxed)
+    )));
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_path(&chunk.0)?;
+    chunks.push(chunk);
+    for (key, seq, record) in rows.drain(..) {
+        let mut row = StringRecord::from(vec![key.to_string(), seq.to_string()]);
+        row.extend(record.iter());
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read the next row of chunk `idx` into `heads`, returning its heap entry
+fn next_row(
+    records: &mut csv::StringRecordsIntoIter<File>,
+    idx: usize,
+    heads: &mut [Option<StringRecord>],
+) -> Result<Option<HeapEntry>, Box<dyn std::error::Error>> {
+    let row = match records.next() {
+        Some(row) => row?,
+        None => return Ok(None),
+    };
+    let (key, seq) = (row[0].parse()?, row[1].parse()?);
+    heads[idx] = Some(row.iter().skip(2).collect());
+    Ok(Some(Reverse((key, seq, idx))))
+}
+
+/// Sort a transactions CSV by `key`, keeping at most `chunk_rows` rows in memory
+/// and spilling sorted chunks to `tmp_dir`
+pub fn sort(
+    input: impl Read,
+    output: impl Write,
+    key: SortKey,
+    chunk_rows: usize,
+    tmp_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    let column = headers
+        .iter()
+        .position(|h| h == key.column())
+        .ok_or_else(|| Error::ParsingFailure(format!("missing `{}` column", key.column())))?;
+
+    let mut chunks = Vec::new();
+    let mut rows = Vec::with_capacity(chunk_rows.min(1 << 20));
+    let mut previous = i64::MIN;
+    for (seq, record) in reader.into_records().enumerate() {
+        let record = record?;
+        previous = sort_key(key, record.get(column).unwrap_or_default(), previous)?;
+        rows.push((previous, seq as u64, record));
+        if rows.len() >= chunk_rows.max(1) {
+            spill(&mut rows, tmp_dir, &mut chunks)?;
+        }
+    }
+    if !rows.is_empty() {
+        spill(&mut rows, tmp_dir, &mut chunks)?;
+    }
+
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(output);
+    writer.write_record(&headers)?;
+    let mut readers = chunks
+        .iter()
+        .map(|chunk| {
+            Ok(csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(File::open(&chunk.0)?)
+                .into_records())
+        })
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+    // Merge the chunks, the heap holds the key of the next row of each one
+    let mut heads = vec![None; readers.len()];
+    let mut heap = BinaryHeap::new();
+    for (idx, records) in readers.iter_mut().enumerate() {
+        heap.extend(next_row(records, idx, &mut heads)?);
+    }
+    while let Some(Reverse((_, _, idx))) = heap.pop() {
+        if let Some(record) = heads[idx].take() {
+            writer.write_record(&record)?;
+        }
+        heap.extend(next_row(&mut readers[idx], idx, &mut heads)?);
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sort, SortKey};
+
+    const INPUT: &str = "\
+type, client, tx, amount, timestamp
+deposit, 2, 1, 1.0, 2024-01-01T00:03:00Z
+deposit, 1, 2, 2.0, 2024-01-01T00:01:00Z
+dispute, 1, 2, ,
+deposit, 2, 3, 3.0, 2024-01-01T00:00:00Z
+withdrawal, 1, 4, 1.0, 2024-01-01T00:02:00Z
+";
+
+    fn sorted(key: SortKey) -> String {
+        let mut output = Vec::new();
+        sort(INPUT.as_bytes(), &mut output, key, 2, &std::env::temp_dir()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn by_timestamp() {
+        assert_eq!(
+            sorted(SortKey::Timestamp),
+            "\
+type,client,tx,amount,timestamp
+deposit,2,3,3.0,2024-01-01T00:00:00Z
+deposit,1,2,2.0,2024-01-01T00:01:00Z
+dispute,1,2,,
+withdrawal,1,4,1.0,2024-01-01T00:02:00Z
+deposit,2,1,1.0,2024-01-01T00:03:00Z
+"
+        );
+    }
+
+    #[test]
+    fn by_client() {
+        assert_eq!(
+            sorted(SortKey::Client),
+            "\
+type,client,tx,amount,timestamp
+deposit,1,2,2.0,2024-01-01T00:01:00Z
+dispute,1,2,,
+withdrawal,1,4,1.0,2024-01-01T00:02:00Z
+deposit,2,1,1.0,2024-01-01T00:03:00Z
+deposit,2,3,3.0,2024-01-01T00:00:00Z
+"
+        );
+    }
+
+    #[test]
+    fn missing_column() {
+        let mut output = Vec::new();
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+        assert!(sort(
+            input.as_bytes(),
+            &mut output,
+            SortKey::Timestamp,
+            2,
+            &std::env::temp_dir()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Attribution of transactions to the source they came from, e.g. the feed of a partner, so
+//! that a source producing bad rows can be told from the others.
+//!
+//! `Payments::set_source` names the source of the transactions applied from then on. Every
+//! transaction applied while a source is set is attributed to it, see `Payments::source_of`,
+//! and the transactions of every source are counted, the rejected ones by the kind of error,
+//! see `Payments::source_stats`. Malformed rows count as rejected `parsing_failure`s, see
+//! `Payments::record_malformed`. The counts aren't derived from the event log: transactions
+//! which were rolled back were received still. The latest rejected transactions are kept
+//! too, see `Payments::recent_rejections`.
+//!
+//! ```
+//! use payments::{payments::Payments, transaction::Transaction};
+//! use rust_decimal_macros::dec;
+//!
+//! let mut payments = Payments::default();
+//! payments.set_source(Some("partner-a"));
+//! payments.apply(Transaction::deposit(1, 1, dec!(10)).unwrap()).unwrap();
+//! payments.set_source(Some("partner-b"));
+//! assert!(payments.apply(Transaction::dispute(1, 2)).is_err());
+//!
+//! assert_eq!(payments.source_of(1, 1), Some("partner-a"));
+//! let stats = payments.source_stats().collect::<Vec<_>>();
+//! assert_eq!(stats[1].0, "partner-b");
+//! assert_eq!(stats[1].1.rejections["transaction_not_found"], 1);
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+};
+
+use serde::Serialize;
+
+use crate::{client::ClientId, error::Error, transaction::TransactionId};
+
+/// The transactions of a source
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SourceStats {
+    /// Applied or rejected
+    pub transactions: usize,
+    pub rejected: usize,
+    /// Rejected transactions by `Error::kind`
+    pub rejections: BTreeMap<&'static str, usize>,
+}
+
+impl fmt::Display for SourceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transactions: {}, rejected: {}",
+            self.transactions, self.rejected
+        )?;
+        for (kind, count) in &self.rejections {
+            write!(f, ", {}: {}", kind, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many of the latest rejected transactions are kept
+pub const RECENT_REJECTIONS: usize = 10;
+
+/// A rejected transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    /// The source it came from, if any
+    pub source: Option<String>,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Sources {
+    /// Names of the sources seen, indexed by their attributed transactions
+    names: Vec<String>,
+    stats: Vec<SourceStats>,
+    current: Option<usize>,
+    /// The source of every attributed transaction, with the offset in the event log at which
+    /// its events start. A transaction ID shared by later operations, e.g. a dispute, keeps the
+    /// source of the first one.
+    attributed: HashMap<(ClientId, TransactionId), (usize, usize)>,
+    /// The latest rejected transactions, oldest first
+    recent: VecDeque<Rejection>,
+}
+
+impl Sources {
+    /// Attribute the transactions from now on to `source`, or to none
+    pub fn set(&mut self, source: Option<&str>) {
+        self.current =
+            source.map(
+                |source| match self.names.iter().position(|name| name == source) {
+                    Some(index) => index,
+                    None => {
+                        self.names.push(source.to_string());
+                        self.stats.push(SourceStats::default());
+                        self.names.len() - 1
+                    }
+                },
+            );
+    }
+
+    /// The index of the current source, to attribute transactions applied later to it, see
+    /// `Sources::replace`
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    /// Attribute the transactions from now on to the source of `index`, returning the index of
+    /// the current one
+    pub fn replace(&mut self, index: Option<usize>) -> Option<usize> {
+        std::mem::replace(&mut self.current, index)
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.map(|index| self.names[index].as_str())
+    }
+
+    /// Account for a transaction received from the current source, or a malformed row, with
+    /// why it was rejected
+    pub fn received(&mut self, rejection: Option<&Error>) {
+        let Some(stats) = self.current.and_then(|index| self.stats.get_mut(index)) else {
+            return;
+        };
+        stats.transactions += 1;
+        if let Some(error) = rejection {
+            stats.rejected += 1;
+            *stats.rejections.entry(error.kind()).or_default() += 1;
+        }
+    }
+
+    /// Keep a rejected transaction among the latest ones, see `RECENT_REJECTIONS`
+    pub fn rejected(&mut self, client: ClientId, tx: TransactionId, error: &Error) {
+        if self.recent.len() == RECENT_REJECTIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Rejection {
+            source: self.current().map(str::to_string),
+            client,
+            tx,
+            error: error.to_string(),
+        });
+    }
+
+    /// The latest rejected transactions, oldest first
+    pub fn recent(&self) -> impl Iterator<Item = &Rejection> {
+        self.recent.iter()
+    }
+
+    /// Attribute a transaction applied with its events starting at offset `start`
+    pub fn applied(&mut self, client: ClientId, tx: TransactionId, start: usize) {
+        if let Some(index) = self.current {
+            self.attributed
+                .entry((client, tx))
+                .or_insert((index, start));
+        }
+    }
+
+    /// Forget the transactions `undone` which were applied from offset `end` on, as they were
+    /// rolled back
+    pub fn truncate(
+        &mut self,
+        end: usize,
+        undone: impl IntoIterator<Item = (ClientId, TransactionId)>,
+    ) {
+        for key in undone {
+            if let Some(&(_, start)) = self.attributed.get(&key) {
+                if start >= end {
+                    self.attributed.remove(&key);
+                }
+            }
+        }
+    }
+
+    pub fn source_of(&self, client: ClientId, tx: TransactionId) -> Option<&str> {
+        self.attributed
+            .get(&(client, tx))
+            .map(|&(index, _)| self.names[index].as_str())
+    }
+
+    /// The sources in the order they were first set
+    pub fn stats(&self) -> impl Iterator<Item = (&str, &SourceStats)> {
+        self.names.iter().map(String::as_str).zip(&self.stats)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.attributed.capacity()
+            * (std::mem::size_of::<((ClientId, TransactionId), (usize, usize))>() + 1)
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.attributed.shrink_to_fit();
+    }
+}
@@ -0,0 +1,322 @@
+//! Stress tests of concurrent engines, built with the `stress` feature.
+//!
+//! `run` drives a single engine shared by multiple threads, each applying random transactions
+//! of its own clients, with the threads yielding at random points so that every seed
+//! interleaves them differently. As clients are independent of each other, the engine has to
+//! end up as a single-threaded run of the same transactions would, whatever the interleaving:
+//! every transaction accepted or rejected alike, every client with the same state and
+//! balances adding up, and its event log replaying to that state. A `Violation` tells which
+//! of them failed, for the seed of `Stress` to reproduce it.
+//!
+//! Engines implement `Concurrent`, `Mutex<Payments>` being the baseline. Features depending on
+//! the order of transactions across clients, see `parallel`, should be left out of the
+//! configuration.
+//!
+//! ```
+//! use std::sync::Mutex;
+//!
+//! use payments::{payments::Payments, stress::{run, Stress}};
+//!
+//! let stress = Stress {
+//!     seed: 7,
+//!     ..Stress::default()
+//! };
+//! let report = run(&stress, |config| Mutex::new(Payments::with_config(config))).unwrap();
+//! assert_eq!(report.transactions, 4 * 1000);
+//! ```
+use std::{
+    fmt,
+    sync::{Barrier, Mutex, PoisonError},
+};
+
+use itertools::Itertools;
+use rust_decimal::Decimal;
+
+use crate::{
+    client::ClientId,
+    error::Error,
+    parallel::diverging_clients,
+    payments::{Config, Payments},
+    transaction::{Transaction, TransactionId},
+};
+
+/// An engine applying transactions from multiple threads
+pub trait Concurrent: Sync {
+    fn apply(&self, transaction: Transaction) -> Result<(), Error>;
+
+    /// The state once every thread is done
+    fn into_payments(self) -> Payments;
+}
+
+impl Concurrent for Mutex<Payments> {
+    fn apply(&self, transaction: Transaction) -> Result<(), Error> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .apply(transaction)
+    }
+
+    fn into_payments(self) -> Payments {
+        self.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The shape of a stress run
+#[derive(Debug, Clone)]
+pub struct Stress {
+    pub seed: u64,
+    pub threads: usize,
+    /// Transactions applied by every thread
+    pub transactions: u32,
+    /// Clients of every thread
+    pub clients: u16,
+    /// Probability of a thread yielding before a transaction
+    pub yield_rate: f64,
+    pub config: Config,
+}
+
+impl Default for Stress {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            threads: 4,
+            transactions: 1000,
+            clients: 8,
+            yield_rate: 0.1,
+            config: Config::default(),
+        }
+    }
+}
+
+/// A passed stress run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub transactions: usize,
+    pub rejected: usize,
+}
+
+/// A failed stress run
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    /// A transaction accepted or rejected unlike in a single thread, the index of the
+    /// transaction among the thread's with the concurrent and the single-threaded result
+    Result {
+        thread: usize,
+        index: usize,
+        concurrent: Result<(), Error>,
+        single: Result<(), Error>,
+    },
+    /// Clients whose state differs from a single-threaded run
+    Diverging(Vec<ClientId>),
+    /// A client whose available, held and pending funds don't add up to its total
+    Unbalanced(ClientId),
+    /// Clients whose state differs from the replay of the event log
+    Replay(Vec<ClientId>),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Result {
+                thread,
+                index,
+                concurrent,
+                single,
+            } => write!(
+                f,
+                "transaction {} of thread {}: {:?} concurrently, {:?} in a single thread",
+                index, thread, concurrent, single
+            ),
+            Violation::Diverging(clients) => {
+                write!(f, "clients diverging from a single thread: {:?}", clients)
+            }
+            Violation::Unbalanced(client) => {
+                write!(f, "balances of client `{}` don't add up", client)
+            }
+            Violation::Replay(clients) => {
+                write!(f, "clients diverging from the event log: {:?}", clients)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Random transactions of the clients of `thread`, with IDs of its own. Disputes, resolves,
+/// chargebacks, amends and settles refer to earlier transactions of the thread, not
+/// necessarily of the same client.
+fn workload(stress: &Stress, thread: usize) -> Vec<Transaction> {
+    let mut rng = fastrand::Rng::with_seed(stress.seed ^ thread as u64);
+    let clients = stress.clients.max(1);
+    let first_client = thread as ClientId * clients;
+    let first_tx = thread as TransactionId * stress.transactions;
+    (0..stress.transactions)
+        .map(|n| {
+            let client = first_client + rng.u16(..clients);
+            let tx = first_tx + n;
+            let earlier = first_tx + rng.u32(..=n);
+            let amount = Decimal::new(rng.i64(1..100_000), 2);
+            match rng.u8(0..20) {
+                0..=7 => Transaction::deposit(client, tx, amount),
+                8..=11 => Transaction::withdrawal(client, tx, amount),
+                12..=13 => Ok(Transaction::dispute(client, earlier)),
+                14..=15 => Ok(Transaction::resolve(client, earlier)),
+                16 => Ok(Transaction::chargeback(client, earlier)),
+                17..=18 => Transaction::amend(client, earlier, amount),
+                _ => Ok(Transaction::settle(client, earlier)),
+            }
+        })
+        // The amounts are positive, so none is left out
+        .filter_map(Result::ok)
+        .collect()
+}
+
+/// Apply random transactions to the engine of `engine(stress.config)` from `stress.threads`
+/// threads at once and check the invariants, see the module documentation
+pub fn run<C: Concurrent>(
+    stress: &Stress,
+    engine: impl FnOnce(Config) -> C,
+) -> Result<Report, Box<Violation>> {
+    let threads = stress.threads.max(1);
+    let workloads = (0..threads)
+        .map(|thread| workload(stress, thread))
+        .collect::<Vec<_>>();
+
+    let engine = engine(stress.config.clone());
+    let start = Barrier::new(threads);
+    let results = std::thread::scope(|scope| {
+        let workers = workloads
+            .iter()
+            .enumerate()
+            .map(|(thread, workload)| {
+                let (engine, start) = (&engine, &start);
+                let mut rng = fastrand::Rng::with_seed(stress.seed.wrapping_add(thread as u64));
+                scope.spawn(move || {
+                    start.wait();
+                    workload
+                        .iter()
+                        .map(|transaction| {
+                            if rng.f64() < stress.yield_rate {
+                                std::thread::yield_now();
+                            }
+                            engine.apply(transaction.clone())
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>()
+    });
+    let concurrent = engine.into_payments();
+    let report = Report {
+        transactions: results.iter().map(Vec::len).sum(),
+        rejected: results.iter().flatten().filter(|r| r.is_err()).count(),
+    };
+
+    let mut single = Payments::with_config(stress.config.clone());
+    for (thread, (workload, results)) in workloads.into_iter().zip(results).enumerate() {
+        for (index, (transaction, result)) in workload.into_iter().zip(results).enumerate() {
+            let expected = single.apply(transaction);
+            if result != expected {
+                return Err(Box::new(Violation::Result {
+                    thread,
+                    index,
+                    concurrent: result,
+                    single: expected,
+                }));
+            }
+        }
+    }
+    let diverging = diverging_clients(&concurrent, &single);
+    if !diverging.is_empty() {
+        return Err(Box::new(Violation::Diverging(diverging)));
+    }
+    if let Some(client) = concurrent
+        .clients()
+        .find(|c| c.available() + c.held() + c.pending() != c.total())
+    {
+        return Err(Box::new(Violation::Unbalanced(client.id)));
+    }
+    // A log which can't be replayed diverges for every client
+    let replayed =
+        Payments::replay_with(stress.config.clone(), concurrent.events().iter().copied()).map_err(
+            |_| {
+                let clients = concurrent.clients().map(|client| client.id).sorted();
+                Box::new(Violation::Replay(clients.collect()))
+            },
+        )?;
+    let diverging = diverging_clients(&concurrent, &replayed);
+    if !diverging.is_empty() {
+        return Err(Box::new(Violation::Replay(diverging)));
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use super::{run, Concurrent, Stress, Violation};
+    use crate::{error::Error, payments::Payments, transaction::Transaction};
+
+    fn mutex(config: crate::payments::Config) -> Mutex<Payments> {
+        Mutex::new(Payments::with_config(config))
+    }
+
+    #[test]
+    fn mutex_passes() {
+        for seed in 0..5 {
+            let stress = Stress {
+                seed,
+                threads: 8,
+                transactions: 500,
+                ..Stress::default()
+            };
+            let report = run(&stress, mutex).unwrap();
+            assert_eq!(report.transactions, 8 * 500);
+            assert!(report.rejected > 0);
+        }
+    }
+
+    /// Loses every 100th transaction while reporting it applied
+    struct Lossy {
+        payments: Mutex<Payments>,
+        applied: AtomicUsize,
+    }
+
+    impl Concurrent for Lossy {
+        fn apply(&self, transaction: Transaction) -> Result<(), Error> {
+            if self.applied.fetch_add(1, Ordering::Relaxed) % 100 == 99 {
+                return Ok(());
+            }
+            self.payments.apply(transaction)
+        }
+
+        fn into_payments(self) -> Payments {
+            self.payments.into_payments()
+        }
+    }
+
+    #[test]
+    fn lost_update() {
+        let violation = run(&Stress::default(), |config| Lossy {
+            payments: mutex(config),
+            applied: AtomicUsize::new(0),
+        })
+        .unwrap_err();
+        assert!(matches!(
+            *violation,
+            Violation::Result { .. } | Violation::Diverging(_)
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+//! Named sub-accounts ("pockets") of a client, e.g. `savings`, next to its main account.
+//!
+//! Deposits, withdrawals, disputes and the postings of the close go through the main account,
+//! funds are moved between it and the sub-accounts by `transfer` transactions. The balances
+//! of a client are those of all its accounts together, the main account holds what's not in
+//! a sub-account.
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Longest name of a sub-account, in bytes
+pub const MAX_NAME_LEN: usize = 16;
+
+/// The name the main account goes by in the input, same as leaving the account out
+pub const MAIN: &str = "main";
+
+/// Name of a sub-account: letters, digits, `-` and `_`, stored inline so events stay `Copy`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SubAccount {
+    len: u8,
+    name: [u8; MAX_NAME_LEN],
+}
+
+impl SubAccount {
+    pub fn as_str(&self) -> &str {
+        // Validated ASCII, see `from_str`
+        std::str::from_utf8(&self.name[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl FromStr for SubAccount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = !s.is_empty()
+            && s.len() <= MAX_NAME_LEN
+            && s != MAIN
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(format!(
+                "invalid sub-account `{}`, expected up to {} letters, digits, `-` and `_`",
+                s, MAX_NAME_LEN
+            ));
+        }
+        let mut name = [0; MAX_NAME_LEN];
+        name[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self {
+            len: s.len() as u8,
+            name,
+        })
+    }
+}
+
+impl fmt::Display for SubAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for SubAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SubAccount({:?})", self.as_str())
+    }
+}
+
+impl Serialize for SubAccount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SubAccount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The account named `name` in the input: the main one (`None`) if it's empty or `main`
+pub fn account(name: &str) -> Result<Option<SubAccount>, String> {
+    match name {
+        "" | MAIN => Ok(None),
+        name => name.parse().map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{account, SubAccount};
+
+    #[test]
+    fn names() {
+        let savings: SubAccount = "savings".parse().unwrap();
+        assert_eq!(savings.to_string(), "savings");
+        assert_eq!(account("savings"), Ok(Some(savings)));
+        assert_eq!(account(""), Ok(None));
+        assert_eq!(account("main"), Ok(None));
+        for invalid in ["", "main", "a b", "x".repeat(17).as_str(), "żółw"] {
+            assert!(invalid.parse::<SubAccount>().is_err(), "{}", invalid);
+        }
+        assert!("x".repeat(16).parse::<SubAccount>().is_ok());
+        let json = serde_json::to_string(&savings).unwrap();
+        assert_eq!(json, "\"savings\"");
+        assert_eq!(serde_json::from_str::<SubAccount>(&json).unwrap(), savings);
+    }
+}
@@ -0,0 +1,232 @@
+//! Tenants: isolated states of several brands processed by one engine.
+//!
+//! Every tenant has its own `Payments`, so clients and transaction IDs of different tenants
+//! never meet - the same client ID of two tenants are two accounts. A transaction goes to
+//! the tenant of its `tenant` column, or to the default tenant of its source.
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{
+    dedup::DedupScope,
+    error::Error,
+    payments::{Config, Marker, Payments, Stats},
+    transaction::{TenantId, Transaction},
+};
+
+/// The position of every tenant's event log, see `Tenants::markers`
+pub type Markers = BTreeMap<TenantId, Marker>;
+
+/// The tenant of transactions without one, unless configured otherwise
+pub const DEFAULT_TENANT: &str = "default";
+
+#[derive(Debug, Default)]
+pub struct Tenants {
+    /// The configuration every tenant starts with
+    config: Config,
+    default: TenantId,
+    tenants: BTreeMap<TenantId, Payments>,
+    /// Of the transactions applied from now on, see `source`
+    source: Option<String>,
+}
+
+impl Tenants {
+    /// Tenants configured with `config`, with `default` receiving transactions without a tenant
+    pub fn new(config: Config, default: TenantId) -> Self {
+        Self {
+            config,
+            default,
+            tenants: BTreeMap::new(),
+            source: None,
+        }
+    }
+
+    /// The tenant of transactions without one
+    pub fn default_tenant(&self) -> &str {
+        &self.default
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Apply a transaction to the state of its tenant, which is created on first use
+    pub fn apply(&mut self, transaction: Transaction) -> Result<(), Error> {
+        let tenant = transaction.tenant.as_ref().unwrap_or(&self.default).clone();
+        self.tenant_mut(tenant).apply(transaction)
+    }
+
+    /// The state of `tenant`, which is created on first use
+    pub fn tenant_mut(&mut self, tenant: TenantId) -> &mut Payments {
+        let (config, source) = (&self.config, &self.source);
+        self.tenants.entry(tenant).or_insert_with_key(|tenant| {
+            let mut config = config.clone();
+            // Seen IDs of tenants are kept apart like everything else
+            if let DedupScope::Global(dedup) = &mut config.dedup_scope {
+                dedup.confirmation_dir = dedup.confirmation_dir.take().map(|dir| dir.join(tenant));
+            }
+            let mut payments = Payments::with_config(config);
+            payments.set_source(source.as_deref());
+            payments
+        })
+    }
+
+    /// The state of the default tenant, which is created on first use
+    pub fn default_mut(&mut self) -> &mut Payments {
+        self.tenant_mut(self.default.clone())
+    }
+
+    pub fn tenant(&self, tenant: &str) -> Option<&Payments> {
+        self.tenants.get(tenant)
+    }
+
+    /// The state of `tenant`, if it has one
+    pub fn get_mut(&mut self, tenant: &str) -> Option<&mut Payments> {
+        self.tenants.get_mut(tenant)
+    }
+
+    /// Attribute the transactions of every tenant from now on to `source`, see
+    /// `Payments::set_source`
+    pub fn set_source(&mut self, source: Option<&str>) {
+        self.source = source.map(str::to_string);
+        for payments in self.tenants.values_mut() {
+            payments.set_source(source);
+        }
+    }
+
+    /// Change the configuration of every tenant, and of the ones created later, keeping
+    /// their states, see `Payments::reconfigure`
+    pub fn reconfigure(&mut self, config: Config) {
+        for payments in self.tenants.values_mut() {
+            payments.reconfigure(config.clone());
+        }
+        self.config = Config {
+            dedup_scope: std::mem::take(&mut self.config.dedup_scope),
+            ..config
+        };
+    }
+
+    /// Mark the current position of every tenant, so that everything applied afterwards
+    /// can be undone with `rollback_to`
+    pub fn markers(&self) -> Markers {
+        self.tenants
+            .iter()
+            .map(|(tenant, payments)| (tenant.clone(), payments.marker()))
+            .collect()
+    }
+
+    /// Undo everything applied since `markers` were taken, dropping the tenants created since
+    pub fn rollback_to(&mut self, markers: &Markers) {
+        self.tenants
+            .retain(|tenant, _| markers.contains_key(tenant));
+        for (tenant, payments) in &mut self.tenants {
+            if let Some(&marker) = markers.get(tenant) {
+                payments.rollback_to(marker);
+            }
+        }
+    }
+
+    /// Tenants and their states, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Payments)> {
+        self.tenants
+            .iter()
+            .map(|(tenant, payments)| (tenant.as_str(), payments))
+    }
+
+    /// Tenants and their states, sorted by name
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut Payments)> {
+        self.tenants
+            .iter_mut()
+            .map(|(tenant, payments)| (tenant.as_str(), payments))
+    }
+
+    pub fn stats(&self) -> BTreeMap<&str, Stats> {
+        self.iter()
+            .map(|(tenant, payments)| (tenant, payments.stats()))
+            .collect()
+    }
+
+    /// Serialize the accounts of every tenant to `<dir>/<tenant>/accounts.csv`
+    pub fn serialize(&self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        for (tenant, payments) in self.iter() {
+            let dir = dir.join(tenant);
+            std::fs::create_dir_all(&dir)?;
+            payments.serialize(std::fs::File::create(dir.join("accounts.csv"))?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{Tenants, DEFAULT_TENANT};
+    use crate::{error::Error, parser::parse, transaction::Transaction};
+
+    #[test]
+    fn isolated_states() {
+        let input = "type, client, tx, amount, tenant
+            deposit, 1, 1, 5, brand-a
+            deposit, 1, 1, 7, brand-b
+            deposit, 1, 2, 1,
+            withdrawal, 1, 3, 6, brand-a";
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        let mut tenants = Tenants::new(Default::default(), DEFAULT_TENANT.to_string());
+        let results = parse(rdr)
+            .map(|trans| tenants.apply(trans.unwrap()))
+            .collect::<Vec<_>>();
+        assert!(results[..3].iter().all(Result::is_ok));
+        assert!(matches!(results[3], Err(Error::InsufficientFunds { .. })));
+
+        let total = |tenant| tenants.tenant(tenant).unwrap().client(1).unwrap().total();
+        assert_eq!(total("brand-a"), dec!(5));
+        assert_eq!(total("brand-b"), dec!(7));
+        assert_eq!(total(DEFAULT_TENANT), dec!(1));
+        assert_eq!(
+            tenants.stats().keys().copied().collect::<Vec<_>>(),
+            ["brand-a", "brand-b", DEFAULT_TENANT]
+        );
+    }
+
+    #[test]
+    fn rollback() {
+        let deposit = |tenant: &str, tx| Transaction {
+            tenant: Some(tenant.to_string()),
+            ..Transaction::deposit(1, tx, dec!(5)).unwrap()
+        };
+        let mut tenants = Tenants::new(Default::default(), DEFAULT_TENANT.to_string());
+        tenants.set_source(Some("bank"));
+        tenants.apply(deposit("brand-a", 1)).unwrap();
+        let markers = tenants.markers();
+        tenants.apply(deposit("brand-a", 2)).unwrap();
+        tenants.apply(deposit("brand-b", 3)).unwrap();
+        assert_eq!(
+            tenants.tenant("brand-b").unwrap().source_of(1, 3),
+            Some("bank")
+        );
+
+        tenants.rollback_to(&markers);
+        assert_eq!(
+            tenants
+                .tenant("brand-a")
+                .unwrap()
+                .client(1)
+                .unwrap()
+                .total(),
+            dec!(5)
+        );
+        assert!(tenants.tenant("brand-b").is_none());
+    }
+
+    #[test]
+    fn invalid_tenant() {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("type, client, tx, amount, tenant\ndeposit, 1, 1, 5, ../x".as_bytes());
+        assert!(matches!(
+            parse(rdr).next(),
+            Some(Err(Error::ParsingFailure(_)))
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+//! Helpers for regression tests against transaction fixtures, e.g. in the tests of an
+//! integration of the engine.
+//!
+//! ```
+//! use payments::testing::{assert_golden, process_and_dump, Normalize};
+//!
+//! let accounts = process_and_dump("type, client, tx, amount\ndeposit, 1, 1, 1.50");
+//! assert_eq!(
+//!     Normalize::all().apply(&accounts),
+//!     "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+//! );
+//! ```
+//!
+//! Golden files are compared with `assert_golden`. Running the tests with
+//! `PAYMENTS_UPDATE_GOLDEN=1` writes the actual output to them instead, to create them or to
+//! accept a change of the output after reviewing it.
+//!
+//! A regression corpus is a directory of inputs, e.g. minimized from fuzzing or production
+//! incidents, `<name>.csv` next to their expected accounts `<name>.accounts.csv`, replayed by
+//! `replay_corpus`.
+use std::path::{Path, PathBuf};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    parser::parse,
+    payments::{Config, Payments},
+};
+
+/// Set to write the golden files instead of comparing with them
+pub const UPDATE_GOLDEN: &str = "PAYMENTS_UPDATE_GOLDEN";
+
+/// Apply the transactions of `input` (CSV), ignoring failed transactions
+pub fn process(input: &str) -> Payments {
+    process_with_config(input, Config::default())
+}
+
+/// Same as `process`, with `config`
+#[allow(clippy::expect_used)]
+pub fn process_with_config(input: &str, config: Config) -> Payments {
+    let mut payments = Payments::with_config(config);
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    for transaction in parse(rdr) {
+        let _ = payments.apply(transaction.expect("valid input"));
+    }
+    payments
+}
+
+/// The accounts as written to the output
+#[allow(clippy::expect_used)]
+pub fn dump(payments: &Payments) -> String {
+    let mut output = Vec::<u8>::new();
+    payments
+        .serialize(&mut output)
+        .expect("serializing accounts");
+    String::from_utf8(output).expect("UTF-8 output")
+}
+
+pub fn process_and_dump(input: &str) -> String {
+    dump(&process(input))
+}
+
+/// Differences of CSV output to ignore when comparing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Normalize {
+    /// Whitespace around fields and blank lines. Line endings are always normalized.
+    pub whitespace: bool,
+    /// Trailing zeros of numbers, `1.50` is `1.5`
+    pub numbers: bool,
+    /// The order of the rows after the header
+    pub row_order: bool,
+}
+
+impl Normalize {
+    /// Ignore all the differences
+    pub fn all() -> Self {
+        Self {
+            whitespace: true,
+            numbers: true,
+            row_order: true,
+        }
+    }
+
+    /// The CSV `output` without the ignored differences
+    pub fn apply(&self, output: &str) -> String {
+        let mut lines = output
+            .lines()
+            .filter(|line| !self.whitespace || !line.trim().is_empty())
+            .map(|line| {
+                line.split(',')
+                    .map(|field| self.field(field))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect::<Vec<_>>();
+        if self.row_order && lines.len() > 1 {
+            lines[1..].sort();
+        }
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+
+    fn field(&self, field: &str) -> String {
+        let field = match self.whitespace {
+            true => field.trim(),
+            false => field,
+        };
+        match field.parse::<Decimal>() {
+            Ok(number) if self.numbers => number.normalize().to_string(),
+            _ => field.to_string(),
+        }
+    }
+}
+
+/// Compare `actual` with the golden file at `path`, ignoring the differences of `normalize`.
+/// Panics with the differing lines on a mismatch, or if the file doesn't exist.
+#[allow(clippy::panic, clippy::expect_used)]
+pub fn assert_golden(path: impl AsRef<Path>, actual: &str, normalize: Normalize) {
+    let path = path.as_ref();
+    if std::env::var_os(UPDATE_GOLDEN).is_some() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).expect("creating golden file directory");
+        }
+        std::fs::write(path, actual).expect("writing golden file");
+        return;
+    }
+    let expected = match std::fs::read_to_string(path) {
+        Ok(expected) => expected,
+        Err(e) => panic!(
+            "reading golden file `{}`: {}, run with {}=1 to create it",
+            path.display(),
+            e,
+            UPDATE_GOLDEN
+        ),
+    };
+    let (expected, actual) = (normalize.apply(&expected), normalize.apply(actual));
+    if expected != actual {
+        panic!(
+            "output differs from golden file `{}`, run with {}=1 to update it\n{}",
+            path.display(),
+            UPDATE_GOLDEN,
+            diff(&expected, &actual)
+        );
+    }
+}
+
+/// An input of a regression corpus whose output changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub input: PathBuf,
+    /// The differing lines, or why the expected output couldn't be read
+    pub diff: String,
+}
+
+/// The file of the expected output of the corpus input at `input`
+pub fn expected_output(input: &Path) -> PathBuf {
+    input.with_extension("accounts.csv")
+}
+
+/// Run every input of the corpus in `dir` through `run`, in the order of their names, and
+/// compare the outputs with the expected ones, returning the inputs whose output changed.
+/// With `update`, the outputs are written as the expected ones instead.
+pub fn replay_corpus(
+    dir: &Path,
+    normalize: Normalize,
+    update: bool,
+    mut run: impl FnMut(&Path) -> String,
+) -> std::io::Result<Vec<Mismatch>> {
+    let mut inputs = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .filter(|path| {
+            path.as_ref().map_or(true, |path| {
+                let name = path.to_string_lossy();
+                name.ends_with(".csv") && !name.ends_with(".accounts.csv")
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    inputs.sort();
+    let mut mismatches = Vec::new();
+    for input in inputs {
+        let actual = run(&input);
+        let expected_path = expected_output(&input);
+        if update {
+            std::fs::write(&expected_path, actual)?;
+            continue;
+        }
+        let diff = match std::fs::read_to_string(&expected_path) {
+            Ok(expected) => diff(&normalize.apply(&expected), &normalize.apply(&actual)),
+            Err(e) => format!("reading `{}`: {}", expected_path.display(), e),
+        };
+        if !diff.is_empty() {
+            mismatches.push(Mismatch { input, diff });
+        }
+    }
+    Ok(mismatches)
+}
+
+/// The differing lines, as `-expected` and `+actual`
+fn diff(expected: &str, actual: &str) -> String {
+    let (expected, actual) = (
+        expected.lines().collect::<Vec<_>>(),
+        actual.lines().collect::<Vec<_>>(),
+    );
+    let mut diff = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => {}
+            (e, a) => {
+                if let Some(e) = e {
+                    diff.push_str(&format!("line {}: -{}\n", i + 1, e));
+                }
+                if let Some(a) = a {
+                    diff.push_str(&format!("line {}: +{}\n", i + 1, a));
+                }
+            }
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assert_golden, diff, replay_corpus, Normalize};
+
+    #[test]
+    fn normalize() {
+        let output = "client, available\r\n2, 1.50\n\n1, 2\n";
+        assert_eq!(
+            Normalize::default().apply(output),
+            "client, available\n2, 1.50\n\n1, 2\n"
+        );
+        assert_eq!(
+            Normalize::all().apply(output),
+            "client,available\n1,2\n2,1.5\n"
+        );
+        assert_eq!(
+            Normalize {
+                whitespace: true,
+                ..Normalize::default()
+            }
+            .apply(output),
+            "client,available\n2,1.50\n1,2\n"
+        );
+    }
+
+    #[test]
+    fn golden_files() {
+        let dir = std::env::temp_dir().join(format!("payments-golden-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.csv");
+        std::fs::write(&path, "client,total\n1,1.50\n").unwrap();
+        assert_golden(&path, "client, total\n1, 1.5\n", Normalize::all());
+        let mismatch = std::panic::catch_unwind(|| {
+            assert_golden(&path, "client,total\n1,2\n", Normalize::all())
+        });
+        assert!(mismatch.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corpus() {
+        let dir = std::env::temp_dir().join(format!("payments-corpus-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.csv"), "1").unwrap();
+        std::fs::write(dir.join("b.csv"), "2").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+        let run = |input: &std::path::Path| std::fs::read_to_string(input).unwrap() + "0\n";
+        let missing = replay_corpus(&dir, Normalize::default(), false, run).unwrap();
+        assert_eq!(missing.len(), 2);
+        assert!(missing[0].input.ends_with("a.csv"));
+        assert!(replay_corpus(&dir, Normalize::default(), true, run)
+            .unwrap()
+            .is_empty());
+        assert!(replay_corpus(&dir, Normalize::default(), false, run)
+            .unwrap()
+            .is_empty());
+        let changed = replay_corpus(&dir, Normalize::default(), false, |_| "1\n".to_string());
+        let changed = changed.unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[1].diff, "line 1: -20\nline 1: +1\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn differing_lines() {
+        assert_eq!(
+            diff("a\nb\nc\n", "a\nx\n"),
+            "line 2: -b\nline 2: +x\nline 3: -c\n"
+        );
+    }
+}
@@ -1,26 +1,328 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 
-use crate::client::ClientId;
+use crate::{
+    client::ClientId,
+    counterparty::Counterparty,
+    error::Error,
+    money::{Currency, Money},
+    subaccount::SubAccount,
+};
 
 pub type TransactionId = u32;
+pub type Timestamp = DateTime<Utc>;
+pub type BatchId = u32;
+/// Name of a tenant, see `tenant`
+pub type TenantId = String;
 
-#[derive(Debug, PartialEq)]
+/// Decimal places amounts may have
+pub const AMOUNT_SCALE: u32 = 4;
+
+/// The largest amount of a transaction, 10^15, so that no sum of balances overflows
+pub const MAX_AMOUNT: Decimal = Decimal::from_parts(0xA4C6_8000, 0x0003_8D7E, 0, false, 0);
+
+/// `timestamp` moved by `by`, saturating at the bounds of `Timestamp` rather than
+/// overflowing, e.g. with the dispute timeout of a timestamp far in the future
+pub fn shift(timestamp: Timestamp, by: Duration) -> Timestamp {
+    timestamp
+        .checked_add_signed(by)
+        .unwrap_or(match by < Duration::zero() {
+            true => Timestamp::MIN_UTC,
+            false => Timestamp::MAX_UTC,
+        })
+}
+
+/// `amount` of the transaction `tx` if it's valid: non-negative, up to `AMOUNT_SCALE`
+/// decimal places and `MAX_AMOUNT`
+pub(crate) fn valid_amount(tx: TransactionId, amount: Decimal) -> Result<Decimal, Error> {
+    match amount.is_sign_negative()
+        || amount.normalize().scale() > AMOUNT_SCALE
+        || amount > MAX_AMOUNT
+    {
+        true => Err(Error::InvalidAmount(tx)),
+        false => Ok(amount),
+    }
+}
+
+/// Serialized tagged with `type`, the fields named as the columns of the input
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum OperationType {
-    Deposit { amount: Decimal },
-    Withdrawal { amount: Decimal },
+    /// `ref_tx` optionally links the operation to the transaction it originates from,
+    /// e.g. a refund or a reversal
+    Deposit {
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ref_tx: Option<TransactionId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Counterparty>,
+    },
+    Withdrawal {
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ref_tx: Option<TransactionId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Counterparty>,
+    },
     Dispute,
     Resolve,
     Chargeback,
+    /// Move available funds between the client's accounts, `None` being the main one,
+    /// see `subaccount`
+    Transfer {
+        amount: Decimal,
+        #[serde(
+            rename = "from_account",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        from: Option<SubAccount>,
+        #[serde(
+            rename = "to_account",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        to: Option<SubAccount>,
+    },
+    /// Correct the amount of the client's deposit or withdrawal `tx` which wasn't disputed,
+    /// recorded as an adjustment linked to it
+    Amend {
+        amount: Decimal,
+    },
+    /// Make the pending funds of the client's deposit `tx` available, once the acquirer
+    /// funded it, see `Config::settlement_delay`
+    Settle,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Operation {
+    #[serde(rename = "tx")]
     pub id: TransactionId,
+    #[serde(flatten)]
     pub kind: OperationType,
 }
 
-#[derive(Debug, PartialEq)]
+/// Serialized flat, as a row of the input:
+/// `{"tx":1,"type":"deposit","amount":"1.5","client":2}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
+    #[serde(flatten)]
     pub op: Operation,
+    #[serde(rename = "client")]
     pub client_id: ClientId,
+    /// When the transaction happened, if the input carries timestamps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    /// Transactions of a batch are applied atomically: if any of them fails,
+    /// the effects of the whole batch are rolled back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<BatchId>,
+    /// The tenant whose state the transaction belongs to, see `tenant::Tenants`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    /// HMAC of the transaction's fields, see `signature`, serialized as hex
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::signature::hex_signature"
+    )]
+    pub signature: Option<Vec<u8>>,
+    /// The day a deposit's funds become available, see `Payments::apply`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_date: Option<NaiveDate>,
+    /// Position of the transaction among its client's, increasing, see `ordering`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl Transaction {
+    fn new(client_id: ClientId, id: TransactionId, kind: OperationType) -> Self {
+        Self {
+            op: Operation { id, kind },
+            client_id,
+            timestamp: None,
+            batch: None,
+            tenant: None,
+            signature: None,
+            value_date: None,
+            seq: None,
+        }
+    }
+
+    pub fn deposit(client: ClientId, tx: TransactionId, amount: Decimal) -> Result<Self, Error> {
+        let kind = OperationType::Deposit {
+            amount: valid_amount(tx, amount)?,
+            ref_tx: None,
+            counterparty: None,
+        };
+        Ok(Self::new(client, tx, kind))
+    }
+
+    pub fn withdrawal(client: ClientId, tx: TransactionId, amount: Decimal) -> Result<Self, Error> {
+        let kind = OperationType::Withdrawal {
+            amount: valid_amount(tx, amount)?,
+            ref_tx: None,
+            counterparty: None,
+        };
+        Ok(Self::new(client, tx, kind))
+    }
+
+    /// Move `amount` between the client's accounts, `None` being the main one
+    pub fn transfer(
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+        from: Option<SubAccount>,
+        to: Option<SubAccount>,
+    ) -> Result<Self, Error> {
+        let kind = OperationType::Transfer {
+            amount: valid_amount(tx, amount)?,
+            from,
+            to,
+        };
+        Ok(Self::new(client, tx, kind))
+    }
+
+    /// Dispute the deposit or withdrawal `tx` of the client
+    pub fn dispute(client: ClientId, tx: TransactionId) -> Self {
+        Self::new(client, tx, OperationType::Dispute)
+    }
+
+    pub fn resolve(client: ClientId, tx: TransactionId) -> Self {
+        Self::new(client, tx, OperationType::Resolve)
+    }
+
+    pub fn chargeback(client: ClientId, tx: TransactionId) -> Self {
+        Self::new(client, tx, OperationType::Chargeback)
+    }
+
+    /// Correct the amount of the deposit or withdrawal `tx` of the client
+    pub fn amend(client: ClientId, tx: TransactionId, amount: Decimal) -> Result<Self, Error> {
+        let kind = OperationType::Amend {
+            amount: valid_amount(tx, amount)?,
+        };
+        Ok(Self::new(client, tx, kind))
+    }
+
+    pub fn settle(client: ClientId, tx: TransactionId) -> Self {
+        Self::new(client, tx, OperationType::Settle)
+    }
+
+    pub fn with_timestamp(self, timestamp: Timestamp) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
+
+    pub fn with_batch(self, batch: BatchId) -> Self {
+        Self {
+            batch: Some(batch),
+            ..self
+        }
+    }
+
+    pub fn with_seq(self, seq: u64) -> Self {
+        Self {
+            seq: Some(seq),
+            ..self
+        }
+    }
+
+    pub fn with_value_date(self, value_date: NaiveDate) -> Self {
+        Self {
+            value_date: Some(value_date),
+            ..self
+        }
+    }
+
+    /// The amount moved, in the `currency` of the client's account, if any
+    pub fn money(&self, currency: Currency) -> Option<Money> {
+        match self.op.kind {
+            OperationType::Deposit { amount, .. }
+            | OperationType::Withdrawal { amount, .. }
+            | OperationType::Transfer { amount, .. }
+            | OperationType::Amend { amount } => Some(Money::new(amount, currency)),
+            OperationType::Dispute
+            | OperationType::Resolve
+            | OperationType::Chargeback
+            | OperationType::Settle => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{OperationType, Transaction, MAX_AMOUNT};
+    use crate::{
+        error::Error,
+        money::{Currency, Money},
+    };
+
+    #[test]
+    fn validated_amounts() {
+        let deposit = Transaction::deposit(1, 2, dec!(1.5000)).unwrap();
+        assert_eq!(deposit.client_id, 1);
+        assert_eq!(deposit.op.id, 2);
+        assert!(matches!(
+            deposit.op.kind,
+            OperationType::Deposit { amount, .. } if amount == dec!(1.5)
+        ));
+        assert!(Transaction::withdrawal(1, 3, dec!(0)).is_ok());
+        assert_eq!(
+            Transaction::withdrawal(1, 3, dec!(-1)).unwrap_err(),
+            Error::InvalidAmount(3)
+        );
+        assert_eq!(
+            Transaction::deposit(1, 4, dec!(0.00001)).unwrap_err(),
+            Error::InvalidAmount(4)
+        );
+        assert_eq!(MAX_AMOUNT, dec!(1_000_000_000_000_000));
+        assert!(Transaction::deposit(1, 5, MAX_AMOUNT).is_ok());
+        assert_eq!(
+            Transaction::deposit(1, 5, MAX_AMOUNT + dec!(0.0001)).unwrap_err(),
+            Error::InvalidAmount(5)
+        );
+        assert_eq!(Transaction::dispute(1, 2).op.kind, OperationType::Dispute);
+        assert_eq!(
+            deposit.money(Currency::USD),
+            Some(Money::new(dec!(1.5), Currency::USD))
+        );
+        assert_eq!(Transaction::dispute(1, 2).money(Currency::USD), None);
+    }
+
+    #[test]
+    fn serde() {
+        let mut deposit = Transaction::deposit(2, 1, dec!(1.5)).unwrap().with_batch(7);
+        deposit.signature = Some(vec![0xab, 0x01]);
+        let json = serde_json::to_value(&deposit).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"tx": 1, "type": "deposit", "amount": "1.5", "client": 2,
+                "batch": 7, "signature": "ab01"})
+        );
+        assert_eq!(
+            serde_json::from_value::<Transaction>(json).unwrap(),
+            deposit
+        );
+
+        let transfer =
+            Transaction::transfer(2, 3, dec!(1), None, Some("savings".parse().unwrap())).unwrap();
+        let json = serde_json::to_string(&transfer).unwrap();
+        assert_eq!(
+            json,
+            r#"{"tx":3,"type":"transfer","amount":"1","to_account":"savings","client":2}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Transaction>(&json).unwrap(),
+            transfer
+        );
+        assert_eq!(
+            serde_json::from_str::<Transaction>(r#"{"type":"dispute","client":2,"tx":1}"#).unwrap(),
+            Transaction::dispute(2, 1)
+        );
+    }
 }
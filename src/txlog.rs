@@ -0,0 +1,484 @@
+//! Append-only log of the accepted transactions, hash-chained (see `hashchain`) so that
+//! `payments verify-log` proves no record was altered or removed. Each record carries the
+//! transaction in the canonical form it's signed in, see `signature::canonical`:
+//!
+//! ```text
+//! {"seq":0,"prev":"00..00","recorded_at":"2024-03-31T12:00:05Z","transaction":"deposit,1,1,5,,,,","hash":"5f..c1"}
+//! ```
+//!
+//! Transactions of a batch are logged once the batch is complete, and not at all if it's
+//! rolled back. `replay` rebuilds the accounts from a log, e.g. as of an incident.
+//!
+//! `compact` keeps a log from growing without bound: it folds the records into a snapshot next
+//! to the log, `<log>.snapshot`, and truncates the log. The snapshot's header has the
+//! `Checkpoint` the log's chain continues from, see `snapshot`.
+//!
+//! A record is complete with its newline. A crash mid-append leaves the last one torn, and
+//! opening the log to append to it, or compacting it, drops that record, see `drop_torn`.
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    hashchain::{Chain, Continuation},
+    payments::{Config, Payments},
+    signature::from_canonical,
+    snapshot,
+    transaction::{BatchId, Timestamp},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    /// When the record was written
+    pub recorded_at: Timestamp,
+    pub transaction: String,
+}
+
+/// The accepted transactions, held back until their batch is complete
+#[derive(Debug, Clone, Default)]
+pub struct Accepted {
+    batch: Option<BatchId>,
+    /// The accepted transactions of the open batch
+    pending: Vec<String>,
+}
+
+impl Accepted {
+    /// Take `entry`, the canonical form of a transaction of `batch` applied with `result`,
+    /// returning the accepted transactions which can't be rolled back anymore
+    pub fn record(
+        &mut self,
+        entry: String,
+        batch: Option<BatchId>,
+        result: &Result<(), Error>,
+    ) -> Vec<String> {
+        let mut done = Vec::new();
+        if batch.is_none() || batch != self.batch {
+            done = std::mem::take(&mut self.pending);
+            self.batch = batch;
+        }
+        match result {
+            Err(Error::BatchRolledBack { .. }) => self.pending.clear(),
+            Err(_) => {}
+            Ok(()) => self.pending.push(entry),
+        }
+        if batch.is_none() {
+            done.append(&mut self.pending);
+        }
+        done
+    }
+
+    /// The accepted transactions of the open batch, the input being over
+    pub fn finish(&mut self) -> Vec<String> {
+        self.batch = None;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Appends the accepted transactions to a log
+pub struct TransactionLog<W: Write> {
+    output: W,
+    chain: Chain,
+}
+
+impl TransactionLog<File> {
+    /// Append to the log at `path`, continuing its chain once it's verified
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        drop_torn(path)?;
+        let output = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let chain = checkpoint(path)?
+            .chain
+            .extend(BufReader::new(File::open(path)?))?;
+        Ok(Self::new(output, chain))
+    }
+}
+
+impl<W: Write> TransactionLog<W> {
+    pub fn new(output: W, chain: Chain) -> Self {
+        Self { output, chain }
+    }
+
+    /// Log the canonical form of an accepted transaction, see `Accepted`
+    pub fn append(&mut self, transaction: String) -> Result<(), Box<dyn std::error::Error>> {
+        let record = TransactionRecord {
+            recorded_at: Utc::now(),
+            transaction,
+        };
+        self.chain.append(&record, &mut self.output)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+
+    /// The end of the log's chain
+    pub fn chain(&self) -> &Chain {
+        &self.chain
+    }
+}
+
+/// Drop the record at the end of the log at `path` torn by a crash mid-append, i.e. without
+/// its newline, returning whether there was one. It was never complete, so the transaction
+/// it carried is applied again with the input it came from.
+pub fn drop_torn(path: &Path) -> std::io::Result<bool> {
+    let records = match std::fs::read(path) {
+        Ok(records) => records,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if records.last().is_none_or(|&byte| byte == b'\n') {
+        return Ok(false);
+    }
+    let complete = records
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |end| end + 1);
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    file.set_len(complete as u64)?;
+    file.sync_all()?;
+    Ok(true)
+}
+
+/// How far a log was replayed, e.g. compacted into its snapshot
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    #[serde(flatten)]
+    pub chain: Chain,
+    /// When the last record was written
+    pub recorded_at: Option<Timestamp>,
+}
+
+/// The snapshot the log at `log` was compacted into
+pub fn snapshot_path(log: &Path) -> PathBuf {
+    let mut path = log.as_os_str().to_owned();
+    path.push(".snapshot");
+    path.into()
+}
+
+/// The checkpoint of the snapshot of the log at `log`, the start if it was never compacted
+pub fn checkpoint(log: &Path) -> Result<Checkpoint, Box<dyn std::error::Error>> {
+    match File::open(snapshot_path(log)) {
+        Ok(file) => {
+            let header = snapshot::read_header(BufReader::new(file))?;
+            Ok(header.checkpoint.unwrap_or_default())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Checkpoint::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The snapshot of the log at `log` with its accounts configured with `config`, no accounts
+/// if it was never compacted
+pub fn open_snapshot(
+    log: &Path,
+    config: Config,
+) -> Result<(Checkpoint, Payments), Box<dyn std::error::Error>> {
+    match File::open(snapshot_path(log)) {
+        Ok(file) => {
+            let snapshot = snapshot::read(BufReader::new(file))?;
+            let payments = Payments::replay_with(config, snapshot.events)?;
+            Ok((snapshot.checkpoint.unwrap_or_default(), payments))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok((Checkpoint::default(), Payments::with_config(config)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How much of a log `replay` applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    /// The records before this offset, i.e. this many
+    Offset(u64),
+    /// The records written up to this time
+    Time(Timestamp),
+}
+
+impl FromStr for Until {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(offset) => Ok(Self::Offset(offset)),
+            Err(_) => s.parse().map(Self::Time).map_err(|_| {
+                format!(
+                    "invalid `{}`, expected a record offset or an RFC 3339 timestamp",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// Apply the transactions of the log read from `input`, from the checkpoint `payments` is at,
+/// up to `until`, returning the checkpoint reached. The records were all accepted when
+/// logged, so one being rejected means the accounts diverged, e.g. `payments` isn't
+/// configured as it was.
+pub fn replay(
+    payments: &mut Payments,
+    from: &Checkpoint,
+    input: impl BufRead,
+    until: Option<Until>,
+) -> Result<Checkpoint, Box<dyn std::error::Error>> {
+    let compacted = match until {
+        Some(Until::Offset(offset)) => offset < from.chain.len(),
+        Some(Until::Time(time)) => from.recorded_at.is_some_and(|at| at > time),
+        None => false,
+    };
+    if compacted {
+        return Err("the log was compacted past that point".into());
+    }
+    let mut end = from.clone();
+    let mut continuation = Continuation::new(from.chain.clone());
+    for line in input.lines() {
+        let line = line.map_err(|_| Error::BrokenChain(end.chain.len()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let seq = end.chain.len();
+        let Some(record) = continuation.follow(&line)? else {
+            continue;
+        };
+        let record = serde_json::from_str::<TransactionRecord>(&record)
+            .map_err(|_| Error::BrokenChain(seq))?;
+        let done = match until {
+            Some(Until::Offset(offset)) => seq >= offset,
+            Some(Until::Time(time)) => record.recorded_at > time,
+            None => false,
+        };
+        if done {
+            break;
+        }
+        payments
+            .apply(from_canonical(&record.transaction)?)
+            .map_err(|error| format!("record {} was rejected on replay: {}", seq, error))?;
+        end = Checkpoint {
+            chain: continuation.chain().clone(),
+            recorded_at: Some(record.recorded_at),
+        };
+    }
+    continuation.finish()?;
+    Ok(end)
+}
+
+/// The steps of a compaction, see `compact_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionStep {
+    /// The records were replayed onto the snapshot
+    Replayed,
+    /// The new snapshot was written next to the one it replaces
+    SnapshotWritten,
+    /// The new snapshot replaced the old one, the log is truncated next
+    SnapshotReplaced,
+}
+
+/// Fold the log at `log` into its snapshot, with the accounts configured with `config`, and
+/// truncate it, returning the snapshot's checkpoint. The snapshot is replaced atomically
+/// before the log is truncated, so an interrupted compaction loses nothing. Runs appending to
+/// the log mustn't overlap with it.
+pub fn compact(log: &Path, config: Config) -> Result<Checkpoint, Box<dyn std::error::Error>> {
+    compact_with(log, config, |_| {})
+}
+
+/// `compact`, calling `step` once every step is done, e.g. to inject crashes, see `chaos`
+pub fn compact_with(
+    log: &Path,
+    config: Config,
+    mut step: impl FnMut(CompactionStep),
+) -> Result<Checkpoint, Box<dyn std::error::Error>> {
+    drop_torn(log)?;
+    let (from, mut payments) = open_snapshot(log, config)?;
+    let records = std::fs::read(log)?;
+    let end = replay(&mut payments, &from, records.as_slice(), None)?;
+    step(CompactionStep::Replayed);
+
+    let path = snapshot_path(log);
+    let mut partial = path.clone().into_os_string();
+    partial.push(".part");
+    let mut output = std::io::BufWriter::new(File::create(&partial)?);
+    snapshot::write(Some(&end), payments.events(), &mut output)?;
+    output.into_inner()?.sync_all()?;
+    step(CompactionStep::SnapshotWritten);
+    std::fs::rename(&partial, &path)?;
+    step(CompactionStep::SnapshotReplaced);
+
+    let file = std::fs::OpenOptions::new().write(true).open(log)?;
+    if file.metadata()?.len() != records.len() as u64 {
+        return Err("records were appended while compacting, the log is left as it is".into());
+    }
+    file.set_len(0)?;
+    file.sync_all()?;
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compact, drop_torn, open_snapshot, replay, snapshot_path, Accepted, Checkpoint,
+        TransactionLog, Until,
+    };
+    use crate::{
+        error::Error,
+        hashchain::Chain,
+        parser::parse,
+        payments::{Config, Payments},
+        signature::canonical,
+    };
+
+    #[test]
+    fn accepted_transactions_only() {
+        let mut payments = Payments::default();
+        let mut accepted = Accepted::default();
+        let mut log = TransactionLog::new(Vec::new(), Chain::default());
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(
+                "type, client, tx, amount, batch
+                deposit, 1, 1, 5,
+                withdrawal, 1, 2, 9,
+                deposit, 1, 3, 1, 7
+                withdrawal, 1, 4, 9, 7
+                deposit, 2, 5, 2, 8
+                deposit, 2, 6, 3, 8"
+                    .as_bytes(),
+            );
+        for trans in parse(rdr) {
+            let trans = trans.unwrap();
+            let (entry, batch) = (canonical(&trans), trans.batch);
+            let result = payments.apply(trans);
+            for entry in accepted.record(entry, batch, &result) {
+                log.append(entry).unwrap();
+            }
+        }
+        for entry in accepted.finish() {
+            log.append(entry).unwrap();
+        }
+
+        let output = String::from_utf8(log.output.clone()).unwrap();
+        let logged = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|record| record["transaction"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            logged,
+            [
+                "deposit,1,1,5,,,,",
+                "deposit,2,5,2,,8,,",
+                "deposit,2,6,3,,8,,"
+            ]
+        );
+        assert_eq!(Chain::verify(output.as_bytes()), Ok(log.chain().clone()));
+    }
+
+    #[test]
+    fn replays_up_to_an_offset() {
+        let mut log = TransactionLog::new(Vec::new(), Chain::default());
+        for entry in [
+            "deposit,1,1,5,,,,",
+            "withdrawal,1,2,2,,,,",
+            "dispute,1,2,,,,,",
+        ] {
+            log.append(entry.to_string()).unwrap();
+        }
+
+        let start = Checkpoint::default();
+        let mut payments = Payments::default();
+        let end = replay(
+            &mut payments,
+            &start,
+            log.output.as_slice(),
+            Some(Until::Offset(2)),
+        );
+        assert_eq!(end.unwrap().chain.len(), 2);
+        assert_eq!(payments.client(1).unwrap().available(), 3.into());
+        let mut payments = Payments::default();
+        let end = replay(&mut payments, &start, log.output.as_slice(), None).unwrap();
+        assert_eq!(&end.chain, log.chain());
+        assert!(payments.client(1).unwrap().open_disputes().next().is_some());
+
+        assert_eq!("12".parse(), Ok(Until::Offset(12)));
+        assert_eq!(
+            "2024-03-31T12:00:00Z".parse(),
+            Ok(Until::Time("2024-03-31T12:00:00Z".parse().unwrap()))
+        );
+        let tampered = String::from_utf8(log.output.clone())
+            .unwrap()
+            .replace("withdrawal,1,2,2", "withdrawal,1,2,1");
+        let error = replay(&mut Payments::default(), &start, tampered.as_bytes(), None);
+        assert_eq!(
+            error.unwrap_err().downcast_ref(),
+            Some(&Error::BrokenChain(1))
+        );
+    }
+
+    #[test]
+    fn compaction() {
+        let path =
+            std::env::temp_dir().join(format!("payments-compact-{}.jsonl", std::process::id()));
+        let append = |entries: &[&str]| {
+            let mut log = TransactionLog::open(&path).unwrap();
+            for entry in entries {
+                log.append(entry.to_string()).unwrap();
+            }
+            log.chain().clone()
+        };
+        append(&["deposit,1,1,5,,,,", "deposit,2,2,3,,,,"]);
+        let records = std::fs::read(&path).unwrap();
+        let checkpoint = compact(&path, Config::default()).unwrap();
+        assert_eq!(checkpoint.chain.len(), 2);
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        // As if the compaction was interrupted before truncating the log
+        std::fs::write(&path, records).unwrap();
+        let chain = append(&["withdrawal,1,3,1,,,,"]);
+        assert_eq!(chain.len(), 3);
+
+        let (from, mut payments) = open_snapshot(&path, Config::default()).unwrap();
+        assert_eq!(from, checkpoint);
+        let input = std::fs::read(&path).unwrap();
+        let end = replay(&mut payments, &from, input.as_slice(), None).unwrap();
+        assert_eq!(end.chain, chain);
+        assert_eq!(payments.client(1).unwrap().available(), 4.into());
+        assert!(replay(
+            &mut payments,
+            &from,
+            input.as_slice(),
+            Some(Until::Offset(1))
+        )
+        .is_err());
+
+        assert_eq!(compact(&path, Config::default()).unwrap().chain, chain);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(snapshot_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn drops_a_torn_record() {
+        let path = std::env::temp_dir().join(format!("payments-torn-{}.jsonl", std::process::id()));
+        assert!(!drop_torn(&path).unwrap());
+        let mut log = TransactionLog::open(&path).unwrap();
+        log.append("deposit,1,1,5,,,,".to_string()).unwrap();
+        let records = std::fs::read(&path).unwrap();
+        let mut torn = records.clone();
+        torn.extend_from_slice(br#"{"seq":1,"prev":"#);
+        std::fs::write(&path, torn).unwrap();
+
+        let log = TransactionLog::open(&path).unwrap();
+        assert_eq!(log.chain().len(), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), records);
+        assert!(!drop_torn(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+//! The guarantees of the pipeline under injected faults: every run must end in the same
+//! state as a run without them.
+use std::{
+    fs::File,
+    io::{BufReader, Write},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::{Duration, TimeZone, Utc};
+use payments::{
+    chaos::{Chaos, CrashingWriter, Faults},
+    error::Error,
+    hashchain::Chain,
+    parser::parse,
+    payments::{Config, Payments},
+    reorder::reordered,
+    signature::canonical,
+    transaction::Transaction,
+    txlog::{
+        compact, compact_with, open_snapshot, replay, snapshot_path, CompactionStep, TransactionLog,
+    },
+};
+
+/// Random timestamped transactions of a few clients, a second apart
+fn workload(seed: u64, rows: u32) -> String {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let start = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+    let mut input = "type, client, tx, amount, timestamp\n".to_string();
+    for tx in 1..=rows {
+        let client = rng.u16(1..=5);
+        let timestamp = (start + Duration::seconds(tx.into())).to_rfc3339();
+        let earlier = rng.u32(1..=tx);
+        let row = match rng.u8(0..20) {
+            0..=7 => format!("deposit, {}, {}, {}", client, tx, rng.u32(1..100)),
+            8..=12 => format!("withdrawal, {}, {}, {}", client, tx, rng.u32(1..50)),
+            13..=15 => format!("dispute, {}, {}, ", client, earlier),
+            16..=18 => format!("resolve, {}, {}, ", client, earlier),
+            _ => format!("chargeback, {}, {}, ", client, earlier),
+        };
+        input.push_str(&format!("{}, {}\n", row, timestamp));
+    }
+    input
+}
+
+fn transactions(input: &str) -> impl Iterator<Item = Result<Transaction, Error>> + '_ {
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    parse(rdr)
+}
+
+fn apply_all(
+    payments: &mut Payments,
+    transactions: impl Iterator<Item = Result<Transaction, Error>>,
+) {
+    for transaction in transactions {
+        let _ = payments.apply(transaction.unwrap());
+    }
+}
+
+fn clean(input: &str) -> Payments {
+    let mut payments = Payments::default();
+    apply_all(&mut payments, transactions(input));
+    payments
+}
+
+fn accounts(payments: &Payments) -> String {
+    let mut output = Vec::new();
+    payments.serialize(&mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+fn assert_same_state(actual: &Payments, expected: &Payments) {
+    assert_eq!(accounts(actual), accounts(expected));
+    assert_eq!(actual.events(), expected.events());
+}
+
+#[test]
+fn duplicated_deliveries_are_applied_once() {
+    for seed in 0..10 {
+        let input = workload(seed, 300);
+        let faults = Faults {
+            seed,
+            duplicate: 0.3,
+            ..Faults::default()
+        };
+        let mut chaos = Chaos::new(transactions(&input), faults);
+        let mut payments = Payments::default();
+        apply_all(&mut payments, chaos.by_ref());
+        assert!(chaos.injected().duplicates > 0);
+        assert_same_state(&payments, &clean(&input));
+    }
+}
+
+#[test]
+fn delayed_records_are_put_back_in_order() {
+    for seed in 0..10 {
+        let input = workload(seed, 300);
+        let faults = Faults {
+            seed,
+            delay: 0.3,
+            max_delay: 5,
+            ..Faults::default()
+        };
+        let chaos = Chaos::new(transactions(&input), faults);
+        let mut payments = Payments::default();
+        apply_all(
+            &mut payments,
+            reordered(chaos, Duration::seconds(10), |late| {
+                panic!("arrived too late: {}", late)
+            }),
+        );
+        assert_same_state(&payments, &clean(&input));
+    }
+}
+
+#[test]
+fn failed_reads_roll_back_the_file() {
+    let input = workload(1, 300);
+    let mut payments = Payments::default();
+    let mut failed = 0;
+    'attempts: for attempt in 0.. {
+        let marker = payments.marker();
+        let faults = Faults {
+            seed: attempt,
+            io_error: 0.005,
+            ..Faults::default()
+        };
+        for transaction in Chaos::new(transactions(&input), faults) {
+            match transaction {
+                Ok(transaction) => {
+                    let _ = payments.apply(transaction);
+                }
+                Err(_) => {
+                    failed += 1;
+                    payments.rollback_to(marker);
+                    continue 'attempts;
+                }
+            }
+        }
+        break;
+    }
+    assert!(failed > 0);
+    assert_same_state(&payments, &clean(&input));
+}
+
+#[test]
+fn crashes_recover_from_the_last_checkpoint() {
+    const CHECKPOINT_EVERY: usize = 25;
+    let input = workload(2, 300);
+    for crash_after in [1, 24, 25, 26, 151, 299] {
+        // The event log and the number of records applied when it was exported
+        let mut checkpoint = (Vec::new(), 0);
+        let crashed = catch_unwind(AssertUnwindSafe(|| {
+            let faults = Faults {
+                crash_after: Some(crash_after),
+                ..Faults::default()
+            };
+            let mut payments = Payments::default();
+            for (applied, transaction) in Chaos::new(transactions(&input), faults).enumerate() {
+                let _ = payments.apply(transaction.unwrap());
+                if (applied + 1) % CHECKPOINT_EVERY == 0 {
+                    let mut events = Vec::new();
+                    payments.export_events(&mut events).unwrap();
+                    checkpoint = (events, applied + 1);
+                }
+            }
+        }));
+        assert!(crashed.is_err());
+
+        let (events, applied) = checkpoint;
+        let mut recovered = Payments::import_events(events.as_slice()).unwrap();
+        apply_all(&mut recovered, transactions(&input).skip(applied));
+        assert_same_state(&recovered, &clean(&input));
+    }
+}
+
+/// A transaction log in the temporary directory, removed with its snapshot when dropped
+struct TempLog(PathBuf);
+
+impl TempLog {
+    fn new(name: &str) -> Self {
+        // Unique, the tests run in parallel
+        static LOGS: AtomicUsize = AtomicUsize::new(0);
+        let id = LOGS.fetch_add(1, Ordering::Relaxed);
+        let name = format!(
+            "payments-chaos-{}-{}-{}.jsonl",
+            name,
+            std::process::id(),
+            id
+        );
+        let log = Self(std::env::temp_dir().join(name));
+        log.remove();
+        log
+    }
+
+    fn remove(&self) {
+        let snapshot = snapshot_path(&self.0);
+        let mut partial = snapshot.clone().into_os_string();
+        partial.push(".part");
+        for path in [self.0.clone(), snapshot, partial.into()] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for TempLog {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Apply `transactions`, appending the accepted ones to `log`
+fn apply_logged(
+    payments: &mut Payments,
+    transactions: impl Iterator<Item = Result<Transaction, Error>>,
+    log: &mut TransactionLog<impl Write>,
+) {
+    for transaction in transactions {
+        let transaction = transaction.unwrap();
+        let entry = canonical(&transaction);
+        if payments.apply(transaction).is_ok() {
+            log.append(entry).unwrap();
+        }
+    }
+    log.flush().unwrap();
+}
+
+/// The state rebuilt from the log at `path` and its snapshot
+fn recover(path: &Path) -> Payments {
+    let (from, mut payments) = open_snapshot(path, Config::default()).unwrap();
+    let input = BufReader::new(File::open(path).unwrap());
+    replay(&mut payments, &from, input, None).unwrap();
+    payments
+}
+
+/// The state rebuilt from a log of `input` written without crashes, and the size of the log.
+/// It doesn't have the events of rejected transactions, which aren't logged, e.g. of clients
+/// created by them.
+fn logged(input: &str) -> (Payments, usize) {
+    let log = TempLog::new("logged");
+    let mut txlog = TransactionLog::open(&log.0).unwrap();
+    apply_logged(&mut Payments::default(), transactions(input), &mut txlog);
+    let size = std::fs::metadata(&log.0).unwrap().len();
+    (recover(&log.0), size as usize)
+}
+
+/// The transactions of `input` after the first `accepted` ones which were accepted, where
+/// a run recovered from its log picks up the input again
+fn after_accepted(input: &str, accepted: u64) -> Vec<Result<Transaction, Error>> {
+    let mut payments = Payments::default();
+    let mut rest = transactions(input);
+    let mut applied = 0;
+    while applied < accepted {
+        if payments.apply(rest.next().unwrap().unwrap()).is_ok() {
+            applied += 1;
+        }
+    }
+    rest.collect()
+}
+
+#[test]
+fn crashes_mid_append_lose_only_the_torn_record() {
+    let input = workload(3, 200);
+    let (expected, size) = logged(&input);
+
+    let log = TempLog::new("append");
+    let mut rng = fastrand::Rng::with_seed(3);
+    let crashes = [0, 1, 150]
+        .into_iter()
+        .chain((0..10).map(|_| rng.usize(..size * 9 / 10)));
+    for limit in crashes {
+        log.remove();
+        let crashed = catch_unwind(AssertUnwindSafe(|| {
+            let output = CrashingWriter::new(File::create(&log.0).unwrap(), limit);
+            let mut txlog = TransactionLog::new(output, Chain::default());
+            apply_logged(&mut Payments::default(), transactions(&input), &mut txlog);
+        }));
+        assert!(crashed.is_err());
+
+        // Reopened, the torn record is dropped and the chain goes on from the last whole one
+        let mut txlog = TransactionLog::open(&log.0).unwrap();
+        let mut recovered = recover(&log.0);
+        let rest = after_accepted(&input, txlog.chain().len());
+        apply_logged(&mut recovered, rest.into_iter(), &mut txlog);
+        assert_eq!(accounts(&recovered), accounts(&clean(&input)));
+        // Every accepted transaction is logged once
+        assert_same_state(&recover(&log.0), &expected);
+    }
+}
+
+#[test]
+fn crashes_mid_compaction_keep_the_state() {
+    let input = workload(4, 300);
+    let (expected, _) = logged(&input);
+    let rows = input.lines().skip(1).collect::<Vec<_>>();
+    let header = input.lines().next().unwrap();
+    let chunks = rows
+        .chunks(100)
+        .map(|chunk| format!("{}\n{}\n", header, chunk.join("\n")))
+        .collect::<Vec<_>>();
+    let steps = [
+        CompactionStep::Replayed,
+        CompactionStep::SnapshotWritten,
+        CompactionStep::SnapshotReplaced,
+    ];
+    for crash_at in steps {
+        let log = TempLog::new("compact");
+        let mut payments = Payments::default();
+        let append = |payments: &mut Payments, chunk: &str| {
+            let mut txlog = TransactionLog::open(&log.0).unwrap();
+            apply_logged(payments, transactions(chunk), &mut txlog);
+        };
+        append(&mut payments, &chunks[0]);
+        compact(&log.0, Config::default()).unwrap();
+        append(&mut payments, &chunks[1]);
+        let before = recover(&log.0);
+        let crashed = catch_unwind(|| {
+            compact_with(&log.0, Config::default(), |step| {
+                if step == crash_at {
+                    panic!("injected crash at {:?}", step);
+                }
+            })
+        });
+        assert!(crashed.is_err());
+        assert_same_state(&recover(&log.0), &before);
+
+        // The next compaction completes it
+        compact(&log.0, Config::default()).unwrap();
+        assert!(std::fs::read(&log.0).unwrap().is_empty());
+        assert_same_state(&recover(&log.0), &before);
+        append(&mut payments, &chunks[2]);
+        assert_eq!(accounts(&payments), accounts(&clean(&input)));
+        assert_same_state(&recover(&log.0), &expected);
+    }
+}
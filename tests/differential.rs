@@ -0,0 +1,97 @@
+//! The engine against the reference implementation on random workloads: every transaction
+//! must be accepted or rejected by both, and leave its client with the same balances.
+//! `PAYMENTS_DIFFERENTIAL_SEEDS` runs more workloads than the default, e.g. before merging
+//! changes of the engine.
+use payments::{
+    parser::parse,
+    payments::Payments,
+    reference::{Account, Reference},
+    transaction::Transaction,
+};
+
+const SEEDS: u64 = 20;
+
+/// Random transactions of a few clients, with reused IDs, disputes and amendments of other
+/// clients' transactions, transfers between sub-accounts and amounts of up to four decimal
+/// places
+fn workload(seed: u64, rows: u32) -> Vec<Transaction> {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let mut input = "type, client, tx, amount, from_account, to_account\n".to_string();
+    let pockets = ["main", "savings", "holiday"];
+    for tx in 1..=rows {
+        let client = rng.u16(1..=8);
+        let amount = format!("{}.{:04}", rng.u32(0..100), rng.u32(0..10_000));
+        let earlier = rng.u32(1..=tx);
+        let row = match rng.u8(0..24) {
+            0..=6 => format!("deposit, {}, {}, {}, ,", client, tx, amount),
+            7 => format!("deposit, {}, {}, {}, ,", client, earlier, amount),
+            8..=11 => format!("withdrawal, {}, {}, {}, ,", client, tx, amount),
+            12..=14 => format!("dispute, {}, {}, , ,", client, earlier),
+            15..=17 => format!("resolve, {}, {}, , ,", client, earlier),
+            18..=19 => format!("chargeback, {}, {}, , ,", client, earlier),
+            20..=21 => format!("amend, {}, {}, {}, ,", client, earlier, amount),
+            22 => format!("settle, {}, {}, , ,", client, earlier),
+            _ => {
+                let from = rng.usize(..pockets.len());
+                let to = (from + rng.usize(1..pockets.len())) % pockets.len();
+                format!(
+                    "transfer, {}, {}, {}, {}, {}",
+                    client, tx, amount, pockets[from], pockets[to]
+                )
+            }
+        };
+        input.push_str(&row);
+        input.push('\n');
+    }
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    parse(rdr).map(Result::unwrap).collect()
+}
+
+fn account(payments: &Payments, client: u16) -> Option<Account> {
+    payments.client(client).map(|client| Account {
+        available: client.available(),
+        held: client.held(),
+        total: client.total(),
+        locked: client.locked(),
+    })
+}
+
+#[test]
+fn engine_matches_reference() {
+    let seeds = std::env::var("PAYMENTS_DIFFERENTIAL_SEEDS")
+        .map(|seeds| seeds.parse().expect("number of seeds"))
+        .unwrap_or(SEEDS);
+    for seed in 0..seeds {
+        let mut payments = Payments::default();
+        let mut reference = Reference::default();
+        for (row, transaction) in workload(seed, 500).into_iter().enumerate() {
+            let expected = reference.apply(&transaction);
+            let client = transaction.client_id;
+            let actual = payments.apply(transaction.clone());
+            assert_eq!(
+                actual.is_ok(),
+                expected.is_ok(),
+                "seed {}, row {}: {:?}, engine: {:?}, reference: {:?}",
+                seed,
+                row + 1,
+                transaction,
+                actual,
+                expected
+            );
+            assert_eq!(
+                account(&payments, client),
+                Some(reference.account(client)),
+                "seed {}, row {}: {:?}",
+                seed,
+                row + 1,
+                transaction
+            );
+        }
+        for client in reference.clients() {
+            assert_eq!(account(&payments, client), Some(reference.account(client)));
+        }
+        assert_eq!(payments.stats().clients, reference.clients().count());
+    }
+}
@@ -1,20 +1,32 @@
-use payments::{parser::parse, payments::Payments};
+use std::sync::{Arc, Mutex};
 
-fn process_and_dump(input: &str) -> String {
-    let mut payments = Payments::default();
-
-    let rdr = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(input.as_bytes());
-
-    for trans in parse(rdr) {
-        let _ = payments.apply(trans.unwrap()); // ignore errors
-    }
-
-    let mut output = Vec::<u8>::new();
-    payments.serialize(&mut output).unwrap();
-    String::from_utf8(output).unwrap()
-}
+use payments::{
+    access::{Access, ClientList},
+    approval::{AdminAction, Approval},
+    audit::{Actor, AuditLog},
+    client::{DisputePolicy, WithdrawalChargeback},
+    credit::CreditLimits,
+    dedup::{DedupConfig, DedupScope},
+    error::Error,
+    event::Event,
+    hashchain::Chain,
+    merkle::MerkleTree,
+    money::Currency,
+    ordering::Sequence,
+    parser::parse,
+    payments::{Config, Marker, Partition, Payments},
+    reserve::Reserves,
+    settlement::{instructions, write_csv},
+    signature::{canonical, to_hex, SigningKey},
+    tenant::Tenants,
+    testing::{
+        assert_golden, dump, process, process_and_dump, process_with_config, replay_corpus,
+        Normalize, UPDATE_GOLDEN,
+    },
+    transaction::Transaction,
+    txlog::{Accepted, TransactionLog},
+};
+use rust_decimal_macros::dec;
 
 #[test]
 fn empty() {
@@ -146,3 +158,2079 @@ fn sophisticated() {
         .replace(' ', "")
     );
 }
+
+#[test]
+fn replay_event_log() {
+    let payments = process(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 1
+        deposit, 2, 3, 10.1234
+        withdrawal, 1, 2, 1
+        deposit, 1, 4, 0.6666
+        dispute, 1, 2,
+        chargeback, 1, 2,
+        deposit, 3, 5, 1.7777
+        dispute, 3, 5,
+        deposit, 1, 5, 2
+        withdrawal, 4, 6, 1"#,
+    );
+    // Created by its failed withdrawal
+    assert!(payments.client(4).is_some());
+
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert_eq!(dump(&replayed), dump(&payments));
+    assert_eq!(replayed.events(), payments.events());
+
+    let mut log = Vec::<u8>::new();
+    payments.export_events(&mut log).unwrap();
+    let imported = Payments::import_events(log.as_slice()).unwrap();
+    assert_eq!(dump(&imported), dump(&payments));
+    assert_eq!(imported.events(), payments.events());
+}
+
+#[test]
+fn balances_as_of() {
+    let payments = process(
+        r#"type,client,tx,amount,timestamp
+        deposit, 1, 1, 10, 2024-03-30T10:00:00Z
+        deposit, 2, 2, 5, 2024-03-31T10:00:00Z
+        dispute, 1, 1, ,
+        withdrawal, 2, 3, 1, 2024-04-01T00:00:00Z
+        resolve, 1, 1, , 2024-04-02T00:00:00Z"#,
+    );
+    let as_of = "2024-03-31T23:59:59Z".parse().unwrap();
+
+    assert_eq!(
+        dump(&payments.as_of(as_of).unwrap()),
+        r#"client,available,held,total,locked
+        1, 0, 10, 10, false
+        2, 5, 0, 5, false
+        "#
+        .replace(' ', "")
+    );
+    let client = payments.balance_at(2, as_of).unwrap();
+    assert_eq!(
+        (client.available(), client.held(), client.total()),
+        (5.into(), 0.into(), 5.into())
+    );
+    assert_eq!(dump(&payments.as_of(Default::default()).unwrap()), "");
+}
+
+#[test]
+fn rollback() {
+    let mut payments = process(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 10
+        deposit, 2, 2, 5"#,
+    );
+    let marker = payments.marker();
+
+    let rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(
+        r#"type,client,tx,amount
+            withdrawal, 1, 3, 2
+            withdrawal, 1, 4, 100
+            dispute, 2, 2,
+            chargeback, 2, 2,"#
+            .as_bytes(),
+    );
+    for trans in parse(rdr) {
+        let _ = payments.apply(trans.unwrap());
+    }
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 8, 0, 8, false
+        2, 0, 0, 0, true
+        "#
+        .replace(' ', "")
+    );
+
+    // The failed withdrawal doesn't count
+    assert_eq!(payments.rollback(2), 2);
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 8, 0, 8, false
+        2, 5, 0, 5, false
+        "#
+        .replace(' ', "")
+    );
+
+    payments.rollback_to(marker);
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 10, 0, 10, false
+        2, 5, 0, 5, false
+        "#
+        .replace(' ', "")
+    );
+    assert_eq!(payments.rollback(10), 2);
+    assert_eq!(payments.events(), []);
+}
+
+#[test]
+fn rollback_to_restores_the_read_models() {
+    let dir = std::env::temp_dir().join(format!("payments-rollback-{}", std::process::id()));
+    let config = Config {
+        dedup_scope: DedupScope::Global(DedupConfig {
+            confirmation_dir: Some(dir.clone()),
+            ..DedupConfig::default()
+        }),
+        withdrawal_chargeback: WithdrawalChargeback::WriteOff,
+        ..Config::default()
+    };
+    let apply = |payments: &mut Payments, input: &str| {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        for trans in parse(rdr) {
+            let _ = payments.apply(trans.unwrap());
+        }
+    };
+    let mut payments = process_with_config(
+        r#"type,client,tx,amount,timestamp
+        deposit, 1, 1, 10, 2024-01-01T00:00:00Z
+        deposit, 2, 2, 10, 2024-01-01T00:00:00Z
+        dispute, 1, 1, , 2024-01-02T00:00:00Z
+        withdrawal, 2, 3, 4, 2024-01-03T00:00:00Z"#,
+        config,
+    );
+    let expected = (
+        dump(&payments),
+        payments.stats(),
+        payments.events().to_vec(),
+    );
+    let marker = payments.marker();
+    let segments = || {
+        std::fs::read_dir(
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .path(),
+        )
+        .unwrap()
+        .count()
+    };
+    let written = segments();
+
+    apply(
+        &mut payments,
+        r#"type,client,tx,amount,timestamp
+        resolve, 1, 1, , 2024-01-04T00:00:00Z
+        dispute, 2, 3, , 2024-01-05T00:00:00Z
+        chargeback, 2, 3, , 2024-01-06T00:00:00Z
+        deposit, 3, 4, 1, 2024-01-07T00:00:00Z"#,
+    );
+    assert_eq!(payments.stats().written_off, dec!(4));
+    payments.rollback_to(marker);
+    let stats = payments.stats();
+    assert_eq!(
+        (dump(&payments), stats.open_disputes, stats.written_off),
+        (expected.0, expected.1.open_disputes, expected.1.written_off)
+    );
+    assert_eq!(payments.events(), expected.2);
+    // The segments on disk were kept, and the rolled back ID can be used again
+    assert!(segments() >= written);
+    assert_eq!(
+        payments.apply(Transaction::deposit(3, 4, dec!(1)).unwrap()),
+        Ok(())
+    );
+    assert_eq!(payments.stats().clients, 3);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn batches() {
+    assert_eq!(
+        process_and_dump(
+            r#"type,client,tx,amount,timestamp,batch
+            deposit, 1, 1, 10, , 1
+            deposit, 2, 2, 10, , 1
+            withdrawal, 1, 3, 5, , 2
+            deposit, 2, 4, 5, , 2
+            withdrawal, 2, 5, 100, , 2
+            deposit, 2, 6, 1, , 2
+            deposit, 3, 7, 1, ,
+            deposit, 1, 8, 1, , 2"#
+        ),
+        r#"client,available,held,total,locked
+        1, 11, 0, 11, false
+        2, 10, 0, 10, false
+        3, 1, 0, 1, false
+        "#
+        .replace(' ', "")
+    );
+}
+
+#[test]
+fn expired_disputes_are_released() {
+    let mut payments = process_with_config(
+        r#"type,client,tx,amount,timestamp
+        deposit, 1, 1, 10, 2024-01-01T00:00:00Z
+        deposit, 2, 2, 10, 2024-01-01T00:00:00Z
+        dispute, 1, 1, , 2024-01-02T00:00:00Z
+        dispute, 2, 2, , 2024-01-20T00:00:00Z
+        deposit, 3, 3, 1, 2024-02-05T00:00:00Z"#,
+        Config {
+            dispute_timeout: Some(chrono::Duration::days(30)),
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 10, 0, 10, false
+        2, 0, 10, 10, false
+        3, 1, 0, 1, false
+        "#
+        .replace(' ', "")
+    );
+
+    let mut register = Vec::<u8>::new();
+    payments.serialize_disputes(&mut register).unwrap();
+    assert_eq!(
+        String::from_utf8(register).unwrap(),
+        r#"client,tx,amount,held_since,age_days
+        2,2,10,2024-01-20T00:00:00Z,16
+        "#
+        .replace(' ', "")
+    );
+
+    // Released exactly when the dispute expired
+    let as_of = "2024-01-31T23:59:59Z".parse().unwrap();
+    assert_eq!(payments.balance_at(1, as_of).unwrap().held(), 10.into());
+    let as_of = "2024-02-01T00:00:00Z".parse().unwrap();
+    assert_eq!(payments.balance_at(1, as_of).unwrap().held(), 0.into());
+
+    // The release isn't a transaction, the last two undone are the dispute and the
+    // deposit around it, the release recorded in between goes with them
+    assert_eq!(payments.rollback(2), 2);
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 0, 10, 10, false
+        2, 10, 0, 10, false
+        "#
+        .replace(' ', "")
+    );
+}
+
+#[test]
+fn risky_withdrawals_are_blocked() {
+    let payments = process_with_config(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 10
+        deposit, 1, 2, 10
+        dispute, 1, 1,
+        resolve, 1, 1,
+        withdrawal, 1, 3, 5
+        deposit, 2, 4, 10
+        withdrawal, 2, 5, 5"#,
+        Config {
+            max_risk_score: Some(10.0),
+            risk_score_column: true,
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked,risk_score
+        1, 20, 0, 20, false, 15.0
+        2, 5, 0, 5, false, 0.0
+        "#
+        .replace(' ', "")
+    );
+}
+
+#[test]
+fn globally_unique_transaction_ids() {
+    let input = r#"type,client,tx,amount
+        deposit, 1, 1, 10
+        deposit, 2, 1, 10
+        withdrawal, 2, 2, 5"#;
+    let config = Config {
+        dedup_scope: DedupScope::Global(DedupConfig::default()),
+        ..Config::default()
+    };
+    assert_eq!(
+        dump(&process_with_config(input, config)),
+        r#"client,available,held,total,locked
+        1, 10, 0, 10, false
+        2, 0, 0, 0, false
+        "#
+        .replace(' ', "")
+    );
+}
+
+#[test]
+fn partitioned_output() {
+    let payments = process(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 1
+        deposit, 2, 2, 2
+        deposit, 3, 3, 3
+        dispute, 3, 3,
+        chargeback, 3, 3,"#,
+    );
+    let dir = std::env::temp_dir().join(format!("payments-partitions-{}", std::process::id()));
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+
+    payments
+        .serialize_partitioned(&dir, Partition::ClientShard(2))
+        .unwrap();
+    assert_eq!(
+        read("shard-000.csv"),
+        "client,available,held,total,locked\n2,2,0,2,false\n"
+    );
+    assert_eq!(
+        read("shard-001.csv"),
+        "client,available,held,total,locked\n1,1,0,1,false\n3,0,0,0,true\n"
+    );
+
+    payments
+        .serialize_partitioned(&dir, Partition::Locked)
+        .unwrap();
+    assert_eq!(
+        read("locked.csv"),
+        "client,available,held,total,locked\n3,0,0,0,true\n"
+    );
+    assert_eq!(
+        read("unlocked.csv"),
+        "client,available,held,total,locked\n1,1,0,1,false\n2,2,0,2,false\n"
+    );
+
+    payments
+        .serialize_partitioned(&dir, Partition::Currency)
+        .unwrap();
+    assert_eq!(
+        read("EUR.csv"),
+        "client,available,held,total,locked\n1,1,0,1,false\n2,2,0,2,false\n3,0,0,0,true\n"
+    );
+    let usd = process_with_config(
+        "type,client,tx,amount\ndeposit,4,4,4",
+        Config {
+            currency: Currency::USD,
+            ..Config::default()
+        },
+    );
+    usd.serialize_partitioned(&dir, Partition::Currency)
+        .unwrap();
+    assert_eq!(
+        read("USD.csv"),
+        "client,available,held,total,locked\n4,4,0,4,false\n"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sharded_snapshot() {
+    let payments = process(
+        r#"type,client,tx,amount,timestamp
+        deposit, 1, 1, 1, 2024-03-01T00:00:00Z
+        deposit, 2, 2, 2, 2024-03-02T00:00:00Z
+        deposit, 3, 3, 3, 2024-03-03T00:00:00Z
+        withdrawal, 1, 4, 1, 2024-03-04T00:00:00Z
+        dispute, 3, 3, , 2024-03-05T00:00:00Z
+        chargeback, 3, 3, ,"#,
+    );
+    let dir = std::env::temp_dir().join(format!("payments-sharded-{}", std::process::id()));
+
+    payments.export_sharded(&dir, 2).unwrap();
+    // Exporting again replaces the shards
+    payments.export_sharded(&dir, 2).unwrap();
+    let shards = std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(shards, 2);
+    let imported = Payments::import_sharded_with(Config::default(), &dir).unwrap();
+    assert_eq!(dump(&imported), dump(&payments));
+    // Interleaved in the order they happened
+    assert_eq!(imported.events(), payments.events());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn memory_limit() {
+    let input = std::iter::once("type,client,tx,amount".to_string())
+        .chain((0..5000).map(|tx| format!("deposit,{},{},1", tx % 100, tx)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let payments = process(&input);
+    let stats = payments.stats();
+    assert_eq!(
+        (
+            stats.clients,
+            stats.operations,
+            stats.events,
+            stats.open_disputes
+        ),
+        (100, 5000, 5000, 0)
+    );
+    assert!(stats.memory_bytes > 5000 * std::mem::size_of::<payments::event::ClientEvent>());
+
+    let limit = stats.memory_bytes / 4;
+    let mut limited = Payments::with_config(Config {
+        max_memory: Some(limit),
+        ..Config::default()
+    });
+    let rdr = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+    let error = parse(rdr)
+        .map(|trans| limited.apply(trans.unwrap()))
+        .find_map(Result::err);
+    assert!(matches!(
+        error,
+        Some(Error::MemoryLimitExceeded { limit: l, .. }) if l == limit
+    ));
+}
+
+#[test]
+fn create_clients_on_success() {
+    let payments = process_with_config(
+        r#"type,client,tx,amount
+        withdrawal, 1, 1, 1
+        dispute, 2, 1,
+        deposit, 3, 2, 1
+        withdrawal, 3, 3, 5"#,
+        Config {
+            create_clients_on_success: true,
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        3, 1, 0, 1, false
+        "#
+        .replace(' ', "")
+    );
+}
+
+#[test]
+fn skip_empty_accounts() {
+    let payments = process_with_config(
+        r#"type,client,tx,amount
+        dispute, 1, 4,
+        deposit, 2, 1, 1
+        withdrawal, 2, 2, 1
+        withdrawal, 3, 3, 1"#,
+        Config {
+            skip_empty_accounts: true,
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        2, 0, 0, 0, false
+        "#
+        .replace(' ', "")
+    );
+}
+
+#[test]
+fn preview() {
+    let payments = process(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 5
+        dispute, 1, 1,"#,
+    );
+    let transaction = |row: &str| {
+        let input = format!("type,client,tx,amount\n{}", row);
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        let mut transactions = parse(rdr);
+        transactions.next().unwrap().unwrap()
+    };
+
+    let preview = payments.preview(&transaction("resolve, 1, 1,")).unwrap();
+    assert_eq!(
+        (
+            preview.available,
+            preview.held,
+            preview.total,
+            preview.locked
+        ),
+        (dec!(5), dec!(0), dec!(5), false)
+    );
+    assert_eq!(
+        preview.events,
+        [Event::FundsReleased {
+            tx: 1,
+            amount: dec!(5),
+            pending: false,
+        }]
+    );
+    let preview = payments.preview(&transaction("chargeback, 1, 1,")).unwrap();
+    assert_eq!((preview.total, preview.locked), (dec!(0), true));
+    assert_eq!(
+        payments.preview(&transaction("withdrawal, 1, 2, 1")),
+        Err(Error::InsufficientFunds {
+            id: 2,
+            available: dec!(0),
+            requested: dec!(1)
+        })
+    );
+    assert_eq!(
+        payments
+            .preview(&transaction("deposit, 2, 3, 1"))
+            .unwrap()
+            .total,
+        dec!(1)
+    );
+
+    // Nothing was applied
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 0, 5, 5, false
+        "#
+        .replace(' ', "")
+    );
+}
+
+#[test]
+fn fork() {
+    let payments = process(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 5
+        deposit, 2, 2, 3"#,
+    );
+    let mut fork = payments.clone();
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader("type,client,tx,amount\nwithdrawal,1,3,2\ndeposit,3,4,1".as_bytes());
+    for trans in parse(rdr) {
+        fork.apply(trans.unwrap()).unwrap();
+    }
+    assert_eq!(
+        dump(&fork),
+        r#"client,available,held,total,locked
+        1, 3, 0, 3, false
+        2, 3, 0, 3, false
+        3, 1, 0, 1, false
+        "#
+        .replace(' ', "")
+    );
+    assert_eq!(fork.events().len(), 4);
+
+    // The original is untouched
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 5, 0, 5, false
+        2, 3, 0, 3, false
+        "#
+        .replace(' ', "")
+    );
+    assert_eq!(payments.events().len(), 2);
+    assert_eq!(fork.rollback(2), 2);
+    assert_eq!(fork.client(1), payments.client(1));
+    assert_eq!(fork.events(), payments.events());
+}
+
+#[test]
+fn withdrawal_chargeback_write_off() {
+    let input = r#"type,client,tx,amount
+        deposit, 1, 1, 10
+        withdrawal, 1, 2, 4
+        dispute, 1, 2,
+        chargeback, 1, 2,"#;
+    let credited = process(input);
+    assert_eq!(
+        dump(&credited),
+        r#"client,available,held,total,locked
+        1, 10, 0, 10, true
+        "#
+        .replace(' ', "")
+    );
+    assert_eq!(credited.written_off(), dec!(0));
+
+    let written_off = process_with_config(
+        input,
+        Config {
+            withdrawal_chargeback: WithdrawalChargeback::WriteOff,
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&written_off),
+        r#"client,available,held,total,locked
+        1, 6, 0, 6, true
+        "#
+        .replace(' ', "")
+    );
+    assert_eq!(written_off.written_off(), dec!(4));
+    assert_eq!(written_off.stats().written_off, dec!(4));
+}
+
+#[test]
+fn tenants_keep_transaction_ids_apart() {
+    let config = Config {
+        dedup_scope: DedupScope::Global(DedupConfig::default()),
+        ..Config::default()
+    };
+    let mut tenants = Tenants::new(config, "brand-a".to_string());
+    let rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(
+        "type, client, tx, amount, tenant
+        deposit, 1, 1, 5,
+        deposit, 2, 1, 3, brand-b
+        deposit, 2, 1, 4, brand-a"
+            .as_bytes(),
+    );
+    let results = parse(rdr)
+        .map(|trans| tenants.apply(trans.unwrap()))
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        results[..],
+        [Ok(()), Ok(()), Err(Error::DuplicatedTransaction(1))]
+    ));
+    assert_eq!(
+        tenants
+            .tenant("brand-b")
+            .unwrap()
+            .client(2)
+            .unwrap()
+            .total(),
+        dec!(3)
+    );
+}
+
+#[test]
+fn signed_rows() {
+    let key = SigningKey::new("secret");
+    let unsigned = "type, client, tx, amount, batch
+        deposit, 1, 1, 5,
+        deposit, 1, 2, 1, 7
+        deposit, 1, 3, 2, 7";
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(unsigned.as_bytes());
+    let signatures = parse(rdr)
+        .map(|trans| to_hex(&key.sign(&trans.unwrap()).unwrap()))
+        .collect::<Vec<_>>();
+    // The amount of the last row is tampered with, failing its batch
+    let signed = format!(
+        "type, client, tx, amount, batch, signature
+        deposit, 1, 1, 5.00, , {}
+        deposit, 1, 2, 1, 7, {}
+        deposit, 1, 3, 20, 7, {}
+        deposit, 1, 4, 1, ,",
+        signatures[0], signatures[1], signatures[2]
+    );
+    let config = Config {
+        signing_key: Some(key),
+        ..Config::default()
+    };
+    let mut payments = Payments::with_config(config);
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(signed.as_bytes());
+    let results = parse(rdr)
+        .map(|trans| payments.apply(trans.unwrap()))
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        &results[..],
+        [Ok(()), Ok(()), Err(Error::BatchRolledBack { reason, .. }), Err(Error::InvalidSignature(4))]
+            if **reason == Error::InvalidSignature(3)
+    ));
+    assert_eq!(payments.client(1).unwrap().total(), dec!(5));
+}
+
+#[test]
+fn operator_actions() {
+    // Deposit 2 is still disputed when the account is locked by the chargeback of 1
+    let mut payments = process(
+        "type, client, tx, amount
+        deposit, 1, 1, 5
+        deposit, 1, 2, 3
+        dispute, 1, 2,
+        dispute, 1, 1,
+        chargeback, 1, 1,",
+    );
+    let resolve = AdminAction::ForceResolve { tx: 2 };
+    assert_eq!(
+        payments.act(2, resolve, 7, None),
+        Err(Error::ClientNotFound(2))
+    );
+    assert_eq!(
+        payments.act(1, resolve, 7, None),
+        Ok(Approval::Taken { amount: dec!(3) })
+    );
+    assert_eq!(
+        payments.act(1, AdminAction::Unlock, 7, None),
+        Ok(Approval::Taken { amount: dec!(3) })
+    );
+    assert_eq!(
+        payments.act(1, AdminAction::Unlock, 7, None),
+        Err(Error::AccountNotLocked(1))
+    );
+    assert_eq!(
+        dump(&payments),
+        r#"client,available,held,total,locked
+        1, 3, 0, 3, false
+        "#
+        .replace(' ', "")
+    );
+    assert_eq!(payments.stats().open_disputes, 0);
+    assert_eq!(
+        payments.events().last().map(|e| e.event),
+        Some(Event::AccountUnlocked)
+    );
+    // The unlock survives a replay of the event log
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert_eq!(replayed.client(1), payments.client(1));
+}
+
+#[test]
+fn value_dates() {
+    let input = "type, client, tx, amount, timestamp, value_date
+        deposit, 1, 1, 10, 2024-03-29T12:00:00Z, 2024-04-02
+        deposit, 1, 2, 5, 2024-03-30T12:00:00Z, 2024-03-30
+        withdrawal, 1, 3, 6, 2024-03-31T12:00:00Z,
+        deposit, 2, 4, 3, , 2024-04-02";
+    let config = Config {
+        pending_column: true,
+        ..Config::default()
+    };
+    let mut payments = process_with_config(input, config);
+    let client = payments.client(1).unwrap();
+    assert_eq!(
+        (client.available(), client.pending(), client.total()),
+        (dec!(5), dec!(10), dec!(15))
+    );
+    // The withdrawal is rejected, pending funds aren't available
+    assert_eq!(payments.events().len(), 4);
+    // Without a timestamp, the value date is ignored
+    assert_eq!(payments.client(2).unwrap().available(), dec!(3));
+    assert_eq!(
+        dump(&payments),
+        [
+            "client,available,held,total,locked,pending",
+            "1,5,0,15,false,10",
+            "2,3,0,3,false,0",
+            ""
+        ]
+        .join("\n")
+    );
+
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    payments
+        .apply(
+            Transaction::withdrawal(1, 5, dec!(6))
+                .unwrap()
+                .with_timestamp("2024-04-02T00:00:00Z".parse().unwrap()),
+        )
+        .unwrap();
+    let client = payments.client(1).unwrap();
+    assert_eq!(
+        (client.available(), client.pending(), client.total()),
+        (dec!(9), dec!(0), dec!(9))
+    );
+    assert!(matches!(
+        payments.events()[4].event,
+        Event::FundsCleared { tx: 1, amount } if amount == dec!(10)
+    ));
+
+    // The pending deposits are rebuilt from the event log
+    let mut replayed = replayed;
+    assert_eq!(
+        replayed.advance_to("2024-04-02T00:00:00Z".parse().unwrap()),
+        0
+    );
+    assert_eq!(replayed.client(1).unwrap().available(), dec!(15));
+}
+
+#[test]
+fn settlement_delay() {
+    let input = "type, client, tx, amount, timestamp
+        deposit, 1, 1, 10, 2024-03-01T12:00:00Z
+        deposit, 1, 2, 5, 2024-03-01T13:00:00Z
+        withdrawal, 1, 3, 1, 2024-03-01T14:00:00Z
+        settle, 1, 1, , 2024-03-02T00:00:00Z
+        dispute, 1, 2, , 2024-03-02T01:00:00Z
+        deposit, 1, 4, 3, 2024-03-02T02:00:00Z
+        deposit, 2, 5, 7,";
+    let config = Config {
+        settlement_delay: Some(chrono::Duration::days(2)),
+        ..Config::default()
+    };
+    let mut payments = process_with_config(input, config);
+    // The dispute holds the pending funds of its deposit
+    let client = payments.client(1).unwrap();
+    assert_eq!(
+        (client.available(), client.held(), client.pending()),
+        (dec!(10), dec!(5), dec!(3))
+    );
+    assert_eq!(client.total(), dec!(18));
+    assert!(!client.operations().any(|op| op.tx == 3));
+
+    assert_eq!(
+        payments.apply(Transaction::amend(1, 4, dec!(4)).unwrap()),
+        Err(Error::AmendmentOfPending(4))
+    );
+    assert_eq!(
+        payments.apply(Transaction::settle(1, 6)),
+        Err(Error::TransactionNotFound(6))
+    );
+    // Settled already
+    assert_eq!(payments.apply(Transaction::settle(1, 1)), Ok(()));
+
+    let at = |timestamp: &str| timestamp.parse().unwrap();
+    payments.advance_to(at("2024-03-04T01:59:59Z"));
+    assert_eq!(payments.client(1).unwrap().pending(), dec!(3));
+    payments.advance_to(at("2024-03-04T02:00:00Z"));
+    let client = payments.client(1).unwrap();
+    assert_eq!((client.available(), client.pending()), (dec!(13), dec!(0)));
+
+    // Without a timestamp, only settling makes the funds available
+    payments.advance_to(at("2025-01-01T00:00:00Z"));
+    assert_eq!(payments.client(2).unwrap().pending(), dec!(7));
+    payments.apply(Transaction::settle(2, 5)).unwrap();
+    assert_eq!(payments.client(2).unwrap().available(), dec!(7));
+
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert_eq!(replayed.client(1), payments.client(1));
+}
+
+#[test]
+fn disputed_pending_deposit() {
+    let input = "type, client, tx, amount, timestamp
+        deposit, 1, 1, 10, 2024-03-01T12:00:00Z
+        dispute, 1, 1, , 2024-03-01T13:00:00Z
+        resolve, 1, 1, , 2024-03-01T14:00:00Z
+        withdrawal, 1, 2, 5, 2024-03-01T15:00:00Z
+        deposit, 3, 4, 10, 2024-03-01T12:00:00Z
+        dispute, 3, 4, , 2024-03-01T13:00:00Z
+        deposit, 2, 3, 5, 2024-03-01T12:00:00Z
+        dispute, 2, 3, , 2024-03-01T13:00:00Z
+        chargeback, 2, 3, , 2024-03-01T14:00:00Z";
+    let config = Config {
+        settlement_delay: Some(chrono::Duration::days(2)),
+        ..Config::default()
+    };
+    let mut payments = process_with_config(input, config);
+    // The resolve releases the funds back to pending, the withdrawal is rejected
+    let client = payments.client(1).unwrap();
+    assert_eq!(
+        (client.available(), client.held(), client.pending()),
+        (dec!(0), dec!(0), dec!(10))
+    );
+    assert!(!client.operations().any(|op| op.tx == 2));
+
+    // Cleared while disputed, the funds stay held until the resolve
+    payments.advance_to("2024-03-03T12:00:00Z".parse().unwrap());
+    assert_eq!(payments.client(1).unwrap().available(), dec!(10));
+    let client = payments.client(3).unwrap();
+    assert_eq!(
+        (client.available(), client.held(), client.pending()),
+        (dec!(0), dec!(10), dec!(0))
+    );
+    payments.apply(Transaction::resolve(3, 4)).unwrap();
+    let client = payments.client(3).unwrap();
+    assert_eq!((client.available(), client.held()), (dec!(10), dec!(0)));
+
+    // A charged back pending deposit is never cleared
+    let client = payments.client(2).unwrap();
+    assert_eq!((client.total(), client.pending()), (dec!(0), dec!(0)));
+    assert!(client.locked());
+
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert_eq!(replayed.client(1), payments.client(1));
+    assert_eq!(replayed.client(2), payments.client(2));
+    assert_eq!(replayed.client(3), payments.client(3));
+}
+
+#[test]
+fn sequence_gaps() {
+    let mut payments = process(
+        "type, client, tx, amount, seq
+        deposit, 1, 1, 10, 1
+        deposit, 2, 2, 5, 100
+        deposit, 1, 3, 1, 4
+        withdrawal, 1, 4, 50, 5
+        deposit, 2, 5, 1, 101
+        deposit, 1, 6, 1, 2
+        deposit, 3, 7, 1,",
+    );
+    // Rejected transactions count still, they were received
+    assert_eq!(
+        payments.sequence_gaps().collect::<Vec<_>>(),
+        [(
+            1,
+            Sequence {
+                last: 5,
+                missing: 2,
+                regressions: 1
+            }
+        )]
+    );
+    let stats = payments.stats();
+    assert_eq!((stats.sequence_gaps, stats.sequence_regressions), (2, 1));
+
+    // Nor does rolling back forget them
+    payments.rollback(3);
+    assert_eq!(payments.stats().sequence_gaps, 2);
+}
+
+#[test]
+fn client_lifecycle() {
+    let mut payments = process(
+        "type, client, tx, amount, timestamp
+        deposit, 1, 1, 5, 2024-01-01T00:00:00Z
+        deposit, 2, 2, 5, 2024-01-01T00:00:00Z
+        withdrawal, 2, 3, 5, 2024-01-02T00:00:00Z
+        deposit, 3, 4, 1, 2024-03-01T00:00:00Z",
+    );
+    assert_eq!(
+        payments.close_account(1, None, None),
+        Err(Error::AccountNotEmpty(1))
+    );
+    assert_eq!(payments.close_account(2, None, None), Ok(()));
+    assert_eq!(payments.close_account(2, None, None), Ok(()));
+    let rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader("type, client, tx, amount\ndeposit, 2, 5, 1".as_bytes());
+    for transaction in parse(rdr) {
+        assert_eq!(
+            payments.apply(transaction.unwrap()),
+            Err(Error::AccountClosed(5))
+        );
+    }
+
+    let mut report = Vec::new();
+    payments
+        .serialize_lifecycle(&mut report, None, chrono::Duration::days(30))
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(report).unwrap(),
+        [
+            "client,status,last_activity",
+            "1,dormant,2024-01-01T00:00:00Z",
+            "2,closed,2024-01-02T00:00:00Z",
+            "3,active,2024-03-01T00:00:00Z",
+            ""
+        ]
+        .join("\n")
+    );
+    // The closure survives a replay of the event log
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert!(replayed.client(2).unwrap().closed());
+}
+
+#[test]
+fn amendments() {
+    let mut payments = process(
+        "type, client, tx, amount
+        deposit, 1, 1, 10
+        withdrawal, 1, 2, 4
+        deposit, 1, 3, 5
+        dispute, 1, 3,
+        amend, 1, 1, 8
+        amend, 1, 2, 5",
+    );
+    let client = payments.client(1).unwrap();
+    assert_eq!(
+        (client.available(), client.held(), client.total()),
+        (dec!(3), dec!(5), dec!(8))
+    );
+    assert_eq!(
+        client.operations().map(|op| op.amount).collect::<Vec<_>>(),
+        [dec!(8), dec!(-5), dec!(5)]
+    );
+    // Recorded as adjustments linked to the transactions
+    assert!(matches!(
+        payments.events()[4].event,
+        Event::TransactionAmended { tx: 1, amount, delta } if amount == dec!(8) && delta == dec!(-2)
+    ));
+
+    let mut amend = |tx, amount| payments.apply(Transaction::amend(1, tx, amount).unwrap());
+    assert_eq!(amend(3, dec!(1)), Err(Error::AmendmentOfDisputed(3)));
+    assert_eq!(amend(4, dec!(1)), Err(Error::TransactionNotFound(4)));
+    assert_eq!(amend(1, dec!(0)), Err(Error::InvalidAmount(1)));
+    assert!(matches!(
+        amend(1, dec!(1)),
+        Err(Error::InsufficientFunds { id: 1, .. })
+    ));
+    // Delivered again
+    assert_eq!(amend(2, dec!(5)), Ok(()));
+    assert_eq!(amend(1, dec!(9)), Ok(()));
+    assert_eq!(payments.client(1).unwrap().available(), dec!(4));
+    assert_eq!(payments.events().len(), 7);
+
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert_eq!(replayed.client(1), payments.client(1));
+}
+
+#[test]
+fn audit_log() {
+    let mut payments = process(
+        "type, client, tx, amount
+        deposit, 1, 1, 5
+        deposit, 1, 2, 3
+        dispute, 1, 2,
+        dispute, 1, 1,
+        chargeback, 1, 1,
+        deposit, 2, 3, 1
+        amend, 2, 3, 2",
+    );
+    let mut audit = AuditLog::new(Vec::new(), Chain::default());
+    audit
+        .record(&payments, Marker::default(), Actor::Transaction)
+        .unwrap();
+    let marker = payments.marker();
+    payments
+        .act(1, AdminAction::ForceResolve { tx: 2 }, 7, None)
+        .unwrap();
+    payments.act(1, AdminAction::Unlock, 7, None).unwrap();
+    assert_eq!(
+        payments.close_account(2, None, None),
+        Err(Error::AccountNotEmpty(2))
+    );
+    audit.record(&payments, marker, Actor::Operator).unwrap();
+
+    let actions = |log: &[u8]| {
+        std::str::from_utf8(log)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let record = serde_json::from_str::<serde_json::Value>(line).unwrap();
+                format!("{} {}", record["actor"], record["action"])
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        actions(audit.get_ref()),
+        [
+            r#""transaction" "lock""#,
+            r#""transaction" "amend""#,
+            r#""operator" "force-resolve""#,
+            r#""operator" "unlock""#
+        ]
+    );
+    assert_eq!(
+        Chain::verify(audit.get_ref().as_slice()),
+        Ok(audit.chain().clone())
+    );
+}
+
+#[test]
+fn transaction_log_resumes_and_detects_tampering() {
+    let path = std::env::temp_dir().join(format!("payments-txlog-{}.jsonl", std::process::id()));
+    let append = |input: &str| {
+        let mut payments = Payments::default();
+        let mut accepted = Accepted::default();
+        let mut log = TransactionLog::open(&path).unwrap();
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        for trans in parse(rdr) {
+            let trans = trans.unwrap();
+            let (entry, batch) = (canonical(&trans), trans.batch);
+            let result = payments.apply(trans);
+            for entry in accepted.record(entry, batch, &result) {
+                log.append(entry).unwrap();
+            }
+        }
+        for entry in accepted.finish() {
+            log.append(entry).unwrap();
+        }
+        log.chain().clone()
+    };
+    append("type, client, tx, amount\ndeposit, 1, 1, 5\nwithdrawal, 1, 2, 9");
+    let chain = append("type, client, tx, amount\ndeposit, 2, 3, 2");
+    assert_eq!(chain.len(), 2);
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(Chain::verify(log.as_bytes()), Ok(chain));
+    std::fs::write(&path, log.replace("deposit,2,3,2", "deposit,2,3,20")).unwrap();
+    assert!(TransactionLog::open(&path).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn merkle_root_of_accepted_transactions() {
+    let root = |input: &str| {
+        let mut payments = Payments::default();
+        let mut accepted = Accepted::default();
+        let mut tree = MerkleTree::default();
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        for trans in parse(rdr) {
+            let trans = trans.unwrap();
+            let (entry, batch) = (canonical(&trans), trans.batch);
+            let result = payments.apply(trans);
+            let done = accepted.record(entry, batch, &result);
+            done.iter().for_each(|entry| tree.push(entry));
+        }
+        accepted.finish().iter().for_each(|entry| tree.push(entry));
+        tree.summary()
+    };
+    let summary = root("type, client, tx, amount\ndeposit, 1, 1, 5\ndeposit, 2, 2, 3");
+    assert_eq!(summary.transactions, 2);
+    // Rejected transactions aren't part of the processed data
+    assert_eq!(
+        root("type, client, tx, amount\ndeposit, 1, 1, 5\nwithdrawal, 1, 9, 7\ndeposit, 2, 2, 3"),
+        summary
+    );
+    assert_ne!(
+        root("type, client, tx, amount\ndeposit, 1, 1, 5\ndeposit, 2, 2, 3.1"),
+        summary
+    );
+}
+
+#[test]
+fn builder() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let observed = events.clone();
+    let builder = Payments::builder()
+        .create_clients_on_success(true)
+        .withdrawal_chargeback(WithdrawalChargeback::WriteOff)
+        .max_risk_score(50.0)
+        .observer(move |event| observed.lock().unwrap().push(event.event));
+    assert_eq!(builder.config().max_risk_score, Some(50.0));
+    let mut payments = builder.build();
+    let rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(
+        "type, client, tx, amount
+        withdrawal, 1, 1, 5
+        deposit, 2, 2, 5
+        withdrawal, 2, 3, 2
+        dispute, 2, 3,
+        chargeback, 2, 3,"
+            .as_bytes(),
+    );
+    for trans in parse(rdr) {
+        let _ = payments.apply(trans.unwrap());
+    }
+    // The failed first withdrawal created no client, the chargeback was written off
+    assert_eq!(
+        dump(&payments),
+        "client,available,held,total,locked\n2,3,0,3,true\n"
+    );
+    assert_eq!(payments.stats().written_off, dec!(2));
+    assert_eq!(
+        *events.lock().unwrap(),
+        payments
+            .events()
+            .iter()
+            .map(|event| event.event)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn invalid_amounts() {
+    let mut payments = Payments::default();
+    let rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(
+        "type, client, tx, amount
+        deposit, 1, 1, 10
+        deposit, 1, 2, -1
+        withdrawal, 1, 3, -5
+        withdrawal, 1, 4, 1.00001
+        deposit, 1, 5, 0.0001"
+            .as_bytes(),
+    );
+    let results = parse(rdr)
+        .map(|transaction| payments.apply(transaction.unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        [
+            Ok(()),
+            Err(Error::InvalidAmount(2)),
+            Err(Error::InvalidAmount(3)),
+            Err(Error::InvalidAmount(4)),
+            Ok(())
+        ]
+    );
+    assert_eq!(
+        dump(&payments),
+        "client,available,held,total,locked\n1,10.0001,0,10.0001,false\n"
+    );
+}
+
+#[test]
+fn clients_read_model() {
+    let payments = process(
+        "type, client, tx, amount
+        deposit, 2, 1, 5
+        deposit, 1, 2, 3
+        withdrawal, 3, 3, 1",
+    );
+    assert_eq!(payments.len(), 3);
+    assert!(!payments.is_empty());
+    assert!(payments.contains(3));
+    assert!(!payments.contains(4));
+    assert_eq!(
+        payments
+            .clients()
+            .map(|client| (client.id, client.total()))
+            .collect::<Vec<_>>(),
+        [(1, dec!(3)), (2, dec!(5)), (3, dec!(0))]
+    );
+    assert!(Payments::default().is_empty());
+}
+
+#[test]
+fn view_is_unaffected_by_later_transactions() {
+    let mut payments = process("type, client, tx, amount\ndeposit, 1, 1, 5");
+    let view = payments.view();
+    let writer = std::thread::spawn(move || dump(&view));
+    payments
+        .apply(Transaction::deposit(1, 2, dec!(3)).unwrap())
+        .unwrap();
+    assert_eq!(
+        writer.join().unwrap(),
+        "client,available,held,total,locked\n1,5,0,5,false\n"
+    );
+    assert_eq!(payments.client(1).unwrap().total(), dec!(8));
+}
+
+#[test]
+fn open_disputes() {
+    let mut payments = process(
+        "type, client, tx, amount
+        deposit, 1, 1, 5
+        deposit, 1, 2, 3
+        withdrawal, 1, 3, 1
+        deposit, 2, 4, 2
+        dispute, 2, 4,
+        dispute, 1, 3,
+        dispute, 1, 1,
+        dispute, 1, 2,
+        resolve, 1, 2,",
+    );
+    assert_eq!(
+        payments.open_disputes().collect::<Vec<_>>(),
+        [(1, 1, dec!(5)), (1, 3, dec!(-1)), (2, 4, dec!(2))]
+    );
+    payments
+        .act(1, AdminAction::ForceResolve { tx: 1 }, 7, None)
+        .unwrap();
+    assert_eq!(
+        payments
+            .client(1)
+            .unwrap()
+            .open_disputes()
+            .collect::<Vec<_>>(),
+        [(3, dec!(-1))]
+    );
+}
+
+#[test]
+fn write_off_negative_balance() {
+    let mut credit_limits = CreditLimits::default();
+    credit_limits.insert(1, dec!(50));
+    let mut payments = process_with_config(
+        "type, client, tx, amount
+        deposit, 1, 1, 5
+        withdrawal, 1, 2, 20
+        deposit, 2, 3, 1",
+        Config {
+            credit_limits,
+            ..Config::default()
+        },
+    );
+    let write_off = AdminAction::WriteOff;
+    assert_eq!(
+        payments.act(3, write_off, 7, None),
+        Err(Error::ClientNotFound(3))
+    );
+    assert_eq!(
+        payments.act(2, write_off, 7, None),
+        Err(Error::BalanceNotNegative(2))
+    );
+    assert_eq!(
+        payments.act(1, write_off, 7, None),
+        Ok(Approval::Taken { amount: dec!(15) })
+    );
+    assert_eq!(
+        payments.act(1, write_off, 7, None),
+        Err(Error::BalanceNotNegative(1))
+    );
+    let client = payments.client(1).unwrap();
+    assert_eq!((client.available(), client.total()), (dec!(0), dec!(0)));
+    assert_eq!(payments.written_off(), dec!(15));
+    assert_eq!(payments.stats().written_off, dec!(15));
+
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert_eq!(replayed.client(1), payments.client(1));
+    assert_eq!(replayed.written_off(), dec!(15));
+}
+
+#[test]
+fn golden_accounts() {
+    let input = std::fs::read_to_string("tests/golden/disputes.csv").unwrap();
+    assert_golden(
+        "tests/golden/disputes.accounts.csv",
+        &process_and_dump(&input),
+        Normalize::default(),
+    );
+}
+
+#[test]
+fn regression_corpus() {
+    let update = std::env::var_os(UPDATE_GOLDEN).is_some();
+    let mismatches = replay_corpus(
+        std::path::Path::new("tests/corpus"),
+        Normalize::default(),
+        update,
+        |input| {
+            let mut payments = Payments::default();
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(input)
+                .unwrap();
+            for transaction in parse(rdr) {
+                match transaction {
+                    Ok(transaction) => {
+                        let _ = payments.apply(transaction);
+                    }
+                    Err(e) => return format!("error: {}\n", e),
+                }
+            }
+            dump(&payments)
+        },
+    )
+    .unwrap();
+    assert!(mismatches.is_empty(), "{:#?}", mismatches);
+}
+
+#[test]
+fn dispute_states() {
+    let input = r#"type, client, tx, amount
+        deposit, 1, 1, 3.0
+        deposit, 1, 2, 2.0
+        deposit, 1, 4, 3.0
+        withdrawal, 1, 3, 1.0
+        dispute, 1, 1,
+        dispute, 1, 4,
+        resolve, 1, 4,
+        deposit, 2, 5, 1.0
+        dispute, 2, 5,
+        chargeback, 2, 5,"#;
+    let payments = process_with_config(
+        input,
+        Config {
+            dispute_columns: true,
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        [
+            "client,available,held,total,locked,disputes",
+            "1,4,3,7,false,1:in_dispute:3 4:resolved:0",
+            "2,0,0,0,true,5:chargedback:0",
+            ""
+        ]
+        .join("\n")
+    );
+
+    let mut history = Vec::new();
+    payments.serialize_dispute_history(&mut history).unwrap();
+    assert_eq!(
+        String::from_utf8(history).unwrap(),
+        [
+            "client,tx,amount,state,held",
+            "1,1,3,in_dispute,3",
+            "1,4,3,resolved,0",
+            "2,5,1,chargedback,0",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn chargeback_losses() {
+    let mut credit_limits = CreditLimits::default();
+    credit_limits.insert(2, dec!(50));
+    let payments = process_with_config(
+        r#"type, client, tx, amount
+        deposit, 1, 1, 10.0
+        withdrawal, 1, 2, 4.0
+        dispute, 1, 2,
+        chargeback, 1, 2,
+        deposit, 2, 3, 10.0
+        dispute, 2, 3,
+        withdrawal, 2, 4, 5.0
+        chargeback, 2, 3,
+        deposit, 3, 5, 1.0"#,
+        Config {
+            credit_limits,
+            ..Config::default()
+        },
+    );
+    let mut losses = Vec::new();
+    payments.serialize_chargeback_losses(&mut losses).unwrap();
+    assert_eq!(
+        String::from_utf8(losses).unwrap(),
+        [
+            "client,chargebacks,charged_back,reversed,written_off,negative_total,lost",
+            "1,1,0,4,0,0,4",
+            "2,1,10,0,0,5,5",
+            ",2,10,4,0,5,9",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn dispute_report() {
+    let payments = process(
+        r#"type, client, tx, amount, timestamp
+        deposit, 1, 1, 10.0, 2024-03-01T00:00:00Z
+        deposit, 1, 2, 20.0, 2024-03-01T00:00:00Z
+        dispute, 1, 1, , 2024-03-02T00:00:00Z
+        resolve, 1, 1, , 2024-03-02T01:00:00Z
+        dispute, 1, 2, , 2024-03-03T00:00:00Z
+        chargeback, 1, 2, , 2024-03-05T00:00:00Z
+        deposit, 2, 3, 5.0, 2024-03-01T00:00:00Z
+        dispute, 2, 3, , 2024-03-04T00:00:00Z
+        deposit, 3, 4, 1.0, 2024-03-01T00:00:00Z"#,
+    );
+    let mut report = Vec::new();
+    payments.serialize_dispute_report(&mut report).unwrap();
+    assert_eq!(
+        String::from_utf8(report).unwrap(),
+        [
+            "client,opened,resolved,charged_back,average_amount,latency_p50,latency_p90,latency_p99",
+            "1,2,1,1,15,3600,172800,172800",
+            "2,1,0,0,5,,,",
+            ",3,1,1,11.6667,3600,172800,172800",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn counterparty_exposure() {
+    let payments = process(
+        r#"type, client, tx, amount, counterparty
+        deposit, 1, 1, 10.0, bank-a
+        deposit, 2, 2, 5.0, bank-a
+        withdrawal, 1, 3, 4.0, bank-b
+        withdrawal, 2, 4, 1.0, bank-a
+        deposit, 2, 5, 3.0, bank-b
+        dispute, 2, 5, ,
+        chargeback, 2, 5, ,
+        deposit, 3, 6, 1.0,"#,
+    );
+    let mut report = Vec::new();
+    payments.serialize_exposure(&mut report).unwrap();
+    assert_eq!(
+        String::from_utf8(report).unwrap(),
+        [
+            "counterparty,clients,deposited,withdrawn,gross,net",
+            "bank-a,2,15,1,16,14",
+            "bank-b,1,0,4,4,-4",
+            ",2,15,5,20,10",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn settlement_instructions() {
+    let payments = process(
+        r#"type, client, tx, amount, counterparty
+        deposit, 1, 1, 10.0, bank-a
+        withdrawal, 1, 2, 4.0, bank-b
+        withdrawal, 1, 3, 1.0, bank-a
+        deposit, 2, 4, 2.0, bank-c
+        withdrawal, 2, 5, 2.0, bank-c"#,
+    );
+    let mut settlement = Vec::new();
+    write_csv(&instructions(payments.net_positions()), &mut settlement).unwrap();
+    assert_eq!(
+        String::from_utf8(settlement).unwrap(),
+        [
+            "counterparty,direction,amount",
+            "bank-a,collect,9",
+            "bank-b,pay,4",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn blocked_clients() {
+    let input = r#"type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 5.0
+        deposit, 3, 3, 1.0
+        withdrawal, 2, 4, 1.0"#;
+    let mut denied = ClientList::default();
+    denied.insert(2);
+    let payments = process_with_config(
+        input,
+        Config {
+            access: Access {
+                denied: denied.clone(),
+                allowed: None,
+            },
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        [
+            "client,available,held,total,locked",
+            "1,10,0,10,false",
+            "3,1,0,1,false",
+            ""
+        ]
+        .join("\n")
+    );
+    assert_eq!(payments.stats().blocked, 2);
+
+    let mut allowed = ClientList::default();
+    allowed.insert(1);
+    allowed.insert(2);
+    let mut payments = Payments::with_config(Config {
+        access: Access {
+            denied,
+            allowed: Some(allowed),
+        },
+        ..Config::default()
+    });
+    let results = parse(
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes()),
+    )
+    .map(|trans| payments.apply(trans.unwrap()))
+    .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        [
+            Ok(()),
+            Err(Error::ClientBlocked(2)),
+            Err(Error::ClientBlocked(3)),
+            Err(Error::ClientBlocked(2))
+        ]
+    );
+    assert_eq!(payments.stats().blocked, 3);
+}
+
+#[test]
+fn reserves() {
+    let mut reserves = Reserves::new(dec!(5));
+    reserves.insert(2, dec!(0));
+    let payments = process_with_config(
+        r#"type, client, tx, amount
+        deposit, 1, 1, 10.0
+        withdrawal, 1, 2, 6.0
+        withdrawal, 1, 3, 5.0
+        deposit, 2, 4, 10.0
+        withdrawal, 2, 5, 10.0"#,
+        Config {
+            reserves,
+            reserve_column: true,
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        [
+            "client,available,held,total,locked,reserved",
+            "1,5,0,5,false,5",
+            "2,0,0,0,false,0",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn credit_lines() {
+    let mut credit_limits = CreditLimits::default();
+    credit_limits.insert(1, dec!(50));
+    let payments = process_with_config(
+        r#"type, client, tx, amount
+        deposit, 1, 1, 10.0
+        withdrawal, 1, 2, 40.0
+        withdrawal, 1, 3, 25.0
+        deposit, 2, 4, 10.0
+        withdrawal, 2, 5, 15.0"#,
+        Config {
+            credit_limits,
+            ..Config::default()
+        },
+    );
+    assert_eq!(
+        dump(&payments),
+        [
+            "client,available,held,total,locked,credit_limit,credit_drawn",
+            "1,-30,0,-30,false,50,30",
+            "2,10,0,10,false,0,0",
+            ""
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn sub_accounts() {
+    let input = r#"type, client, tx, amount, from_account, to_account
+        deposit, 1, 1, 10.0, ,
+        transfer, 1, 2, 6.0, , savings
+        transfer, 1, 3, 2.0, savings, holiday
+        withdrawal, 1, 4, 5.0, ,
+        transfer, 1, 5, 1.0, savings, main
+        withdrawal, 1, 6, 5.0, ,
+        deposit, 2, 7, 1.0, ,"#;
+    let config = || Config {
+        sub_account_columns: true,
+        ..Config::default()
+    };
+    let payments = process_with_config(input, config());
+    let expected = [
+        "client,available,held,total,locked,sub_accounts",
+        "1,5,0,5,false,holiday:2 savings:3",
+        "2,1,0,1,false,",
+        "",
+    ]
+    .join("\n");
+    assert_eq!(dump(&payments), expected);
+
+    let mut events = Vec::new();
+    payments.export_events(&mut events).unwrap();
+    let replayed = Payments::import_events_with(config(), events.as_slice()).unwrap();
+    assert_eq!(dump(&replayed), expected);
+}
+
+#[test]
+fn disputed_transfers() {
+    let input = r#"type, client, tx, amount, from_account, to_account
+        deposit, 1, 1, 10.0, ,
+        transfer, 1, 2, 6.0, , savings
+        transfer, 1, 3, 2.0, savings, holiday
+        dispute, 1, 3, , ,
+        chargeback, 1, 3, , ,
+        dispute, 1, 2, , ,
+        withdrawal, 1, 4, 5.0, ,"#;
+    let config = || Config {
+        sub_account_columns: true,
+        ..Config::default()
+    };
+    let payments = process_with_config(input, config());
+    let expected = [
+        "client,available,held,total,locked,sub_accounts",
+        "1,4,6,10,false,holiday:0 savings:0",
+        "",
+    ]
+    .join("\n");
+    assert_eq!(dump(&payments), expected);
+
+    let mut events = Vec::new();
+    payments.export_events(&mut events).unwrap();
+    let replayed = Payments::import_events_with(config(), events.as_slice()).unwrap();
+    assert_eq!(dump(&replayed), expected);
+}
+
+#[test]
+fn transfer_dispute_policies() {
+    // The funds of transfer 2 moved on to another account before it's disputed
+    let input = r#"type, client, tx, amount, from_account, to_account
+        deposit, 1, 1, 10.0, ,
+        transfer, 1, 2, 6.0, , savings
+        transfer, 1, 3, 4.0, savings, holiday
+        dispute, 1, 2, , ,
+        chargeback, 1, 2, , ,"#;
+    let process = |policy| {
+        let config = Config {
+            sub_account_columns: true,
+            transfer_dispute: policy,
+            ..Config::default()
+        };
+        let payments = process_with_config(input, config);
+        (dump(&payments), payments.written_off())
+    };
+    let header = "client,available,held,total,locked,sub_accounts";
+
+    assert_eq!(
+        process(DisputePolicy::Hold),
+        (
+            [header, "1,10,0,10,false,holiday:4 savings:2", ""].join("\n"),
+            dec!(0)
+        )
+    );
+    assert_eq!(
+        process(DisputePolicy::NegativeBalance),
+        (
+            [header, "1,10,0,10,false,holiday:4 savings:-4", ""].join("\n"),
+            dec!(0)
+        )
+    );
+    // Only the 2 left in savings are clawed back, the house covers the rest
+    assert_eq!(
+        process(DisputePolicy::WriteOff),
+        (
+            [header, "1,14,0,14,false,holiday:4 savings:0", ""].join("\n"),
+            dec!(4)
+        )
+    );
+}
+
+#[test]
+fn dedup_scopes() {
+    let input = r#"type, client, tx, amount
+        deposit, 1, 1, 10.0
+        withdrawal, 1, 1, 4.0
+        deposit, 2, 1, 1.0"#;
+    let process_scoped = |dedup_scope| {
+        dump(&process_with_config(
+            input,
+            Config {
+                dedup_scope,
+                ..Config::default()
+            },
+        ))
+    };
+    assert_eq!(
+        process_scoped(DedupScope::Client),
+        "client,available,held,total,locked\n1,10,0,10,false\n2,1,0,1,false\n"
+    );
+    assert_eq!(
+        process_scoped(DedupScope::ClientOperation),
+        "client,available,held,total,locked\n1,6,0,6,false\n2,1,0,1,false\n"
+    );
+    assert_eq!(
+        process_scoped(DedupScope::Global(DedupConfig::default())),
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+}
+
+#[test]
+fn sources() {
+    let mut payments = Payments::default();
+    let mut apply = |source, input: &str| {
+        payments.set_source(Some(source));
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        for trans in parse(rdr) {
+            let _ = payments.apply(trans.unwrap());
+        }
+    };
+    apply(
+        "bank",
+        r#"type,client,tx,amount,timestamp,batch
+        deposit, 1, 1, 10, ,
+        deposit, 2, 2, 5, , 1
+        withdrawal, 2, 3, 100, , 1"#,
+    );
+    apply(
+        "psp",
+        r#"type,client,tx,amount
+        dispute, 1, 1,
+        withdrawal, 1, 4, 20
+        dispute, 1, 9,
+        dispute, 3, 9,"#,
+    );
+
+    // A dispute keeps the source of its deposit, a rolled back batch is attributed to none
+    assert_eq!(payments.source_of(1, 1), Some("bank"));
+    assert_eq!(payments.source_of(2, 2), None);
+    assert_eq!(payments.source_of(1, 4), None);
+    let stats = payments
+        .source_stats()
+        .map(|(source, stats)| {
+            (
+                source,
+                stats.transactions,
+                stats.rejected,
+                stats.rejections.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        stats,
+        [
+            (
+                "bank",
+                3,
+                1,
+                [("batch_rolled_back", 1)].into_iter().collect()
+            ),
+            (
+                "psp",
+                4,
+                3,
+                [("insufficient_funds", 1), ("transaction_not_found", 2)]
+                    .into_iter()
+                    .collect()
+            ),
+        ]
+    );
+
+    payments.set_source(None);
+    let _ = payments.apply(Transaction::deposit(1, 5, dec!(1)).unwrap());
+    assert_eq!(payments.source_of(1, 5), None);
+    assert_eq!(
+        payments
+            .source_stats()
+            .map(|(_, s)| s.transactions)
+            .sum::<usize>(),
+        7
+    );
+}
+
+#[test]
+fn quarantine() {
+    let mut payments = process_with_config(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 10
+        deposit, 1, 2, 10
+        dispute, 1, 1,
+        resolve, 1, 1,
+        withdrawal, 1, 3, 5
+        withdrawal, 1, 4, 100
+        deposit, 2, 5, 10"#,
+        Config {
+            quarantine_risk_score: Some(10.0),
+            ..Config::default()
+        },
+    );
+    // Quarantined by the dispute, the rest of the client's transactions are parked
+    assert!(payments.client(1).unwrap().quarantined());
+    assert_eq!(
+        dump(&payments),
+        "client,available,held,total,locked\n1,10,10,20,false\n2,10,0,10,false\n"
+    );
+    assert_eq!(
+        payments.parked().map(|t| t.op.id).collect::<Vec<_>>(),
+        [1, 3, 4]
+    );
+    assert_eq!(
+        (payments.stats().quarantined, payments.stats().parked),
+        (1, 3)
+    );
+
+    assert_eq!(
+        payments.release(1, 9, None),
+        Err(Error::TransactionNotFound(9))
+    );
+    assert_eq!(payments.release(1, 3, None), Ok(()));
+    assert!(payments.client(1).unwrap().quarantined());
+    // A state rebuilt from the event log has the same transactions to release
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert!(replayed.parked().eq(payments.parked()));
+    assert_eq!(
+        replayed.parked().map(|t| t.op.id).collect::<Vec<_>>(),
+        [1, 4]
+    );
+    let released = payments.lift_quarantine(1, None, None).unwrap();
+    assert_eq!(released[0], (1, Ok(())));
+    assert!(matches!(
+        released[1],
+        (4, Err(Error::InsufficientFunds { id: 4, .. }))
+    ));
+    assert_eq!(
+        dump(&payments),
+        "client,available,held,total,locked\n1,15,0,15,false\n2,10,0,10,false\n"
+    );
+    assert_eq!(payments.parked().count(), 0);
+    assert_eq!(
+        payments.lift_quarantine(1, None, None),
+        Err(Error::NotQuarantined(1))
+    );
+    assert_eq!(
+        payments.quarantine(7, None, None),
+        Err(Error::ClientNotFound(7))
+    );
+
+    // Parking is rolled back with its batch
+    payments.quarantine(2, None, None).unwrap();
+    let batch = |transaction: Transaction| Transaction {
+        batch: Some(1),
+        ..transaction
+    };
+    assert!(payments
+        .apply(batch(Transaction::deposit(2, 6, dec!(1)).unwrap()))
+        .is_ok());
+    assert_eq!(payments.parked().count(), 1);
+    assert!(payments
+        .apply(batch(Transaction::withdrawal(1, 7, dec!(100)).unwrap()))
+        .is_err());
+    assert_eq!(payments.parked().count(), 0);
+    assert!(payments.client(2).unwrap().quarantined());
+
+    let replayed = Payments::replay(payments.events().iter().copied()).unwrap();
+    assert_eq!(replayed.client(1), payments.client(1));
+    assert_eq!(replayed.client(2), payments.client(2));
+}
+
+#[test]
+fn four_eyes_approval() {
+    let mut payments = process_with_config(
+        r#"type,client,tx,amount
+        deposit, 1, 1, 500
+        deposit, 1, 2, 50
+        dispute, 1, 2,
+        chargeback, 1, 2,
+        deposit, 2, 3, 20
+        deposit, 2, 4, 10
+        dispute, 2, 4,
+        dispute, 2, 3,
+        chargeback, 2, 3,"#,
+        Config {
+            approval_threshold: Some(dec!(100)),
+            ..Config::default()
+        },
+    );
+    // Below the threshold, taken right away
+    assert_eq!(
+        payments.act(2, AdminAction::ForceResolve { tx: 4 }, 7, None),
+        Ok(Approval::Taken { amount: dec!(10) })
+    );
+    assert_eq!(
+        payments.act(2, AdminAction::Unlock, 7, None),
+        Ok(Approval::Taken { amount: dec!(10) })
+    );
+    assert_eq!(
+        payments.act(2, AdminAction::WriteOff, 7, None),
+        Err(Error::BalanceNotNegative(2))
+    );
+
+    let unlock =
+        |payments: &mut Payments, operator| payments.act(1, AdminAction::Unlock, operator, None);
+    assert_eq!(
+        unlock(&mut payments, 7),
+        Ok(Approval::Pending { amount: dec!(500) })
+    );
+    assert!(payments.client(1).unwrap().locked());
+    assert_eq!(
+        unlock(&mut payments, 7),
+        Err(Error::SameOperator {
+            client: 1,
+            operator: 7
+        })
+    );
+    assert_eq!(payments.stats().pending_approvals, 1);
+    let pending = *payments.pending_approvals().next().unwrap();
+    assert_eq!((pending.client, pending.requested_by), (1, 7));
+
+    // Kept in the event log
+    let events = payments
+        .events()
+        .iter()
+        .map(|event| serde_json::to_string(event).unwrap())
+        .collect::<Vec<_>>();
+    assert!(events.contains(
+        &r#"{"client":1,"operator":7,"event":"ApprovalRequested","action":"unlock","amount":"500"}"#
+            .to_string()
+    ));
+    let replayed = Payments::replay(
+        events
+            .iter()
+            .map(|event| serde_json::from_str(event).unwrap()),
+    )
+    .unwrap();
+    assert_eq!(replayed.pending_approvals().next(), Some(&pending));
+
+    assert_eq!(
+        unlock(&mut payments, 8),
+        Ok(Approval::Taken { amount: dec!(500) })
+    );
+    assert!(!payments.client(1).unwrap().locked());
+    assert_eq!(payments.pending_approvals().count(), 0);
+    assert_eq!(unlock(&mut payments, 8), Err(Error::AccountNotLocked(1)));
+
+    // An approval isn't taken for more than was asked for
+    let mut credit_limits = CreditLimits::default();
+    credit_limits.insert(3, dec!(500));
+    let mut payments = process_with_config(
+        "type, client, tx, amount
+        withdrawal, 3, 5, 150",
+        Config {
+            approval_threshold: Some(dec!(100)),
+            credit_limits,
+            ..Config::default()
+        },
+    );
+    let write_off =
+        |payments: &mut Payments, operator| payments.act(3, AdminAction::WriteOff, operator, None);
+    assert_eq!(
+        write_off(&mut payments, 7),
+        Ok(Approval::Pending { amount: dec!(150) })
+    );
+    payments
+        .apply(Transaction::withdrawal(3, 6, dec!(20)).unwrap())
+        .unwrap();
+    assert_eq!(
+        write_off(&mut payments, 8),
+        Err(Error::ApprovalExceeded {
+            client: 3,
+            requested: dec!(150),
+            amount: dec!(170)
+        })
+    );
+    assert_eq!(
+        write_off(&mut payments, 7),
+        Ok(Approval::Pending { amount: dec!(170) })
+    );
+    assert_eq!(
+        write_off(&mut payments, 8),
+        Ok(Approval::Taken { amount: dec!(170) })
+    );
+}
+
+#[test]
+fn operator_identity() {
+    let mut payments = process(
+        "type, client, tx, amount
+        deposit, 1, 1, 10
+        deposit, 1, 2, 5
+        dispute, 1, 1,
+        chargeback, 1, 1,",
+    );
+    let marker = payments.marker();
+    payments.act(1, AdminAction::Unlock, 7, None).unwrap();
+    payments.quarantine(1, Some(8), None).unwrap();
+    payments
+        .apply(Transaction::deposit(1, 3, dec!(4)).unwrap())
+        .unwrap();
+    payments.lift_quarantine(1, Some(9), None).unwrap();
+
+    let operators = payments
+        .events()
+        .iter()
+        .map(|event| (event.event, event.operator))
+        .collect::<Vec<_>>();
+    assert!(operators.contains(&(Event::AccountUnlocked, Some(7))));
+    assert!(operators.contains(&(Event::ClientQuarantined { tx: None }, Some(8))));
+    assert!(operators
+        .iter()
+        .any(|event| matches!(event, (Event::TransactionParked { tx: 3, .. }, None))));
+    // Released by the operator lifting the quarantine
+    assert!(operators
+        .iter()
+        .any(|event| matches!(event, (Event::FundsDeposited { tx: 3, .. }, Some(9)))));
+    // Kept in the event log
+    let replayed = Payments::replay(
+        payments
+            .events()
+            .iter()
+            .map(|event| serde_json::from_str(&serde_json::to_string(event).unwrap()).unwrap()),
+    )
+    .unwrap();
+    assert_eq!(replayed.events(), payments.events());
+
+    let mut audit = AuditLog::new(Vec::new(), Chain::default());
+    audit.record(&payments, marker, Actor::Operator).unwrap();
+    let log = String::from_utf8(audit.get_ref().clone()).unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].contains(r#""operator":7,"action":"unlock""#));
+    assert!(lines[1].contains(r#""operator":8,"action":"quarantine""#));
+    assert!(lines[2].contains(r#""operator":9,"action":"lift-quarantine""#));
+}
@@ -0,0 +1,243 @@
+//! The engine must not panic on any input: random rows, valid or not, with extreme amounts,
+//! dates and IDs, under configurations exercising the timed features. Rejecting them is fine.
+//! `PAYMENTS_ROBUSTNESS_SEEDS` runs more workloads than the default, and `fuzz/` has the
+//! same checks as coverage-guided fuzz targets.
+use chrono::Duration;
+use payments::{
+    client::WithdrawalChargeback,
+    error::Error,
+    event::{ClientEvent, Event},
+    features::Format,
+    parser::{parse, ParseOptions},
+    payments::{Config, Payments},
+    pipeline::{run, Policy, RunConfig},
+    snapshot,
+};
+use rust_decimal::Decimal;
+
+const SEEDS: u64 = 30;
+
+const KINDS: [&str; 10] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "amend",
+    "settle",
+    "transfer",
+    "refund",
+    "",
+];
+
+const AMOUNTS: [&str; 13] = [
+    "1",
+    "0.0001",
+    "0",
+    "-1",
+    "1.00001",
+    "99999999999999.9999",
+    "1000000000000000",
+    "7922816251426433759354395033.5",
+    "79228162514264337593543950335",
+    "50000000000000000000000000000.0",
+    "1e3",
+    "abc",
+    "",
+];
+
+const TIMESTAMPS: [&str; 7] = [
+    "2024-03-01T00:00:00Z",
+    "2024-03-01T00:00:00+14:00",
+    "0001-01-01T00:00:00Z",
+    "9999-12-31T23:59:59Z",
+    "+262142-12-31T23:59:59Z",
+    "2024-02-30T00:00:00Z",
+    "",
+];
+
+const DATES: [&str; 5] = ["2024-03-02", "9999-12-31", "0001-01-01", "2024-13-01", ""];
+
+fn pick<'a>(rng: &mut fastrand::Rng, values: &[&'a str]) -> &'a str {
+    values[rng.usize(..values.len())]
+}
+
+/// Random rows, most of them well-formed, with fields drawn from edge cases
+fn workload(seed: u64, rows: u32) -> String {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let mut input =
+        "type, client, tx, amount, timestamp, value_date, seq, counterparty, batch\n".to_string();
+    for _ in 0..rows {
+        let client = match rng.u8(..10) {
+            0 => u16::MAX,
+            _ => rng.u16(..4),
+        };
+        let tx = match rng.u8(..10) {
+            0 => u32::MAX,
+            _ => rng.u32(..20),
+        };
+        let amount = match rng.bool() {
+            true => format!("{}.{:04}", rng.u32(..1000), rng.u32(..10_000)),
+            false => pick(&mut rng, &AMOUNTS).to_string(),
+        };
+        let seq = match rng.u8(..4) {
+            0 => String::new(),
+            1 => u64::MAX.to_string(),
+            _ => rng.u64(..10).to_string(),
+        };
+        let counterparty = match rng.u8(..4) {
+            0 => "acme",
+            1 => "a-counterparty-name-longer-than-allowed",
+            _ => "",
+        };
+        let batch = match rng.u8(..8) {
+            0 => "1",
+            _ => "",
+        };
+        input.push_str(&format!(
+            "{}, {}, {}, {}, {}, {}, {}, {}, {}\n",
+            pick(&mut rng, &KINDS),
+            client,
+            tx,
+            amount,
+            pick(&mut rng, &TIMESTAMPS),
+            pick(&mut rng, &DATES),
+            seq,
+            counterparty,
+            batch
+        ));
+    }
+    input
+}
+
+fn configs() -> Vec<Config> {
+    vec![
+        Config::default(),
+        Config {
+            dispute_timeout: Some(Duration::days(1)),
+            settlement_delay: Some(Duration::days(2)),
+            max_risk_score: Some(50.0),
+            withdrawal_chargeback: WithdrawalChargeback::WriteOff,
+            ..Config::default()
+        },
+        Config {
+            dispute_timeout: Some(Duration::MAX),
+            settlement_delay: Some(Duration::MAX),
+            ..Config::default()
+        },
+    ]
+}
+
+/// Everything a run writes, to exercise the reports too
+fn outputs(payments: &Payments) {
+    let sink = std::io::sink;
+    let _ = payments.serialize(sink());
+    let _ = payments.serialize_disputes(sink());
+    let _ = payments.serialize_dispute_history(sink());
+    let _ = payments.serialize_dispute_report(sink());
+    let _ = payments.serialize_exposure(sink());
+    let _ = payments.serialize_chargeback_losses(sink());
+    let _ = payments.serialize_lifecycle(sink(), None, Duration::days(30));
+    let features = payments.features();
+    let _ = features.write_clients(Format::Csv, sink());
+    let _ = features.write_transactions(Format::Csv, sink());
+    let _ = payments.stats().to_string();
+    Payments::replay_with(payments.config().clone(), payments.events().to_vec()).unwrap();
+}
+
+#[test]
+fn no_panics() {
+    let seeds = std::env::var("PAYMENTS_ROBUSTNESS_SEEDS")
+        .ok()
+        .and_then(|seeds| seeds.parse().ok())
+        .unwrap_or(SEEDS);
+    for seed in 0..seeds {
+        let input = workload(seed, 200);
+        for config in configs() {
+            let rdr = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(input.as_bytes());
+            let mut payments = Payments::with_config(config);
+            for transaction in parse(rdr).flatten() {
+                let _ = payments.preview(&transaction);
+                let _ = payments.apply(transaction);
+            }
+            outputs(&payments);
+            payments.rollback(50);
+            outputs(&payments);
+        }
+    }
+}
+
+#[test]
+fn malformed_snapshots() {
+    let snapshots = [
+        "",
+        "{",
+        r#"{"snapshot_version":0}"#,
+        r#"{"snapshot_version":1}"#,
+        "{\"snapshot_version\":2}\n{\"client\":1}",
+        "{\"snapshot_version\":4294967295}\n",
+        r#"{"seq":1}"#,
+    ];
+    for data in snapshots {
+        let _ = snapshot::read_header(data.as_bytes());
+        let _ = snapshot::read(data.as_bytes());
+    }
+}
+
+#[test]
+fn overflowing_replays_fail() {
+    // A modified log, amounts of applied transactions are bounded
+    let deposit = |tx| ClientEvent {
+        client: 1,
+        timestamp: None,
+        operator: None,
+        event: Event::FundsDeposited {
+            tx,
+            amount: Decimal::MAX,
+            ref_tx: None,
+            counterparty: None,
+        },
+    };
+    assert!(Payments::replay([deposit(1)]).is_ok());
+    assert!(matches!(
+        Payments::replay([deposit(1), deposit(2)]),
+        Err(Error::Overflow(_))
+    ));
+}
+
+/// Fails every write
+struct Broken;
+
+impl std::io::Write for Broken {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn run_fails_without_panicking() {
+    let input = workload(0, 200);
+    for lenient in [false, true] {
+        let config = RunConfig {
+            parse: ParseOptions {
+                lenient_quotes: lenient,
+                flexible: true,
+            },
+            policy: Policy {
+                skip_malformed: lenient,
+            },
+            ..RunConfig::default()
+        };
+        let _ = run(config.clone(), input.as_bytes(), std::io::sink());
+        let deposit = "type, client, tx, amount\ndeposit, 1, 1, 1\n";
+        let error = run(config, deposit.as_bytes(), Broken).unwrap_err();
+        assert!(matches!(error, Error::Io(_)));
+    }
+}